@@ -0,0 +1,110 @@
+// Time the queries relayed by the proxy, with the messages decoded and given
+// to an observer (relay) or copied as they are read (forward), against the
+// queries sent to the server directly:
+//
+//   cargo run --release --example bench_proxy
+//
+// The latency is measured with small queries, one round trip each, and the
+// throughput with large results. relay() writes each message on its own, so
+// the answer of a small query can wait for the delayed ACK of the previous
+// write (Nagle's algorithm, about 40ms on Linux), forward() writes what it
+// read at once.
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fakepostmaster::columns;
+use fakepostmaster::handler::{client, proxy, server};
+use fakepostmaster::value::PgValue;
+
+const SMALL_QUERIES: u32 = 200;
+const ROWS: i32 = 100_000;
+const LARGE_QUERIES: usize = 10;
+
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    Direct,
+    Relay,
+    Forward,
+}
+
+/// A server answering "SELECT 1" with one row and anything else with ROWS
+/// rows, for each connection
+fn serve(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        thread::spawn(move || -> anyhow::Result<()> {
+            let mut handler = server::TcpHandler::new(stream)?;
+            handler.md5_authentication_handler(&|| true)?;
+            let executor = |query: String| {
+                let rows = if query == "SELECT 1" { 1 } else { ROWS };
+                server::QueryResult {
+                    columns: columns![("id", Int4), ("name", Text)].expect("columns"),
+                    rows: (0..rows)
+                        .map(|id| vec![PgValue::Int4(id), PgValue::Text(format!("name {id}"))])
+                        .collect(),
+                    command_tag: format!("SELECT {rows}"),
+                    error: None,
+                }
+            };
+            // The client disconnects without Terminate
+            while handler.query_handler(&executor).unwrap_or(false) {}
+            Ok(())
+        });
+    }
+}
+
+/// The address of a proxy relaying one connection to the server
+fn proxy(mode: Mode, server: String) -> anyhow::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?.to_string();
+    thread::spawn(move || -> anyhow::Result<()> {
+        let handler = proxy::TcpHandler::new(listener.accept()?.0, TcpStream::connect(server)?)?;
+        match mode {
+            Mode::Relay => handler.relay(&mut |message| {
+                std::hint::black_box(message);
+            }),
+            _ => handler.forward(),
+        }
+    });
+    Ok(address)
+}
+
+fn run(mode: Mode, server: &str) -> anyhow::Result<()> {
+    let address = match mode {
+        Mode::Direct => server.to_string(),
+        _ => proxy(mode, server.to_string())?,
+    };
+    let mut handler = client::TcpHandler::new(TcpStream::connect(address)?)?;
+    handler.md5_authentication_handler()?;
+
+    let start = Instant::now();
+    for _ in 0..SMALL_QUERIES {
+        assert_eq!(1, handler.simple_query_handler("SELECT 1")?.len());
+    }
+    let latency = start.elapsed() / SMALL_QUERIES;
+
+    let start = Instant::now();
+    for _ in 0..LARGE_QUERIES {
+        let rows = handler.simple_query_handler("SELECT id, name FROM t")?;
+        assert_eq!(ROWS as usize, rows.len());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{mode:?}: {:?} per query, {:.0} rows/s",
+        Duration::from_nanos(latency.as_nanos() as u64),
+        f64::from(ROWS) * LARGE_QUERIES as f64 / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let server = listener.local_addr()?.to_string();
+    thread::spawn(move || serve(listener));
+
+    for mode in [Mode::Direct, Mode::Relay, Mode::Forward] {
+        run(mode, &server)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+// Time the transfer of large results between the server and the client
+// handlers, to compare the write paths:
+//
+//   cargo run --release --example bench_rows
+//   cargo run --release --example bench_rows --features vectored-writes
+//
+// The server writes through a counting writer, each write or writev is a
+// system call on the socket: the rows are sent 8kB at a time without the
+// feature and up to IOV_MAX messages at a time with it. The same counts
+// can be checked with `strace -c -f -e trace=sendto,writev`.
+use std::io::{self, IoSlice, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use fakepostmaster::columns;
+use fakepostmaster::handler::{client, server};
+use fakepostmaster::value::PgValue;
+
+const ROWS: i32 = 100_000;
+const QUERIES: usize = 10;
+
+/// The socket of the server, counting the write system calls
+struct CountingWriter {
+    stream: TcpStream,
+    writes: Arc<AtomicU64>,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.stream.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.stream.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    let writes = Arc::new(AtomicU64::new(0));
+
+    let server_writes = writes.clone();
+    let server = thread::spawn(move || -> anyhow::Result<()> {
+        let stream = listener.accept()?.0;
+        let writer = CountingWriter {
+            stream: stream.try_clone()?,
+            writes: server_writes,
+        };
+        let mut handler = server::Handler::from_parts(stream, writer);
+        handler.md5_authentication_handler(&|| true)?;
+        let executor = |_query: String| server::QueryResult {
+            columns: columns![("id", Int4), ("name", Text)].expect("columns"),
+            rows: (0..ROWS)
+                .map(|id| vec![PgValue::Int4(id), PgValue::Text(format!("name {id}"))])
+                .collect(),
+            command_tag: format!("SELECT {ROWS}"),
+            error: None,
+        };
+        // The client disconnects without Terminate
+        while handler.query_handler(&executor).unwrap_or(false) {}
+        Ok(())
+    });
+
+    let mut handler = client::TcpHandler::new(TcpStream::connect(address)?)?;
+    handler.md5_authentication_handler()?;
+
+    let (start, before) = (Instant::now(), writes.load(Ordering::Relaxed));
+    for _ in 0..QUERIES {
+        let rows = handler.simple_query_handler("SELECT id, name FROM t")?;
+        assert_eq!(ROWS as usize, rows.len());
+    }
+    report("SELECT", start, writes.load(Ordering::Relaxed) - before);
+
+    let (start, before) = (Instant::now(), writes.load(Ordering::Relaxed));
+    for _ in 0..QUERIES {
+        let rows = handler.copy_out("COPY t TO STDOUT", &mut io::sink())?;
+        assert_eq!(ROWS as u64, rows);
+    }
+    report(
+        "COPY TO STDOUT",
+        start,
+        writes.load(Ordering::Relaxed) - before,
+    );
+
+    drop(handler);
+    server.join().expect("server thread")
+}
+
+fn report(command: &str, start: Instant, writes: u64) {
+    let elapsed = start.elapsed();
+    println!(
+        "{QUERIES} {command} of {ROWS} rows in {elapsed:?}, {:.0} rows/s, {} writes per query",
+        f64::from(ROWS) * QUERIES as f64 / elapsed.as_secs_f64(),
+        writes / QUERIES as u64
+    );
+}
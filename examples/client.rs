@@ -1,6 +1,5 @@
-use std::net::TcpStream;
+use std::time::Duration;
 use tracing::*;
-use tracing_subscriber;
 
 use fakepostmaster::handler::client::TcpHandler;
 
@@ -10,16 +9,15 @@ fn main() -> anyhow::Result<()> {
         .compact()
         .init();
 
-    let stream = TcpStream::connect("pgsrv:5435");
     info!("Connecting to pgsrv:5435...");
+    // Any of the IPv6 and IPv4 addresses of the name
+    let handler = TcpHandler::connect("pgsrv:5435", Duration::from_secs(5));
 
-    match stream {
-        Ok(stream) => {
+    match handler {
+        Ok(mut handler) => {
             info!("Connection established");
-            let mut handler = TcpHandler::new(stream)?;
-
             handler.md5_authentication_handler()?;
-            handler.simple_query_handler()?;
+            handler.simple_query_handler("SELECT 1 as a, 2 as a, 3 as a;")?;
             info!("Connection ended");
         }
         Err(e) => {
@@ -0,0 +1,51 @@
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+use tracing::*;
+
+use fakepostmaster::handler::pool::{Connector, Pool, PoolConfig};
+use fakepostmaster::handler::pooler::TransactionPooler;
+use fakepostmaster::handler::probe::{self, ConnInfo};
+
+// Share a few connections to a real server between the frontends, one
+// transaction at a time:
+//   pooler [listen address] [server conninfo] [pool size]
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .compact()
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let listen = args.next().unwrap_or(String::from("127.0.0.1:6432"));
+    let conninfo: ConnInfo = args
+        .next()
+        .unwrap_or(String::from("host=127.0.0.1 port=5432 user=postgres"))
+        .parse()?;
+    let max_size = args.next().map(|size| size.parse()).transpose()?;
+
+    let connect: Connector =
+        Box::new(move || probe::connect(&conninfo, "pooler", Duration::from_secs(5)));
+    let config = PoolConfig {
+        max_size: max_size.unwrap_or(10),
+        ..PoolConfig::default()
+    };
+    let pooler = TransactionPooler::new(Pool::new(config, connect))?;
+
+    let listener = TcpListener::bind(&listen)?;
+    info!("Listening on {listen}");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let pooler = pooler.clone();
+                thread::spawn(move || {
+                    if let Err(e) = pooler.serve(stream) {
+                        debug!("Session ended: {e}");
+                    }
+                });
+            }
+            Err(e) => error!("error: {e}"),
+        }
+    }
+    Ok(())
+}
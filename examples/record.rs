@@ -0,0 +1,59 @@
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+use tracing::*;
+
+use fakepostmaster::handler::proxy::TcpHandler;
+use fakepostmaster::recorder::Recorder;
+use fakepostmaster::stats::{ProxyLatency, QueryStats};
+
+// Relay the connections to a real server and record the queries and their
+// results in a scenario file that can be replayed offline:
+//   record [listen address] [server address] [scenario file]
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .compact()
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let listen = args.next().unwrap_or(String::from("127.0.0.1:9092"));
+    let server = args.next().unwrap_or(String::from("127.0.0.1:5432"));
+    let scenario = args.next().unwrap_or(String::from("scenario.toml"));
+
+    let listener = TcpListener::bind(&listen)?;
+    info!("Listening on {listen}, recording the traffic to {server} in {scenario}");
+
+    let mut recorder = Recorder::new();
+    let stats = QueryStats::new(Some(Duration::from_millis(100)));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                info!("accepted new connection");
+                let mut latency =
+                    ProxyLatency::new(stats.clone(), &stream.peer_addr()?.to_string());
+                let handler = TcpHandler::new(stream, TcpStream::connect(&server)?)?;
+                handler.relay(&mut |message| {
+                    latency.observe(&message);
+                    if let Err(e) = recorder.message(message) {
+                        error!("error: {}", e);
+                    }
+                })?;
+
+                // Saved after each connection since the recording ends with ^C
+                recorder.scenario.save(Path::new(&scenario))?;
+                info!("{} queries recorded", recorder.scenario.rules.len());
+                for query in stats.summary() {
+                    info!(
+                        "{} queries, p50 {:?}, p99 {:?}: {}",
+                        query.count, query.p50, query.p99, query.pattern
+                    );
+                }
+            }
+            Err(e) => {
+                error!("error: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
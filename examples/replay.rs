@@ -0,0 +1,48 @@
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread;
+use tracing::*;
+
+use fakepostmaster::handler::server::TcpHandler;
+use fakepostmaster::scenario::Scenario;
+
+// Answer the queries with the results of a scenario file, e.g. one written
+// by the record example:
+//   replay [listen address] [scenario file]
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .compact()
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let listen = args.next().unwrap_or(String::from("127.0.0.1:9092"));
+    let scenario = args.next().unwrap_or(String::from("scenario.toml"));
+
+    let executor = Scenario::load(Path::new(&scenario))?.executor()?;
+    let listener = TcpListener::bind(&listen)?;
+    info!("Listening on {listen}, replaying {scenario}");
+
+    // One thread per session, the clones of the executor share the schedule
+    // of the scenario
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                info!("accepted new connection");
+                let executor = executor.clone();
+                thread::spawn(move || -> anyhow::Result<()> {
+                    let mut handler = TcpHandler::new(stream)?;
+                    let _connection_parameters = handler.md5_authentication_handler(&|| true)?;
+
+                    while handler.query_handler(&|query| executor.execute(query))? {}
+                    info!("Session ended");
+                    Ok(())
+                });
+            }
+            Err(e) => {
+                error!("error: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,14 +1,11 @@
-use bytes::BytesMut;
-use std::{ffi::CString, net::TcpListener};
+use std::net::TcpListener;
+use std::thread;
 use tracing::*;
-use tracing_subscriber;
 
-use fakepostmaster::handler::server::TcpHandler;
-use fakepostmaster::message::{ColumnData, ColumnDescription, PgType};
-use libpq_serde_types::{
-    Serialize,
-    libpq_types::{Byte, Vec32},
-};
+use fakepostmaster::columns;
+use fakepostmaster::dual_stack;
+use fakepostmaster::handler::server::{QueryResult, TcpHandler};
+use fakepostmaster::value::PgValue;
 
 fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt()
@@ -16,8 +13,19 @@ fn main() -> anyhow::Result<()> {
         .compact()
         .init();
 
-    let listener = TcpListener::bind("192.168.121.1:9092").unwrap();
-    info!("Listening on 192.168.121.1:9092");
+    // Any interface, in IPv6 and IPv4
+    let threads: Vec<_> = dual_stack::bind("*:9092")?
+        .into_iter()
+        .map(|listener| thread::spawn(move || serve(listener)))
+        .collect();
+    for thread in threads {
+        thread.join().expect("listener thread")?;
+    }
+    Ok(())
+}
+
+fn serve(listener: TcpListener) -> anyhow::Result<()> {
+    info!("Listening on {}", listener.local_addr()?);
 
     for stream in listener.incoming() {
         match stream {
@@ -25,32 +33,26 @@ fn main() -> anyhow::Result<()> {
                 fn auth_func() -> bool {
                     true
                 }
-                fn executor(query: String) -> (Vec<ColumnDescription>, Vec<ColumnData>, String) {
-                    let mut row_description = Vec::new();
-                    row_description.push(
-                        ColumnDescription::new(&String::from("Custom Field"), PgType::Text)
-                            .expect("Dont care"),
-                    );
-                    let mut buffer = BytesMut::new();
-                    let col_data =
-                        CString::new(String::from("my data")).expect("No 0x00 in strings");
-                    col_data.serialize(&mut buffer);
-                    let col_data: Vec32<Byte> = buffer.to_vec().into();
-                    let row_data = vec![col_data];
+                fn executor(_query: String) -> QueryResult {
+                    let row_description = columns![("Custom Field", Text)].expect("Dont care");
+                    let row_data = vec![vec![PgValue::Text(String::from("my data"))]];
 
                     //let row_data = Vec::new();
                     let command_tag = String::from("SELECT 0");
 
-                    (row_description, row_data, command_tag)
+                    QueryResult {
+                        columns: row_description,
+                        rows: row_data,
+                        command_tag,
+                        error: None,
+                    }
                 }
 
                 info!("accepted new connection");
                 let mut handler = TcpHandler::new(stream)?;
                 let _connection_parameters = handler.md5_authentication_handler(&auth_func)?;
 
-                loop {
-                    handler.simple_query_handler(&executor)?;
-                }
+                while handler.query_handler(&executor)? {}
             }
             Err(e) => {
                 error!("error: {}", e);
@@ -0,0 +1,48 @@
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread;
+use tracing::*;
+
+use fakepostmaster::handler::tls::{GatewayRoute, TlsGateway, server_config};
+
+// Accept TLS from the frontends and relay their connections to a server
+// without TLS:
+//   tls_gateway [listen address] [certificate] [key] [server address]
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .compact()
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let listen = args.next().unwrap_or(String::from("127.0.0.1:9092"));
+    let cert = args.next().unwrap_or(String::from("server.pem"));
+    let key = args.next().unwrap_or(String::from("server.key"));
+    let server = args.next().unwrap_or(String::from("127.0.0.1:5432"));
+
+    let gateway = TlsGateway {
+        frontend_tls: Some(server_config(Path::new(&cert), Path::new(&key))?),
+        routes: vec![GatewayRoute {
+            server_name: None,
+            address: server,
+            tls: None,
+        }],
+    };
+
+    let listener = TcpListener::bind(&listen)?;
+    info!("Listening on {listen}");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let gateway = gateway.clone();
+                thread::spawn(move || {
+                    if let Err(e) = gateway.serve(stream) {
+                        error!("error: {e}");
+                    }
+                });
+            }
+            Err(e) => error!("error: {e}"),
+        }
+    }
+    Ok(())
+}
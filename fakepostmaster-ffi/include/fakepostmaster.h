@@ -0,0 +1,17 @@
+/* The C interface of fakepostmaster-ffi, see src/lib.rs for the JSON
+ * configuration. The functions that fail return NULL or -1, the reason is
+ * given by fakepostmaster_last_error(). */
+#ifndef FAKEPOSTMASTER_H
+#define FAKEPOSTMASTER_H
+
+#include <stdint.h>
+
+typedef struct TestServer TestServer;
+
+TestServer *start_server(const char *config_json);
+int32_t server_port(const TestServer *handle);
+int32_t set_scenario(const TestServer *handle, const char *scenario_json);
+int32_t stop_server(TestServer *handle);
+const char *fakepostmaster_last_error(void);
+
+#endif
@@ -0,0 +1,174 @@
+//! A C interface to start and stop fake servers from the test suites of
+//! other languages, see include/fakepostmaster.h.
+//!
+//! The configuration is JSON:
+//!
+//! ```json
+//! {"listen": "127.0.0.1:0", "scenario": {"rules": [...]}}
+//! ```
+//!
+//! The scenario has the fields of a scenario file, the functions that fail
+//! return NULL or -1 and fakepostmaster_last_error() tells why.
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::ptr;
+
+use fakepostmaster::scenario::Scenario;
+use fakepostmaster::test_server::TestServer;
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    #[serde(default = "default_listen")]
+    listen: String,
+    #[serde(default)]
+    scenario: Scenario,
+}
+
+fn default_listen() -> String {
+    String::from("127.0.0.1:0")
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(e: anyhow::Error) {
+    let message = CString::new(e.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+}
+
+/// # Safety
+/// `text` is NULL or a nul terminated string
+unsafe fn to_str<'a>(text: *const c_char) -> anyhow::Result<&'a str> {
+    if text.is_null() {
+        return Err(anyhow!("NULL string"));
+    }
+    Ok(unsafe { CStr::from_ptr(text) }.to_str()?)
+}
+
+/// Start a server, the handle is given to the other functions and freed by
+/// stop_server()
+///
+/// # Safety
+/// `config_json` is NULL or a nul terminated string
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn start_server(config_json: *const c_char) -> *mut TestServer {
+    let start = || -> anyhow::Result<TestServer> {
+        let config: Config = serde_json::from_str(unsafe { to_str(config_json) }?)?;
+        TestServer::start(&config.listen, &config.scenario)
+    };
+    match start() {
+        Ok(server) => Box::into_raw(Box::new(server)),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// The port the server listens on, useful with the port 0
+///
+/// # Safety
+/// `handle` was returned by start_server() and not stopped
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn server_port(handle: *const TestServer) -> i32 {
+    match unsafe { handle.as_ref() } {
+        Some(server) => i32::from(server.address().port()),
+        None => {
+            set_last_error(anyhow!("NULL handle"));
+            -1
+        }
+    }
+}
+
+/// Replace the scenario, given as JSON
+///
+/// # Safety
+/// `handle` was returned by start_server() and not stopped, `scenario_json`
+/// is NULL or a nul terminated string
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn set_scenario(
+    handle: *const TestServer,
+    scenario_json: *const c_char,
+) -> i32 {
+    let set = || -> anyhow::Result<()> {
+        let server = unsafe { handle.as_ref() }.ok_or(anyhow!("NULL handle"))?;
+        let scenario: Scenario = serde_json::from_str(unsafe { to_str(scenario_json) }?)?;
+        server.set_scenario(&scenario)
+    };
+    match set() {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Stop accepting connections and free the handle
+///
+/// # Safety
+/// `handle` was returned by start_server() and not stopped
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stop_server(handle: *mut TestServer) -> i32 {
+    if handle.is_null() {
+        set_last_error(anyhow!("NULL handle"));
+        return -1;
+    }
+    match unsafe { Box::from_raw(handle) }.stop() {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// The error of the last function that failed on this thread, valid until
+/// the next failure
+#[unsafe(no_mangle)]
+pub extern "C" fn fakepostmaster_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fakepostmaster::handler::client;
+    use std::net::TcpStream;
+
+    #[test]
+    fn server_lifecycle() -> anyhow::Result<()> {
+        let config = CString::new(
+            r#"{"scenario": {"rules": [{
+                "query": "SELECT n FROM t",
+                "command_tag": "SELECT 1",
+                "columns": [{"name": "n", "type_oid": 23}],
+                "rows": [["1"]]
+            }]}}"#,
+        )?;
+        let handle = unsafe { start_server(config.as_ptr()) };
+        assert!(!handle.is_null());
+
+        let port = u16::try_from(unsafe { server_port(handle) })?;
+        let mut client = client::TcpHandler::new(TcpStream::connect(("127.0.0.1", port))?)?;
+        client.md5_authentication_handler()?;
+        assert_eq!(1, client.simple_query_handler("SELECT n FROM t")?.len());
+
+        let scenario = CString::new(r#"{"rules": []}"#)?;
+        assert_eq!(0, unsafe { set_scenario(handle, scenario.as_ptr()) });
+        assert!(client.simple_query_handler("SELECT n FROM t")?.is_empty());
+
+        let invalid = CString::new("{")?;
+        assert_eq!(-1, unsafe { set_scenario(handle, invalid.as_ptr()) });
+        let error = unsafe { CStr::from_ptr(fakepostmaster_last_error()) };
+        assert!(error.to_str()?.starts_with("EOF while parsing"));
+
+        assert_eq!(0, unsafe { stop_server(handle) });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! The Python module, built with maturin:
+//!
+//! ```python
+//! import fakepostmaster
+//!
+//! scenario = fakepostmaster.Scenario.load("scenario.toml")
+//! with fakepostmaster.TestServer(scenario) as server:
+//!     psycopg.connect(
+//!         host=server.host, port=server.port, user="u", password="p", sslmode="disable"
+//!     )
+//! ```
+//!
+//! The expectations are rules added to a scenario, checked once the code
+//! under test ran:
+//!
+//! ```python
+//! rule = fakepostmaster.Rule(
+//!     "SELECT name FROM users WHERE id = $1",
+//!     match="normalized",
+//!     command_tag="SELECT 1",
+//!     columns=[("name", 25)],
+//!     rows=[["alice"]],
+//! )
+//! scenario = fakepostmaster.Scenario()
+//! scenario.add_rule(rule)
+//! with fakepostmaster.TestServer(scenario) as server:
+//!     ...
+//!     server.verify(rule, times=1)
+//! ```
+//!
+//! The tests in tests/ run on the module built by `maturin develop`.
+
+use anyhow::anyhow;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::exceptions::{PyAssertionError, PyValueError};
+use pyo3::prelude::*;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
+
+use fakepostmaster_rs::error::PgError;
+use fakepostmaster_rs::events::ServerEvent;
+use fakepostmaster_rs::scenario::{self, Cell, Column, MatchKind};
+use fakepostmaster_rs::test_server;
+
+// Without the backtrace that the anyhow feature of pyo3 adds
+fn error(e: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{e:#}"))
+}
+
+/// A query and its result or its error, the values are in text format and
+/// None is NULL
+#[pyclass]
+#[derive(Clone)]
+struct Rule {
+    rule: scenario::Rule,
+}
+
+#[pymethods]
+impl Rule {
+    #[new]
+    #[pyo3(signature = (
+        query,
+        r#match="exact",
+        command_tag="",
+        columns=Vec::new(),
+        rows=Vec::new(),
+        error=None,
+    ))]
+    fn new(
+        query: &str,
+        r#match: &str,
+        command_tag: &str,
+        columns: Vec<(String, i32)>,
+        rows: Vec<Vec<Option<String>>>,
+        error: Option<(String, String)>,
+    ) -> PyResult<Self> {
+        let match_kind = match r#match {
+            "exact" => MatchKind::Exact,
+            "normalized" => MatchKind::Normalized,
+            "regex" => MatchKind::Regex,
+            _ => return Err(PyValueError::new_err(format!("Unknown match: {match}"))),
+        };
+        let rule = scenario::Rule {
+            query: String::from(query),
+            match_kind,
+            command_tag: String::from(command_tag),
+            columns: columns
+                .into_iter()
+                .map(|(name, type_oid)| Column {
+                    name,
+                    type_oid,
+                    type_len: -1,
+                    type_mod: -1,
+                })
+                .collect(),
+            rows: rows
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|value| value.map_or_else(Cell::null, Cell::from))
+                        .collect()
+                })
+                .collect(),
+            steps: Vec::new(),
+            error: error.map(|(code, message)| PgError::new(&code, &message)),
+        };
+        // Fail here rather than at the first query, e.g. on a bad regex
+        rule.matcher().map_err(crate::error)?;
+        Ok(Self { rule })
+    }
+
+    #[getter]
+    fn query(&self) -> String {
+        self.rule.query.clone()
+    }
+}
+
+/// The rules answering the queries, see the scenario files
+#[pyclass]
+#[derive(Clone, Default)]
+struct Scenario {
+    scenario: scenario::Scenario,
+}
+
+#[pymethods]
+impl Scenario {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    #[staticmethod]
+    fn from_toml(content: &str) -> PyResult<Self> {
+        Ok(Self {
+            scenario: scenario::Scenario::from_toml(content).map_err(error)?,
+        })
+    }
+
+    #[staticmethod]
+    fn load(path: &str) -> PyResult<Self> {
+        Ok(Self {
+            scenario: scenario::Scenario::load(Path::new(path)).map_err(error)?,
+        })
+    }
+
+    fn to_toml(&self) -> PyResult<String> {
+        self.scenario.to_toml().map_err(error)
+    }
+
+    /// The rules are tried in order, the first one matching answers
+    fn add_rule(&mut self, rule: &Rule) {
+        self.scenario.rules.push(rule.rule.clone());
+    }
+
+    #[getter]
+    fn rules(&self) -> Vec<Rule> {
+        self.scenario
+            .rules
+            .iter()
+            .map(|rule| Rule { rule: rule.clone() })
+            .collect()
+    }
+
+    fn __len__(&self) -> usize {
+        self.scenario.rules.len()
+    }
+}
+
+/// A fake server running in the background until stop() or the end of the
+/// with block
+#[pyclass]
+struct TestServer {
+    server: Option<test_server::TestServer>,
+    // Subscribed at the start, so that no query is missed
+    events: Mutex<Receiver<ServerEvent>>,
+    received: Mutex<Vec<String>>,
+}
+
+impl TestServer {
+    fn server(&self) -> PyResult<&test_server::TestServer> {
+        self.server
+            .as_ref()
+            .ok_or(error(anyhow!("The server is stopped")))
+    }
+}
+
+#[pymethods]
+impl TestServer {
+    #[new]
+    #[pyo3(signature = (scenario=None, listen="127.0.0.1:0"))]
+    fn new(scenario: Option<Scenario>, listen: &str) -> PyResult<Self> {
+        let scenario = scenario.unwrap_or_default();
+        let server = test_server::TestServer::start(listen, &scenario.scenario).map_err(error)?;
+        Ok(Self {
+            events: Mutex::new(server.events()),
+            server: Some(server),
+            received: Mutex::new(Vec::new()),
+        })
+    }
+
+    #[getter]
+    fn host(&self) -> PyResult<String> {
+        Ok(self.server()?.address().ip().to_string())
+    }
+
+    #[getter]
+    fn port(&self) -> PyResult<u16> {
+        Ok(self.server()?.address().port())
+    }
+
+    fn set_scenario(&self, scenario: &Scenario) -> PyResult<()> {
+        self.server()?
+            .set_scenario(&scenario.scenario)
+            .map_err(error)
+    }
+
+    /// The queries received by all the sessions, in order. A query is
+    /// received before it is answered.
+    fn received(&self) -> PyResult<Vec<String>> {
+        let events = self
+            .events
+            .lock()
+            .map_err(|_| error(anyhow!("events lock")))?;
+        let mut received = self
+            .received
+            .lock()
+            .map_err(|_| error(anyhow!("received lock")))?;
+        received.extend(events.try_iter().filter_map(|event| match event {
+            ServerEvent::Query { query, .. } => Some(query),
+            _ => None,
+        }));
+        Ok(received.clone())
+    }
+
+    /// How many queries received the rule matches
+    fn calls(&self, rule: &Rule) -> PyResult<usize> {
+        let matcher = rule.rule.matcher().map_err(error)?;
+        let received = self.received()?;
+        Ok(received
+            .iter()
+            .filter(|query| matcher.matches(query))
+            .count())
+    }
+
+    /// Raise AssertionError unless the rule matched `times` queries, or at
+    /// least one when None
+    #[pyo3(signature = (rule, times=None))]
+    fn verify(&self, rule: &Rule, times: Option<usize>) -> PyResult<()> {
+        let calls = self.calls(rule)?;
+        let expected = match times {
+            Some(times) if calls != times => format!("{times} times"),
+            None if calls == 0 => String::from("at least once"),
+            _ => return Ok(()),
+        };
+        Err(PyAssertionError::new_err(format!(
+            "{} expected {expected}, matched {calls} times in {:?}",
+            rule.rule.query,
+            self.received()?
+        )))
+    }
+
+    fn stop(&mut self) -> PyResult<()> {
+        match self.server.take() {
+            Some(server) => server.stop().map_err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        _exc_type: Py<PyAny>,
+        _exc_value: Py<PyAny>,
+        _traceback: Py<PyAny>,
+    ) -> PyResult<bool> {
+        self.stop()?;
+        Ok(false)
+    }
+}
+
+#[pymodule]
+fn fakepostmaster(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Rule>()?;
+    m.add_class::<Scenario>()?;
+    m.add_class::<TestServer>()?;
+    Ok(())
+}
@@ -0,0 +1,107 @@
+"""The expectations of the module, run after `maturin develop` with:
+
+    python -m unittest discover -s tests
+
+The queries are sent with the simple query protocol on a socket, so that
+the tests need no driver.
+"""
+
+import socket
+import struct
+import unittest
+
+import fakepostmaster
+
+
+def message(kind, payload):
+    return kind + struct.pack("!i", len(payload) + 4) + payload
+
+
+def read_message(sock):
+    header = sock.recv(5, socket.MSG_WAITALL)
+    kind, length = header[:1], struct.unpack("!i", header[1:])[0]
+    return kind, sock.recv(length - 4, socket.MSG_WAITALL) if length > 4 else b""
+
+
+def read_until_ready(sock):
+    """The messages before ReadyForQuery"""
+    messages = []
+    while True:
+        kind, payload = read_message(sock)
+        if kind == b"Z":
+            return messages
+        messages.append((kind, payload))
+
+
+def connect(server):
+    sock = socket.create_connection((server.host, server.port))
+    parameters = b"user\0test\0database\0test\0\0"
+    sock.sendall(struct.pack("!ii", len(parameters) + 8, 196608) + parameters)
+    # The server asks for an MD5 password and accepts any by default
+    kind, _ = read_message(sock)
+    assert kind == b"R"
+    sock.sendall(message(b"p", b"md5" + b"0" * 32 + b"\0"))
+    read_until_ready(sock)
+    return sock
+
+
+def query(sock, text):
+    sock.sendall(message(b"Q", text.encode() + b"\0"))
+    return read_until_ready(sock)
+
+
+class ExpectationTest(unittest.TestCase):
+    def test_rule_answers_and_is_verified(self):
+        rule = fakepostmaster.Rule(
+            "SELECT name FROM users WHERE id = $1",
+            match="normalized",
+            command_tag="SELECT 1",
+            columns=[("name", 25)],
+            rows=[["alice"]],
+        )
+        scenario = fakepostmaster.Scenario()
+        scenario.add_rule(rule)
+        self.assertEqual(1, len(scenario))
+        self.assertEqual(rule.query, scenario.rules[0].query)
+
+        with fakepostmaster.TestServer(scenario) as server:
+            sock = connect(server)
+            messages = query(sock, "SELECT name FROM users WHERE id = 42")
+            self.assertIn((b"D", b"\0\x01\0\0\0\x05alice"), messages)
+            self.assertIn((b"C", b"SELECT 1\0"), messages)
+            sock.close()
+
+            self.assertEqual(
+                ["SELECT name FROM users WHERE id = 42"], server.received()
+            )
+            self.assertEqual(1, server.calls(rule))
+            server.verify(rule)
+            server.verify(rule, times=1)
+            with self.assertRaises(AssertionError):
+                server.verify(rule, times=2)
+
+    def test_error_rule(self):
+        rule = fakepostmaster.Rule(
+            "DELETE FROM users", error=("42501", "permission denied")
+        )
+        scenario = fakepostmaster.Scenario()
+        scenario.add_rule(rule)
+        with fakepostmaster.TestServer(scenario) as server:
+            with self.assertRaises(AssertionError):
+                server.verify(rule)
+            sock = connect(server)
+            messages = query(sock, "DELETE FROM users")
+            self.assertEqual(b"E", messages[0][0])
+            self.assertIn(b"C42501\0", messages[0][1])
+            sock.close()
+            server.verify(rule, times=1)
+
+    def test_bad_rules_are_refused(self):
+        with self.assertRaises(ValueError):
+            fakepostmaster.Rule("SELECT 1", match="prefix")
+        with self.assertRaises(RuntimeError):
+            fakepostmaster.Rule("(", match="regex")
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -127,8 +127,8 @@ fn serde_libpq_data_derive_macro2(
 
         Ok(quote! {
             impl ByteSized for #ident {
-                fn byte_size(&self) -> i32 {
-                    0 #(+ #fields_size)*
+                fn byte_size(&self) -> usize {
+                    0_usize #(.saturating_add(#fields_size))*
                 }
             }
 
@@ -223,7 +223,10 @@ fn try_from_raw_backend_message_derive_macro2(
         // define impl variables
         let ident = &ast.ident;
 
+        let unknown = format!("{ident}: '{kind}' is not a BackendMessageKind");
         Ok(quote! {
+            const _: () = assert!(BackendMessageKind::is_known(#kind as u8), #unknown);
+
             impl TryFrom<&mut RawBackendMessage> for #ident {
                 type Error = anyhow::Error;
 
@@ -269,7 +272,10 @@ fn try_from_raw_frontend_message_derive_macro2(
         // define impl variables
         let ident = &ast.ident;
 
+        let unknown = format!("{ident}: '{kind}' is not a FrontendMessageKind");
         Ok(quote! {
+            const _: () = assert!(FrontendMessageKind::is_known(#kind as u8), #unknown);
+
             impl TryFrom<&mut RawFrontendMessage> for #ident {
                 type Error = anyhow::Error;
 
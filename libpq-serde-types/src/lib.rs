@@ -1,3 +1,9 @@
+//! Without the std feature (enabled by default) the crate only needs
+//! alloc.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 use bytes::{Buf, Bytes, BytesMut};
 
 pub mod libpq_types;
@@ -13,8 +19,10 @@ pub trait Deserialize {
         Bytes: Buf;
 }
 
+/// The size of the serialized data, it can be larger than what the 32-bit
+/// length of a message can tell: the writers check it
 pub trait ByteSized {
-    fn byte_size(&self) -> i32;
+    fn byte_size(&self) -> usize;
 }
 
 #[cfg(test)]
@@ -86,7 +94,7 @@ mod tests {
         let b = example_struct();
         let m = example_from_serialize();
 
-        assert_eq!(b.byte_size(), m.len() as i32);
+        assert_eq!(b.byte_size(), m.len());
 
         Ok(())
     }
@@ -96,7 +104,7 @@ mod tests {
         let b = example_struct();
         let m = example_from_serialize();
 
-        assert_eq!(b.byte_size(), m.len() as i32);
+        assert_eq!(b.byte_size(), m.len());
 
         Ok(())
     }
@@ -1,9 +1,15 @@
+use alloc::ffi::CString;
+use alloc::vec::Vec;
 use anyhow::anyhow;
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::ffi::CString;
+use bytes::{Buf, BufMut, Bytes, BytesMut, TryGetError};
 
 use crate::{ByteSized, Deserialize, Serialize};
 
+// TryGetError only implements Error with the std feature of bytes
+fn truncated(e: TryGetError) -> anyhow::Error {
+    anyhow!("{e}")
+}
+
 // the list of types can be found here:
 // https://www.postgresql.org/docs/17/protocol-message-types.html
 
@@ -20,12 +26,12 @@ impl Deserialize for i8 {
         Self: Sized,
         Bytes: Buf,
     {
-        buffer.try_get_i8().map_err(|e| e.into())
+        buffer.try_get_i8().map_err(truncated)
     }
 }
 
 impl ByteSized for i8 {
-    fn byte_size(&self) -> i32 {
+    fn byte_size(&self) -> usize {
         1
     }
 }
@@ -43,12 +49,12 @@ impl Deserialize for i16 {
         Self: Sized,
         Bytes: Buf,
     {
-        buffer.try_get_i16().map_err(|e| e.into())
+        buffer.try_get_i16().map_err(truncated)
     }
 }
 
 impl ByteSized for i16 {
-    fn byte_size(&self) -> i32 {
+    fn byte_size(&self) -> usize {
         2
     }
 }
@@ -66,12 +72,12 @@ impl Deserialize for i32 {
         Self: Sized,
         Bytes: Buf,
     {
-        buffer.try_get_i32().map_err(|e| e.into())
+        buffer.try_get_i32().map_err(truncated)
     }
 }
 
 impl ByteSized for i32 {
-    fn byte_size(&self) -> i32 {
+    fn byte_size(&self) -> usize {
         4
     }
 }
@@ -91,12 +97,12 @@ impl Deserialize for Byte {
         Self: Sized,
         Bytes: Buf,
     {
-        buffer.try_get_u8().map_err(|e| e.into())
+        buffer.try_get_u8().map_err(truncated)
     }
 }
 
 impl ByteSized for Byte {
-    fn byte_size(&self) -> i32 {
+    fn byte_size(&self) -> usize {
         1
     }
 }
@@ -118,13 +124,13 @@ impl Deserialize for Byte4 {
         Bytes: Buf,
     {
         let mut t = [0_u8; 4];
-        buffer.try_copy_to_slice(&mut t)?;
+        buffer.try_copy_to_slice(&mut t).map_err(truncated)?;
         Ok(t)
     }
 }
 
 impl ByteSized for Byte4 {
-    fn byte_size(&self) -> i32 {
+    fn byte_size(&self) -> usize {
         4
     }
 }
@@ -144,11 +150,11 @@ impl Deserialize for CString {
         Bytes: Buf,
     {
         let mut v = Vec::new();
-        let mut c: u8 = buffer.try_get_u8()?;
+        let mut c: u8 = buffer.try_get_u8().map_err(truncated)?;
 
         while c != 0_u8 {
             v.push(c);
-            c = buffer.try_get_u8()?;
+            c = buffer.try_get_u8().map_err(truncated)?;
         }
 
         // This operation is safe because we stopped copying data when
@@ -159,8 +165,8 @@ impl Deserialize for CString {
 }
 
 impl ByteSized for CString {
-    fn byte_size(&self) -> i32 {
-        self.count_bytes() as i32 + 1
+    fn byte_size(&self) -> usize {
+        self.count_bytes() + 1
     }
 }
 
@@ -198,8 +204,11 @@ where
     T: Serialize,
 {
     fn serialize(&self, buffer: &mut BytesMut) {
-        // length
-        (self.0.len() as i16).serialize(buffer);
+        // length, a truncated count would desync the stream: the callers
+        // check the limits of the protocol before building the message
+        i16::try_from(self.0.len())
+            .expect("Vec16 with more than i16::MAX elements")
+            .serialize(buffer);
         // data
         for elt in &self.0 {
             elt.serialize(buffer);
@@ -217,7 +226,7 @@ where
         Bytes: Buf,
     {
         let mut v = Self::new();
-        let len = buffer.try_get_i16()?;
+        let len = buffer.try_get_i16().map_err(truncated)?;
         for _ in 0..len {
             v.0.push(T::deserialize(buffer)?);
         }
@@ -229,12 +238,12 @@ impl<T> ByteSized for Vec16<T>
 where
     T: ByteSized,
 {
-    fn byte_size(&self) -> i32 {
-        let mut size = 2;
-        for elt in &self.0 {
-            size += elt.byte_size();
-        }
-        size
+    fn byte_size(&self) -> usize {
+        // Saturated, the sizes over the 32-bit length of a message are
+        // refused when the message is written
+        self.0
+            .iter()
+            .fold(2, |size: usize, elt| size.saturating_add(elt.byte_size()))
     }
 }
 
@@ -273,8 +282,10 @@ where
     T: Serialize,
 {
     fn serialize(&self, buffer: &mut BytesMut) {
-        // length
-        (self.0.len() as i32).serialize(buffer);
+        // length, see Vec16
+        i32::try_from(self.0.len())
+            .expect("Vec32 with more than i32::MAX elements")
+            .serialize(buffer);
         // data
         for elt in &self.0 {
             elt.serialize(buffer);
@@ -292,7 +303,7 @@ where
         Bytes: Buf,
     {
         let mut v = Self::new();
-        let len = buffer.try_get_i32()?;
+        let len = buffer.try_get_i32().map_err(truncated)?;
         for _ in 0..len {
             v.0.push(T::deserialize(buffer)?);
         }
@@ -304,12 +315,102 @@ impl<T> ByteSized for Vec32<T>
 where
     T: ByteSized,
 {
-    fn byte_size(&self) -> i32 {
-        let mut size = 4;
-        for elt in &self.0 {
-            size += elt.byte_size();
+    fn byte_size(&self) -> usize {
+        self.0
+            .iter()
+            .fold(4, |size: usize, elt| size.saturating_add(elt.byte_size()))
+    }
+}
+//--------------------------------------------------------------------------------
+/// Bytes prefixed by their length on 32 bits, or the length -1 alone for
+/// NULL: the values of the columns, of the parameters and of the results
+/// of the function calls
+#[derive(Debug, Clone, PartialEq)]
+pub struct NullableBytes(Option<Vec<u8>>);
+
+impl NullableBytes {
+    /// An empty value, not NULL
+    pub fn new() -> Self {
+        Self(Some(Vec::new()))
+    }
+
+    pub fn null() -> Self {
+        Self(None)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// `None` for NULL
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        self.0.as_deref()
+    }
+
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        self.0
+    }
+}
+
+impl Default for NullableBytes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Vec<u8>> for NullableBytes {
+    fn from(item: Vec<u8>) -> NullableBytes {
+        NullableBytes(Some(item))
+    }
+}
+
+impl From<Option<Vec<u8>>> for NullableBytes {
+    fn from(item: Option<Vec<u8>>) -> NullableBytes {
+        NullableBytes(item)
+    }
+}
+
+impl Serialize for NullableBytes {
+    fn serialize(&self, buffer: &mut BytesMut) {
+        match &self.0 {
+            None => buffer.put_i32(-1),
+            Some(bytes) => {
+                // length, see Vec16
+                buffer.put_i32(
+                    i32::try_from(bytes.len()).expect("Value of more than i32::MAX bytes"),
+                );
+                buffer.put_slice(bytes);
+            }
         }
-        size
+    }
+}
+
+impl Deserialize for NullableBytes {
+    fn deserialize(buffer: &mut Bytes) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+        Bytes: Buf,
+    {
+        let len = buffer.try_get_i32().map_err(truncated)?;
+        if len == -1 {
+            return Ok(Self::null());
+        }
+        let len = usize::try_from(len).map_err(|_| anyhow!("Invalid value length: {len}"))?;
+        if buffer.remaining() < len {
+            return Err(anyhow!(
+                "Value of {len} bytes truncated to {}",
+                buffer.remaining()
+            ));
+        }
+        Ok(Self::from(buffer.split_to(len).to_vec()))
+    }
+}
+
+impl ByteSized for NullableBytes {
+    fn byte_size(&self) -> usize {
+        self.0
+            .as_ref()
+            .map_or(4, |bytes| bytes.len().saturating_add(4))
     }
 }
 //--------------------------------------------------------------------------------
@@ -375,7 +476,7 @@ where
         let mut v = Self::new();
         loop {
             if buffer.len() == 1 {
-                if let 0 = buffer.try_get_u8()? {
+                if let 0 = buffer.try_get_u8().map_err(truncated)? {
                     return Ok(v);
                 } else {
                     return Err(anyhow!("Incorrect terminator in null terminated vec"));
@@ -393,12 +494,86 @@ impl<T> ByteSized for VecNull<T>
 where
     T: ByteSized,
 {
-    fn byte_size(&self) -> i32 {
-        let mut size = 1;
+    fn byte_size(&self) -> usize {
+        self.0
+            .iter()
+            .fold(1, |size: usize, elt| size.saturating_add(elt.byte_size()))
+    }
+}
+
+//--------------------------------------------------------------------------------
+/// An array that occupies the rest of the buffer, without a count or a
+/// terminator (Byten in the documentation).
+#[derive(Debug, PartialEq)]
+pub struct VecEnd<T>(Vec<T>);
+
+impl<T> VecEnd<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+// Without the T: Default bound of the derive
+impl<T> Default for VecEnd<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T> From<Vec<T>> for VecEnd<T> {
+    fn from(item: Vec<T>) -> VecEnd<T> {
+        VecEnd(item)
+    }
+}
+
+impl<T> From<VecEnd<T>> for Vec<T> {
+    fn from(item: VecEnd<T>) -> Vec<T> {
+        item.0
+    }
+}
+
+impl<T> AsRef<Vec<T>> for VecEnd<T> {
+    fn as_ref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> Serialize for VecEnd<T>
+where
+    T: Serialize,
+{
+    fn serialize(&self, buffer: &mut BytesMut) {
         for elt in &self.0 {
-            size += elt.byte_size();
+            elt.serialize(buffer);
+        }
+    }
+}
+
+impl<T> Deserialize for VecEnd<T>
+where
+    T: Deserialize,
+{
+    fn deserialize(buffer: &mut Bytes) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+        Bytes: Buf,
+    {
+        let mut v = Self::new();
+        while !buffer.is_empty() {
+            v.0.push(T::deserialize(buffer)?);
         }
-        size
+        Ok(v)
+    }
+}
+
+impl<T> ByteSized for VecEnd<T>
+where
+    T: ByteSized,
+{
+    fn byte_size(&self) -> usize {
+        self.0
+            .iter()
+            .fold(0, |size: usize, elt| size.saturating_add(elt.byte_size()))
     }
 }
 
@@ -407,6 +582,7 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
+    use anyhow::Result;
 
     //----------------------------------------------------------------------------
     #[test]
@@ -738,6 +914,14 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[should_panic(expected = "Vec16 with more than i16::MAX elements")]
+    fn vec16_overflow_serialize() {
+        let mut m = BytesMut::new();
+        let v: Vec16<Byte> = Vec16::from(vec![0; i16::MAX as usize + 1]);
+        v.serialize(&mut m);
+    }
+
     #[test]
     fn vec16_i32_deserialize() -> Result<()> {
         let mut buffer = Bytes::from_static(&[
@@ -946,4 +1130,53 @@ mod test {
         assert_eq!(1, VecNull::<CString>::from(vec![]).byte_size());
         Ok(())
     }
+
+    //----------------------------------------------------------------------------
+    #[test]
+    fn vecend_byte_serialize() -> Result<()> {
+        let mut m = BytesMut::new();
+        let v: VecEnd<Byte> = VecEnd::from(vec![1, 2, 3, 4, 5]);
+        v.serialize(&mut m);
+        assert_eq!(vec![0x01, 0x02, 0x03, 0x04, 0x05], m.to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn vecend_byte_deserialize() -> Result<()> {
+        let mut buffer = Bytes::from_static(&[0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert_eq!(
+            VecEnd::<Byte>::from(vec![1, 2, 3, 4, 5]),
+            VecEnd::<Byte>::deserialize(&mut buffer)?
+        );
+        assert!(buffer.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn vecend_byte_byte_size() -> Result<()> {
+        assert_eq!(5, VecEnd::<Byte>::from(vec![1, 2, 3, 4, 5]).byte_size());
+        assert_eq!(0, VecEnd::<Byte>::new().byte_size());
+        Ok(())
+    }
+
+    //----------------------------------------------------------------------------
+    #[test]
+    fn nullable_bytes_serde() -> Result<()> {
+        for (value, encoded) in [
+            (NullableBytes::from(b"ab".to_vec()), &[0, 0, 0, 2, b'a', b'b'][..]),
+            (NullableBytes::new(), &[0, 0, 0, 0][..]),
+            (NullableBytes::null(), &[0xff, 0xff, 0xff, 0xff][..]),
+        ] {
+            let mut m = BytesMut::new();
+            value.serialize(&mut m);
+            assert_eq!(encoded, &m[..]);
+            assert_eq!(encoded.len(), value.byte_size());
+            assert_eq!(value, NullableBytes::deserialize(&mut m.freeze())?);
+        }
+        assert!(NullableBytes::deserialize(&mut Bytes::from_static(&[0, 0, 0, 3, 1])).is_err());
+        assert!(NullableBytes::deserialize(&mut Bytes::from_static(&[0xff, 0xff, 0xff, 0xfe])).is_err());
+
+        Ok(())
+    }
 }
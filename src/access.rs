@@ -0,0 +1,142 @@
+//! The addresses allowed to connect, checked when a connection is accepted
+//! before anything is read, like the host rules of pg_hba.conf.
+
+use anyhow::anyhow;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::error::PgError;
+
+/// A network in the CIDR notation, e.g. "10.0.0.0/8" or "::1/128". A bare
+/// address is the network of this address only.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        // IPv4 clients of an IPv6 listener are seen as ::ffff:a.b.c.d
+        let (network, address) = match (self.address.to_canonical(), address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                (u32::from(network) as u128, u32::from(address) as u128)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                (u128::from(network), u128::from(address))
+            }
+            _ => return false,
+        };
+        let bits = match self.address.to_canonical() {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let shift = bits - u32::from(self.prefix);
+        shift == bits || network >> shift == address >> shift
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Invalid address \"{address}\""))?;
+        let bits = match address.to_canonical() {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or(anyhow!("Invalid prefix length \"{prefix}\""))?,
+            None => bits,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// The networks denied, and the ones allowed when the list is not empty.
+/// A network in both lists is denied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Why the address can't connect, with the messages of pg_hba.conf
+    pub fn check(&self, address: &IpAddr) -> Result<(), PgError> {
+        if self.deny.iter().any(|network| network.contains(address)) {
+            return Err(PgError::new(
+                "28000",
+                &format!("pg_hba.conf rejects connection for host \"{address}\""),
+            ));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|network| network.contains(address)) {
+            return Err(PgError::new(
+                "28000",
+                &format!("no pg_hba.conf entry for host \"{address}\""),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn access_list() -> anyhow::Result<()> {
+        let cidr = |value: &str| value.parse::<Cidr>();
+        let ip = |value: &str| value.parse::<IpAddr>();
+        assert!(cidr("10.0.0.0/8")?.contains(&ip("10.1.2.3")?));
+        assert!(!cidr("10.0.0.0/8")?.contains(&ip("11.0.0.1")?));
+        assert!(cidr("0.0.0.0/0")?.contains(&ip("::ffff:192.168.1.1")?));
+        assert!(cidr("fd00::/8")?.contains(&ip("fd12::1")?));
+        assert!(!cidr("::1")?.contains(&ip("127.0.0.1")?));
+        assert!(cidr("10.0.0.0/33").is_err());
+        assert!(cidr("localhost").is_err());
+
+        let list = AccessList {
+            allow: vec![cidr("10.0.0.0/8")?, cidr("127.0.0.1")?],
+            deny: vec![cidr("10.0.0.0/24")?],
+        };
+        assert!(list.check(&ip("127.0.0.1")?).is_ok());
+        assert!(list.check(&ip("10.1.0.1")?).is_ok());
+        let message = |address: &str| -> anyhow::Result<String> {
+            Ok(list
+                .check(&ip(address)?)
+                .err()
+                .map(|e| e.message)
+                .unwrap_or_default())
+        };
+        assert_eq!(
+            "pg_hba.conf rejects connection for host \"10.0.0.1\"",
+            message("10.0.0.1")?
+        );
+        assert_eq!(
+            "no pg_hba.conf entry for host \"192.168.0.1\"",
+            message("192.168.0.1")?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+//! Scrub a capture of production traffic before sharing it: the passwords,
+//! the SCRAM nonces and proofs, the literals of the queries and the data
+//! (rows, parameters, COPY, notifications, details of the errors) are
+//! replaced by placeholders. The messages keep their kind, their fields and
+//! the length of the values, so that the capture can still be replayed and
+//! diffed.
+
+use bytes::{BufMut, BytesMut};
+use libpq_serde_types::{ByteSized, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::BufReader;
+
+use crate::capture::{Capture, CapturedMessage};
+use crate::matcher::redact_literals;
+use crate::message::*;
+use crate::validator::Direction;
+
+/// A value of the same length: zeros for the numbers and the binary values,
+/// 'x' for the text
+fn placeholder(value: &[u8]) -> Vec<u8> {
+    let fill = match std::str::from_utf8(value) {
+        Ok(text)
+            if text
+                .chars()
+                .all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) =>
+        {
+            b'0'
+        }
+        Ok(text) if !text.chars().any(char::is_control) => b'x',
+        _ => 0,
+    };
+    vec![fill; value.len()]
+}
+
+/// The text of COPY with its separators, tabs, newlines and escapes, and
+/// 'x' for everything else
+fn copy_placeholder(data: &[u8]) -> Vec<u8> {
+    let mut escaped = false;
+    data.iter()
+        .map(|byte| {
+            let kept = escaped || matches!(byte, b'\t' | b'\n' | b'\\');
+            escaped = !escaped && *byte == b'\\';
+            if kept { *byte } else { b'x' }
+        })
+        .collect()
+}
+
+/// The attributes of a SCRAM message with the nonce, the salt, the proof
+/// and the signature replaced, e.g. "n,,n=,r=AAAA"
+fn scram_placeholder(data: &[u8]) -> Vec<u8> {
+    let mut attributes = Vec::new();
+    for attribute in data.split(|byte| *byte == b',') {
+        match attribute {
+            [b'r' | b's' | b'p' | b'v', b'=', value @ ..] => {
+                let mut masked = attribute[..2].to_vec();
+                masked.extend(value.iter().map(|byte| match byte {
+                    // The padding of base64
+                    b'=' => b'=',
+                    _ => b'A',
+                }));
+                attributes.push(masked);
+            }
+            _ => attributes.push(attribute.to_vec()),
+        }
+    }
+    attributes.join(&b","[..])
+}
+
+/// A value of the same length or NULL, see placeholder
+fn nullable(value: &ColumnData) -> ColumnData {
+    value.as_bytes().map(placeholder).into()
+}
+
+/// A string of the same length made of 'x'
+fn masked(value: &CString) -> anyhow::Result<CString> {
+    Ok(CString::new(vec![b'x'; value.as_bytes().len()])?)
+}
+
+fn redacted(query: &CString) -> anyhow::Result<CString> {
+    Ok(CString::new(redact_literals(&query.to_string_lossy()))?)
+}
+
+/// The details of the errors and the notices masked
+fn masked_details(messages: &mut [ErrorMessage]) -> anyhow::Result<()> {
+    for message in messages.iter_mut().filter(|message| message.code == b'D') {
+        message.message = masked(&message.message)?;
+    }
+    Ok(())
+}
+
+/// The frame of a scrubbed message
+fn frame<T>(body: &T) -> anyhow::Result<Option<BytesMut>>
+where
+    T: MessageBody + Serialize + ByteSized,
+{
+    let mut frame = BytesMut::new();
+    MessageHeader::serialize_message(&mut frame, body)?;
+    Ok(Some(frame))
+}
+
+/// Scrubs the messages of a capture in order, the authentication requested
+/// by the backend tells what the next password message is
+#[derive(Debug, Default)]
+pub struct Anonymizer {
+    // The last authentication request of each session
+    authentication: HashMap<u32, AuthenticationMessageKind>,
+}
+
+impl Anonymizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The message with its sensitive fields replaced, as is when it is not
+    /// a typed message or when it can't be decoded
+    pub fn message(&mut self, message: &CapturedMessage) -> CapturedMessage {
+        match self.scrub(message) {
+            Ok(Some(bytes)) => CapturedMessage {
+                session: message.session,
+                sender: message.sender,
+                bytes,
+            },
+            Ok(None) | Err(_) => message.clone(),
+        }
+    }
+
+    /// The frame deserialized into its typed message, changed and
+    /// serialized again, None when nothing is replaced
+    fn scrub(&mut self, message: &CapturedMessage) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut reader = BufReader::new(&message.bytes[..]);
+        let (frame, left) = match (message.sender, message.bytes.first()) {
+            // The untyped requests, starting with their length
+            (Direction::Frontend, Some(0)) | (_, None) => return Ok(None),
+            (Direction::Frontend, Some(_)) => {
+                let mut raw = RawFrontendMessage::get(&mut reader)?;
+                (self.frontend(message.session, &mut raw)?, raw.raw_body)
+            }
+            (Direction::Backend, Some(_)) => {
+                let mut raw = RawBackendMessage::get(&mut reader)?;
+                (self.backend(message.session, &mut raw)?, raw.raw_body)
+            }
+        };
+        let Some(mut frame) = frame else {
+            return Ok(None);
+        };
+        // What could not be decoded is kept
+        if !left.is_empty() {
+            frame.put_slice(&left);
+            let length = MessageHeader::length_for(frame.len() - 5)?;
+            frame[1..5].copy_from_slice(&length.to_be_bytes());
+        }
+        Ok(Some(frame.to_vec()))
+    }
+
+    fn frontend(
+        &self,
+        session: u32,
+        raw: &mut RawFrontendMessage,
+    ) -> anyhow::Result<Option<BytesMut>> {
+        match raw.header.message_type {
+            b'Q' => {
+                let mut query = Query::try_from(raw)?;
+                query.query = redacted(&query.query)?;
+                frame(&query)
+            }
+            b'P' => {
+                let mut parse = Parse::try_from(raw)?;
+                parse.query = redacted(&parse.query)?;
+                frame(&parse)
+            }
+            b'B' => {
+                let mut bind = Bind::try_from(raw)?;
+                bind.parameters
+                    .as_mut()
+                    .iter_mut()
+                    .for_each(|value| *value = nullable(value));
+                frame(&bind)
+            }
+            b'F' => {
+                let mut call = FunctionCall::try_from(raw)?;
+                call.arguments
+                    .as_mut()
+                    .iter_mut()
+                    .for_each(|value| *value = nullable(value));
+                frame(&call)
+            }
+            b'p' => match self.authentication.get(&session) {
+                Some(AuthenticationMessageKind::MD5Password) => {
+                    let mut password = PasswordMessage::try_from(raw)?;
+                    password.password = CString::new(format!("md5{}", "0".repeat(32)))?;
+                    frame(&password)
+                }
+                Some(AuthenticationMessageKind::SASL) => {
+                    let mut response = SASLInitialResponse::try_from(raw)?;
+                    response.data = scram_placeholder(response.data.as_ref()).into();
+                    frame(&response)
+                }
+                Some(AuthenticationMessageKind::SASLContinue) => {
+                    let mut response = SASLResponse::try_from(raw)?;
+                    response.data = scram_placeholder(response.data.as_ref()).into();
+                    frame(&response)
+                }
+                Some(AuthenticationMessageKind::CleartextPassword) => {
+                    let mut password = PasswordMessage::try_from(raw)?;
+                    password.password = masked(&password.password)?;
+                    frame(&password)
+                }
+                _ => {
+                    let mut response = GSSResponse::try_from(raw)?;
+                    response.data = vec![0; response.data.as_ref().len()].into();
+                    frame(&response)
+                }
+            },
+            b'd' => {
+                let mut data = CopyData::try_from(raw)?;
+                data.data = copy_placeholder(data.data.as_ref()).into();
+                frame(&data)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn backend(
+        &mut self,
+        session: u32,
+        raw: &mut RawBackendMessage,
+    ) -> anyhow::Result<Option<BytesMut>> {
+        match raw.header.message_type {
+            b'R' => {
+                let Some(kind) = raw.get_auth_message_kind() else {
+                    self.authentication.remove(&session);
+                    return Ok(None);
+                };
+                self.authentication.insert(session, kind);
+                match kind {
+                    AuthenticationMessageKind::MD5Password => {
+                        let mut request = AuthenticationMD5Password::try_from(raw)?;
+                        request.salt = [0; 4];
+                        frame(&request)
+                    }
+                    AuthenticationMessageKind::GSSContinue => {
+                        let mut request = AuthenticationGSSContinue::try_from(raw)?;
+                        request.data = vec![0; request.data.as_ref().len()].into();
+                        frame(&request)
+                    }
+                    AuthenticationMessageKind::SASLContinue => {
+                        let mut request = AuthenticationSASLContinue::try_from(raw)?;
+                        request.data = scram_placeholder(request.data.as_ref()).into();
+                        frame(&request)
+                    }
+                    AuthenticationMessageKind::SASLFinal => {
+                        let mut request = AuthenticationSASLFinal::try_from(raw)?;
+                        request.data = scram_placeholder(request.data.as_ref()).into();
+                        frame(&request)
+                    }
+                    _ => Ok(None),
+                }
+            }
+            b'd' => {
+                let mut data = CopyData::try_from(raw)?;
+                data.data = copy_placeholder(data.data.as_ref()).into();
+                frame(&data)
+            }
+            b'D' => {
+                let mut row = DataRow::try_from(raw)?;
+                row.columns
+                    .as_mut()
+                    .iter_mut()
+                    .for_each(|value| *value = nullable(value));
+                frame(&row)
+            }
+            b'V' => {
+                let mut response = FunctionCallResponse::try_from(raw)?;
+                response.result = nullable(&response.result);
+                frame(&response)
+            }
+            b'A' => {
+                let mut notification = NotificationResponse::try_from(raw)?;
+                notification.payload = masked(&notification.payload)?;
+                frame(&notification)
+            }
+            // The detail of an error can quote the rows, e.g. a duplicate
+            // key
+            b'E' => {
+                let mut error = ErrorResponse::try_from(raw)?;
+                masked_details(error.messages.as_mut())?;
+                frame(&error)
+            }
+            b'N' => {
+                let mut notice = NoticeResponse::try_from(raw)?;
+                masked_details(notice.messages.as_mut())?;
+                frame(&notice)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The capture with the sensitive fields of all its messages replaced, see
+/// Anonymizer
+pub fn anonymize(capture: &Capture) -> Capture {
+    let mut anonymizer = Anonymizer::new();
+    Capture {
+        messages: capture
+            .messages
+            .iter()
+            .map(|message| anonymizer.message(message))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message<U>(sender: Direction, body: U) -> CapturedMessage
+    where
+        U: MessageBody + Serialize + ByteSized,
+    {
+        let mut bytes = BytesMut::new();
+        MessageHeader::serialize_message(&mut bytes, &body).expect("message length");
+        CapturedMessage {
+            session: 0,
+            sender,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn anonymized_capture() -> anyhow::Result<()> {
+        let mut capture = Capture {
+            messages: vec![
+                message(
+                    Direction::Backend,
+                    AuthenticationMD5Password::new([9, 8, 7, 6]),
+                ),
+                message(
+                    Direction::Frontend,
+                    PasswordMessage::new_from_user_password(
+                        &String::from("app"),
+                        &String::from("secret"),
+                        &[9, 8, 7, 6],
+                    )?,
+                ),
+                message(
+                    Direction::Frontend,
+                    Query::new(String::from("SELECT name FROM users WHERE id = 42"))?,
+                ),
+            ],
+        };
+        // A row with a NULL
+        let mut row = BytesMut::new();
+        row.put_u8(b'D');
+        row.put_i32(4 + 2 + 9 + 4 + 8);
+        row.put_i16(3);
+        row.put_i32(5);
+        row.put_slice(b"alice");
+        row.put_i32(-1);
+        row.put_i32(4);
+        row.put_slice(b"-1.5");
+        capture.messages.push(CapturedMessage {
+            session: 0,
+            sender: Direction::Backend,
+            bytes: row.to_vec(),
+        });
+        capture.messages.push(message(
+            Direction::Backend,
+            ErrorResponse::new(vec![
+                ErrorMessage::new('C', "23505")?,
+                ErrorMessage::new('D', "Key (id)=(42) already exists.")?,
+            ]),
+        ));
+        let anonymized = anonymize(&capture);
+        assert_eq!(capture.messages.len(), anonymized.messages.len());
+        let decoded: Vec<String> = anonymized
+            .messages
+            .iter()
+            .map(CapturedMessage::to_string)
+            .collect();
+        assert_eq!(
+            vec![
+                "B AuthenticationMD5Password salt='\\x00\\x00\\x00\\x00'",
+                "F PasswordMessage data='md500000000000000000000000000000000\\x00'",
+                "F Query query=\"SELECT name FROM users WHERE id = 0\"",
+                "B DataRow values[0]='xxxxx' values[1]=NULL values[2]='0000'",
+                "B ErrorResponse fields.C=\"23505\" fields.D=\"xxxxxxxxxxxxxxxxxxxxxxxxxxxxx\"",
+            ],
+            decoded
+        );
+
+        assert_eq!(
+            b"c=biws,r=AAAA,p=AAAA==".to_vec(),
+            scram_placeholder(b"c=biws,r=nonc,p=pr0f==")
+        );
+        assert_eq!(b"xx\t\\N\nxxx".to_vec(), copy_placeholder(b"ab\t\\N\n1.5"));
+
+        Ok(())
+    }
+}
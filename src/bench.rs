@@ -0,0 +1,147 @@
+//! A load generator built on the client stack, see `fakepostmaster bench`.
+//! Each connection runs the statements of a script in a loop from its own
+//! thread, a run of the whole script is a transaction. The round trips go
+//! to a QueryStats shared by the connections.
+
+use anyhow::anyhow;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+use crate::handler::probe::{self, ConnInfo};
+use crate::stats::{QueryStats, QuerySummary};
+
+/// The outcome of a benchmark
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub transactions: u64,
+    // The transactions stopped by an error
+    pub errors: u64,
+    pub elapsed: Duration,
+    // By query pattern
+    pub queries: Vec<QuerySummary>,
+}
+
+impl BenchReport {
+    /// The transactions completed per second
+    pub fn tps(&self) -> f64 {
+        self.transactions as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "transactions: {}", self.transactions)?;
+        writeln!(f, "errors: {}", self.errors)?;
+        writeln!(f, "tps: {:.1}", self.tps())?;
+        writeln!(f, "count\tp50\tp95\tp99\tmax\tquery")?;
+        for query in &self.queries {
+            writeln!(
+                f,
+                "{}\t{:?}\t{:?}\t{:?}\t{:?}\t{}",
+                query.count, query.p50, query.p95, query.p99, query.max, query.pattern
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the statements in a loop on `concurrency` connections for
+/// `duration`. The connections are opened first, a failure ends the
+/// benchmark. A connection that stops answering ends its thread.
+pub fn run(
+    conninfo: &ConnInfo,
+    statements: &[String],
+    concurrency: usize,
+    duration: Duration,
+) -> anyhow::Result<BenchReport> {
+    if statements.is_empty() {
+        return Err(anyhow!("No statement to run"));
+    }
+    let timeout = Duration::from_secs(5);
+    let stats = QueryStats::new(None);
+    let mut handlers = Vec::with_capacity(concurrency);
+    for _ in 0..concurrency {
+        let mut handler = probe::connect(conninfo, "fakepostmaster bench", timeout)?;
+        handler.stats = Some(stats.clone());
+        handlers.push(handler);
+    }
+
+    let start = Instant::now();
+    let deadline = start + duration;
+    let threads: Vec<_> = handlers
+        .into_iter()
+        .map(|mut handler| {
+            let statements = statements.to_vec();
+            thread::spawn(move || -> anyhow::Result<(u64, u64)> {
+                let (mut transactions, mut errors) = (0, 0);
+                while Instant::now() < deadline {
+                    match statements
+                        .iter()
+                        .try_for_each(|statement| handler.simple_query_handler(statement).map(drop))
+                    {
+                        Ok(()) => transactions += 1,
+                        Err(e) => {
+                            debug!("Transaction failed: {e}");
+                            errors += 1;
+                            if !handler.is_alive(timeout) {
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+                Ok((transactions, errors))
+            })
+        })
+        .collect();
+
+    let mut report = BenchReport {
+        transactions: 0,
+        errors: 0,
+        elapsed: Duration::ZERO,
+        queries: Vec::new(),
+    };
+    for thread in threads {
+        match thread.join() {
+            Ok(Ok((transactions, errors))) => {
+                report.transactions += transactions;
+                report.errors += errors;
+            }
+            Ok(Err(e)) => warn!("Connection lost: {e}"),
+            Err(_) => warn!("A connection thread panicked"),
+        }
+    }
+    report.elapsed = start.elapsed();
+    report.queries = stats.summary();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scenario::Scenario;
+    use crate::test_server::TestServer;
+
+    #[test]
+    fn bench_test_server() -> anyhow::Result<()> {
+        let server = TestServer::start("127.0.0.1:0", &Scenario::default())?;
+        let conninfo: ConnInfo = format!(
+            "host=127.0.0.1 port={} user=app password=secret",
+            server.address().port()
+        )
+        .parse()?;
+
+        let statements = [String::from("SELECT 1"), String::from("SELECT 2")];
+        let report = run(&conninfo, &statements, 2, Duration::from_millis(100))?;
+        assert!(report.transactions > 0);
+        assert_eq!(0, report.errors);
+        assert_eq!(2, report.queries.len());
+        assert!(report.queries[0].count >= report.transactions);
+        assert!(report.to_string().contains("tps: "));
+
+        assert!(run(&conninfo, &[], 1, Duration::ZERO).is_err());
+
+        server.stop()
+    }
+}
@@ -0,0 +1,989 @@
+//! Captures of the messages of a session, to check that a frontend (e.g. a
+//! driver under test) sends and receives the same messages as in an expected
+//! capture. The messages are compared by their decoded fields instead of
+//! their bytes, so that the fields that change from one session to the next
+//! (keys, salts...) can be ignored, and the first difference is reported
+//! with the values of the field.
+//!
+//! A capture is recorded by a CapturingReader and a CapturingWriter in the
+//! reader/writer stack of a handler, and saved as the frames of the two
+//! sides after the FORMAT_MAGIC and the FORMAT_VERSION, each prefixed by its
+//! sender ('F' or 'B') and its session (Int32). The files of the version 0,
+//! without the magic nor the sessions, are still read. A capture can also be
+//! written as JSON lines, one object per message with the frame in hex, or
+//! while it is recorded in blocks, see capture_file.
+
+use anyhow::anyhow;
+use bytes::Buf;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::*;
+
+use crate::capture_file::{BLOCKS_FORMAT_VERSION, CaptureFileReader, CaptureFileWriter};
+use crate::corruption::Framing;
+use crate::message::*;
+use crate::validator::Direction;
+
+/// The fields that differ between two sessions doing the same thing, see
+/// Capture::assert_matches
+pub const VOLATILE_FIELDS: &[&str] = &[
+    "BackendKeyData.process_id",
+    "BackendKeyData.secret_key",
+    "CancelRequest.process_id",
+    "CancelRequest.secret_key",
+    "AuthenticationMD5Password.salt",
+    "AuthenticationSASLContinue.data",
+    "AuthenticationSASLFinal.data",
+    "PasswordMessage.data",
+    "NotificationResponse.process_id",
+    // The location in the source code of the server
+    "ErrorResponse.fields.F",
+    "ErrorResponse.fields.L",
+    "ErrorResponse.fields.R",
+    "NoticeResponse.fields.F",
+    "NoticeResponse.fields.L",
+    "NoticeResponse.fields.R",
+];
+
+/// The start of the capture files, followed by their FORMAT_VERSION
+pub const FORMAT_MAGIC: &[u8] = b"PGCAPTURE";
+pub const FORMAT_VERSION: u8 = 1;
+
+/// A message of a capture, the whole frame as it was sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedMessage {
+    // The sessions of a capture are numbered by their Recording, 0 for the
+    // only one
+    pub session: u32,
+    pub sender: Direction,
+    pub bytes: Vec<u8>,
+}
+
+impl CapturedMessage {
+    /// The name of the message and its fields in order, the repeated ones
+    /// are numbered, e.g. `columns[0].name`
+    pub fn decode(&self) -> (String, Vec<(String, String)>) {
+        let mut fields = Fields::default();
+        let mut reader = BufReader::new(&self.bytes[..]);
+        let decoded = match (self.sender, self.bytes.first()) {
+            (Direction::Backend, Some(b'N')) if self.bytes.len() == 1 => {
+                fields.push("response", bytes(b"N"));
+                Ok(0)
+            }
+            // The length of the untyped requests starts with 0
+            (Direction::Frontend, Some(0)) => RawRequest::get(&mut reader)
+                .and_then(|mut raw| fields.request(&mut raw).map(|()| raw.raw_body.len())),
+            (Direction::Frontend, Some(_)) => RawFrontendMessage::get(&mut reader)
+                .and_then(|mut raw| fields.frontend(&mut raw).map(|()| raw.raw_body.len())),
+            (Direction::Backend, Some(_)) => RawBackendMessage::get(&mut reader)
+                .and_then(|mut raw| fields.backend(&mut raw).map(|()| raw.raw_body.len())),
+            (_, None) => Ok(0),
+        };
+        match decoded {
+            Ok(0) => {}
+            Ok(left) => fields.push("error", format!("{left} bytes left")),
+            Err(e) => fields.push("error", e.to_string()),
+        }
+        (self.name(), fields.0)
+    }
+
+    /// The name of the message from its type, and from its code for the
+    /// requests and the authentication messages
+    fn name(&self) -> String {
+        let code = |range: std::ops::Range<usize>| {
+            let code = self.bytes.get(range)?.try_into().ok()?;
+            Some(i32::from_be_bytes(code))
+        };
+        match (self.sender, self.bytes.first()) {
+            (Direction::Backend, Some(b'N')) if self.bytes.len() == 1 => {
+                String::from("EncryptionResponse")
+            }
+            (Direction::Frontend, Some(0)) => match code(4..8).map(RequestMessageKind::try_from) {
+                Some(Ok(kind)) => format!("{kind:?}"),
+                _ => String::from("Unknown"),
+            },
+            // A password, a GSS or SASL response depending on the
+            // authentication
+            (Direction::Frontend, Some(b'p')) => String::from("PasswordMessage"),
+            (Direction::Frontend, Some(message_type)) => {
+                match FrontendMessageKind::try_from(*message_type) {
+                    Ok(kind) => format!("{kind:?}"),
+                    Err(_) => String::from("Unknown"),
+                }
+            }
+            (Direction::Backend, Some(b'R')) => {
+                match code(5..9).map(AuthenticationMessageKind::try_from) {
+                    Some(Ok(kind)) => format!("Authentication{kind:?}"),
+                    _ => String::from("Authentication"),
+                }
+            }
+            (Direction::Backend, Some(message_type)) => {
+                match BackendMessageKind::try_from(*message_type) {
+                    Ok(kind) => format!("{kind:?}"),
+                    Err(_) => String::from("Unknown"),
+                }
+            }
+            (_, None) => String::from("Empty"),
+        }
+    }
+
+    /// The message like in the output of PQtrace: the sender, the length,
+    /// the name and the values of the fields, e.g. `F\t13\tQuery\t "SELECT 1"`
+    pub fn trace(&self) -> String {
+        let length = match (self.sender, self.bytes.first()) {
+            (Direction::Frontend, Some(0)) => self.bytes.len(),
+            _ if self.bytes.len() == 1 => 1,
+            _ => self.bytes.len().saturating_sub(1),
+        };
+        let sender = match self.sender {
+            Direction::Frontend => 'F',
+            Direction::Backend => 'B',
+        };
+        let (name, fields) = self.decode();
+        let mut trace = format!("{sender}\t{length}\t{name}");
+        if !fields.is_empty() {
+            trace.push('\t');
+            for (_, value) in fields {
+                trace.push(' ');
+                trace.push_str(&value);
+            }
+        }
+        trace
+    }
+}
+
+impl fmt::Display for CapturedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sender = match self.sender {
+            Direction::Frontend => 'F',
+            Direction::Backend => 'B',
+        };
+        let (name, fields) = self.decode();
+        write!(f, "{sender} {name}")?;
+        for (name, value) in fields {
+            write!(f, " {name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+fn string(value: &CString) -> String {
+    format!("\"{}\"", value.to_string_lossy().escape_debug())
+}
+
+fn bytes(value: &[u8]) -> String {
+    format!("'{}'", value.escape_ascii())
+}
+
+fn int(value: &impl ToString) -> String {
+    value.to_string()
+}
+
+fn nullable(value: &ColumnData) -> String {
+    value.as_bytes().map_or(String::from("NULL"), bytes)
+}
+
+/// The fields of a message decoded by its typed struct, the bytes left
+/// after them are counted by the caller
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl Fields {
+    fn push(&mut self, name: &str, value: String) {
+        self.0.push((String::from(name), value));
+    }
+
+    fn repeated<T>(&mut self, name: &str, values: &[T], format: impl Fn(&T) -> String) {
+        for (i, value) in values.iter().enumerate() {
+            self.push(&format!("{name}[{i}]"), format(value));
+        }
+    }
+
+    /// The fields of an ErrorResponse or a NoticeResponse, by code
+    fn notice(&mut self, messages: &[ErrorMessage]) {
+        for message in messages {
+            let name = format!("fields.{}", char::from(message.code));
+            self.push(&name, string(&message.message));
+        }
+    }
+
+    fn request(&mut self, raw: &mut RawRequest) -> anyhow::Result<()> {
+        match raw.request_kind {
+            RequestMessageKind::StartupMessage => {
+                let startup = StartupMessage::try_from(raw)?;
+                let ProtocolVersion { major, minor } = startup.protocol_version;
+                self.push("protocol_version", format!("{major}.{minor}"));
+                for parameter in startup.parameters.as_ref() {
+                    let name = format!("parameters.{}", parameter.name.to_string_lossy());
+                    self.push(&name, string(&parameter.value));
+                }
+            }
+            RequestMessageKind::CancelRequest => {
+                // The code
+                raw.raw_body.try_get_i32()?;
+                self.push("process_id", int(&raw.raw_body.try_get_i32()?));
+                self.push("secret_key", int(&raw.raw_body.try_get_i32()?));
+            }
+            RequestMessageKind::GSSENCRequest | RequestMessageKind::SSLRequest => {
+                raw.raw_body.try_get_i32()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn frontend(&mut self, raw: &mut RawFrontendMessage) -> anyhow::Result<()> {
+        match raw.header.message_type {
+            b'B' => {
+                let bind = Bind::try_from(raw)?;
+                self.push("portal", string(&bind.portal));
+                self.push("statement", string(&bind.statement));
+                self.repeated("parameter_formats", bind.parameter_formats.as_ref(), int);
+                self.repeated("parameters", bind.parameters.as_ref(), nullable);
+                self.repeated("result_formats", bind.result_formats.as_ref(), int);
+            }
+            b'C' => {
+                let close = Close::try_from(raw)?;
+                self.push("target", bytes(&[close.target]));
+                self.push("name", string(&close.name));
+            }
+            b'D' => {
+                let describe = Describe::try_from(raw)?;
+                self.push("target", bytes(&[describe.target]));
+                self.push("name", string(&describe.name));
+            }
+            b'd' => self.push("data", bytes(CopyData::try_from(raw)?.data.as_ref())),
+            b'f' => self.push("message", string(&CopyFail::try_from(raw)?.message)),
+            b'E' => {
+                let execute = Execute::try_from(raw)?;
+                self.push("portal", string(&execute.portal));
+                self.push("max_rows", int(&execute.max_rows));
+            }
+            b'F' => {
+                let call = FunctionCall::try_from(raw)?;
+                self.push("function", int(&call.function));
+                self.repeated("argument_formats", call.argument_formats.as_ref(), int);
+                self.repeated("arguments", call.arguments.as_ref(), nullable);
+                self.push("result_format", int(&call.result_format));
+            }
+            // Its layout depends on the authentication, all its bytes are
+            // kept
+            b'p' => self.push("data", bytes(GSSResponse::try_from(raw)?.data.as_ref())),
+            b'P' => {
+                let parse = Parse::try_from(raw)?;
+                self.push("statement", string(&parse.statement));
+                self.push("query", string(&parse.query));
+                self.repeated("parameter_types", parse.parameter_types.as_ref(), int);
+            }
+            b'Q' => self.push("query", string(&Query::try_from(raw)?.query)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn backend(&mut self, raw: &mut RawBackendMessage) -> anyhow::Result<()> {
+        match raw.header.message_type {
+            // The code is in the name of the message
+            b'R' => match raw.get_auth_message_kind() {
+                Some(AuthenticationMessageKind::Ok) => {
+                    AuthenticationOk::try_from(raw)?;
+                }
+                Some(AuthenticationMessageKind::MD5Password) => {
+                    let salt = AuthenticationMD5Password::try_from(raw)?.salt;
+                    self.push("salt", bytes(&salt));
+                }
+                Some(AuthenticationMessageKind::GSS) => {
+                    AuthenticationGSS::try_from(raw)?;
+                }
+                Some(AuthenticationMessageKind::GSSContinue) => {
+                    let data = AuthenticationGSSContinue::try_from(raw)?.data;
+                    self.push("data", bytes(data.as_ref()));
+                }
+                Some(AuthenticationMessageKind::SSPI) => {
+                    AuthenticationSSPI::try_from(raw)?;
+                }
+                Some(AuthenticationMessageKind::SASL) => {
+                    let mechanisms = AuthenticationSASL::try_from(raw)?.mechanisms;
+                    self.repeated("mechanisms", mechanisms.as_ref(), string);
+                }
+                Some(AuthenticationMessageKind::SASLContinue) => {
+                    let data = AuthenticationSASLContinue::try_from(raw)?.data;
+                    self.push("data", bytes(data.as_ref()));
+                }
+                Some(AuthenticationMessageKind::SASLFinal) => {
+                    let data = AuthenticationSASLFinal::try_from(raw)?.data;
+                    self.push("data", bytes(data.as_ref()));
+                }
+                // No struct, nothing after the code
+                Some(AuthenticationMessageKind::KerberosV5)
+                | Some(AuthenticationMessageKind::CleartextPassword)
+                | None => {
+                    raw.raw_body.try_get_i32()?;
+                }
+            },
+            b'K' => {
+                let key = BackendKeyData::try_from(raw)?;
+                self.push("process_id", int(&key.process_id));
+                self.push("secret_key", int(&key.secret_key));
+            }
+            b'C' => {
+                let complete = CommandComplete::try_from(raw)?;
+                self.push("command_tag", string(&complete.command_tag));
+            }
+            b'd' => self.push("data", bytes(CopyData::try_from(raw)?.data.as_ref())),
+            b'G' => {
+                let response = CopyInResponse::try_from(raw)?;
+                self.copy_response(response.format, response.column_formats.as_ref());
+            }
+            // A CopyBothResponse has the fields of a CopyOutResponse
+            b'H' | b'W' => {
+                let response = <CopyOutResponse as libpq_serde_types::Deserialize>::deserialize(
+                    &mut raw.raw_body,
+                )?;
+                self.copy_response(response.format, response.column_formats.as_ref());
+            }
+            b'D' => {
+                let row = DataRow::try_from(raw)?;
+                self.repeated("values", row.columns.as_ref(), nullable);
+            }
+            b'E' => self.notice(ErrorResponse::try_from(raw)?.messages.as_ref()),
+            b'N' => self.notice(NoticeResponse::try_from(raw)?.messages.as_ref()),
+            b'V' => {
+                let response = FunctionCallResponse::try_from(raw)?;
+                self.push("result", nullable(&response.result));
+            }
+            b'v' => {
+                let negotiate = NegotiateProtocolVersion::try_from(raw)?;
+                let version = negotiate.newest_minor_version;
+                self.push("newest_minor_version", int(&version));
+                self.repeated("options", negotiate.options.as_ref(), string);
+            }
+            b'A' => {
+                let notification = NotificationResponse::try_from(raw)?;
+                self.push("process_id", int(&notification.process_id));
+                self.push("channel", string(&notification.channel));
+                self.push("payload", string(&notification.payload));
+            }
+            b't' => {
+                let description = ParameterDescription::try_from(raw)?;
+                let types = description.parameter_types;
+                self.repeated("parameter_types", types.as_ref(), int);
+            }
+            b'S' => {
+                let status = ParameterStatus::try_from(raw)?;
+                self.push("name", string(&status.name));
+                self.push("value", string(&status.value));
+            }
+            b'Z' => {
+                let ready = ReadyForQuery::try_from(raw)?;
+                self.push("transaction_status", bytes(&[ready.transaction_indicator]));
+            }
+            b'T' => {
+                let description = RowDescription::try_from(raw)?;
+                for (i, column) in description.columns.as_ref().iter().enumerate() {
+                    let name = |field: &str| format!("columns[{i}].{field}");
+                    self.push(&name("name"), string(&column.name));
+                    self.push(&name("relation_id"), int(&column.relation_id));
+                    self.push(&name("attribute_id"), int(&column.attribute_id));
+                    self.push(&name("datatype_id"), int(&column.datatype_id));
+                    self.push(&name("datatype_len"), int(&column.datatype_len));
+                    self.push(&name("datatype_mod"), int(&column.datatype_mod));
+                    self.push(&name("format"), int(&column.format));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn copy_response(&mut self, format: i8, column_formats: &[i16]) {
+        self.push("format", bytes(&format.to_be_bytes()));
+        self.repeated("column_formats", column_formats, int);
+    }
+}
+
+/// The first difference between an expected capture and an actual one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    // The position of the message in the captures, from 0
+    pub index: usize,
+    // None when the capture has no message at this position
+    pub expected: Option<CapturedMessage>,
+    pub actual: Option<CapturedMessage>,
+    // The first field that differs, None when the messages are of different
+    // kinds
+    pub field: Option<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (expected, actual) = match (&self.expected, &self.actual) {
+            (Some(expected), Some(actual)) => (expected, actual),
+            (Some(expected), None) => {
+                return write!(f, "message {}: missing {expected}", self.index);
+            }
+            (None, Some(actual)) => {
+                return write!(f, "message {}: unexpected {actual}", self.index);
+            }
+            (None, None) => return write!(f, "message {}: no difference", self.index),
+        };
+        let Some(field) = &self.field else {
+            return write!(
+                f,
+                "message {}: expected {expected}, got {actual}",
+                self.index
+            );
+        };
+        let value = |message: &CapturedMessage| {
+            let (_, fields) = message.decode();
+            fields
+                .into_iter()
+                .find(|(name, _)| name == field)
+                .map_or(String::from("missing"), |(_, value)| value)
+        };
+        let (name, _) = expected.decode();
+        write!(
+            f,
+            "message {} ({name}): {field} is {}, expected {}",
+            self.index,
+            value(actual),
+            value(expected)
+        )
+    }
+}
+
+/// Whether a field is one of the ignored ones, `[*]` matches any index
+fn is_ignored(ignored: &[&str], message: &str, field: &str) -> bool {
+    let mut any_index = String::new();
+    let mut rest = field;
+    while let Some(start) = rest.find('[') {
+        any_index.push_str(&rest[..start]);
+        match rest[start..].find(']') {
+            Some(end) => {
+                any_index.push_str("[*]");
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    any_index.push_str(rest);
+    ignored.iter().any(|ignored| {
+        ignored.split_once('.').is_some_and(|(name, ignored)| {
+            name == message && (ignored == field || ignored == any_index)
+        })
+    })
+}
+
+/// The messages of a session, or of several ones, in the order they were
+/// sent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Capture {
+    pub messages: Vec<CapturedMessage>,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The first message of `actual` that differs from this capture,
+    /// ignoring the fields like "BackendKeyData.process_id"
+    pub fn diff(&self, actual: &Capture, ignored: &[&str]) -> Option<Divergence> {
+        let length = self.messages.len().max(actual.messages.len());
+        for index in 0..length {
+            let divergence = |field: Option<String>| Divergence {
+                index,
+                expected: self.messages.get(index).cloned(),
+                actual: actual.messages.get(index).cloned(),
+                field,
+            };
+            let (Some(expected), Some(found)) =
+                (self.messages.get(index), actual.messages.get(index))
+            else {
+                return Some(divergence(None));
+            };
+            let ((name, expected_fields), (found_name, found_fields)) =
+                (expected.decode(), found.decode());
+            if expected.sender != found.sender || name != found_name {
+                return Some(divergence(None));
+            }
+            for i in 0..expected_fields.len().max(found_fields.len()) {
+                match (expected_fields.get(i), found_fields.get(i)) {
+                    (Some(a), Some(b)) if a == b => {}
+                    (Some((field, _)), Some((other, _)))
+                        if field == other && is_ignored(ignored, &name, field) => {}
+                    (Some((field, _)), _) | (None, Some((field, _))) => {
+                        return Some(divergence(Some(field.clone())));
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        None
+    }
+
+    /// Fail with the first difference, the VOLATILE_FIELDS are ignored
+    pub fn assert_matches(&self, actual: &Capture) -> anyhow::Result<()> {
+        match self.diff(actual, VOLATILE_FIELDS) {
+            Some(divergence) => Err(anyhow!("{divergence}")),
+            None => Ok(()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = FORMAT_MAGIC.to_vec();
+        bytes.push(FORMAT_VERSION);
+        for message in &self.messages {
+            bytes.push(match message.sender {
+                Direction::Frontend => b'F',
+                Direction::Backend => b'B',
+            });
+            bytes.extend_from_slice(&message.session.to_be_bytes());
+            bytes.extend_from_slice(&message.bytes);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let (version, mut bytes) = match bytes.strip_prefix(FORMAT_MAGIC) {
+            Some(rest) => match rest.split_first() {
+                Some((&FORMAT_VERSION, rest)) => (FORMAT_VERSION, rest),
+                Some((&BLOCKS_FORMAT_VERSION, _)) => {
+                    let file = CaptureFileReader::new(io::Cursor::new(bytes))?;
+                    return Ok(Self {
+                        messages: file.collect::<anyhow::Result<_>>()?,
+                    });
+                }
+                Some((version, _)) => {
+                    return Err(anyhow!("Unsupported capture format version {version}"));
+                }
+                None => return Err(anyhow!("Missing capture format version")),
+            },
+            None => (0, bytes),
+        };
+        let mut capture = Self::new();
+        // The frames of each side of each session
+        let mut framings = HashMap::new();
+        while let Some((sender, rest)) = bytes.split_first() {
+            let sender = match sender {
+                b'F' => Direction::Frontend,
+                b'B' => Direction::Backend,
+                _ => {
+                    return Err(anyhow!(
+                        "Invalid sender {} at message {}",
+                        sender.escape_ascii(),
+                        capture.messages.len()
+                    ));
+                }
+            };
+            let (session, rest) = match version {
+                0 => (0, rest),
+                _ => match rest.split_first_chunk::<4>() {
+                    Some((session, rest)) => (u32::from_be_bytes(*session), rest),
+                    None => return Err(anyhow!("Truncated message {}", capture.messages.len())),
+                },
+            };
+            let framing = framings
+                .entry((session, sender))
+                .or_insert_with(|| Framing::new(sender));
+            let length = match framing.next_frame(rest) {
+                Some(Ok((length, _))) if length <= rest.len() => length,
+                Some(Err(reason)) => {
+                    return Err(anyhow!("{reason} at message {}", capture.messages.len()));
+                }
+                _ => {
+                    return Err(anyhow!("Truncated message {}", capture.messages.len()));
+                }
+            };
+            capture.messages.push(CapturedMessage {
+                session,
+                sender,
+                bytes: rest[..length].to_vec(),
+            });
+            bytes = &rest[length..];
+        }
+        Ok(capture)
+    }
+
+    /// One JSON object per line and per message, with the name of the
+    /// message for the reader, e.g.
+    /// `{"session":0,"sender":"F","message":"Sync","bytes":"5300000004"}`
+    pub fn to_json_lines(&self) -> anyhow::Result<String> {
+        let mut lines = String::new();
+        for message in &self.messages {
+            let line = JsonMessage {
+                session: message.session,
+                sender: match message.sender {
+                    Direction::Frontend => 'F',
+                    Direction::Backend => 'B',
+                },
+                message: message.decode().0,
+                bytes: message
+                    .bytes
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect(),
+            };
+            lines.push_str(&serde_json::to_string(&line)?);
+            lines.push('\n');
+        }
+        Ok(lines)
+    }
+
+    /// The messages of to_json_lines(), their name is not checked
+    pub fn from_json_lines(lines: &str) -> anyhow::Result<Self> {
+        let mut capture = Self::new();
+        for (number, line) in lines.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let error = |e: &dyn fmt::Display| anyhow!("line {}: {e}", number + 1);
+            let message: JsonMessage = serde_json::from_str(line).map_err(|e| error(&e))?;
+            let sender = match message.sender {
+                'F' => Direction::Frontend,
+                'B' => Direction::Backend,
+                other => return Err(error(&format!("invalid sender {other}"))),
+            };
+            let bytes = (0..message.bytes.len())
+                .step_by(2)
+                .map(|i| {
+                    message
+                        .bytes
+                        .get(i..i + 2)
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                        .ok_or_else(|| error(&"invalid hex bytes"))
+                })
+                .collect::<anyhow::Result<Vec<u8>>>()?;
+            capture.messages.push(CapturedMessage {
+                session: message.session,
+                sender,
+                bytes,
+            });
+        }
+        Ok(capture)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// A binary capture or JSON lines, told apart by their first byte
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = fs::read(path)?;
+        let capture = match bytes.first() {
+            Some(b'{') => Self::from_json_lines(&String::from_utf8(bytes)?),
+            _ => Self::from_bytes(&bytes),
+        };
+        capture.map_err(|e| anyhow!("{}: {e}", path.display()))
+    }
+
+    /// The sessions of the messages, in the order of their first message
+    pub fn sessions(&self) -> Vec<u32> {
+        let mut sessions = Vec::new();
+        for message in &self.messages {
+            if !sessions.contains(&message.session) {
+                sessions.push(message.session);
+            }
+        }
+        sessions
+    }
+}
+
+/// A line of Capture::to_json_lines()
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonMessage {
+    session: u32,
+    sender: char,
+    #[serde(default)]
+    message: String,
+    bytes: String,
+}
+
+/// The capture recorded by the CapturingReader and the CapturingWriter of
+/// a handler, shared by both: cloning gives another handle on the same
+/// capture.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    capture: Arc<Mutex<Capture>>,
+    // The messages are written there instead of kept in capture
+    file: Option<Arc<Mutex<CaptureFileWriter<BufWriter<File>>>>>,
+    session: u32,
+}
+
+impl Recording {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A recording written in a capture file as the messages arrive, for
+    /// the sessions too large to be kept in memory. It ends with finish().
+    pub fn to_file(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(CaptureFileWriter::create(path)?))),
+            ..Self::default()
+        })
+    }
+
+    /// Another handle on the same capture, recording the messages of
+    /// another session
+    pub fn for_session(&self, session: u32) -> Self {
+        Self {
+            session,
+            ..self.clone()
+        }
+    }
+
+    /// The messages recorded so far, none when they are written in a file
+    pub fn capture(&self) -> Capture {
+        self.capture.lock().expect("recording lock").clone()
+    }
+
+    fn push(&self, message: CapturedMessage) {
+        match &self.file {
+            Some(file) => {
+                if let Err(e) = file.lock().expect("recording lock").write(&message) {
+                    warn!("The capture stops: {e}");
+                }
+            }
+            None => self
+                .capture
+                .lock()
+                .expect("recording lock")
+                .messages
+                .push(message),
+        }
+    }
+
+    /// Write the last messages and the index of the capture file
+    pub fn finish(&self) -> anyhow::Result<()> {
+        match &self.file {
+            Some(file) => file.lock().expect("recording lock").finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The bytes of one side, recorded once they make a whole message
+struct Recorder {
+    framing: Framing,
+    sender: Direction,
+    pending: Vec<u8>,
+    // The messages can't be found anymore after an invalid header
+    lost: bool,
+    recording: Recording,
+}
+
+impl Recorder {
+    fn new(sender: Direction, recording: Recording) -> Self {
+        Self {
+            framing: Framing::new(sender),
+            sender,
+            pending: Vec::new(),
+            lost: false,
+            recording,
+        }
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        if self.lost {
+            return;
+        }
+        self.pending.extend_from_slice(bytes);
+        let mut start = 0;
+        while let Some(frame) = self.framing.next_frame(&self.pending[start..]) {
+            let length = match frame {
+                Ok((length, _)) if start + length <= self.pending.len() => length,
+                Ok(_) => break,
+                Err(reason) => {
+                    warn!("The capture stops: {reason}");
+                    self.lost = true;
+                    break;
+                }
+            };
+            let message = CapturedMessage {
+                session: self.recording.session,
+                sender: self.sender,
+                bytes: self.pending[start..start + length].to_vec(),
+            };
+            self.recording.push(message);
+            start += length;
+        }
+        self.pending.drain(..start);
+    }
+}
+
+/// Records the messages read, sent by `sender`
+pub struct CapturingReader<R> {
+    inner: R,
+    recorder: Recorder,
+}
+
+impl<R: Read> CapturingReader<R> {
+    pub fn new(inner: R, sender: Direction, recording: Recording) -> Self {
+        Self {
+            inner,
+            recorder: Recorder::new(sender, recording),
+        }
+    }
+}
+
+impl<R: Read> Read for CapturingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.recorder.record(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Records the messages written, sent by `sender`
+pub struct CapturingWriter<W> {
+    inner: W,
+    recorder: Recorder,
+}
+
+impl<W: Write> CapturingWriter<W> {
+    pub fn new(inner: W, sender: Direction, recording: Recording) -> Self {
+        Self {
+            inner,
+            recorder: Recorder::new(sender, recording),
+        }
+    }
+}
+
+impl<W: Write> Write for CapturingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.recorder.record(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::server::{Authentication, Handler, QueryResult};
+    use bytes::{BufMut, BytesMut};
+    use libpq_serde_types::Serialize;
+
+    /// The capture of a session running one query answered with `tag`
+    fn session(tag: &str) -> anyhow::Result<Capture> {
+        let startup = StartupMessage::new(
+            ProtocolVersion { major: 3, minor: 0 },
+            vec![ParameterStatus::new("user", "app")?],
+        );
+        let mut body = BytesMut::new();
+        startup.serialize(&mut body);
+        let mut frontend = BytesMut::new();
+        frontend.put_i32(body.len() as i32 + 4);
+        frontend.extend_from_slice(&body);
+        MessageHeader::serialize_message(&mut frontend, &Query::new(String::from("SELECT 1"))?)?;
+        MessageHeader::serialize_message(&mut frontend, &Terminate::new())?;
+
+        let recording = Recording::new();
+        let mut handler = Handler::from_parts(
+            CapturingReader::new(&frontend[..], Direction::Frontend, recording.clone()),
+            CapturingWriter::new(Vec::new(), Direction::Backend, recording.clone()),
+        );
+        handler.authentication_handler(&|_| Authentication::Trust)?;
+        while handler.query_handler(&|_| QueryResult {
+            command_tag: String::from(tag),
+            ..Default::default()
+        })? {}
+        Ok(recording.capture())
+    }
+
+    #[test]
+    fn capture() -> anyhow::Result<()> {
+        let expected = session("SELECT 0")?;
+        assert_eq!(
+            "F StartupMessage protocol_version=3.0 parameters.user=\"app\"",
+            expected.messages[0].to_string()
+        );
+        // In the order of the wire, the frontend sent everything at once
+        assert_eq!("F Terminate", expected.messages[2].to_string());
+        assert_eq!(
+            "B CommandComplete command_tag=\"SELECT 0\"",
+            expected.messages[expected.messages.len() - 2].to_string()
+        );
+        assert_eq!(expected, Capture::from_bytes(&expected.to_bytes())?);
+        assert_eq!(
+            expected,
+            Capture::from_json_lines(&expected.to_json_lines()?)?
+        );
+        assert_eq!("F\t13\tQuery\t \"SELECT 1\"", expected.messages[1].trace());
+        // The version 0, without the magic nor the sessions
+        let version_0: Vec<u8> = expected
+            .messages
+            .iter()
+            .flat_map(|message| {
+                let sender = match message.sender {
+                    Direction::Frontend => b'F',
+                    Direction::Backend => b'B',
+                };
+                [&[sender][..], &message.bytes].concat()
+            })
+            .collect();
+        assert_eq!(expected, Capture::from_bytes(&version_0)?);
+        let mut future = expected.to_bytes();
+        future[FORMAT_MAGIC.len()] = FORMAT_VERSION + 1;
+        assert!(Capture::from_bytes(&future).is_err());
+        expected.assert_matches(&session("SELECT 0")?)?;
+
+        let actual = session("SELECT 2")?;
+        let divergence = expected.diff(&actual, &[]).expect("a divergence");
+        assert_eq!(Some(String::from("command_tag")), divergence.field);
+        assert_eq!(
+            format!(
+                "message {} (CommandComplete): command_tag is \"SELECT 2\", expected \"SELECT 0\"",
+                divergence.index
+            ),
+            divergence.to_string()
+        );
+        assert!(
+            expected
+                .diff(&actual, &["CommandComplete.command_tag"])
+                .is_none()
+        );
+
+        let mut truncated = expected.clone();
+        truncated.messages.pop();
+        assert!(expected.diff(&truncated, &[]).is_some_and(|divergence| {
+            divergence
+                .to_string()
+                .ends_with("missing B ReadyForQuery transaction_status='I'")
+        }));
+        assert!(is_ignored(
+            &["RowDescription.columns[*].relation_id"],
+            "RowDescription",
+            "columns[3].relation_id"
+        ));
+
+        // The bytes left after the fields, and a body the message can't be
+        // deserialized from
+        let message = |bytes: &[u8]| CapturedMessage {
+            session: 0,
+            sender: Direction::Backend,
+            bytes: bytes.to_vec(),
+        };
+        assert_eq!(
+            "B ReadyForQuery transaction_status='I' error=1 bytes left",
+            message(b"Z\0\0\0\x06I!").to_string()
+        );
+        let (name, fields) = message(b"C\0\0\0\x06AB").decode();
+        assert_eq!("CommandComplete", name);
+        assert!(matches!(&fields[..], [(field, _)] if field == "error"));
+
+        Ok(())
+    }
+}
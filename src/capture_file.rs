@@ -0,0 +1,456 @@
+//! Capture files written while the sessions run, instead of a Capture kept
+//! in memory and saved at the end: the messages are written in blocks, each
+//! one compressed with zstd when the feature is enabled, and an index of
+//! the blocks is appended when the file is finished, to start a replay at a
+//! session or at a time without reading what comes before.
+//!
+//! After the FORMAT_MAGIC and the version BLOCKS_FORMAT_VERSION, the file is
+//! a sequence of blocks:
+//!
+//! * Byte1 The kind of the block: 'b' for messages, 'z' for messages
+//!   compressed with zstd, 'i' for the index.
+//! * Int32 The length of the content, not including the header.
+//! * Int64 The time of the first message of the block since the start of
+//!   the capture, in microseconds.
+//! * Byte[n] The content.
+//!
+//! The messages are the sender ('F' or 'B'), the session (Int32), the
+//! length of the frame (Int32) and the frame. The index has an entry per
+//! block of messages: its offset in the file (Int64), its time (Int64), the
+//! number of its first message in the capture (Int64) and the sessions of
+//! its messages (Int32 count, Int32 each). The file ends with the offset of
+//! the index (Int64), a file without it (e.g. the capture stopped with ^C)
+//! is read block by block.
+
+use anyhow::anyhow;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::capture::{CapturedMessage, FORMAT_MAGIC};
+use crate::validator::Direction;
+
+pub const BLOCKS_FORMAT_VERSION: u8 = 2;
+/// The size of the messages written in a block, before the compression
+pub const BLOCK_SIZE: usize = 256 * 1024;
+
+const MESSAGES: u8 = b'b';
+const ZSTD_MESSAGES: u8 = b'z';
+const INDEX: u8 = b'i';
+const BLOCK_HEADER_LENGTH: u64 = 13;
+
+/// A block of messages in the index of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    // From the start of the file
+    pub offset: u64,
+    // Since the start of the capture
+    pub elapsed: Duration,
+    pub first_message: u64,
+    pub sessions: Vec<u32>,
+}
+
+/// Writes the messages of a capture in blocks as they are recorded
+#[derive(Debug)]
+pub struct CaptureFileWriter<W: Write> {
+    writer: W,
+    // A block is written once its messages are this large
+    pub block_size: usize,
+    start: Instant,
+    offset: u64,
+    messages: u64,
+    block: Vec<u8>,
+    // The entry of the block being filled
+    entry: Option<IndexEntry>,
+    index: Vec<IndexEntry>,
+    finished: bool,
+}
+
+impl CaptureFileWriter<BufWriter<File>> {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::create(path).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+        Self::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> CaptureFileWriter<W> {
+    pub fn new(mut writer: W) -> anyhow::Result<Self> {
+        writer.write_all(FORMAT_MAGIC)?;
+        writer.write_all(&[BLOCKS_FORMAT_VERSION])?;
+        Ok(Self {
+            writer,
+            block_size: BLOCK_SIZE,
+            start: Instant::now(),
+            offset: FORMAT_MAGIC.len() as u64 + 1,
+            messages: 0,
+            block: Vec::new(),
+            entry: None,
+            index: Vec::new(),
+            finished: false,
+        })
+    }
+
+    pub fn write(&mut self, message: &CapturedMessage) -> anyhow::Result<()> {
+        if self.finished {
+            return Err(anyhow!("The capture file is finished"));
+        }
+        let entry = self.entry.get_or_insert_with(|| IndexEntry {
+            offset: self.offset,
+            elapsed: self.start.elapsed(),
+            first_message: self.messages,
+            sessions: Vec::new(),
+        });
+        if !entry.sessions.contains(&message.session) {
+            entry.sessions.push(message.session);
+        }
+        self.block.push(match message.sender {
+            Direction::Frontend => b'F',
+            Direction::Backend => b'B',
+        });
+        self.block.extend_from_slice(&message.session.to_be_bytes());
+        self.block
+            .extend_from_slice(&(message.bytes.len() as u32).to_be_bytes());
+        self.block.extend_from_slice(&message.bytes);
+        self.messages += 1;
+        if self.block.len() >= self.block_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the block being filled, even if it is small
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        let Some(entry) = self.entry.take() else {
+            return Ok(());
+        };
+        let block = std::mem::take(&mut self.block);
+        #[cfg(feature = "zstd")]
+        let (kind, content) = (ZSTD_MESSAGES, zstd::bulk::compress(&block, 3)?);
+        #[cfg(not(feature = "zstd"))]
+        let (kind, content) = (MESSAGES, block);
+        self.write_block(kind, entry.elapsed, &content)?;
+        self.index.push(entry);
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn write_block(&mut self, kind: u8, elapsed: Duration, content: &[u8]) -> anyhow::Result<()> {
+        self.writer.write_all(&[kind])?;
+        self.writer
+            .write_all(&(content.len() as u32).to_be_bytes())?;
+        self.writer
+            .write_all(&(elapsed.as_micros() as u64).to_be_bytes())?;
+        self.writer.write_all(content)?;
+        self.offset += BLOCK_HEADER_LENGTH + content.len() as u64;
+        Ok(())
+    }
+
+    /// Write the last block and the index, once
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.flush()?;
+        self.finished = true;
+        let mut index = Vec::new();
+        for entry in &self.index {
+            index.extend_from_slice(&entry.offset.to_be_bytes());
+            index.extend_from_slice(&(entry.elapsed.as_micros() as u64).to_be_bytes());
+            index.extend_from_slice(&entry.first_message.to_be_bytes());
+            index.extend_from_slice(&(entry.sessions.len() as u32).to_be_bytes());
+            for session in &entry.sessions {
+                index.extend_from_slice(&session.to_be_bytes());
+            }
+        }
+        let offset = self.offset;
+        self.write_block(INDEX, self.start.elapsed(), &index)?;
+        self.writer.write_all(&offset.to_be_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Splits the bytes of a block in their fields
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, length: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < length {
+            return Err(anyhow!("Truncated capture block"));
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn int32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn int64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+}
+
+/// Reads the messages of a capture file from the start, or from a block of
+/// its index
+pub struct CaptureFileReader<R: Read + Seek> {
+    reader: R,
+    index: Vec<IndexEntry>,
+    // The offset of the index, or of the end of the file without it
+    end: u64,
+    // The messages of the current block not read yet, in reverse order
+    pending: Vec<CapturedMessage>,
+}
+
+impl<R: Read + Seek> CaptureFileReader<R> {
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let mut header = vec![0; FORMAT_MAGIC.len() + 1];
+        reader.read_exact(&mut header)?;
+        if !header.starts_with(FORMAT_MAGIC) || header[FORMAT_MAGIC.len()] != BLOCKS_FORMAT_VERSION
+        {
+            return Err(anyhow!("Not a capture file with blocks"));
+        }
+        let length = reader.seek(SeekFrom::End(0))?;
+        let mut file = Self {
+            reader,
+            index: Vec::new(),
+            end: length,
+            pending: Vec::new(),
+        };
+        if file.read_index().is_err() {
+            file.scan_index()?;
+        }
+        file.reader.seek(SeekFrom::Start(header.len() as u64))?;
+        Ok(file)
+    }
+
+    /// The index written at the end of the file
+    fn read_index(&mut self) -> anyhow::Result<()> {
+        let length = self.end;
+        self.reader
+            .seek(SeekFrom::Start(length.saturating_sub(8)))?;
+        let offset = self.int64()?;
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let (kind, content) = self.block()?;
+        if kind != INDEX || offset + BLOCK_HEADER_LENGTH + content.len() as u64 + 8 != length {
+            return Err(anyhow!("No index"));
+        }
+        let mut fields = Fields(&content);
+        while !fields.0.is_empty() {
+            let offset = fields.int64()?;
+            let elapsed = Duration::from_micros(fields.int64()?);
+            let first_message = fields.int64()?;
+            let sessions = (0..fields.int32()?)
+                .map(|_| fields.int32())
+                .collect::<anyhow::Result<_>>()?;
+            self.index.push(IndexEntry {
+                offset,
+                elapsed,
+                first_message,
+                sessions,
+            });
+        }
+        self.end = offset;
+        Ok(())
+    }
+
+    /// The index of a file that was not finished, from its blocks
+    fn scan_index(&mut self) -> anyhow::Result<()> {
+        self.index.clear();
+        let mut offset = self
+            .reader
+            .seek(SeekFrom::Start(FORMAT_MAGIC.len() as u64 + 1))?;
+        let mut messages = 0;
+        // The last block can be truncated
+        while let Ok((kind, elapsed, content)) = self.block_with_time() {
+            if kind == INDEX {
+                break;
+            }
+            let messages_of_block = decode_block(kind, &content)?;
+            let mut sessions = Vec::new();
+            for message in &messages_of_block {
+                if !sessions.contains(&message.session) {
+                    sessions.push(message.session);
+                }
+            }
+            self.index.push(IndexEntry {
+                offset,
+                elapsed,
+                first_message: messages,
+                sessions,
+            });
+            messages += messages_of_block.len() as u64;
+            offset += BLOCK_HEADER_LENGTH + content.len() as u64;
+        }
+        self.end = offset;
+        Ok(())
+    }
+
+    fn int64(&mut self) -> anyhow::Result<u64> {
+        let mut bytes = [0; 8];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn block_with_time(&mut self) -> anyhow::Result<(u8, Duration, Vec<u8>)> {
+        let mut header = [0; BLOCK_HEADER_LENGTH as usize];
+        self.reader.read_exact(&mut header)?;
+        let mut fields = Fields(&header[1..]);
+        let length = fields.int32()?;
+        let elapsed = Duration::from_micros(fields.int64()?);
+        let mut content = vec![0; length as usize];
+        self.reader.read_exact(&mut content)?;
+        Ok((header[0], elapsed, content))
+    }
+
+    fn block(&mut self) -> anyhow::Result<(u8, Vec<u8>)> {
+        let (kind, _, content) = self.block_with_time()?;
+        Ok((kind, content))
+    }
+
+    /// The blocks of messages of the file
+    pub fn index(&self) -> &[IndexEntry] {
+        &self.index
+    }
+
+    /// Read from the first block with a message of the session, false when
+    /// there is none
+    pub fn seek_session(&mut self, session: u32) -> anyhow::Result<bool> {
+        let entry = self
+            .index
+            .iter()
+            .find(|entry| entry.sessions.contains(&session));
+        let Some(offset) = entry.map(|entry| entry.offset) else {
+            return Ok(false);
+        };
+        self.seek(offset)?;
+        Ok(true)
+    }
+
+    /// Read from the last block started at `elapsed` or before, so that the
+    /// messages sent at this time are read
+    pub fn seek_time(&mut self, elapsed: Duration) -> anyhow::Result<()> {
+        let offset = self
+            .index
+            .iter()
+            .take_while(|entry| entry.elapsed <= elapsed)
+            .last()
+            .or(self.index.first())
+            .map_or(self.end, |entry| entry.offset);
+        self.seek(offset)
+    }
+
+    fn seek(&mut self, offset: u64) -> anyhow::Result<()> {
+        self.pending.clear();
+        self.reader.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+
+    /// The next message, None at the end of the file
+    pub fn next_message(&mut self) -> anyhow::Result<Option<CapturedMessage>> {
+        while self.pending.is_empty() {
+            if self.reader.stream_position()? >= self.end {
+                return Ok(None);
+            }
+            let (kind, content) = self.block()?;
+            self.pending = decode_block(kind, &content)?;
+            self.pending.reverse();
+        }
+        Ok(self.pending.pop())
+    }
+}
+
+fn decode_block(kind: u8, content: &[u8]) -> anyhow::Result<Vec<CapturedMessage>> {
+    let content = match kind {
+        MESSAGES => Cow::Borrowed(content),
+        #[cfg(feature = "zstd")]
+        ZSTD_MESSAGES => Cow::Owned(zstd::stream::decode_all(content)?),
+        #[cfg(not(feature = "zstd"))]
+        ZSTD_MESSAGES => {
+            return Err(anyhow!(
+                "The capture is compressed with zstd, the zstd feature is required"
+            ));
+        }
+        _ => return Err(anyhow!("Invalid capture block {}", kind.escape_ascii())),
+    };
+    let mut fields = Fields(&content);
+    let mut messages = Vec::new();
+    while !fields.0.is_empty() {
+        let sender = match fields.take(1)?[0] {
+            b'F' => Direction::Frontend,
+            b'B' => Direction::Backend,
+            sender => return Err(anyhow!("Invalid sender {}", sender.escape_ascii())),
+        };
+        let session = fields.int32()?;
+        let length = fields.int32()?;
+        messages.push(CapturedMessage {
+            session,
+            sender,
+            bytes: fields.take(length as usize)?.to_vec(),
+        });
+    }
+    Ok(messages)
+}
+
+impl<R: Read + Seek> Iterator for CaptureFileReader<R> {
+    type Item = anyhow::Result<CapturedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_message().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::capture::Capture;
+    use std::io::Cursor;
+
+    #[test]
+    fn capture_file() -> anyhow::Result<()> {
+        let message = |session, message_type: u8| CapturedMessage {
+            session,
+            sender: Direction::Backend,
+            bytes: vec![message_type, 0, 0, 0, 5, b'I'],
+        };
+        let messages: Vec<CapturedMessage> = (0..10)
+            .map(|i| message(i / 4, b'Z'))
+            .chain([message(7, b'Z')])
+            .collect();
+        let mut writer = CaptureFileWriter::new(Cursor::new(Vec::new()))?;
+        // A block of 3 messages
+        writer.block_size = 45;
+        for message in &messages {
+            writer.write(message)?;
+        }
+        writer.finish()?;
+        let bytes = writer.into_inner().into_inner();
+
+        let mut file = CaptureFileReader::new(Cursor::new(&bytes))?;
+        assert_eq!(4, file.index().len());
+        assert_eq!(vec![2, 7], file.index()[3].sessions);
+        assert_eq!(messages, file.by_ref().collect::<anyhow::Result<Vec<_>>>()?);
+        assert!(file.seek_session(2)?);
+        assert_eq!(Some(messages[6].clone()), file.next_message()?);
+        assert!(!file.seek_session(3)?);
+        file.seek_time(Duration::ZERO)?;
+        assert_eq!(Some(messages[0].clone()), file.next_message()?);
+        assert_eq!(messages, Capture::from_bytes(&bytes)?.messages);
+
+        // Without the index and the end of the last block
+        let index = u64::from_be_bytes(bytes[bytes.len() - 8..].try_into()?);
+        let unfinished = &bytes[..index as usize - 1];
+        let file = CaptureFileReader::new(Cursor::new(unfinished))?;
+        assert_eq!(3, file.index().len());
+        assert_eq!(9, file.count());
+
+        Ok(())
+    }
+}
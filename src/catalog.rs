@@ -0,0 +1,462 @@
+//! The system catalogs of the tables declared in the configuration, so that
+//! the introspection of the ORMs and of the query checkers (e.g. Diesel's
+//! print-schema, SQLx's query! or Prisma's db pull) finds the schema:
+//!
+//! * `pg_namespace`, `pg_class`, `pg_attribute`, `pg_type` and
+//!   `pg_constraint` with the columns these tools read
+//! * `information_schema.tables` and `information_schema.columns`
+//!
+//! The catalogs are answered like the views of the virtual schema, see
+//! select_bound_view: the columns selected, joined on equal columns,
+//! `WHERE column = value` conditions, also on the parameters of a portal,
+//! and ORDER BY. The other queries (e.g. with subqueries or outer joins)
+//! still need a rule.
+
+use anyhow::anyhow;
+
+use crate::columns;
+use crate::config::{ServerConfig, TableConfig};
+use crate::handler::server::QueryResult;
+use crate::message::{ColumnDescription, PgType};
+use crate::value::PgValue;
+
+// The oids of the objects created by the users start there in PostgreSQL
+const FIRST_OID: u32 = 16384;
+const PG_CATALOG_OID: u32 = 11;
+const PUBLIC_OID: u32 = 2200;
+
+/// The rows of a catalog named as normalized (e.g. `pg_catalog . pg_class`),
+/// `None` for the other relations
+pub fn relation(config: &ServerConfig, name: &str) -> Option<anyhow::Result<QueryResult>> {
+    let catalog = Catalog { config };
+    Some(match name.strip_prefix("pg_catalog . ").unwrap_or(name) {
+        "pg_namespace" => catalog.pg_namespace(),
+        "pg_class" => catalog.pg_class(),
+        "pg_attribute" => catalog.pg_attribute(),
+        "pg_type" => catalog.pg_type(),
+        "pg_constraint" => catalog.pg_constraint(),
+        "information_schema . tables" => catalog.tables(),
+        "information_schema . columns" => catalog.columns(),
+        _ => return None,
+    })
+}
+
+struct Catalog<'a> {
+    config: &'a ServerConfig,
+}
+
+impl Catalog<'_> {
+    /// The schemas of the tables after pg_catalog and public, in order
+    fn namespaces(&self) -> Vec<(u32, &str)> {
+        let mut namespaces = vec![(PG_CATALOG_OID, "pg_catalog"), (PUBLIC_OID, "public")];
+        for table in &self.config.tables {
+            if !namespaces.iter().any(|(_, name)| *name == table.schema) {
+                let oid = FIRST_OID + namespaces.len() as u32 - 2;
+                namespaces.push((oid, &table.schema));
+            }
+        }
+        namespaces
+    }
+
+    fn namespace_oid(&self, table: &TableConfig) -> u32 {
+        self.namespaces()
+            .into_iter()
+            .find(|(_, name)| *name == table.schema)
+            .map_or(PUBLIC_OID, |(oid, _)| oid)
+    }
+
+    /// The oid of the first table, after the schemas
+    fn first_table_oid(&self) -> u32 {
+        FIRST_OID + self.namespaces().len() as u32 - 2
+    }
+
+    /// The tables with their oid
+    fn tables_oids(&self) -> impl Iterator<Item = (u32, &TableConfig)> {
+        (self.first_table_oid()..).zip(&self.config.tables)
+    }
+
+    fn pg_namespace(&self) -> anyhow::Result<QueryResult> {
+        let rows = self
+            .namespaces()
+            .into_iter()
+            .map(|(oid, name)| vec![PgValue::Oid(oid), PgValue::from(name)])
+            .collect();
+        result(columns![("oid", Oid), ("nspname", Text)], rows)
+    }
+
+    fn pg_class(&self) -> anyhow::Result<QueryResult> {
+        let rows = self
+            .tables_oids()
+            .map(|(oid, table)| {
+                vec![
+                    PgValue::Oid(oid),
+                    PgValue::from(&table.name[..]),
+                    PgValue::Oid(self.namespace_oid(table)),
+                    PgValue::from("r"),
+                    PgValue::Int4(table.columns.len() as i32),
+                ]
+            })
+            .collect();
+        result(
+            columns![
+                ("oid", Oid),
+                ("relname", Text),
+                ("relnamespace", Oid),
+                ("relkind", Text),
+                ("relnatts", Int4)
+            ],
+            rows,
+        )
+    }
+
+    fn pg_attribute(&self) -> anyhow::Result<QueryResult> {
+        let mut rows = Vec::new();
+        for (oid, table) in self.tables_oids() {
+            for (attnum, column) in (1..).zip(&table.columns) {
+                let pg_type = column.pg_type.parse::<PgType>()?;
+                rows.push(vec![
+                    PgValue::Oid(oid),
+                    PgValue::from(&column.name[..]),
+                    PgValue::Oid(i32::from(&pg_type) as u32),
+                    PgValue::Int4(attnum),
+                    PgValue::Int4(i32::from(pg_type.typlen())),
+                    PgValue::Int4(pg_type.typmod()),
+                    PgValue::Bool(!column.nullable || column.primary_key),
+                    PgValue::Bool(false),
+                    PgValue::Bool(false),
+                ]);
+            }
+        }
+        result(
+            columns![
+                ("attrelid", Oid),
+                ("attname", Text),
+                ("atttypid", Oid),
+                ("attnum", Int4),
+                ("attlen", Int4),
+                ("atttypmod", Int4),
+                ("attnotnull", Bool),
+                ("atthasdef", Bool),
+                ("attisdropped", Bool)
+            ],
+            rows,
+        )
+    }
+
+    fn pg_type(&self) -> anyhow::Result<QueryResult> {
+        let rows = PgType::ALL
+            .iter()
+            .map(|pg_type| {
+                vec![
+                    PgValue::Oid(i32::from(pg_type) as u32),
+                    PgValue::from(pg_type.typname()),
+                    PgValue::Oid(PG_CATALOG_OID),
+                    PgValue::Int4(i32::from(pg_type.typlen())),
+                    PgValue::from("b"),
+                    PgValue::from(typcategory(*pg_type)),
+                    PgValue::Oid(0),
+                    PgValue::Oid(0),
+                    PgValue::Oid(0),
+                ]
+            })
+            .collect();
+        result(
+            columns![
+                ("oid", Oid),
+                ("typname", Text),
+                ("typnamespace", Oid),
+                ("typlen", Int4),
+                ("typtype", Text),
+                ("typcategory", Text),
+                ("typelem", Oid),
+                ("typbasetype", Oid),
+                ("typrelid", Oid)
+            ],
+            rows,
+        )
+    }
+
+    /// The primary keys and the foreign keys, the arrays of attnums are in
+    /// their text representation
+    fn pg_constraint(&self) -> anyhow::Result<QueryResult> {
+        let mut rows = Vec::new();
+        let first_table_oid = self.first_table_oid();
+        let mut oid = first_table_oid + self.config.tables.len() as u32;
+        let mut constraint =
+            |name: String, kind, (relid, table), referenced, keys, foreign_keys| {
+                rows.push(vec![
+                    PgValue::Oid(oid),
+                    PgValue::Text(name),
+                    PgValue::Oid(self.namespace_oid(table)),
+                    PgValue::from(kind),
+                    PgValue::Oid(relid),
+                    PgValue::Oid(referenced),
+                    PgValue::Text(keys),
+                    PgValue::Text(foreign_keys),
+                ]);
+                oid += 1;
+            };
+        for (relid, table) in self.tables_oids() {
+            let keys: Vec<i32> = (1..)
+                .zip(&table.columns)
+                .filter(|(_, column)| column.primary_key)
+                .map(|(attnum, _)| attnum)
+                .collect();
+            if !keys.is_empty() {
+                let name = format!("{}_pkey", table.name);
+                constraint(name, "p", (relid, table), 0, array(&keys), String::new());
+            }
+            for (attnum, column) in (1..).zip(&table.columns) {
+                let Some(references) = &column.references else {
+                    continue;
+                };
+                let (i, j) = self
+                    .config
+                    .referenced(references)
+                    .ok_or(anyhow!("Unknown column \"{references}\""))?;
+                let name = format!("{}_{}_fkey", table.name, column.name);
+                let referenced = first_table_oid + i as u32;
+                let foreign_keys = array(&[j + 1]);
+                constraint(
+                    name,
+                    "f",
+                    (relid, table),
+                    referenced,
+                    array(&[attnum]),
+                    foreign_keys,
+                );
+            }
+        }
+        result(
+            columns![
+                ("oid", Oid),
+                ("conname", Text),
+                ("connamespace", Oid),
+                ("contype", Text),
+                ("conrelid", Oid),
+                ("confrelid", Oid),
+                ("conkey", Text),
+                ("confkey", Text)
+            ],
+            rows,
+        )
+    }
+
+    fn tables(&self) -> anyhow::Result<QueryResult> {
+        let rows = self
+            .config
+            .tables
+            .iter()
+            .map(|table| {
+                vec![
+                    PgValue::from("postgres"),
+                    PgValue::from(&table.schema[..]),
+                    PgValue::from(&table.name[..]),
+                    PgValue::from("BASE TABLE"),
+                ]
+            })
+            .collect();
+        result(
+            columns![
+                ("table_catalog", Text),
+                ("table_schema", Text),
+                ("table_name", Text),
+                ("table_type", Text)
+            ],
+            rows,
+        )
+    }
+
+    fn columns(&self) -> anyhow::Result<QueryResult> {
+        let mut rows = Vec::new();
+        for table in &self.config.tables {
+            for (position, column) in (1..).zip(&table.columns) {
+                let pg_type = column.pg_type.parse::<PgType>()?;
+                let nullable = column.nullable && !column.primary_key;
+                rows.push(vec![
+                    PgValue::from("postgres"),
+                    PgValue::from(&table.schema[..]),
+                    PgValue::from(&table.name[..]),
+                    PgValue::from(&column.name[..]),
+                    PgValue::Int4(position),
+                    PgValue::from(if nullable { "YES" } else { "NO" }),
+                    PgValue::from(data_type(pg_type)),
+                    PgValue::from(pg_type.typname()),
+                ]);
+            }
+        }
+        result(
+            columns![
+                ("table_catalog", Text),
+                ("table_schema", Text),
+                ("table_name", Text),
+                ("column_name", Text),
+                ("ordinal_position", Int4),
+                ("is_nullable", Text),
+                ("data_type", Text),
+                ("udt_name", Text)
+            ],
+            rows,
+        )
+    }
+}
+
+fn result(
+    columns: anyhow::Result<Vec<ColumnDescription>>,
+    rows: Vec<Vec<PgValue>>,
+) -> anyhow::Result<QueryResult> {
+    Ok(QueryResult {
+        columns: columns?,
+        command_tag: format!("SELECT {}", rows.len()),
+        rows,
+        error: None,
+    })
+}
+
+/// The text representation of an array, e.g. {1,2}
+fn array<T: ToString>(elements: &[T]) -> String {
+    let elements: Vec<String> = elements.iter().map(ToString::to_string).collect();
+    format!("{{{}}}", elements.join(","))
+}
+
+fn typcategory(pg_type: PgType) -> &'static str {
+    match pg_type {
+        PgType::Bool => "B",
+        PgType::Int4 | PgType::Int8 | PgType::Oid | PgType::Numeric => "N",
+        PgType::Text => "S",
+        PgType::Date | PgType::Timestamp => "D",
+        PgType::Interval => "T",
+        PgType::Json | PgType::Jsonb | PgType::Uuid | PgType::Bytea => "U",
+    }
+}
+
+/// The name of the type in information_schema.columns
+fn data_type(pg_type: PgType) -> &'static str {
+    match pg_type {
+        PgType::Bool => "boolean",
+        PgType::Int4 => "integer",
+        PgType::Int8 => "bigint",
+        PgType::Text => "text",
+        PgType::Oid => "oid",
+        PgType::Date => "date",
+        PgType::Timestamp => "timestamp without time zone",
+        PgType::Interval => "interval",
+        PgType::Numeric => "numeric",
+        PgType::Json => "json",
+        PgType::Jsonb => "jsonb",
+        PgType::Uuid => "uuid",
+        PgType::Bytea => "bytea",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sessions::select_bound_view;
+
+    #[test]
+    fn catalog() -> anyhow::Result<()> {
+        let config = ServerConfig::from_toml(
+            r#"
+            [[tables]]
+            name = "users"
+            columns = [
+                { name = "id", type = "int8", primary_key = true },
+                { name = "email", type = "text", nullable = true },
+            ]
+
+            [[tables]]
+            name = "orders"
+            schema = "shop"
+            columns = [
+                { name = "id", type = "int8", primary_key = true },
+                { name = "user_id", type = "int8", references = "users.id" },
+            ]
+            "#,
+        )?;
+        let relation = |name| relation(&config, name).ok_or(anyhow!("{name} not answered"))?;
+
+        let namespaces = relation("pg_catalog . pg_namespace")?;
+        assert_eq!(
+            vec![PgValue::Oid(16384), PgValue::from("shop")],
+            namespaces.rows[2]
+        );
+        let attributes = relation("pg_attribute")?;
+        assert_eq!(4, attributes.rows.len());
+        assert_eq!(
+            vec![
+                PgValue::Oid(16385),
+                PgValue::from("email"),
+                PgValue::Oid(25)
+            ],
+            attributes.rows[1][..3]
+        );
+        assert_eq!(PgValue::Bool(false), attributes.rows[1][6]);
+        let constraints = relation("pg_constraint")?;
+        assert_eq!(
+            vec![
+                PgValue::Oid(16389),
+                PgValue::from("orders_user_id_fkey"),
+                PgValue::Oid(16384),
+                PgValue::from("f"),
+                PgValue::Oid(16386),
+                PgValue::Oid(16385),
+                PgValue::from("{2}"),
+                PgValue::from("{1}"),
+            ],
+            constraints.rows[2]
+        );
+        let columns = relation("information_schema . columns")?;
+        assert_eq!("SELECT 4", columns.command_tag);
+        assert_eq!(PgValue::from("YES"), columns.rows[1][5]);
+        assert_eq!(PgValue::from("bigint"), columns.rows[3][6]);
+        assert!(super::relation(&config, "pg_proc").is_none());
+
+        // Like the column inference of Diesel, from a prepared statement
+        let catalogs = |name: &str| super::relation(&config, name);
+        let query = "SELECT a.attname, t.typname AS type, a.attnotnull \
+            FROM pg_catalog.pg_attribute a \
+            INNER JOIN pg_catalog.pg_type t ON a.atttypid = t.oid \
+            JOIN pg_class c ON c.oid = a.attrelid \
+            WHERE c.relname = $1 AND a.attisdropped = false \
+            ORDER BY a.attnum DESC";
+        let columns = select_bound_view(query, &[Some(String::from("orders"))], catalogs)
+            .ok_or(anyhow!("join not answered"))??;
+        assert_eq!(
+            vec!["attname", "type", "attnotnull"],
+            columns
+                .columns
+                .iter()
+                .map(|column| column.name.to_str())
+                .collect::<Result<Vec<_>, _>>()?
+        );
+        assert_eq!(
+            vec![
+                vec![
+                    PgValue::from("user_id"),
+                    PgValue::from("int8"),
+                    PgValue::Bool(true)
+                ],
+                vec![
+                    PgValue::from("id"),
+                    PgValue::from("int8"),
+                    PgValue::Bool(true)
+                ],
+            ],
+            columns.rows
+        );
+        let ambiguous = select_bound_view(
+            "SELECT oid FROM pg_class JOIN pg_namespace ON relnamespace = pg_namespace.oid",
+            &[],
+            catalogs,
+        );
+        assert!(matches!(ambiguous, Some(Err(e)) if e.to_string().contains("ambiguous")));
+        // The parameters not bound are left to the executor
+        assert!(
+            select_bound_view("SELECT relname FROM pg_class WHERE oid = $1", &[], catalogs)
+                .is_none()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+//! The compression of the messages negotiated with the `_pq_.compression`
+//! protocol option, to experiment with the compression proposed upstream.
+//!
+//! The frontend lists the algorithms it supports in the startup message
+//! (e.g. `_pq_.compression=zstd,lz4`), the backend reports the one chosen
+//! with a ParameterStatus of the same name. Both peers compress the messages
+//! following the first ReadyForQuery, each one is sent whole in a
+//! CompressedData message:
+//!
+//! * Byte1('z')
+//! * Int32 Length of message contents in bytes, including self.
+//! * Byte[n] The compressed message, with its type and length.
+//!
+//! The algorithms are enabled by the features of the same name. Without
+//! them, the option is not recognized and the backend lists it in
+//! NegotiateProtocolVersion.
+
+use anyhow::anyhow;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::message::MessageHeader;
+
+pub const COMPRESSION_OPTION: &str = "_pq_.compression";
+pub const COMPRESSED_DATA: u8 = b'z';
+
+/// A compression algorithm applied to each message
+pub trait Compressor: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn compress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>>;
+    fn decompress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+#[cfg(feature = "zstd")]
+pub struct Zstd {
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor for Zstd {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn compress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(zstd::bulk::compress(data, self.level)?)
+    }
+
+    fn decompress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(zstd::stream::decode_all(data)?)
+    }
+}
+
+#[cfg(feature = "lz4")]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4 {
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn compress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(lz4_flex::compress_prepend_size(data))
+    }
+
+    fn decompress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(lz4_flex::decompress_size_prepended(data)?)
+    }
+}
+
+/// The algorithms built in, in order of preference
+// Pushed one by one since each one has its feature
+#[allow(clippy::vec_init_then_push)]
+pub fn available() -> Vec<Arc<dyn Compressor>> {
+    #[allow(unused_mut)]
+    let mut compressors: Vec<Arc<dyn Compressor>> = Vec::new();
+    #[cfg(feature = "zstd")]
+    compressors.push(Arc::new(Zstd { level: 3 }));
+    #[cfg(feature = "lz4")]
+    compressors.push(Arc::new(Lz4));
+    compressors
+}
+
+/// The first algorithm of a comma separated list that is available
+pub fn negotiate(requested: &str) -> Option<Arc<dyn Compressor>> {
+    let available = available();
+    requested.split(',').map(str::trim).find_map(|name| {
+        available
+            .iter()
+            .find(|compressor| compressor.name() == name)
+            .cloned()
+    })
+}
+
+/// Turns the compression on for a CompressedReader and a CompressedWriter,
+/// the handlers call it between two messages once it is negotiated
+#[derive(Clone, Default)]
+pub struct CompressionSwitch(Arc<Mutex<Option<Arc<dyn Compressor>>>>);
+
+impl CompressionSwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn activate(&self, compressor: Arc<dyn Compressor>) {
+        *self.0.lock().expect("compression lock") = Some(compressor);
+    }
+
+    pub fn active(&self) -> Option<Arc<dyn Compressor>> {
+        self.0.lock().expect("compression lock").clone()
+    }
+}
+
+fn invalid_data(e: anyhow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Reads the CompressedData messages once the switch is on, and gives the
+/// messages they contain
+pub struct CompressedReader<R> {
+    inner: R,
+    switch: CompressionSwitch,
+    decompressed: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> CompressedReader<R> {
+    pub fn new(inner: R, switch: CompressionSwitch) -> Self {
+        Self {
+            inner,
+            switch,
+            decompressed: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for CompressedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.decompressed.len() {
+            let Some(compressor) = self.switch.active() else {
+                return self.inner.read(buf);
+            };
+            let mut header = [0; 5];
+            self.inner.read_exact(&mut header)?;
+            if header[0] != COMPRESSED_DATA {
+                return Err(invalid_data(anyhow!(
+                    "CompressedData expected, got {:?}",
+                    header[0] as char
+                )));
+            }
+            let length = i32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+            let length = usize::try_from(length - 4)
+                .map_err(|_| invalid_data(anyhow!("Invalid CompressedData length {length}")))?;
+            let mut compressed = vec![0; length];
+            self.inner.read_exact(&mut compressed)?;
+            self.decompressed = compressor.decompress(&compressed).map_err(invalid_data)?;
+            self.position = 0;
+        }
+        let count = buf.len().min(self.decompressed.len() - self.position);
+        buf[..count].copy_from_slice(&self.decompressed[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Sends each message in a CompressedData message once the switch is on,
+/// the bytes are kept until the message is complete
+pub struct CompressedWriter<W> {
+    inner: W,
+    switch: CompressionSwitch,
+    pending: Vec<u8>,
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(inner: W, switch: CompressionSwitch) -> Self {
+        Self {
+            inner,
+            switch,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The length of the first message pending when it is complete
+    fn complete_message(&self) -> Option<usize> {
+        let header = self.pending.get(..5)?;
+        let length = i32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let length = 1 + usize::try_from(length).ok()?;
+        (self.pending.len() >= length).then_some(length)
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(compressor) = self.switch.active() else {
+            return self.inner.write(buf);
+        };
+        self.pending.extend_from_slice(buf);
+        while let Some(length) = self.complete_message() {
+            let compressed = compressor
+                .compress(&self.pending[..length])
+                .map_err(invalid_data)?;
+            let mut message = Vec::with_capacity(5 + compressed.len());
+            message.push(COMPRESSED_DATA);
+            let compressed_length =
+                MessageHeader::length_for(compressed.len()).map_err(invalid_data)?;
+            message.extend_from_slice(&compressed_length.to_be_bytes());
+            message.extend_from_slice(&compressed);
+            self.inner.write_all(&message)?;
+            self.pending.drain(..length);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(all(test, feature = "lz4"))]
+mod test {
+    use super::*;
+    use crate::handler::server::{Authentication, QueryResult};
+    use crate::handler::{client, server};
+    use crate::message::{ColumnDescription, PgType};
+    use crate::value::PgValue;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Run a query through a server accepting the compression or not,
+    /// whether the client compressed the messages
+    fn compressed_query(accept: bool) -> anyhow::Result<bool> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let server = thread::spawn(move || -> anyhow::Result<()> {
+            let stream = listener.accept()?.0;
+            let switch = CompressionSwitch::new();
+            let mut handler = server::Handler::from_parts(
+                CompressedReader::new(stream.try_clone()?, switch.clone()),
+                CompressedWriter::new(stream, switch.clone()),
+            );
+            if accept {
+                handler.compression = Some(switch);
+            }
+            handler.authentication_handler(&|_| Authentication::Md5(None))?;
+            // Until the client disconnects
+            while let Ok(true) = handler.query_handler(&|_| QueryResult {
+                columns: vec![ColumnDescription::new("n", PgType::Int4).expect("column")],
+                rows: vec![vec![PgValue::Int4(1)]],
+                command_tag: String::from("SELECT 1"),
+                error: None,
+            }) {}
+            Ok(())
+        });
+
+        let stream = TcpStream::connect(address)?;
+        let switch = CompressionSwitch::new();
+        let mut handler = client::Handler::from_parts(
+            CompressedReader::new(stream.try_clone()?, switch.clone()),
+            CompressedWriter::new(stream, switch.clone()),
+        );
+        handler.compression = Some(switch.clone());
+        handler.md5_authentication_handler()?;
+        assert_eq!(1, handler.simple_query_handler("SELECT 1")?.len());
+        drop(handler);
+        server
+            .join()
+            .map_err(|_| anyhow!("The server thread panicked"))??;
+        Ok(switch.active().is_some())
+    }
+
+    #[test]
+    fn compression_negotiation() -> anyhow::Result<()> {
+        assert_eq!(Some("lz4"), negotiate("snappy, lz4").map(|c| c.name()));
+        assert!(negotiate("snappy").is_none());
+
+        assert!(compressed_query(true)?);
+        // The option is listed in NegotiateProtocolVersion
+        assert!(!compressed_query(false)?);
+
+        Ok(())
+    }
+}
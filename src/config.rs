@@ -0,0 +1,662 @@
+//! The configuration of a whole server in a TOML file, e.g.:
+//!
+//! ```toml
+//! # Relative to the directory of the configuration file
+//! scenarios = ["scenarios/users.toml"]
+//! # The queries sent on connect by these drivers, after the scenarios
+//! drivers = ["jdbc", "sqlalchemy"]
+//!
+//! # The tables shown by the system catalogs, in the schema public unless
+//! # another one is given
+//! [[tables]]
+//! name = "users"
+//! columns = [
+//!     { name = "id", type = "int8", primary_key = true },
+//!     { name = "email", type = "text", nullable = true },
+//! ]
+//!
+//! [[tables]]
+//! name = "orders"
+//! columns = [
+//!     { name = "id", type = "int8", primary_key = true },
+//!     { name = "user_id", type = "int8", references = "users.id" },
+//! ]
+//!
+//! # Both 127.0.0.1 and ::1
+//! [[listeners]]
+//! address = "localhost:5432"
+//!
+//! [[users]]
+//! name = "app"
+//! password = "secret"
+//!
+//! # The first rule matching the user and the database is used, like in
+//! # pg_hba.conf
+//! [[auth]]
+//! user = "app"
+//! method = "md5"
+//!
+//! [[auth]]
+//! method = "reject"
+//!
+//! # The defaults of the output parameters, the frontend can override them
+//! [parameters]
+//! DateStyle = "ISO, DMY"
+//!
+//! # Checked before anything is read, a denied network wins
+//! [access]
+//! allow = ["127.0.0.1", "10.0.0.0/8"]
+//! deny = ["10.0.1.0/24"]
+//! error_response = true
+//!
+//! # Closing the connections not authenticated in time
+//! [connections]
+//! authentication_timeout_ms = 10000
+//! max_startup_bytes = 16384
+//! # By source address
+//! max_connections_per_second = 10
+//! max_sessions = 5
+//! # The addresses above are the ones of the PROXY protocol headers
+//! proxy_protocol = true
+//!
+//! # The answers to the encryption requests: deny, error or close
+//! [negotiation]
+//! ssl = "deny"
+//! gssenc = "error"
+//!
+//! # The results and the parameters over them fail with 54011 or 54000
+//! [limits]
+//! max_columns = 1600
+//! max_field_length = 1048576
+//!
+//! [faults]
+//! flush_every_bytes = 100
+//! memory_limit = 1048576
+//! skip_corrupted_messages = true
+//! # Each message in two writes, 20 ms apart
+//! split_messages_at = 3
+//! split_delay_ms = 20
+//! # duplicate_ready_for_query, garbled_ready_for_query or
+//! # data_row_before_row_description
+//! protocol_violation = "duplicate_ready_for_query"
+//!
+//! [logging]
+//! level = "debug"
+//! slow_query_ms = 100
+//! ```
+//!
+//! The errors found after parsing name the offending key, e.g.
+//! `auth[0].user: Unknown user "ap"`.
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::Level;
+
+use crate::access::{AccessList, Cidr};
+use crate::drivers::Driver;
+use crate::dual_stack;
+use crate::handler::FlushPolicy;
+use crate::handler::actor::StartupLimits;
+use crate::handler::desync::DesyncPolicy;
+use crate::handler::server::{
+    Authentication, Fragmentation, Handler, MemoryLimit, NegotiationPolicy, ProtocolLimits,
+    ProtocolViolation, StartupParameters,
+};
+use crate::message::PgType;
+use crate::rate_limiter::RateLimiter;
+use crate::scenario::Scenario;
+use crate::value::OutputSettings;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    // Bound on each address of a name, "*:5432" for any interface in IPv4
+    // and IPv6
+    pub address: String,
+}
+
+/// A table of the schema shown by the system catalogs, see catalog
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TableConfig {
+    pub name: String,
+    #[serde(default = "default_schema")]
+    pub schema: String,
+    pub columns: Vec<ColumnConfig>,
+}
+
+fn default_schema() -> String {
+    String::from("public")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnConfig {
+    pub name: String,
+    // The name of the type in pg_type, e.g. int8
+    #[serde(rename = "type")]
+    pub pg_type: String,
+    #[serde(default)]
+    pub nullable: bool,
+    #[serde(default)]
+    pub primary_key: bool,
+    // A foreign key, "table.column"
+    pub references: Option<String>,
+}
+
+//FIXME: The certificates are checked but the server doesn't negotiate TLS
+//yet, TestServer::from_config refuses this section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Trust,
+    Md5,
+    Reject,
+}
+
+/// A rule matching the connections by user and database, any when not set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthRule {
+    pub user: Option<String>,
+    pub database: Option<String>,
+    pub method: AuthMethod,
+}
+
+impl AuthRule {
+    fn matches(&self, startup: &StartupParameters) -> bool {
+        let matches = |expected: &Option<String>, actual: Option<&str>| match expected {
+            Some(expected) => Some(&expected[..]) == actual,
+            None => true,
+        };
+        matches(&self.user, startup.user()) && matches(&self.database, startup.database())
+    }
+}
+
+/// The networks allowed to connect, any when `allow` is empty
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // Tell the connections denied why before closing them
+    #[serde(default)]
+    pub error_response: bool,
+}
+
+impl AccessConfig {
+    pub fn access_list(&self) -> anyhow::Result<AccessList> {
+        let parse = |key: &str, networks: &[String]| {
+            networks
+                .iter()
+                .enumerate()
+                .map(|(i, network)| {
+                    network
+                        .parse::<Cidr>()
+                        .map_err(|e| anyhow!("access.{key}[{i}]: {e}"))
+                })
+                .collect::<anyhow::Result<Vec<Cidr>>>()
+        };
+        Ok(AccessList {
+            allow: parse("allow", &self.allow)?,
+            deny: parse("deny", &self.deny)?,
+        })
+    }
+}
+
+/// The bounds on the connections, PostgreSQL's defaults when not set
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub authentication_timeout_ms: Option<u64>,
+    // Received before the end of the authentication
+    pub max_startup_bytes: Option<usize>,
+    // By source address, the connections over the limits are refused with
+    // 08004 and 53300
+    pub max_connections_per_second: Option<usize>,
+    pub max_sessions: Option<usize>,
+    // Behind a load balancer, the connections start with a PROXY protocol
+    // header giving the address of the client
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+impl ConnectionConfig {
+    pub fn startup_limits(&self) -> StartupLimits {
+        let default = StartupLimits::default();
+        StartupLimits {
+            timeout: self
+                .authentication_timeout_ms
+                .map_or(default.timeout, Duration::from_millis),
+            max_bytes: self.max_startup_bytes.unwrap_or(default.max_bytes),
+        }
+    }
+
+    pub fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter::new(self.max_connections_per_second, self.max_sessions)
+    }
+}
+
+/// The answers to the SSLRequest and the GSSENCRequest, see NegotiationPolicy
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NegotiationConfig {
+    #[serde(default)]
+    pub ssl: NegotiationPolicy,
+    #[serde(default)]
+    pub gssenc: NegotiationPolicy,
+}
+
+/// The bounds of the rows, see ProtocolLimits for the defaults
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_columns: Option<usize>,
+    pub max_field_length: Option<usize>,
+}
+
+impl LimitsConfig {
+    pub fn protocol_limits(&self) -> ProtocolLimits {
+        let default = ProtocolLimits::default();
+        ProtocolLimits {
+            max_columns: self.max_columns.unwrap_or(default.max_columns),
+            max_field_length: self.max_field_length.unwrap_or(default.max_field_length),
+        }
+    }
+}
+
+/// The misbehaviors of the server, to test how the frontends cope with them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultConfig {
+    // Split the results in small writes, see FlushPolicy
+    pub flush_every_bytes: Option<usize>,
+    pub flush_interval_ms: Option<u64>,
+    // The queries with larger results fail with out_of_memory
+    pub memory_limit: Option<usize>,
+    #[serde(default)]
+    pub skip_corrupted_messages: bool,
+    // The bytes of the first write of each message, see Fragmentation
+    pub split_messages_at: Option<usize>,
+    pub split_delay_ms: Option<u64>,
+    pub protocol_violation: Option<ProtocolViolation>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: Option<String>,
+    // The queries running longer are logged with their session
+    pub slow_query_ms: Option<u64>,
+}
+
+impl LoggingConfig {
+    pub fn level(&self) -> Option<Level> {
+        self.level.as_ref().and_then(|level| level.parse().ok())
+    }
+}
+
+/// The configuration of the listeners, the authentication, the sessions and
+/// the scenarios of a server, see the module documentation
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub scenarios: Vec<PathBuf>,
+    #[serde(default)]
+    pub drivers: Vec<Driver>,
+    #[serde(default)]
+    pub tables: Vec<TableConfig>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    // Without rules, any user is accepted with any MD5 password
+    #[serde(default)]
+    pub auth: Vec<AuthRule>,
+    #[serde(default)]
+    pub parameters: BTreeMap<String, String>,
+    #[serde(default)]
+    pub access: AccessConfig,
+    #[serde(default)]
+    pub connections: ConnectionConfig,
+    #[serde(default)]
+    pub negotiation: NegotiationConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub faults: FaultConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+impl ServerConfig {
+    /// Parse and validate a configuration, the paths are relative to the
+    /// current directory
+    pub fn from_toml(content: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load a configuration file, the paths are relative to its directory
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let error = |e: anyhow::Error| anyhow!("{}: {e}", path.display());
+        let mut config: Self =
+            toml::from_str(&fs::read_to_string(path).map_err(|e| error(e.into()))?)
+                .map_err(|e| error(e.into()))?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for scenario in &mut config.scenarios {
+            *scenario = directory.join(&scenario);
+        }
+        if let Some(tls) = &mut config.tls {
+            tls.cert = directory.join(&tls.cert);
+            tls.key = directory.join(&tls.key);
+        }
+        config.validate().map_err(error)?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for (i, listener) in self.listeners.iter().enumerate() {
+            dual_stack::listen_addresses(&listener.address)
+                .map_err(|e| anyhow!("listeners[{i}].address: {e}"))?;
+        }
+        if let Some(tls) = &self.tls {
+            for (key, path) in [("cert", &tls.cert), ("key", &tls.key)] {
+                fs::metadata(path).map_err(|e| anyhow!("tls.{key}: {}: {e}", path.display()))?;
+            }
+        }
+        for (i, user) in self.users.iter().enumerate() {
+            if self.users[..i].iter().any(|other| other.name == user.name) {
+                return Err(anyhow!("users[{i}].name: Duplicate user \"{}\"", user.name));
+            }
+        }
+        for (i, rule) in self.auth.iter().enumerate() {
+            if let (AuthMethod::Md5, Some(name)) = (rule.method, &rule.user)
+                && !self.users.is_empty()
+                && self.password(name).is_none()
+            {
+                return Err(anyhow!("auth[{i}].user: Unknown user \"{name}\""));
+            }
+        }
+        for (i, table) in self.tables.iter().enumerate() {
+            for (j, column) in table.columns.iter().enumerate() {
+                column
+                    .pg_type
+                    .parse::<PgType>()
+                    .map_err(|e| anyhow!("tables[{i}].columns[{j}].type: {e}"))?;
+                if let Some(references) = &column.references
+                    && self.referenced(references).is_none()
+                {
+                    return Err(anyhow!(
+                        "tables[{i}].columns[{j}].references: Unknown column \"{references}\""
+                    ));
+                }
+            }
+        }
+        let mut settings = OutputSettings::default();
+        for (name, value) in &self.parameters {
+            settings
+                .set(name, value)
+                .map_err(|e| anyhow!("parameters.{name}: {e}"))?;
+        }
+        self.access.access_list()?;
+        if self.connections.authentication_timeout_ms == Some(0) {
+            return Err(anyhow!(
+                "connections.authentication_timeout_ms: Must be at least 1"
+            ));
+        }
+        // The counts and the lengths of the messages are on 16 and 32 bits
+        if let Some(max) = self.limits.max_columns
+            && !(1..=i16::MAX as usize).contains(&max)
+        {
+            return Err(anyhow!("limits.max_columns: Must be between 1 and 32767"));
+        }
+        if let Some(max) = self.limits.max_field_length
+            && max > i32::MAX as usize
+        {
+            return Err(anyhow!(
+                "limits.max_field_length: Must be at most 2147483647"
+            ));
+        }
+        if self.faults.flush_every_bytes.is_some() && self.faults.flush_interval_ms.is_some() {
+            return Err(anyhow!(
+                "faults.flush_interval_ms: Conflicts with faults.flush_every_bytes"
+            ));
+        }
+        match (self.faults.split_messages_at, self.faults.split_delay_ms) {
+            (Some(0), _) => return Err(anyhow!("faults.split_messages_at: Must be at least 1")),
+            (None, Some(_)) => {
+                return Err(anyhow!(
+                    "faults.split_delay_ms: Requires faults.split_messages_at"
+                ));
+            }
+            _ => (),
+        }
+        if let Some(level) = &self.logging.level {
+            level
+                .parse::<Level>()
+                .map_err(|e| anyhow!("logging.level: {e}"))?;
+        }
+        for (i, path) in self.scenarios.iter().enumerate() {
+            Scenario::load(path)
+                .and_then(|scenario| scenario.executor())
+                .map_err(|e| anyhow!("scenarios[{i}]: {}: {e}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// The indexes of the table and of the column of a foreign key
+    pub fn referenced(&self, references: &str) -> Option<(usize, usize)> {
+        let (table, column) = references.split_once('.')?;
+        self.tables.iter().enumerate().find_map(|(i, candidate)| {
+            let j = candidate
+                .columns
+                .iter()
+                .position(|candidate| candidate.name == column)?;
+            (candidate.name == table).then_some((i, j))
+        })
+    }
+
+    fn password(&self, user: &str) -> Option<&str> {
+        self.users
+            .iter()
+            .find(|candidate| candidate.name == user)
+            .map(|user| &user.password[..])
+    }
+
+    /// The rules of all the scenarios, in order, then the ones of the
+    /// drivers. The version is the one of the first scenario setting it.
+    pub fn scenario(&self) -> anyhow::Result<Scenario> {
+        let mut scenario = Scenario::default();
+        for path in &self.scenarios {
+            let loaded = Scenario::load(path)?;
+            scenario.rules.extend(loaded.rules);
+            scenario.version = scenario.version.or(loaded.version);
+        }
+        for driver in &self.drivers {
+            scenario.rules.extend(driver.scenario()?.rules);
+        }
+        Ok(scenario)
+    }
+
+    /// The authentication of the first rule matching the startup parameters,
+    /// the connections matching no rule are rejected
+    pub fn authentication(&self, startup: &StartupParameters) -> Authentication {
+        if self.auth.is_empty() {
+            return Authentication::Md5(None);
+        }
+        match self.auth.iter().find(|rule| rule.matches(startup)) {
+            Some(AuthRule {
+                method: AuthMethod::Trust,
+                ..
+            }) => Authentication::Trust,
+            Some(AuthRule {
+                method: AuthMethod::Md5,
+                ..
+            }) if self.users.is_empty() => Authentication::Md5(None),
+            Some(AuthRule {
+                method: AuthMethod::Md5,
+                ..
+            }) => match startup.user().and_then(|user| self.password(user)) {
+                Some(password) => Authentication::Md5(Some(String::from(password))),
+                None => Authentication::Reject,
+            },
+            Some(AuthRule {
+                method: AuthMethod::Reject,
+                ..
+            })
+            | None => Authentication::Reject,
+        }
+    }
+
+    /// Apply the parameters and the faults to a new session
+    pub fn configure<R: Read, W: Write>(&self, handler: &mut Handler<R, W>) -> anyhow::Result<()> {
+        for (name, value) in &self.parameters {
+            handler.session.settings.set(name, value)?;
+        }
+        handler.ssl_request = self.negotiation.ssl;
+        handler.gssenc_request = self.negotiation.gssenc;
+        handler.limits = self.limits.protocol_limits();
+        if let Some(bytes) = self.faults.flush_every_bytes {
+            handler.flush_policy = FlushPolicy::EveryBytes(bytes);
+        }
+        if let Some(interval) = self.faults.flush_interval_ms {
+            handler.flush_policy = FlushPolicy::Interval(Duration::from_millis(interval));
+        }
+        handler.memory_limit = self.faults.memory_limit.map(MemoryLimit::Error);
+        handler.fragmentation = self.faults.split_messages_at.map(|offset| Fragmentation {
+            offset,
+            delay: Duration::from_millis(self.faults.split_delay_ms.unwrap_or_default()),
+        });
+        handler.violation = self.faults.protocol_violation;
+        if self.faults.skip_corrupted_messages {
+            handler.desync_policy = DesyncPolicy::SkipToNextMessage;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn server_config() -> anyhow::Result<()> {
+        let config = ServerConfig::from_toml(
+            r#"
+            drivers = ["psycopg"]
+
+            [[listeners]]
+            address = "127.0.0.1:5433"
+
+            [[users]]
+            name = "app"
+            password = "secret"
+
+            [[auth]]
+            user = "app"
+            method = "md5"
+
+            [[auth]]
+            database = "public"
+            method = "trust"
+
+            [parameters]
+            DateStyle = "ISO, DMY"
+
+            [negotiation]
+            gssenc = "close"
+
+            [logging]
+            level = "debug"
+            "#,
+        )?;
+        assert_eq!(Some(Level::DEBUG), config.logging.level());
+        assert_eq!(NegotiationPolicy::Deny, config.negotiation.ssl);
+        assert_eq!(NegotiationPolicy::Close, config.negotiation.gssenc);
+        assert_eq!(3, config.scenario()?.rules.len());
+
+        let startup = |user: &str, database: &str| StartupParameters {
+            parameters: vec![
+                (String::from("user"), String::from(user)),
+                (String::from("database"), String::from(database)),
+            ],
+        };
+        assert_eq!(
+            Authentication::Md5(Some(String::from("secret"))),
+            config.authentication(&startup("app", "db"))
+        );
+        assert_eq!(
+            Authentication::Trust,
+            config.authentication(&startup("other", "public"))
+        );
+        assert_eq!(
+            Authentication::Reject,
+            config.authentication(&startup("other", "db"))
+        );
+
+        let error = |content: &str| match ServerConfig::from_toml(content) {
+            Ok(_) => String::new(),
+            Err(e) => e.to_string(),
+        };
+        assert_eq!(
+            "auth[0].user: Unknown user \"ap\"",
+            error(
+                "[[users]]\nname = \"app\"\npassword = \"p\"\n\
+                 [[auth]]\nuser = \"ap\"\nmethod = \"md5\"\n"
+            )
+        );
+        assert!(
+            error("[parameters]\nDateStyle = \"Roman\"\n").starts_with("parameters.DateStyle: ")
+        );
+        assert!(
+            error("[faults]\nflush_every_byte = 1\n").contains("unknown field `flush_every_byte`")
+        );
+        assert_eq!(
+            "faults.split_delay_ms: Requires faults.split_messages_at",
+            error("[faults]\nsplit_delay_ms = 10\n")
+        );
+        assert_eq!(
+            "limits.max_columns: Must be between 1 and 32767",
+            error("[limits]\nmax_columns = 40000\n")
+        );
+        assert!(error("drivers = [\"odbc\"]\n").contains("unknown variant `odbc`"));
+        assert_eq!(
+            "tables[0].columns[1].references: Unknown column \"users.uid\"",
+            error(
+                "[[tables]]\nname = \"users\"\ncolumns = [{ name = \"id\", type = \"int8\" }, \
+                 { name = \"parent\", type = \"int8\", references = \"users.uid\" }]\n"
+            )
+        );
+        assert!(
+            error("[[listeners]]\naddress = \"nowhere\"\n").starts_with("listeners[0].address: ")
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,525 @@
+//! Middleware for the reader/writer stack of the handlers, to test how the
+//! peers cope with damaged messages. The CorruptingWriter corrupts, truncates
+//! or flips a bit of the messages at random, the ValidatingReader checks the
+//! frames it reads and logs the malformed ones with their offset in the
+//! stream. Both leave the bytes in order and pass them through: a truncated
+//! message keeps its length, the peer reads the next message as its end.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use tracing::*;
+
+use bytes::Bytes;
+use libpq_serde_types::Deserialize;
+
+use crate::handler::desync::check_frontend_header;
+use crate::hexdump::Hexdump;
+use crate::message::{
+    BackendMessageKind, MAX_STARTUP_PACKET_LENGTH, MessageHeader, RequestMessageKind,
+};
+use crate::random;
+use crate::validator::Direction;
+
+// The limit of PostgreSQL on the length of the messages it reads
+const MESSAGE_LIMIT: i32 = 0x3fff_fffe;
+// The bodies are only kept to be checked below this length
+const CHECKED_BODY_LIMIT: usize = 65536;
+
+/// The probability of each damage, drawn for each message written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorruptionPolicy {
+    // A random byte of the body is replaced by a random value
+    pub corrupt: f64,
+    // The message is cut at a random place, its length is left as is
+    pub truncate: f64,
+    // A random bit of the message is flipped, the header included
+    pub bit_flip: f64,
+    // The same seed damages the same messages the same way
+    pub seed: u64,
+}
+
+impl CorruptionPolicy {
+    pub fn new(corrupt: f64, truncate: f64, bit_flip: f64) -> Self {
+        Self {
+            corrupt,
+            truncate,
+            bit_flip,
+            seed: random::random_u64(),
+        }
+    }
+}
+
+impl Default for CorruptionPolicy {
+    fn default() -> Self {
+        Self::new(0.0, 0.0, 0.0)
+    }
+}
+
+/// xorshift64*, good enough to pick the damages
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        // The state must not be 0
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && (self.next() >> 11) as f64 / (1u64 << 53) as f64 <= probability
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Where the messages start in a stream sent by one side. The untyped
+/// requests of the frontend come before the StartupMessage, the backend
+/// answers the SSLRequest and GSSENCRequest with a single 'N' before its
+/// first message.
+#[derive(Debug, Clone, Copy)]
+pub struct Framing {
+    sender: Direction,
+    started: bool,
+}
+
+impl Framing {
+    pub fn new(sender: Direction) -> Self {
+        Self {
+            sender,
+            started: false,
+        }
+    }
+
+    fn header_length(&self) -> usize {
+        match (self.sender, self.started) {
+            (Direction::Frontend, false) => 8,
+            (Direction::Backend, false) => 1,
+            (_, true) => 5,
+        }
+    }
+
+    /// The length of the frame starting with the header, or why the header
+    /// can't start one
+    fn frame_length(&self, header: &[u8]) -> Result<usize, String> {
+        let length = |bytes: &[u8]| i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        match (self.sender, self.started) {
+            (Direction::Frontend, false) => {
+                let (length, code) = (length(header), length(&header[4..]));
+                if !(8..=MAX_STARTUP_PACKET_LENGTH).contains(&length) {
+                    return Err(format!("invalid request length {length}"));
+                }
+                match RequestMessageKind::try_from(code) {
+                    Ok(_) => Ok(length as usize),
+                    Err(_) => Err(format!("invalid request code {code}")),
+                }
+            }
+            (Direction::Frontend, true) => {
+                let header = MessageHeader::deserialize(&mut Bytes::copy_from_slice(header))
+                    .map_err(|e| e.to_string())?;
+                match check_frontend_header(&header) {
+                    Some(reason) => Err(reason),
+                    None => Ok(1 + header.length as usize),
+                }
+            }
+            (Direction::Backend, _) => {
+                let (message_type, length) = (header[0], length(&header[1..]));
+                if BackendMessageKind::try_from(message_type).is_err() {
+                    return Err(format!("invalid backend message type {message_type}"));
+                }
+                if !(4..=MESSAGE_LIMIT).contains(&length) {
+                    return Err(format!(
+                        "invalid message length {length} for '{}'",
+                        char::from(message_type)
+                    ));
+                }
+                Ok(1 + length as usize)
+            }
+        }
+    }
+
+    /// The length of the next frame of the bytes and the length of its
+    /// header, `None` until the header is complete
+    pub fn next_frame(&mut self, bytes: &[u8]) -> Option<Result<(usize, usize), String>> {
+        if self.sender == Direction::Backend && !self.started {
+            match bytes.first()? {
+                b'N' => return Some(Ok((1, 1))),
+                _ => self.started = true,
+            }
+        }
+        let header_length = self.header_length();
+        let header = bytes.get(..header_length)?;
+        let length = match self.frame_length(header) {
+            Ok(length) => length,
+            Err(reason) => return Some(Err(reason)),
+        };
+        if header_length == 8
+            && header[4..] == i32::from(&RequestMessageKind::StartupMessage).to_be_bytes()
+        {
+            self.started = true;
+        }
+        Some(Ok((length, header_length)))
+    }
+}
+
+/// Why the body of a frame is wrong, with the offset of the faulty byte in
+/// the frame
+fn check_body(sender: Direction, frame: &[u8]) -> Option<(usize, String)> {
+    let (message_type, last) = (frame[0], frame.len() - 1);
+    let fixed_length = match (sender, message_type) {
+        (Direction::Frontend, b'S' | b'H' | b'X' | b'c') => Some(5),
+        (Direction::Backend, b'1' | b'2' | b'3' | b'n' | b'I' | b's' | b'c') => Some(5),
+        (Direction::Backend, b'Z') => Some(6),
+        (Direction::Backend, b'K') => Some(13),
+        _ => None,
+    };
+    if let Some(length) = fixed_length.filter(|length| *length != frame.len()) {
+        return Some((
+            1,
+            format!(
+                "length {} for '{}', expected {}",
+                frame.len() - 1,
+                char::from(message_type),
+                length - 1
+            ),
+        ));
+    }
+    match (sender, message_type) {
+        (Direction::Backend, b'Z') if !b"ITE".contains(&frame[5]) => Some((
+            5,
+            format!("invalid transaction status {}", frame[5].escape_ascii()),
+        )),
+        // Made of strings
+        (Direction::Frontend, b'Q') | (Direction::Backend, b'C' | b'E' | b'N' | b'S')
+            if frame[last] != 0 =>
+        {
+            Some((last, String::from("missing string terminator")))
+        }
+        _ => None,
+    }
+}
+
+/// A frame that can't be right
+#[derive(Debug, Clone, PartialEq)]
+pub struct Malformation {
+    // From the start of the stream, of the faulty byte
+    pub offset: u64,
+    // Of the frame, when its header could be read
+    pub frame_offset: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for Malformation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at offset {} (frame at offset {})",
+            self.reason, self.offset, self.frame_offset
+        )
+    }
+}
+
+/// Writes the messages, damaged by chance as the policy says
+pub struct CorruptingWriter<W> {
+    inner: W,
+    policy: CorruptionPolicy,
+    random: Random,
+    framing: Framing,
+    pending: Vec<u8>,
+    // The framing was lost, the bytes are passed through
+    lost: bool,
+    damaged: usize,
+}
+
+impl<W: Write> CorruptingWriter<W> {
+    /// The sender is the side whose messages are written
+    pub fn new(inner: W, sender: Direction, policy: CorruptionPolicy) -> Self {
+        Self {
+            inner,
+            random: Random::new(policy.seed),
+            policy,
+            framing: Framing::new(sender),
+            pending: Vec::new(),
+            lost: false,
+            damaged: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The number of messages damaged so far
+    pub fn damaged(&self) -> usize {
+        self.damaged
+    }
+
+    fn damage(&mut self, mut frame: Vec<u8>, header_length: usize) -> Vec<u8> {
+        let mut damages = Vec::new();
+        if frame.len() > header_length && self.random.chance(self.policy.corrupt) {
+            let index = header_length + self.random.below(frame.len() - header_length);
+            frame[index] = self.random.next() as u8;
+            damages.push(format!("byte {index} corrupted"));
+        }
+        if self.random.chance(self.policy.bit_flip) {
+            let bit = self.random.below(frame.len() * 8);
+            frame[bit / 8] ^= 1 << (bit % 8);
+            damages.push(format!("bit {bit} flipped"));
+        }
+        if frame.len() > 1 && self.random.chance(self.policy.truncate) {
+            let length = 1 + self.random.below(frame.len() - 1);
+            frame.truncate(length);
+            damages.push(format!("truncated to {length} bytes"));
+        }
+        if !damages.is_empty() {
+            self.damaged += 1;
+            debug!(
+                "Damaged '{}' message: {}",
+                frame[0].escape_ascii(),
+                damages.join(", ")
+            );
+        }
+        frame
+    }
+}
+
+impl<W: Write> Write for CorruptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.lost {
+            return self.inner.write(buf);
+        }
+        self.pending.extend_from_slice(buf);
+        // The header is checked before the damage
+        let mut framing = self.framing;
+        while let Some(length) = framing.next_frame(&self.pending) {
+            let (length, header_length) = match length {
+                Ok((length, header_length)) if self.pending.len() >= length => {
+                    (length, header_length)
+                }
+                Ok(_) => break,
+                Err(reason) => {
+                    warn!("Not corrupting the messages anymore: {reason}");
+                    self.lost = true;
+                    self.inner.write_all(&self.pending)?;
+                    self.pending.clear();
+                    break;
+                }
+            };
+            let frame: Vec<u8> = self.pending.drain(..length).collect();
+            let frame = self.damage(frame, header_length);
+            self.framing = framing;
+            self.inner.write_all(&frame)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The frames found malformed by a ValidatingReader, shared with the caller
+/// since the reader is usually owned by a handler
+#[derive(Debug, Clone, Default)]
+pub struct Malformations(Arc<Mutex<Vec<Malformation>>>);
+
+impl Malformations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn all(&self) -> Vec<Malformation> {
+        self.0.lock().expect("malformations lock").clone()
+    }
+
+    fn push(&self, malformation: Malformation) {
+        self.0
+            .lock()
+            .expect("malformations lock")
+            .push(malformation);
+    }
+}
+
+/// Reads the bytes unchanged and checks the frames they make
+pub struct ValidatingReader<R> {
+    inner: R,
+    framing: Framing,
+    malformations: Malformations,
+    // The frame being read, its body is only kept when short enough
+    frame: Vec<u8>,
+    frame_offset: u64,
+    // With the length of its header
+    frame_length: Option<(usize, usize)>,
+    // The framing was lost, nothing can be checked anymore
+    lost: bool,
+}
+
+impl<R: Read> ValidatingReader<R> {
+    /// The sender is the side whose messages are read
+    pub fn new(inner: R, sender: Direction, malformations: Malformations) -> Self {
+        Self {
+            inner,
+            framing: Framing::new(sender),
+            malformations,
+            frame: Vec::new(),
+            frame_offset: 0,
+            frame_length: None,
+            lost: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    fn report(&mut self, offset: usize, reason: String) {
+        let malformation = Malformation {
+            offset: self.frame_offset + offset as u64,
+            frame_offset: self.frame_offset,
+            reason,
+        };
+        warn!(
+            "Malformed {:?} frame: {malformation}\n{}",
+            self.framing.sender,
+            Hexdump::with_offset(&self.frame, self.frame_offset as usize)
+        );
+        self.malformations.push(malformation);
+    }
+
+    fn check(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() && !self.lost {
+            let read = match self.frame_length {
+                None => {
+                    let missing = self.framing.header_length() - self.frame.len();
+                    let read = missing.min(bytes.len());
+                    self.frame.extend_from_slice(&bytes[..read]);
+                    match self.framing.next_frame(&self.frame) {
+                        None => (),
+                        Some(Ok(length)) => self.frame_length = Some(length),
+                        Some(Err(reason)) => {
+                            self.report(0, format!("{reason}, the framing is lost"));
+                            self.lost = true;
+                        }
+                    }
+                    read
+                }
+                Some((length, _)) => {
+                    let read = (length - self.frame.len()).min(bytes.len());
+                    if length <= CHECKED_BODY_LIMIT {
+                        self.frame.extend_from_slice(&bytes[..read]);
+                    } else {
+                        // Only the length is kept, the body is not checked
+                        self.frame.resize(self.frame.len() + read, 0);
+                    }
+                    read
+                }
+            };
+            bytes = &bytes[read..];
+            if let Some((length, header_length)) =
+                self.frame_length.filter(|(l, _)| self.frame.len() == *l)
+            {
+                if length <= CHECKED_BODY_LIMIT
+                    && header_length == 5
+                    && let Some((offset, reason)) = check_body(self.framing.sender, &self.frame)
+                {
+                    self.report(offset, reason);
+                }
+                self.frame_offset += length as u64;
+                self.frame.clear();
+                self.frame_length = None;
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for ValidatingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.check(&buf[..count]);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{CommandComplete, ReadyForQuery, TransactionIndicator};
+    use bytes::BytesMut;
+
+    fn messages() -> BytesMut {
+        let mut data = BytesMut::new();
+        MessageHeader::serialize_message(
+            &mut data,
+            &CommandComplete::new(String::from("SELECT 1")).expect("tag"),
+        )
+        .expect("message length");
+        MessageHeader::serialize_message(
+            &mut data,
+            &ReadyForQuery::new(TransactionIndicator::Idle),
+        )
+        .expect("message length");
+        data
+    }
+
+    #[test]
+    fn corruption() -> anyhow::Result<()> {
+        let data = messages();
+
+        // Damaged, each message keeps its first byte
+        let mut writer = CorruptingWriter::new(
+            Vec::new(),
+            Direction::Backend,
+            CorruptionPolicy::new(0.0, 1.0, 0.0),
+        );
+        writer.write_all(&data[..3])?;
+        assert!(writer.get_ref().is_empty());
+        writer.write_all(&data[3..])?;
+        assert_eq!(2, writer.damaged());
+        assert!(writer.get_ref().len() < data.len());
+        assert_eq!(b'C', writer.get_ref()[0]);
+
+        // The same seed gives the same damages
+        let policy = CorruptionPolicy::new(0.5, 0.0, 0.5);
+        let damaged = || -> anyhow::Result<Vec<u8>> {
+            let mut writer = CorruptingWriter::new(Vec::new(), Direction::Backend, policy);
+            for _ in 0..10 {
+                writer.write_all(&data)?;
+            }
+            Ok(writer.inner)
+        };
+        assert_eq!(damaged()?, damaged()?);
+
+        // The status of ReadyForQuery, then a header
+        let mut data = messages();
+        let status = data.len() - 1;
+        data[status] = b'x';
+        let mut input = Vec::from(&data[..]);
+        input.extend_from_slice(b"garbage");
+        let malformations = Malformations::new();
+        let mut reader =
+            ValidatingReader::new(&input[..], Direction::Backend, malformations.clone());
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read)?;
+        assert_eq!(input, read);
+        let offsets: Vec<(u64, u64)> = malformations
+            .all()
+            .iter()
+            .map(|m| (m.frame_offset, m.offset))
+            .collect();
+        assert_eq!(vec![(14, 19), (20, 20)], offsets);
+        assert_eq!(
+            "invalid backend message type 103, the framing is lost at offset 20 (frame at offset 20)",
+            malformations.all()[1].to_string()
+        );
+
+        Ok(())
+    }
+}
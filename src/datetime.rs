@@ -0,0 +1,683 @@
+use anyhow::anyhow;
+use std::fmt::Write;
+
+use crate::error::PgError;
+
+pub const USECS_PER_SEC: i64 = 1_000_000;
+pub const USECS_PER_MINUTE: i64 = 60 * USECS_PER_SEC;
+pub const USECS_PER_HOUR: i64 = 60 * USECS_PER_MINUTE;
+pub const USECS_PER_DAY: i64 = 24 * USECS_PER_HOUR;
+
+// The dates of PostgreSQL are counted from 2000-01-01, a Saturday
+const POSTGRES_EPOCH_DAYS: i64 = 10957;
+const POSTGRES_EPOCH_DOW: i64 = 6;
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// The output format of DateStyle
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DateFormat {
+    #[default]
+    Iso,
+    Sql,
+    Postgres,
+    German,
+}
+
+/// The field order of DateStyle, YMD is written like MDY
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DateOrder {
+    #[default]
+    Mdy,
+    Dmy,
+    Ymd,
+}
+
+/// How the dates and timestamps are written, e.g. "ISO, MDY"
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DateStyle {
+    pub format: DateFormat,
+    pub order: DateOrder,
+}
+
+fn invalid_value(name: &str, value: &str) -> anyhow::Error {
+    PgError::new(
+        "22023",
+        &format!("invalid value for parameter \"{name}\": \"{value}\""),
+    )
+    .into()
+}
+
+impl DateStyle {
+    /// The value reported in ParameterStatus
+    pub fn name(&self) -> String {
+        let format = match self.format {
+            DateFormat::Iso => "ISO",
+            DateFormat::Sql => "SQL",
+            DateFormat::Postgres => "Postgres",
+            DateFormat::German => "German",
+        };
+        let order = match self.order {
+            DateOrder::Mdy => "MDY",
+            DateOrder::Dmy => "DMY",
+            DateOrder::Ymd => "YMD",
+        };
+        format!("{format}, {order}")
+    }
+
+    /// Apply a new value of DateStyle, the parts that are not given are kept
+    /// like in PostgreSQL (e.g. SET DateStyle = 'German' keeps the order
+    /// unless it's the default)
+    pub fn parse(&self, value: &str) -> anyhow::Result<DateStyle> {
+        let mut style = *self;
+        let mut has_order = false;
+        for token in value.split(',').map(|token| token.trim().to_lowercase()) {
+            match &token[..] {
+                "iso" => style.format = DateFormat::Iso,
+                "sql" => style.format = DateFormat::Sql,
+                "postgres" => style.format = DateFormat::Postgres,
+                "german" => {
+                    style.format = DateFormat::German;
+                    if !has_order {
+                        style.order = DateOrder::Dmy;
+                    }
+                }
+                "mdy" | "us" | "noneuro" | "noneuropean" => {
+                    style.order = DateOrder::Mdy;
+                    has_order = true;
+                }
+                "dmy" | "euro" | "european" => {
+                    style.order = DateOrder::Dmy;
+                    has_order = true;
+                }
+                "ymd" => {
+                    style.order = DateOrder::Ymd;
+                    has_order = true;
+                }
+                "default" => style = DateStyle::default(),
+                _ => return Err(invalid_value("DateStyle", value)),
+            }
+        }
+        Ok(style)
+    }
+}
+
+/// How the intervals are written
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum IntervalStyle {
+    #[default]
+    Postgres,
+    PostgresVerbose,
+    SqlStandard,
+    Iso8601,
+}
+
+impl IntervalStyle {
+    /// The value reported in ParameterStatus
+    pub fn name(&self) -> &'static str {
+        match self {
+            IntervalStyle::Postgres => "postgres",
+            IntervalStyle::PostgresVerbose => "postgres_verbose",
+            IntervalStyle::SqlStandard => "sql_standard",
+            IntervalStyle::Iso8601 => "iso_8601",
+        }
+    }
+}
+
+impl TryFrom<&str> for IntervalStyle {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> anyhow::Result<IntervalStyle> {
+        match &value.trim().to_lowercase()[..] {
+            "postgres" => Ok(IntervalStyle::Postgres),
+            "postgres_verbose" => Ok(IntervalStyle::PostgresVerbose),
+            "sql_standard" => Ok(IntervalStyle::SqlStandard),
+            "iso_8601" => Ok(IntervalStyle::Iso8601),
+            _ => Err(invalid_value("IntervalStyle", value)),
+        }
+    }
+}
+
+/// The year, month and day of a date counted from 2000-01-01, the year is
+/// astronomical (0 is 1 BC)
+pub fn date_to_ymd(date: i32) -> (i64, u32, u32) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = i64::from(date) + POSTGRES_EPOCH_DAYS + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// A timestamp counted from 2000-01-01 00:00:00 UTC, from the system clock
+pub fn timestamp_from_system_time(time: std::time::SystemTime) -> i64 {
+    let unix = match time.duration_since(std::time::UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    };
+    unix - POSTGRES_EPOCH_DAYS * USECS_PER_DAY
+}
+
+/// The inverse of date_to_ymd()
+pub fn ymd_to_date(year: i64, month: u32, day: u32) -> anyhow::Result<i32> {
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => return Err(anyhow!("Invalid month: {month}")),
+    };
+    if day == 0 || day > days_in_month {
+        return Err(anyhow!("Invalid day: {day}"));
+    }
+
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let (month, day) = (i64::from(month), i64::from(day));
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Ok(i32::try_from(
+        era * 146097 + doe - 719468 - POSTGRES_EPOCH_DAYS,
+    )?)
+}
+
+// Seconds with the fractional part, without the trailing zeros
+fn append_seconds(out: &mut String, seconds: i64, microseconds: i64, fill_zeros: bool) {
+    let (seconds, microseconds) = (seconds.abs(), microseconds.abs());
+    if fill_zeros {
+        let _ = write!(out, "{seconds:02}");
+    } else {
+        let _ = write!(out, "{seconds}");
+    }
+    if microseconds != 0 {
+        let fraction = format!("{microseconds:06}");
+        let _ = write!(out, ".{}", fraction.trim_end_matches('0'));
+    }
+}
+
+// The year with its era, e.g. 0044 BC for the year -43
+fn year_and_era(year: i64) -> (i64, &'static str) {
+    if year > 0 {
+        (year, "")
+    } else {
+        (1 - year, " BC")
+    }
+}
+
+fn append_date(out: &mut String, date: i32, style: &DateStyle) {
+    let (year, month, day) = date_to_ymd(date);
+    let (year, _) = year_and_era(year);
+    let _ = match (style.format, style.order) {
+        (DateFormat::Iso, _) => write!(out, "{year:04}-{month:02}-{day:02}"),
+        (DateFormat::Sql, DateOrder::Dmy) => write!(out, "{day:02}/{month:02}/{year:04}"),
+        (DateFormat::Sql, _) => write!(out, "{month:02}/{day:02}/{year:04}"),
+        (DateFormat::German, _) => write!(out, "{day:02}.{month:02}.{year:04}"),
+        (DateFormat::Postgres, DateOrder::Dmy) => write!(out, "{day:02}-{month:02}-{year:04}"),
+        (DateFormat::Postgres, _) => write!(out, "{month:02}-{day:02}-{year:04}"),
+    };
+}
+
+/// A date (days from 2000-01-01) in the given DateStyle
+pub fn format_date(date: i32, style: &DateStyle) -> String {
+    match date {
+        i32::MIN => return String::from("-infinity"),
+        i32::MAX => return String::from("infinity"),
+        _ => (),
+    }
+
+    let mut out = String::new();
+    append_date(&mut out, date, style);
+    out.push_str(year_and_era(date_to_ymd(date).0).1);
+    out
+}
+
+/// A timestamp without time zone (microseconds from 2000-01-01 00:00:00)
+/// in the given DateStyle
+pub fn format_timestamp(timestamp: i64, style: &DateStyle) -> String {
+    match timestamp {
+        i64::MIN => return String::from("-infinity"),
+        i64::MAX => return String::from("infinity"),
+        _ => (),
+    }
+
+    let days = timestamp.div_euclid(USECS_PER_DAY);
+    let time = timestamp.rem_euclid(USECS_PER_DAY);
+    let (hour, minute) = (
+        time / USECS_PER_HOUR,
+        time % USECS_PER_HOUR / USECS_PER_MINUTE,
+    );
+    let (seconds, microseconds) = (
+        time % USECS_PER_MINUTE / USECS_PER_SEC,
+        time % USECS_PER_SEC,
+    );
+    // The dates of the timestamp range fit in an i32
+    let date = days as i32;
+    let (year, month, day) = date_to_ymd(date);
+    let (year, era) = year_and_era(year);
+
+    let mut out = String::new();
+    if style.format == DateFormat::Postgres {
+        // e.g. Wed Dec 17 07:37:16 1997
+        let dow = DAYS[(i64::from(date) + POSTGRES_EPOCH_DOW).rem_euclid(7) as usize];
+        let month = MONTHS[month as usize - 1];
+        let _ = match style.order {
+            DateOrder::Dmy => write!(out, "{dow} {day:02} {month} {hour:02}:{minute:02}:"),
+            _ => write!(out, "{dow} {month} {day:02} {hour:02}:{minute:02}:"),
+        };
+        append_seconds(&mut out, seconds, microseconds, true);
+        let _ = write!(out, " {year:04}");
+    } else {
+        append_date(&mut out, date, style);
+        let _ = write!(out, " {hour:02}:{minute:02}:");
+        append_seconds(&mut out, seconds, microseconds, true);
+    }
+    out.push_str(era);
+    out
+}
+
+// The fields of an interval, like pg_itm in PostgreSQL
+struct IntervalFields {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+    microsecond: i64,
+}
+
+impl IntervalFields {
+    fn new(months: i32, days: i32, microseconds: i64) -> Self {
+        let months = i64::from(months);
+        Self {
+            year: months / 12,
+            month: months % 12,
+            day: i64::from(days),
+            hour: microseconds / USECS_PER_HOUR,
+            minute: microseconds % USECS_PER_HOUR / USECS_PER_MINUTE,
+            second: microseconds % USECS_PER_MINUTE / USECS_PER_SEC,
+            microsecond: microseconds % USECS_PER_SEC,
+        }
+    }
+
+    fn has_time(&self) -> bool {
+        self.hour != 0 || self.minute != 0 || self.second != 0 || self.microsecond != 0
+    }
+}
+
+/// An interval in the given IntervalStyle, following EncodeInterval() of
+/// PostgreSQL
+pub fn format_interval(months: i32, days: i32, microseconds: i64, style: IntervalStyle) -> String {
+    let f = IntervalFields::new(months, days, microseconds);
+    let mut out = String::new();
+
+    match style {
+        IntervalStyle::Postgres => {
+            let mut is_zero = true;
+            let mut is_before = false;
+            for (value, unit) in [(f.year, "year"), (f.month, "mon"), (f.day, "day")] {
+                if value == 0 {
+                    continue;
+                }
+                let _ = write!(
+                    out,
+                    "{}{}{value} {unit}{}",
+                    if is_zero { "" } else { " " },
+                    if is_before && value > 0 { "+" } else { "" },
+                    if value != 1 { "s" } else { "" }
+                );
+                is_before = value < 0;
+                is_zero = false;
+            }
+            if is_zero || f.has_time() {
+                let minus = f.hour < 0 || f.minute < 0 || f.second < 0 || f.microsecond < 0;
+                let _ = write!(
+                    out,
+                    "{}{}{:02}:{:02}:",
+                    if is_zero { "" } else { " " },
+                    if minus {
+                        "-"
+                    } else if is_before {
+                        "+"
+                    } else {
+                        ""
+                    },
+                    f.hour.abs(),
+                    f.minute.abs()
+                );
+                append_seconds(&mut out, f.second, f.microsecond, true);
+            }
+        }
+        IntervalStyle::PostgresVerbose => {
+            let mut is_zero = true;
+            let mut is_before = false;
+            out.push('@');
+            for (value, unit) in [
+                (f.year, "year"),
+                (f.month, "mon"),
+                (f.day, "day"),
+                (f.hour, "hour"),
+                (f.minute, "min"),
+            ] {
+                if value == 0 {
+                    continue;
+                }
+                // The sign of the first field applies to the whole interval
+                let value = if is_zero {
+                    is_before = value < 0;
+                    value.abs()
+                } else if is_before {
+                    -value
+                } else {
+                    value
+                };
+                let _ = write!(out, " {value} {unit}{}", if value != 1 { "s" } else { "" });
+                is_zero = false;
+            }
+            if f.second != 0 || f.microsecond != 0 {
+                out.push(' ');
+                if f.second < 0 || (f.second == 0 && f.microsecond < 0) {
+                    if is_zero {
+                        is_before = true;
+                    } else if !is_before {
+                        out.push('-');
+                    }
+                } else if is_before {
+                    out.push('-');
+                }
+                append_seconds(&mut out, f.second, f.microsecond, false);
+                let plural = f.second.abs() != 1 || f.microsecond != 0;
+                let _ = write!(out, " sec{}", if plural { "s" } else { "" });
+                is_zero = false;
+            }
+            if is_zero {
+                out.push_str(" 0");
+            }
+            if is_before {
+                out.push_str(" ago");
+            }
+        }
+        IntervalStyle::SqlStandard => {
+            let fields = [
+                f.year,
+                f.month,
+                f.day,
+                f.hour,
+                f.minute,
+                f.second,
+                f.microsecond,
+            ];
+            let has_negative = fields.iter().any(|v| *v < 0);
+            let has_positive = fields.iter().any(|v| *v > 0);
+            let has_year_month = f.year != 0 || f.month != 0;
+            let has_day_time = f.day != 0 || f.has_time();
+            let sql_standard_value =
+                !(has_negative && has_positive || has_year_month && has_day_time);
+
+            if !has_negative && !has_positive {
+                out.push('0');
+            } else if !sql_standard_value {
+                // Mixed signs or fields, every part gets a sign
+                let year_sign = if f.year < 0 || f.month < 0 { '-' } else { '+' };
+                let day_sign = if f.day < 0 { '-' } else { '+' };
+                let time_sign = if f.hour < 0 || f.minute < 0 || f.second < 0 || f.microsecond < 0 {
+                    '-'
+                } else {
+                    '+'
+                };
+                let _ = write!(
+                    out,
+                    "{year_sign}{}-{} {day_sign}{} {time_sign}{}:{:02}:",
+                    f.year.abs(),
+                    f.month.abs(),
+                    f.day.abs(),
+                    f.hour.abs(),
+                    f.minute.abs()
+                );
+                append_seconds(&mut out, f.second, f.microsecond, true);
+            } else {
+                let sign = if has_negative { "-" } else { "" };
+                if has_year_month {
+                    let _ = write!(out, "{sign}{}-{}", f.year.abs(), f.month.abs());
+                } else {
+                    if f.day != 0 {
+                        let _ = write!(out, "{sign}{} ", f.day.abs());
+                    } else {
+                        out.push_str(sign);
+                    }
+                    let _ = write!(out, "{}:{:02}:", f.hour.abs(), f.minute.abs());
+                    append_seconds(&mut out, f.second, f.microsecond, true);
+                }
+            }
+        }
+        IntervalStyle::Iso8601 => {
+            if f.year == 0 && f.month == 0 && f.day == 0 && !f.has_time() {
+                return String::from("PT0S");
+            }
+            out.push('P');
+            for (value, unit) in [(f.year, 'Y'), (f.month, 'M'), (f.day, 'D')] {
+                if value != 0 {
+                    let _ = write!(out, "{value}{unit}");
+                }
+            }
+            if f.has_time() {
+                out.push('T');
+            }
+            for (value, unit) in [(f.hour, 'H'), (f.minute, 'M')] {
+                if value != 0 {
+                    let _ = write!(out, "{value}{unit}");
+                }
+            }
+            if f.second != 0 || f.microsecond != 0 {
+                if f.second < 0 || f.microsecond < 0 {
+                    out.push('-');
+                }
+                append_seconds(&mut out, f.second, f.microsecond, false);
+                out.push('S');
+            }
+        }
+    }
+    out
+}
+
+// [+-]seconds[.fraction] as microseconds
+fn parse_seconds(text: &str) -> anyhow::Result<i64> {
+    let (seconds, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if fraction.len() > 6 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(anyhow!("Invalid seconds: {text}"));
+    }
+    let microseconds = format!("{fraction:0<6}").parse::<i64>()?;
+    Ok(seconds.parse::<i64>()? * USECS_PER_SEC + microseconds)
+}
+
+// HH:MM:SS[.fraction] as microseconds
+fn parse_time(text: &str) -> anyhow::Result<i64> {
+    let parts: Vec<&str> = text.split(':').collect();
+    let [hour, minute, seconds] = parts[..] else {
+        return Err(anyhow!("Invalid time: {text}"));
+    };
+    Ok(hour.parse::<i64>()? * USECS_PER_HOUR
+        + minute.parse::<i64>()? * USECS_PER_MINUTE
+        + parse_seconds(seconds)?)
+}
+
+// YYYY-MM-DD, the era is handled by the caller
+fn parse_ymd(text: &str) -> anyhow::Result<(i64, u32, u32)> {
+    let parts: Vec<&str> = text.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return Err(anyhow!("Invalid date: {text}"));
+    };
+    Ok((year.parse()?, month.parse()?, day.parse()?))
+}
+
+// The date and the rest of a value in ISO format, e.g. 0044-03-15 BC
+fn parse_iso(text: &str) -> anyhow::Result<(i32, Option<&str>)> {
+    let (text, bc) = match text.strip_suffix(" BC") {
+        Some(text) => (text, true),
+        None => (text, false),
+    };
+    let (date, time) = match text.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (text, None),
+    };
+    let (year, month, day) = parse_ymd(date)?;
+    let year = if bc { 1 - year } else { year };
+    Ok((ymd_to_date(year, month, day)?, time))
+}
+
+/// Parse a date written in the ISO DateStyle
+pub fn parse_date(text: &str) -> anyhow::Result<i32> {
+    match text {
+        "infinity" => Ok(i32::MAX),
+        "-infinity" => Ok(i32::MIN),
+        _ => match parse_iso(text)? {
+            (date, None) => Ok(date),
+            (_, Some(_)) => Err(anyhow!("Invalid date: {text}")),
+        },
+    }
+}
+
+/// Parse a timestamp written in the ISO DateStyle
+pub fn parse_timestamp(text: &str) -> anyhow::Result<i64> {
+    match text {
+        "infinity" => Ok(i64::MAX),
+        "-infinity" => Ok(i64::MIN),
+        _ => {
+            let (date, time) = parse_iso(text)?;
+            let time = match time {
+                Some(time) => parse_time(time)?,
+                None => 0,
+            };
+            Ok(i64::from(date) * USECS_PER_DAY + time)
+        }
+    }
+}
+
+/// Parse an interval written in the postgres IntervalStyle, returns the
+/// months, days and microseconds
+pub fn parse_interval(text: &str) -> anyhow::Result<(i32, i32, i64)> {
+    let (mut months, mut days, mut microseconds) = (0i32, 0i32, 0i64);
+    let mut words = text.split_whitespace();
+    while let Some(word) = words.next() {
+        if word.contains(':') {
+            let (negative, time) = match word.strip_prefix('-') {
+                Some(time) => (true, time),
+                None => (false, word.trim_start_matches('+')),
+            };
+            let time = parse_time(time)?;
+            microseconds += if negative { -time } else { time };
+            continue;
+        }
+        let value: i32 = word.parse()?;
+        match words.next().map(|unit| unit.trim_end_matches('s')) {
+            Some("year") => months += value * 12,
+            Some("mon") => months += value,
+            Some("day") => days += value,
+            _ => return Err(anyhow!("Invalid interval: {text}")),
+        }
+    }
+    Ok((months, days, microseconds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn datetime_date_style() -> anyhow::Result<()> {
+        let date = ymd_to_date(1997, 12, 17)?;
+        let timestamp = i64::from(date) * USECS_PER_DAY + parse_time("07:37:16.5")?;
+        for (style, expected_date, expected_timestamp) in [
+            ("ISO, MDY", "1997-12-17", "1997-12-17 07:37:16.5"),
+            ("SQL, DMY", "17/12/1997", "17/12/1997 07:37:16.5"),
+            ("SQL, MDY", "12/17/1997", "12/17/1997 07:37:16.5"),
+            ("German", "17.12.1997", "17.12.1997 07:37:16.5"),
+            ("Postgres, MDY", "12-17-1997", "Wed Dec 17 07:37:16.5 1997"),
+            ("Postgres, DMY", "17-12-1997", "Wed 17 Dec 07:37:16.5 1997"),
+        ] {
+            let style = DateStyle::default().parse(style)?;
+            assert_eq!(expected_date, format_date(date, &style));
+            assert_eq!(expected_timestamp, format_timestamp(timestamp, &style));
+        }
+        assert_eq!("German, DMY", DateStyle::default().parse("German")?.name());
+        assert!(DateStyle::default().parse("Klingon").is_err());
+
+        let iso = DateStyle::default();
+        assert_eq!("2000-01-01", format_date(0, &iso));
+        assert_eq!(
+            "0044-03-15 BC",
+            format_date(parse_date("0044-03-15 BC")?, &iso)
+        );
+        let postgres = iso.parse("Postgres")?;
+        assert_eq!(
+            "Fri Mar 15 01:00:00 0044 BC",
+            format_timestamp(parse_timestamp("0044-03-15 01:00:00 BC")?, &postgres)
+        );
+        assert_eq!("infinity", format_date(parse_date("infinity")?, &iso));
+        assert_eq!(
+            "1999-12-31 23:59:59.999999",
+            format_timestamp(parse_timestamp("1999-12-31 23:59:59.999999")?, &iso)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn datetime_interval_style() -> anyhow::Result<()> {
+        // The output of PostgreSQL for each style
+        let intervals = [
+            (14, 3, parse_time("04:05:06")?),
+            (0, -1, parse_time("02:03:00")?),
+            (0, 0, 0),
+            (-14, 0, 0),
+            (0, 0, -1_500_000),
+            (0, 1, -USECS_PER_SEC),
+        ];
+        for (style, expected) in [
+            (
+                IntervalStyle::Postgres,
+                "1 year 2 mons 3 days 04:05:06|-1 days +02:03:00|00:00:00|-1 years -2 mons|-00:00:01.5|1 day -00:00:01",
+            ),
+            (
+                IntervalStyle::PostgresVerbose,
+                "@ 1 year 2 mons 3 days 4 hours 5 mins 6 secs|@ 1 day -2 hours -3 mins ago|@ 0|@ 1 year 2 mons ago|@ 1.5 secs ago|@ 1 day -1 sec",
+            ),
+            (
+                IntervalStyle::SqlStandard,
+                "+1-2 +3 +4:05:06|+0-0 -1 +2:03:00|0|-1-2|-0:00:01.5|+0-0 +1 -0:00:01",
+            ),
+            (
+                IntervalStyle::Iso8601,
+                "P1Y2M3DT4H5M6S|P-1DT2H3M|PT0S|P-1Y-2M|PT-1.5S|P1DT-1S",
+            ),
+        ] {
+            let formatted: Vec<String> = intervals
+                .iter()
+                .map(|(months, days, microseconds)| {
+                    format_interval(*months, *days, *microseconds, style)
+                })
+                .collect();
+            assert_eq!(expected, formatted.join("|"));
+        }
+
+        for (text, interval) in [
+            ("1 year 2 mons 3 days 04:05:06", intervals[0]),
+            ("-1 days +02:03:00", intervals[1]),
+            ("-00:00:01.5", intervals[4]),
+        ] {
+            assert_eq!(interval, parse_interval(text)?);
+        }
+
+        Ok(())
+    }
+}
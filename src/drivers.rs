@@ -0,0 +1,99 @@
+//! Canned scenarios answering the queries that the drivers send on connect,
+//! e.g. `drivers = ["jdbc"]` in the configuration of a server. The rules
+//! are in scenarios/drivers, with the versions of the drivers they were
+//! written for. They were written by hand, not captured.
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::scenario::Scenario;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Driver {
+    #[serde(rename = "jdbc")]
+    Jdbc,
+    #[serde(rename = "npgsql")]
+    Npgsql,
+    #[serde(rename = "psycopg")]
+    Psycopg,
+    #[serde(rename = "sqlalchemy")]
+    SqlAlchemy,
+    #[serde(rename = "tokio-postgres")]
+    TokioPostgres,
+}
+
+impl Driver {
+    pub const ALL: [Driver; 5] = [
+        Driver::Jdbc,
+        Driver::Npgsql,
+        Driver::Psycopg,
+        Driver::SqlAlchemy,
+        Driver::TokioPostgres,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Driver::Jdbc => "jdbc",
+            Driver::Npgsql => "npgsql",
+            Driver::Psycopg => "psycopg",
+            Driver::SqlAlchemy => "sqlalchemy",
+            Driver::TokioPostgres => "tokio-postgres",
+        }
+    }
+
+    /// The rules answering the probes of the driver
+    pub fn scenario(&self) -> anyhow::Result<Scenario> {
+        Scenario::from_toml(match self {
+            Driver::Jdbc => include_str!("../scenarios/drivers/jdbc.toml"),
+            Driver::Npgsql => include_str!("../scenarios/drivers/npgsql.toml"),
+            Driver::Psycopg => include_str!("../scenarios/drivers/psycopg.toml"),
+            Driver::SqlAlchemy => include_str!("../scenarios/drivers/sqlalchemy.toml"),
+            Driver::TokioPostgres => include_str!("../scenarios/drivers/tokio-postgres.toml"),
+        })
+    }
+}
+
+impl FromStr for Driver {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Self> {
+        Driver::ALL
+            .into_iter()
+            .find(|driver| driver.name() == name)
+            .ok_or(anyhow!("Unknown driver \"{name}\""))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn driver_scenarios() -> anyhow::Result<()> {
+        for driver in Driver::ALL {
+            assert_eq!(driver, driver.name().parse()?);
+            driver.scenario()?.executor()?;
+        }
+        assert!("odbc".parse::<Driver>().is_err());
+
+        let executor = Driver::SqlAlchemy.scenario()?.executor()?;
+        let result = executor.execute(String::from("select pg_catalog.version()"));
+        assert_eq!("SELECT 1", result.command_tag);
+        let result = executor.execute(String::from(
+            "SELECT t.oid, typarray\nFROM pg_type t JOIN pg_namespace ns\n    ON typnamespace = ns.oid\nWHERE typname = 'hstore';",
+        ));
+        assert_eq!("SELECT 0", result.command_tag);
+        assert_eq!(2, result.columns.len());
+        let executor = Driver::TokioPostgres.scenario()?.executor()?;
+        let result = executor.execute(String::from(
+            "SELECT t.typname, t.typtype, t.typelem, r.rngsubtype, t.typbasetype, n.nspname, t.typrelid\nFROM pg_catalog.pg_type t\nLEFT OUTER JOIN pg_catalog.pg_range r ON r.rngtypid = t.oid\nINNER JOIN pg_catalog.pg_namespace n ON t.typnamespace = n.oid\nWHERE t.oid = $1\n",
+        ));
+        assert_eq!(7, result.columns.len());
+        let executor = Driver::Jdbc.scenario()?.executor()?;
+        let result = executor.execute(String::from("SHOW TRANSACTION ISOLATION LEVEL"));
+        assert_eq!("SHOW", result.command_tag);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! The addresses of a name in both families: a listener is bound on each of
+//! them, and a client tries them in turn without waiting for the ones that
+//! don't answer, like the Happy Eyeballs of RFC 8305.
+//!
+//! The name is resolved with the system resolver, which asks for the IPv6
+//! and the IPv4 addresses together and sorts them with RFC 6724, the
+//! resolution delay of the RFC is left out.
+
+use anyhow::anyhow;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use tracing::*;
+
+/// How long an attempt runs alone before the next address is tried, the
+/// recommended value of RFC 8305
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The addresses to listen on: "*:PORT" is any interface in IPv6 and IPv4,
+/// like listen_addresses = '*', a name is each of its addresses, e.g. both
+/// 127.0.0.1 and ::1 for localhost.
+pub fn listen_addresses(address: &str) -> io::Result<Vec<SocketAddr>> {
+    if let Some(port) = address.strip_prefix("*:") {
+        let port = port
+            .parse::<u16>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        return Ok(vec![
+            (Ipv6Addr::UNSPECIFIED, port).into(),
+            (Ipv4Addr::UNSPECIFIED, port).into(),
+        ]);
+    }
+    let mut addresses = Vec::new();
+    for address in address.to_socket_addrs()? {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    Ok(addresses)
+}
+
+/// A listener for each of the addresses, see listen_addresses. With the port
+/// 0, they all get the port chosen for the first one.
+///
+/// The IPv6 addresses are skipped when the host has no IPv6, and so is the
+/// IPv4 wildcard when the IPv6 one already accepts the IPv4 connections
+/// (bindv6only = 0 on Linux).
+pub fn bind(address: &str) -> anyhow::Result<Vec<TcpListener>> {
+    let mut listeners: Vec<TcpListener> = Vec::new();
+    for mut address in listen_addresses(address)? {
+        if address.port() == 0
+            && let Some(first) = listeners.first()
+        {
+            address.set_port(first.local_addr()?.port());
+        }
+        match TcpListener::bind(address) {
+            Ok(listener) => listeners.push(listener),
+            Err(e)
+                if e.kind() == io::ErrorKind::AddrInUse
+                    && address.ip() == Ipv4Addr::UNSPECIFIED
+                    && listeners.iter().any(|listener| {
+                        listener.local_addr().is_ok_and(|local| {
+                            local.ip() == Ipv6Addr::UNSPECIFIED && local.port() == address.port()
+                        })
+                    }) =>
+            {
+                debug!("Not listening on {address}: the IPv6 listener is dual-stack");
+            }
+            Err(e) if address.is_ipv6() && e.kind() != io::ErrorKind::AddrInUse => {
+                warn!("Not listening on {address}: {e}");
+            }
+            Err(e) => return Err(anyhow!("{address}: {e}")),
+        }
+    }
+    if listeners.is_empty() {
+        return Err(anyhow!("No address to listen on"));
+    }
+    Ok(listeners)
+}
+
+/// The addresses in the order of the attempts: the families alternate,
+/// starting with the one of the address preferred by the resolver
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_ipv6 = addresses.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_ipv6);
+    preferred.reverse();
+    other.reverse();
+    let mut interleaved = Vec::new();
+    while let Some(address) = preferred.pop() {
+        interleaved.push(address);
+        if let Some(address) = other.pop() {
+            interleaved.push(address);
+        }
+    }
+    interleaved.extend(other.into_iter().rev());
+    interleaved
+}
+
+/// Connect to the first of the addresses of a name that answers: an attempt
+/// is started every `CONNECTION_ATTEMPT_DELAY`, or as soon as the previous
+/// one fails, each attempt gives up after `timeout`.
+pub fn connect(address: impl ToSocketAddrs, timeout: Duration) -> anyhow::Result<TcpStream> {
+    let addresses = address.to_socket_addrs()?.collect();
+    race(&interleave(addresses), timeout, CONNECTION_ATTEMPT_DELAY)
+}
+
+fn race(addresses: &[SocketAddr], timeout: Duration, delay: Duration) -> anyhow::Result<TcpStream> {
+    let (sender, receiver) = mpsc::channel();
+    let mut remaining = addresses.iter().copied();
+    let attempt = |address: SocketAddr| {
+        let sender = sender.clone();
+        // The attempts still running when another one succeeds are left to
+        // finish, their connection is dropped
+        thread::spawn(move || {
+            let _ = sender.send((address, TcpStream::connect_timeout(&address, timeout)));
+        });
+    };
+
+    let mut running = 0;
+    let mut last_error = anyhow!("No address to connect to");
+    if let Some(address) = remaining.next() {
+        attempt(address);
+        running += 1;
+    }
+    while running > 0 {
+        match receiver.recv_timeout(delay) {
+            Ok((address, Ok(stream))) => {
+                debug!("Connected to {address}");
+                return Ok(stream);
+            }
+            Ok((address, Err(e))) => {
+                running -= 1;
+                last_error = anyhow!("{address}: {e}");
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if let Some(address) = remaining.next() {
+            attempt(address);
+            running += 1;
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dual_stack() -> anyhow::Result<()> {
+        let address = |value: &str| value.parse::<SocketAddr>();
+        assert_eq!(
+            vec![
+                address("[::1]:1")?,
+                address("127.0.0.1:1")?,
+                address("[::2]:1")?,
+                address("127.0.0.2:1")?,
+                address("127.0.0.3:1")?,
+            ],
+            interleave(vec![
+                address("[::1]:1")?,
+                address("[::2]:1")?,
+                address("127.0.0.1:1")?,
+                address("127.0.0.2:1")?,
+                address("127.0.0.3:1")?,
+            ])
+        );
+        assert_eq!(2, listen_addresses("*:5432")?.len());
+        assert!(listen_addresses("*:port").is_err());
+
+        // A closed port, then a listener bound on the same port in both
+        // families when the host has IPv6
+        let closed = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let listeners = bind("localhost:0")?;
+        let open = listeners[0].local_addr()?;
+        assert!(listeners.iter().all(|listener| {
+            listener
+                .local_addr()
+                .is_ok_and(|local| local.port() == open.port())
+        }));
+        let timeout = Duration::from_secs(5);
+        let stream = race(&[closed, open], timeout, Duration::from_secs(60))?;
+        assert_eq!(open, stream.peer_addr()?);
+        assert!(race(&[closed], timeout, CONNECTION_ATTEMPT_DELAY).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+use crate::error::PgError;
+
+/// The encodings a frontend can ask for with client_encoding, the server
+/// side is always UTF8.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ClientEncoding {
+    #[default]
+    Utf8,
+    Latin1,
+    // No conversion at all, the bytes are sent as they are
+    SqlAscii,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::from("0x"), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+impl ClientEncoding {
+    /// The name reported in ParameterStatus
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientEncoding::Utf8 => "UTF8",
+            ClientEncoding::Latin1 => "LATIN1",
+            ClientEncoding::SqlAscii => "SQL_ASCII",
+        }
+    }
+
+    /// Convert text for the frontend, the characters that don't exist in
+    /// the client encoding are rejected (22021).
+    pub fn encode(&self, text: &str) -> anyhow::Result<Vec<u8>> {
+        match self {
+            ClientEncoding::Utf8 | ClientEncoding::SqlAscii => Ok(text.as_bytes().to_vec()),
+            ClientEncoding::Latin1 => text
+                .chars()
+                .map(|c| {
+                    u8::try_from(u32::from(c)).map_err(|_| {
+                        let mut utf8 = [0; 4];
+                        PgError::new(
+                            "22021",
+                            &format!(
+                                "character with byte sequence {} in encoding \"UTF8\" has no equivalent in encoding \"LATIN1\"",
+                                hex(c.encode_utf8(&mut utf8).as_bytes())
+                            ),
+                        )
+                        .into()
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Convert text sent by the frontend, invalid byte sequences are
+    /// rejected (22021).
+    pub fn decode(&self, bytes: &[u8]) -> anyhow::Result<String> {
+        match self {
+            // Every byte is a valid LATIN1 character
+            ClientEncoding::Latin1 => Ok(bytes.iter().map(|&b| char::from(b)).collect()),
+            ClientEncoding::Utf8 | ClientEncoding::SqlAscii => match str::from_utf8(bytes) {
+                Ok(text) => Ok(String::from(text)),
+                Err(e) => {
+                    let start = e.valid_up_to();
+                    let end = start + e.error_len().unwrap_or(bytes.len() - start);
+                    Err(PgError::new(
+                        "22021",
+                        &format!(
+                            "invalid byte sequence for encoding \"UTF8\": {}",
+                            hex(&bytes[start..end])
+                        ),
+                    )
+                    .into())
+                }
+            },
+        }
+    }
+}
+
+impl TryFrom<&str> for ClientEncoding {
+    type Error = anyhow::Error;
+
+    /// Parse an encoding name, case and punctuation are ignored like in
+    /// PostgreSQL (e.g. utf-8, ISO_8859_1)
+    fn try_from(name: &str) -> anyhow::Result<ClientEncoding> {
+        let normalized: String = name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+        match &normalized[..] {
+            "utf8" | "unicode" => Ok(ClientEncoding::Utf8),
+            "latin1" | "iso88591" => Ok(ClientEncoding::Latin1),
+            "sqlascii" => Ok(ClientEncoding::SqlAscii),
+            _ => Err(PgError::new(
+                "22023",
+                &format!("invalid value for parameter \"client_encoding\": \"{name}\""),
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encoding_transcode() -> anyhow::Result<()> {
+        let latin1 = ClientEncoding::try_from("iso-8859-1")?;
+        assert_eq!(ClientEncoding::Latin1, latin1);
+        assert_eq!(vec![b'c', 0xe9], latin1.encode("cé")?);
+        assert_eq!("cé", latin1.decode(&[b'c', 0xe9])?);
+
+        let e = latin1.encode("c€").unwrap_err();
+        assert_eq!("22021", e.downcast_ref::<PgError>().unwrap().code);
+        assert!(e.to_string().contains("0xe282ac"));
+
+        let e = ClientEncoding::Utf8.decode(&[b'c', 0xe9]).unwrap_err();
+        assert_eq!("22021", e.downcast_ref::<PgError>().unwrap().code);
+        assert!(e.to_string().ends_with("0xe9"));
+
+        assert!(ClientEncoding::try_from("EBCDIC").is_err());
+
+        Ok(())
+    }
+}
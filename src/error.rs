@@ -0,0 +1,27 @@
+use std::fmt;
+
+/// An error sent to the frontend with its SQLSTATE, other errors are sent
+/// as internal errors (XX000).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "server", derive(serde::Serialize, serde::Deserialize))]
+pub struct PgError {
+    pub code: String,
+    pub message: String,
+}
+
+impl PgError {
+    pub fn new(code: &str, message: &str) -> Self {
+        Self {
+            code: String::from(code),
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for PgError {}
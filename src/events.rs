@@ -0,0 +1,114 @@
+//! The events of a server, sent to the subscribers of TestServer::events()
+//! so that a test can wait for what the server received instead of polling
+//! the sessions or reading the logs.
+
+use anyhow::anyhow;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::PgError;
+use crate::matcher::QueryMatcher;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    // Before the startup message
+    Connected {
+        pid: i32,
+        client: SocketAddr,
+    },
+    // Received, before the executor runs it
+    Query {
+        pid: i32,
+        query: String,
+    },
+    // The error of the result of a query
+    Error {
+        pid: i32,
+        query: String,
+        error: PgError,
+    },
+    // With the error that ended the session, if any
+    Disconnected {
+        pid: i32,
+        error: Option<String>,
+    },
+}
+
+impl ServerEvent {
+    pub fn pid(&self) -> i32 {
+        match self {
+            ServerEvent::Connected { pid, .. }
+            | ServerEvent::Query { pid, .. }
+            | ServerEvent::Error { pid, .. }
+            | ServerEvent::Disconnected { pid, .. } => *pid,
+        }
+    }
+}
+
+/// The subscribers to the events of a server, cloning gives another handle
+/// on the same subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<ServerEvent>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive the events published from now on
+    pub fn subscribe(&self) -> Receiver<ServerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(sender);
+        }
+        receiver
+    }
+
+    /// Send an event to every subscriber, the ones that dropped their
+    /// receiver are forgotten
+    pub fn publish(&self, event: ServerEvent) {
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+}
+
+/// Wait for the first event accepted by `predicate`, the events received
+/// before it are skipped
+pub fn wait_for(
+    events: &Receiver<ServerEvent>,
+    timeout: Duration,
+    predicate: impl Fn(&ServerEvent) -> bool,
+) -> anyhow::Result<ServerEvent> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match events.recv_timeout(remaining) {
+            Ok(event) if predicate(&event) => return Ok(event),
+            Ok(_) => (),
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(anyhow!("No such event after {timeout:?}"));
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("The server stopped"));
+            }
+        }
+    }
+}
+
+/// Wait until a query accepted by `matcher` is received, by any session
+pub fn wait_for_query(
+    events: &Receiver<ServerEvent>,
+    matcher: &QueryMatcher,
+    timeout: Duration,
+) -> anyhow::Result<ServerEvent> {
+    wait_for(
+        events,
+        timeout,
+        |event| matches!(event, ServerEvent::Query { query, .. } if matcher.matches(query)),
+    )
+}
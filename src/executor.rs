@@ -0,0 +1,301 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use tracing::*;
+
+use crate::handler::server::{Notices, QueryResult, ServerVersion};
+use crate::matcher::QueryMatcher;
+use crate::message::{ColumnDescription, PgType};
+use crate::schedule::{DEFAULT_TIMEOUT, Schedule};
+use crate::simulation::Clock;
+use crate::value::PgValue;
+
+/// A step of a multi-connection scenario, run before answering a query
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    WaitFor(String),
+    Signal(String),
+    Barrier { name: String, parties: usize },
+    // Like a lock wait, until another session runs this query
+    WaitForQuery(String),
+    SleepMs(u64),
+    // A NoticeResponse sent before the result, e.g. between two sleeps to
+    // report the progress of a long query
+    Notice(String),
+}
+
+/// The rows of a result, each value converted to a PgValue:
+/// `rows![[1, "alice"], [2, "bob"]]`
+#[macro_export]
+macro_rules! rows {
+    ($([$($value:expr),* $(,)?]),* $(,)?) => {
+        vec![$(
+            vec![$($crate::value::PgValue::from($value)),*]
+        ),*]
+    };
+}
+
+/// Build a ScriptedExecutor from a list of queries and their results:
+///
+/// ```
+/// use fakepostmaster::{pg_mock, rows};
+///
+/// let executor = pg_mock! {
+///     on "SELECT 1" => rows![[1]],
+///     on regex "(?i)select name from users.*" => rows![["alice"], ["bob"]],
+///     on normalized "select 1 + $1" => rows![[2]],
+/// }?;
+/// # anyhow::Ok(())
+/// ```
+///
+/// The query is matched exactly unless it is prefixed by `regex` or
+/// `normalized`. The result is rows![], with the columns typed after the
+/// first row, or any QueryResult. The macro returns an
+/// `anyhow::Result<ScriptedExecutor>`.
+///
+/// It is a `macro_rules!` of this crate, not a procedural macro of
+/// libpq-serde-macros: it only rearranges its input into calls to
+/// ScriptedExecutor and QueryMatcher, which that crate can't name since
+/// this one depends on it.
+#[macro_export]
+macro_rules! pg_mock {
+    (@matcher $query:literal) => {
+        anyhow::Ok($crate::matcher::QueryMatcher::exact($query))
+    };
+    (@matcher regex $query:literal) => {
+        $crate::matcher::QueryMatcher::regex($query)
+    };
+    (@matcher normalized $query:literal) => {
+        $crate::matcher::QueryMatcher::normalized($query)
+    };
+    ($(on $($kind:ident)? $query:literal => $result:expr),* $(,)?) => {
+        [$((
+            $crate::pg_mock!(@matcher $($kind)? $query),
+            $crate::handler::server::QueryResult::try_from($result).map_err(anyhow::Error::from),
+        )),*]
+        .into_iter()
+        .try_fold(
+            $crate::executor::ScriptedExecutor::new(),
+            |executor, (matcher, result)| anyhow::Ok(executor.on(matcher?, result?)),
+        )
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct ScriptedRule {
+    pub matcher: QueryMatcher,
+    pub result: QueryResult,
+    pub steps: Vec<Step>,
+}
+
+/// An executor answering queries from a list of rules, the first rule whose
+/// matcher accepts the query gives the result.
+#[derive(Debug, Clone)]
+pub struct ScriptedExecutor {
+    pub rules: Vec<ScriptedRule>,
+    // Sent when no rule matches the query
+    pub fallback: QueryResult,
+    // Shared by the sessions answered by this executor (or its clones)
+    pub schedule: Schedule,
+    // The time of the steps sleep_ms, virtual in a simulation
+    pub clock: Clock,
+    // Reported by the server, see Scenario::version
+    pub version: ServerVersion,
+    // The notices of the session running the query, see Handler::notices
+    pub notices: Notices,
+}
+
+impl Default for ScriptedExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScriptedExecutor {
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            fallback: QueryResult {
+                columns: Vec::new(),
+                rows: Vec::new(),
+                command_tag: String::from("SELECT 0"),
+                error: None,
+            },
+            schedule: Schedule::new(),
+            clock: Clock::Real,
+            version: ServerVersion::default(),
+            notices: Notices::default(),
+        }
+    }
+
+    pub fn on(self, matcher: QueryMatcher, result: QueryResult) -> Self {
+        self.on_scheduled(matcher, result, Vec::new())
+    }
+
+    /// The steps are run in order before the result is sent
+    pub fn on_scheduled(
+        mut self,
+        matcher: QueryMatcher,
+        result: QueryResult,
+        steps: Vec<Step>,
+    ) -> Self {
+        self.rules.push(ScriptedRule {
+            matcher,
+            result,
+            steps,
+        });
+        self
+    }
+
+    /// Answer with one jsonb column named `column`, one row per document
+    pub fn on_json(
+        self,
+        matcher: QueryMatcher,
+        column: &str,
+        documents: Vec<Value>,
+    ) -> anyhow::Result<Self> {
+        let result = QueryResult {
+            columns: vec![ColumnDescription::new(column, PgType::Jsonb)?],
+            command_tag: format!("SELECT {}", documents.len()),
+            rows: documents
+                .into_iter()
+                .map(|document| vec![PgValue::Jsonb(document)])
+                .collect(),
+            error: None,
+        };
+        Ok(self.on(matcher, result))
+    }
+
+    pub fn find(&self, query: &str) -> Option<&ScriptedRule> {
+        self.rules.iter().find(|rule| rule.matcher.matches(query))
+    }
+
+    fn run_step(&self, step: &Step) -> anyhow::Result<()> {
+        debug!("step: {step:?}");
+        match step {
+            Step::WaitFor(event) => self.schedule.wait_for(event, DEFAULT_TIMEOUT),
+            Step::Signal(event) => {
+                self.schedule.signal(event);
+                Ok(())
+            }
+            Step::Barrier { name, parties } => {
+                self.schedule.barrier(name, *parties, DEFAULT_TIMEOUT)
+            }
+            Step::WaitForQuery(query) => self.schedule.wait_for_query(query, DEFAULT_TIMEOUT),
+            Step::SleepMs(ms) => {
+                self.clock.sleep(Duration::from_millis(*ms));
+                Ok(())
+            }
+            Step::Notice(message) => {
+                self.notices.notice(message);
+                Ok(())
+            }
+        }
+    }
+
+    /// Can be given to the query handlers as `&|query| executor.execute(query)`
+    pub fn execute(&self, query: String) -> QueryResult {
+        self.schedule.query_executed(&query);
+        match self.find(&query) {
+            Some(rule) => {
+                for step in &rule.steps {
+                    // The result is still sent, the ordering assertions on the
+                    // schedule will tell what went wrong
+                    if let Err(e) = self.run_step(step) {
+                        error!("{e}");
+                    }
+                }
+                rule.result.clone()
+            }
+            None => {
+                warn!("No rule matches the query: {query}");
+                self.fallback.clone()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scripted_executor_first_match() -> anyhow::Result<()> {
+        let result = QueryResult {
+            columns: vec![ColumnDescription::new("id", PgType::Int4)?],
+            rows: vec![vec![PgValue::Int4(42)]],
+            command_tag: String::from("SELECT 1"),
+            error: None,
+        };
+        let executor = ScriptedExecutor::new()
+            .on(
+                QueryMatcher::normalized("select id from users where name = $1")?,
+                result.clone(),
+            )
+            .on(
+                QueryMatcher::regex("(?i)select .*")?,
+                QueryResult::default(),
+            );
+
+        assert_eq!(
+            result,
+            executor.execute(String::from("SELECT id FROM users WHERE name = 'bob'"))
+        );
+        assert_eq!(
+            QueryResult::default(),
+            executor.execute(String::from("SELECT 2"))
+        );
+        assert_eq!(
+            "SELECT 0",
+            executor.execute(String::from("VACUUM")).command_tag
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pg_mock_rules() -> anyhow::Result<()> {
+        let executor = crate::pg_mock! {
+            on "SELECT 1" => crate::rows![[1]],
+            on regex "(?i)select id, name .*" => crate::rows![[1, "alice"], [2i64, "bob"]],
+            on "VACUUM" => QueryResult::default(),
+        }?;
+
+        let result = executor.execute(String::from("SELECT 1"));
+        assert_eq!(vec![vec![PgValue::Int4(1)]], result.rows);
+        assert_eq!("SELECT 1", result.command_tag);
+        let result = executor.execute(String::from("select id, name from users"));
+        assert_eq!(i32::from(&PgType::Text), result.columns[1].datatype_id);
+        assert_eq!(
+            vec![PgValue::Int8(2), PgValue::Text(String::from("bob"))],
+            result.rows[1]
+        );
+        assert_eq!(
+            QueryResult::default(),
+            executor.execute(String::from("VACUUM"))
+        );
+        assert!(crate::pg_mock! { on "SELECT 1, 2" => crate::rows![[1, 2], [3]] }.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scripted_executor_json() -> anyhow::Result<()> {
+        let executor = ScriptedExecutor::new().on_json(
+            QueryMatcher::regex("(?i)select doc .*")?,
+            "doc",
+            vec![serde_json::json!({"id": 1}), serde_json::json!([true])],
+        )?;
+
+        let result = executor.execute(String::from("SELECT doc FROM documents"));
+        assert_eq!(i32::from(&PgType::Jsonb), result.columns[0].datatype_id);
+        assert_eq!(
+            vec![PgValue::Jsonb(serde_json::json!([true]))],
+            result.rows[1]
+        );
+        assert_eq!("SELECT 2", result.command_tag);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+//! The functions of PostgreSQL that a server answers when no rule matches
+//! the query, so that the health checks of the frameworks succeed without a
+//! scenario: `now()`, `current_timestamp`, `current_user`,
+//! `current_database()`, `version()` and `pg_backend_pid()`. A select list
+//! can have several of them, with aliases, e.g.
+//! `SELECT current_user AS name, version()`.
+//!
+//! The version settings are also answered, from the version of the
+//! scenario: `SHOW server_version`, `SHOW server_version_num` and
+//! `current_setting('server_version_num')`.
+//!
+//! The timestamps are without time zone and follow the clock of the server.
+
+use crate::datetime::timestamp_from_system_time;
+use crate::handler::server::{QueryResult, ServerVersion};
+use crate::matcher::normalize;
+use crate::message::ColumnDescription;
+use crate::sessions::SessionInfo;
+use crate::simulation::Clock;
+use crate::value::PgValue;
+
+/// The result of a select list made of the functions only, `None` for the
+/// other queries
+pub fn call(
+    query: &str,
+    session: &SessionInfo,
+    clock: &Clock,
+    version: &ServerVersion,
+) -> Option<anyhow::Result<QueryResult>> {
+    let normalized = normalize(query).ok()?;
+    let statement = normalized.trim_end_matches([' ', ';']);
+    let setting = |name: &str| match name {
+        "server_version" => Some(PgValue::Text(version.server_version.clone())),
+        "server_version_num" => Some(PgValue::Text(version.num().to_string())),
+        _ => None,
+    };
+    if let Some(name) = statement.strip_prefix("show ") {
+        let value = setting(name)?;
+        return Some(result(vec![(name, value)]));
+    }
+    let list = statement.strip_prefix("select ")?;
+    let text = |value: Option<&str>| PgValue::Text(String::from(value.unwrap_or_default()));
+    let mut items = Vec::new();
+    for item in list.split(" , ") {
+        let (function, alias) = match item.split_once(" as ") {
+            Some((function, alias)) => (function, Some(alias)),
+            None => (item, None),
+        };
+        let (name, value) = match function.strip_prefix("pg_catalog . ").unwrap_or(function) {
+            "now ( )" => ("now", now(clock)),
+            "current_timestamp" => ("current_timestamp", now(clock)),
+            "current_user" => ("current_user", text(session.startup.user())),
+            "current_database ( )" => ("current_database", text(session.startup.database())),
+            "version ( )" => ("version", PgValue::Text(version.banner())),
+            "pg_backend_pid ( )" => ("pg_backend_pid", PgValue::Int4(session.pid)),
+            function => {
+                let name = function
+                    .strip_prefix("current_setting ( '")?
+                    .strip_suffix("' )")?;
+                ("current_setting", setting(name)?)
+            }
+        };
+        // An unquoted alias is lowercased like the other identifiers
+        items.push((alias.map_or(name, |alias| alias.trim_matches('"')), value));
+    }
+    Some(result(items))
+}
+
+/// A row of the values given, by column name
+fn result(items: Vec<(&str, PgValue)>) -> anyhow::Result<QueryResult> {
+    let columns = items
+        .iter()
+        .map(|(name, value)| ColumnDescription::new(name, value.pg_type()))
+        .collect::<anyhow::Result<Vec<ColumnDescription>>>()?;
+    Ok(QueryResult {
+        columns,
+        rows: vec![items.into_iter().map(|(_, value)| value).collect()],
+        command_tag: String::from("SELECT 1"),
+        error: None,
+    })
+}
+
+fn now(clock: &Clock) -> PgValue {
+    PgValue::Timestamp(timestamp_from_system_time(clock.system_time()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::server::StartupParameters;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn functions() -> anyhow::Result<()> {
+        let session = SessionInfo {
+            pid: 7,
+            client_addr: None,
+            startup: StartupParameters {
+                parameters: vec![(String::from("user"), String::from("alice"))],
+            },
+            backend_start: SystemTime::UNIX_EPOCH,
+            queries: 0,
+            last_query: String::new(),
+            active: false,
+        };
+        let clock = Clock::manual();
+        let version = ServerVersion {
+            server_version: String::from("9.6.24"),
+            server_version_num: None,
+            banner: None,
+        };
+        let call = |query: &str| call(query, &session, &clock, &version);
+
+        let result = call("SELECT current_database(), pg_backend_pid() AS \"Pid\";")
+            .ok_or(anyhow::anyhow!("Not a function call"))??;
+        assert_eq!("current_database", result.columns[0].name.to_str()?);
+        assert_eq!("Pid", result.columns[1].name.to_str()?);
+        assert_eq!(
+            vec![vec![PgValue::from("alice"), PgValue::Int4(7)]],
+            result.rows
+        );
+
+        let before = call("select now()").ok_or(anyhow::anyhow!("Not a function call"))??;
+        if let Clock::Manual(manual) = &clock {
+            manual.advance(Duration::from_secs(1));
+        }
+        let after =
+            call("SELECT CURRENT_TIMESTAMP").ok_or(anyhow::anyhow!("Not a function call"))??;
+        match (&before.rows[0][0], &after.rows[0][0]) {
+            (PgValue::Timestamp(before), PgValue::Timestamp(after)) => {
+                assert_eq!(1_000_000, after - before)
+            }
+            rows => panic!("Not timestamps: {rows:?}"),
+        }
+
+        let result = call("SELECT version(), current_setting('server_version_num')")
+            .ok_or(anyhow::anyhow!("Not a function call"))??;
+        assert_eq!(
+            vec![vec![
+                PgValue::from("PostgreSQL 9.6.24"),
+                PgValue::from("90624")
+            ]],
+            result.rows
+        );
+        let result =
+            call("SHOW server_version").ok_or(anyhow::anyhow!("Not a function call"))??;
+        assert_eq!("server_version", result.columns[0].name.to_str()?);
+
+        let version = |server_version: &str| ServerVersion {
+            server_version: String::from(server_version),
+            ..ServerVersion::default()
+        };
+        assert_eq!(160002, version("16.2").num());
+        assert_eq!(170000, version("17beta1").num());
+
+        assert!(call("SELECT 1").is_none());
+        assert!(call("SHOW work_mem").is_none());
+        assert!(call("SELECT now() FROM t").is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+/// The outcome of a step of the GSSAPI token exchange
+#[derive(Debug, PartialEq)]
+pub enum GssStep {
+    /// The context is not established yet, the token is sent to the frontend
+    /// and the next token of the frontend is expected.
+    Continue(Vec<u8>),
+    /// The context is established, the last token (if any) is sent to the
+    /// frontend before AuthenticationOk.
+    Complete(Option<Vec<u8>>),
+}
+
+/// Accepts the GSSAPI or SSPI security context of a frontend, e.g. with the
+/// gssapi crate and a keytab. The tokens are opaque to the server, which only
+/// relays them in GSSResponse and AuthenticationGSSContinue messages.
+///
+/// An error ends the authentication with an ErrorResponse.
+pub trait GssAuthenticator {
+    /// Process a token sent by the frontend
+    fn accept(&mut self, token: &[u8]) -> anyhow::Result<GssStep>;
+}
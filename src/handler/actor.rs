@@ -0,0 +1,450 @@
+//! A session as an actor: its Handler reads the events of an inbox and writes
+//! the messages to an outbox, the IO is left to whoever feeds them. A socket
+//! is pumped by two threads, a test sends the events of a whole exchange and
+//! reads the answers without any socket.
+
+use anyhow::anyhow;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SendError, SyncSender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+use crate::handler::server::{Authentication, Handler, QueryResult, StartupParameters};
+
+/// What happens to a session, in the order it happens
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    // Bytes sent by the frontend, not necessarily whole messages
+    Received(Vec<u8>),
+    // A CancelRequest for the session, it cancels the query running
+    // without waiting for the handler to read it
+    Cancel,
+    // The frontend closed the connection
+    Disconnected,
+}
+
+// The events or the batches of messages a box holds before its sender
+// blocks
+const CAPACITY: usize = 64;
+
+/// The sending end of the inbox of a session
+#[derive(Clone)]
+pub struct InboxSender {
+    sender: SyncSender<Event>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl InboxSender {
+    /// Queue the event, blocking while the inbox is full. A Cancel sets the
+    /// flag checked during the execution of the queries right away.
+    pub fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        match event {
+            Event::Cancel => {
+                self.cancel.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            event => self.sender.send(event),
+        }
+    }
+}
+
+/// The events of a session, read by its Handler as a stream of bytes
+pub struct Inbox {
+    receiver: Receiver<Event>,
+    received: Vec<u8>,
+    position: usize,
+    disconnected: bool,
+    // Called before blocking on an empty inbox
+    waiting: Option<Box<dyn FnMut() + Send>>,
+}
+
+impl Read for Inbox {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.received.len() {
+            if self.disconnected {
+                return Ok(0);
+            }
+            let event = match self.receiver.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => {
+                    if let Some(waiting) = &mut self.waiting {
+                        waiting();
+                    }
+                    // The senders are gone once the frontend can't send
+                    // anything
+                    self.receiver.recv().unwrap_or(Event::Disconnected)
+                }
+                Err(TryRecvError::Disconnected) => Event::Disconnected,
+            };
+            match event {
+                Event::Received(bytes) => {
+                    self.received = bytes;
+                    self.position = 0;
+                }
+                // Handled by the InboxSender
+                Event::Cancel => (),
+                Event::Disconnected => self.disconnected = true,
+            }
+        }
+        let count = buf.len().min(self.received.len() - self.position);
+        buf[..count].copy_from_slice(&self.received[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// The messages of a session, sent in one batch each time the Handler
+/// flushes
+pub struct Outbox {
+    sender: SyncSender<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl Write for Outbox {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.sender
+            .send(std::mem::take(&mut self.pending))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The outbox is closed"))
+    }
+}
+
+/// A session without IO, its handler is configured like any other
+pub struct SessionActor {
+    pub handler: Handler<Inbox, Outbox>,
+    // Set once the frontend is authenticated
+    authenticated: Arc<AtomicBool>,
+}
+
+impl SessionActor {
+    /// The actor, the sender of its inbox and the receiver of its outbox
+    pub fn new() -> (Self, InboxSender, Receiver<Vec<u8>>) {
+        Self::with_cancel_flag(Arc::default())
+    }
+
+    /// An actor sharing the flag set by the Cancel events, e.g. with a
+    /// registry where the other sessions cancel this one
+    pub fn with_cancel_flag(cancel: Arc<AtomicBool>) -> (Self, InboxSender, Receiver<Vec<u8>>) {
+        let (inbox, receiver) = mpsc::sync_channel(CAPACITY);
+        let (sender, outbox) = mpsc::sync_channel(CAPACITY);
+        let mut handler = Handler::from_parts(
+            Inbox {
+                receiver,
+                received: Vec::new(),
+                position: 0,
+                disconnected: false,
+                waiting: None,
+            },
+            Outbox {
+                sender,
+                pending: Vec::new(),
+            },
+        );
+        handler.cancel = Some(cancel.clone());
+        let actor = Self {
+            handler,
+            authenticated: Arc::default(),
+        };
+        (
+            actor,
+            InboxSender {
+                sender: inbox,
+                cancel,
+            },
+            outbox,
+        )
+    }
+
+    /// Call `waiting` each time the handler waits for the next event, e.g.
+    /// to know when a simulated session is idle
+    pub fn on_waiting(&mut self, waiting: impl FnMut() + Send + 'static) {
+        self.handler.reader.get_mut().waiting = Some(Box::new(waiting));
+    }
+
+    /// Set once the frontend is authenticated, e.g. for the StartupLimits
+    /// of a Pump
+    pub fn authenticated(&self) -> Arc<AtomicBool> {
+        self.authenticated.clone()
+    }
+
+    /// Authenticate the frontend and answer its queries until it terminates
+    /// the connection
+    pub fn run(
+        &mut self,
+        method: &dyn Fn(&StartupParameters) -> Authentication,
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> anyhow::Result<()> {
+        self.handler.authentication_handler(method)?;
+        self.authenticated.store(true, Ordering::SeqCst);
+        while self.handler.query_handler(executor)? {}
+        Ok(())
+    }
+}
+
+/// Bounds on a connection until its frontend is authenticated, to close the
+/// ones that never finish, e.g. slowloris
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StartupLimits {
+    // Like authentication_timeout, from the connection
+    pub timeout: Duration,
+    // The bytes received, from the startup packet to the last password
+    pub max_bytes: usize,
+}
+
+impl Default for StartupLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            max_bytes: 65536,
+        }
+    }
+}
+
+/// Why the frontend can't go on, `None` while it is within the limits or
+/// once it is authenticated
+fn startup_violation(limits: &StartupLimits, start: Instant, received: usize) -> Option<String> {
+    if start.elapsed() >= limits.timeout {
+        Some(format!(
+            "the authentication took more than {:?}",
+            limits.timeout
+        ))
+    } else if received > limits.max_bytes {
+        Some(format!(
+            "more than {} bytes were sent before the authentication",
+            limits.max_bytes
+        ))
+    } else {
+        None
+    }
+}
+
+/// The threads moving the bytes between a socket and the boxes of an actor
+pub struct Pump {
+    stream: TcpStream,
+    writer: JoinHandle<()>,
+}
+
+impl Pump {
+    /// One thread reads the socket into the inbox, another writes the
+    /// outbox to the socket until the actor is dropped
+    pub fn new(
+        stream: &TcpStream,
+        inbox: InboxSender,
+        outbox: Receiver<Vec<u8>>,
+    ) -> anyhow::Result<Self> {
+        Self::spawn(stream, inbox, outbox, None)
+    }
+
+    /// Close the connection, like PostgreSQL without any message, when the
+    /// frontend goes over the limits before it is authenticated
+    pub fn with_startup_limits(
+        stream: &TcpStream,
+        inbox: InboxSender,
+        outbox: Receiver<Vec<u8>>,
+        limits: StartupLimits,
+        authenticated: Arc<AtomicBool>,
+    ) -> anyhow::Result<Self> {
+        Self::spawn(stream, inbox, outbox, Some((limits, authenticated)))
+    }
+
+    fn spawn(
+        stream: &TcpStream,
+        inbox: InboxSender,
+        outbox: Receiver<Vec<u8>>,
+        mut startup: Option<(StartupLimits, Arc<AtomicBool>)>,
+    ) -> anyhow::Result<Self> {
+        let mut reader = stream.try_clone()?;
+        let start = Instant::now();
+        thread::spawn(move || {
+            let mut buffer = [0; 8192];
+            let mut received = 0;
+            loop {
+                if let Some((limits, authenticated)) = &startup {
+                    if authenticated.load(Ordering::SeqCst) {
+                        startup = None;
+                        let _ = reader.set_read_timeout(None);
+                    } else if let Some(violation) = startup_violation(limits, start, received) {
+                        warn!(
+                            "Closing the connection of {}: {violation}",
+                            reader
+                                .peer_addr()
+                                .map_or(String::from("?"), |peer| peer.to_string())
+                        );
+                        let _ = reader.shutdown(Shutdown::Both);
+                        let _ = inbox.send(Event::Disconnected);
+                        break;
+                    } else {
+                        let remaining = limits.timeout.saturating_sub(start.elapsed());
+                        let _ =
+                            reader.set_read_timeout(Some(remaining.max(Duration::from_millis(1))));
+                    }
+                }
+                let event = match reader.read(&mut buffer) {
+                    Ok(0) => Event::Disconnected,
+                    Ok(count) => {
+                        received += count;
+                        // Not forwarded when over the limit
+                        if startup.as_ref().is_some_and(|(limits, authenticated)| {
+                            received > limits.max_bytes && !authenticated.load(Ordering::SeqCst)
+                        }) {
+                            continue;
+                        }
+                        Event::Received(buffer[..count].to_vec())
+                    }
+                    // The limits are checked again
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        continue;
+                    }
+                    Err(e) => {
+                        debug!("Reading the socket: {e}");
+                        Event::Disconnected
+                    }
+                };
+                let disconnected = event == Event::Disconnected;
+                if inbox.send(event).is_err() || disconnected {
+                    break;
+                }
+            }
+        });
+        let mut writer = stream.try_clone()?;
+        let writer = thread::spawn(move || {
+            for bytes in outbox {
+                if let Err(e) = writer.write_all(&bytes) {
+                    debug!("Writing the socket: {e}");
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            stream: stream.try_clone()?,
+            writer,
+        })
+    }
+
+    /// Wait for the messages left in the outbox to be written and close
+    /// the socket, the actor must be dropped first
+    pub fn close(self) -> anyhow::Result<()> {
+        self.writer
+            .join()
+            .map_err(|_| anyhow!("The writer thread panicked"))?;
+        // Ends the reader thread
+        let _ = self.stream.shutdown(Shutdown::Both);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::{LibPqReader, LibPqWriter};
+    use crate::message::*;
+    use std::io::{BufReader, BufWriter};
+
+    #[test]
+    fn session_actor() -> anyhow::Result<()> {
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_request(StartupMessage::new(
+            ProtocolVersion { major: 3, minor: 0 },
+            vec![ParameterStatus::new("user", "postgres")?],
+        ))?;
+        frontend.put_message(Query::new(String::from("SELECT 1"))?)?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let (mut actor, inbox, outbox) = SessionActor::new();
+        // The messages can be split anywhere
+        for bytes in frontend.chunks(7) {
+            inbox.send(Event::Received(bytes.to_vec()))?;
+        }
+        drop(inbox);
+        actor.run(&|_| Authentication::Trust, &|_| QueryResult {
+            command_tag: String::from("SELECT 0"),
+            ..Default::default()
+        })?;
+        drop(actor);
+
+        let backend: Vec<u8> = outbox.into_iter().flatten().collect();
+        let mut reader = BufReader::new(&backend[..]);
+        let mut received = String::new();
+        while let Ok(raw_message) = reader.get_raw_backend_message() {
+            received.push(raw_message.header.message_type as char);
+        }
+        assert_eq!("RSSSSZCZ", received);
+
+        // The frontend disconnects in the middle of the startup
+        let (mut actor, inbox, _outbox) = SessionActor::new();
+        inbox.send(Event::Received(frontend[..3].to_vec()))?;
+        inbox.send(Event::Disconnected)?;
+        assert!(
+            actor
+                .run(&|_| Authentication::Trust, &|_| Default::default())
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_running_query() -> anyhow::Result<()> {
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_request(StartupMessage::new(
+            ProtocolVersion { major: 3, minor: 0 },
+            vec![ParameterStatus::new("user", "postgres")?],
+        ))?;
+        frontend.put_message(Query::new(String::from("SELECT pg_sleep(10)"))?)?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let (mut actor, inbox, outbox) = SessionActor::new();
+        inbox.send(Event::Received(frontend))?;
+        let (started, running) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let session = thread::spawn(move || {
+            actor.run(&|_| Authentication::Trust, &|_| {
+                let _ = started.send(());
+                // Until the test has sent the Cancel
+                let _ = released.recv();
+                QueryResult {
+                    command_tag: String::from("SELECT 1"),
+                    ..Default::default()
+                }
+            })
+        });
+        running.recv()?;
+        inbox.send(Event::Cancel)?;
+        release.send(())?;
+        session
+            .join()
+            .map_err(|_| anyhow!("The session panicked"))??;
+
+        let backend: Vec<u8> = outbox.into_iter().flatten().collect();
+        let mut reader = BufReader::new(&backend[..]);
+        let mut received = String::new();
+        while let Ok(mut raw_message) = reader.get_raw_backend_message() {
+            received.push(raw_message.header.message_type as char);
+            if raw_message.header.message_type == b'E' {
+                let error = ErrorResponse::try_from(&mut raw_message)?;
+                assert!(format!("{error:?}").contains("57014"));
+            }
+        }
+        assert_eq!("RSSSSZEZ", received);
+
+        Ok(())
+    }
+}
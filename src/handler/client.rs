@@ -1,114 +1,1134 @@
 use anyhow::anyhow;
+use libpq_serde_types::{ByteSized, Serialize};
 use std::{
-    io::{BufReader, BufWriter},
-    net::TcpStream,
+    collections::HashMap,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    net::SocketAddr,
+    time::{Duration, Instant},
 };
 use tracing::*;
 
-use crate::handler::{LibPqReader, LibPqWriter};
+#[cfg(not(target_family = "wasm"))]
+use std::{
+    net::TcpStream,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use crate::compression::{self, COMPRESSION_OPTION, CompressionSwitch};
+#[cfg(not(target_family = "wasm"))]
+use crate::dual_stack;
+use crate::handler::copy::{CopyDirection, CopyProgress};
+use crate::handler::{LibPqReader, LibPqWriter, MessageStats};
 use crate::message::*;
+use crate::protocol::{self, Answer, AnswerEffect};
+use crate::random;
+use crate::simulation::Clock;
+use crate::stats::QueryStats;
+
+/// The client side of a connection over any transport that can be read and
+/// written, e.g. the two halves of a WebSocket, or the sockets of the host
+/// on wasm32 where there is no TcpStream.
+pub struct Handler<R, W: Write> {
+    pub reader: BufReader<R>,
+    pub writer: BufWriter<W>,
+    // The asynchronous messages can be sent by the server at any time, they
+    // are given to these callbacks instead of the receive loops.
+    pub on_notice: Box<dyn FnMut(NoticeResponse) + Send>,
+    pub on_parameter_status: Box<dyn FnMut(ParameterStatus) + Send>,
+    pub on_notification: Box<dyn FnMut(NotificationResponse) + Send>,
+    // Told about each CopyData of copy_in() and copy_out()
+    pub on_copy_progress: Box<dyn FnMut(CopyProgress) + Send>,
+    // The last value reported by the server for each parameter, see
+    // parameter()
+    parameters: HashMap<String, String>,
+    // keep_alive pings the server after this idle time
+    pub ping_interval: Option<Duration>,
+    last_activity: Instant,
+    // The time of the keepalives and of the reconnection delays
+    pub clock: Clock,
+    // The server to reconnect to, see TcpHandler::ensure_connected
+    peer: Option<SocketAddr>,
+    // Set when the transport is a CompressedReader and a CompressedWriter,
+    // the compression is then requested with _pq_.compression
+    pub compression: Option<CompressionSwitch>,
+    // The round trips of the queries, from the Query sent to its
+    // ReadyForQuery, measured with the clock
+    pub stats: Option<QueryStats>,
+    // The messages sent and received by kind
+    pub message_stats: MessageStats,
+    // The process id and the secret key of BackendKeyData, for a
+    // CancelRequest
+    pub backend_key: Option<(i32, i32)>,
+}
+
+/// A notification sent by NOTIFY or pg_notify() on a channel listened to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub pid: i32,
+    pub channel: String,
+    pub payload: String,
+}
+
+impl TryFrom<NotificationResponse> for Notification {
+    type Error = anyhow::Error;
+
+    fn try_from(message: NotificationResponse) -> anyhow::Result<Self> {
+        Ok(Self {
+            pid: message.process_id,
+            channel: message.channel.into_string()?,
+            payload: message.payload.into_string()?,
+        })
+    }
+}
+
+/// The notifications received after Handler::listen, the iterator blocks
+/// until the next one and ends after an error.
+pub struct NotificationStream<'a, R, W: Write> {
+    handler: &'a mut Handler<R, W>,
+    failed: bool,
+}
+
+impl<R, W> Iterator for NotificationStream<'_, R, W>
+where
+    R: Read,
+    W: Write,
+{
+    type Item = anyhow::Result<Notification>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let notification = self.handler.get_notification();
+        self.failed = notification.is_err();
+        Some(notification)
+    }
+}
+
+/// How TcpHandler::ensure_connected reconnects: the delay before the
+/// attempt n doubles from backoff up to max_backoff, and a random part of
+/// it (jitter, from 0 to 1) is removed so that the clients don't reconnect
+/// all at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: f64,
+    // For the ping and each connection attempt
+    pub timeout: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.5,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn new(max_attempts: u32, backoff: Duration) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+            ..Self::default()
+        }
+    }
 
-pub struct TcpHandler {
-    pub tcp_reader: BufReader<TcpStream>,
-    pub tcp_writer: BufWriter<TcpStream>,
+    /// The delay before the attempt, from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let random = random::random_u64() as f64 / u64::MAX as f64;
+        delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random)
+    }
 }
 
+// The size of the CopyData messages sent by copy_in()
+pub const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+// There is no TcpStream to connect or clone on wasm32, see Handler
+#[cfg(not(target_family = "wasm"))]
+pub type TcpHandler = Handler<TcpStream, TcpStream>;
+
+#[cfg(not(target_family = "wasm"))]
 impl TcpHandler {
     pub fn new(stream: TcpStream) -> anyhow::Result<Self> {
+        let peer = stream.peer_addr().ok();
         Ok(Self {
-            tcp_reader: BufReader::new(stream.try_clone().expect("Failed to clone TcpStream")),
-            tcp_writer: BufWriter::new(stream),
+            peer,
+            ..Self::from_parts(
+                stream.try_clone().expect("Failed to clone TcpStream"),
+                stream,
+            )
+        })
+    }
+
+    /// Connect to the first address of a name that answers, e.g. ::1 or
+    /// 127.0.0.1 for localhost, see dual_stack::connect
+    pub fn connect(address: &str, timeout: Duration) -> anyhow::Result<Self> {
+        Self::new(dual_stack::connect(address, timeout)?)
+    }
+
+    /// Check the connection with a ping, after a failure connect again and
+    /// authenticate with the attempts of the policy. The callbacks and the
+    /// options are kept, the buffered messages are dropped.
+    ///
+    /// Whether the connection was replaced.
+    pub fn ensure_connected(&mut self, policy: &ReconnectPolicy) -> anyhow::Result<bool> {
+        if self.is_alive(policy.timeout) {
+            return Ok(false);
+        }
+        let peer = self
+            .peer
+            .ok_or(anyhow!("No server address to reconnect to"))?;
+
+        let mut last_error = anyhow!("No reconnection attempt");
+        for attempt in 0..policy.max_attempts {
+            self.clock.sleep(policy.delay(attempt));
+            match self.reconnect(peer, policy.timeout) {
+                Ok(()) => {
+                    info!("Reconnected to {peer} after {} attempts", attempt + 1);
+                    return Ok(true);
+                }
+                Err(e) => {
+                    warn!("Reconnecting to {peer}: {e}");
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// LISTEN to a channel and receive the notifications from a thread,
+    /// which stops after an error or when the receiver is dropped
+    pub fn listen_in_background(
+        mut self,
+        channel: &str,
+    ) -> anyhow::Result<Receiver<anyhow::Result<Notification>>> {
+        self.listen(channel)?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let stream = NotificationStream {
+                handler: &mut self,
+                failed: false,
+            };
+            for notification in stream {
+                if sender.send(notification).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(receiver)
+    }
+
+    fn reconnect(&mut self, peer: SocketAddr, timeout: Duration) -> anyhow::Result<()> {
+        let stream = TcpStream::connect_timeout(&peer, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        self.reader = BufReader::new(stream.try_clone()?);
+        self.writer = BufWriter::new(stream.try_clone()?);
+        self.parameters.clear();
+        self.md5_authentication_handler()?;
+        stream.set_read_timeout(None)?;
+        self.last_activity = self.clock.now();
+        Ok(())
+    }
+
+    /// Whether the server answers a ping within the timeout, e.g. to
+    /// validate a pooled connection before using it
+    pub fn is_alive(&mut self, timeout: Duration) -> bool {
+        let stream = self.reader.get_ref();
+        let timeouts = (stream.read_timeout(), stream.write_timeout());
+        let alive = stream
+            .set_read_timeout(Some(timeout))
+            .and_then(|_| stream.set_write_timeout(Some(timeout)))
+            .is_ok()
+            && self.ping().is_ok();
+
+        let stream = self.reader.get_ref();
+        if let (Ok(read_timeout), Ok(write_timeout)) = timeouts
+            && let Err(e) = stream
+                .set_read_timeout(read_timeout)
+                .and_then(|_| stream.set_write_timeout(write_timeout))
+        {
+            debug!("Timeouts not restored: {e}");
+        }
+        alive
+    }
+}
+
+impl<R, W> Handler<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    pub fn from_parts(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            on_notice: Box::new(|message| debug!("rcv: {message:?}")),
+            on_parameter_status: Box::new(|message| debug!("rcv: {message:?}")),
+            on_notification: Box::new(|message| debug!("rcv: {message:?}")),
+            on_copy_progress: Box::new(|progress| trace!("{progress:?}")),
+            parameters: HashMap::new(),
+            ping_interval: None,
+            last_activity: Instant::now(),
+            clock: Clock::Real,
+            peer: None,
+            compression: None,
+            stats: None,
+            message_stats: MessageStats::default(),
+            backend_key: None,
+        }
+    }
+
+    fn put_message<U>(&mut self, msg: U) -> anyhow::Result<()>
+    where
+        U: MessageBody + Serialize + ByteSized + std::fmt::Debug,
+    {
+        self.message_stats
+            .record_frontend(msg.message_type(), msg.byte_size().saturating_add(5));
+        self.writer.put_message(msg)
+    }
+
+    fn put_message_and_flush<U>(&mut self, msg: U) -> anyhow::Result<()>
+    where
+        U: MessageBody + Serialize + ByteSized + std::fmt::Debug,
+    {
+        self.put_message(msg)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Get the next message that is not an asynchronous message, an
+    /// ErrorResponse is returned as an error
+    pub fn get_raw_backend_message(&mut self) -> anyhow::Result<RawBackendMessage> {
+        self.get_message(false, false)
+    }
+
+    /// Get the next message, the asynchronous ones go to their callbacks but
+    /// the notifications are returned when `notifications` is set, and the
+    /// ErrorResponses when `errors` is set
+    fn get_message(
+        &mut self,
+        notifications: bool,
+        errors: bool,
+    ) -> anyhow::Result<RawBackendMessage> {
+        loop {
+            let mut raw_message = self.reader.get_raw_backend_message()?;
+            self.message_stats.record_backend(
+                raw_message.header.message_type,
+                raw_message.raw_body.len() + 5,
+            );
+            match raw_message.get_message_kind() {
+                Some(BackendMessageKind::ErrorResponse) if !errors => {
+                    let error = ErrorResponse::try_from(&mut raw_message)?;
+                    //FIXME:
+                    error!("{error:?}");
+                    return Err(anyhow!("Error"));
+                }
+                Some(BackendMessageKind::NoticeResponse) => {
+                    (self.on_notice)(NoticeResponse::try_from(&mut raw_message)?)
+                }
+                Some(BackendMessageKind::ParameterStatus) => {
+                    let message = ParameterStatus::try_from(&mut raw_message)?;
+                    self.parameters.insert(
+                        message.name.to_str()?.to_string(),
+                        message.value.to_str()?.to_string(),
+                    );
+                    (self.on_parameter_status)(message)
+                }
+                Some(BackendMessageKind::NotificationResponse) if !notifications => {
+                    (self.on_notification)(NotificationResponse::try_from(&mut raw_message)?)
+                }
+                _ => return Ok(raw_message),
+            }
+        }
+    }
+
+    /// The value of a parameter reported by the server at the startup or
+    /// when it changed, e.g. server_version or DateStyle after a SET
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters.get(name).map(|value| &value[..])
+    }
+
+    /// The parameters reported by the server, see parameter()
+    pub fn parameters(&self) -> &HashMap<String, String> {
+        &self.parameters
+    }
+
+    /// Wait for the next notification, the notices and the parameters
+    /// received meanwhile go to their callbacks
+    pub fn get_notification(&mut self) -> anyhow::Result<Notification> {
+        let mut raw_message = self.get_message(true, false)?;
+        match raw_message.get_message_kind() {
+            Some(BackendMessageKind::NotificationResponse) => {
+                let message = NotificationResponse::try_from(&mut raw_message)?;
+                debug!("rcv: {message:?}");
+                Notification::try_from(message)
+            }
+            kind => Err(anyhow!(
+                "Unexpected message {kind:?} while waiting for a notification"
+            )),
+        }
+    }
+
+    /// LISTEN to a channel, the notifications are then read from the stream
+    /// instead of on_notification
+    pub fn listen(&mut self, channel: &str) -> anyhow::Result<NotificationStream<'_, R, W>> {
+        self.simple_query_handler(&format!("LISTEN \"{}\"", channel.replace('"', "\"\"")))?;
+        Ok(NotificationStream {
+            handler: self,
+            failed: false,
         })
     }
 
     pub fn md5_authentication_handler(&mut self) -> anyhow::Result<()> {
         // StartupMessage (ssl_mode ) prefer => Text Auth
-        self.tcp_writer.put_request(StartupMessage::new(
+        let mut parameters = vec![
+            ParameterStatus::new(&(String::from("user")), &(String::from("md5user")))?,
+            ParameterStatus::new(&(String::from("database")), &(String::from("postgres")))?,
+            ParameterStatus::new(
+                &(String::from("application_name")),
+                &(String::from("pgfake")),
+            )?,
+            ParameterStatus::new(&(String::from("client_encoding")), &(String::from("utf8")))?,
+        ];
+        let algorithms: Vec<&str> = compression::available()
+            .iter()
+            .map(|compressor| compressor.name())
+            .collect();
+        if self.compression.is_some() && !algorithms.is_empty() {
+            parameters.push(ParameterStatus::new(
+                COMPRESSION_OPTION,
+                &algorithms.join(","),
+            )?);
+        }
+        self.writer.put_request(StartupMessage::new(
             ProtocolVersion { major: 3, minor: 0 },
-            vec![
-                ParameterStatus::new(&(String::from("user")), &(String::from("md5user")))?,
-                ParameterStatus::new(&(String::from("database")), &(String::from("postgres")))?,
-                ParameterStatus::new(
-                    &(String::from("application_name")),
-                    &(String::from("pgfake")),
-                )?,
-                ParameterStatus::new(&(String::from("client_encoding")), &(String::from("utf8")))?,
-            ],
+            parameters,
         ))?;
 
-        // Receive Athentication message from server
-        //let mut raw_message = RawBackendMessage::get(&mut self.tcp_reader)?;
-        let mut raw_message = self.tcp_reader.get_raw_backend_message()?;
+        // Receive Athentication message from server, after the protocol
+        // options it doesn't know
+        let mut raw_message = self.get_raw_backend_message()?;
+        if let Some(BackendMessageKind::NegotiateProtocolVersion) = raw_message.get_message_kind() {
+            debug!(
+                "rcv: {:?}",
+                NegotiateProtocolVersion::try_from(&mut raw_message)?
+            );
+            raw_message = self.get_raw_backend_message()?;
+        }
         match AuthenticationMD5Password::try_from(&mut raw_message) {
             Ok(message) => {
                 debug!("rcv: {message:?}");
-                self.tcp_writer
-                    .put_message_and_flush(PasswordMessage::new_from_user_password(
-                        &"md5user".to_string(),
-                        &"md5pass".to_string(),
-                        &message.salt,
-                    )?)?;
+                self.put_message_and_flush(PasswordMessage::new_from_user_password(
+                    &"md5user".to_string(),
+                    &"md5pass".to_string(),
+                    &message.salt,
+                )?)?;
             }
             Err(_) => return Err(anyhow!("AuthenticationMD5Password message expected")),
         }
 
         // Receive Authentication Ok
-        let mut raw_message = self.tcp_reader.get_raw_backend_message()?;
+        let mut raw_message = self.get_raw_backend_message()?;
         match AuthenticationOk::try_from(&mut raw_message) {
             Ok(message) => debug!("rcv: {message:?}"),
             _ => return Err(anyhow!("AuthenticationOk message expected")),
         };
 
-        // ParameterStatus Messages
-        let mut raw_message = self.tcp_reader.get_raw_backend_message()?;
-        while let Some(BackendMessageKind::ParameterStatus) = raw_message.get_message_kind() {
-            debug!("rcv: {:?}", ParameterStatus::try_from(&mut raw_message)?);
-
-            raw_message = self.tcp_reader.get_raw_backend_message()?;
+        // The ParameterStatus messages go to their callback, BackendKeyData
+        // is optional
+        let mut raw_message = self.get_raw_backend_message()?;
+        if let Some(BackendMessageKind::BackendKeyData) = raw_message.get_message_kind() {
+            let key = BackendKeyData::try_from(&mut raw_message)?;
+            debug!("rcv: {key:?}");
+            self.backend_key = Some((key.process_id, key.secret_key));
+            raw_message = self.get_raw_backend_message()?;
         }
 
-        // BackendKeyData
-        match BackendKeyData::try_from(&mut raw_message) {
+        // ReadyForQuery
+        match ReadyForQuery::try_from(&mut raw_message) {
             Ok(message) => debug!("rcv: {message:?}"),
-            _ => return Err(anyhow!("BackendKeyData message expected")),
+            _ => return Err(anyhow!("ReadyForQuery message expected")),
         }
 
-        // ReadyForQuery
-        let mut raw_message = self.tcp_reader.get_raw_backend_message()?;
+        // The messages following the first ReadyForQuery are compressed with
+        // the algorithm chosen by the server
+        if let Some(switch) = &self.compression
+            && let Some(name) = self.parameter(COMPRESSION_OPTION)
+        {
+            let compressor = compression::negotiate(name)
+                .ok_or(anyhow!("Unknown compression algorithm {name}"))?;
+            switch.activate(compressor);
+        }
+
+        Ok(())
+    }
+
+    /// Send an empty query, a working connection answers with
+    /// EmptyQueryResponse and ReadyForQuery
+    pub fn ping(&mut self) -> anyhow::Result<()> {
+        self.put_message_and_flush(Query::new(String::new())?)?;
+
+        let raw_message = self.get_raw_backend_message()?;
+        match raw_message.get_message_kind() {
+            Some(BackendMessageKind::EmptyQuery) => debug!("rcv: EmptyQueryResponse"),
+            kind => return Err(anyhow!("EmptyQueryResponse expected, got {kind:?}")),
+        }
+        let mut raw_message = self.get_raw_backend_message()?;
         match ReadyForQuery::try_from(&mut raw_message) {
             Ok(message) => debug!("rcv: {message:?}"),
             _ => return Err(anyhow!("ReadyForQuery message expected")),
         }
+        self.last_activity = self.clock.now();
+        Ok(())
+    }
+
+    /// Ping the server when the connection has been idle for ping_interval,
+    /// to keep it open through the idle timeouts of the server or of the
+    /// network. Call it regularly, e.g. in the loop of a monitoring script,
+    /// it tells whether a ping was sent.
+    pub fn keep_alive(&mut self) -> anyhow::Result<bool> {
+        match self.ping_interval {
+            Some(interval)
+                if self
+                    .clock
+                    .now()
+                    .saturating_duration_since(self.last_activity)
+                    >= interval =>
+            {
+                self.ping()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Send a simple query and return the rows of its results, the query can
+    /// contain several statements.
+    pub fn simple_query_handler(&mut self, query: &str) -> anyhow::Result<Vec<DataRow>> {
+        let sent = self.clock.now();
+        self.put_message_and_flush(Query::new(query.to_string())?)?;
+
+        let mut rows = Vec::new();
+        let mut error = None;
+        let mut state = Answer::QueryStarted;
+        loop {
+            let mut raw_message = self.get_message(false, true)?;
+            let kind = raw_message.get_message_kind();
+            match protocol::answer_transition(state, raw_message.header.message_type) {
+                Some(AnswerEffect::Next(next)) => {
+                    match kind {
+                        Some(BackendMessageKind::RowDescription) => {
+                            debug!("rcv: {:?}", RowDescription::try_from(&mut raw_message)?)
+                        }
+                        Some(BackendMessageKind::DataRow) => {
+                            let message = DataRow::try_from(&mut raw_message)?;
+                            debug!("rcv: {message:?}");
+                            rows.push(message);
+                        }
+                        Some(BackendMessageKind::CommandComplete) => {
+                            debug!("rcv: {:?}", CommandComplete::try_from(&mut raw_message)?)
+                        }
+                        Some(BackendMessageKind::ErrorResponse) => {
+                            let message = ErrorResponse::try_from(&mut raw_message)?;
+                            debug!("rcv: {message:?}");
+                            error = Some(message);
+                        }
+                        _ => debug!("rcv: {kind:?}"),
+                    }
+                    state = next;
+                }
+                Some(AnswerEffect::Done) => {
+                    let message = ReadyForQuery::try_from(&mut raw_message)?;
+                    debug!("rcv: {message:?}");
+                    TransactionIndicator::try_from(&message.transaction_indicator)?;
+                    self.last_activity = self.clock.now();
+                    if let Some(stats) = &self.stats {
+                        let context = match self.peer {
+                            Some(peer) => format!("server={peer}"),
+                            None => String::from("client"),
+                        };
+                        stats.record(query, self.last_activity - sent, &context);
+                    }
+                    return match error {
+                        Some(error) => Err(anyhow!("Query failed: {error:?}")),
+                        None => Ok(rows),
+                    };
+                }
+                _ => {
+                    return Err(anyhow!("Unexpected message {kind:?} in state {state:?}"));
+                }
+            }
+        }
+    }
+
+    /// Run a COPY ... FROM STDIN with the data read from `input`, sent in
+    /// chunks of COPY_CHUNK_SIZE bytes. The number of rows copied.
+    ///
+    /// An error reading the input aborts the COPY with a CopyFail giving
+    /// its message, the server then fails the COPY with it.
+    pub fn copy_in(&mut self, query: &str, input: &mut dyn Read) -> anyhow::Result<u64> {
+        self.copy(query, Some(input), None)
+    }
+
+    /// Run a COPY ... TO STDOUT, the data is written to `output`. The
+    /// number of rows copied.
+    ///
+    /// After an error of the server, `output` has the rows received before
+    /// it.
+    pub fn copy_out(&mut self, query: &str, output: &mut dyn Write) -> anyhow::Result<u64> {
+        self.copy(query, None, Some(output))
+    }
+
+    fn copy(
+        &mut self,
+        query: &str,
+        mut input: Option<&mut dyn Read>,
+        mut output: Option<&mut dyn Write>,
+    ) -> anyhow::Result<u64> {
+        self.put_message_and_flush(Query::new(query.to_string())?)?;
+
+        let mut rows = None;
+        let mut error = None;
+        let mut copying_out: Option<CopyProgress> = None;
+        let mut state = Answer::QueryStarted;
+        loop {
+            let mut raw_message = self.get_message(false, true)?;
+            let kind = raw_message.get_message_kind();
+            // The data goes to the output up to the CopyDone, an error ends
+            // the COPY and the answer
+            if let Some(progress) = &mut copying_out {
+                match kind {
+                    Some(BackendMessageKind::CopyData) => {
+                        let message = CopyData::try_from(&mut raw_message)?;
+                        if let Some(output) = &mut output {
+                            output.write_all(message.data.as_ref())?;
+                        }
+                        progress.add(message.data.as_ref());
+                        (self.on_copy_progress)(*progress);
+                        continue;
+                    }
+                    Some(BackendMessageKind::CopyDone) => {
+                        debug!("rcv: {:?}", CopyDone::try_from(&mut raw_message)?);
+                        copying_out = None;
+                        continue;
+                    }
+                    _ => copying_out = None,
+                }
+            }
+            match protocol::answer_transition(state, raw_message.header.message_type) {
+                Some(AnswerEffect::CopyIn(next)) => {
+                    debug!("rcv: {:?}", CopyInResponse::try_from(&mut raw_message)?);
+                    match &mut input {
+                        Some(input) => self.put_copy_data(*input)?,
+                        None => self
+                            .put_message_and_flush(CopyFail::new("no data for COPY FROM STDIN")?)?,
+                    }
+                    state = next;
+                }
+                Some(AnswerEffect::CopyOut(next)) => {
+                    debug!("rcv: {:?}", CopyOutResponse::try_from(&mut raw_message)?);
+                    copying_out = Some(CopyProgress::new(CopyDirection::Out));
+                    state = next;
+                }
+                Some(AnswerEffect::Next(next)) => {
+                    match kind {
+                        Some(BackendMessageKind::CommandComplete) => {
+                            let message = CommandComplete::try_from(&mut raw_message)?;
+                            debug!("rcv: {message:?}");
+                            rows = message
+                                .command_tag
+                                .to_str()?
+                                .strip_prefix("COPY ")
+                                .and_then(|count| count.parse().ok());
+                        }
+                        Some(BackendMessageKind::ErrorResponse) => {
+                            let message = ErrorResponse::try_from(&mut raw_message)?;
+                            debug!("rcv: {message:?}");
+                            error = Some(message);
+                        }
+                        _ => debug!("rcv: {kind:?}"),
+                    }
+                    state = next;
+                }
+                Some(AnswerEffect::Done) => {
+                    let message = ReadyForQuery::try_from(&mut raw_message)?;
+                    debug!("rcv: {message:?}");
+                    TransactionIndicator::try_from(&message.transaction_indicator)?;
+                    self.last_activity = self.clock.now();
+                    return match (error, rows) {
+                        (Some(error), _) => Err(anyhow!("COPY failed: {error:?}")),
+                        (None, Some(rows)) => Ok(rows),
+                        (None, None) => Err(anyhow!("Not a COPY: {query}")),
+                    };
+                }
+                _ => {
+                    return Err(anyhow!("Unexpected message {kind:?} in state {state:?}"));
+                }
+            }
+        }
+    }
+
+    /// Send the data of a COPY FROM STDIN up to the CopyDone
+    fn put_copy_data(&mut self, input: &mut dyn Read) -> anyhow::Result<()> {
+        let mut progress = CopyProgress::new(CopyDirection::In);
+        let mut buffer = vec![0; COPY_CHUNK_SIZE];
+        loop {
+            let size = match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return self.put_message_and_flush(CopyFail::new(&e.to_string())?);
+                }
+            };
+            self.put_message(CopyData::new(&buffer[..size]))?;
+            progress.add(&buffer[..size]);
+            (self.on_copy_progress)(progress);
+        }
+        self.put_message_and_flush(CopyDone::new())
+    }
+
+    /// Call a function with the fastpath protocol, e.g. the lo_* functions
+    /// of the large objects, the arguments and the result are in binary
+    pub fn function_call(
+        &mut self,
+        function: u32,
+        arguments: Vec<ColumnData>,
+    ) -> anyhow::Result<ColumnData> {
+        self.put_message_and_flush(FunctionCall::new(function, arguments))?;
+
+        let mut result = Err(anyhow!("No result"));
+        let mut state = Answer::FunctionCall;
+        loop {
+            let mut raw_message = self.get_message(false, true)?;
+            let kind = raw_message.get_message_kind();
+            match protocol::answer_transition(state, raw_message.header.message_type) {
+                Some(AnswerEffect::Next(next)) => {
+                    result = match kind {
+                        Some(BackendMessageKind::FunctionCallResponse) => {
+                            let message = FunctionCallResponse::try_from(&mut raw_message)?;
+                            debug!("rcv: {message:?}");
+                            Ok(message.result)
+                        }
+                        _ => {
+                            let message = ErrorResponse::try_from(&mut raw_message)?;
+                            debug!("rcv: {message:?}");
+                            Err(anyhow!("Function call failed: {message:?}"))
+                        }
+                    };
+                    state = next;
+                }
+                Some(AnswerEffect::Done) => {
+                    let message = ReadyForQuery::try_from(&mut raw_message)?;
+                    debug!("rcv: {message:?}");
+                    TransactionIndicator::try_from(&message.transaction_indicator)?;
+                    self.last_activity = self.clock.now();
+                    return result;
+                }
+                _ => {
+                    return Err(anyhow!("Unexpected message {kind:?} in state {state:?}"));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::handler::probe::{self, ConnInfo};
+    use crate::scenario::Scenario;
+    use crate::test_server::TestServer;
+    use crate::value::{OutputSettings, PgValue};
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn simple_query_interleaved_messages() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut handler = TcpHandler::new(TcpStream::connect(listener.local_addr()?)?)?;
+        let mut server = BufWriter::new(listener.accept()?.0);
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let notices = received.clone();
+        handler.on_notice = Box::new(move |_| notices.lock().unwrap().push("notice"));
+        let parameters = received.clone();
+        handler.on_parameter_status =
+            Box::new(move |_| parameters.lock().unwrap().push("parameter"));
+        let notifications = received.clone();
+        handler.on_notification =
+            Box::new(move |_| notifications.lock().unwrap().push("notification"));
+
+        server.put_message(RowDescription::new(vec![ColumnDescription::new(
+            "n",
+            PgType::Int4,
+        )?]))?;
+        server.put_message(DataRow::new_from_values(
+            &[PgValue::Int4(1)],
+            &[],
+            &OutputSettings::default(),
+        )?)?;
+        server.put_message(NoticeResponse::new(vec![ErrorMessage::new('M', "hi")?]))?;
+        server.put_message(ParameterStatus::new("TimeZone", "UTC")?)?;
+        server.put_message(DataRow::new_from_values(
+            &[PgValue::Int4(2)],
+            &[],
+            &OutputSettings::default(),
+        )?)?;
+        server.put_message(CommandComplete::new(String::from("SELECT 2"))?)?;
+        server.put_message(NotificationResponse::new(42, "jobs", "new")?)?;
+        server.put_message(ReadyForQuery::new(TransactionIndicator::Idle))?;
+        server.flush()?;
+
+        assert_eq!(2, handler.simple_query_handler("SELECT n FROM t")?.len());
+        assert_eq!(
+            vec!["notice", "parameter", "notification"],
+            *received.lock().unwrap()
+        );
+
+        // A DataRow without a RowDescription is still an error
+        server.put_message(DataRow::new_from_values(
+            &[PgValue::Int4(1)],
+            &[],
+            &OutputSettings::default(),
+        )?)?;
+        server.flush()?;
+        assert!(handler.simple_query_handler("SELECT n FROM t").is_err());
 
         Ok(())
     }
 
-    pub fn simple_query_handler(&mut self) -> anyhow::Result<()> {
-        self.tcp_writer
-            .put_message_and_flush(Query::new("SELECT 1 as a, 2 as a, 3 as a;".to_string())?)?;
+    #[test]
+    fn query_errors() -> anyhow::Result<()> {
+        let scenario = Scenario::from_toml(
+            r#"
+            [[rules]]
+            query = "SELECT nope"
+            error = { code = "42703", message = "column \"nope\" does not exist" }
 
-        let mut raw_message = self.tcp_reader.get_raw_backend_message()?;
-        match RowDescription::try_from(&mut raw_message) {
-            Ok(message) => debug!("rcv: {message:?}"),
-            _ => return Err(anyhow!("RowDescription message expected")),
+            [[rules]]
+            query = "SELECT 1"
+            command_tag = "SELECT 1"
+            columns = [{ name = "n", type_oid = 23 }]
+            rows = [["1"]]
+            "#,
+        )?;
+        let server = TestServer::start("127.0.0.1:0", &scenario)?;
+        let mut handler = TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+
+        let error = handler.simple_query_handler("SELECT nope").unwrap_err();
+        assert!(error.to_string().contains("42703"), "{error}");
+        // The ReadyForQuery of the failed query has been read
+        let rows = handler.simple_query_handler("SELECT 1")?;
+        assert_eq!(Some(&b"1"[..]), rows[0].columns.as_ref()[0].as_bytes());
+
+        server.stop()
+    }
+
+    #[test]
+    fn copy_in_and_out() -> anyhow::Result<()> {
+        let scenario = Scenario::from_toml(
+            r#"
+            [[rules]]
+            query = "COPY t FROM STDIN"
+
+            [[rules]]
+            query = "COPY t TO STDOUT"
+            columns = [{ name = "n", type_oid = 23 }, { name = "s", type_oid = 25 }]
+            rows = [["1", "a"], ["2", "b\tc"]]
+            "#,
+        )?;
+        let server = TestServer::start("127.0.0.1:0", &scenario)?;
+        let mut handler = TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let reported = progress.clone();
+        handler.on_copy_progress = Box::new(move |progress| {
+            reported.lock().unwrap().push(progress);
+        });
+
+        let data = "1\ta\n".repeat(COPY_CHUNK_SIZE);
+        assert_eq!(
+            COPY_CHUNK_SIZE as u64,
+            handler.copy_in("COPY t FROM STDIN", &mut data.as_bytes())?
+        );
+        let mut output = Vec::new();
+        assert_eq!(2, handler.copy_out("COPY t TO STDOUT", &mut output)?);
+        assert_eq!(b"1\ta\n2\tb\\tc\n".to_vec(), output);
+        assert!(handler.copy_out("SELECT 1", &mut output).is_err());
+        let stats = &handler.message_stats;
+        assert_eq!(4, stats.frontend(FrontendMessageKind::CopyData).messages);
+        assert_eq!(1, stats.frontend(FrontendMessageKind::CopyDone).messages);
+        assert_eq!(2, stats.backend(BackendMessageKind::CopyData).messages);
+
+        let progress = progress.lock().unwrap();
+        let (copy_in, copy_out): (Vec<&CopyProgress>, Vec<_>) = progress
+            .iter()
+            .partition(|progress| progress.direction == CopyDirection::In);
+        // 4 chunks of COPY_CHUNK_SIZE bytes
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            copy_in
+                .iter()
+                .map(|progress| progress.bytes / COPY_CHUNK_SIZE as u64)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(4, 1), (11, 2)],
+            copy_out
+                .iter()
+                .map(|progress| (progress.bytes, progress.rows))
+                .collect::<Vec<_>>()
+        );
+
+        server.stop()
+    }
+
+    #[test]
+    fn copy_errors() -> anyhow::Result<()> {
+        let scenario = Scenario::from_toml(
+            r#"
+            [[rules]]
+            query = "COPY t FROM STDIN"
+
+            [[rules]]
+            query = "COPY t TO STDOUT"
+            columns = [{ name = "n", type_oid = 23 }]
+            rows = [["1"], ["2"]]
+            error = { code = "22P02", message = "invalid input syntax for type integer" }
+            "#,
+        )?;
+        let server = TestServer::start("127.0.0.1:0", &scenario)?;
+        let mut handler = TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+
+        // A file that can't be read after its first row
+        struct BrokenInput(bool);
+        impl Read for BrokenInput {
+            fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+                if std::mem::replace(&mut self.0, true) {
+                    return Err(std::io::Error::other("disk failure"));
+                }
+                buffer[..2].copy_from_slice(b"1\n");
+                Ok(2)
+            }
         }
+        let error = handler
+            .copy_in("COPY t FROM STDIN", &mut BrokenInput(false))
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("COPY from stdin failed: disk failure"),
+            "{error}"
+        );
 
-        let mut raw_message = self.tcp_reader.get_raw_backend_message()?;
-        match DataRow::try_from(&mut raw_message) {
-            Ok(message) => debug!("rcv: {message:?}"),
-            _ => return Err(anyhow!("DataRow message expected")),
+        // The rows before the error are received
+        let mut output = Vec::new();
+        let error = handler
+            .copy_out("COPY t TO STDOUT", &mut output)
+            .unwrap_err();
+        assert!(error.to_string().contains("22P02"), "{error}");
+        assert_eq!(b"1\n2\n".to_vec(), output);
+
+        // The connection is still usable
+        assert_eq!(0, handler.copy_in("COPY t FROM STDIN", &mut &b""[..])?);
+
+        server.stop()
+    }
+
+    #[test]
+    fn keep_alive() -> anyhow::Result<()> {
+        use crate::scenario::Scenario;
+        use crate::test_server::TestServer;
+
+        let server = TestServer::start("127.0.0.1:0", &Scenario::default())?;
+        let mut handler = TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+        assert!(handler.is_alive(Duration::from_secs(5)));
+        assert!(!handler.keep_alive()?);
+        handler.ping_interval = Some(Duration::ZERO);
+        assert!(handler.keep_alive()?);
+
+        // A peer that never answers
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut handler = TcpHandler::new(TcpStream::connect(listener.local_addr()?)?)?;
+        assert!(!handler.is_alive(Duration::from_millis(50)));
+        assert_eq!(None, handler.reader.get_ref().read_timeout()?);
+
+        server.stop()
+    }
+
+    #[test]
+    fn reconnect() -> anyhow::Result<()> {
+        use crate::scenario::Scenario;
+        use crate::test_server::TestServer;
+
+        let policy = ReconnectPolicy {
+            jitter: 0.0,
+            ..ReconnectPolicy::new(3, Duration::from_millis(10))
+        };
+        assert_eq!(Duration::from_millis(40), policy.delay(2));
+        assert_eq!(Duration::from_secs(10), policy.delay(20));
+
+        let server = TestServer::start("127.0.0.1:0", &Scenario::default())?;
+        let mut handler = TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+        assert!(!handler.ensure_connected(&policy)?);
+
+        assert_eq!(
+            Some("0.1 (fakepostmaster)"),
+            handler.parameter("server_version")
+        );
+        handler.simple_query_handler("SET DateStyle TO German")?;
+        assert_eq!(Some("German, DMY"), handler.parameter("DateStyle"));
+
+        let pid = server.sessions().sessions()[0].pid;
+        assert!(server.sessions().terminate(pid));
+        assert!(handler.ensure_connected(&policy)?);
+        // A new session with the default settings
+        assert_eq!(Some("ISO, MDY"), handler.parameter("DateStyle"));
+        assert!(handler.simple_query_handler("SELECT 1")?.is_empty());
+
+        server.stop()
+    }
+
+    #[test]
+    fn listen() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let handler = TcpHandler::new(TcpStream::connect(listener.local_addr()?)?)?;
+        let mut server = BufWriter::new(listener.accept()?.0);
+
+        server.put_message(CommandComplete::new(String::from("LISTEN"))?)?;
+        server.put_message(ReadyForQuery::new(TransactionIndicator::Idle))?;
+        server.put_message(NotificationResponse::new(42, "jobs", "1")?)?;
+        server.put_message(NoticeResponse::new(vec![ErrorMessage::new('M', "hi")?]))?;
+        server.put_message(NotificationResponse::new(42, "jobs", "2")?)?;
+        server.flush()?;
+
+        let notifications = handler.listen_in_background("jobs")?;
+        for payload in ["1", "2"] {
+            assert_eq!(
+                Notification {
+                    pid: 42,
+                    channel: String::from("jobs"),
+                    payload: String::from(payload),
+                },
+                notifications.recv_timeout(Duration::from_secs(5))??
+            );
         }
 
-        let mut raw_message = self.tcp_reader.get_raw_backend_message()?;
-        match CommandComplete::try_from(&mut raw_message) {
-            Ok(message) => debug!("rcv: {message:?}"),
-            _ => return Err(anyhow!("CommandComplete message expected")),
+        // The stream ends after an error, e.g. a disconnection
+        drop(server);
+        assert!(notifications.recv_timeout(Duration::from_secs(5))?.is_err());
+        assert!(notifications.recv_timeout(Duration::from_secs(5)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn in_memory_transport() -> anyhow::Result<()> {
+        let mut answer = BufWriter::new(Vec::new());
+        answer.put_message(CommandComplete::new(String::from("SET"))?)?;
+        answer.put_message(ReadyForQuery::new(TransactionIndicator::Idle))?;
+        let answer = answer.into_inner()?;
+
+        let mut handler = Handler::from_parts(&answer[..], Vec::new());
+        assert!(handler.simple_query_handler("SET a TO b")?.is_empty());
+        assert_eq!(b"Q\0\0\0\x0fSET a TO b\0", &handler.writer.get_ref()[..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn query_latency() -> anyhow::Result<()> {
+        let mut answer = BufWriter::new(Vec::new());
+        for _ in 0..2 {
+            answer.put_message(CommandComplete::new(String::from("SET"))?)?;
+            answer.put_message(ReadyForQuery::new(TransactionIndicator::Idle))?;
         }
+        let answer = answer.into_inner()?;
 
-        let mut raw_message = self.tcp_reader.get_raw_backend_message()?;
-        match ReadyForQuery::try_from(&mut raw_message) {
-            Ok(message) => debug!("rcv: {message:?}"),
-            _ => return Err(anyhow!("ReadyForQuery message expected")),
+        let stats = QueryStats::new(None);
+        let mut handler = Handler::from_parts(&answer[..], Vec::new());
+        handler.stats = Some(stats.clone());
+        handler.simple_query_handler("SET a TO b")?;
+        handler.simple_query_handler("SET a TO b")?;
+        let summary = stats.summary();
+        assert_eq!(1, summary.len());
+        assert_eq!(2, summary[0].count);
+        assert!(summary[0].p50 <= summary[0].max);
+
+        Ok(())
+    }
+
+    #[test]
+    fn protocol_violations() -> anyhow::Result<()> {
+        for (violation, first, second) in [
+            (
+                "duplicate_ready_for_query",
+                None,
+                Some("Unexpected message Some(ReadyForQuery) in state QueryStarted"),
+            ),
+            (
+                "garbled_ready_for_query",
+                Some("Invalid transaction status 'X' in ReadyForQuery"),
+                None,
+            ),
+            (
+                "data_row_before_row_description",
+                Some("Unexpected message Some(DataRow) in state QueryStarted"),
+                None,
+            ),
+        ] {
+            let config = ServerConfig::from_toml(&format!(
+                "[faults]\nprotocol_violation = \"{violation}\"\n"
+            ))?;
+            let server = TestServer::from_config(TcpListener::bind("127.0.0.1:0")?, &config)?;
+            server.set_scenario(&Scenario::from_toml(
+                r#"
+                [[rules]]
+                query = "SELECT 1"
+                command_tag = "SELECT 1"
+                columns = [{ name = "n", type_oid = 23 }]
+                rows = [["1"]]
+                "#,
+            )?)?;
+            let conninfo: ConnInfo = format!(
+                "host=127.0.0.1 port={} user=app password=secret",
+                server.address().port()
+            )
+            .parse()?;
+            let mut handler = probe::connect(&conninfo, "client", Duration::from_secs(5))?;
+
+            // The client fails instead of misreading the answers
+            for expected in [first, second] {
+                let result = handler.simple_query_handler("SELECT 1");
+                match expected {
+                    Some(expected) => {
+                        let error = result.err().map(|e| e.to_string()).unwrap_or_default();
+                        assert!(error.contains(expected), "{violation}: {error}");
+                        break;
+                    }
+                    None => assert!(result.is_ok(), "{violation}"),
+                }
+            }
+            server.stop()?;
         }
 
         Ok(())
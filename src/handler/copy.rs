@@ -0,0 +1,152 @@
+//! COPY ... FROM STDIN and COPY ... TO STDOUT in the text format. The
+//! executor gets the COPY statement: its rows are sent to the frontend, or
+//! it runs once all the data has been received. Both sides report their
+//! progress with a CopyProgress after each CopyData.
+
+use regex::Regex;
+use std::sync::LazyLock;
+
+use crate::error::PgError;
+use crate::value::{OutputSettings, PgValue};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyDirection {
+    // COPY ... FROM STDIN
+    In,
+    // COPY ... TO STDOUT
+    Out,
+}
+
+/// The data exchanged since the start of a COPY
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyProgress {
+    pub direction: CopyDirection,
+    pub bytes: u64,
+    pub rows: u64,
+}
+
+impl CopyProgress {
+    pub fn new(direction: CopyDirection) -> Self {
+        Self {
+            direction,
+            bytes: 0,
+            rows: 0,
+        }
+    }
+
+    /// Count a CopyData, the rows are the lines it ends
+    pub fn add(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u64;
+        self.rows += data.iter().filter(|byte| **byte == b'\n').count() as u64;
+    }
+}
+
+/// Called after each CopyData, e.g. to show a progress bar or to cancel
+/// the COPY once some rows are copied
+pub type CopyProgressCallback = Box<dyn FnMut(CopyProgress) + Send>;
+
+// COPY { table [ ( column [, ...] ) ] | ( query ) } { FROM STDIN | TO STDOUT }
+// [ [ WITH ] ( option [, ...] ) ]
+static COPY_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?is)^\s*copy\s+(.+?)\s+(from\s+stdin|to\s+stdout)\b(.*?)\s*;?\s*$"#)
+        .expect("Invalid COPY regex")
+});
+
+static FORMAT_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)\bformat\s+'?(\w+)'?|\b(binary|csv)\b"#).expect("Invalid FORMAT regex")
+});
+
+/// `None` when the query is not a COPY with the frontend, the files and
+/// the programs of the server are not supported
+pub fn copy_direction(query: &str) -> Option<anyhow::Result<CopyDirection>> {
+    let captures = COPY_REGEX.captures(query)?;
+    let options = format!("{} {}", &captures[1], &captures[3]);
+    if let Some(format) = FORMAT_REGEX
+        .captures(&options)
+        .and_then(|format| format.get(1).or(format.get(2)))
+        .map(|format| format.as_str().to_lowercase())
+        .filter(|format| format != "text")
+    {
+        return Some(Err(PgError::new(
+            "0A000",
+            &format!("COPY format \"{format}\" is not supported"),
+        )
+        .into()));
+    }
+    Some(Ok(match captures[2].to_lowercase().starts_with("from") {
+        true => CopyDirection::In,
+        false => CopyDirection::Out,
+    }))
+}
+
+/// A row in the text format of COPY: the values separated by tabs, with
+/// the backslashes, the tabs and the line breaks escaped, and NULL as \N
+pub fn text_row(values: &[PgValue], settings: &OutputSettings) -> anyhow::Result<Vec<u8>> {
+    let mut row = Vec::new();
+    for (index, value) in values.iter().enumerate() {
+        if index > 0 {
+            row.push(b'\t');
+        }
+        if *value == PgValue::Null {
+            row.extend_from_slice(b"\\N");
+            continue;
+        }
+        let text = settings
+            .encoding
+            .encode(&String::from_utf8(value.to_text_with(settings))?)?;
+        for byte in text {
+            match byte {
+                b'\\' => row.extend_from_slice(b"\\\\"),
+                b'\t' => row.extend_from_slice(b"\\t"),
+                b'\n' => row.extend_from_slice(b"\\n"),
+                b'\r' => row.extend_from_slice(b"\\r"),
+                byte => row.push(byte),
+            }
+        }
+    }
+    row.push(b'\n');
+    Ok(row)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn copy_statements() -> anyhow::Result<()> {
+        assert_eq!(
+            Some(CopyDirection::In),
+            copy_direction("copy t (a, b) FROM stdin;").transpose()?
+        );
+        assert_eq!(
+            Some(CopyDirection::Out),
+            copy_direction("COPY (SELECT 1) TO STDOUT WITH (FORMAT text)").transpose()?
+        );
+        assert_eq!(None, copy_direction("COPY t TO '/tmp/t.txt'").transpose()?);
+        assert_eq!(
+            None,
+            copy_direction("SELECT 'copy t to stdout'").transpose()?
+        );
+        for query in [
+            "COPY t TO STDOUT (FORMAT binary)",
+            "COPY t FROM STDIN WITH CSV",
+            "COPY BINARY t TO STDOUT",
+        ] {
+            assert!(copy_direction(query).is_some_and(|direction| direction.is_err()));
+        }
+
+        assert_eq!(
+            b"1\ta\\tb\\\\c\\nd\t\\N\n".to_vec(),
+            text_row(
+                &[
+                    PgValue::Int4(1),
+                    PgValue::Text(String::from("a\tb\\c\nd")),
+                    PgValue::Null
+                ],
+                &OutputSettings::default()
+            )?
+        );
+
+        Ok(())
+    }
+}
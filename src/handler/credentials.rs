@@ -0,0 +1,97 @@
+//! The users of the proxy when it injects the credentials, see
+//! proxy::TcpHandler::relay_with_credentials. The frontends authenticate
+//! with the passwords of this file, the server only sees the credentials
+//! stored for them, so the applications under test never know them.
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path};
+
+/// A user of the frontends and the credentials given to the server for it
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyUser {
+    pub name: String,
+    // Checked with MD5 by the proxy
+    pub password: String,
+    // The name of the user when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_user: Option<String>,
+    // Answers the MD5, SCRAM-SHA-256 or cleartext request of the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_password: Option<String>,
+}
+
+// The passwords are not printed in the traces
+impl fmt::Debug for ProxyUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyUser")
+            .field("name", &self.name)
+            .field("password", &"<redacted>")
+            .field("server_user", &self.server_user)
+            .field(
+                "server_password",
+                &self.server_password.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl ProxyUser {
+    /// The user of the StartupMessage sent to the server
+    pub fn server_user(&self) -> &str {
+        self.server_user.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// The user store of the proxy, in a TOML file e.g.:
+///
+/// ```toml
+/// [[users]]
+/// name = "app"
+/// password = "test"
+/// server_user = "app_production"
+/// server_password = "<server password>"
+/// ```
+///
+/// The frontends of the users that are not in the file are refused.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    #[serde(default)]
+    pub users: Vec<ProxyUser>,
+}
+
+impl Credentials {
+    pub fn from_toml(content: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn user(&self, name: &str) -> Option<&ProxyUser> {
+        self.users.iter().find(|user| user.name == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn debug_redacts_the_passwords() -> anyhow::Result<()> {
+        let credentials = Credentials::from_toml(
+            r#"
+            [[users]]
+            name = "app"
+            password = "frontend-secret"
+            server_password = "server-secret"
+            "#,
+        )?;
+        let debug = format!("{credentials:?}");
+        assert!(debug.contains("\"app\""));
+        assert!(!debug.contains("secret"));
+        Ok(())
+    }
+}
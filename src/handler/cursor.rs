@@ -0,0 +1,228 @@
+//! The cursors of the simple query protocol: DECLARE runs the query with the
+//! executor, FETCH and MOVE walk through its rows and CLOSE forgets it.
+
+use anyhow::anyhow;
+use regex::Regex;
+use std::sync::LazyLock;
+
+use crate::error::PgError;
+use crate::handler::server::QueryResult;
+use crate::message::ColumnDescription;
+use crate::value::PgValue;
+
+/// A DECLARE statement, the query is left to the executor
+#[derive(Debug, PartialEq)]
+pub struct Declare {
+    pub name: String,
+    pub query: String,
+    pub binary: bool,
+    pub scroll: bool,
+    pub hold: bool,
+}
+
+// DECLARE name [ BINARY ] [ ASENSITIVE | INSENSITIVE ] [ [ NO ] SCROLL ]
+// CURSOR [ { WITH | WITHOUT } HOLD ] FOR query
+static DECLARE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?is)^\s*declare\s+("[^"]+"|[a-z_][a-z0-9_$]*)\s+((?:[a-z]+\s+)*?)cursor\s+((?:with|without)\s+hold\s+)?for\s+(.*?)\s*;?\s*$"#,
+    )
+    .expect("Invalid DECLARE regex")
+});
+
+impl Declare {
+    /// `None` when the query is not a DECLARE statement
+    pub fn parse(query: &str) -> Option<anyhow::Result<Self>> {
+        let captures = DECLARE_REGEX.captures(query)?;
+        let options = captures[2].to_lowercase();
+        let options: Vec<&str> = options.split_whitespace().collect();
+        let mut binary = false;
+        let mut scroll = true;
+        for option in options.iter() {
+            match *option {
+                "binary" => binary = true,
+                "scroll" | "asensitive" | "insensitive" => (),
+                "no" if options.contains(&"scroll") => scroll = false,
+                _ => {
+                    return Some(Err(PgError::new(
+                        "42601",
+                        &format!("syntax error at or near \"{option}\""),
+                    )
+                    .into()));
+                }
+            }
+        }
+        let name = &captures[1];
+        let name = match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+            Some(name) => String::from(name),
+            None => name.to_lowercase(),
+        };
+        Some(Ok(Self {
+            name,
+            query: String::from(&captures[4]),
+            binary,
+            scroll,
+            hold: captures
+                .get(3)
+                .is_some_and(|hold| hold.as_str().to_lowercase().starts_with("with ")),
+        }))
+    }
+}
+
+/// Where FETCH and MOVE go, from the current row
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    // The row n, counted from the end when negative
+    Absolute(i64),
+    // The row n rows away
+    Relative(i64),
+    // The next n rows, the previous ones when negative
+    Count(i64),
+}
+
+impl Direction {
+    /// The direction of a normalized FETCH or MOVE, without the cursor name
+    pub fn parse(words: &[&str]) -> anyhow::Result<Self> {
+        let count = |word: &str| {
+            word.parse::<i64>().map_err(|_| {
+                anyhow!(PgError::new(
+                    "42601",
+                    &format!("syntax error at or near \"{word}\"")
+                ))
+            })
+        };
+        // The tokens of a negative number are separated, e.g. "- 5"
+        let joined = words.join(" ").replace("- ", "-");
+        let words: Vec<&str> = joined.split_whitespace().collect();
+        let words = match words[..] {
+            [.., "from" | "in"] => &words[..words.len() - 1],
+            _ => &words[..],
+        };
+        Ok(match words {
+            [] | ["next"] | ["forward"] => Direction::Count(1),
+            ["prior"] | ["backward"] => Direction::Count(-1),
+            ["first"] => Direction::Absolute(1),
+            ["last"] => Direction::Absolute(-1),
+            ["absolute", n] => Direction::Absolute(count(n)?),
+            ["relative", n] => Direction::Relative(count(n)?),
+            ["all"] | ["forward", "all"] => Direction::Count(i64::MAX),
+            ["backward", "all"] => Direction::Count(-i64::MAX),
+            [n] | ["forward", n] => Direction::Count(count(n)?),
+            ["backward", n] => Direction::Count(-count(n)?),
+            [word, ..] => {
+                return Err(
+                    PgError::new("42601", &format!("syntax error at or near \"{word}\"")).into(),
+                );
+            }
+        })
+    }
+}
+
+/// A cursor declared by a session, with the result of its query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    pub columns: Vec<ColumnDescription>,
+    pub rows: Vec<Vec<PgValue>>,
+    pub scroll: bool,
+    // Kept after the end of the transaction
+    pub hold: bool,
+    // Like PostgreSQL: 0 before the first row, rows.len() + 1 after the last
+    position: i64,
+}
+
+impl Cursor {
+    pub fn new(declare: &Declare, result: QueryResult) -> Self {
+        let format = i16::from(declare.binary);
+        Self {
+            columns: result
+                .columns
+                .into_iter()
+                .map(|column| ColumnDescription { format, ..column })
+                .collect(),
+            rows: result.rows,
+            scroll: declare.scroll,
+            hold: declare.hold,
+            position: 0,
+        }
+    }
+
+    /// Move the cursor, the rows it goes through are returned in order
+    pub fn fetch(&mut self, direction: Direction) -> anyhow::Result<Vec<Vec<PgValue>>> {
+        let last = self.rows.len() as i64;
+        let target = match direction {
+            Direction::Absolute(n) if n < 0 => last + 1 + n,
+            Direction::Absolute(n) => n,
+            Direction::Relative(n) | Direction::Count(n) => self.position.saturating_add(n),
+        }
+        .clamp(0, last + 1);
+        if !self.scroll && target < self.position {
+            return Err(PgError::new("55000", "cursor can only scan forward").into());
+        }
+
+        let row = |position: i64| self.rows[(position - 1) as usize].clone();
+        let rows = match direction {
+            Direction::Count(n) if n > 0 => {
+                (self.position + 1..=target.min(last)).map(row).collect()
+            }
+            Direction::Count(n) if n < 0 => (target.max(1)..self.position.min(last + 1))
+                .rev()
+                .map(row)
+                .collect(),
+            _ if (1..=last).contains(&target) => vec![row(target)],
+            _ => Vec::new(),
+        };
+        self.position = target;
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::PgType;
+
+    #[test]
+    fn cursor_fetch() -> anyhow::Result<()> {
+        let declare = match Declare::parse(
+            "DECLARE \"C\" BINARY SCROLL CURSOR WITH HOLD FOR SELECT n FROM t;",
+        ) {
+            Some(declare) => declare?,
+            None => return Err(anyhow!("DECLARE not recognized")),
+        };
+        assert_eq!(
+            ("C", "SELECT n FROM t"),
+            (&declare.name[..], &declare.query[..])
+        );
+        assert!(declare.binary && declare.scroll && declare.hold);
+        assert!(Declare::parse("DECLARE c FOR SELECT 1").is_none());
+
+        let mut cursor = Cursor::new(
+            &declare,
+            QueryResult {
+                columns: vec![ColumnDescription::new("n", PgType::Int4)?],
+                rows: (1..=5).map(|n| vec![PgValue::Int4(n)]).collect(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(1, cursor.columns[0].format);
+        let mut fetch = |words: &[&str]| -> anyhow::Result<Vec<i32>> {
+            Ok(cursor
+                .fetch(Direction::parse(words)?)?
+                .into_iter()
+                .map(|row| match row[0] {
+                    PgValue::Int4(n) => n,
+                    _ => 0,
+                })
+                .collect())
+        };
+        assert_eq!(vec![1, 2], fetch(&["2", "from"])?);
+        assert_eq!(vec![3], fetch(&[])?);
+        assert_eq!(vec![2, 1], fetch(&["backward", "all"])?);
+        assert_eq!(vec![5], fetch(&["last"])?);
+        assert_eq!(Vec::<i32>::new(), fetch(&["next"])?);
+        assert_eq!(vec![4], fetch(&["relative", "-", "2"])?);
+        assert_eq!(vec![5], fetch(&["all", "in"])?);
+        assert!(fetch(&["sideways"]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Detect the frontend messages that can't be right, when a length doesn't
+//! match the body that was sent the following bytes are read as garbage.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufReader, Read};
+use tracing::*;
+
+use libpq_serde_types::Deserialize;
+
+use crate::message::*;
+
+// The limits of PostgreSQL on the length of the frontend messages
+// (PQ_LARGE_MESSAGE_LIMIT, PQ_SMALL_MESSAGE_LIMIT and
+// PG_MAX_AUTH_TOKEN_LENGTH), they include the length itself.
+const LARGE_MESSAGE_LIMIT: i32 = 0x3fff_fffe;
+const SMALL_MESSAGE_LIMIT: i32 = 10000;
+const AUTH_TOKEN_LIMIT: i32 = 65535;
+
+/// What to do when a frontend message header is invalid
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DesyncPolicy {
+    // Send a FATAL error (08P01) and close the connection, like PostgreSQL
+    #[default]
+    Abort,
+    // Drop bytes one at a time until they start a plausible header. This is
+    // a guess: the bytes of a body can look like a header.
+    SkipToNextMessage,
+}
+
+/// The last valid messages received, to tell what came before a desync
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageHistory {
+    capacity: usize,
+    messages: VecDeque<String>,
+}
+
+impl MessageHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, message: &RawFrontendMessage) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(format!(
+            "{} ('{}'), length {}",
+            message.annotated().name,
+            char::from(message.header.message_type),
+            message.header.length
+        ));
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        self.messages.iter().cloned().collect()
+    }
+}
+
+impl Default for MessageHistory {
+    fn default() -> Self {
+        Self::new(10)
+    }
+}
+
+/// The stream of frontend messages is out of sync
+#[derive(Debug, PartialEq)]
+pub struct ProtocolDesync {
+    pub reason: String,
+    // The last valid messages, the oldest first
+    pub history: Vec<String>,
+}
+
+impl fmt::Display for ProtocolDesync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)?;
+        if self.history.is_empty() {
+            return write!(f, ", no valid message received");
+        }
+        write!(f, ", last valid messages:")?;
+        for message in &self.history {
+            write!(f, "\n  {message}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ProtocolDesync {}
+
+/// Why the header can't start a frontend message, `None` when it's plausible
+pub fn check_frontend_header(header: &MessageHeader) -> Option<String> {
+    let limit = match header.message_type {
+        b'Q' | b'F' | b'B' | b'P' | b'd' => LARGE_MESSAGE_LIMIT,
+        b'X' | b'C' | b'D' | b'E' | b'H' | b'S' | b'c' | b'f' => SMALL_MESSAGE_LIMIT,
+        b'p' => AUTH_TOKEN_LIMIT,
+        message_type => return Some(format!("invalid frontend message type {message_type}")),
+    };
+    if !(4..=limit).contains(&header.length) {
+        return Some(format!(
+            "invalid message length {} for '{}'",
+            header.length,
+            char::from(header.message_type)
+        ));
+    }
+    None
+}
+
+/// Read the header of the next frontend message and apply the policy when
+/// it's invalid
+pub fn get_frontend_header<T>(
+    buffered_reader: &mut BufReader<T>,
+    policy: DesyncPolicy,
+    history: &MessageHistory,
+) -> anyhow::Result<MessageHeader>
+where
+    T: Read,
+{
+    let mut buffer = [0_u8; 5];
+    buffered_reader.read_exact(&mut buffer)?;
+    let mut skipped = 0;
+    loop {
+        let header = MessageHeader::deserialize(&mut bytes::Bytes::copy_from_slice(&buffer))?;
+        let Some(reason) = check_frontend_header(&header) else {
+            if skipped > 0 {
+                warn!("Skipped {skipped} bytes to find the next message");
+            }
+            return Ok(header);
+        };
+        match policy {
+            DesyncPolicy::Abort => {
+                return Err(ProtocolDesync {
+                    reason,
+                    history: history.messages(),
+                }
+                .into());
+            }
+            DesyncPolicy::SkipToNextMessage => {
+                if skipped == 0 {
+                    warn!("{reason}");
+                }
+                buffer.rotate_left(1);
+                buffered_reader.read_exact(&mut buffer[4..])?;
+                skipped += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn desync_policy() -> anyhow::Result<()> {
+        let mut data = BytesMut::new();
+        MessageHeader::serialize_message(&mut data, &Sync::new())?;
+        // The body of a message sent with a length too short
+        data.extend_from_slice(b"garbage");
+        MessageHeader::serialize_message(&mut data, &Terminate::new())?;
+
+        let mut reader = BufReader::new(&data[..]);
+        let mut history = MessageHistory::new(2);
+        history.push(&RawFrontendMessage::get(&mut reader)?);
+        let error = get_frontend_header(&mut reader, DesyncPolicy::Abort, &history)
+            .expect_err("desync")
+            .downcast::<ProtocolDesync>()?;
+        assert_eq!(
+            "invalid frontend message type 103, last valid messages:\n  Sync ('S'), length 4",
+            error.to_string()
+        );
+
+        let mut reader = BufReader::new(&data[5..]);
+        let header = get_frontend_header(&mut reader, DesyncPolicy::SkipToNextMessage, &history)?;
+        assert_eq!(b'X', header.message_type);
+
+        // Lengths below 4 would underflow
+        let header = MessageHeader {
+            message_type: b'S',
+            length: 3,
+        };
+        assert!(check_frontend_header(&header).is_some());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+//! The large objects, read and written by the lo_* functions that the
+//! drivers call with the fastpath protocol (FunctionCall). The objects are
+//! kept in memory and shared by the sessions of a server, the descriptors
+//! opened by lo_open() belong to a session and are closed at the end of its
+//! transaction.
+//!
+//! The drivers look up the oids of the functions in pg_proc first, that
+//! query is answered too, see lookup().
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::columns;
+use crate::error::PgError;
+use crate::handler::server::QueryResult;
+use crate::matcher::normalize;
+use crate::message::{FormatCode, PgType};
+use crate::value::PgValue;
+
+// The modes of lo_open() and lo_creat()
+pub const INV_WRITE: i32 = 0x20000;
+pub const INV_READ: i32 = 0x40000;
+
+/// The functions by name, with their oid in pg_proc
+pub const FUNCTIONS: [(&str, u32); 10] = [
+    ("lo_create", 715),
+    ("lo_open", 952),
+    ("lo_close", 953),
+    ("loread", 954),
+    ("lowrite", 955),
+    ("lo_lseek", 956),
+    ("lo_creat", 957),
+    ("lo_tell", 958),
+    ("lo_unlink", 964),
+    ("lo_truncate", 1004),
+];
+
+// The first oid given to a large object
+const FIRST_OID: u32 = 16384;
+
+/// The large objects of a server by oid, cloning gives another handle on
+/// the same objects
+#[derive(Debug, Clone, Default)]
+pub struct LargeObjects(Arc<Mutex<BTreeMap<u32, Vec<u8>>>>);
+
+impl LargeObjects {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The content of a large object, `None` when it does not exist
+    pub fn get(&self, oid: u32) -> Option<Vec<u8>> {
+        self.0
+            .lock()
+            .expect("large objects lock")
+            .get(&oid)
+            .cloned()
+    }
+
+    /// Create an empty large object, with the next free oid when `oid` is 0
+    pub fn create(&self, oid: u32) -> anyhow::Result<u32> {
+        let mut objects = self.0.lock().expect("large objects lock");
+        let oid = match oid {
+            0 => objects
+                .last_key_value()
+                .map_or(FIRST_OID, |(last, _)| last + 1),
+            oid if objects.contains_key(&oid) => {
+                return Err(
+                    PgError::new("42710", &format!("large object {oid} already exists")).into(),
+                );
+            }
+            oid => oid,
+        };
+        objects.insert(oid, Vec::new());
+        Ok(oid)
+    }
+
+    pub fn unlink(&self, oid: u32) -> anyhow::Result<()> {
+        match self.0.lock().expect("large objects lock").remove(&oid) {
+            Some(_) => Ok(()),
+            None => Err(not_found(oid)),
+        }
+    }
+
+    fn update<T>(&self, oid: u32, update: impl FnOnce(&mut Vec<u8>) -> T) -> anyhow::Result<T> {
+        let mut objects = self.0.lock().expect("large objects lock");
+        match objects.get_mut(&oid) {
+            Some(object) => Ok(update(object)),
+            None => Err(not_found(oid)),
+        }
+    }
+}
+
+/// A large object opened by lo_open()
+#[derive(Debug, Clone, PartialEq)]
+pub struct Descriptor {
+    pub oid: u32,
+    pub position: usize,
+    pub writable: bool,
+}
+
+/// The large objects and the descriptors opened by a session
+#[derive(Debug, Clone, Default)]
+pub struct LargeObjectSession {
+    // Shared with the other sessions of the server
+    pub objects: LargeObjects,
+    pub descriptors: BTreeMap<i32, Descriptor>,
+}
+
+impl LargeObjectSession {
+    /// Run the function of a FunctionCall, its arguments are in the format
+    /// given for each
+    pub fn call(
+        &mut self,
+        function: u32,
+        arguments: &[(FormatCode, &[u8])],
+    ) -> anyhow::Result<PgValue> {
+        let name = FUNCTIONS
+            .iter()
+            .find(|(_, oid)| *oid == function)
+            .map(|(name, _)| *name)
+            .ok_or(PgError::new(
+                "42883",
+                &format!("function with OID {function} does not exist"),
+            ))?;
+        let int = |index: usize| -> anyhow::Result<i32> {
+            match arguments.get(index) {
+                Some((FormatCode::Binary, data)) => match PgValue::from_binary(PgType::Int4, data)?
+                {
+                    PgValue::Int4(value) => Ok(value),
+                    value => Err(anyhow::anyhow!("Unexpected argument {value:?}")),
+                },
+                Some((FormatCode::Text, data)) => Ok(std::str::from_utf8(data)?.parse()?),
+                None => Err(wrong_arguments(name)),
+            }
+        };
+        match name {
+            "lo_creat" => Ok(PgValue::Oid(self.objects.create(0)?)),
+            "lo_create" => Ok(PgValue::Oid(self.objects.create(int(0)? as u32)?)),
+            "lo_open" => {
+                let (oid, mode) = (int(0)? as u32, int(1)?);
+                if self.objects.get(oid).is_none() {
+                    return Err(not_found(oid));
+                }
+                let fd = (0..)
+                    .find(|fd| !self.descriptors.contains_key(fd))
+                    .expect("free descriptor");
+                self.descriptors.insert(
+                    fd,
+                    Descriptor {
+                        oid,
+                        position: 0,
+                        writable: mode & INV_WRITE != 0,
+                    },
+                );
+                Ok(PgValue::Int4(fd))
+            }
+            "lo_close" => {
+                let fd = int(0)?;
+                self.descriptors.remove(&fd).ok_or(invalid_descriptor(fd))?;
+                Ok(PgValue::Int4(0))
+            }
+            "loread" => {
+                let (fd, len) = (int(0)?, int(1)?.max(0) as usize);
+                let descriptor = descriptor(&mut self.descriptors, fd)?;
+                let object = self.objects.get(descriptor.oid).unwrap_or_default();
+                let start = descriptor.position.min(object.len());
+                let data = object[start..(start + len).min(object.len())].to_vec();
+                descriptor.position = start + data.len();
+                Ok(PgValue::Bytea(data))
+            }
+            "lowrite" => {
+                let fd = int(0)?;
+                let data = arguments.get(1).ok_or(wrong_arguments(name))?.1;
+                let descriptor = descriptor(&mut self.descriptors, fd)?;
+                if !descriptor.writable {
+                    return Err(PgError::new(
+                        "55000",
+                        &format!("large object descriptor {fd} was not opened for writing"),
+                    )
+                    .into());
+                }
+                let position = descriptor.position;
+                self.objects.update(descriptor.oid, |object| {
+                    if object.len() < position + data.len() {
+                        object.resize(position + data.len(), 0);
+                    }
+                    object[position..position + data.len()].copy_from_slice(data);
+                })?;
+                descriptor.position += data.len();
+                Ok(PgValue::Int4(data.len() as i32))
+            }
+            "lo_lseek" => {
+                let (fd, offset, whence) = (int(0)?, int(1)?, int(2)?);
+                let descriptor = descriptor(&mut self.descriptors, fd)?;
+                let base = match whence {
+                    // SEEK_SET, SEEK_CUR and SEEK_END
+                    0 => 0,
+                    1 => descriptor.position as i64,
+                    2 => self.objects.get(descriptor.oid).unwrap_or_default().len() as i64,
+                    _ => return Err(PgError::new("22023", "invalid whence setting").into()),
+                };
+                let position = base + i64::from(offset);
+                if !(0..=i64::from(i32::MAX)).contains(&position) {
+                    return Err(
+                        PgError::new("22023", &format!("invalid seek offset: {position}")).into(),
+                    );
+                }
+                descriptor.position = position as usize;
+                Ok(PgValue::Int4(position as i32))
+            }
+            "lo_tell" => Ok(PgValue::Int4(
+                descriptor(&mut self.descriptors, int(0)?)?.position as i32,
+            )),
+            "lo_truncate" => {
+                let (fd, len) = (int(0)?, int(1)?.max(0) as usize);
+                let oid = descriptor(&mut self.descriptors, fd)?.oid;
+                self.objects.update(oid, |object| object.resize(len, 0))?;
+                Ok(PgValue::Int4(0))
+            }
+            "lo_unlink" => {
+                let oid = int(0)? as u32;
+                self.objects.unlink(oid)?;
+                self.descriptors
+                    .retain(|_, descriptor| descriptor.oid != oid);
+                Ok(PgValue::Int4(1))
+            }
+            _ => Err(wrong_arguments(name)),
+        }
+    }
+}
+
+fn descriptor(
+    descriptors: &mut BTreeMap<i32, Descriptor>,
+    fd: i32,
+) -> anyhow::Result<&mut Descriptor> {
+    descriptors.get_mut(&fd).ok_or(invalid_descriptor(fd))
+}
+
+/// The oids of the functions, for the queries on pg_proc naming them (e.g.
+/// `select proname, oid from pg_catalog.pg_proc where proname in (...)`).
+/// `None` for the other queries.
+pub fn lookup(query: &str) -> Option<anyhow::Result<QueryResult>> {
+    let normalized = normalize(query).ok()?;
+    let columns = normalized.strip_prefix("select ")?;
+    let (columns, from) = columns.split_once(" from ")?;
+    if !matches!(columns, "proname , oid" | "p . proname , p . oid")
+        || !(from.starts_with("pg_catalog . pg_proc ") || from.starts_with("pg_proc "))
+    {
+        return None;
+    }
+    let rows: Vec<Vec<PgValue>> = FUNCTIONS
+        .iter()
+        .filter(|(name, _)| from.contains(&format!("'{name}'")))
+        .map(|(name, oid)| vec![PgValue::from(*name), PgValue::Oid(*oid)])
+        .collect();
+    Some(
+        columns![("proname", Text), ("oid", Oid)].map(|columns| QueryResult {
+            columns,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        }),
+    )
+}
+
+fn not_found(oid: u32) -> anyhow::Error {
+    PgError::new("42704", &format!("large object {oid} does not exist")).into()
+}
+
+fn invalid_descriptor(fd: i32) -> anyhow::Error {
+    PgError::new("42704", &format!("invalid large-object descriptor: {fd}")).into()
+}
+
+fn wrong_arguments(name: &str) -> anyhow::Error {
+    PgError::new("08P01", &format!("wrong number of arguments for {name}()")).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn large_objects() -> anyhow::Result<()> {
+        let oid = |name: &str| -> anyhow::Result<u32> {
+            FUNCTIONS
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, oid)| *oid)
+                .ok_or(anyhow::anyhow!("No function {name}"))
+        };
+        let int = |value: i32| value.to_be_bytes();
+        let mut session = LargeObjectSession::default();
+
+        let created = session.call(oid("lo_creat")?, &[(FormatCode::Binary, &int(INV_WRITE))])?;
+        assert_eq!(PgValue::Oid(FIRST_OID), created);
+        let open = [
+            (FormatCode::Binary, &int(FIRST_OID as i32)[..]),
+            (FormatCode::Binary, &int(INV_READ | INV_WRITE)[..]),
+        ];
+        assert_eq!(PgValue::Int4(0), session.call(oid("lo_open")?, &open)?);
+        let write = [
+            (FormatCode::Binary, &int(0)[..]),
+            (FormatCode::Binary, b"hello"),
+        ];
+        assert_eq!(PgValue::Int4(5), session.call(oid("lowrite")?, &write)?);
+        let seek = [
+            (FormatCode::Text, &b"0"[..]),
+            (FormatCode::Text, b"1"),
+            (FormatCode::Text, b"0"),
+        ];
+        assert_eq!(PgValue::Int4(1), session.call(oid("lo_lseek")?, &seek)?);
+        let read = [
+            (FormatCode::Binary, &int(0)[..]),
+            (FormatCode::Binary, &int(10)[..]),
+        ];
+        assert_eq!(
+            PgValue::Bytea(b"ello".to_vec()),
+            session.call(oid("loread")?, &read)?
+        );
+        session.call(oid("lo_close")?, &[(FormatCode::Binary, &int(0))])?;
+        assert!(session.call(oid("loread")?, &read).is_err());
+
+        // Another session of the server, read only
+        let mut other = LargeObjectSession {
+            objects: session.objects.clone(),
+            ..LargeObjectSession::default()
+        };
+        let open = [open[0], (FormatCode::Binary, &int(INV_READ)[..])];
+        other.call(oid("lo_open")?, &open)?;
+        assert!(other.call(oid("lowrite")?, &write).is_err());
+        assert_eq!(
+            PgValue::Bytea(b"hello".to_vec()),
+            other.call(oid("loread")?, &read)?
+        );
+        assert!(session.call(1, &[]).is_err());
+
+        let result = lookup(
+            "select proname, oid from pg_catalog.pg_proc where proname in ('lo_open', 'lo_close', 'lo_creat', 'lo_create', 'lo_unlink', 'lo_lseek', 'lo_lseek64', 'lo_tell', 'lo_tell64', 'lo_truncate', 'lo_truncate64', 'loread', 'lowrite') and pronamespace = (select oid from pg_catalog.pg_namespace where nspname = 'pg_catalog')",
+        )
+        .ok_or(anyhow::anyhow!("Not a lookup"))??;
+        assert_eq!(10, result.rows.len());
+        assert!(lookup("select proname, oid from pg_catalog.pg_class").is_none());
+
+        Ok(())
+    }
+}
@@ -1,21 +1,53 @@
+// The readers and writers of the messages are only used by the handlers,
+// some of them only by the TCP ones that are not built on wasm32
+#![cfg_attr(any(not(feature = "server"), target_family = "wasm"), allow(dead_code))]
+
+#[cfg(feature = "server")]
+pub mod actor;
+#[cfg(feature = "server")]
 pub mod client;
+#[cfg(feature = "server")]
+pub mod copy;
+#[cfg(feature = "proxy")]
+pub mod credentials;
+#[cfg(feature = "server")]
+pub mod cursor;
+pub mod desync;
+#[cfg(feature = "server")]
+pub mod large_object;
+#[cfg(feature = "proxy")]
+pub mod pool;
+#[cfg(feature = "proxy")]
+pub mod pooler;
+#[cfg(all(feature = "server", not(target_family = "wasm")))]
+pub mod probe;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+#[cfg(feature = "proxy")]
+pub mod rewrite;
+#[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "proxy")]
+pub mod translate;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
-use anyhow::anyhow;
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{BufMut, BytesMut};
+#[cfg(all(feature = "vectored-writes", target_os = "linux"))]
+use std::io::IoSlice;
 use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
+use std::time::{Duration, Instant};
 
 use tracing::*;
-use tracing_subscriber;
 
-use libpq_serde_types::{ByteSized, Deserialize, Serialize};
+use libpq_serde_types::{ByteSized, Serialize};
 
 use crate::message::*;
 
 trait LibPqReader: Read {
     fn get_raw_backend_message(&mut self) -> anyhow::Result<RawBackendMessage>;
-    fn get_raw_frontend_message(&mut self) -> anyhow::Result<RawFrontendMessage>;
 }
 
 impl<T> LibPqReader for BufReader<T>
@@ -23,20 +55,127 @@ where
     T: Read,
 {
     fn get_raw_backend_message(&mut self) -> anyhow::Result<RawBackendMessage> {
-        let mut raw_message = RawBackendMessage::get(self)?;
-        if let Some(BackendMessageKind::ErrorResponse) = raw_message.get_message_kind() {
-            let error = ErrorResponse::try_from(&mut raw_message)?;
-            //FIXME:
-            error!("{error:?}");
-            Err(anyhow!("Error"))
-        } else {
-            Ok(raw_message)
+        let raw_message = RawBackendMessage::get(self)?;
+        trace!("rcv:\n{raw_message}");
+        Ok(raw_message)
+    }
+}
+
+/// When the buffered messages are written to the socket, on top of the
+/// flushes done when the frontend waits for an answer (ReadyForQuery, the
+/// authentication requests, Flush). The writes block when the frontend
+/// doesn't read fast enough.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum FlushPolicy {
+    // Only when the frontend waits, or when the buffer of the writer is full
+    #[default]
+    OnReadyForQuery,
+    // When this many bytes were written since the last flush
+    EveryBytes(usize),
+    // When the last flush is older, checked each time a message is written
+    Interval(Duration),
+}
+
+impl FlushPolicy {
+    /// Whether the messages must be flushed given the bytes and the time
+    /// elapsed since the last flush
+    pub fn is_due(&self, unflushed: usize, last_flush: Instant) -> bool {
+        match self {
+            FlushPolicy::OnReadyForQuery => false,
+            FlushPolicy::EveryBytes(bytes) => unflushed >= *bytes,
+            FlushPolicy::Interval(interval) => last_flush.elapsed() >= *interval,
+        }
+    }
+}
+
+/// The messages of one direction and their bytes, type and length
+/// included
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MessageCount {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// The messages exchanged by a handler by kind, e.g. for a test checking
+/// that exactly one Parse was sent without capturing the traffic. The
+/// messages without a type (StartupMessage, SSLRequest...) are not
+/// counted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageStats {
+    // Indexed by message type
+    frontend: Vec<MessageCount>,
+    backend: Vec<MessageCount>,
+}
+
+impl Default for MessageStats {
+    fn default() -> Self {
+        Self {
+            frontend: vec![MessageCount::default(); 256],
+            backend: vec![MessageCount::default(); 256],
         }
     }
+}
+
+impl MessageStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn frontend(&self, kind: FrontendMessageKind) -> MessageCount {
+        self.frontend[u8::from(&kind) as usize]
+    }
+
+    pub fn backend(&self, kind: BackendMessageKind) -> MessageCount {
+        self.backend[u8::from(&kind) as usize]
+    }
+
+    /// All the messages sent by the frontend
+    pub fn frontend_total(&self) -> MessageCount {
+        total(&self.frontend)
+    }
+
+    /// All the messages sent by the backend
+    pub fn backend_total(&self) -> MessageCount {
+        total(&self.backend)
+    }
 
-    fn get_raw_frontend_message(&mut self) -> anyhow::Result<RawFrontendMessage> {
-        Ok(RawFrontendMessage::get(self)?)
+    pub fn record_frontend(&mut self, message_type: u8, bytes: usize) {
+        record(&mut self.frontend[message_type as usize], bytes);
     }
+
+    pub fn record_backend(&mut self, message_type: u8, bytes: usize) {
+        record(&mut self.backend[message_type as usize], bytes);
+    }
+}
+
+fn record(count: &mut MessageCount, bytes: usize) {
+    count.messages += 1;
+    count.bytes = count.bytes.saturating_add(bytes as u64);
+}
+
+fn total(counts: &[MessageCount]) -> MessageCount {
+    counts
+        .iter()
+        .fold(MessageCount::default(), |total, count| MessageCount {
+            messages: total.messages + count.messages,
+            bytes: total.bytes.saturating_add(count.bytes),
+        })
+}
+
+/// Panic when byte_size() disagrees with the serialized body: the length
+/// is now taken from the serialized body, but a wrong byte_size() still
+/// breaks the sizes computed for the buffers and the memory limit.
+#[cfg(debug_assertions)]
+fn check_byte_size<U>(msg: &U, serialized: usize)
+where
+    U: ByteSized,
+{
+    assert_eq!(
+        msg.byte_size(),
+        serialized,
+        "byte_size() of {} doesn't match its serialized length",
+        std::any::type_name::<U>()
+    );
 }
 
 trait LibPqWriter: Write {
@@ -51,6 +190,14 @@ trait LibPqWriter: Write {
     fn put_request<U>(&mut self, msg: U) -> anyhow::Result<()>
     where
         U: RequestBody + Serialize + ByteSized + std::fmt::Debug;
+
+    /// Write a batch of messages, e.g. the rows of a result
+    fn put_messages<U>(&mut self, msgs: Vec<U>) -> anyhow::Result<()>
+    where
+        U: MessageBody + Serialize + ByteSized + std::fmt::Debug;
+
+    /// Write a message encoded at compile time
+    fn put_static(&mut self, msg: &StaticMessage) -> anyhow::Result<()>;
 }
 
 impl<T> LibPqWriter for BufWriter<T>
@@ -64,9 +211,10 @@ where
         debug!("snd: {msg:?}");
 
         let mut buffer = BytesMut::new();
-        MessageHeader::new_raw_header_from_body(&mut buffer, &msg);
-        msg.serialize(&mut buffer);
-        self.write(&buffer)?;
+        MessageHeader::serialize_message(&mut buffer, &msg)?;
+        #[cfg(debug_assertions)]
+        check_byte_size(&msg, buffer.len() - 5);
+        self.write_all(&buffer)?;
 
         Ok(())
     }
@@ -88,11 +236,160 @@ where
         debug!("snd: {msg:?}");
 
         let mut buffer = BytesMut::new();
-        buffer.put_i32(msg.byte_size() + 4);
+        buffer.put_i32(MessageHeader::length_for(msg.byte_size())?);
         msg.serialize(&mut buffer);
-        self.write(&buffer)?;
+        #[cfg(debug_assertions)]
+        check_byte_size(&msg, buffer.len() - 4);
+        self.write_all(&buffer)?;
+        self.flush()?;
+
+        Ok(())
+    }
+
+    fn put_static(&mut self, msg: &StaticMessage) -> anyhow::Result<()> {
+        debug!("snd: {}", msg.name);
+        self.write_all(msg.bytes)?;
+
+        Ok(())
+    }
+
+    #[cfg(not(all(feature = "vectored-writes", target_os = "linux")))]
+    fn put_messages<U>(&mut self, msgs: Vec<U>) -> anyhow::Result<()>
+    where
+        U: MessageBody + Serialize + ByteSized + std::fmt::Debug,
+    {
+        for msg in msgs {
+            self.put_message(msg)?;
+        }
+        Ok(())
+    }
+
+    /// The batches larger than the free space of the buffer are sent with
+    /// writev, one system call for up to IOV_MAX messages instead of one per
+    /// buffer full. The others are copied into the buffer like one message
+    /// at a time.
+    #[cfg(all(feature = "vectored-writes", target_os = "linux"))]
+    fn put_messages<U>(&mut self, msgs: Vec<U>) -> anyhow::Result<()>
+    where
+        U: MessageBody + Serialize + ByteSized + std::fmt::Debug,
+    {
+        let size = msgs.iter().try_fold(0usize, |size, msg| {
+            let length = MessageHeader::length_for(msg.byte_size())? as usize + 1;
+            Ok::<_, anyhow::Error>(size.saturating_add(length))
+        })?;
+        if size <= self.capacity() - self.buffer().len() {
+            for msg in msgs {
+                self.put_message(msg)?;
+            }
+            return Ok(());
+        }
+
+        let buffers: Vec<BytesMut> = msgs
+            .iter()
+            .map(|msg| {
+                debug!("snd: {msg:?}");
+                let mut buffer = BytesMut::with_capacity(
+                    MessageHeader::length_for(msg.byte_size())? as usize + 1,
+                );
+                MessageHeader::serialize_message(&mut buffer, msg)?;
+                #[cfg(debug_assertions)]
+                check_byte_size(msg, buffer.len() - 5);
+                Ok(buffer)
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        // The messages already buffered go first
         self.flush()?;
+        let mut slices: Vec<IoSlice> = buffers.iter().map(|buffer| IoSlice::new(buffer)).collect();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            match self.get_mut().write_vectored(slices)? {
+                0 => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+                written => IoSlice::advance_slices(&mut slices, written),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::DataRow;
+    use crate::value::{OutputSettings, PgValue};
+
+    // Accepts a few bytes per write, like a socket with a full send buffer
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let count = buf.len().min(7);
+            self.0.extend_from_slice(&buf[..count]);
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn put_messages_partial_writes() -> anyhow::Result<()> {
+        let rows = || {
+            (0..100)
+                .map(|n| {
+                    DataRow::new_from_values(&[PgValue::Int4(n)], &[], &OutputSettings::default())
+                })
+                .collect::<anyhow::Result<Vec<DataRow>>>()
+        };
+        let mut expected = BufWriter::new(Vec::new());
+        for row in rows()? {
+            expected.put_message(row)?;
+        }
+
+        // More than the buffer holds, after a message already buffered
+        let mut writer = BufWriter::with_capacity(64, Trickle(Vec::new()));
+        writer.put_static(&PORTAL_SUSPENDED)?;
+        writer.put_messages(rows()?)?;
+        writer.flush()?;
+        let written = &writer.get_ref().0;
+        assert_eq!(PORTAL_SUSPENDED.bytes, &written[..5]);
+        assert_eq!(expected.buffer(), &written[5..]);
 
         Ok(())
     }
+
+    // The check of the sizes only exists in debug builds
+    #[cfg(debug_assertions)]
+    #[derive(Debug)]
+    struct WrongSize;
+
+    #[cfg(debug_assertions)]
+    impl MessageBody for WrongSize {
+        fn message_type(&self) -> u8 {
+            b'W'
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    impl Serialize for WrongSize {
+        fn serialize(&self, buffer: &mut BytesMut) {
+            buffer.put_slice(b"abc");
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    impl ByteSized for WrongSize {
+        fn byte_size(&self) -> usize {
+            1
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "byte_size() of fakepostmaster::handler::test::WrongSize")]
+    fn byte_size_mismatch() {
+        let mut writer = BufWriter::new(Vec::new());
+        let _ = writer.put_message(WrongSize);
+    }
 }
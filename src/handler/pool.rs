@@ -0,0 +1,281 @@
+//! A pool of client connections shared by threads, e.g. by the connections
+//! of a stress tool. The threads waiting for a connection are served in
+//! the order they arrived.
+
+use anyhow::anyhow;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+use crate::handler::client::TcpHandler;
+use crate::simulation::Clock;
+
+/// Opens a new connection of the pool, authenticated
+pub type Connector = Box<dyn Fn() -> anyhow::Result<TcpHandler> + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    // The connections open, in the pool or checked out
+    pub max_size: usize,
+    // The connections unused for longer are closed
+    pub idle_timeout: Option<Duration>,
+    // How long checkout() waits for a connection
+    pub checkout_timeout: Duration,
+    // A connection taken from the pool must answer a ping within this
+    // time, or it is replaced
+    pub health_check: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            idle_timeout: Some(Duration::from_secs(600)),
+            checkout_timeout: Duration::from_secs(30),
+            health_check: Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PoolState {
+    // With the time they were returned, the oldest first
+    idle: VecDeque<(TcpHandler, Instant)>,
+    // Idle or checked out
+    open: usize,
+    // The tickets of the threads waiting, served from the front
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+struct Shared {
+    config: PoolConfig,
+    connect: Connector,
+    clock: Clock,
+    state: Mutex<PoolState>,
+    changed: Condvar,
+}
+
+/// The pool, cloning gives another handle on the same connections
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+impl Pool {
+    pub fn new(config: PoolConfig, connect: Connector) -> Self {
+        Self::with_clock(config, connect, Clock::Real)
+    }
+
+    /// Like new(), the idle time of the connections is measured with
+    /// `clock`, e.g. Clock::manual()
+    pub fn with_clock(config: PoolConfig, connect: Connector, clock: Clock) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                config,
+                connect,
+                clock,
+                state: Mutex::new(PoolState::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.shared
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The connections idle in the pool and the ones open
+    pub fn status(&self) -> (usize, usize) {
+        let state = self.lock();
+        (state.idle.len(), state.open)
+    }
+
+    /// Take an idle connection or open a new one when the pool isn't full,
+    /// after the threads that were waiting before
+    pub fn checkout(&self) -> anyhow::Result<PooledConnection> {
+        let deadline = Instant::now() + self.shared.config.checkout_timeout;
+        let mut state = self.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(ticket);
+        loop {
+            self.close_expired(&mut state);
+            if state.waiting.front() == Some(&ticket) {
+                if let Some((mut handler, _)) = state.idle.pop_back() {
+                    state.waiting.pop_front();
+                    self.shared.changed.notify_all();
+                    drop(state);
+                    if let Some(timeout) = self.shared.config.health_check
+                        && !handler.is_alive(timeout)
+                    {
+                        debug!("Pooled connection lost, replaced");
+                        state = self.lock();
+                        state.open -= 1;
+                        state.waiting.push_front(ticket);
+                        continue;
+                    }
+                    return Ok(self.pooled(handler));
+                }
+                if state.open < self.shared.config.max_size {
+                    state.open += 1;
+                    state.waiting.pop_front();
+                    self.shared.changed.notify_all();
+                    drop(state);
+                    return match (self.shared.connect)() {
+                        Ok(handler) => Ok(self.pooled(handler)),
+                        Err(e) => {
+                            self.lock().open -= 1;
+                            self.shared.changed.notify_all();
+                            Err(e)
+                        }
+                    };
+                }
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                state.waiting.retain(|waiting| *waiting != ticket);
+                self.shared.changed.notify_all();
+                return Err(anyhow!(
+                    "No connection available after {:?}",
+                    self.shared.config.checkout_timeout
+                ));
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    fn pooled(&self, handler: TcpHandler) -> PooledConnection {
+        PooledConnection {
+            pool: self.clone(),
+            handler: Some(handler),
+        }
+    }
+
+    /// Close the idle connections unused for longer than idle_timeout
+    fn close_expired(&self, state: &mut PoolState) {
+        let Some(timeout) = self.shared.config.idle_timeout else {
+            return;
+        };
+        let now = self.shared.clock.now();
+        while let Some((_, returned)) = state.idle.front() {
+            if now.saturating_duration_since(*returned) < timeout {
+                break;
+            }
+            state.idle.pop_front();
+            state.open -= 1;
+        }
+    }
+
+    fn checkin(&self, handler: TcpHandler) {
+        let mut state = self.lock();
+        state.idle.push_back((handler, self.shared.clock.now()));
+        self.close_expired(&mut state);
+        self.shared.changed.notify_all();
+    }
+
+    fn forget(&self) {
+        self.lock().open -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// A connection checked out of a pool, it goes back to the pool when
+/// dropped
+pub struct PooledConnection {
+    pool: Pool,
+    handler: Option<TcpHandler>,
+}
+
+impl PooledConnection {
+    /// Close the connection instead of returning it, e.g. after an error
+    /// that left it in an unknown state
+    pub fn discard(mut self) {
+        self.handler = None;
+        self.pool.forget();
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = TcpHandler;
+
+    fn deref(&self) -> &TcpHandler {
+        self.handler.as_ref().expect("pooled connection")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut TcpHandler {
+        self.handler.as_mut().expect("pooled connection")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(handler) = self.handler.take() {
+            self.pool.checkin(handler);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::probe::{self, ConnInfo};
+    use crate::scenario::Scenario;
+    use crate::test_server::TestServer;
+    use std::thread;
+
+    #[test]
+    fn pool() -> anyhow::Result<()> {
+        let server = TestServer::start("127.0.0.1:0", &Scenario::default())?;
+        let conninfo: ConnInfo = format!(
+            "host=127.0.0.1 port={} user=app password=secret",
+            server.address().port()
+        )
+        .parse()?;
+        let connect: Connector =
+            Box::new(move || probe::connect(&conninfo, "pool", Duration::from_secs(5)));
+        let clock = Clock::manual();
+        let config = PoolConfig {
+            max_size: 1,
+            checkout_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        };
+        let pool = Pool::with_clock(config, connect, clock.clone());
+
+        let mut connection = pool.checkout()?;
+        connection.simple_query_handler("SELECT 1")?;
+        assert!(pool.checkout().is_err());
+
+        // A waiting thread gets the connection once it's returned
+        let waiting = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.checkout().map(drop))
+        };
+        drop(connection);
+        waiting.join().expect("waiting thread")?;
+        assert_eq!((1, 1), pool.status());
+
+        // Closed once idle for too long
+        if let Clock::Manual(manual) = &clock {
+            manual.advance(Duration::from_secs(600));
+        }
+        let connection = pool.checkout()?;
+        assert_eq!((0, 1), pool.status());
+        connection.discard();
+        assert_eq!((0, 0), pool.status());
+
+        server.stop()
+    }
+}
@@ -0,0 +1,401 @@
+//! A transaction pooler in front of a real server, like PgBouncer with
+//! pool_mode = transaction: many frontend sessions share the connections of
+//! a Pool. A frontend gets a server connection for a transaction and gives
+//! it back when the server reports that it is idle again.
+//!
+//! The state that outlives a transaction (SET, LISTEN, named prepared
+//! statements, ...) would be left on a connection that the next transaction
+//! of the session may not get, these statements are refused with an
+//! ErrorResponse instead. The frontends are not authenticated: the pooler
+//! answers the startup itself with the parameters of the server, and their
+//! cancel requests are ignored.
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use tracing::*;
+
+use crate::error::PgError;
+use crate::handler::LibPqWriter;
+use crate::handler::client::TcpHandler;
+use crate::handler::pool::{Pool, PooledConnection};
+use crate::handler::proxy::{forward_command, put_error_response};
+use crate::handler::server::session_level_feature;
+use crate::message::*;
+use crate::validator::Validator;
+
+/// Serves the frontends on the connections of a pool, a clone serves the
+/// same pool
+#[derive(Clone)]
+pub struct TransactionPooler {
+    pool: Pool,
+    // Reported to the frontends at their startup
+    parameters: Vec<(String, String)>,
+}
+
+impl TransactionPooler {
+    /// The parameters of the server are taken from a first connection
+    pub fn new(pool: Pool) -> anyhow::Result<Self> {
+        let connection = pool.checkout()?;
+        let mut parameters: Vec<(String, String)> = connection
+            .parameters()
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        parameters.sort();
+        drop(connection);
+
+        Ok(Self { pool, parameters })
+    }
+
+    /// Serve a frontend until it terminates or closes the connection, e.g.
+    /// from a thread per accepted connection
+    pub fn serve(&self, frontend: TcpStream) -> anyhow::Result<()> {
+        let mut session = Session {
+            pool: &self.pool,
+            reader: BufReader::new(frontend.try_clone()?),
+            writer: BufWriter::new(frontend),
+            backend: None,
+            validator: Validator::new(),
+            transaction: TransactionIndicator::Idle,
+        };
+        let result = session
+            .startup(&self.parameters)
+            .and_then(|()| session.run());
+        // Left in the middle of a transaction or of an extended query
+        if let Some(backend) = session.backend.take() {
+            debug!("Server connection discarded");
+            backend.discard();
+        }
+        result
+    }
+}
+
+/// A frontend session and the server connection of its current transaction
+struct Session<'a> {
+    pool: &'a Pool,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    backend: Option<PooledConnection>,
+    // Follows the exchanges with the server connection to know when all
+    // the commands of the frontend were answered
+    validator: Validator,
+    // As reported by the last ReadyForQuery of the server
+    transaction: TransactionIndicator,
+}
+
+impl Session<'_> {
+    /// Refuse the encryption and answer the StartupMessage
+    fn startup(&mut self, parameters: &[(String, String)]) -> anyhow::Result<()> {
+        let mut request = RawRequest::get(&mut self.reader)?;
+        while matches!(
+            request.request_kind,
+            RequestMessageKind::SSLRequest | RequestMessageKind::GSSENCRequest
+        ) {
+            debug!("rcv: {:?}, refused", request.request_kind);
+            self.writer.write_all(b"N")?;
+            self.writer.flush()?;
+            request = RawRequest::get(&mut self.reader)?;
+        }
+        let sm = StartupMessage::try_from(&mut request)?;
+        debug!("rcv: {sm:?}");
+
+        self.writer.put_static(&AUTHENTICATION_OK)?;
+        for (name, value) in parameters {
+            self.writer
+                .put_message(ParameterStatus::new(name, value)?)?;
+        }
+        self.writer
+            .put_static(ready_for_query(TransactionIndicator::Idle))?;
+        self.writer.flush()?;
+        // The validator starts with the startup of the server connections
+        self.validator.backend_message(b'Z')?;
+
+        Ok(())
+    }
+
+    fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            let message = RawFrontendMessage::get(&mut self.reader)?;
+            match message.get_message_kind() {
+                Some(FrontendMessageKind::Terminate) => return Ok(()),
+                // Nothing to synchronize outside of a transaction
+                Some(FrontendMessageKind::Sync) if self.backend.is_none() => {
+                    self.writer.put_static(ready_for_query(self.transaction))?;
+                    self.writer.flush()?;
+                    continue;
+                }
+                Some(FrontendMessageKind::Flush) if self.backend.is_none() => continue,
+                _ => (),
+            }
+            match self.check(&message).and_then(|()| self.checkout()) {
+                Ok(()) => self.forward(message)?,
+                Err(e) => self.refuse(message.header.message_type, &e)?,
+            }
+        }
+    }
+
+    /// Refuse the statements that leave a state on the server connection
+    fn check(&self, message: &RawFrontendMessage) -> Result<(), PgError> {
+        // The body is still needed to forward the message
+        let mut copy = RawFrontendMessage {
+            header: MessageHeader {
+                message_type: message.header.message_type,
+                length: message.header.length,
+            },
+            raw_body: message.raw_body.clone(),
+        };
+        let query = match message.get_message_kind() {
+            Some(FrontendMessageKind::Query) => Query::try_from(&mut copy).map(|m| m.query),
+            Some(FrontendMessageKind::Parse) => match Parse::try_from(&mut copy) {
+                Ok(parse) if !parse.statement.is_empty() => {
+                    return Err(PgError::new(
+                        "0A000",
+                        "Named prepared statements are not supported in transaction pooling mode",
+                    ));
+                }
+                parse => parse.map(|m| m.query),
+            },
+            _ => return Ok(()),
+        };
+        // An invalid message is left to the server
+        let Ok(query) = query else { return Ok(()) };
+        match session_level_feature(&query.to_string_lossy()) {
+            Some(feature) => Err(PgError::new(
+                "0A000",
+                &format!("{feature} is not supported in transaction pooling mode"),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn checkout(&mut self) -> Result<(), PgError> {
+        if self.backend.is_none() {
+            let connection = self.pool.checkout().map_err(|e| {
+                PgError::new("53300", &format!("No server connection available: {e}"))
+            })?;
+            self.backend = Some(connection);
+        }
+        Ok(())
+    }
+
+    /// Send the message to the server connection and relay its answers
+    /// when the frontend waits for them. The connection goes back to the
+    /// pool after a ReadyForQuery outside of a transaction.
+    fn forward(&mut self, message: RawFrontendMessage) -> anyhow::Result<()> {
+        let backend: &mut TcpHandler = self
+            .backend
+            .as_mut()
+            .ok_or(anyhow!("No server connection"))?;
+        let ready = forward_command(
+            &message,
+            &mut backend.reader,
+            &mut backend.writer,
+            &mut self.writer,
+            &mut self.validator,
+        )?;
+        if let Some(transaction) = ready {
+            self.transaction = transaction;
+            if transaction == TransactionIndicator::Idle {
+                self.backend = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer a refused message with an error, the rest of an extended
+    /// query is skipped up to its Sync
+    fn refuse(&mut self, message_type: u8, e: &PgError) -> anyhow::Result<()> {
+        debug!("Refused: {e}");
+        let extended = !b"QF".contains(&message_type);
+        if extended && self.backend.is_some() {
+            // The answers to the messages already forwarded come first
+            self.forward(RawFrontendMessage {
+                header: MessageHeader {
+                    message_type: b'H',
+                    length: 4,
+                },
+                raw_body: Bytes::new(),
+            })?;
+        }
+        put_error_response(&mut self.writer, e)?;
+        if !extended {
+            self.writer.put_static(ready_for_query(self.transaction))?;
+            self.writer.flush()?;
+            return Ok(());
+        }
+
+        loop {
+            let message = RawFrontendMessage::get(&mut self.reader)?;
+            match message.get_message_kind() {
+                Some(FrontendMessageKind::Sync) if self.backend.is_some() => {
+                    self.writer.flush()?;
+                    return self.forward(message);
+                }
+                Some(FrontendMessageKind::Sync) => {
+                    self.writer.put_static(ready_for_query(self.transaction))?;
+                    self.writer.flush()?;
+                    return Ok(());
+                }
+                Some(FrontendMessageKind::Terminate) => {
+                    return Err(anyhow!("Terminate sent before the Sync"));
+                }
+                _ => debug!("rcv: {:?}, skipped", message.get_message_kind()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::pool::{Connector, PoolConfig};
+    use crate::handler::probe::{self, ConnInfo};
+    use crate::scenario::Scenario;
+    use crate::test_server::TestServer;
+    use bytes::BytesMut;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    // Send the messages, the types of the messages answering them up to
+    // ReadyForQuery and the first error
+    fn exchange(
+        frontend: &mut TcpHandler,
+        messages: &[u8],
+    ) -> anyhow::Result<(String, Option<String>)> {
+        frontend.writer.write_all(messages)?;
+        frontend.writer.flush()?;
+        let (mut types, mut error) = (String::new(), None);
+        loop {
+            let mut raw_message = RawBackendMessage::get(&mut frontend.reader)?;
+            types.push(raw_message.header.message_type as char);
+            match raw_message.get_message_kind() {
+                Some(BackendMessageKind::ReadyForQuery) => return Ok((types, error)),
+                Some(BackendMessageKind::ErrorResponse) if error.is_none() => {
+                    let message = ErrorResponse::try_from(&mut raw_message)?;
+                    error = message
+                        .messages
+                        .as_ref()
+                        .iter()
+                        .find(|field| field.code == b'M')
+                        .map(|field| field.message.to_string_lossy().into_owned());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn query(query: &str) -> anyhow::Result<BytesMut> {
+        let mut buffer = BytesMut::new();
+        MessageHeader::serialize_message(&mut buffer, &Query::new(String::from(query))?)?;
+        Ok(buffer)
+    }
+
+    fn extended_query(statement: &str) -> anyhow::Result<BytesMut> {
+        let mut buffer = BytesMut::new();
+        MessageHeader::serialize_message(&mut buffer, &Parse::new(statement, "SELECT 1", vec![])?)?;
+        MessageHeader::serialize_message(
+            &mut buffer,
+            &Bind::new("", statement, vec![], vec![], vec![])?,
+        )?;
+        MessageHeader::serialize_message(&mut buffer, &Execute::new("", 0)?)?;
+        MessageHeader::serialize_message(&mut buffer, &Sync::new())?;
+        Ok(buffer)
+    }
+
+    #[test]
+    fn transaction_pooling() -> anyhow::Result<()> {
+        let server = TestServer::start(
+            "127.0.0.1:0",
+            &Scenario::from_toml(
+                r#"
+                [[rules]]
+                query = "BEGIN"
+                command_tag = "BEGIN"
+
+                [[rules]]
+                query = "COMMIT"
+                command_tag = "COMMIT"
+
+                [[rules]]
+                query = "SELECT 1"
+                command_tag = "SELECT 1"
+                columns = [{ name = "n", type_oid = 23 }]
+                rows = [["1"]]
+                "#,
+            )?,
+        )?;
+        let conninfo: ConnInfo = format!(
+            "host=127.0.0.1 port={} user=app password=secret",
+            server.address().port()
+        )
+        .parse()?;
+        let connect: Connector =
+            Box::new(move || probe::connect(&conninfo, "pooler", Duration::from_secs(5)));
+        let config = PoolConfig {
+            max_size: 1,
+            checkout_timeout: Duration::from_millis(100),
+            ..PoolConfig::default()
+        };
+        let pool = Pool::new(config, connect);
+        let pooler = TransactionPooler::new(pool.clone())?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let frontend_conninfo: ConnInfo = format!(
+            "host=127.0.0.1 port={} user=app",
+            listener.local_addr()?.port()
+        )
+        .parse()?;
+        let sessions = thread::spawn(move || -> anyhow::Result<()> {
+            let threads: Vec<_> = listener
+                .incoming()
+                .take(2)
+                .map(|stream| {
+                    let pooler = pooler.clone();
+                    let stream = stream.expect("frontend connection");
+                    thread::spawn(move || pooler.serve(stream))
+                })
+                .collect();
+            for thread in threads {
+                thread.join().expect("session thread")?;
+            }
+            Ok(())
+        });
+        let timeout = Duration::from_secs(5);
+        let mut first = probe::connect(&frontend_conninfo, "first", timeout)?;
+        let mut second = probe::connect(&frontend_conninfo, "second", timeout)?;
+        assert!(first.parameter("server_version").is_some());
+
+        // The only server connection is kept for the transaction
+        assert_eq!("CZ", exchange(&mut first, &query("BEGIN")?)?.0);
+        let (types, error) = exchange(&mut second, &query("SELECT 1")?)?;
+        assert_eq!("EZ", types);
+        assert!(error.is_some_and(|e| e.starts_with("No server connection available")));
+        assert_eq!("CZ", exchange(&mut first, &query("COMMIT")?)?.0);
+        assert_eq!("TDCZ", exchange(&mut second, &query("SELECT 1")?)?.0);
+
+        let (types, error) = exchange(&mut second, &query("SET search_path TO app")?)?;
+        assert_eq!("EZ", types);
+        assert_eq!(
+            Some("SET is not supported in transaction pooling mode"),
+            error.as_deref()
+        );
+
+        // The rest of the extended query is skipped
+        let (types, error) = exchange(&mut first, &extended_query("s1")?)?;
+        assert_eq!("EZ", types);
+        assert!(error.is_some_and(|e| e.starts_with("Named prepared statements")));
+        assert_eq!("12DCZ", exchange(&mut first, &extended_query("")?)?.0);
+
+        for frontend in [&mut first, &mut second] {
+            frontend.writer.put_message_and_flush(Terminate::new())?;
+        }
+        sessions.join().expect("sessions thread")?;
+        assert_eq!((1, 1), pool.status());
+
+        server.stop()
+    }
+}
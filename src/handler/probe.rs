@@ -0,0 +1,419 @@
+//! The capabilities of a server, found by connecting to it as a client, see
+//! `fakepostmaster probe`. The probe only reads: it authenticates when it
+//! can, collects what the server sends up to ReadyForQuery and leaves
+//! without running a query.
+
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::*;
+
+use crate::dual_stack;
+use crate::handler::LibPqWriter;
+use crate::handler::client::TcpHandler;
+use crate::message::*;
+use crate::scram::{SCRAM_SHA_256, ScramClient};
+
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+
+/// The keywords of a libpq connection string known by the probe, e.g.
+/// `host=localhost port=5432 user=app dbname=app password=secret`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnInfo {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    // The name of the user when not set
+    pub dbname: Option<String>,
+    // Without it, the probe stops at the password request
+    pub password: Option<String>,
+}
+
+impl FromStr for ConnInfo {
+    type Err = anyhow::Error;
+
+    fn from_str(conninfo: &str) -> anyhow::Result<Self> {
+        let mut info = Self {
+            host: String::from("localhost"),
+            port: 5432,
+            user: String::from("postgres"),
+            dbname: None,
+            password: None,
+        };
+        for pair in conninfo.split_whitespace() {
+            let (keyword, value) = pair
+                .split_once('=')
+                .ok_or(anyhow!("Missing value for \"{pair}\""))?;
+            match keyword {
+                "host" => info.host = String::from(value),
+                "port" => info.port = value.parse()?,
+                "user" => info.user = String::from(value),
+                "dbname" => info.dbname = Some(String::from(value)),
+                "password" => info.password = Some(String::from(value)),
+                _ => return Err(anyhow!("Unknown keyword \"{keyword}\"")),
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// The answer to an SSLRequest or a GSSENCRequest
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionAnswer {
+    // 'S' or 'G', the probe doesn't go on with the handshake
+    Accepted,
+    // 'N'
+    Refused,
+    // An ErrorResponse, like the servers that don't know the request
+    Error,
+    // Closed without an answer
+    Closed,
+}
+
+impl fmt::Display for EncryptionAnswer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EncryptionAnswer::Accepted => "accepted",
+            EncryptionAnswer::Refused => "refused",
+            EncryptionAnswer::Error => "error",
+            EncryptionAnswer::Closed => "closed",
+        })
+    }
+}
+
+/// What a server told the probe
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProbeReport {
+    // Not set when the requests weren't sent, see connect()
+    pub ssl: Option<EncryptionAnswer>,
+    pub gssenc: Option<EncryptionAnswer>,
+    // The minor version of the protocol 3, lowered by NegotiateProtocolVersion
+    pub minor_version: i32,
+    // The authentication requested, e.g. md5 or the SASL mechanisms
+    pub authentication: Option<String>,
+    pub authenticated: bool,
+    // From BackendKeyData
+    pub pid: Option<i32>,
+    pub parameters: BTreeMap<String, String>,
+    // The message of the ErrorResponse that ended the startup
+    pub error: Option<String>,
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ssl) = self.ssl {
+            writeln!(f, "ssl: {ssl}")?;
+        }
+        if let Some(gssenc) = self.gssenc {
+            writeln!(f, "gssenc: {gssenc}")?;
+        }
+        writeln!(f, "protocol: 3.{}", self.minor_version)?;
+        match &self.authentication {
+            Some(method) if self.authenticated => writeln!(f, "authentication: {method}")?,
+            Some(method) => writeln!(f, "authentication: {method}, not completed")?,
+            None => writeln!(f, "authentication: none requested")?,
+        }
+        if let Some(pid) = self.pid {
+            writeln!(f, "pid: {pid}")?;
+        }
+        for (name, value) in &self.parameters {
+            writeln!(f, "parameter {name}: {value}")?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "error: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the server of `conninfo`, once for each encryption request
+/// and once for the startup
+pub fn probe(conninfo: &ConnInfo, timeout: Duration) -> anyhow::Result<ProbeReport> {
+    let mut report = ProbeReport {
+        ssl: Some(encryption(conninfo, SSL_REQUEST, timeout)?),
+        gssenc: Some(encryption(conninfo, GSSENC_REQUEST, timeout)?),
+        ..ProbeReport::default()
+    };
+    let mut handler = startup(conninfo, timeout, "fakepostmaster probe", &mut report)?;
+    if report.authenticated && report.error.is_none() {
+        handler.writer.put_message_and_flush(Terminate::new())?;
+    }
+    report.parameters = handler
+        .parameters()
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    Ok(report)
+}
+
+/// Connect and authenticate to the server of `conninfo`, e.g. for the
+/// connections of a benchmark. The timeout is the one of the startup.
+pub fn connect(
+    conninfo: &ConnInfo,
+    application_name: &str,
+    timeout: Duration,
+) -> anyhow::Result<TcpHandler> {
+    let mut report = ProbeReport::default();
+    let handler = startup(conninfo, timeout, application_name, &mut report)?;
+    match (report.error, report.authentication) {
+        (Some(error), _) => Err(anyhow!("Connection refused: {error}")),
+        (None, _) if report.authenticated => {
+            handler.reader.get_ref().set_read_timeout(None)?;
+            Ok(handler)
+        }
+        (None, Some(method)) => Err(anyhow!("Authentication {method} not completed")),
+        (None, None) => Err(anyhow!("The startup ended without AuthenticationOk")),
+    }
+}
+
+/// Send the StartupMessage and authenticate when possible, up to the
+/// ReadyForQuery or the first request the probe can't answer
+fn startup(
+    conninfo: &ConnInfo,
+    timeout: Duration,
+    application_name: &str,
+    report: &mut ProbeReport,
+) -> anyhow::Result<TcpHandler> {
+    let stream = dual_stack::connect((&conninfo.host[..], conninfo.port), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let mut handler = TcpHandler::new(stream)?;
+    let user = &conninfo.user;
+    let parameters = vec![
+        ParameterStatus::new("user", user)?,
+        ParameterStatus::new("database", conninfo.dbname.as_ref().unwrap_or(user))?,
+        ParameterStatus::new("application_name", application_name)?,
+    ];
+    authenticate(
+        &mut handler,
+        parameters,
+        conninfo.password.as_deref(),
+        report,
+    )?;
+    if !report.authenticated || report.error.is_some() {
+        return Ok(handler);
+    }
+
+    loop {
+        let mut raw_message = handler.get_raw_backend_message()?;
+        match raw_message.get_message_kind() {
+            Some(BackendMessageKind::BackendKeyData) => {
+                report.pid = Some(BackendKeyData::try_from(&mut raw_message)?.process_id);
+            }
+            Some(BackendMessageKind::ReadyForQuery) => break,
+            Some(BackendMessageKind::ErrorResponse) => {
+                report.error = error_message(&mut raw_message)?;
+                break;
+            }
+            Some(BackendMessageKind::ParameterStatus | BackendMessageKind::NoticeResponse) => (),
+            kind => return Err(anyhow!("Unexpected message {kind:?} during the startup")),
+        }
+    }
+    Ok(handler)
+}
+
+/// Send a StartupMessage on a new connection and answer the authentication
+/// requests with the password, up to the AuthenticationOk (the messages
+/// following it are left unread) or the first request that can't be
+/// answered. The user is the one of the parameters.
+pub fn authenticate(
+    handler: &mut TcpHandler,
+    parameters: Vec<ParameterStatus>,
+    password: Option<&str>,
+    report: &mut ProbeReport,
+) -> anyhow::Result<()> {
+    let user = parameters
+        .iter()
+        .find(|parameter| parameter.name.as_bytes() == b"user")
+        .map(|parameter| parameter.value.to_string_lossy().into_owned())
+        .ok_or(anyhow!("The startup parameters have no user"))?;
+    handler.writer.put_request(StartupMessage::new(
+        ProtocolVersion { major: 3, minor: 0 },
+        parameters,
+    ))?;
+
+    let mut scram = None;
+    loop {
+        // Read as is for the message of an ErrorResponse
+        let mut raw_message = RawBackendMessage::get(&mut handler.reader)?;
+        match raw_message.get_message_kind() {
+            Some(BackendMessageKind::NegotiateProtocolVersion) => {
+                let message = NegotiateProtocolVersion::try_from(&mut raw_message)?;
+                debug!("rcv: {message:?}");
+                report.minor_version = message.newest_minor_version;
+            }
+            Some(BackendMessageKind::Authentication) => {
+                let code = i32::from_be_bytes(
+                    raw_message
+                        .raw_body
+                        .get(..4)
+                        .ok_or(anyhow!("Authentication message too short"))?
+                        .try_into()?,
+                );
+                match AuthenticationMessageKind::try_from(code)? {
+                    AuthenticationMessageKind::Ok => {
+                        report.authenticated = true;
+                        report
+                            .authentication
+                            .get_or_insert_with(|| String::from("trust"));
+                        return Ok(());
+                    }
+                    AuthenticationMessageKind::MD5Password => {
+                        report.authentication = Some(String::from("md5"));
+                        let Some(password) = password else { break };
+                        let message = AuthenticationMD5Password::try_from(&mut raw_message)?;
+                        handler.writer.put_message_and_flush(
+                            PasswordMessage::new_from_user_password(
+                                &user,
+                                &String::from(password),
+                                &message.salt,
+                            )?,
+                        )?;
+                    }
+                    AuthenticationMessageKind::CleartextPassword => {
+                        report.authentication = Some(String::from("password"));
+                        let Some(password) = password else { break };
+                        handler
+                            .writer
+                            .put_message_and_flush(PasswordMessage::new(password)?)?;
+                    }
+                    AuthenticationMessageKind::SASL => {
+                        let message = AuthenticationSASL::try_from(&mut raw_message)?;
+                        let mechanisms: Vec<String> = message
+                            .mechanisms
+                            .as_ref()
+                            .iter()
+                            .map(|mechanism| mechanism.to_string_lossy().into_owned())
+                            .collect();
+                        report.authentication = Some(format!("SASL ({})", mechanisms.join(", ")));
+                        let Some(password) = password else { break };
+                        if !mechanisms.iter().any(|m| m == SCRAM_SHA_256) {
+                            break;
+                        }
+                        let client = scram.insert(ScramClient::new(password)?);
+                        handler
+                            .writer
+                            .put_message_and_flush(SASLInitialResponse::new(
+                                SCRAM_SHA_256,
+                                client.client_first().into_bytes(),
+                            )?)?;
+                    }
+                    AuthenticationMessageKind::SASLContinue => {
+                        let message = AuthenticationSASLContinue::try_from(&mut raw_message)?;
+                        let client = scram
+                            .as_mut()
+                            .ok_or(anyhow!("SASL challenge before the initial response"))?;
+                        let server_first = String::from_utf8(message.data.into())?;
+                        handler.writer.put_message_and_flush(SASLResponse::new(
+                            client.client_final(&server_first)?.into_bytes(),
+                        ))?;
+                    }
+                    AuthenticationMessageKind::SASLFinal => {
+                        let message = AuthenticationSASLFinal::try_from(&mut raw_message)?;
+                        let client = scram
+                            .as_ref()
+                            .ok_or(anyhow!("SASL outcome before the initial response"))?;
+                        client.verify(&String::from_utf8(message.data.into())?)?;
+                    }
+                    kind => {
+                        report.authentication = Some(format!("{kind:?}"));
+                        break;
+                    }
+                }
+            }
+            Some(BackendMessageKind::ErrorResponse) => {
+                report.error = error_message(&mut raw_message)?;
+                break;
+            }
+            Some(BackendMessageKind::NoticeResponse) => (),
+            kind => return Err(anyhow!("Unexpected message {kind:?} during the startup")),
+        }
+    }
+    Ok(())
+}
+
+/// The message of an ErrorResponse
+fn error_message(raw_message: &mut RawBackendMessage) -> anyhow::Result<Option<String>> {
+    let message = ErrorResponse::try_from(raw_message)?;
+    Ok(message
+        .messages
+        .as_ref()
+        .iter()
+        .find(|field| field.code == b'M')
+        .map(|field| field.message.to_string_lossy().into_owned()))
+}
+
+/// Send an encryption request on a new connection and read the answer
+fn encryption(
+    conninfo: &ConnInfo,
+    request: i32,
+    timeout: Duration,
+) -> anyhow::Result<EncryptionAnswer> {
+    let mut stream = dual_stack::connect((&conninfo.host[..], conninfo.port), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let mut packet = 8_i32.to_be_bytes().to_vec();
+    packet.extend_from_slice(&request.to_be_bytes());
+    stream.write_all(&packet)?;
+
+    let mut answer = [0; 1];
+    Ok(match stream.read(&mut answer)? {
+        0 => EncryptionAnswer::Closed,
+        _ => match answer[0] {
+            b'S' | b'G' => EncryptionAnswer::Accepted,
+            b'N' => EncryptionAnswer::Refused,
+            b'E' => EncryptionAnswer::Error,
+            byte => return Err(anyhow!("Unexpected answer {byte:#04x} to {request}")),
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::handler::server::NegotiationPolicy;
+    use crate::test_server::TestServer;
+    use std::net::TcpListener;
+
+    #[test]
+    fn probe_test_server() -> anyhow::Result<()> {
+        let mut config = ServerConfig::default();
+        config.negotiation.gssenc = NegotiationPolicy::Close;
+        let server = TestServer::from_config(TcpListener::bind("127.0.0.1:0")?, &config)?;
+        let conninfo: ConnInfo = format!(
+            "host=127.0.0.1 port={} user=app password=secret",
+            server.address().port()
+        )
+        .parse()?;
+
+        let report = probe(&conninfo, Duration::from_secs(5))?;
+        assert_eq!(Some(EncryptionAnswer::Refused), report.ssl);
+        assert_eq!(Some(EncryptionAnswer::Closed), report.gssenc);
+        assert_eq!(Some("md5"), report.authentication.as_deref());
+        assert!(report.authenticated);
+        assert_eq!(
+            Some("0.1 (fakepostmaster)"),
+            report.parameters.get("server_version").map(|v| &v[..])
+        );
+
+        // Without the password
+        let conninfo = ConnInfo {
+            password: None,
+            ..conninfo
+        };
+        let report = probe(&conninfo, Duration::from_secs(5))?;
+        assert!(!report.authenticated);
+        assert!(
+            report
+                .to_string()
+                .contains("authentication: md5, not completed")
+        );
+
+        assert!("host=db sslmode=require".parse::<ConnInfo>().is_err());
+
+        server.stop()
+    }
+}
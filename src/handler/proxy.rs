@@ -0,0 +1,1101 @@
+use anyhow::anyhow;
+use bytes::BytesMut;
+use libpq_serde_types::{ByteSized, Serialize};
+use std::{
+    ffi::CString,
+    io::{BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpStream},
+    ops::DerefMut,
+    sync::mpsc,
+    thread,
+};
+use tracing::*;
+
+use crate::error::PgError;
+use crate::handler::LibPqWriter;
+use crate::handler::client;
+use crate::handler::credentials::Credentials;
+use crate::handler::pool::{Pool, PooledConnection};
+use crate::handler::probe::{self, ProbeReport};
+use crate::handler::rewrite::{Rewriter, Route};
+use crate::handler::translate::ErrorTranslator;
+use crate::message::*;
+use crate::protocol::Phase;
+use crate::random;
+use crate::validator::Validator;
+
+/// A message relayed by the proxy
+#[derive(Debug)]
+pub enum ProxiedMessage {
+    Frontend(RawFrontendMessage),
+    Backend(RawBackendMessage),
+}
+
+/// Write a message as it was read and flush it
+pub fn put_raw_message(
+    writer: &mut BufWriter<TcpStream>,
+    header: &MessageHeader,
+    raw_body: &[u8],
+) -> anyhow::Result<()> {
+    let mut buffer = BytesMut::with_capacity(header.length as usize + 1);
+    header.serialize(&mut buffer);
+    buffer.extend_from_slice(raw_body);
+    writer.write_all(&buffer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Send an ErrorResponse, without flushing
+pub fn put_error_response(writer: &mut BufWriter<TcpStream>, e: &PgError) -> anyhow::Result<()> {
+    writer.put_message(ErrorResponse::new(vec![
+        ErrorMessage::new('S', "ERROR")?,
+        ErrorMessage::new('V', "ERROR")?,
+        ErrorMessage::new('C', &e.code)?,
+        ErrorMessage::new('M', &e.message)?,
+    ]))
+}
+
+/// Send a message of the frontend to a server and, when the frontend waits
+/// for them, relay the answers until the commands sent are answered or a
+/// COPY IN waits for its data. The validator follows the exchanges with
+/// this server.
+///
+/// The status of the transaction when the answers ended with a
+/// ReadyForQuery.
+pub fn forward_command(
+    message: &RawFrontendMessage,
+    backend_reader: &mut BufReader<TcpStream>,
+    backend_writer: &mut BufWriter<TcpStream>,
+    frontend_writer: &mut BufWriter<TcpStream>,
+    validator: &mut Validator,
+) -> anyhow::Result<Option<TransactionIndicator>> {
+    let message_type = message.header.message_type;
+    validator.frontend_message(message_type)?;
+    put_raw_message(backend_writer, &message.header, &message.raw_body)?;
+    if !b"QSHFcf".contains(&message_type) {
+        return Ok(None);
+    }
+
+    let mut ready = None;
+    while validator.is_waiting() && validator.phase() != Phase::CopyIn {
+        let answer = RawBackendMessage::get(backend_reader)?;
+        validator.backend_message(answer.header.message_type)?;
+        ready = match (answer.get_message_kind(), answer.raw_body.first()) {
+            (Some(BackendMessageKind::ReadyForQuery), Some(status)) => {
+                Some(TransactionIndicator::try_from(status)?)
+            }
+            _ => None,
+        };
+        put_raw_message(frontend_writer, &answer.header, &answer.raw_body)?;
+    }
+    Ok(ready)
+}
+
+/// Copy the messages as they are read, a write for each read instead of one
+/// per message. Only the headers are looked at, to check the lengths and to
+/// stop after a Terminate when `frontend`.
+///
+/// The number of messages copied.
+pub fn forward_frames(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    frontend: bool,
+) -> anyhow::Result<u64> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut messages = 0;
+    // The bytes of the current message not read yet, and its header while
+    // it is split between two reads
+    let mut body_left = 0;
+    let mut header = Vec::with_capacity(5);
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            return Err(anyhow!("Connection closed"));
+        }
+        let mut position = 0;
+        while position < count {
+            if body_left > 0 {
+                let skipped = body_left.min(count - position);
+                body_left -= skipped;
+                position += skipped;
+                continue;
+            }
+            header.push(buffer[position]);
+            position += 1;
+            if header.len() < 5 {
+                continue;
+            }
+            let length = i32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+            if length < 4 {
+                return Err(anyhow!("Invalid message length: {length}"));
+            }
+            body_left = (length - 4) as usize;
+            messages += 1;
+            if frontend && header[0] == b'X' && body_left == 0 {
+                writer.write_all(&buffer[..position])?;
+                writer.flush()?;
+                return Ok(messages);
+            }
+            header.clear();
+        }
+        writer.write_all(&buffer[..count])?;
+        writer.flush()?;
+    }
+}
+
+/// Serialize a message to forward it
+fn raw_frontend_message<U>(message: &U) -> anyhow::Result<RawFrontendMessage>
+where
+    U: MessageBody + Serialize + ByteSized,
+{
+    let mut buffer = BytesMut::new();
+    MessageHeader::serialize_message(&mut buffer, message)?;
+    let raw_body = buffer.split_off(5).freeze();
+    Ok(RawFrontendMessage {
+        header: MessageHeader {
+            message_type: message.message_type(),
+            length: MessageHeader::length_for(raw_body.len())?,
+        },
+        raw_body,
+    })
+}
+
+/// Skip the messages of an extended query up to its Sync
+fn skip_to_sync(reader: &mut BufReader<TcpStream>) -> anyhow::Result<RawFrontendMessage> {
+    loop {
+        let message = RawFrontendMessage::get(reader)?;
+        match message.get_message_kind() {
+            Some(FrontendMessageKind::Sync) => return Ok(message),
+            Some(FrontendMessageKind::Terminate) => {
+                return Err(anyhow!("Terminate sent before the Sync"));
+            }
+            kind => debug!("rcv: {kind:?}, skipped"),
+        }
+    }
+}
+
+/// End a startup with a FATAL error, the server isn't told
+fn refuse_startup(writer: &mut BufWriter<TcpStream>, e: &PgError) -> anyhow::Result<()> {
+    warn!("Connection refused: {e}");
+    writer.put_message_and_flush(ErrorResponse::new(vec![
+        ErrorMessage::new('S', "FATAL")?,
+        ErrorMessage::new('V', "FATAL")?,
+        ErrorMessage::new('C', &e.code)?,
+        ErrorMessage::new('M', &e.message)?,
+    ]))?;
+    Err(anyhow!("Connection refused: {e}"))
+}
+
+/// A server of relay_rewritten()
+struct Backend<H> {
+    handler: H,
+    validator: Validator,
+    // As reported by its last ReadyForQuery
+    transaction: TransactionIndicator,
+}
+
+impl<H: DerefMut<Target = client::TcpHandler>> Backend<H> {
+    /// A connection that was already authenticated
+    fn ready(handler: H) -> anyhow::Result<Self> {
+        let mut validator = Validator::new();
+        validator.backend_message(b'Z')?;
+        Ok(Self {
+            handler,
+            validator,
+            transaction: TransactionIndicator::Idle,
+        })
+    }
+
+    /// Relay the messages of the server up to its first ReadyForQuery, the
+    /// answers of the frontend to the authentication requests included
+    fn relay_authentication(
+        &mut self,
+        frontend_reader: &mut BufReader<TcpStream>,
+        frontend_writer: &mut BufWriter<TcpStream>,
+    ) -> anyhow::Result<()> {
+        let handler: &mut client::TcpHandler = &mut self.handler;
+        loop {
+            let message = RawBackendMessage::get(&mut handler.reader)?;
+            self.validator
+                .backend_message(message.header.message_type)?;
+            put_raw_message(frontend_writer, &message.header, &message.raw_body)?;
+            match message.get_message_kind() {
+                Some(BackendMessageKind::ReadyForQuery) => return Ok(()),
+                Some(BackendMessageKind::ErrorResponse) => {
+                    return Err(anyhow!("The server refused the connection"));
+                }
+                // Everything but AuthenticationOk and AuthenticationSASLFinal
+                // waits for an answer
+                Some(BackendMessageKind::Authentication)
+                    if !matches!(message.raw_body.get(..4), Some([0, 0, 0, 0 | 12])) =>
+                {
+                    let answer = RawFrontendMessage::get(frontend_reader)?;
+                    self.validator
+                        .frontend_message(answer.header.message_type)?;
+                    put_raw_message(&mut handler.writer, &answer.header, &answer.raw_body)?;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Whether the answers ended with a ReadyForQuery outside of a
+    /// transaction
+    fn forward(
+        &mut self,
+        message: &RawFrontendMessage,
+        frontend_writer: &mut BufWriter<TcpStream>,
+    ) -> anyhow::Result<bool> {
+        let handler: &mut client::TcpHandler = &mut self.handler;
+        let ready = forward_command(
+            message,
+            &mut handler.reader,
+            &mut handler.writer,
+            frontend_writer,
+            &mut self.validator,
+        )?;
+        if let Some(transaction) = ready {
+            self.transaction = transaction;
+        }
+        Ok(ready == Some(TransactionIndicator::Idle))
+    }
+}
+
+/// The state of relay_rewritten()
+struct RewriteSession<'a> {
+    frontend_reader: BufReader<TcpStream>,
+    frontend_writer: BufWriter<TcpStream>,
+    primary: Backend<Box<client::TcpHandler>>,
+    replicas: Option<&'a Pool>,
+    // Taken from the replicas for a command, or for a transaction
+    replica: Option<Backend<PooledConnection>>,
+    // The server of the extended query up to its Sync
+    sequence: Option<Route>,
+    // The server of the last command, for the COPY messages
+    last: Route,
+}
+
+impl RewriteSession<'_> {
+    /// An explicit transaction stays on the server where it started
+    fn sticky(&self) -> Option<Route> {
+        if self.primary.transaction != TransactionIndicator::Idle {
+            Some(Route::Primary)
+        } else if self
+            .replica
+            .as_ref()
+            .is_some_and(|replica| replica.transaction != TransactionIndicator::Idle)
+        {
+            Some(Route::Replica)
+        } else {
+            None
+        }
+    }
+
+    fn choose(&self, route: Route) -> Route {
+        match (self.sticky(), route) {
+            (Some(sticky), _) => sticky,
+            (None, Route::Replica) if self.replicas.is_some() => Route::Replica,
+            (None, _) => Route::Primary,
+        }
+    }
+
+    /// The status sent with a refused query
+    fn transaction(&self) -> TransactionIndicator {
+        match (self.sticky(), &self.replica) {
+            (Some(Route::Replica), Some(replica)) => replica.transaction,
+            _ => self.primary.transaction,
+        }
+    }
+
+    /// Take a connection of the replicas when needed, the primary is used
+    /// when none is available
+    fn connect(&mut self, route: Route) -> Route {
+        if route == Route::Replica
+            && self.replica.is_none()
+            && let Some(replicas) = self.replicas
+        {
+            match replicas.checkout().and_then(Backend::ready) {
+                Ok(replica) => self.replica = Some(replica),
+                Err(e) => {
+                    warn!("No replica connection, sent to the primary: {e}");
+                    return Route::Primary;
+                }
+            }
+        }
+        route
+    }
+
+    /// The connection of a replica goes back to the pool after a
+    /// ReadyForQuery outside of a transaction
+    fn forward(&mut self, route: Route, message: &RawFrontendMessage) -> anyhow::Result<()> {
+        match (route, self.replica.as_mut()) {
+            (Route::Replica, Some(replica)) => {
+                if replica.forward(message, &mut self.frontend_writer)? {
+                    self.replica = None;
+                }
+            }
+            _ => {
+                self.primary.forward(message, &mut self.frontend_writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, rewriter: &Rewriter) -> anyhow::Result<()> {
+        loop {
+            let mut message = RawFrontendMessage::get(&mut self.frontend_reader)?;
+            let route = match message.get_message_kind() {
+                Some(FrontendMessageKind::Terminate) => {
+                    let handler: &mut client::TcpHandler = &mut self.primary.handler;
+                    put_raw_message(&mut handler.writer, &message.header, &message.raw_body)?;
+                    return Ok(());
+                }
+                Some(FrontendMessageKind::Query) => {
+                    let mut query = Query::try_from(&mut message)?;
+                    match rewriter.rewrite(&query.query.to_string_lossy()) {
+                        Ok((rewritten, route)) => {
+                            query.query = CString::new(rewritten)?;
+                            message = raw_frontend_message(&query)?;
+                            self.choose(route)
+                        }
+                        Err(e) => {
+                            debug!("Refused: {e}");
+                            put_error_response(&mut self.frontend_writer, &e)?;
+                            self.frontend_writer
+                                .put_static(ready_for_query(self.transaction()))?;
+                            self.frontend_writer.flush()?;
+                            continue;
+                        }
+                    }
+                }
+                Some(FrontendMessageKind::Parse) => {
+                    let mut parse = Parse::try_from(&mut message)?;
+                    match rewriter.rewrite(&parse.query.to_string_lossy()) {
+                        Ok((rewritten, route)) => {
+                            parse.query = CString::new(rewritten)?;
+                            message = raw_frontend_message(&parse)?;
+                            // The replica connections are shared, the named
+                            // statements stay on the primary
+                            let route = match parse.statement.is_empty() {
+                                true => self.choose(route),
+                                false => self.choose(Route::Primary),
+                            };
+                            *self.sequence.get_or_insert(route)
+                        }
+                        Err(e) => {
+                            // The answers to the messages already sent come
+                            // first, the Sync gives the ReadyForQuery
+                            debug!("Refused: {e}");
+                            let route = self.sequence.unwrap_or_default();
+                            self.forward(route, &raw_frontend_message(&Flush::new())?)?;
+                            put_error_response(&mut self.frontend_writer, &e)?;
+                            message = skip_to_sync(&mut self.frontend_reader)?;
+                            route
+                        }
+                    }
+                }
+                Some(
+                    FrontendMessageKind::CopyData
+                    | FrontendMessageKind::CopyDone
+                    | FrontendMessageKind::CopyFail,
+                ) => self.last,
+                Some(FrontendMessageKind::FunctionCall) | None => self.choose(Route::Primary),
+                _ => match self.sequence {
+                    Some(route) => route,
+                    None => self.choose(Route::Primary),
+                },
+            };
+
+            let route = self.connect(route);
+            match message.get_message_kind() {
+                Some(FrontendMessageKind::Sync) => self.sequence = None,
+                Some(
+                    FrontendMessageKind::Parse
+                    | FrontendMessageKind::Bind
+                    | FrontendMessageKind::Describe
+                    | FrontendMessageKind::Execute
+                    | FrontendMessageKind::Close
+                    | FrontendMessageKind::Flush,
+                ) => self.sequence = Some(route),
+                _ => (),
+            }
+            self.last = route;
+            self.forward(route, &message)?;
+        }
+    }
+}
+
+/// Sits between a frontend and a real server and relays the messages
+/// untouched, so that the traffic can be observed.
+pub struct TcpHandler {
+    pub frontend: TcpStream,
+    pub backend: TcpStream,
+}
+
+impl TcpHandler {
+    pub fn new(frontend: TcpStream, backend: TcpStream) -> anyhow::Result<Self> {
+        Ok(Self { frontend, backend })
+    }
+
+    /// Relay the startup request, the SSL and GSS encryption requests are
+    /// refused so that the rest of the traffic can be read.
+    fn relay_startup(&mut self) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(self.frontend.try_clone()?);
+        loop {
+            let request = RawRequest::get(&mut reader)?;
+            match request.request_kind {
+                RequestMessageKind::SSLRequest | RequestMessageKind::GSSENCRequest => {
+                    debug!("rcv: {:?}, refused", request.request_kind);
+                    self.frontend.write_all(b"N")?;
+                }
+                _ => {
+                    debug!("rcv: {:?}", request.request_kind);
+                    let mut buffer = BytesMut::new();
+                    request.header.serialize(&mut buffer);
+                    buffer.extend_from_slice(&request.raw_body);
+                    self.backend.write_all(&buffer)?;
+
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Relay the messages until one side closes the connection. Every message
+    /// following the startup request is given to the observer in the order
+    /// it was relayed. Without an observer, forward() is faster.
+    pub fn relay(mut self, observer: &mut dyn FnMut(ProxiedMessage)) -> anyhow::Result<()> {
+        self.relay_startup()?;
+
+        let (sender, receiver) = mpsc::channel();
+
+        // The message is given to the observer before being relayed, this way
+        // a query is always observed before its answer. When a side closes
+        // the connection, both are shut down to end the other thread.
+        let mut frontend_reader = BufReader::new(self.frontend.try_clone()?);
+        let mut backend_writer = BufWriter::new(self.backend.try_clone()?);
+        let frontend_sender = sender.clone();
+        let frontend_thread = thread::spawn(move || -> anyhow::Result<()> {
+            let result = loop {
+                let message = match RawFrontendMessage::get(&mut frontend_reader) {
+                    Ok(message) => message,
+                    Err(e) => break Err(e),
+                };
+                let header = MessageHeader {
+                    message_type: message.header.message_type,
+                    length: message.header.length,
+                };
+                let raw_body = message.raw_body.clone();
+                let _ = frontend_sender.send(ProxiedMessage::Frontend(message));
+                if let Err(e) = put_raw_message(&mut backend_writer, &header, &raw_body) {
+                    break Err(e);
+                }
+                if let Ok(FrontendMessageKind::Terminate) =
+                    FrontendMessageKind::try_from(header.message_type)
+                {
+                    break Ok(());
+                }
+            };
+            let _ = frontend_reader.get_ref().shutdown(Shutdown::Both);
+            let _ = backend_writer.get_ref().shutdown(Shutdown::Both);
+            result
+        });
+
+        let mut backend_reader = BufReader::new(self.backend.try_clone()?);
+        let mut frontend_writer = BufWriter::new(self.frontend.try_clone()?);
+        let backend_thread = thread::spawn(move || -> anyhow::Result<()> {
+            let result = loop {
+                let message = match RawBackendMessage::get(&mut backend_reader) {
+                    Ok(message) => message,
+                    Err(e) => break Err(e),
+                };
+                let header = MessageHeader {
+                    message_type: message.header.message_type,
+                    length: message.header.length,
+                };
+                let raw_body = message.raw_body.clone();
+                let _ = sender.send(ProxiedMessage::Backend(message));
+                if let Err(e) = put_raw_message(&mut frontend_writer, &header, &raw_body) {
+                    break Err(e);
+                }
+            };
+            let _ = backend_reader.get_ref().shutdown(Shutdown::Both);
+            let _ = frontend_writer.get_ref().shutdown(Shutdown::Both);
+            result
+        });
+
+        // The loop ends when both threads have dropped their sender
+        for message in receiver {
+            observer(message);
+        }
+
+        for (side, thread) in [("frontend", frontend_thread), ("backend", backend_thread)] {
+            match thread.join() {
+                Ok(Err(e)) => debug!("{side} connection closed: {e}"),
+                Ok(Ok(())) => debug!("{side} connection closed"),
+                Err(_) => error!("{side} relay thread panicked"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Relay the messages one command at a time, the queries of the Query
+    /// and Parse messages are rewritten by the rules first. The queries
+    /// routed to the replicas go to a connection of their pool, kept up to
+    /// the end of the command or of the transaction. An explicit
+    /// transaction stays on the server where it started, and an extended
+    /// query on the server of its first message up to its Sync.
+    ///
+    /// The notifications of the primary are only relayed with the answers
+    /// of the next command.
+    pub fn relay_rewritten(
+        mut self,
+        rewriter: &Rewriter,
+        replicas: Option<&Pool>,
+    ) -> anyhow::Result<()> {
+        self.relay_startup()?;
+
+        let mut session = RewriteSession {
+            frontend_reader: BufReader::new(self.frontend.try_clone()?),
+            frontend_writer: BufWriter::new(self.frontend.try_clone()?),
+            primary: Backend {
+                handler: Box::new(client::TcpHandler::new(self.backend.try_clone()?)?),
+                validator: Validator::new(),
+                transaction: TransactionIndicator::Idle,
+            },
+            replicas,
+            replica: None,
+            sequence: None,
+            last: Route::Primary,
+        };
+        session
+            .primary
+            .relay_authentication(&mut session.frontend_reader, &mut session.frontend_writer)?;
+        let result = session.run(rewriter);
+        // Left in the middle of a transaction or of an extended query
+        if let Some(replica) = session.replica.take() {
+            replica.handler.discard();
+        }
+        result
+    }
+
+    /// Relay the messages like relay() without decoding them nor giving
+    /// them to an observer: the bytes are copied as they arrive, only the
+    /// headers are read (see examples/bench_proxy.rs for the difference).
+    pub fn forward(mut self) -> anyhow::Result<()> {
+        self.relay_startup()?;
+        self.forward_streams()
+    }
+
+    /// Relay the messages like forward(), the ErrorResponses and the
+    /// NoticeResponses of the server are read one by one to be translated
+    pub fn relay_translated(mut self, translator: &ErrorTranslator) -> anyhow::Result<()> {
+        self.relay_startup()?;
+
+        let mut frontend = self.frontend.try_clone()?;
+        let mut backend = self.backend.try_clone()?;
+        let frontend_thread = thread::spawn(move || -> anyhow::Result<u64> {
+            let result = forward_frames(&mut frontend, &mut backend, true);
+            let _ = frontend.shutdown(Shutdown::Both);
+            let _ = backend.shutdown(Shutdown::Both);
+            result
+        });
+
+        let mut backend_reader = BufReader::new(self.backend.try_clone()?);
+        let mut frontend_writer = BufWriter::new(self.frontend.try_clone()?);
+        let result = (|| -> anyhow::Result<()> {
+            loop {
+                let message = translator.translate(RawBackendMessage::get(&mut backend_reader)?)?;
+                put_raw_message(&mut frontend_writer, &message.header, &message.raw_body)?;
+            }
+        })();
+        let _ = self.backend.shutdown(Shutdown::Both);
+        let _ = self.frontend.shutdown(Shutdown::Both);
+
+        if let Err(e) = result {
+            debug!("backend connection closed: {e}");
+        }
+        match frontend_thread.join() {
+            Ok(Err(e)) => debug!("frontend connection closed: {e}"),
+            Ok(Ok(messages)) => debug!("frontend connection closed after {messages} messages"),
+            Err(_) => error!("frontend forward thread panicked"),
+        }
+
+        Ok(())
+    }
+
+    /// Authenticate the frontend with the user store of the proxy, then the
+    /// server with the credentials stored for this user: the frontend never
+    /// sees the password of the server. The rest of the startup and the
+    /// session are forwarded untouched, like forward().
+    ///
+    /// The frontends are asked for an MD5 password, the server can ask for
+    /// any of the methods of probe::authenticate().
+    pub fn relay_with_credentials(self, credentials: &Credentials) -> anyhow::Result<()> {
+        let mut frontend_reader = BufReader::new(self.frontend.try_clone()?);
+        let mut frontend_writer = BufWriter::new(self.frontend.try_clone()?);
+        let mut request = RawRequest::get(&mut frontend_reader)?;
+        while matches!(
+            request.request_kind,
+            RequestMessageKind::SSLRequest | RequestMessageKind::GSSENCRequest
+        ) {
+            debug!("rcv: {:?}, refused", request.request_kind);
+            frontend_writer.write_all(b"N")?;
+            frontend_writer.flush()?;
+            request = RawRequest::get(&mut frontend_reader)?;
+        }
+        let startup = StartupMessage::try_from(&mut request)?;
+        let parameter = |name: &str| {
+            startup
+                .parameters
+                .as_ref()
+                .iter()
+                .find(|parameter| parameter.name.as_bytes() == name.as_bytes())
+                .map(|parameter| parameter.value.to_string_lossy().into_owned())
+        };
+        let name = parameter("user").unwrap_or_default();
+
+        let Some(user) = credentials.user(&name) else {
+            let e = PgError::new("28000", &format!("no entry for user \"{name}\""));
+            return refuse_startup(&mut frontend_writer, &e);
+        };
+        let salt = random::secure_bytes()?;
+        frontend_writer.put_message_and_flush(AuthenticationMD5Password::new(salt))?;
+        let mut raw_message = RawFrontendMessage::get(&mut frontend_reader)?;
+        let password = PasswordMessage::try_from(&mut raw_message)
+            .map_err(|_| anyhow!("Password message expected"))?;
+        if PasswordMessage::new_from_user_password(&user.name, &user.password, &salt)? != password {
+            let e = PgError::new(
+                "28P01",
+                &format!("password authentication failed for user \"{name}\""),
+            );
+            return refuse_startup(&mut frontend_writer, &e);
+        }
+
+        // The other parameters of the frontend are kept, e.g. the database
+        let mut parameters = vec![ParameterStatus::new("user", user.server_user())?];
+        for parameter in startup.parameters.as_ref() {
+            if parameter.name.as_bytes() != b"user" {
+                parameters.push(ParameterStatus::new(
+                    &parameter.name.to_string_lossy(),
+                    &parameter.value.to_string_lossy(),
+                )?);
+            }
+        }
+        let mut backend = client::TcpHandler::new(self.backend.try_clone()?)?;
+        let mut report = ProbeReport::default();
+        probe::authenticate(
+            &mut backend,
+            parameters,
+            user.server_password.as_deref(),
+            &mut report,
+        )?;
+        if !report.authenticated {
+            let message = match (report.error, report.authentication) {
+                (Some(error), _) => error,
+                (None, method) => format!(
+                    "authentication {} of the server not completed",
+                    method.unwrap_or_default()
+                ),
+            };
+            return refuse_startup(&mut frontend_writer, &PgError::new("28000", &message));
+        }
+        debug!("{name} authenticated as {}", user.server_user());
+
+        // The messages following AuthenticationOk that were already read
+        frontend_writer.put_static(&AUTHENTICATION_OK)?;
+        frontend_writer.write_all(backend.reader.buffer())?;
+        frontend_writer.flush()?;
+        self.forward_streams()
+    }
+
+    /// Copy the bytes both ways until one side closes the connection
+    fn forward_streams(mut self) -> anyhow::Result<()> {
+        let mut frontend = self.frontend.try_clone()?;
+        let mut backend = self.backend.try_clone()?;
+        let frontend_thread = thread::spawn(move || -> anyhow::Result<u64> {
+            let result = forward_frames(&mut frontend, &mut backend, true);
+            let _ = frontend.shutdown(Shutdown::Both);
+            let _ = backend.shutdown(Shutdown::Both);
+            result
+        });
+        let backend_thread = thread::spawn(move || -> anyhow::Result<u64> {
+            let result = forward_frames(&mut self.backend, &mut self.frontend, false);
+            let _ = self.backend.shutdown(Shutdown::Both);
+            let _ = self.frontend.shutdown(Shutdown::Both);
+            result
+        });
+
+        for (side, thread) in [("frontend", frontend_thread), ("backend", backend_thread)] {
+            match thread.join() {
+                Ok(Err(e)) => debug!("{side} connection closed: {e}"),
+                Ok(Ok(messages)) => debug!("{side} connection closed after {messages} messages"),
+                Err(_) => error!("{side} forward thread panicked"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::handler::pool::{Connector, PoolConfig};
+    use crate::handler::probe::{self, ConnInfo};
+    use crate::handler::rewrite::RewriteRules;
+    use crate::handler::translate::ErrorTranslations;
+    use crate::scenario::Scenario;
+    use crate::test_server::TestServer;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    // A server answering the SELECTs on t with `n`
+    fn server(n: &str) -> anyhow::Result<TestServer> {
+        TestServer::start(
+            "127.0.0.1:0",
+            &Scenario::from_toml(&format!(
+                r#"
+                [[rules]]
+                query = "BEGIN.*"
+                match = "regex"
+                command_tag = "BEGIN"
+
+                [[rules]]
+                query = "COMMIT"
+                command_tag = "COMMIT"
+
+                [[rules]]
+                query = "INSERT INTO t VALUES (3)"
+                command_tag = "INSERT 0 1"
+
+                [[rules]]
+                query = "SELECT n FROM t.*"
+                match = "regex"
+                command_tag = "SELECT 1"
+                columns = [{{ name = "n", type_oid = 23 }}]
+                rows = [["{n}"]]
+                "#
+            ))?,
+        )
+    }
+
+    fn conninfo(server: &TestServer) -> anyhow::Result<ConnInfo> {
+        format!(
+            "host=127.0.0.1 port={} user=app password=secret",
+            server.address().port()
+        )
+        .parse()
+    }
+
+    /// Connect a frontend through a proxy to the primary, the replicas
+    /// are the connections of the pool
+    fn connect_rewritten(
+        primary: &TestServer,
+        replicas: Pool,
+        rules: &str,
+    ) -> anyhow::Result<(client::TcpHandler, thread::JoinHandle<anyhow::Result<()>>)> {
+        let rewriter = RewriteRules::from_toml(rules)?.rewriter()?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let primary_address = primary.address();
+        let mut frontend_conninfo = conninfo(primary)?;
+        frontend_conninfo.port = listener.local_addr()?.port();
+        let proxy = thread::spawn(move || -> anyhow::Result<()> {
+            let (frontend, _) = listener.accept()?;
+            TcpHandler::new(frontend, TcpStream::connect(primary_address)?)?
+                .relay_rewritten(&rewriter, Some(&replicas))
+        });
+        let frontend = probe::connect(&frontend_conninfo, "frontend", Duration::from_secs(5))?;
+        Ok((frontend, proxy))
+    }
+
+    fn replica_pool(replica: &TestServer) -> anyhow::Result<Pool> {
+        let conninfo = conninfo(replica)?;
+        let connect: Connector =
+            Box::new(move || probe::connect(&conninfo, "proxy", Duration::from_secs(5)));
+        Ok(Pool::new(PoolConfig::default(), connect))
+    }
+
+    // The value of the first row
+    fn value(rows: Vec<DataRow>) -> Vec<u8> {
+        rows[0].columns.as_ref()[0]
+            .as_bytes()
+            .unwrap_or_default()
+            .to_vec()
+    }
+
+    #[test]
+    fn rewritten_queries() -> anyhow::Result<()> {
+        let (primary, replica) = (server("1")?, server("2")?);
+        let (mut frontend, proxy) = connect_rewritten(
+            &primary,
+            replica_pool(&replica)?,
+            r#"
+            [[rules]]
+            pattern = "^SELECT n FROM t$"
+            replace = "$0 LIMIT 10"
+
+            [[rules]]
+            statements = ["select"]
+            route = "replica"
+
+            [[rules]]
+            statements = ["drop"]
+            error = { code = "42501", message = "DDL is not allowed through this proxy" }
+            "#,
+        )?;
+
+        // Rewritten and sent to the replica
+        let rows = frontend.simple_query_handler("SELECT n FROM t")?;
+        assert_eq!(b"2".to_vec(), value(rows));
+
+        // Refused, the ReadyForQuery follows the error
+        frontend
+            .writer
+            .put_message_and_flush(Query::new(String::from("DROP TABLE t"))?)?;
+        let mut answer = RawBackendMessage::get(&mut frontend.reader)?;
+        let error = ErrorResponse::try_from(&mut answer)?;
+        assert_eq!(
+            Some(&b"42501"[..]),
+            error
+                .messages
+                .as_ref()
+                .iter()
+                .find(|field| field.code == b'C')
+                .map(|field| field.message.as_bytes())
+        );
+        assert_eq!(
+            b'Z',
+            RawBackendMessage::get(&mut frontend.reader)?
+                .header
+                .message_type
+        );
+
+        // The extended queries too
+        frontend
+            .writer
+            .put_message(Parse::new("", "SELECT n FROM t", vec![])?)?;
+        frontend
+            .writer
+            .put_message(Bind::new("", "", vec![], vec![], vec![])?)?;
+        frontend.writer.put_message(Execute::new("", 0)?)?;
+        frontend.writer.put_message_and_flush(Sync::new())?;
+        let mut types = String::new();
+        let mut rows = Vec::new();
+        while !types.ends_with('Z') {
+            let mut answer = RawBackendMessage::get(&mut frontend.reader)?;
+            types.push(answer.header.message_type as char);
+            if answer.header.message_type == b'D' {
+                rows.push(DataRow::try_from(&mut answer)?);
+            }
+        }
+        assert_eq!("12DCZ", types);
+        assert_eq!(b"2".to_vec(), value(rows));
+
+        // The primary in a transaction
+        frontend.simple_query_handler("BEGIN")?;
+        let rows = frontend.simple_query_handler("SELECT n FROM t")?;
+        assert_eq!(b"1".to_vec(), value(rows));
+
+        frontend.writer.put_message_and_flush(Terminate::new())?;
+        proxy.join().expect("proxy thread")?;
+        primary.stop()?;
+        replica.stop()
+    }
+
+    #[test]
+    fn read_write_split() -> anyhow::Result<()> {
+        let (primary, replica) = (server("1")?, server("2")?);
+        let replicas = replica_pool(&replica)?;
+        let (mut frontend, proxy) = connect_rewritten(
+            &primary,
+            replicas.clone(),
+            r#"routing = "read_write_split""#,
+        )?;
+
+        // The replica lags behind
+        frontend.simple_query_handler("INSERT INTO t VALUES (3)")?;
+        let rows = frontend.simple_query_handler("SELECT n FROM t")?;
+        assert_eq!(b"2".to_vec(), value(rows));
+        assert_eq!((1, 1), replicas.status());
+
+        // The transactions stay on their server
+        frontend.simple_query_handler("BEGIN")?;
+        let rows = frontend.simple_query_handler("SELECT n FROM t")?;
+        assert_eq!(b"1".to_vec(), value(rows));
+        frontend.simple_query_handler("COMMIT")?;
+        frontend.simple_query_handler("BEGIN READ ONLY")?;
+        assert_eq!((0, 1), replicas.status());
+        let rows = frontend.simple_query_handler("SELECT n FROM t")?;
+        assert_eq!(b"2".to_vec(), value(rows));
+        frontend.simple_query_handler("COMMIT")?;
+        assert_eq!((1, 1), replicas.status());
+
+        frontend.writer.put_message_and_flush(Terminate::new())?;
+        proxy.join().expect("proxy thread")?;
+        primary.stop()?;
+        replica.stop()
+    }
+
+    #[test]
+    fn injected_credentials() -> anyhow::Result<()> {
+        let config = ServerConfig::from_toml(
+            r#"
+            [[users]]
+            name = "prod"
+            password = "prod-secret"
+
+            [[auth]]
+            method = "md5"
+            "#,
+        )?;
+        let server = TestServer::from_config(TcpListener::bind("127.0.0.1:0")?, &config)?;
+        let credentials = Credentials::from_toml(
+            r#"
+            [[users]]
+            name = "app"
+            password = "test"
+            server_user = "prod"
+            server_password = "prod-secret"
+
+            [[users]]
+            name = "stale"
+            password = "test"
+            server_user = "prod"
+            server_password = "expired"
+            "#,
+        )?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let (port, server_address) = (listener.local_addr()?.port(), server.address());
+        let proxy = thread::spawn(move || -> anyhow::Result<Vec<bool>> {
+            listener
+                .incoming()
+                .take(4)
+                .map(|stream| {
+                    let handler = TcpHandler::new(stream?, TcpStream::connect(server_address)?)?;
+                    Ok(handler.relay_with_credentials(&credentials).is_ok())
+                })
+                .collect()
+        });
+        let connect = |user: &str, password: &str| -> anyhow::Result<client::TcpHandler> {
+            let conninfo: ConnInfo =
+                format!("host=127.0.0.1 port={port} user={user} password={password}").parse()?;
+            probe::connect(&conninfo, "frontend", Duration::from_secs(5))
+        };
+
+        let mut frontend = connect("app", "test")?;
+        frontend.simple_query_handler("SELECT 1")?;
+        frontend.writer.put_message_and_flush(Terminate::new())?;
+
+        for (user, password, error) in [
+            (
+                "app",
+                "prod-secret",
+                "password authentication failed for user \"app\"",
+            ),
+            ("admin", "test", "no entry for user \"admin\""),
+            // Refused by the server
+            (
+                "stale",
+                "test",
+                "password authentication failed for user \"prod\"",
+            ),
+        ] {
+            let refused = connect(user, password).err().map(|e| e.to_string());
+            assert!(refused.is_some_and(|e| e.contains(error)), "{user}");
+        }
+        assert_eq!(
+            vec![true, false, false, false],
+            proxy.join().expect("proxy thread")?
+        );
+
+        server.stop()
+    }
+
+    #[test]
+    fn translated_errors() -> anyhow::Result<()> {
+        let server = TestServer::start(
+            "127.0.0.1:0",
+            &Scenario::from_toml(
+                r#"
+                [[rules]]
+                query = "LOCK TABLE orders"
+                error = { code = "HY000", message = "Lock wait timeout exceeded" }
+                "#,
+            )?,
+        )?;
+        let translator = ErrorTranslations::from_toml(
+            r#"
+            [[errors]]
+            code = "HY000"
+            pattern = "^Lock wait timeout exceeded$"
+            new_code = "55P03"
+            new_message = "canceling statement due to lock timeout"
+            "#,
+        )?
+        .translator()?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut frontend_conninfo = conninfo(&server)?;
+        frontend_conninfo.port = listener.local_addr()?.port();
+        let server_address = server.address();
+        let proxy = thread::spawn(move || -> anyhow::Result<()> {
+            let (frontend, _) = listener.accept()?;
+            TcpHandler::new(frontend, TcpStream::connect(server_address)?)?
+                .relay_translated(&translator)
+        });
+
+        let mut frontend = probe::connect(&frontend_conninfo, "frontend", Duration::from_secs(5))?;
+        // Read as is, the client only reports that the query failed
+        frontend
+            .writer
+            .put_message_and_flush(Query::new(String::from("LOCK TABLE orders"))?)?;
+        let mut error = RawBackendMessage::get(&mut frontend.reader)?;
+        let fields: Vec<ErrorMessage> = ErrorResponse::try_from(&mut error)?.messages.into();
+        let fields: Vec<(u8, String)> = fields
+            .iter()
+            .map(|field| (field.code, field.message.to_string_lossy().into_owned()))
+            .collect();
+        assert!(fields.contains(&(b'C', String::from("55P03"))));
+        assert!(fields.contains(&(
+            b'M',
+            String::from("canceling statement due to lock timeout")
+        )));
+        assert_eq!(
+            b'Z',
+            RawBackendMessage::get(&mut frontend.reader)?
+                .header
+                .message_type
+        );
+        frontend.simple_query_handler("SELECT 1")?;
+        frontend.writer.put_message_and_flush(Terminate::new())?;
+        proxy.join().expect("proxy thread")?;
+
+        server.stop()
+    }
+
+    #[test]
+    fn forwarded_frames() -> anyhow::Result<()> {
+        let mut frontend = BytesMut::new();
+        MessageHeader::serialize_message(&mut frontend, &Query::new(String::from("SELECT 1"))?)?;
+        MessageHeader::serialize_message(&mut frontend, &Sync::new())?;
+        MessageHeader::serialize_message(&mut frontend, &Terminate::new())?;
+        let length = frontend.len();
+        // Not sent after the Terminate
+        frontend.extend_from_slice(b"Q");
+
+        let mut forwarded = Vec::new();
+        assert_eq!(3, forward_frames(&mut &frontend[..], &mut forwarded, true)?);
+        assert_eq!(&frontend[..length], &forwarded[..]);
+
+        // The backend is forwarded until it closes the connection
+        let mut forwarded = Vec::new();
+        assert!(forward_frames(&mut &frontend[..length], &mut forwarded, false).is_err());
+        assert_eq!(&frontend[..length], &forwarded[..]);
+        assert!(forward_frames(&mut &[b'Z', 0, 0, 0, 2][..], &mut Vec::new(), false).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+//! The rules applied by the proxy to the queries of the Query and Parse
+//! messages before forwarding them, see proxy::TcpHandler::relay_rewritten.
+
+use anyhow::anyhow;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::error::PgError;
+use crate::matcher::{normalize, split_statements};
+
+/// The server a query is sent to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+    #[default]
+    Primary,
+    // Only outside of a transaction of the primary, to see its writes
+    Replica,
+}
+
+/// Where the queries go when no rule routes them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingPolicy {
+    // To the primary
+    #[default]
+    Rules,
+    // The reads to the replicas, see is_read_only()
+    ReadWriteSplit,
+}
+
+/// Matches the queries with a regex, with the first keyword of the
+/// statement or with both, and rewrites, routes or refuses them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    // Searched in the query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    // The statement types, e.g. "select" or "drop", in lowercase. A query
+    // of several statements is refused when one of them is of these types,
+    // it is rewritten or routed when all of them are.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub statements: Vec<String>,
+    // Replaces the matches of the pattern, $1 is its first group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<Route>,
+    // Sent to the frontend instead of forwarding the query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<PgError>,
+}
+
+/// The rules of the proxy, in a TOML file e.g.:
+///
+/// ```toml
+/// [[rules]]
+/// pattern = "(?i)^select \\* from events$"
+/// replace = "$0 LIMIT 1000"
+///
+/// [[rules]]
+/// statements = ["select"]
+/// route = "replica"
+///
+/// [[rules]]
+/// statements = ["create", "alter", "drop", "truncate"]
+/// error = { code = "42501", message = "DDL is not allowed through this proxy" }
+/// ```
+///
+/// The rules are evaluated in order, each one on the query rewritten by the
+/// previous ones. The first error refuses the query, the first route
+/// decides the server. With `routing = "read_write_split"` the reads that
+/// no rule routes go to the replicas.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRules {
+    #[serde(default)]
+    pub routing: RoutingPolicy,
+    #[serde(default)]
+    pub rules: Vec<RewriteRule>,
+}
+
+impl RewriteRules {
+    pub fn from_toml(content: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn rewriter(&self) -> anyhow::Result<Rewriter> {
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| {
+                if rule.replace.is_some() && rule.pattern.is_none() {
+                    return Err(anyhow!("A rule replacing text needs a pattern"));
+                }
+                if rule.replace.is_none() && rule.route.is_none() && rule.error.is_none() {
+                    return Err(anyhow!("A rule must replace, route or refuse the queries"));
+                }
+                Ok(CompiledRule {
+                    pattern: rule.pattern.as_deref().map(Regex::new).transpose()?,
+                    rule: rule.clone(),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Rewriter {
+            routing: self.routing,
+            rules,
+        })
+    }
+}
+
+struct CompiledRule {
+    pattern: Option<Regex>,
+    rule: RewriteRule,
+}
+
+/// The rules ready to be applied
+pub struct Rewriter {
+    routing: RoutingPolicy,
+    rules: Vec<CompiledRule>,
+}
+
+impl Rewriter {
+    /// The query to forward and its server, or the error answering it
+    pub fn rewrite(&self, query: &str) -> Result<(String, Route), PgError> {
+        let mut query = String::from(query);
+        let mut route = None;
+        for CompiledRule { pattern, rule } in &self.rules {
+            if !rule.statements.is_empty() {
+                let types = statement_types(&query);
+                let listed = |statement_type: &String| rule.statements.contains(statement_type);
+                let matched = match rule.error {
+                    Some(_) => types.iter().any(listed),
+                    None => !types.is_empty() && types.iter().all(listed),
+                };
+                if !matched {
+                    continue;
+                }
+            }
+            if let Some(pattern) = pattern
+                && !pattern.is_match(&query)
+            {
+                continue;
+            }
+            if let Some(error) = &rule.error {
+                return Err(error.clone());
+            }
+            if let (Some(pattern), Some(replace)) = (pattern, &rule.replace) {
+                query = pattern.replace_all(&query, replace).into_owned();
+            }
+            route = route.or(rule.route);
+        }
+        let route = route.unwrap_or(match self.routing {
+            RoutingPolicy::ReadWriteSplit if is_read_only(&query) => Route::Replica,
+            _ => Route::Primary,
+        });
+        Ok((query, route))
+    }
+}
+
+/// Whether a query only reads, e.g. a SELECT or a BEGIN READ ONLY, to send
+/// it to a replica. The doubtful ones are writes: the locking clauses, the
+/// sequences, the DML in a CTE, ... Every statement of the query must only
+/// read.
+pub fn is_read_only(query: &str) -> bool {
+    let statements = split_statements(query);
+    !statements.is_empty() && statements.into_iter().all(is_read_only_statement)
+}
+
+fn is_read_only_statement(query: &str) -> bool {
+    let Ok(normalized) = normalize(query) else {
+        return false;
+    };
+    let words: Vec<&str> = normalized.split(' ').collect();
+    match words[..] {
+        ["begin" | "start", ..] => words.windows(2).any(|w| w == ["read", "only"]),
+        ["select" | "values" | "table" | "show" | "with" | "(", ..] => !words.iter().any(|w| {
+            matches!(
+                *w,
+                "insert" | "update" | "delete" | "merge" | "into" | "share" | "nextval" | "setval"
+            )
+        }),
+        _ => false,
+    }
+}
+
+/// The first keyword of each statement of a query in lowercase, e.g.
+/// "with" for a CTE, after the comments and the parentheses
+fn statement_types(query: &str) -> Vec<String> {
+    split_statements(query)
+        .into_iter()
+        .map(|statement| {
+            let normalized = normalize(statement).unwrap_or_else(|_| String::from(statement));
+            normalized
+                .split(|c: char| !c.is_alphanumeric() && c != '_')
+                .find(|word| !word.is_empty())
+                .unwrap_or_default()
+                .to_lowercase()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rewrite_rules() -> anyhow::Result<()> {
+        let rules = RewriteRules::from_toml(
+            r#"
+            [[rules]]
+            pattern = "(?i)^select \\* from events$"
+            replace = "$0 LIMIT 1000"
+
+            [[rules]]
+            statements = ["select"]
+            route = "replica"
+
+            [[rules]]
+            statements = ["create", "alter", "drop"]
+            error = { code = "42501", message = "DDL is not allowed through this proxy" }
+            "#,
+        )?;
+        let rewriter = rules.rewriter()?;
+
+        assert_eq!(
+            Ok((
+                String::from("SELECT * FROM events LIMIT 1000"),
+                Route::Replica
+            )),
+            rewriter.rewrite("SELECT * FROM events")
+        );
+        assert_eq!(
+            Ok((String::from("(select 1)"), Route::Replica)),
+            rewriter.rewrite("(select 1)")
+        );
+        assert_eq!(
+            Ok((String::from("UPDATE t SET n = 1"), Route::Primary)),
+            rewriter.rewrite("UPDATE t SET n = 1")
+        );
+        assert_eq!(
+            Err(PgError::new(
+                "42501",
+                "DDL is not allowed through this proxy"
+            )),
+            rewriter.rewrite("DROP TABLE events")
+        );
+        // Every statement is checked, after the comments
+        for query in ["/* x */ DROP TABLE t", "SELECT 1; DROP TABLE t"] {
+            assert_eq!(
+                Err(PgError::new(
+                    "42501",
+                    "DDL is not allowed through this proxy"
+                )),
+                rewriter.rewrite(query)
+            );
+        }
+        assert_eq!(
+            Ok((String::from("SELECT 1; SELECT 2"), Route::Replica)),
+            rewriter.rewrite("SELECT 1; SELECT 2")
+        );
+
+        let rewriter = RewriteRules::from_toml(r#"routing = "read_write_split""#)?.rewriter()?;
+        for (query, route) in [
+            ("SELECT n FROM t", Route::Replica),
+            ("WITH c AS (SELECT 1) SELECT * FROM c", Route::Replica),
+            ("BEGIN READ ONLY", Route::Replica),
+            ("BEGIN", Route::Primary),
+            ("SELECT n FROM t FOR UPDATE", Route::Primary),
+            ("SELECT nextval('s')", Route::Primary),
+            (
+                "WITH d AS (DELETE FROM t RETURNING n) SELECT * FROM d",
+                Route::Primary,
+            ),
+            ("INSERT INTO t VALUES (1)", Route::Primary),
+            ("SELECT 1; DROP TABLE t", Route::Primary),
+            ("", Route::Primary),
+        ] {
+            assert_eq!(Ok((String::from(query), route)), rewriter.rewrite(query));
+        }
+
+        let invalid = RewriteRules {
+            rules: vec![RewriteRule {
+                replace: Some(String::from("x")),
+                ..RewriteRule::default()
+            }],
+            ..RewriteRules::default()
+        };
+        assert!(invalid.rewriter().is_err());
+
+        Ok(())
+    }
+}
@@ -1,109 +1,3869 @@
 use anyhow::anyhow;
+use bytes::{Buf, BytesMut};
+use libpq_serde_types::{ByteSized, Serialize};
+use regex::Regex;
 use std::{
-    io::{BufReader, BufWriter},
+    collections::{HashMap, HashSet},
+    io::{BufReader, BufWriter, Read, Write},
     net::TcpStream,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use tracing::*;
 
-use crate::handler::{LibPqReader, LibPqWriter};
+use crate::columns;
+use crate::compression::{self, COMPRESSION_OPTION, CompressionSwitch, Compressor};
+use crate::datetime::timestamp_from_system_time;
+use crate::error::PgError;
+use crate::gss::{GssAuthenticator, GssStep};
+use crate::handler::copy::{
+    CopyDirection, CopyProgress, CopyProgressCallback, copy_direction, text_row,
+};
+use crate::handler::cursor::{Cursor, Declare, Direction};
+use crate::handler::desync::{DesyncPolicy, MessageHistory, ProtocolDesync, get_frontend_header};
+use crate::handler::large_object::{self, LargeObjectSession};
+use crate::handler::{FlushPolicy, LibPqWriter, MessageStats};
+use crate::matcher::{normalize, split_statements};
 use crate::message::*;
+use crate::protocol::{self, Phase};
+use crate::sessions::select_bound_view;
+use crate::simulation::Clock;
+use crate::stats::{QueryStats, session_context};
+use crate::validator::Validator;
+use crate::value::{OutputSettings, PgValue};
+
+/// The notices raised by an executor while it runs a query, e.g. the
+/// RAISE NOTICE of a function reporting its progress. The executor is given
+/// a clone of Handler::notices, the handler sends the notices queued once
+/// the executor returns, before the result or the error of the query.
+#[derive(Debug, Clone, Default)]
+pub struct Notices(Arc<Mutex<Vec<PgError>>>);
+
+impl Notices {
+    /// A notice with the SQLSTATE 00000, like RAISE NOTICE
+    pub fn notice(&self, message: &str) {
+        self.raise(PgError::new("00000", message));
+    }
 
-pub struct TcpHandler {
-    pub tcp_reader: BufReader<TcpStream>,
-    pub tcp_writer: BufWriter<TcpStream>,
+    pub fn raise(&self, notice: PgError) {
+        self.0.lock().expect("notices lock").push(notice);
+    }
+
+    /// The notices queued, in the order they were raised
+    pub fn take(&self) -> Vec<PgError> {
+        std::mem::take(&mut *self.0.lock().expect("notices lock"))
+    }
 }
 
-impl TcpHandler {
-    pub fn new(stream: TcpStream) -> anyhow::Result<Self> {
+/// The result of a query as produced by the executor
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<ColumnDescription>,
+    pub rows: Vec<Vec<PgValue>>,
+    pub command_tag: String,
+    // Sent instead of the result, e.g. 40P01 for a deadlock. A COPY TO
+    // STDOUT sends its rows before, as copied up to the error.
+    pub error: Option<PgError>,
+}
+
+impl QueryResult {
+    /// The result of INSERT, UPDATE, DELETE or MERGE ... RETURNING: one row
+    /// per affected row, e.g. `INSERT 0 3` with 3 rows
+    pub fn returning(
+        command: &str,
+        columns: Vec<ColumnDescription>,
+        rows: Vec<Vec<PgValue>>,
+    ) -> anyhow::Result<Self> {
+        let command = command.to_uppercase();
+        let command_tag = match &command[..] {
+            // The OID of the inserted row is always 0 since PostgreSQL 12
+            "INSERT" => format!("INSERT 0 {}", rows.len()),
+            "UPDATE" | "DELETE" | "MERGE" => format!("{command} {}", rows.len()),
+            _ => return Err(anyhow!("No RETURNING clause for {command}")),
+        };
         Ok(Self {
-            tcp_reader: BufReader::new(stream.try_clone().expect("Failed to clone TcpStream")),
-            tcp_writer: BufWriter::new(stream),
+            columns,
+            rows,
+            command_tag,
+            error: None,
         })
     }
+}
 
-    //FIXME: Go Back to a HashMap
-    pub fn md5_authentication_handler(
-        &mut self,
-        auth_function: &dyn Fn() -> bool,
-    ) -> anyhow::Result<Vec<ParameterStatus>> {
-        // StartupMessage: (ssl_mode) prefer => Text Auth
-        let sm = StartupMessage::try_from(&mut RawRequest::get(&mut self.tcp_reader)?)?;
-        debug!("rcv: {sm:?}");
+/// The result of a SELECT, the columns are named like the expressions of
+/// PostgreSQL (?column?) and typed after the values of the first row, text
+/// when there is no row.
+impl TryFrom<Vec<Vec<PgValue>>> for QueryResult {
+    type Error = anyhow::Error;
 
-        // Ask for the Password
-        //FIXME: random salt
-        self.tcp_writer
-            .put_message_and_flush(AuthenticationMD5Password::new([1, 2, 3, 4]))?;
+    fn try_from(rows: Vec<Vec<PgValue>>) -> anyhow::Result<Self> {
+        let columns = match rows.first() {
+            Some(row) => row
+                .iter()
+                .map(|value| ColumnDescription::new("?column?", value.pg_type()))
+                .collect::<anyhow::Result<Vec<ColumnDescription>>>()?,
+            None => vec![ColumnDescription::new("?column?", PgType::Text)?],
+        };
+        if let Some(row) = rows.iter().find(|row| row.len() != columns.len()) {
+            return Err(anyhow!(
+                "{} values in a row of {} columns",
+                row.len(),
+                columns.len()
+            ));
+        }
+        Ok(Self {
+            columns,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        })
+    }
+}
 
-        // PasswordMessage
-        let mut raw_message = self.tcp_reader.get_raw_frontend_message()?;
-        let _password_message = match PasswordMessage::try_from(&mut raw_message) {
-            Ok(message) => {
-                debug!("rcv: {message:?}");
-                message
+/// The commands counting the affected rows in their tag rather than the
+/// rows sent
+fn data_modifying(command_tag: &str) -> bool {
+    matches!(
+        command_tag.split(' ').next(),
+        Some("INSERT" | "UPDATE" | "DELETE" | "MERGE")
+    )
+}
+
+/// A statement created by a Parse message
+#[derive(Debug, PartialEq)]
+pub struct PreparedStatement {
+    pub query: String,
+    pub parameter_types: Vec<i32>,
+    pub prepare_time: SystemTime,
+    // The Bind messages using it, shown as the custom_plans
+    pub binds: u64,
+}
+
+impl PreparedStatement {
+    /// A statement prepared now, at the time of `clock`
+    pub fn new(query: String, parameter_types: Vec<i32>, clock: &Clock) -> Self {
+        Self {
+            query,
+            parameter_types,
+            prepare_time: clock.system_time(),
+            binds: 0,
+        }
+    }
+}
+
+/// A portal created by a Bind message, it remembers the result formats
+/// requested by the frontend so that the rows are encoded accordingly.
+#[derive(Debug, PartialEq)]
+pub struct Portal {
+    pub query: String,
+    // The parameters in text, NULL as None. Empty when one of them is in
+    // binary with a type that can't be read.
+    pub parameters: Vec<Option<String>>,
+    pub result_formats: Vec<FormatCode>,
+    // Set when the portal is described or executed for the first time, the
+    // next executions fetch the following rows of the same result
+    pub result: Option<QueryResult>,
+    // The number of rows already sent
+    pub position: usize,
+}
+
+impl Portal {
+    /// The columns of the result with the format requested in Bind
+    pub fn describe(&self, columns: &[ColumnDescription]) -> anyhow::Result<RowDescription> {
+        let mut described = Vec::with_capacity(columns.len());
+        for (index, column) in columns.iter().enumerate() {
+            let mut column = column.clone();
+            column.format = i16::from(&FormatCode::for_column(&self.result_formats, index)?);
+            described.push(column);
+        }
+        Ok(RowDescription::new(described))
+    }
+
+    pub fn data_row(
+        &self,
+        values: &[PgValue],
+        settings: &OutputSettings,
+    ) -> anyhow::Result<DataRow> {
+        DataRow::new_from_values(values, &self.result_formats, settings)
+    }
+
+    /// Fetch at most `max_rows` rows (all of them when 0) from the result.
+    /// The command tag is returned once the result is exhausted, `None`
+    /// means that the portal is suspended.
+    ///
+    /// Like PostgreSQL, the row count of the command tag is the number of
+    /// rows sent by this execution, except for the rows returned by DML
+    /// (e.g. INSERT ... RETURNING) whose tag counts the affected rows.
+    pub fn execute(
+        &mut self,
+        max_rows: i32,
+        settings: &OutputSettings,
+    ) -> anyhow::Result<(Vec<DataRow>, Option<String>)> {
+        let Some(result) = &self.result else {
+            return Err(anyhow!("Portal executed without a result"));
+        };
+        if let Some(error) = &result.error {
+            return Err(error.clone().into());
+        }
+        let start = self.position.min(result.rows.len());
+        let end = match usize::try_from(max_rows) {
+            Ok(max_rows) if max_rows > 0 => (start + max_rows).min(result.rows.len()),
+            _ => result.rows.len(),
+        };
+
+        let data_rows = result.rows[start..end]
+            .iter()
+            .map(|row| self.data_row(row, settings))
+            .collect::<anyhow::Result<Vec<DataRow>>>()?;
+        let command_tag = if end < result.rows.len() {
+            None
+        } else if end - start == result.rows.len() || data_modifying(&result.command_tag) {
+            Some(result.command_tag.clone())
+        } else {
+            // Replace the row count of the tag, e.g. SELECT 5 => SELECT 2
+            let mut words: Vec<String> = result.command_tag.split(' ').map(String::from).collect();
+            if let Some(count) = words.last_mut()
+                && count.parse::<u64>().is_ok()
+            {
+                *count = (end - start).to_string();
             }
-            _ => return Err(anyhow!("Password message expected")),
+            Some(words.join(" "))
         };
+        self.position = end;
 
-        if auth_function() {
-            // Validate the authentication
-            self.tcp_writer.put_message(AuthenticationOk::new())?;
-
-            // Validate the authentication
-            //FIXME: There should me much mode parameters to send back to the client..
-            self.tcp_writer.put_message(ParameterStatus::new(
-                &String::from("server_version"),
-                &String::from("0.1 (fakepostmaster)"),
-            )?)?;
+        Ok((data_rows, command_tag))
+    }
+}
 
-            // Tell the client he can continue
-            self.tcp_writer
-                .put_message_and_flush(ReadyForQuery::new(TransactionIndicator::Idle))?;
+/// The server_version reported to the frontends by default
+pub const SERVER_VERSION: &str = "0.1 (fakepostmaster)";
 
-            Ok(sm.parameters.into())
-        } else {
-            // Error out
-            self.tcp_writer
-                .put_message_and_flush(ErrorResponse::new(vec![ErrorMessage::new(
-                    'M',
-                    &String::from("Incorrect password or user"),
-                )?]))?;
+/// The version that a server claims to be, for the clients enabling
+/// features by version, e.g. in a scenario:
+///
+/// ```toml
+/// [version]
+/// server_version = "16.2"
+/// # Derived from server_version when omitted
+/// server_version_num = 160002
+/// # The result of version(), "PostgreSQL <server_version>" when omitted
+/// banner = "PostgreSQL 16.2 on x86_64-pc-linux-gnu, compiled by gcc 12.2.0, 64-bit"
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerVersion {
+    pub server_version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_version_num: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banner: Option<String>,
+}
 
-            Err(anyhow!("Auth failed"))
+impl Default for ServerVersion {
+    fn default() -> Self {
+        Self {
+            server_version: String::from(SERVER_VERSION),
+            server_version_num: None,
+            banner: None,
         }
     }
+}
 
-    pub fn simple_query_handler(
+impl ServerVersion {
+    /// The server_version_num setting, e.g. 160002 for 16.2 and 90624 for
+    /// 9.6.24
+    pub fn num(&self) -> i32 {
+        if let Some(num) = self.server_version_num {
+            return num;
+        }
+        let mut parts = self.server_version.split('.').map(|part| {
+            part.bytes()
+                .take_while(u8::is_ascii_digit)
+                .fold(0, |n, digit| n * 10 + i32::from(digit - b'0'))
+        });
+        let (major, minor) = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+        match major {
+            // The versions before 10 have 3 parts, e.g. 9.6.24
+            0..10 => major * 10000 + minor * 100 + parts.next().unwrap_or(0),
+            _ => major * 10000 + minor,
+        }
+    }
+
+    /// The result of version()
+    pub fn banner(&self) -> String {
+        match &self.banner {
+            Some(banner) => banner.clone(),
+            None => format!("PostgreSQL {}", self.server_version),
+        }
+    }
+}
+
+/// The parameters sent by the frontend in the startup message, kept so
+/// that tests can check what an application asks for
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StartupParameters {
+    pub parameters: Vec<(String, String)>,
+}
+
+impl StartupParameters {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.get("user")
+    }
+
+    /// The database defaults to the user name
+    pub fn database(&self) -> Option<&str> {
+        self.get("database").or(self.user())
+    }
+
+    pub fn application_name(&self) -> Option<&str> {
+        self.get("application_name")
+    }
+
+    /// The settings given in the options parameter (e.g. `-c search_path=app`)
+    pub fn options(&self) -> Vec<(&str, &str)> {
+        self.get("options")
+            .map(command_line_options)
+            .unwrap_or_default()
+    }
+
+    /// Check the value of a parameter given directly or in the options, the
+    /// last one wins like in PostgreSQL
+    pub fn assert_parameter(&self, name: &str, expected: &str) -> anyhow::Result<()> {
+        let value = self
+            .options()
+            .into_iter()
+            .rev()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+            .or(self.get(name));
+        match value {
+            Some(value) if value == expected => Ok(()),
+            _ => Err(anyhow!(
+                "Startup parameter {name}: expected {expected:?}, got {value:?}"
+            )),
+        }
+    }
+}
+
+/// The identifiers of the transactions prepared for two-phase commit, shared
+/// by the sessions of a server: cloning gives another handle on the same
+/// registry.
+#[derive(Debug, Clone, Default)]
+pub struct PreparedTransactions(Arc<Mutex<HashSet<String>>>);
+
+impl PreparedTransactions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sorted, for pg_prepared_xacts like listings
+    pub fn gids(&self) -> Vec<String> {
+        let mut gids: Vec<String> = self.0.lock().expect("gids lock").iter().cloned().collect();
+        gids.sort();
+        gids
+    }
+
+    fn insert(&self, gid: &str) -> bool {
+        self.0.lock().expect("gids lock").insert(String::from(gid))
+    }
+
+    fn remove(&self, gid: &str) -> bool {
+        self.0.lock().expect("gids lock").remove(gid)
+    }
+}
+
+/// The name of a cursor from its normalized token, e.g. "C" or c
+fn cursor_name(token: &str) -> String {
+    String::from(token.trim_matches('"'))
+}
+
+/// The savepoint of a ROLLBACK TO SAVEPOINT, from its normalized words
+fn rollback_to<'a>(words: &[&'a str]) -> Option<&'a str> {
+    match words {
+        ["rollback", .., name] if words.contains(&"to") => Some(name),
+        _ => None,
+    }
+}
+
+/// Whether the statement ending a transaction block starts the next one,
+/// from its normalized words
+fn chained(words: &[&str]) -> bool {
+    matches!(
+        words,
+        ["commit" | "rollback" | "end" | "abort", .., "and", "chain"]
+    )
+}
+
+/// A CopyData, a CopyDone or a CopyFail
+fn is_copy_in_message(message_type: u8) -> bool {
+    b"dcf".contains(&message_type)
+}
+
+/// The text of a string literal token, e.g. 'It''s'
+fn string_literal(token: &str) -> Option<String> {
+    let text = token.strip_prefix('\'')?.strip_suffix('\'')?;
+    Some(text.replace("''", "'"))
+}
+
+/// The state kept by the server between the messages of a connection
+#[derive(Debug, Default)]
+pub struct Session {
+    pub startup: StartupParameters,
+    pub statements: HashMap<String, PreparedStatement>,
+    pub portals: HashMap<String, Portal>,
+    pub transaction: TransactionIndicator,
+    // Set while the statements of a multi-statement Query run, they form an
+    // implicit transaction block
+    pub implicit_transaction: bool,
+    // The parameters changed with SET, by name
+    pub parameters: HashMap<String, String>,
+    // The output settings in use and the ones given in the startup message,
+    // restored by RESET
+    pub settings: OutputSettings,
+    pub startup_settings: OutputSettings,
+    // Shared with the other sessions of the server to commit the
+    // transactions they prepared
+    pub prepared_transactions: PreparedTransactions,
+    // The cursors declared in the simple protocol, by name
+    pub cursors: HashMap<String, Cursor>,
+    // The large objects of the server and the descriptors opened
+    pub large_objects: LargeObjectSession,
+    // The savepoints of the transaction block, in the order they were set
+    pub savepoints: Vec<String>,
+}
+
+impl Session {
+    /// In an explicit or an implicit transaction block, where the statements
+    /// like DISCARD ALL can't run
+    pub fn in_transaction_block(&self) -> bool {
+        self.transaction != TransactionIndicator::Idle || self.implicit_transaction
+    }
+
+    /// Follow the transaction blocks from the statements run and their
+    /// command tags, the cursors without hold are closed at the end of the
+    /// transaction. A ROLLBACK TO SAVEPOINT goes back into the block, an
+    /// AND CHAIN starts the next one.
+    pub fn update_transaction(&mut self, query: &str, command_tag: &str) {
+        let normalized = normalize(query).unwrap_or_default();
+        let words: Vec<&str> = normalized.split(' ').collect();
+        match command_tag {
+            "BEGIN" => {
+                self.transaction = TransactionIndicator::IdleInTransaction;
+                self.savepoints.clear();
+            }
+            "SAVEPOINT" if self.in_transaction_block() => {
+                if let ["savepoint", name] = words[..] {
+                    self.savepoints.push(cursor_name(name));
+                }
+            }
+            "RELEASE" => {
+                if let Some(position) = words.last().and_then(|name| self.savepoint(name)) {
+                    self.savepoints.truncate(position);
+                }
+            }
+            "ROLLBACK" if rollback_to(&words).is_some() => {
+                if let Some(position) = rollback_to(&words).and_then(|name| self.savepoint(name)) {
+                    self.savepoints.truncate(position + 1);
+                    self.transaction = TransactionIndicator::IdleInTransaction;
+                }
+            }
+            "COMMIT" | "ROLLBACK" | "PREPARE TRANSACTION" => {
+                self.transaction = match chained(&words) {
+                    true => TransactionIndicator::IdleInTransaction,
+                    false => TransactionIndicator::Idle,
+                };
+                self.savepoints.clear();
+                self.cursors.retain(|_, cursor| cursor.hold);
+                self.large_objects.descriptors.clear();
+            }
+            _ => (),
+        }
+    }
+
+    /// The position of a savepoint from its normalized token, the last one
+    /// set when several have the same name
+    fn savepoint(&self, token: &str) -> Option<usize> {
+        let name = cursor_name(token);
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| *savepoint == name)
+    }
+
+    /// The named statements, by name. The parameter types are the type
+    /// names of pg_type and the plans are not cached: the Bind messages are
+    /// counted as custom plans.
+    pub fn pg_prepared_statements(&self) -> anyhow::Result<QueryResult> {
+        let mut statements: Vec<(&String, &PreparedStatement)> = self
+            .statements
+            .iter()
+            .filter(|(name, _)| !name.is_empty())
+            .collect();
+        statements.sort_by_key(|(name, _)| *name);
+        let rows: Vec<Vec<PgValue>> = statements
+            .into_iter()
+            .map(|(name, statement)| {
+                let types: Vec<String> = statement
+                    .parameter_types
+                    .iter()
+                    .map(|oid| match PgType::try_from(*oid) {
+                        Ok(pg_type) => String::from(pg_type.typname()),
+                        Err(_) => oid.to_string(),
+                    })
+                    .collect();
+                vec![
+                    PgValue::Text(name.clone()),
+                    PgValue::Text(statement.query.clone()),
+                    PgValue::Timestamp(timestamp_from_system_time(statement.prepare_time)),
+                    PgValue::Text(format!("{{{}}}", types.join(","))),
+                    PgValue::Bool(false),
+                    PgValue::Int8(0),
+                    PgValue::Int8(statement.binds as i64),
+                ]
+            })
+            .collect();
+        Ok(QueryResult {
+            columns: columns![
+                ("name", Text),
+                ("statement", Text),
+                ("prepare_time", Timestamp),
+                ("parameter_types", Text),
+                ("from_sql", Bool),
+                ("generic_plans", Int8),
+                ("custom_plans", Int8),
+            ]?,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        })
+    }
+
+    /// Declare a cursor on the result of its query
+    pub fn declare(
         &mut self,
-        executor: &dyn Fn(String) -> (Vec<ColumnDescription>, Vec<ColumnData>, String),
-    ) -> anyhow::Result<()> {
-        // Query?
-        let mut raw_message = self.tcp_reader.get_raw_frontend_message()?;
-        let query_message = match Query::try_from(&mut raw_message) {
-            Ok(message) => message,
-            _ => return Err(anyhow!("Query message expected")),
+        declare: &Declare,
+        result: QueryResult,
+    ) -> anyhow::Result<QueryResult> {
+        if !declare.hold && !self.in_transaction_block() {
+            return Err(PgError::new(
+                "25P01",
+                "DECLARE CURSOR can only be used in transaction blocks",
+            )
+            .into());
+        }
+        if self.cursors.contains_key(&declare.name) {
+            return Err(PgError::new(
+                "42P03",
+                &format!("cursor \"{}\" already exists", declare.name),
+            )
+            .into());
+        }
+        if let Some(error) = result.error {
+            return Err(error.into());
+        }
+        self.cursors
+            .insert(declare.name.clone(), Cursor::new(declare, result));
+        Ok(QueryResult {
+            command_tag: String::from("DECLARE CURSOR"),
+            ..Default::default()
+        })
+    }
+
+    /// An error aborts the current transaction block
+    pub fn fail(&mut self) {
+        if self.transaction == TransactionIndicator::IdleInTransaction {
+            self.transaction = TransactionIndicator::IdlerInTransactionAborted;
+        }
+    }
+
+    /// In an aborted transaction block, the statements ending it and the
+    /// ROLLBACK TO of a savepoint set before the error are answered with
+    /// ROLLBACK, the others fail with 25P02 (in_failed_sql_transaction)
+    /// until then. `None` when the query can run, e.g. PREPARE TRANSACTION
+    /// which rolls the transaction back.
+    pub fn in_failed_transaction(&self, query: &str) -> Option<anyhow::Result<QueryResult>> {
+        if self.transaction != TransactionIndicator::IdlerInTransactionAborted {
+            return None;
+        }
+        let normalized = normalize(query).unwrap_or_default();
+        let words: Vec<&str> = normalized.split(' ').collect();
+        if let Some(name) = rollback_to(&words) {
+            if self.savepoint(name).is_none() {
+                return Some(Err(PgError::new(
+                    "3B001",
+                    &format!("savepoint \"{}\" does not exist", cursor_name(name)),
+                )
+                .into()));
+            }
+            return Some(Ok(QueryResult {
+                command_tag: String::from("ROLLBACK"),
+                ..Default::default()
+            }));
+        }
+        match words[..] {
+            ["prepare", "transaction", _] => None,
+            // COMMIT can only roll back the transaction
+            ["rollback" | "abort" | "commit" | "end", ref options @ ..]
+                if options
+                    .iter()
+                    .all(|w| ["work", "transaction", "and", "no", "chain"].contains(w)) =>
+            {
+                Some(Ok(QueryResult {
+                    command_tag: String::from("ROLLBACK"),
+                    ..Default::default()
+                }))
+            }
+            _ => Some(Err(PgError::new(
+                "25P02",
+                "current transaction is aborted, commands ignored until end of transaction block",
+            )
+            .into())),
+        }
+    }
+
+    /// Answer the statements acting on the session state (DISCARD,
+    /// DEALLOCATE, SET, RESET, FETCH, MOVE, CLOSE and the two-phase commit
+    /// ones), `None` for the other queries.
+    pub fn preprocess(&mut self, query: &str) -> Option<anyhow::Result<QueryResult>> {
+        if let Some(result) = self.in_failed_transaction(query) {
+            return Some(result);
+        }
+        let normalized = normalize(query).ok()?;
+        let words: Vec<&str> = normalized.split(' ').collect();
+
+        let command_tag = match words[..] {
+            ["discard", target] => {
+                let target = target.to_uppercase();
+                if target == "ALL" {
+                    if self.in_transaction_block() {
+                        return Some(Err(PgError::new(
+                            "25001",
+                            "DISCARD ALL cannot run inside a transaction block",
+                        )
+                        .into()));
+                    }
+                    self.statements.clear();
+                    self.portals.clear();
+                    self.cursors.clear();
+                    self.parameters.clear();
+                    self.settings = self.startup_settings;
+                } else if !["PLANS", "SEQUENCES", "TEMP", "TEMPORARY"].contains(&&target[..]) {
+                    return None;
+                }
+                // There are no plans, sequences or temporary tables to forget
+                format!("DISCARD {}", target.replace("TEMPORARY", "TEMP"))
+            }
+            ["deallocate", "prepare", "all"] | ["deallocate", "all"] => {
+                self.statements.clear();
+                String::from("DEALLOCATE ALL")
+            }
+            ["deallocate", "prepare", name] | ["deallocate", name] => {
+                let name = name.trim_matches('"');
+                if self.statements.remove(name).is_none() {
+                    return Some(Err(PgError::new(
+                        "26000",
+                        &format!("prepared statement \"{name}\" does not exist"),
+                    )
+                    .into()));
+                }
+                String::from("DEALLOCATE")
+            }
+            ["reset", "all"] => {
+                self.parameters.clear();
+                self.settings = self.startup_settings;
+                String::from("RESET")
+            }
+            ["reset", name] => {
+                self.parameters.remove(name);
+                self.settings.reset(name, &self.startup_settings);
+                String::from("RESET")
+            }
+            ["set", ..] => {
+                let captures = SET_REGEX.captures(query)?;
+                let value = captures[2].trim();
+                let value = match value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+                    Some(value) => value.replace("''", "'"),
+                    None => String::from(value),
+                };
+                let name = captures[1].to_lowercase();
+                if let Err(e) = self.settings.set(&name, &value) {
+                    return Some(Err(e));
+                }
+                self.parameters.insert(name, value);
+                String::from("SET")
+            }
+            [command @ ("fetch" | "move"), .., name] => {
+                let name = cursor_name(name);
+                let direction = match Direction::parse(&words[1..words.len() - 1]) {
+                    Ok(direction) => direction,
+                    Err(e) => return Some(Err(e)),
+                };
+                let Some(cursor) = self.cursors.get_mut(&name) else {
+                    return Some(Err(PgError::new(
+                        "34000",
+                        &format!("cursor \"{name}\" does not exist"),
+                    )
+                    .into()));
+                };
+                let rows = match cursor.fetch(direction) {
+                    Ok(rows) => rows,
+                    Err(e) => return Some(Err(e)),
+                };
+                let command_tag = format!("{} {}", command.to_uppercase(), rows.len());
+                if command == "move" {
+                    return Some(Ok(QueryResult {
+                        command_tag,
+                        ..Default::default()
+                    }));
+                }
+                return Some(Ok(QueryResult {
+                    columns: cursor.columns.clone(),
+                    rows,
+                    command_tag,
+                    error: None,
+                }));
+            }
+            ["close", "all"] => {
+                self.cursors.clear();
+                String::from("CLOSE CURSOR ALL")
+            }
+            ["close", name] => {
+                let name = cursor_name(name);
+                if self.cursors.remove(&name).is_none() {
+                    return Some(Err(PgError::new(
+                        "34000",
+                        &format!("cursor \"{name}\" does not exist"),
+                    )
+                    .into()));
+                }
+                String::from("CLOSE CURSOR")
+            }
+            ["prepare", "transaction", gid] => {
+                let gid = string_literal(gid)?;
+                let transaction = self.transaction;
+                // The transaction ends even when the identifier is taken
+                self.transaction = TransactionIndicator::Idle;
+                match transaction {
+                    // PostgreSQL warns that there is no transaction in progress
+                    TransactionIndicator::Idle
+                    | TransactionIndicator::IdlerInTransactionAborted => String::from("ROLLBACK"),
+                    TransactionIndicator::IdleInTransaction => {
+                        if !self.prepared_transactions.insert(&gid) {
+                            return Some(Err(PgError::new(
+                                "42710",
+                                &format!("transaction identifier \"{gid}\" is already in use"),
+                            )
+                            .into()));
+                        }
+                        String::from("PREPARE TRANSACTION")
+                    }
+                }
+            }
+            [command @ ("commit" | "rollback"), "prepared", gid] => {
+                let gid = string_literal(gid)?;
+                let command_tag = format!("{} PREPARED", command.to_uppercase());
+                if self.in_transaction_block() {
+                    return Some(Err(PgError::new(
+                        "25001",
+                        &format!("{command_tag} cannot run inside a transaction block"),
+                    )
+                    .into()));
+                }
+                if !self.prepared_transactions.remove(&gid) {
+                    return Some(Err(PgError::new(
+                        "42704",
+                        &format!("prepared transaction with identifier \"{gid}\" does not exist"),
+                    )
+                    .into()));
+                }
+                command_tag
+            }
+            _ => return None,
         };
-        debug!("rcv: {query_message:?}");
 
-        // execute query
-        let (column_desc, column_data, command_tag) = executor(query_message.query.into_string()?);
+        Some(Ok(QueryResult {
+            command_tag,
+            ..Default::default()
+        }))
+    }
+}
+
+// SET [ SESSION ] name { TO | = } value, SET LOCAL is left to the executor
+static SET_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)^\s*set\s+(?:session\s+)?([a-z_][a-z0-9_.]*)\s*(?:to|=)\s*(.*?)\s*;?\s*$")
+        .expect("Invalid SET regex")
+});
 
-        // row description
-        self.tcp_writer
-            .put_message(RowDescription::new(column_desc))?;
+// The first words of the statements that never return rows
+const UTILITY_STATEMENTS: &[&str] = &[
+    "abort",
+    "alter",
+    "analyze",
+    "begin",
+    "checkpoint",
+    "close",
+    "cluster",
+    "comment",
+    "commit",
+    "copy",
+    "create",
+    "deallocate",
+    "declare",
+    "discard",
+    "do",
+    "drop",
+    "end",
+    "grant",
+    "listen",
+    "load",
+    "lock",
+    "move",
+    "notify",
+    "prepare",
+    "reassign",
+    "refresh",
+    "reindex",
+    "release",
+    "reset",
+    "revoke",
+    "rollback",
+    "savepoint",
+    "security",
+    "set",
+    "start",
+    "truncate",
+    "unlisten",
+    "vacuum",
+];
 
-        // data row
-        if column_data.len() > 0 {
-            self.tcp_writer.put_message(DataRow::new(column_data))?;
+/// The statements that never return rows whatever the executor answers:
+/// the utility statements, the DML without RETURNING and SELECT INTO.
+/// Describe answers NoData for them without running them.
+fn returns_no_rows(query: &str) -> bool {
+    let Ok(normalized) = normalize(query) else {
+        return false;
+    };
+    // The words outside of the parentheses, e.g. of the subqueries
+    let mut words = Vec::new();
+    let mut depth = 0;
+    for word in normalized.split(' ') {
+        match word {
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            ";" => (),
+            word if depth == 0 => words.push(word),
+            _ => (),
         }
+    }
+    // The statement following the common table expressions
+    if words.first() == Some(&"with") {
+        match words
+            .iter()
+            .position(|word| ["select", "insert", "update", "delete", "merge"].contains(word))
+        {
+            Some(start) => words.drain(..start),
+            None => return false,
+        };
+    }
+    match words[..] {
+        ["insert" | "update" | "delete" | "merge", ..] => !words.contains(&"returning"),
+        ["select", ..] => words.contains(&"into"),
+        [first, ..] => UTILITY_STATEMENTS.contains(&first),
+        [] => true,
+    }
+}
 
-        // Tell the client the commadn tag
-        self.tcp_writer
-            .put_message(CommandComplete::new(command_tag)?)?;
+/// The parameters of a Bind in text, the binary ones read with the types of
+/// the statement. `None` when a binary parameter has no type known.
+fn text_parameters(bind: &Bind, parameter_types: &[i32]) -> Option<Vec<Option<String>>> {
+    let formats = bind
+        .parameter_formats
+        .as_ref()
+        .iter()
+        .map(|code| FormatCode::try_from(*code))
+        .collect::<anyhow::Result<Vec<FormatCode>>>()
+        .ok()?;
+    let mut parameters = Vec::new();
+    for (index, parameter) in bind.parameters.as_ref().iter().enumerate() {
+        let Some(data) = parameter.as_bytes() else {
+            parameters.push(None);
+            continue;
+        };
+        let text = match FormatCode::for_column(&formats, index).ok()? {
+            FormatCode::Text => String::from_utf8(data.to_vec()).ok()?,
+            FormatCode::Binary => {
+                let pg_type = PgType::try_from(*parameter_types.get(index)?).ok()?;
+                String::from_utf8(PgValue::from_binary(pg_type, data).ok()?.to_text()).ok()?
+            }
+        };
+        parameters.push(Some(text));
+    }
+    Some(parameters)
+}
 
-        // Tell the client he can continue
-        self.tcp_writer
-            .put_message_and_flush(ReadyForQuery::new(TransactionIndicator::Idle))?;
+/// The features that don't survive a transaction pooler since the next
+/// transaction can run on another server connection.
+pub fn session_level_feature(query: &str) -> Option<&'static str> {
+    let normalized = normalize(query).ok()?;
+    let words: Vec<&str> = normalized.split(' ').collect();
+    match words[..] {
+        ["set", "local" | "transaction" | "constraints", ..] => None,
+        ["set", ..] => Some("SET"),
+        ["reset", ..] => Some("RESET"),
+        ["listen", ..] => Some("LISTEN"),
+        ["prepare", "transaction", ..] => None,
+        ["prepare", ..] => Some("PREPARE"),
+        ["load", ..] => Some("LOAD"),
+        ["declare", ..] if words.windows(2).any(|w| w == ["with", "hold"]) => {
+            Some("DECLARE ... WITH HOLD")
+        }
+        _ if words
+            .iter()
+            .any(|w| *w == "pg_advisory_lock" || *w == "pg_advisory_lock_shared") =>
+        {
+            Some("Session level advisory locks")
+        }
+        _ => None,
+    }
+}
+
+/// The settings sent to the frontend with ParameterStatus
+fn reported_parameters(settings: &OutputSettings) -> [(&'static str, String); 3] {
+    [
+        ("client_encoding", String::from(settings.encoding.name())),
+        ("DateStyle", settings.date_style.name()),
+        (
+            "IntervalStyle",
+            String::from(settings.interval_style.name()),
+        ),
+    ]
+}
+
+/// The parameters given on the command line of the backend with the options
+/// startup parameter (e.g. PGOPTIONS="-c DateStyle=German")
+fn command_line_options(options: &str) -> Vec<(&str, &str)> {
+    let mut parameters = Vec::new();
+    let mut words = options.split_whitespace();
+    while let Some(word) = words.next() {
+        let option = match word {
+            "-c" => words.next(),
+            _ => word.strip_prefix("--").or(word.strip_prefix("-c")),
+        };
+        if let Some((name, value)) = option.and_then(|option| option.split_once('=')) {
+            parameters.push((name, value));
+        }
+    }
+    parameters
+}
+
+// The bytes sent for the rows, headers included
+fn data_rows_size(data_rows: &[DataRow]) -> usize {
+    data_rows
+        .iter()
+        .map(|data_row| data_row.byte_size().saturating_add(5))
+        .fold(0, usize::saturating_add)
+}
+
+/// How a frontend is authenticated, chosen from its startup parameters
+#[derive(Debug, Clone, PartialEq)]
+pub enum Authentication {
+    // No password asked
+    Trust,
+    // The MD5 password of the user, any password is accepted with None
+    Md5(Option<String>),
+    // The connection is refused
+    Reject,
+}
+
+/// Run the executor, its latency on `clock` is recorded when there are
+/// stats
+fn timed(
+    stats: Option<&QueryStats>,
+    clock: &Clock,
+    startup: &StartupParameters,
+    executor: &dyn Fn(String) -> QueryResult,
+    query: String,
+) -> QueryResult {
+    let Some(stats) = stats else {
+        return executor(query);
+    };
+    let start = clock.now();
+    let result = executor(query.clone());
+    stats.record(&query, clock.now() - start, &session_context(startup));
+    result
+}
+
+fn canceled_error() -> anyhow::Error {
+    PgError::new("57014", "canceling statement due to user request").into()
+}
+
+/// A cap on the bytes buffered by a session before they are sent to the
+/// frontend, e.g. the rows of a portal fetched at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryLimit {
+    // The query fails with 53200 (out_of_memory)
+    Error(usize),
+    // The messages are flushed, the writes block until the frontend reads
+    Backpressure(usize),
+}
+
+/// The bounds of the rows exchanged with the frontend, the results and the
+/// parameters over them fail instead of being sent in messages that can't
+/// represent them, e.g. more than i16::MAX columns in a DataRow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProtocolLimits {
+    // The columns of a result, 1600 like the columns of a PostgreSQL table
+    pub max_columns: usize,
+    // The bytes of a value of a column or of a parameter, 1 GB like a
+    // PostgreSQL field
+    pub max_field_length: usize,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            max_columns: 1600,
+            max_field_length: 0x3fff_ffff,
+        }
+    }
+}
+
+impl ProtocolLimits {
+    pub fn check_columns(&self, count: usize) -> Result<(), PgError> {
+        match count > self.max_columns {
+            true => Err(PgError::new(
+                "54011",
+                &format!(
+                    "target lists can have at most {} entries, the result has {count}",
+                    self.max_columns
+                ),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    pub fn check_field(&self, length: usize) -> Result<(), PgError> {
+        match length > self.max_field_length {
+            true => Err(PgError::new(
+                "54000",
+                &format!(
+                    "field of {length} bytes exceeds the maximum length of {} bytes",
+                    self.max_field_length
+                ),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    pub fn check_row(&self, row: &DataRow) -> Result<(), PgError> {
+        self.check_columns(row.columns.as_ref().len())?;
+        row.columns
+            .as_ref()
+            .iter()
+            .try_for_each(|column| self.check_field(column.as_bytes().map_or(0, <[u8]>::len)))
+    }
+}
+
+/// Each message is sent in two writes, the second one after a delay, to
+/// test how the frontends reassemble a message split between TCP segments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fragmentation {
+    // The bytes of the first write, inside the header when below 5
+    pub offset: usize,
+    pub delay: Duration,
+}
+
+/// A sequence breaking the protocol, sent on purpose to check that the
+/// frontends detect it and fail safely instead of misreading the answers.
+/// The validator of the handler doesn't see these messages.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolViolation {
+    // A second ReadyForQuery after the one ending the commands
+    DuplicateReadyForQuery,
+    // The ReadyForQuery ending the commands has an unknown status, 'X'
+    GarbledReadyForQuery,
+    // The first row of a simple query sent before its RowDescription
+    DataRowBeforeRowDescription,
+}
+
+/// The answer to an SSLRequest or a GSSENCRequest sent before the
+/// StartupMessage. The server can't encrypt the connection, so it can't
+/// accept them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NegotiationPolicy {
+    // 'N', the frontend goes on unencrypted or sends another request
+    #[default]
+    Deny,
+    // A fatal ErrorResponse like the servers that don't know the request,
+    // e.g. GSSENCRequest before PostgreSQL 12, libpq then reconnects
+    Error,
+    // Closed without an answer, the frontend has to reconnect
+    Close,
+}
+
+/// The columns of a statement, `None` when only the executor can tell.
+/// Without a describer, Describe of a statement runs the executor for the
+/// columns, so a statement described then executed runs it twice.
+pub type StatementDescriber = Box<dyn Fn(&str) -> Option<Vec<ColumnDescription>> + Send>;
+
+/// The result of a query with the parameters of its portal, `None` to
+/// leave the query to the executor
+pub type PortalAnswerer = Box<dyn Fn(&str, &[Option<String>]) -> Option<QueryResult> + Send>;
+
+/// Cancels the query of the session with a process id and a secret key,
+/// those of a CancelRequest received instead of a StartupMessage
+pub type CancelRequester = Box<dyn Fn(i32, i32) + Send>;
+
+/// The server side of a connection over any transport that can be read and
+/// written, see client::Handler
+pub struct Handler<R, W: Write> {
+    pub reader: BufReader<R>,
+    pub writer: BufWriter<W>,
+    pub session: Session,
+    // When set, every message exchanged is checked against the protocol flow
+    pub validator: Option<Validator>,
+    // Emulate a transaction pooler (e.g. PgBouncer with pool_mode=transaction)
+    pub transaction_pooling: bool,
+    pub flush_policy: FlushPolicy,
+    pub memory_limit: Option<MemoryLimit>,
+    pub fragmentation: Option<Fragmentation>,
+    pub violation: Option<ProtocolViolation>,
+    pub limits: ProtocolLimits,
+    // Raised by the executor, see Notices
+    pub notices: Notices,
+    // Told about each CopyData of a COPY FROM STDIN or TO STDOUT
+    pub copy_progress: Option<CopyProgressCallback>,
+    pub desync_policy: DesyncPolicy,
+    // The last valid messages, logged when the stream is out of sync
+    pub message_history: MessageHistory,
+    // The latency of the executor by query
+    pub stats: Option<QueryStats>,
+    // The messages received and sent by kind
+    pub message_stats: MessageStats,
+    // Set by another thread to cancel the query running, it fails with
+    // 57014 once the executor returns
+    pub cancel: Option<Arc<AtomicBool>>,
+    // The process id and the secret key sent in BackendKeyData after the
+    // authentication, for the CancelRequest of the frontend
+    pub backend_key: Option<(i32, i32)>,
+    // Told about a CancelRequest, the connection is then closed without an
+    // answer
+    pub cancel_request: Option<CancelRequester>,
+    // Describes the statements without running them, e.g. from the rules
+    // of a scenario
+    pub describe_statement: Option<StatementDescriber>,
+    // Answers the portals with their parameters, which the executor doesn't
+    // see, e.g. the catalog queries of the drivers
+    pub answer_portal: Option<PortalAnswerer>,
+    // Set when the transport is a CompressedReader and a CompressedWriter,
+    // _pq_.compression is then recognized
+    pub compression: Option<CompressionSwitch>,
+    // Reported in the server_version parameter
+    pub server_version: ServerVersion,
+    // The time of the prepared statements, of the latency of the queries
+    // and of the fragmentation delays
+    pub clock: Clock,
+    // The answers to the encryption requests, any number of them can come
+    // before the StartupMessage
+    pub ssl_request: NegotiationPolicy,
+    pub gssenc_request: NegotiationPolicy,
+    compressor: Option<Arc<dyn Compressor>>,
+    // The bytes written since the last flush and its time
+    unflushed: usize,
+    last_flush: Instant,
+    // The bytes written since the connection started, and up to the end
+    // of the last result set completed: the memory limit doesn't count the
+    // completed ones still buffered
+    written: usize,
+    completed: usize,
+    // The settings last sent with ParameterStatus
+    reported_settings: OutputSettings,
+    // Set when a COPY FROM STDIN fails before the end of its data, the
+    // CopyData, CopyDone and CopyFail still sent are dropped
+    copy_draining: bool,
+}
+
+pub type TcpHandler = Handler<TcpStream, TcpStream>;
+
+impl TcpHandler {
+    pub fn new(stream: TcpStream) -> anyhow::Result<Self> {
+        Ok(Self::from_parts(
+            stream.try_clone().expect("Failed to clone TcpStream"),
+            stream,
+        ))
+    }
+}
+
+impl<R, W> Handler<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    pub fn from_parts(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+            session: Session::default(),
+            validator: None,
+            transaction_pooling: false,
+            flush_policy: FlushPolicy::default(),
+            memory_limit: None,
+            fragmentation: None,
+            violation: None,
+            limits: ProtocolLimits::default(),
+            notices: Notices::default(),
+            copy_progress: None,
+            desync_policy: DesyncPolicy::default(),
+            message_history: MessageHistory::default(),
+            stats: None,
+            message_stats: MessageStats::default(),
+            cancel: None,
+            backend_key: None,
+            cancel_request: None,
+            describe_statement: None,
+            answer_portal: None,
+            compression: None,
+            server_version: ServerVersion::default(),
+            clock: Clock::Real,
+            ssl_request: NegotiationPolicy::default(),
+            gssenc_request: NegotiationPolicy::default(),
+            compressor: None,
+            unflushed: 0,
+            last_flush: Instant::now(),
+            written: 0,
+            completed: 0,
+            reported_settings: OutputSettings::default(),
+            copy_draining: false,
+        }
+    }
+
+    fn get_raw_frontend_message(&mut self) -> anyhow::Result<RawFrontendMessage> {
+        let header = match get_frontend_header(
+            &mut self.reader,
+            self.desync_policy,
+            &self.message_history,
+        ) {
+            Ok(header) => header,
+            Err(e) => {
+                if let Some(desync) = e.downcast_ref::<ProtocolDesync>() {
+                    error!("{desync}");
+                    self.put_fatal(&PgError::new("08P01", &desync.reason))?;
+                }
+                return Err(e);
+            }
+        };
+        let raw_message = RawFrontendMessage::get_body(&mut self.reader, header)?;
+        trace!("rcv:\n{raw_message}");
+        self.message_stats.record_frontend(
+            raw_message.header.message_type,
+            raw_message.raw_body.len() + 5,
+        );
+        self.message_history.push(&raw_message);
+        if let Some(validator) = &mut self.validator
+            && !(self.copy_draining && is_copy_in_message(raw_message.header.message_type))
+        {
+            validator.frontend_message(raw_message.header.message_type)?;
+        }
+        Ok(raw_message)
+    }
+
+    fn put_message<U>(&mut self, msg: U) -> anyhow::Result<()>
+    where
+        U: MessageBody + Serialize + ByteSized + std::fmt::Debug,
+    {
+        let message_type = msg.message_type();
+        if let Some(validator) = &mut self.validator {
+            validator.backend_message(message_type)?;
+        }
+        // The length counts itself and the body, the type adds 1 byte
+        let size = MessageHeader::length_for(msg.byte_size())? as usize + 1;
+        match self.fragmentation {
+            Some(fragmentation) => {
+                debug!("snd: {msg:?}");
+                let mut buffer = BytesMut::with_capacity(size);
+                MessageHeader::serialize_message(&mut buffer, &msg)?;
+                self.write_fragmented(&buffer, fragmentation)?;
+            }
+            None => self.writer.put_message(msg)?,
+        }
+        self.message_stats.record_backend(message_type, size);
+        self.written(size)
+    }
+
+    fn put_static(&mut self, msg: &StaticMessage) -> anyhow::Result<()> {
+        if let Some(validator) = &mut self.validator {
+            validator.backend_message(msg.message_type())?;
+        }
+        match self.fragmentation {
+            Some(fragmentation) => {
+                debug!("snd: {}", msg.name);
+                self.write_fragmented(msg.bytes, fragmentation)?;
+            }
+            None => self.writer.put_static(msg)?,
+        }
+        self.message_stats
+            .record_backend(msg.message_type(), msg.bytes.len());
+        self.written(msg.bytes.len())
+    }
+
+    /// Send the start of a message alone, the rest is buffered after the
+    /// delay
+    fn write_fragmented(
+        &mut self,
+        message: &[u8],
+        fragmentation: Fragmentation,
+    ) -> anyhow::Result<()> {
+        let (first, rest) = message.split_at(fragmentation.offset.min(message.len()));
+        // The messages buffered before go in their own write
+        self.writer.flush()?;
+        self.writer.write_all(first)?;
+        self.writer.flush()?;
+        self.clock.sleep(fragmentation.delay);
+        self.writer.write_all(rest)?;
+        Ok(())
+    }
+
+    /// Apply the flush policy after writing `bytes`
+    fn written(&mut self, bytes: usize) -> anyhow::Result<()> {
+        self.unflushed += bytes;
+        self.written += bytes;
+        if self.flush_policy.is_due(self.unflushed, self.last_flush) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn put_messages<U>(&mut self, msgs: Vec<U>) -> anyhow::Result<()>
+    where
+        U: MessageBody + Serialize + ByteSized + std::fmt::Debug,
+    {
+        if self.fragmentation.is_some() {
+            for msg in msgs {
+                self.put_message(msg)?;
+            }
+            return Ok(());
+        }
+        if let Some(validator) = &mut self.validator {
+            for msg in &msgs {
+                validator.backend_message(msg.message_type())?;
+            }
+        }
+        let mut size: usize = 0;
+        for msg in &msgs {
+            let bytes = msg.byte_size().saturating_add(5);
+            self.message_stats.record_backend(msg.message_type(), bytes);
+            size = size.saturating_add(bytes);
+        }
+        self.writer.put_messages(msgs)?;
+        self.written(size)
+    }
+
+    /// Check the memory limit before buffering `bytes` more, the messages
+    /// built and not written yet. Like PostgreSQL, the result sets of a
+    /// multi-statement Query are streamed: the completed ones are flushed to
+    /// make room for the next one.
+    fn reserve(&mut self, bytes: usize) -> anyhow::Result<()> {
+        // The buffer holds the last bytes written, it may have been flushed
+        // when full since the last result set completed
+        let in_buffer = self.writer.buffer().len();
+        let completed = self
+            .completed
+            .saturating_sub(self.written.saturating_sub(in_buffer));
+        let buffered = in_buffer + bytes;
+        match self.memory_limit {
+            Some(MemoryLimit::Error(limit))
+                if buffered > limit && buffered - completed <= limit =>
+            {
+                self.flush()
+            }
+            Some(MemoryLimit::Error(limit)) if buffered > limit => Err(PgError::new(
+                "53200",
+                &format!("out of memory: {buffered} bytes buffered, the limit is {limit}"),
+            )
+            .into()),
+            Some(MemoryLimit::Backpressure(limit)) if buffered > limit => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        self.unflushed = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    fn put_message_and_flush<U>(&mut self, msg: U) -> anyhow::Result<()>
+    where
+        U: MessageBody + Serialize + ByteSized + std::fmt::Debug,
+    {
+        self.put_message(msg)?;
+        self.flush()?;
+
+        Ok(())
+    }
+
+    fn put_error(&mut self, e: &anyhow::Error) -> anyhow::Result<()> {
+        error!("{e}");
+        let code = match e.downcast_ref::<PgError>() {
+            Some(e) => &e.code[..],
+            None => "XX000",
+        };
+        self.put_message(ErrorResponse::new(vec![
+            ErrorMessage::new('S', "ERROR")?,
+            ErrorMessage::new('V', "ERROR")?,
+            ErrorMessage::new('C', code)?,
+            ErrorMessage::new('M', &e.to_string())?,
+        ]))
+    }
+
+    /// Send the notices raised by the executor since the last call
+    fn put_notices(&mut self) -> anyhow::Result<()> {
+        for notice in self.notices.take() {
+            self.put_message(NoticeResponse::new(vec![
+                ErrorMessage::new('S', "NOTICE")?,
+                ErrorMessage::new('V', "NOTICE")?,
+                ErrorMessage::new('C', &notice.code)?,
+                ErrorMessage::new('M', &notice.message)?,
+            ]))?;
+        }
+        Ok(())
+    }
+
+    /// Send an error ending the connection
+    fn put_fatal(&mut self, e: &PgError) -> anyhow::Result<()> {
+        self.put_message_and_flush(ErrorResponse::new(vec![
+            ErrorMessage::new('S', "FATAL")?,
+            ErrorMessage::new('V', "FATAL")?,
+            ErrorMessage::new('C', &e.code)?,
+            ErrorMessage::new('M', &e.message)?,
+        ]))
+    }
+
+    /// Tell the client he can continue. With a transaction pooler, the next
+    /// transaction may run on another server connection, where the prepared
+    /// statements don't exist.
+    fn put_ready_for_query(&mut self) -> anyhow::Result<()> {
+        if self.transaction_pooling && self.session.transaction == TransactionIndicator::Idle {
+            self.session.statements.clear();
+            self.session.portals.clear();
+        }
+        // Like PostgreSQL, the parameters changed by the query (e.g. SET or
+        // DISCARD ALL) are reported before ReadyForQuery
+        let reported = reported_parameters(&self.reported_settings);
+        for (name, value) in reported_parameters(&self.session.settings) {
+            if !reported.contains(&(name, value.clone())) {
+                self.put_message(ParameterStatus::new(name, &value)?)?;
+            }
+        }
+        self.reported_settings = self.session.settings;
+        match self.violation {
+            Some(ProtocolViolation::GarbledReadyForQuery) => {
+                debug!("snd: ReadyForQuery with the status 'X'");
+                self.writer.write_all(&[b'Z', 0, 0, 0, 5, b'X'])?;
+            }
+            _ => self.put_static(ready_for_query(self.session.transaction))?,
+        }
+        if self.violation == Some(ProtocolViolation::DuplicateReadyForQuery) {
+            self.writer
+                .put_static(ready_for_query(self.session.transaction))?;
+        }
+        self.flush()
+    }
+
+    /// Whether the query was canceled since the last call
+    fn canceled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.swap(false, Ordering::SeqCst))
+    }
+
+    /// Queries answered without the executor or, for DECLARE, after running
+    /// the query of the cursor. `None` when the executor must run the query.
+    /// The parameters are the ones of the portal, if any.
+    fn preprocess(
+        &mut self,
+        query: &str,
+        parameters: &[Option<String>],
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> Option<anyhow::Result<QueryResult>> {
+        if self.transaction_pooling
+            && let Some(feature) = session_level_feature(query)
+        {
+            return Some(Err(PgError::new(
+                "0A000",
+                &format!("{feature} is not supported in transaction pooling mode"),
+            )
+            .into()));
+        }
+        if let Some(result) = self.session.preprocess(query) {
+            return Some(result);
+        }
+        if let Some(result) = large_object::lookup(query) {
+            return Some(result);
+        }
+        if let Some(result) = select_bound_view(query, parameters, |view| {
+            matches!(
+                view,
+                "pg_prepared_statements" | "pg_catalog . pg_prepared_statements"
+            )
+            .then(|| self.session.pg_prepared_statements())
+        }) {
+            return Some(result);
+        }
+        if !parameters.is_empty()
+            && let Some(result) = self
+                .answer_portal
+                .as_ref()
+                .and_then(|answer| answer(query, parameters))
+        {
+            return Some(Ok(result));
+        }
+        let declare = Declare::parse(query)?;
+        Some(declare.and_then(|declare| {
+            let result = timed(
+                self.stats.as_ref(),
+                &self.clock,
+                &self.session.startup,
+                executor,
+                declare.query.clone(),
+            );
+            self.session.declare(&declare, result)
+        }))
+    }
+
+    /// Read the startup message, the parameters changing the output (e.g.
+    /// client_encoding, DateStyle) are applied to the session. The
+    /// encryption requests before it are answered with their policy, a
+    /// CancelRequest is given to cancel_request and ends the connection.
+    fn get_startup_message(&mut self) -> anyhow::Result<StartupMessage> {
+        let mut request = RawRequest::get(&mut self.reader)?;
+        loop {
+            let (policy, version) = match request.request_kind {
+                RequestMessageKind::SSLRequest => (self.ssl_request, "1234.5679"),
+                RequestMessageKind::GSSENCRequest => (self.gssenc_request, "1234.5680"),
+                RequestMessageKind::CancelRequest => {
+                    // The request code, then the key of the session
+                    request.raw_body.try_get_i32()?;
+                    let pid = request.raw_body.try_get_i32()?;
+                    let secret_key = request.raw_body.try_get_i32()?;
+                    debug!("rcv: CancelRequest for {pid}");
+                    if let Some(cancel_request) = &self.cancel_request {
+                        cancel_request(pid, secret_key);
+                    }
+                    // Like PostgreSQL, the frontend gets no answer
+                    return Err(anyhow!("CancelRequest for {pid}"));
+                }
+                _ => break,
+            };
+            debug!("rcv: {:?}, {policy:?}", request.request_kind);
+            match policy {
+                NegotiationPolicy::Deny => {
+                    self.writer.write_all(b"N")?;
+                    self.flush()?;
+                }
+                NegotiationPolicy::Error => {
+                    let message =
+                        format!("unsupported frontend protocol {version}: server supports 3.0");
+                    self.put_fatal(&PgError::new("0A000", &message))?;
+                    return Err(anyhow!("{:?} refused", request.request_kind));
+                }
+                NegotiationPolicy::Close => {
+                    return Err(anyhow!("{:?} refused", request.request_kind));
+                }
+            }
+            request = RawRequest::get(&mut self.reader)?;
+        }
+        let sm = StartupMessage::try_from(&mut request)?;
+        debug!("rcv: {sm:?}");
+
+        let mut parameters = Vec::new();
+        let mut unrecognized = Vec::new();
+        for parameter in sm.parameters.as_ref() {
+            let (name, value) = (parameter.name.to_str()?, parameter.value.to_str()?);
+            self.session
+                .startup
+                .parameters
+                .push((String::from(name), String::from(value)));
+            if name.starts_with("_pq_.") {
+                // The protocol options are not settings, without a common
+                // algorithm the messages are not compressed
+                match (name, &self.compression) {
+                    (COMPRESSION_OPTION, Some(_)) => {
+                        self.compressor = compression::negotiate(value)
+                    }
+                    _ => unrecognized.push(name),
+                }
+            } else if name == "options" {
+                parameters.extend(command_line_options(value));
+            } else {
+                parameters.push((name, value));
+            }
+        }
+        for (name, value) in parameters {
+            if let Err(e) = self.session.settings.set(name, value) {
+                self.put_error(&e)?;
+                self.flush()?;
+                return Err(e);
+            }
+        }
+        self.session.startup_settings = self.session.settings;
+        if !unrecognized.is_empty() {
+            self.put_message(NegotiateProtocolVersion::new(0, &unrecognized)?)?;
+        }
+        Ok(sm)
+    }
+
+    fn put_authentication_ok(&mut self) -> anyhow::Result<()> {
+        // Validate the authentication
+        self.put_static(&AUTHENTICATION_OK)?;
+
+        // Validate the authentication
+        //FIXME: There should me much mode parameters to send back to the client..
+        self.put_message(ParameterStatus::new(
+            "server_version",
+            &self.server_version.server_version,
+        )?)?;
+        for (name, value) in reported_parameters(&self.session.settings) {
+            self.put_message(ParameterStatus::new(name, &value)?)?;
+        }
+        self.reported_settings = self.session.settings;
+        if let Some(compressor) = &self.compressor {
+            self.put_message(ParameterStatus::new(COMPRESSION_OPTION, compressor.name())?)?;
+        }
+        if let Some((process_id, secret_key)) = self.backend_key {
+            self.put_message(BackendKeyData::new(process_id, secret_key))?;
+        }
+
+        // Tell the client he can continue
+        self.put_static(ready_for_query(TransactionIndicator::Idle))?;
+        self.flush()?;
+
+        // The messages following the first ReadyForQuery are compressed
+        if let (Some(switch), Some(compressor)) = (&self.compression, &self.compressor) {
+            switch.activate(compressor.clone());
+        }
+        Ok(())
+    }
+
+    //FIXME: Go Back to a HashMap
+    pub fn md5_authentication_handler(
+        &mut self,
+        auth_function: &dyn Fn() -> bool,
+    ) -> anyhow::Result<Vec<ParameterStatus>> {
+        // StartupMessage: (ssl_mode) prefer => Text Auth
+        let sm = self.get_startup_message()?;
+
+        // Ask for the Password
+        //FIXME: random salt
+        self.put_message_and_flush(AuthenticationMD5Password::new([1, 2, 3, 4]))?;
+
+        // PasswordMessage
+        let mut raw_message = self.get_raw_frontend_message()?;
+        let _password_message = match PasswordMessage::try_from(&mut raw_message) {
+            Ok(message) => {
+                debug!("rcv: {message:?}");
+                message
+            }
+            _ => return Err(anyhow!("Password message expected")),
+        };
+
+        if auth_function() {
+            self.put_authentication_ok()?;
+
+            Ok(sm.parameters.into())
+        } else {
+            // Error out
+            self.put_message_and_flush(ErrorResponse::new(vec![ErrorMessage::new(
+                'M',
+                &String::from("Incorrect password or user"),
+            )?]))?;
+
+            Err(anyhow!("Auth failed"))
+        }
+    }
+
+    /// Authenticate the frontend with GSSAPI, the tokens are exchanged until
+    /// the authenticator has established the security context.
+    pub fn gss_authentication_handler(
+        &mut self,
+        authenticator: &mut dyn GssAuthenticator,
+    ) -> anyhow::Result<Vec<ParameterStatus>> {
+        let sm = self.get_startup_message()?;
+
+        self.put_message_and_flush(AuthenticationGSS::new())?;
+        self.gss_token_exchange(authenticator, "GSSAPI")?;
+
+        Ok(sm.parameters.into())
+    }
+
+    /// Authenticate a Windows frontend with SSPI, the tokens are exchanged
+    /// like with GSSAPI. An authenticator can also refuse the first token to
+    /// end the authentication with a proper error instead of a protocol error.
+    pub fn sspi_authentication_handler(
+        &mut self,
+        authenticator: &mut dyn GssAuthenticator,
+    ) -> anyhow::Result<Vec<ParameterStatus>> {
+        let sm = self.get_startup_message()?;
+
+        self.put_message_and_flush(AuthenticationSSPI::new())?;
+        self.gss_token_exchange(authenticator, "SSPI")?;
+
+        Ok(sm.parameters.into())
+    }
+
+    /// Refuse the connection before reading anything
+    pub fn refuse(&mut self, e: &PgError) -> anyhow::Result<()> {
+        warn!("Connection refused: {e}");
+        self.put_fatal(e)
+    }
+
+    /// Refuse the connection once the startup message is read, e.g. when
+    /// there are too many connections
+    pub fn reject(&mut self, e: &PgError) -> anyhow::Result<()> {
+        self.get_startup_message()?;
+        warn!("Connection refused: {e}");
+        self.put_fatal(e)
+    }
+
+    /// Authenticate the frontend with the method given for its startup
+    /// parameters, like the rules of pg_hba.conf
+    pub fn authentication_handler(
+        &mut self,
+        method: &dyn Fn(&StartupParameters) -> Authentication,
+    ) -> anyhow::Result<Vec<ParameterStatus>> {
+        let sm = self.get_startup_message()?;
+        let user = self.session.startup.user().unwrap_or_default().to_string();
+
+        match method(&self.session.startup) {
+            Authentication::Trust => (),
+            Authentication::Md5(expected) => {
+                //FIXME: random salt
+                let salt = [1, 2, 3, 4];
+                self.put_message_and_flush(AuthenticationMD5Password::new(salt))?;
+
+                let mut raw_message = self.get_raw_frontend_message()?;
+                let password_message = match PasswordMessage::try_from(&mut raw_message) {
+                    Ok(message) => {
+                        debug!("rcv: {message:?}");
+                        message
+                    }
+                    _ => return Err(anyhow!("Password message expected")),
+                };
+                if let Some(expected) = expected
+                    && PasswordMessage::new_from_user_password(&user, &expected, &salt)?
+                        != password_message
+                {
+                    let e = PgError::new(
+                        "28P01",
+                        &format!("password authentication failed for user \"{user}\""),
+                    );
+                    self.put_error(&e.into())?;
+                    self.flush()?;
+
+                    return Err(anyhow!("Auth failed"));
+                }
+            }
+            Authentication::Reject => {
+                let e = PgError::new("28000", &format!("no entry for user \"{user}\""));
+                self.put_error(&e.into())?;
+                self.flush()?;
+
+                return Err(anyhow!("Auth failed"));
+            }
+        }
+        self.put_authentication_ok()?;
+
+        Ok(sm.parameters.into())
+    }
+
+    fn gss_token_exchange(
+        &mut self,
+        authenticator: &mut dyn GssAuthenticator,
+        method: &str,
+    ) -> anyhow::Result<()> {
+        loop {
+            let mut raw_message = self.get_raw_frontend_message()?;
+            let response = match GSSResponse::try_from(&mut raw_message) {
+                Ok(message) => {
+                    debug!("rcv: {message:?}");
+                    message
+                }
+                _ => return Err(anyhow!("GSSResponse message expected")),
+            };
+
+            match authenticator.accept(response.data.as_ref()) {
+                Ok(GssStep::Continue(token)) => {
+                    self.put_message_and_flush(AuthenticationGSSContinue::new(token))?
+                }
+                Ok(GssStep::Complete(token)) => {
+                    if let Some(token) = token {
+                        self.put_message(AuthenticationGSSContinue::new(token))?;
+                    }
+                    return self.put_authentication_ok();
+                }
+                Err(e) => {
+                    let e = PgError::new("28000", &format!("{method} authentication failed: {e}"));
+                    self.put_error(&e.into())?;
+                    self.flush()?;
+
+                    return Err(anyhow!("Auth failed"));
+                }
+            }
+        }
+    }
+
+    /// Process either a simple query or an extended query, depending on the
+    /// first message sent by the frontend. Returns false once the frontend
+    /// has terminated the connection.
+    pub fn query_handler(
+        &mut self,
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> anyhow::Result<bool> {
+        let mut raw_message = self.get_raw_frontend_message()?;
+        let message_type = raw_message.header.message_type;
+        if self.copy_draining && is_copy_in_message(message_type) {
+            debug!("Dropped {:?}", raw_message.get_message_kind());
+            // Until the end of the data
+            self.copy_draining = message_type == b'd';
+            return Ok(true);
+        }
+        self.copy_draining = false;
+        if protocol::frontend_transition(Phase::Ready, message_type).is_none() {
+            return Err(anyhow!(
+                "Unexpected frontend message '{}'",
+                message_type as char
+            ));
+        }
+        match raw_message.get_message_kind() {
+            Some(FrontendMessageKind::Terminate) => {
+                debug!("rcv: {:?}", Terminate::try_from(&mut raw_message)?);
+                return Ok(false);
+            }
+            Some(FrontendMessageKind::Query) => {
+                self.process_simple_query(Query::try_from(&mut raw_message)?, executor)?
+            }
+            Some(FrontendMessageKind::FunctionCall) => {
+                self.process_function_call(FunctionCall::try_from(&mut raw_message)?)?
+            }
+            _ => self.process_extended_query(raw_message, executor)?,
+        }
+
+        Ok(true)
+    }
+
+    pub fn simple_query_handler(
+        &mut self,
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> anyhow::Result<()> {
+        // Query?
+        let mut raw_message = self.get_raw_frontend_message()?;
+        let query_message = match Query::try_from(&mut raw_message) {
+            Ok(message) => message,
+            _ => return Err(anyhow!("Query message expected")),
+        };
+
+        self.process_simple_query(query_message, executor)
+    }
+
+    /// Process the messages of the extended query protocol until a Sync is
+    /// received.
+    pub fn extended_query_handler(
+        &mut self,
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> anyhow::Result<()> {
+        let raw_message = self.get_raw_frontend_message()?;
+        self.process_extended_query(raw_message, executor)
+    }
+
+    fn process_simple_query(
+        &mut self,
+        query_message: Query,
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> anyhow::Result<()> {
+        debug!("rcv: {query_message:?}");
+
+        let query = match self
+            .session
+            .settings
+            .encoding
+            .decode(query_message.query.as_bytes())
+        {
+            Ok(query) => query,
+            Err(e) => {
+                self.session.fail();
+                self.put_error(&e)?;
+                return self.put_ready_for_query();
+            }
+        };
+        // Nothing to execute, even in an aborted transaction
+        let statements = split_statements(&query);
+        let statements = match statements[..] {
+            [] => {
+                self.put_static(&EMPTY_QUERY_RESPONSE)?;
+                return self.put_ready_for_query();
+            }
+            // The executor gets the query as sent
+            [_] => vec![query.clone()],
+            _ => statements.into_iter().map(String::from).collect(),
+        };
+
+        // Several statements run in an implicit transaction block: a failed
+        // statement rolls back the previous ones and the next ones are
+        // skipped. A COMMIT or a ROLLBACK ends the block, a BEGIN makes it
+        // an explicit one.
+        self.session.implicit_transaction = statements.len() > 1;
+        let mut snapshot = (self.session.parameters.clone(), self.session.settings);
+        self.canceled();
+        for statement in statements {
+            let idle = self.session.transaction == TransactionIndicator::Idle;
+            match self.process_statement(statement, executor)?.as_deref() {
+                None => {
+                    if self.session.transaction == TransactionIndicator::Idle {
+                        (self.session.parameters, self.session.settings) = snapshot;
+                    }
+                    break;
+                }
+                Some("COMMIT" | "PREPARE TRANSACTION") => {
+                    snapshot = (self.session.parameters.clone(), self.session.settings);
+                }
+                Some("ROLLBACK") if idle => {
+                    (self.session.parameters, self.session.settings) = snapshot.clone();
+                }
+                Some(_) => (),
+            }
+        }
+        if self.session.implicit_transaction
+            && self.session.transaction == TransactionIndicator::Idle
+        {
+            self.session.cursors.retain(|_, cursor| cursor.hold);
+        }
+        self.session.implicit_transaction = false;
+
+        self.put_ready_for_query()
+    }
+
+    /// Run a statement of a simple query and send its result, the command
+    /// tag or `None` when it failed and the error is sent.
+    fn process_statement(
+        &mut self,
+        query: String,
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> anyhow::Result<Option<String>> {
+        let copy = match copy_direction(&query).transpose() {
+            Ok(copy) => copy,
+            Err(e) => {
+                self.session.fail();
+                self.put_error(&e)?;
+                return Ok(None);
+            }
+        };
+        // The data of a COPY FROM STDIN comes before the statement runs, in
+        // an aborted transaction the COPY fails without asking for it
+        let mut copied = 0;
+        if copy == Some(CopyDirection::In) && self.session.in_failed_transaction(&query).is_some() {
+            // The frontend may send the data without waiting for the
+            // CopyInResponse
+            self.copy_draining = true;
+        } else if copy == Some(CopyDirection::In) {
+            match self.get_copy_data(&query) {
+                Ok(rows) => copied = rows,
+                Err(e) if e.is::<PgError>() => {
+                    self.session.fail();
+                    self.put_error(&e)?;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let result = match self.preprocess(&query, &[], executor) {
+            Some(result) => result,
+            None => Ok(timed(
+                self.stats.as_ref(),
+                &self.clock,
+                &self.session.startup,
+                executor,
+                query.clone(),
+            )),
+        }
+        .and_then(|result| {
+            if self.canceled() {
+                return Err(canceled_error());
+            }
+            match result.error {
+                Some(_) if copy == Some(CopyDirection::Out) => Ok(result),
+                Some(error) => Err(error.into()),
+                None => {
+                    self.limits.check_columns(result.columns.len())?;
+                    Ok(result)
+                }
+            }
+        });
+        self.put_notices()?;
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                self.session.fail();
+                self.put_error(&e)?;
+                return Ok(None);
+            }
+        };
+        self.session.update_transaction(&query, &result.command_tag);
+
+        if let Some(direction) = copy {
+            if direction == CopyDirection::Out {
+                copied = result.rows.len() as u64;
+                if let Err(e) = self.put_copy_data(&result) {
+                    self.session.fail();
+                    self.put_error(&e)?;
+                    return Ok(None);
+                }
+            }
+            let command_tag = format!("COPY {copied}");
+            self.put_message(CommandComplete::new(command_tag.clone())?)?;
+            self.completed = self.written;
+            return Ok(Some(command_tag));
+        }
+
+        // the rows are sent in the format given in the row description
+        let formats = result
+            .columns
+            .iter()
+            .map(|column| FormatCode::try_from(column.format))
+            .collect::<anyhow::Result<Vec<FormatCode>>>()?;
+
+        // row description, only for the queries returning rows
+        let mut sent_rows = 0;
+        if !result.columns.is_empty() {
+            if let (Some(ProtocolViolation::DataRowBeforeRowDescription), Some(row)) =
+                (self.violation, result.rows.first())
+            {
+                let data_row = DataRow::new_from_values(row, &formats, &self.session.settings)?;
+                self.writer.put_message(data_row)?;
+                sent_rows = 1;
+            }
+            self.put_message(RowDescription::new(result.columns))?;
+        }
+
+        // data row, a value that can't be converted to the client encoding
+        // or going over the memory limit fails the query
+        let data_rows = result
+            .rows
+            .iter()
+            .skip(sent_rows)
+            .map(|row| {
+                let data_row = DataRow::new_from_values(row, &formats, &self.session.settings)?;
+                self.limits.check_row(&data_row)?;
+                Ok(data_row)
+            })
+            .collect::<anyhow::Result<Vec<DataRow>>>()
+            .and_then(|data_rows| {
+                self.reserve(data_rows_size(&data_rows))?;
+                Ok(data_rows)
+            });
+        match data_rows {
+            Ok(data_rows) => self.put_messages(data_rows)?,
+            Err(e) => {
+                self.session.fail();
+                self.put_error(&e)?;
+                return Ok(None);
+            }
+        }
+
+        // Tell the client the commadn tag
+        self.put_message(CommandComplete::new(result.command_tag.clone())?)?;
+        self.completed = self.written;
+        Ok(Some(result.command_tag))
+    }
+
+    /// Ask for the data of a COPY FROM STDIN and count its rows, up to the
+    /// CopyDone of the frontend. The error is a PgError when the COPY
+    /// fails but not the connection, the rest of its data is then dropped
+    /// by query_handler.
+    fn get_copy_data(&mut self, query: &str) -> anyhow::Result<u64> {
+        let columns = self
+            .describe_statement
+            .as_ref()
+            .and_then(|describe| describe(query))
+            .map_or(0, |columns| columns.len());
+        self.put_message_and_flush(CopyInResponse::new(columns))?;
+
+        let mut progress = CopyProgress::new(CopyDirection::In);
+        loop {
+            let mut raw_message = self.get_raw_frontend_message()?;
+            match raw_message.get_message_kind() {
+                Some(FrontendMessageKind::CopyData) => {
+                    let message = CopyData::try_from(&mut raw_message)?;
+                    progress.add(message.data.as_ref());
+                    if let Some(callback) = &mut self.copy_progress {
+                        callback(progress);
+                    }
+                    if self.canceled() {
+                        self.copy_draining = true;
+                        return Err(canceled_error());
+                    }
+                }
+                Some(FrontendMessageKind::CopyDone) => {
+                    debug!("rcv: {:?}", CopyDone::try_from(&mut raw_message)?);
+                    return Ok(progress.rows);
+                }
+                Some(FrontendMessageKind::CopyFail) => {
+                    let message = CopyFail::try_from(&mut raw_message)?;
+                    debug!("rcv: {message:?}");
+                    return Err(PgError::new(
+                        "57014",
+                        &format!(
+                            "COPY from stdin failed: {}",
+                            message.message.to_string_lossy()
+                        ),
+                    )
+                    .into());
+                }
+                // Ignored by PostgreSQL too during a COPY
+                Some(FrontendMessageKind::Flush | FrontendMessageKind::Sync) => {
+                    debug!("rcv: {:?}", raw_message.get_message_kind())
+                }
+                _ => {
+                    return Err(PgError::new(
+                        "08P01",
+                        &format!(
+                            "unexpected message type 0x{:02X} during COPY from stdin",
+                            raw_message.header.message_type
+                        ),
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+
+    /// Send the rows of a COPY TO STDOUT, one CopyData each, written
+    /// together like the DataRows of a query. An error ends the COPY
+    /// without CopyDone, the rows before it are sent but not the ones left.
+    fn put_copy_data(&mut self, result: &QueryResult) -> anyhow::Result<()> {
+        self.put_message(CopyOutResponse::new(result.columns.len()))?;
+        let mut progress = CopyProgress::new(CopyDirection::Out);
+        let mut copy_data = Vec::with_capacity(result.rows.len());
+        let mut error = None;
+        for row in &result.rows {
+            if self.canceled() {
+                error = Some(canceled_error());
+                break;
+            }
+            let data = match text_row(row, &self.session.settings) {
+                Ok(data) => data,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            };
+            progress.add(&data);
+            copy_data.push(CopyData::new(&data));
+            if let Some(callback) = &mut self.copy_progress {
+                callback(progress);
+            }
+        }
+        self.put_messages(copy_data)?;
+        if let Some(error) = error.or_else(|| result.error.clone().map(Into::into)) {
+            return Err(error);
+        }
+        self.put_message(CopyDone::new())
+    }
+
+    /// The functions called with the fastpath protocol are the ones of the
+    /// large objects, see large_object
+    fn process_function_call(&mut self, call: FunctionCall) -> anyhow::Result<()> {
+        debug!("rcv: {call:?}");
+
+        // Only the end of an aborted transaction block is accepted
+        let result = match self.session.in_failed_transaction("") {
+            Some(Err(e)) => Err(e),
+            _ => call
+                .argument_formats
+                .as_ref()
+                .iter()
+                .map(|code| FormatCode::try_from(*code))
+                .collect::<anyhow::Result<Vec<FormatCode>>>()
+                .and_then(|formats| {
+                    let arguments = call
+                        .arguments
+                        .as_ref()
+                        .iter()
+                        .enumerate()
+                        .map(|(index, argument)| {
+                            Ok((
+                                FormatCode::for_column(&formats, index)?,
+                                argument.as_bytes().ok_or(PgError::new(
+                                    "22004",
+                                    "null values are not allowed for the large objects",
+                                ))?,
+                            ))
+                        })
+                        .collect::<anyhow::Result<Vec<(FormatCode, &[u8])>>>()?;
+                    self.session
+                        .large_objects
+                        .call(call.function as u32, &arguments)
+                })
+                .and_then(|value| {
+                    value.encode(
+                        FormatCode::try_from(call.result_format)?,
+                        &self.session.settings,
+                    )
+                }),
+        };
+        match result {
+            Ok(result) => self.put_message(FunctionCallResponse { result })?,
+            Err(e) => {
+                self.session.fail();
+                self.put_error(&e)?;
+            }
+        }
+        // Outside of a transaction block, the call was its own transaction
+        if self.session.transaction == TransactionIndicator::Idle {
+            self.session.large_objects.descriptors.clear();
+        }
+        self.put_ready_for_query()
+    }
+
+    fn process_extended_query(
+        &mut self,
+        mut raw_message: RawFrontendMessage,
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> anyhow::Result<()> {
+        let mut failed = false;
+
+        while !matches!(
+            raw_message.get_message_kind(),
+            Some(FrontendMessageKind::Sync)
+        ) {
+            // After an error, the messages are discarded until Sync
+            if !failed && let Err(e) = self.process_extended_message(&mut raw_message, executor) {
+                failed = true;
+                self.session.fail();
+                self.put_error(&e)?;
+            }
+            raw_message = self.get_raw_frontend_message()?;
+        }
+        debug!("rcv: {:?}", Sync::try_from(&mut raw_message)?);
+
+        self.put_ready_for_query()
+    }
+
+    fn process_extended_message(
+        &mut self,
+        raw_message: &mut RawFrontendMessage,
+        executor: &dyn Fn(String) -> QueryResult,
+    ) -> anyhow::Result<()> {
+        match raw_message.get_message_kind() {
+            Some(FrontendMessageKind::Parse) => {
+                let message = Parse::try_from(raw_message)?;
+                debug!("rcv: {message:?}");
+
+                let query = self
+                    .session
+                    .settings
+                    .encoding
+                    .decode(message.query.as_bytes())?;
+                if self.transaction_pooling
+                    && let Some(feature) = session_level_feature(&query)
+                {
+                    return Err(PgError::new(
+                        "0A000",
+                        &format!("{feature} is not supported in transaction pooling mode"),
+                    )
+                    .into());
+                }
+                if let Some(Err(e)) = self.session.in_failed_transaction(&query) {
+                    return Err(e);
+                }
+
+                self.session.statements.insert(
+                    message.statement.into_string()?,
+                    PreparedStatement::new(
+                        query,
+                        message.parameter_types.as_ref().clone(),
+                        &self.clock,
+                    ),
+                );
+                self.put_static(&PARSE_COMPLETE)?;
+            }
+            Some(FrontendMessageKind::Bind) => {
+                let message = Bind::try_from(raw_message)?;
+                debug!("rcv: {message:?}");
+
+                let statement_name = message.statement.to_str()?;
+                let statement = self
+                    .session
+                    .statements
+                    .get(statement_name)
+                    .ok_or(PgError::new(
+                        "26000",
+                        &format!("prepared statement \"{statement_name}\" does not exist"),
+                    ))?;
+                if let Some(Err(e)) = self.session.in_failed_transaction(&statement.query) {
+                    return Err(e);
+                }
+                for parameter in message.parameters.as_ref() {
+                    self.limits
+                        .check_field(parameter.as_bytes().map_or(0, <[u8]>::len))?;
+                }
+                let portal = Portal {
+                    query: statement.query.clone(),
+                    parameters: text_parameters(&message, &statement.parameter_types)
+                        .unwrap_or_default(),
+                    result_formats: message.result_formats()?,
+                    result: None,
+                    position: 0,
+                };
+                self.session
+                    .portals
+                    .insert(message.portal.into_string()?, portal);
+                if let Some(statement) = self.session.statements.get_mut(statement_name) {
+                    statement.binds += 1;
+                }
+                self.put_static(&BIND_COMPLETE)?;
+            }
+            Some(FrontendMessageKind::Describe) => {
+                let message = Describe::try_from(raw_message)?;
+                debug!("rcv: {message:?}");
+
+                let name = message.name.to_str()?;
+                match DescribeTarget::try_from(&message.target)? {
+                    DescribeTarget::Statement => {
+                        let statement = self.session.statements.get(name).ok_or(PgError::new(
+                            "26000",
+                            &format!("prepared statement \"{name}\" does not exist"),
+                        ))?;
+                        let query = statement.query.clone();
+                        let parameter_types = statement.parameter_types.clone();
+                        let nulls = vec![None; parameter_types.len()];
+                        self.put_message(ParameterDescription::new(parameter_types))?;
+
+                        // The format codes are not known yet. Without a
+                        // describer, there is nothing to learn the columns
+                        // from but the executor. answer_portal is given NULL
+                        // parameters: only the columns count.
+                        let columns = if returns_no_rows(&query) {
+                            Vec::new()
+                        } else {
+                            match self
+                                .describe_statement
+                                .as_ref()
+                                .and_then(|describe| describe(&query))
+                                .or_else(|| {
+                                    let answer = self.answer_portal.as_ref()?;
+                                    Some(answer(&query, &nulls)?.columns)
+                                }) {
+                                Some(columns) => columns,
+                                None => {
+                                    // Only run for its columns, its notices
+                                    // and its latency come with its execution
+                                    let columns = executor(query).columns;
+                                    self.notices.take();
+                                    columns
+                                }
+                            }
+                        };
+                        if columns.is_empty() {
+                            self.put_static(&NO_DATA)?;
+                        } else {
+                            self.limits.check_columns(columns.len())?;
+                            let columns = columns
+                                .into_iter()
+                                .map(|column| ColumnDescription {
+                                    format: 0,
+                                    ..column
+                                })
+                                .collect();
+                            self.put_message(RowDescription::new(columns))?;
+                        }
+                    }
+                    DescribeTarget::Portal => {
+                        let portal = self.session.portals.get(name).ok_or(PgError::new(
+                            "34000",
+                            &format!("portal \"{name}\" does not exist"),
+                        ))?;
+                        let query = portal.query.clone();
+                        let parameters = portal.parameters.clone();
+                        let pending = portal.result.is_none();
+                        // The result is kept for Execute, like its first
+                        // execution would get it
+                        if pending && !returns_no_rows(&query) {
+                            let result = match self.preprocess(&query, &parameters, executor) {
+                                Some(result) => result,
+                                None => Ok(timed(
+                                    self.stats.as_ref(),
+                                    &self.clock,
+                                    &self.session.startup,
+                                    executor,
+                                    query,
+                                )),
+                            };
+                            self.put_notices()?;
+                            let result = result?;
+                            if let Some(portal) = self.session.portals.get_mut(name) {
+                                portal.result = Some(result);
+                            }
+                        }
+                        let portal = self.session.portals.get(name);
+                        let row_description = match portal.and_then(|p| p.result.as_ref()) {
+                            Some(result) if !result.columns.is_empty() => portal
+                                .map(|portal| portal.describe(&result.columns))
+                                .transpose()?,
+                            _ => None,
+                        };
+
+                        match row_description {
+                            Some(row_description) => {
+                                self.limits
+                                    .check_columns(row_description.columns.as_ref().len())?;
+                                self.put_message(row_description)?
+                            }
+                            None => self.put_static(&NO_DATA)?,
+                        }
+                    }
+                }
+            }
+            Some(FrontendMessageKind::Execute) => {
+                let message = Execute::try_from(raw_message)?;
+                debug!("rcv: {message:?}");
+
+                let name = message.portal.into_string()?;
+                let query = match self.session.portals.get(&name) {
+                    Some(portal) if portal.result.is_none() => {
+                        Some((portal.query.clone(), portal.parameters.clone()))
+                    }
+                    Some(_) => None,
+                    None => {
+                        return Err(PgError::new(
+                            "34000",
+                            &format!("portal \"{name}\" does not exist"),
+                        )
+                        .into());
+                    }
+                };
+
+                // First execution
+                if let Some((query, parameters)) = query {
+                    self.canceled();
+                    let result = match self.preprocess(&query, &parameters, executor) {
+                        Some(result) => result,
+                        None => Ok(timed(
+                            self.stats.as_ref(),
+                            &self.clock,
+                            &self.session.startup,
+                            executor,
+                            query.clone(),
+                        )),
+                    };
+                    self.put_notices()?;
+                    let result = result?;
+                    if self.canceled() {
+                        return Err(canceled_error());
+                    }
+                    match self.session.portals.get_mut(&name) {
+                        Some(portal) => portal.result = Some(result),
+                        // The portal was closed by the query (DISCARD ALL)
+                        None => {
+                            self.session.update_transaction(&query, &result.command_tag);
+                            self.put_message(CommandComplete::new(result.command_tag)?)?;
+                            return Ok(());
+                        }
+                    }
+                }
+
+                let portal = self
+                    .session
+                    .portals
+                    .get_mut(&name)
+                    .ok_or(anyhow!("portal \"{name}\" disappeared"))?;
+                let (data_rows, command_tag) =
+                    portal.execute(message.max_rows, &self.session.settings)?;
+                for data_row in &data_rows {
+                    self.limits.check_row(data_row)?;
+                }
+                self.reserve(data_rows_size(&data_rows))?;
+                self.put_messages(data_rows)?;
+                match command_tag {
+                    Some(command_tag) => {
+                        let query = self
+                            .session
+                            .portals
+                            .get(&name)
+                            .map(|portal| portal.query.clone());
+                        self.session
+                            .update_transaction(&query.unwrap_or_default(), &command_tag);
+                        self.put_message(CommandComplete::new(command_tag)?)?;
+                    }
+                    None => self.put_static(&PORTAL_SUSPENDED)?,
+                }
+            }
+            Some(FrontendMessageKind::Close) => {
+                let message = Close::try_from(raw_message)?;
+                debug!("rcv: {message:?}");
+
+                // Closing an object that doesn't exist is not an error
+                let name = message.name.to_str()?;
+                match DescribeTarget::try_from(&message.target)? {
+                    DescribeTarget::Statement => {
+                        self.session.statements.remove(name);
+                    }
+                    DescribeTarget::Portal => {
+                        self.session.portals.remove(name);
+                    }
+                }
+                self.put_static(&CLOSE_COMPLETE)?;
+            }
+            Some(FrontendMessageKind::Flush) => {
+                debug!("rcv: {:?}", Flush::try_from(raw_message)?);
+                self.flush()?;
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unexpected message in extended query: '{}'",
+                    raw_message.header.message_type as char
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::{LibPqReader, MessageCount};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A handler and the frontend side of its connection
+    fn handler_pair() -> anyhow::Result<(TcpHandler, BufReader<TcpStream>, BufWriter<TcpStream>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let frontend = TcpStream::connect(listener.local_addr()?)?;
+        let handler = TcpHandler::new(listener.accept()?.0)?;
+        Ok((
+            handler,
+            BufReader::new(frontend.try_clone()?),
+            BufWriter::new(frontend),
+        ))
+    }
+
+    fn executor(query: String) -> QueryResult {
+        match &query[..] {
+            "BEGIN" | "COMMIT" => QueryResult {
+                command_tag: query,
+                ..Default::default()
+            },
+            _ => QueryResult {
+                columns: vec![ColumnDescription::new("n", PgType::Int4).expect("column")],
+                rows: (1..=5).map(|n| vec![PgValue::Int4(n)]).collect(),
+                command_tag: String::from("SELECT 5"),
+                error: None,
+            },
+        }
+    }
+
+    #[test]
+    fn portal_fetch_size() -> anyhow::Result<()> {
+        let (mut handler, mut reader, mut writer) = handler_pair()?;
+
+        // The JDBC driver with setFetchSize(2) inside a transaction: a named
+        // portal executed until it's exhausted, then the statement is bound
+        // again.
+        writer.put_message(Query::new(String::from("BEGIN"))?)?;
+        writer.put_message(Parse::new("S_1", "SELECT n FROM t", vec![])?)?;
+        for _ in 0..2 {
+            writer.put_message(Bind::new("C_1", "S_1", vec![], vec![], vec![])?)?;
+            for _ in 0..3 {
+                writer.put_message(Execute::new("C_1", 2)?)?;
+                writer.put_message(Sync::new())?;
+            }
+        }
+        writer.put_message_and_flush(Terminate::new())?;
+        while handler.query_handler(&executor)? {}
+        drop(handler);
+
+        let mut received = Vec::new();
+        while let Ok(mut raw_message) = reader.get_raw_backend_message() {
+            received.push(match raw_message.get_message_kind() {
+                Some(BackendMessageKind::DataRow) => {
+                    let row = DataRow::try_from(&mut raw_message)?;
+                    format!(
+                        "D{}",
+                        String::from_utf8_lossy(
+                            row.columns.as_ref()[0].as_bytes().unwrap_or_default()
+                        )
+                    )
+                }
+                Some(BackendMessageKind::CommandComplete) => {
+                    CommandComplete::try_from(&mut raw_message)?
+                        .command_tag
+                        .into_string()?
+                }
+                _ => String::from(raw_message.header.message_type as char),
+            });
+        }
+
+        let fetches = "D1 D2 s Z D3 D4 s Z D5 SELECT 1 Z";
+        assert_eq!(
+            format!("BEGIN Z 1 2 {fetches} 2 {fetches}"),
+            received.join(" ")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn startup_parameters() -> anyhow::Result<()> {
+        let (mut handler, _reader, mut writer) = handler_pair()?;
+        let parameters = [
+            ("user", "bob"),
+            ("application_name", "billing"),
+            ("options", "-c search_path=app --DateStyle=German"),
+        ];
+        writer.put_request(StartupMessage::new(
+            ProtocolVersion { major: 3, minor: 0 },
+            parameters
+                .iter()
+                .map(|(name, value)| ParameterStatus::new(name, value))
+                .collect::<anyhow::Result<Vec<ParameterStatus>>>()?,
+        ))?;
+        writer.flush()?;
+        handler.get_startup_message()?;
+
+        let startup = &handler.session.startup;
+        assert_eq!(Some("bob"), startup.database());
+        assert_eq!(Some("billing"), startup.application_name());
+        startup.assert_parameter("search_path", "app")?;
+        startup.assert_parameter("datestyle", "German")?;
+        assert!(
+            startup
+                .assert_parameter("application_name", "psql")
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn negotiation_requests() -> anyhow::Result<()> {
+        let gssenc_request = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x30];
+        let ssl_request = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+
+        // libpq with gssencmode=prefer and sslmode=prefer, both denied
+        let (mut handler, mut reader, mut writer) = handler_pair()?;
+        writer.write_all(&gssenc_request)?;
+        writer.write_all(&ssl_request)?;
+        writer.put_request(StartupMessage::new(
+            ProtocolVersion { major: 3, minor: 0 },
+            vec![ParameterStatus::new("user", "bob")?],
+        ))?;
+        writer.flush()?;
+        handler.get_startup_message()?;
+        assert_eq!(Some("bob"), handler.session.startup.user());
+        let mut answers = [0; 2];
+        reader.read_exact(&mut answers)?;
+        assert_eq!(b"NN", &answers);
+
+        // Like a server older than GSSENCRequest
+        let (mut handler, mut reader, mut writer) = handler_pair()?;
+        handler.gssenc_request = NegotiationPolicy::Error;
+        writer.write_all(&gssenc_request)?;
+        writer.flush()?;
+        assert!(handler.get_startup_message().is_err());
+        let raw_message = RawBackendMessage::get(&mut reader)?;
+        assert_eq!(b'E', raw_message.header.message_type);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flush_policy() -> anyhow::Result<()> {
+        let (mut handler, _reader, _writer) = handler_pair()?;
+        handler.put_message(CommandComplete::new(String::from("SELECT 1"))?)?;
+        assert!(!handler.writer.buffer().is_empty());
+
+        // CommandComplete with "SELECT 1" is 14 bytes long
+        let (mut handler, _reader, _writer) = handler_pair()?;
+        handler.flush_policy = FlushPolicy::EveryBytes(20);
+        handler.put_message(CommandComplete::new(String::from("SELECT 1"))?)?;
+        assert!(!handler.writer.buffer().is_empty());
+        handler.put_message(CommandComplete::new(String::from("SELECT 1"))?)?;
+        assert!(handler.writer.buffer().is_empty());
+
+        let (mut handler, _reader, _writer) = handler_pair()?;
+        handler.flush_policy = FlushPolicy::Interval(std::time::Duration::ZERO);
+        handler.put_message(CommandComplete::new(String::from("SELECT 1"))?)?;
+        assert!(handler.writer.buffer().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn fragmented_messages() -> anyhow::Result<()> {
+        let (mut handler, mut reader, _writer) = handler_pair()?;
+        let delay = Duration::from_millis(100);
+        let mut expected = BytesMut::new();
+        let complete = CommandComplete::new(String::from("SELECT 1"))?;
+        MessageHeader::serialize_message(&mut expected, &complete)?;
+        let server = thread::spawn(move || -> anyhow::Result<()> {
+            // Inside the header, then inside the body
+            for offset in [2, 7] {
+                handler.fragmentation = Some(Fragmentation { offset, delay });
+                handler.put_message_and_flush(CommandComplete::new(String::from("SELECT 1"))?)?;
+            }
+            Ok(())
+        });
+
+        let mut first = [0; 16];
+        assert_eq!(2, reader.get_mut().read(&mut first)?);
+        let mut rest = vec![0; expected.len() - 2];
+        reader.get_mut().read_exact(&mut rest)?;
+        assert_eq!(&expected[..], &[&first[..2], &rest[..]].concat()[..]);
+
+        // Reassembled by the reader
+        let mut raw_message = RawBackendMessage::get(&mut reader)?;
+        assert_eq!(complete, CommandComplete::try_from(&mut raw_message)?);
+        server.join().expect("server thread")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn manual_clock() -> anyhow::Result<()> {
+        let clock = Clock::manual();
+        let Clock::Manual(manual) = &clock else {
+            unreachable!("manual clock");
+        };
+
+        // The second write waits for the clock to pass the delay
+        let (mut handler, mut reader, _writer) = handler_pair()?;
+        handler.clock = clock.clone();
+        let server = thread::spawn(move || -> anyhow::Result<()> {
+            handler.fragmentation = Some(Fragmentation {
+                offset: 2,
+                delay: Duration::from_secs(3600),
+            });
+            handler.put_message_and_flush(CommandComplete::new(String::from("SELECT 1"))?)?;
+            Ok(())
+        });
+        let mut expected = BytesMut::new();
+        MessageHeader::serialize_message(
+            &mut expected,
+            &CommandComplete::new(String::from("SELECT 1"))?,
+        )?;
+        let mut received = vec![0; expected.len()];
+        reader.get_mut().read_exact(&mut received[..2])?;
+        while manual.sleepers() == 0 {
+            thread::yield_now();
+        }
+        manual.advance(Duration::from_secs(3600));
+        reader.get_mut().read_exact(&mut received[2..])?;
+        assert_eq!(&expected[..], &received[..]);
+        server.join().expect("server thread")?;
+
+        // The latency of the queries is the time of the clock
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_message(Query::new(String::from("SELECT n FROM t"))?)?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        handler.clock = clock.clone();
+        let stats = QueryStats::default();
+        handler.stats = Some(stats.clone());
+        let executor = |query| {
+            manual.advance(Duration::from_millis(250));
+            executor(query)
+        };
+        while handler.query_handler(&executor)? {}
+        assert_eq!(
+            vec![Duration::from_millis(250)],
+            stats
+                .summary()
+                .iter()
+                .map(|summary| summary.max)
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_limit() -> anyhow::Result<()> {
+        // RowDescription is 27 bytes long, each DataRow 12 bytes. The rows
+        // of a result are all built before they are written, the 60 bytes
+        // of the 5 rows fail the query before its first DataRow.
+        let rows = "T D D D D D C";
+        for (limit, query, expected) in [
+            (
+                MemoryLimit::Error(50),
+                "SELECT n FROM t",
+                String::from("T E Z"),
+            ),
+            (
+                MemoryLimit::Backpressure(50),
+                "SELECT n FROM t",
+                format!("{rows} Z"),
+            ),
+            // Each result set fits, the first one is flushed for the second
+            (
+                MemoryLimit::Error(100),
+                "SELECT n FROM t; SELECT n FROM u",
+                format!("{rows} {rows} Z"),
+            ),
+        ] {
+            let (mut handler, mut reader, mut writer) = handler_pair()?;
+            handler.memory_limit = Some(limit);
+            writer.put_message(Query::new(String::from(query))?)?;
+            writer.put_message_and_flush(Terminate::new())?;
+            while handler.query_handler(&executor)? {}
+            drop(handler);
+
+            let mut received = Vec::new();
+            while let Ok(raw_message) = RawBackendMessage::get(&mut reader) {
+                received.push(String::from(raw_message.header.message_type as char));
+            }
+            assert_eq!(expected, received.join(" "));
+        }
+
+        // The rows fetched one by one go over the limit in total, but the
+        // buffer is sent each time it is full
+        let (mut handler, mut reader, mut writer) = handler_pair()?;
+        handler.memory_limit = Some(MemoryLimit::Error(10_000));
+        writer.put_message(Parse::new("", "SELECT n FROM t", vec![])?)?;
+        writer.put_message(Bind::new("", "", vec![], vec![], vec![])?)?;
+        for _ in 0..1000 {
+            writer.put_message(Execute::new("", 1)?)?;
+        }
+        writer.put_message(Sync::new())?;
+        writer.put_message_and_flush(Terminate::new())?;
+        let columns = columns![("n", Int4)]?;
+        let executor = |_| QueryResult {
+            columns: columns.clone(),
+            rows: (0..1000).map(|n| vec![PgValue::Int4(n)]).collect(),
+            command_tag: String::from("SELECT 1000"),
+            error: None,
+        };
+        // The frontend reads while the handler writes
+        let frontend = thread::spawn(move || {
+            let mut received = Vec::new();
+            while let Ok(raw_message) = RawBackendMessage::get(&mut reader) {
+                received.push(raw_message.header.message_type);
+            }
+            received
+        });
+        while handler.query_handler(&executor)? {}
+        drop(handler);
+        let received = frontend.join().expect("frontend thread");
+        assert!(!received.contains(&b'E'));
+        assert_eq!(Some(&b'Z'), received.last());
+
+        Ok(())
+    }
+
+    #[test]
+    fn executor_notices() -> anyhow::Result<()> {
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_message(Query::new(String::from("SELECT n FROM t"))?)?;
+        frontend.put_message(Parse::new("", "SELECT n FROM t", vec![])?)?;
+        frontend.put_message(Bind::new("", "", vec![], vec![], vec![])?)?;
+        frontend.put_message(Execute::new("", 0)?)?;
+        frontend.put_message(Sync::new())?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        let notices = handler.notices.clone();
+        let executor = move |query: String| {
+            notices.notice("step 1 of 2");
+            notices.raise(PgError::new("01000", "step 2 of 2"));
+            executor(query)
+        };
+        while handler.query_handler(&executor)? {}
+        let backend = handler.writer.into_inner()?;
+
+        let mut reader = BufReader::new(&backend[..]);
+        let mut received = Vec::new();
+        while let Ok(mut raw_message) = RawBackendMessage::get(&mut reader) {
+            received.push(match raw_message.header.message_type {
+                b'N' => {
+                    let notice = NoticeResponse::try_from(&mut raw_message)?;
+                    let fields: Vec<String> = notice
+                        .messages
+                        .as_ref()
+                        .iter()
+                        .filter(|field| matches!(field.code, b'C' | b'M'))
+                        .map(|field| field.message.to_string_lossy().into_owned())
+                        .collect();
+                    format!("N:{}", fields.join(":"))
+                }
+                message_type => String::from(message_type as char),
+            });
+        }
+        let notices = "N:00000:step 1 of 2 N:01000:step 2 of 2";
+        assert_eq!(
+            format!("{notices} T D D D D D C Z 1 2 {notices} D D D D D C Z"),
+            received.join(" ")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn message_stats() -> anyhow::Result<()> {
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_message(Query::new(String::from("SELECT n FROM t"))?)?;
+        frontend.put_message(Parse::new("", "SELECT n FROM t", vec![])?)?;
+        for _ in 0..2 {
+            frontend.put_message(Bind::new("", "", vec![], vec![], vec![])?)?;
+            frontend.put_message(Execute::new("", 0)?)?;
+        }
+        frontend.put_message(Sync::new())?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        while handler.query_handler(&executor)? {}
+        let stats = handler.message_stats.clone();
+        let backend = handler.writer.into_inner()?;
+
+        assert_eq!(1, stats.frontend(FrontendMessageKind::Parse).messages);
+        assert_eq!(2, stats.frontend(FrontendMessageKind::Bind).messages);
+        assert_eq!(
+            MessageCount {
+                messages: 8,
+                bytes: frontend.len() as u64
+            },
+            stats.frontend_total()
+        );
+        assert_eq!(15, stats.backend(BackendMessageKind::DataRow).messages);
+        assert_eq!(
+            MessageCount {
+                messages: 2,
+                bytes: 12
+            },
+            stats.backend(BackendMessageKind::ReadyForQuery)
+        );
+        assert_eq!(backend.len() as u64, stats.backend_total().bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_progress() -> anyhow::Result<()> {
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_message(Query::new(String::from("COPY t FROM STDIN"))?)?;
+        frontend.put_message(CopyData::new(b"1\n2\n"))?;
+        frontend.put_message(Flush::new())?;
+        frontend.put_message(CopyData::new(b"3\n"))?;
+        frontend.put_message(CopyDone::new())?;
+        frontend.put_message(Query::new(String::from("COPY t FROM STDIN"))?)?;
+        frontend.put_message(CopyFail::new("canceled by the user")?)?;
+        frontend.put_message(Query::new(String::from("COPY t TO STDOUT"))?)?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        let cancel = Arc::new(AtomicBool::new(false));
+        handler.cancel = Some(cancel.clone());
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let reported = progress.clone();
+        // The COPY TO STDOUT is canceled after 3 rows
+        handler.copy_progress = Some(Box::new(move |progress: CopyProgress| {
+            if progress.direction == CopyDirection::Out && progress.rows == 3 {
+                cancel.store(true, Ordering::SeqCst);
+            }
+            reported
+                .lock()
+                .unwrap()
+                .push((progress.direction, progress.bytes, progress.rows))
+        }));
+        while handler.query_handler(&executor)? {}
+        let backend = handler.writer.into_inner()?;
+
+        let mut reader = BufReader::new(&backend[..]);
+        let mut received = Vec::new();
+        while let Ok(mut raw_message) = RawBackendMessage::get(&mut reader) {
+            received.push(match raw_message.header.message_type {
+                b'C' => format!(
+                    "C:{}",
+                    CommandComplete::try_from(&mut raw_message)?
+                        .command_tag
+                        .to_string_lossy()
+                ),
+                message_type => String::from(message_type as char),
+            });
+        }
+        assert_eq!("G C:COPY 3 Z G E Z H d d d E Z", received.join(" "));
+        let (copy_in, copy_out) = (CopyDirection::In, CopyDirection::Out);
+        assert_eq!(
+            vec![
+                (copy_in, 4, 2),
+                (copy_in, 6, 3),
+                (copy_out, 2, 1),
+                (copy_out, 4, 2),
+                (copy_out, 6, 3),
+            ],
+            *progress.lock().unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_errors() -> anyhow::Result<()> {
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_message(Query::new(String::from("BEGIN"))?)?;
+        frontend.put_message(Query::new(String::from("COPY t FROM STDIN"))?)?;
+        frontend.put_message(CopyData::new(b"1\n"))?;
+        frontend.put_message(CopyFail::new("bad data")?)?;
+        // Fails without asking for the data, which is dropped
+        frontend.put_message(Query::new(String::from("COPY t FROM STDIN"))?)?;
+        frontend.put_message(CopyData::new(b"2\n"))?;
+        frontend.put_message(CopyDone::new())?;
+        frontend.put_message(Query::new(String::from("ROLLBACK"))?)?;
+        frontend.put_message(Query::new(String::from("COPY t TO STDOUT"))?)?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        // The dropped data is legal
+        let mut validator = Validator::new();
+        validator.backend_message(b'Z')?;
+        handler.validator = Some(validator);
+        // The COPY TO STDOUT fails after 2 rows
+        let executor = |query: String| {
+            let mut result = executor(query);
+            result.rows.truncate(2);
+            result.error = Some(PgError::new("22P02", "invalid input syntax"));
+            result
+        };
+        let executor = |query: String| match &query[..] {
+            "COPY t TO STDOUT" => executor(query),
+            _ => self::executor(query),
+        };
+        while handler.query_handler(&executor)? {}
+        let backend = handler.writer.into_inner()?;
+
+        let mut reader = BufReader::new(&backend[..]);
+        let mut received = Vec::new();
+        while let Ok(mut raw_message) = RawBackendMessage::get(&mut reader) {
+            received.push(match raw_message.header.message_type {
+                b'E' => {
+                    let error = ErrorResponse::try_from(&mut raw_message)?;
+                    let fields: Vec<String> = error
+                        .messages
+                        .as_ref()
+                        .iter()
+                        .filter(|field| matches!(field.code, b'C' | b'M'))
+                        .map(|field| field.message.to_string_lossy().into_owned())
+                        .collect();
+                    format!("E:{}", fields.join(":"))
+                }
+                b'Z' => format!(
+                    "Z:{}",
+                    ReadyForQuery::try_from(&mut raw_message)?.transaction_indicator as char
+                ),
+                message_type => String::from(message_type as char),
+            });
+        }
+        assert_eq!(
+            [
+                "C Z:T",
+                "G E:57014:COPY from stdin failed: bad data Z:E",
+                "E:25P02:current transaction is aborted, commands ignored until end of transaction block Z:E",
+                "C Z:I",
+                "H d d E:22P02:invalid input syntax Z:I",
+            ]
+            .join(" "),
+            received.join(" ")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_transaction_end() -> anyhow::Result<()> {
+        // The command tag is the first word, SELECT fails
+        let executor = |query: String| match query.split(' ').next() {
+            Some("SELECT") => QueryResult {
+                error: Some(PgError::new("42703", "column \"nope\" does not exist")),
+                ..Default::default()
+            },
+            command => QueryResult {
+                command_tag: String::from(command.unwrap_or_default()),
+                ..Default::default()
+            },
+        };
+        let run = |queries: &[&str]| -> anyhow::Result<String> {
+            let mut frontend = BufWriter::new(Vec::new());
+            for query in queries {
+                frontend.put_message(Query::new(String::from(*query))?)?;
+            }
+            frontend.put_message(Terminate::new())?;
+            let frontend = frontend.into_inner()?;
+            let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+            while handler.query_handler(&executor)? {}
+            let backend = handler.writer.into_inner()?;
+
+            let mut reader = BufReader::new(&backend[..]);
+            let mut received = Vec::new();
+            while let Ok(mut raw_message) = RawBackendMessage::get(&mut reader) {
+                received.push(match raw_message.header.message_type {
+                    b'C' => format!(
+                        "C:{}",
+                        CommandComplete::try_from(&mut raw_message)?
+                            .command_tag
+                            .to_string_lossy()
+                    ),
+                    b'E' => {
+                        let error = ErrorResponse::try_from(&mut raw_message)?;
+                        let code = error
+                            .messages
+                            .as_ref()
+                            .iter()
+                            .find(|field| field.code == b'C');
+                        format!(
+                            "E:{}",
+                            code.map_or("", |field| field.message.to_str().unwrap_or(""))
+                        )
+                    }
+                    b'Z' => format!(
+                        "Z:{}",
+                        ReadyForQuery::try_from(&mut raw_message)?.transaction_indicator as char
+                    ),
+                    message_type => String::from(message_type as char),
+                });
+            }
+            Ok(received.join(" "))
+        };
+
+        // The prepared transaction is rolled back, there is nothing to commit
+        assert_eq!(
+            "C:BEGIN Z:T E:42703 Z:E C:ROLLBACK Z:I E:42704 Z:I",
+            run(&[
+                "BEGIN",
+                "SELECT nope",
+                "PREPARE TRANSACTION 'tx1'",
+                "COMMIT PREPARED 'tx1'",
+            ])?
+        );
+        // The next transaction block starts right away
+        assert_eq!(
+            "C:BEGIN Z:T E:42703 Z:E C:ROLLBACK Z:T C:COMMIT Z:I",
+            run(&["BEGIN", "SELECT nope", "COMMIT AND CHAIN", "COMMIT"])?
+        );
+        // Back to the savepoint set before the error
+        assert_eq!(
+            [
+                "C:BEGIN Z:T C:SAVEPOINT Z:T E:42703 Z:E",
+                "E:3B001 Z:E C:ROLLBACK Z:T",
+                "E:42703 Z:E C:ROLLBACK Z:T C:COMMIT Z:I",
+            ]
+            .join(" "),
+            run(&[
+                "BEGIN",
+                "SAVEPOINT s1",
+                "SELECT nope",
+                "ROLLBACK TO SAVEPOINT s2",
+                "ROLLBACK TO SAVEPOINT s1",
+                "SELECT nope",
+                "ROLLBACK TO s1",
+                "COMMIT",
+            ])?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn protocol_limits() -> anyhow::Result<()> {
+        for (max_columns, max_field_length, expected) in [
+            (0, 10, "E:54011 Z 1 2 E:54011 Z"),
+            // The values of the rows are 1 byte long
+            (1, 0, "T E:54000 Z 1 E:54000 Z"),
+            (1, 3, "T D D D D D C Z 1 E:54000 Z"),
+        ] {
+            let mut frontend = BufWriter::new(Vec::new());
+            frontend.put_message(Query::new(String::from("SELECT n FROM t"))?)?;
+            frontend.put_message(Parse::new("", "SELECT n FROM t WHERE n = $1", vec![])?)?;
+            frontend.put_message(Bind::new(
+                "",
+                "",
+                vec![],
+                vec![ColumnData::from(b"1234".to_vec())],
+                vec![],
+            )?)?;
+            frontend.put_message(Execute::new("", 0)?)?;
+            frontend.put_message(Sync::new())?;
+            frontend.put_message(Terminate::new())?;
+            let frontend = frontend.into_inner()?;
+
+            let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+            handler.limits = ProtocolLimits {
+                max_columns,
+                max_field_length,
+            };
+            while handler.query_handler(&executor)? {}
+            let backend = handler.writer.into_inner()?;
+
+            let mut reader = BufReader::new(&backend[..]);
+            let mut received = Vec::new();
+            while let Ok(mut raw_message) = RawBackendMessage::get(&mut reader) {
+                received.push(match raw_message.header.message_type {
+                    b'E' => {
+                        let error = ErrorResponse::try_from(&mut raw_message)?;
+                        let code = error
+                            .messages
+                            .as_ref()
+                            .iter()
+                            .find(|field| field.code == b'C')
+                            .map(|field| field.message.to_string_lossy().into_owned());
+                        format!("E:{}", code.unwrap_or_default())
+                    }
+                    message_type => String::from(message_type as char),
+                });
+            }
+            assert_eq!(expected, received.join(" "));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_stream() -> anyhow::Result<()> {
+        for (policy, expected) in [
+            (DesyncPolicy::Abort, "T D D D D D C Z E"),
+            (
+                DesyncPolicy::SkipToNextMessage,
+                "T D D D D D C Z T D D D D D C Z",
+            ),
+        ] {
+            let (mut handler, mut reader, mut writer) = handler_pair()?;
+            handler.desync_policy = policy;
+            writer.put_message(Query::new(String::from("SELECT n FROM t"))?)?;
+            writer.write_all(b"garbage")?;
+            writer.put_message(Query::new(String::from("SELECT n FROM t"))?)?;
+            writer.put_message_and_flush(Terminate::new())?;
+            let result = loop {
+                match handler.query_handler(&executor) {
+                    Ok(true) => continue,
+                    result => break result,
+                }
+            };
+            drop(handler);
+
+            let mut received = Vec::new();
+            while let Ok(raw_message) = RawBackendMessage::get(&mut reader) {
+                received.push(String::from(raw_message.header.message_type as char));
+            }
+            assert_eq!(expected, received.join(" "));
+            if policy == DesyncPolicy::Abort {
+                let error = result.expect_err("desync");
+                let desync = error.downcast_ref::<ProtocolDesync>().expect("desync");
+                assert_eq!(vec!["Query ('Q'), length 20"], desync.history);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Expects two tokens, "hello" then "again"
+    struct FakeGss {
+        step: usize,
+    }
+
+    impl GssAuthenticator for FakeGss {
+        fn accept(&mut self, token: &[u8]) -> anyhow::Result<GssStep> {
+            self.step += 1;
+            match (self.step, token) {
+                (1, b"hello") => Ok(GssStep::Continue(b"challenge".to_vec())),
+                (2, b"again") => Ok(GssStep::Complete(Some(b"done".to_vec()))),
+                _ => Err(anyhow!("unexpected token")),
+            }
+        }
+    }
+
+    #[test]
+    fn gss_authentication() -> anyhow::Result<()> {
+        for (sspi, tokens, expected) in [
+            (false, vec!["hello", "again"], "challenge done Ok"),
+            (false, vec!["hello", "nope"], "challenge"),
+            (true, vec!["hello", "again"], "challenge done Ok"),
+        ] {
+            let (mut handler, mut reader, mut writer) = handler_pair()?;
+            writer.put_request(StartupMessage::new(
+                ProtocolVersion { major: 3, minor: 0 },
+                vec![ParameterStatus::new(
+                    &String::from("user"),
+                    &String::from("bob"),
+                )?],
+            ))?;
+            for token in &tokens {
+                writer.put_message(GSSResponse::new(token.as_bytes().to_vec()))?;
+            }
+            writer.flush()?;
+            let mut authenticator = FakeGss { step: 0 };
+            let result = match sspi {
+                false => handler.gss_authentication_handler(&mut authenticator),
+                true => handler.sspi_authentication_handler(&mut authenticator),
+            };
+            assert_eq!(expected.ends_with("Ok"), result.is_ok());
+            drop(handler);
+
+            let mut raw_message = reader.get_raw_backend_message()?;
+            match sspi {
+                false => AuthenticationGSS::try_from(&mut raw_message).map(|_| ())?,
+                true => AuthenticationSSPI::try_from(&mut raw_message).map(|_| ())?,
+            }
+            let mut received = Vec::new();
+            while let Ok(mut raw_message) = reader.get_raw_backend_message() {
+                match raw_message.get_auth_message_kind() {
+                    Some(AuthenticationMessageKind::GSSContinue) => {
+                        let message = AuthenticationGSSContinue::try_from(&mut raw_message)?;
+                        received.push(String::from_utf8(message.data.into())?);
+                    }
+                    Some(AuthenticationMessageKind::Ok) => received.push(String::from("Ok")),
+                    _ => (),
+                }
+            }
+            assert_eq!(expected, received.join(" "));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_level_feature_detection() -> anyhow::Result<()> {
+        assert_eq!(Some("SET"), session_level_feature("SET search_path TO app"));
+        assert_eq!(None, session_level_feature("set local search_path to app"));
+        assert_eq!(
+            Some("PREPARE"),
+            session_level_feature("PREPARE q AS SELECT 1")
+        );
+        assert_eq!(None, session_level_feature("PREPARE TRANSACTION 'tx'"));
+        assert_eq!(
+            Some("DECLARE ... WITH HOLD"),
+            session_level_feature("DECLARE c CURSOR WITH HOLD FOR SELECT 1")
+        );
+        assert_eq!(
+            Some("Session level advisory locks"),
+            session_level_feature("SELECT pg_advisory_lock(42)")
+        );
+        assert_eq!(
+            None,
+            session_level_feature("SELECT pg_advisory_xact_lock(42)")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pipelined_describe_flow() -> anyhow::Result<()> {
+        // pgJDBC writes the whole extended sequence of a PreparedStatement at
+        // once, without Flush: the reader hands every message in one read.
+        #[derive(serde::Deserialize)]
+        struct Segment {
+            source: String,
+            frontend: String,
+            backend: String,
+        }
+        let segment: Segment = toml::from_str(include_str!(
+            "../../testdata/pgjdbc_pipelined_describe.toml"
+        ))?;
+        let mut frontend = crate::wire_vectors::from_hex(&segment.frontend)?;
+        let mut terminate = BufWriter::new(Vec::new());
+        terminate.put_message(Terminate::new())?;
+        frontend.extend(terminate.into_inner()?);
+
+        // The row of n = 3 in the table of the replay
+        let executor = |_query: String| QueryResult {
+            columns: vec![ColumnDescription::new("n", PgType::Int4).expect("column")],
+            rows: vec![vec![PgValue::Int4(3)]],
+            command_tag: String::from("SELECT 1"),
+            error: None,
+        };
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        while handler.query_handler(&executor)? {}
+        let backend = handler.writer.into_inner()?;
+
+        let mut reader = BufReader::new(&backend[..]);
+        let mut received = String::new();
+        while let Ok(raw_message) = reader.get_raw_backend_message() {
+            received.push(raw_message.header.message_type as char);
+        }
+        assert_eq!(segment.backend, received, "answer of {}", segment.source);
+
+        Ok(())
+    }
+
+    #[test]
+    fn describe_statement_stats() -> anyhow::Result<()> {
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_message(Parse::new("", "SELECT n FROM t", vec![])?)?;
+        frontend.put_message(Describe::new(DescribeTarget::Statement, "")?)?;
+        frontend.put_message(Bind::new("", "", vec![], vec![], vec![])?)?;
+        frontend.put_message(Execute::new("", 0)?)?;
+        frontend.put_message(Sync::new())?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        let stats = QueryStats::default();
+        handler.stats = Some(stats.clone());
+        let runs = Mutex::new(0);
+        let executor = |query| {
+            *runs.lock().expect("runs lock") += 1;
+            executor(query)
+        };
+        while handler.query_handler(&executor)? {}
+        // Run for Describe then Execute, only Execute is recorded
+        assert_eq!(2, *runs.lock().expect("runs lock"));
+        assert_eq!(
+            vec![(String::from("select n from t"), 1)],
+            stats
+                .summary()
+                .into_iter()
+                .map(|summary| (summary.pattern, summary.count))
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn answer_portal() -> anyhow::Result<()> {
+        // Like the catalog queries of SQLx, with binary parameters
+        let mut frontend = BufWriter::new(Vec::new());
+        frontend.put_message(Parse::new(
+            "",
+            "SELECT typname FROM pg_type WHERE oid = $1",
+            vec![26],
+        )?)?;
+        frontend.put_message(Describe::new(DescribeTarget::Statement, "")?)?;
+        frontend.put_message(Bind::new(
+            "",
+            "",
+            vec![FormatCode::Binary],
+            vec![ColumnData::from(25u32.to_be_bytes().to_vec())],
+            vec![],
+        )?)?;
+        frontend.put_message(Execute::new("", 0)?)?;
+        // Not readable without a type, left to the executor
+        frontend.put_message(Parse::new("", "SELECT $1", vec![])?)?;
+        frontend.put_message(Bind::new(
+            "",
+            "",
+            vec![FormatCode::Binary],
+            vec![ColumnData::from(vec![0, 1])],
+            vec![],
+        )?)?;
+        frontend.put_message(Execute::new("", 0)?)?;
+        frontend.put_message(Sync::new())?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        handler.answer_portal = Some(Box::new(|_, parameters| {
+            let typname = match parameters {
+                [Some(oid)] if oid == "25" => PgValue::from("text"),
+                _ => PgValue::Null,
+            };
+            Some(QueryResult {
+                columns: vec![ColumnDescription::new("typname", PgType::Text).ok()?],
+                rows: vec![vec![typname]],
+                command_tag: String::from("SELECT 1"),
+                error: None,
+            })
+        }));
+        while handler.query_handler(&executor)? {}
+        let backend = handler.writer.into_inner()?;
+
+        let mut reader = BufReader::new(&backend[..]);
+        let mut received = Vec::new();
+        while let Ok(mut raw_message) = reader.get_raw_backend_message() {
+            received.push(match raw_message.header.message_type {
+                b'D' => {
+                    let row = DataRow::try_from(&mut raw_message)?;
+                    let value = row.columns.as_ref()[0].as_bytes().unwrap_or_default();
+                    format!("D:{}", String::from_utf8_lossy(value))
+                }
+                message_type => String::from(message_type as char),
+            });
+        }
+        assert_eq!(
+            "1 t T 2 D:text C 1 2 D:1 D:2 D:3 D:4 D:5 C Z",
+            received.join(" ")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn describe_portal() -> anyhow::Result<()> {
+        // The executor answers rows for any query, even the utility ones
+        let mut frontend = BufWriter::new(Vec::new());
+        for query in [
+            "SET DateStyle = German",
+            "INSERT INTO t VALUES (1) RETURNING n",
+        ] {
+            frontend.put_message(Parse::new("", query, vec![])?)?;
+            frontend.put_message(Bind::new("", "", vec![], vec![], vec![])?)?;
+            frontend.put_message(Describe::new(DescribeTarget::Portal, "")?)?;
+            frontend.put_message(Execute::new("", 0)?)?;
+        }
+        frontend.put_message(Sync::new())?;
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        while handler.query_handler(&executor)? {}
+        // Run by Execute, after the Describe
+        assert!(
+            handler
+                .session
+                .settings
+                .date_style
+                .name()
+                .starts_with("German")
+        );
+        let backend = handler.writer.into_inner()?;
+
+        let mut reader = BufReader::new(&backend[..]);
+        let mut received = String::new();
+        while let Ok(raw_message) = reader.get_raw_backend_message() {
+            received.push(raw_message.header.message_type as char);
+        }
+        assert_eq!("12nC12TDDDDDCSZ", received);
+
+        assert!(returns_no_rows("UPDATE t SET n = (SELECT 1 RETURNING)"));
+        assert!(returns_no_rows("SELECT n INTO u FROM t"));
+        assert!(!returns_no_rows(
+            "SELECT n FROM t WHERE n IN (SELECT n INTO u)"
+        ));
+        assert!(!returns_no_rows(
+            "WITH d AS (DELETE FROM t RETURNING n) SELECT n FROM d"
+        ));
+        assert!(returns_no_rows("WITH d AS (SELECT 1) DELETE FROM t"));
+        assert!(!returns_no_rows("SHOW DateStyle"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn implicit_transaction() -> anyhow::Result<()> {
+        let failing = |query: String| {
+            if query.contains("1/0") {
+                QueryResult {
+                    error: Some(PgError::new("22012", "division by zero")),
+                    ..Default::default()
+                }
+            } else {
+                executor(query)
+            }
+        };
+        let mut frontend = BufWriter::new(Vec::new());
+        for query in [
+            "SET DateStyle = German; SELECT 1/0; SELECT 3",
+            "-- nothing to run;",
+            "BEGIN; SELECT 1/0; SELECT 3",
+        ] {
+            frontend.put_message(Query::new(String::from(query))?)?;
+        }
+        frontend.put_message(Terminate::new())?;
+        let frontend = frontend.into_inner()?;
+
+        let mut handler = Handler::from_parts(&frontend[..], Vec::new());
+        while handler.query_handler(&failing)? {}
+        // The SET is rolled back with the failed statement
+        assert!(
+            !handler
+                .session
+                .settings
+                .date_style
+                .name()
+                .starts_with("German")
+        );
+        assert!(!handler.session.implicit_transaction);
+        assert_eq!(
+            TransactionIndicator::IdlerInTransactionAborted,
+            handler.session.transaction
+        );
+        let backend = handler.writer.into_inner()?;
+
+        // The backend reader stops at an ErrorResponse, the headers are
+        // read as is
+        let mut received = String::new();
+        let mut rest = &backend[..];
+        while let [message_type, a, b, c, d, ..] = rest[..] {
+            received.push(message_type as char);
+            rest = &rest[1 + u32::from_be_bytes([a, b, c, d]) as usize..];
+        }
+        assert_eq!("CEZIZCEZ", received);
+
+        Ok(())
+    }
+
+    #[test]
+    fn portal_returning() -> anyhow::Result<()> {
+        let rows = (1..=3).map(|n| vec![PgValue::Int4(n)]).collect();
+        let result = QueryResult::returning(
+            "insert",
+            vec![ColumnDescription::new("id", PgType::Int4)?],
+            rows,
+        )?;
+        assert_eq!("INSERT 0 3", result.command_tag);
+        assert!(QueryResult::returning("SELECT", vec![], vec![]).is_err());
+
+        let mut portal = Portal {
+            query: String::from("INSERT INTO t VALUES (1), (2), (3) RETURNING id"),
+            parameters: vec![],
+            result_formats: vec![],
+            result: Some(result),
+            position: 0,
+        };
+        let settings = OutputSettings::default();
+        let (data_rows, command_tag) = portal.execute(2, &settings)?;
+        assert_eq!((2, None), (data_rows.len(), command_tag));
+        // The tag counts the inserted rows, not the ones of the last fetch
+        let (data_rows, command_tag) = portal.execute(2, &settings)?;
+        assert_eq!(
+            (1, Some(String::from("INSERT 0 3"))),
+            (data_rows.len(), command_tag)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pg_prepared_statements() -> anyhow::Result<()> {
+        let mut session = Session::default();
+        let mut statement =
+            PreparedStatement::new(String::from("SELECT $1"), vec![23, 999], &Clock::Real);
+        statement.binds = 2;
+        session.statements.insert(String::from("s1"), statement);
+        session.statements.insert(
+            String::new(),
+            PreparedStatement::new(String::from("SELECT 2"), vec![], &Clock::Real),
+        );
+        let query = "SELECT name, parameter_types, custom_plans FROM pg_catalog.pg_prepared_statements WHERE name = $1";
+        let select = |session: &Session| {
+            let parameters = [Some(String::from("s1"))];
+            select_bound_view(query, &parameters, |_| {
+                Some(session.pg_prepared_statements())
+            })
+            .ok_or(anyhow!("Not a view"))?
+        };
+
+        assert_eq!(
+            vec![vec![
+                PgValue::from("s1"),
+                PgValue::from("{int4,999}"),
+                PgValue::Int8(2)
+            ]],
+            select(&session)?.rows
+        );
+        session.preprocess("DEALLOCATE s1");
+        assert!(select(&session)?.rows.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn prepare_time() -> anyhow::Result<()> {
+        let parse = |name: &str| -> anyhow::Result<Vec<u8>> {
+            let mut frontend = BufWriter::new(Vec::new());
+            frontend.put_message(Parse::new(name, "SELECT 1", vec![])?)?;
+            frontend.put_message(Sync::new())?;
+            frontend.put_message(Terminate::new())?;
+            Ok(frontend.into_inner()?)
+        };
+        let (s1, s2) = (parse("s1")?, parse("s2")?);
+        let clock = Clock::manual();
+        let mut handler = Handler::from_parts(&s1[..], Vec::new());
+        handler.clock = clock.clone();
+        while handler.query_handler(&executor)? {}
+        if let Clock::Manual(manual) = &clock {
+            manual.advance(Duration::from_secs(3600));
+        }
+        handler.reader = BufReader::new(&s2[..]);
+        while handler.query_handler(&executor)? {}
+
+        let result = select_bound_view(
+            "SELECT prepare_time FROM pg_prepared_statements ORDER BY name",
+            &[],
+            |_| Some(handler.session.pg_prepared_statements()),
+        )
+        .ok_or(anyhow!("Not a view"))??;
+        let expected = |time| PgValue::Timestamp(timestamp_from_system_time(time));
+        assert_eq!(
+            vec![
+                vec![expected(clock.system_time() - Duration::from_secs(3600))],
+                vec![expected(clock.system_time())]
+            ],
+            result.rows
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_preprocess() -> anyhow::Result<()> {
+        let mut session = Session::default();
+        session.statements.insert(
+            String::from("s1"),
+            PreparedStatement::new(String::from("SELECT 1"), vec![], &Clock::Real),
+        );
+        let command_tag = |result: Option<anyhow::Result<QueryResult>>| match result {
+            Some(Ok(result)) => Ok(result.command_tag),
+            Some(Err(e)) => Err(e),
+            None => Ok(String::from("executor")),
+        };
+
+        assert_eq!(
+            "SET",
+            command_tag(session.preprocess("SET search_path TO 'a, b'"))?
+        );
+        assert_eq!(
+            Some(&String::from("a, b")),
+            session.parameters.get("search_path")
+        );
+        assert_eq!(
+            "executor",
+            command_tag(session.preprocess("SET LOCAL x = 1"))?
+        );
+        assert_eq!("RESET", command_tag(session.preprocess("RESET ALL;"))?);
+        assert!(session.parameters.is_empty());
+
+        assert_eq!(
+            "DEALLOCATE",
+            command_tag(session.preprocess("deallocate s1"))?
+        );
+        assert!(command_tag(session.preprocess("DEALLOCATE PREPARE s1")).is_err());
+        assert_eq!(
+            "DEALLOCATE ALL",
+            command_tag(session.preprocess("DEALLOCATE ALL"))?
+        );
+        assert_eq!(
+            "DISCARD TEMP",
+            command_tag(session.preprocess("discard temporary"))?
+        );
+        assert_eq!(
+            "DISCARD PLANS",
+            command_tag(session.preprocess("DISCARD PLANS"))?
+        );
+
+        session.update_transaction("BEGIN", "BEGIN");
+        assert!(command_tag(session.preprocess("DISCARD ALL")).is_err());
+        session.update_transaction("COMMIT", "COMMIT");
+        assert_eq!(
+            "DISCARD ALL",
+            command_tag(session.preprocess("DISCARD ALL"))?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_two_phase_commit() -> anyhow::Result<()> {
+        let mut session = Session::default();
+        let mut other = Session {
+            prepared_transactions: session.prepared_transactions.clone(),
+            ..Default::default()
+        };
+        let command_tag = |result: Option<anyhow::Result<QueryResult>>| match result {
+            Some(Ok(result)) => Ok(result.command_tag),
+            Some(Err(e)) => Err(e),
+            None => Ok(String::from("executor")),
+        };
+
+        assert_eq!(
+            "ROLLBACK",
+            command_tag(session.preprocess("PREPARE TRANSACTION 'tx1'"))?
+        );
+        session.update_transaction("BEGIN", "BEGIN");
+        assert_eq!(
+            "PREPARE TRANSACTION",
+            command_tag(session.preprocess("PREPARE TRANSACTION 'tx1'"))?
+        );
+        assert_eq!(TransactionIndicator::Idle, session.transaction);
+        session.update_transaction("BEGIN", "BEGIN");
+        assert!(command_tag(session.preprocess("PREPARE TRANSACTION 'tx1'")).is_err());
+        assert_eq!(
+            vec![String::from("tx1")],
+            other.prepared_transactions.gids()
+        );
+
+        other.update_transaction("BEGIN", "BEGIN");
+        assert!(command_tag(other.preprocess("COMMIT PREPARED 'tx1'")).is_err());
+        other.update_transaction("COMMIT", "COMMIT");
+        assert_eq!(
+            "COMMIT PREPARED",
+            command_tag(other.preprocess("commit prepared 'tx1';"))?
+        );
+        assert!(command_tag(session.preprocess("ROLLBACK PREPARED 'tx1'")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn session_transaction() -> anyhow::Result<()> {
+        let mut session = Session::default();
+        session.fail();
+        assert_eq!(TransactionIndicator::Idle, session.transaction);
+
+        session.update_transaction("BEGIN", "BEGIN");
+        assert_eq!(TransactionIndicator::IdleInTransaction, session.transaction);
+        session.fail();
+        assert_eq!(
+            TransactionIndicator::IdlerInTransactionAborted,
+            session.transaction
+        );
+        session.update_transaction("ROLLBACK", "ROLLBACK");
+        assert_eq!(TransactionIndicator::Idle, session.transaction);
+
+        // Like PostgreSQL after an error in a transaction block
+        session.update_transaction("BEGIN", "BEGIN");
+        session.fail();
+        let code = |result: Option<anyhow::Result<QueryResult>>| match result {
+            Some(Err(e)) => e.downcast::<PgError>().map(|e| e.code).ok(),
+            _ => None,
+        };
+        assert_eq!(
+            Some(String::from("25P02")),
+            code(session.preprocess("SELECT 1"))
+        );
+        assert_eq!(
+            Some(String::from("25P02")),
+            code(session.preprocess("SET x = 1"))
+        );
+        assert_eq!(
+            Some(String::from("25P02")),
+            code(session.preprocess("COMMIT PREPARED 'tx1'"))
+        );
+        assert_eq!(
+            Some(String::from("3B001")),
+            code(session.preprocess("ROLLBACK TO SAVEPOINT s1"))
+        );
+        let Some(Ok(result)) = session.preprocess("commit;") else {
+            return Err(anyhow!("COMMIT refused"));
+        };
+        assert_eq!("ROLLBACK", result.command_tag);
+        session.update_transaction("commit;", &result.command_tag);
+        assert!(session.preprocess("SELECT 1").is_none());
 
         Ok(())
     }
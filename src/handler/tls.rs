@@ -0,0 +1,486 @@
+//! A protocol-aware TLS gateway: the frontend can negotiate TLS with an
+//! SSLRequest and the connection to the server can use TLS too, each side
+//! with its own settings. The server is chosen with the name the frontend
+//! sent in the SNI extension, e.g. for a lab where the frontends reach
+//! several servers through one address.
+
+use anyhow::anyhow;
+use bytes::BytesMut;
+use libpq_serde_types::Serialize;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, Connection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme,
+};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use tracing::*;
+
+use crate::handler::proxy::forward_frames;
+use crate::message::*;
+
+const SSL_REQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+
+/// A connection with or without TLS. A clone shares the connection, like
+/// TcpStream::try_clone: one thread can read while another one writes.
+#[derive(Clone)]
+pub enum Transport {
+    Plain(Arc<TcpStream>),
+    Tls(TlsStream),
+}
+
+impl Transport {
+    pub fn shutdown(&self) {
+        if let Transport::Tls(stream) = self {
+            stream.close();
+        }
+        let socket = match self {
+            Transport::Plain(socket) => socket,
+            Transport::Tls(stream) => &stream.socket,
+        };
+        let _ = socket.shutdown(Shutdown::Both);
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(socket) => socket.as_ref().read(buf),
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(socket) => socket.as_ref().write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(socket) => socket.as_ref().flush(),
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A TLS connection shared by its clones. The socket is read without the
+/// lock, the lock is only held to decrypt what was received and to encrypt
+/// what is written.
+#[derive(Clone)]
+pub struct TlsStream {
+    connection: Arc<Mutex<Connection>>,
+    socket: Arc<TcpStream>,
+}
+
+impl TlsStream {
+    /// Complete the handshake
+    fn new(connection: impl Into<Connection>, mut socket: TcpStream) -> anyhow::Result<Self> {
+        let mut connection = connection.into();
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket)?;
+        }
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            socket: Arc::new(socket),
+        })
+    }
+
+    fn connection(&self) -> io::Result<MutexGuard<'_, Connection>> {
+        self.connection
+            .lock()
+            .map_err(|_| io::Error::other("TLS lock poisoned"))
+    }
+
+    /// The name sent by the client in the SNI extension
+    pub fn server_name(&self) -> Option<String> {
+        match &*self.connection().ok()? {
+            Connection::Server(connection) => connection.server_name().map(String::from),
+            Connection::Client(_) => None,
+        }
+    }
+
+    /// Send the close_notify alert
+    fn close(&self) {
+        if let Ok(mut connection) = self.connection() {
+            connection.send_close_notify();
+            let _ = connection.write_tls(&mut self.socket.as_ref());
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Small enough for the plaintext of a read to fit in the buffer of
+        // rustls, which is empty when the socket is read
+        let mut received = [0; 4096];
+        loop {
+            match self.connection()?.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+            let count = self.socket.as_ref().read(&mut received)?;
+            if count == 0 {
+                return Ok(0);
+            }
+            let mut connection = self.connection()?;
+            let mut received = &received[..count];
+            while !received.is_empty() {
+                connection.read_tls(&mut received)?;
+                connection
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            // e.g. a key update
+            while connection.wants_write() {
+                connection.write_tls(&mut self.socket.as_ref())?;
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection()?;
+        let count = connection.writer().write(buf)?;
+        while connection.wants_write() {
+            connection.write_tls(&mut self.socket.as_ref())?;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The settings of the TLS connections accepted, from PEM files
+pub fn server_config(cert: &Path, key: &Path) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    Ok(Arc::new(
+        ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?,
+    ))
+}
+
+/// The settings of the TLS connections to the servers. Without root
+/// certificates, any certificate is accepted: the connection is encrypted
+/// but the server is not authenticated.
+pub fn client_config(roots: Option<&Path>) -> anyhow::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder();
+    let config = match roots {
+        Some(roots) => {
+            let mut store = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(roots)? {
+                store.add(cert?)?;
+            }
+            builder.with_root_certificates(store).with_no_client_auth()
+        }
+        None => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(
+                rustls::crypto::ring::default_provider(),
+            )))
+            .with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+#[derive(Debug)]
+struct AcceptAnyCertificate(rustls::crypto::CryptoProvider);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// TLS towards a server
+#[derive(Clone)]
+pub struct BackendTls {
+    pub config: Arc<ClientConfig>,
+    // Sent with SNI and checked against the certificate
+    pub server_name: ServerName<'static>,
+}
+
+/// A server of the gateway
+#[derive(Clone)]
+pub struct GatewayRoute {
+    // The SNI name selecting this server, any name when not set
+    pub server_name: Option<String>,
+    pub address: String,
+    pub tls: Option<BackendTls>,
+}
+
+/// Relays the connections after the TLS negotiations
+#[derive(Clone, Default)]
+pub struct TlsGateway {
+    // The SSLRequests are refused without it
+    pub frontend_tls: Option<Arc<ServerConfig>>,
+    // The first one matching the SNI name is used
+    pub routes: Vec<GatewayRoute>,
+}
+
+impl TlsGateway {
+    pub fn route(&self, server_name: Option<&str>) -> Option<&GatewayRoute> {
+        self.routes.iter().find(|route| match &route.server_name {
+            Some(name) => Some(&name[..]) == server_name,
+            None => true,
+        })
+    }
+
+    /// Negotiate TLS with the frontend, connect to the server of its SNI
+    /// name and relay the messages until one side closes the connection
+    pub fn serve(&self, frontend: TcpStream) -> anyhow::Result<()> {
+        let mut frontend = Transport::Plain(Arc::new(frontend));
+        let mut request = RawRequest::get(&mut BufReader::new(frontend.clone()))?;
+        let mut server_name = None;
+        while matches!(
+            request.request_kind,
+            RequestMessageKind::SSLRequest | RequestMessageKind::GSSENCRequest
+        ) {
+            let accepted = match (&request.request_kind, &self.frontend_tls, &frontend) {
+                (RequestMessageKind::SSLRequest, Some(config), Transport::Plain(socket)) => {
+                    Some((config.clone(), socket.try_clone()?))
+                }
+                _ => None,
+            };
+            match accepted {
+                Some((config, socket)) => {
+                    debug!("rcv: SSLRequest, accepted");
+                    frontend.write_all(b"S")?;
+                    let stream = TlsStream::new(ServerConnection::new(config)?, socket)?;
+                    server_name = stream.server_name();
+                    frontend = Transport::Tls(stream);
+                }
+                None => {
+                    debug!("rcv: {:?}, refused", request.request_kind);
+                    frontend.write_all(b"N")?;
+                }
+            }
+            request = RawRequest::get(&mut BufReader::new(frontend.clone()))?;
+        }
+
+        let route = self
+            .route(server_name.as_deref())
+            .ok_or(anyhow!("No server for the name {server_name:?}"))?;
+        debug!("{server_name:?} relayed to {}", route.address);
+        let socket = TcpStream::connect(&route.address)?;
+        let mut backend = match &route.tls {
+            Some(tls) => {
+                (&socket).write_all(&SSL_REQUEST)?;
+                let mut answer = [0];
+                (&socket).read_exact(&mut answer)?;
+                if answer != *b"S" {
+                    return Err(anyhow!("{} refused TLS", route.address));
+                }
+                let connection =
+                    ClientConnection::new(tls.config.clone(), tls.server_name.clone())?;
+                Transport::Tls(TlsStream::new(connection, socket)?)
+            }
+            None => Transport::Plain(Arc::new(socket)),
+        };
+        let mut buffer = BytesMut::new();
+        request.header.serialize(&mut buffer);
+        buffer.extend_from_slice(&request.raw_body);
+        backend.write_all(&buffer)?;
+
+        relay(frontend, backend)
+    }
+}
+
+/// Copy the messages both ways until a side closes the connection
+fn relay(frontend: Transport, backend: Transport) -> anyhow::Result<()> {
+    let threads = [
+        (frontend.clone(), backend.clone(), true),
+        (backend, frontend, false),
+    ]
+    .map(|(mut reader, mut writer, from_frontend)| {
+        thread::spawn(move || {
+            let result = forward_frames(&mut reader, &mut writer, from_frontend);
+            reader.shutdown();
+            writer.shutdown();
+            result
+        })
+    });
+    for (side, thread) in ["frontend", "backend"].into_iter().zip(threads) {
+        match thread.join() {
+            Ok(Err(e)) => debug!("{side} connection closed: {e}"),
+            Ok(Ok(messages)) => debug!("{side} connection closed after {messages} messages"),
+            Err(_) => error!("{side} relay thread panicked"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::probe::{self, ConnInfo};
+    use crate::scenario::Scenario;
+    use crate::test_server::TestServer;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    // Serve the connections of a listener in the background
+    fn listen(gateway: TlsGateway) -> anyhow::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?.to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let gateway = gateway.clone();
+                thread::spawn(move || gateway.serve(stream));
+            }
+        });
+        Ok(address)
+    }
+
+    #[test]
+    fn tls_gateway() -> anyhow::Result<()> {
+        let server = |n: &str| {
+            TestServer::start(
+                "127.0.0.1:0",
+                &Scenario::from_toml(&format!(
+                    r#"
+                    [[rules]]
+                    query = "SELECT n"
+                    command_tag = "SELECT 1"
+                    columns = [{{ name = "n", type_oid = 23 }}]
+                    rows = [["{n}"]]
+                    "#
+                ))?,
+            )
+        };
+        let (primary, replica) = (server("1")?, server("2")?);
+
+        // Terminates the TLS of the first gateway and picks the server with
+        // the SNI name
+        let terminating = listen(TlsGateway {
+            frontend_tls: Some(server_config(
+                &testdata("server.pem"),
+                &testdata("server.key"),
+            )?),
+            routes: vec![
+                GatewayRoute {
+                    server_name: Some(String::from("replica.test")),
+                    address: replica.address().to_string(),
+                    tls: None,
+                },
+                GatewayRoute {
+                    server_name: None,
+                    address: primary.address().to_string(),
+                    tls: None,
+                },
+            ],
+        })?;
+        let originating = |server_name: &str| {
+            listen(TlsGateway {
+                frontend_tls: None,
+                routes: vec![GatewayRoute {
+                    server_name: None,
+                    address: terminating.clone(),
+                    tls: Some(BackendTls {
+                        config: client_config(Some(&testdata("ca.pem")))?,
+                        server_name: ServerName::try_from(String::from(server_name))?,
+                    }),
+                }],
+            })
+        };
+
+        for (server_name, n) in [("replica.test", b"2"), ("primary.test", b"1")] {
+            let conninfo: ConnInfo = format!(
+                "host=127.0.0.1 port={} user=app password=secret",
+                originating(server_name)?
+                    .rsplit(':')
+                    .next()
+                    .unwrap_or_default()
+            )
+            .parse()?;
+            let mut frontend = probe::connect(&conninfo, "tls", Duration::from_secs(5))?;
+            let rows = frontend.simple_query_handler("SELECT n")?;
+            assert_eq!(Some(&n[..]), rows[0].columns.as_ref()[0].as_bytes());
+        }
+
+        // An untrusted certificate
+        let untrusted = listen(TlsGateway {
+            frontend_tls: None,
+            routes: vec![GatewayRoute {
+                server_name: None,
+                address: terminating.clone(),
+                tls: Some(BackendTls {
+                    config: Arc::new(
+                        ClientConfig::builder()
+                            .with_root_certificates(RootCertStore::empty())
+                            .with_no_client_auth(),
+                    ),
+                    server_name: ServerName::try_from("primary.test")?,
+                }),
+            }],
+        })?;
+        let conninfo: ConnInfo = format!(
+            "host=127.0.0.1 port={} user=app",
+            untrusted.rsplit(':').next().unwrap_or_default()
+        )
+        .parse()?;
+        assert!(probe::connect(&conninfo, "tls", Duration::from_secs(5)).is_err());
+
+        primary.stop()?;
+        replica.stop()
+    }
+}
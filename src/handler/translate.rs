@@ -0,0 +1,309 @@
+//! The translation of the ErrorResponses and NoticeResponses relayed by the
+//! proxy, see proxy::TcpHandler::relay_translated. A frontend can be tested
+//! against the errors of another server, e.g. the SQLSTATEs of a vendor
+//! translated to the ones of PostgreSQL, or against errors downgraded to
+//! notices.
+
+use anyhow::anyhow;
+use bytes::BytesMut;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::message::*;
+
+/// The severity of an ErrorResponse or of a NoticeResponse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Severity {
+    Error,
+    Fatal,
+    Panic,
+    Warning,
+    Notice,
+    Debug,
+    Info,
+    Log,
+}
+
+impl Severity {
+    /// Sent in an ErrorResponse, the others in a NoticeResponse
+    pub fn is_error(self) -> bool {
+        matches!(self, Severity::Error | Severity::Fatal | Severity::Panic)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "ERROR",
+            Severity::Fatal => "FATAL",
+            Severity::Panic => "PANIC",
+            Severity::Warning => "WARNING",
+            Severity::Notice => "NOTICE",
+            Severity::Debug => "DEBUG",
+            Severity::Info => "INFO",
+            Severity::Log => "LOG",
+        }
+    }
+}
+
+/// Matches the errors and the notices by SQLSTATE, by message or by both,
+/// and changes them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorTranslation {
+    // The SQLSTATE, any when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    // Searched in the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_code: Option<String>,
+    // Replaces the matches of the pattern, or the whole message without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_message: Option<String>,
+    // Also changes the type of the message, e.g. an ERROR becomes a
+    // NoticeResponse with "NOTICE"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+}
+
+/// The translations of the proxy, in a TOML file e.g.:
+///
+/// ```toml
+/// [[errors]]
+/// code = "HY000"
+/// pattern = "(?i)^lock wait timeout exceeded.*"
+/// new_code = "55P03"
+/// new_message = "canceling statement due to lock timeout"
+///
+/// [[errors]]
+/// code = "42P07"
+/// severity = "NOTICE"
+/// ```
+///
+/// The first translation matching a message is applied. A downgraded error
+/// still ends the command on the server: the frontend gets the notice and
+/// the ReadyForQuery without the rest of the answers, and the notices
+/// raised to errors don't stop the answers.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorTranslations {
+    #[serde(default)]
+    pub errors: Vec<ErrorTranslation>,
+}
+
+impl ErrorTranslations {
+    pub fn from_toml(content: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn translator(&self) -> anyhow::Result<ErrorTranslator> {
+        let translations = self
+            .errors
+            .iter()
+            .map(|translation| {
+                if translation.new_code.is_none()
+                    && translation.new_message.is_none()
+                    && translation.severity.is_none()
+                {
+                    return Err(anyhow!(
+                        "A translation must change the code, the message or the severity"
+                    ));
+                }
+                Ok(CompiledTranslation {
+                    pattern: translation.pattern.as_deref().map(Regex::new).transpose()?,
+                    translation: translation.clone(),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(ErrorTranslator { translations })
+    }
+}
+
+struct CompiledTranslation {
+    pattern: Option<Regex>,
+    translation: ErrorTranslation,
+}
+
+/// The translations ready to be applied
+pub struct ErrorTranslator {
+    translations: Vec<CompiledTranslation>,
+}
+
+impl ErrorTranslator {
+    /// The message to relay instead of an ErrorResponse or a
+    /// NoticeResponse, the other messages are returned as they are
+    pub fn translate(&self, message: RawBackendMessage) -> anyhow::Result<RawBackendMessage> {
+        // The body is still needed to relay the messages left untouched
+        let mut copy = RawBackendMessage {
+            header: MessageHeader {
+                message_type: message.header.message_type,
+                length: message.header.length,
+            },
+            raw_body: message.raw_body.clone(),
+        };
+        let mut error = matches!(
+            message.get_message_kind(),
+            Some(BackendMessageKind::ErrorResponse)
+        );
+        let mut fields: Vec<ErrorMessage> = match message.get_message_kind() {
+            Some(BackendMessageKind::ErrorResponse) => {
+                ErrorResponse::try_from(&mut copy)?.messages.into()
+            }
+            Some(BackendMessageKind::NoticeResponse) => {
+                NoticeResponse::try_from(&mut copy)?.messages.into()
+            }
+            _ => return Ok(message),
+        };
+        let field = |fields: &[ErrorMessage], code: u8| {
+            fields
+                .iter()
+                .find(|field| field.code == code)
+                .map(|field| field.message.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let (code, text) = (field(&fields, b'C'), field(&fields, b'M'));
+        let Some(CompiledTranslation {
+            pattern,
+            translation,
+        }) = self.translations.iter().find(|candidate| {
+            candidate
+                .translation
+                .code
+                .as_ref()
+                .is_none_or(|expected| *expected == code)
+                && candidate
+                    .pattern
+                    .as_ref()
+                    .is_none_or(|pattern| pattern.is_match(&text))
+        })
+        else {
+            return Ok(message);
+        };
+
+        let mut set = |code: char, value: &str| -> anyhow::Result<()> {
+            match fields.iter_mut().find(|field| field.code == code as u8) {
+                Some(field) => *field = ErrorMessage::new(code, value)?,
+                None => fields.push(ErrorMessage::new(code, value)?),
+            }
+            Ok(())
+        };
+        if let Some(new_code) = &translation.new_code {
+            set('C', new_code)?;
+        }
+        if let Some(new_message) = &translation.new_message {
+            match pattern {
+                Some(pattern) => set('M', &pattern.replace_all(&text, new_message))?,
+                None => set('M', new_message)?,
+            }
+        }
+        if let Some(severity) = translation.severity {
+            set('S', severity.as_str())?;
+            set('V', severity.as_str())?;
+            error = severity.is_error();
+        }
+
+        let mut buffer = BytesMut::new();
+        match error {
+            true => MessageHeader::serialize_message(&mut buffer, &ErrorResponse::new(fields))?,
+            false => MessageHeader::serialize_message(&mut buffer, &NoticeResponse::new(fields))?,
+        }
+        let raw_body = buffer.split_off(5).freeze();
+        Ok(RawBackendMessage {
+            header: MessageHeader {
+                message_type: if error { b'E' } else { b'N' },
+                length: MessageHeader::length_for(raw_body.len())?,
+            },
+            raw_body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::BufReader;
+
+    fn error(severity: &str, code: &str, message: &str) -> anyhow::Result<RawBackendMessage> {
+        let mut buffer = BytesMut::new();
+        MessageHeader::serialize_message(
+            &mut buffer,
+            &ErrorResponse::new(vec![
+                ErrorMessage::new('S', severity)?,
+                ErrorMessage::new('C', code)?,
+                ErrorMessage::new('M', message)?,
+            ]),
+        )?;
+        RawBackendMessage::get(&mut BufReader::new(&buffer[..]))
+    }
+
+    // The type, the SQLSTATE and the message
+    fn fields(mut message: RawBackendMessage) -> anyhow::Result<(char, String, String)> {
+        let message_type = message.header.message_type as char;
+        let fields: Vec<ErrorMessage> = match message_type {
+            'E' => ErrorResponse::try_from(&mut message)?.messages.into(),
+            _ => NoticeResponse::try_from(&mut message)?.messages.into(),
+        };
+        let field = |code: u8| {
+            fields
+                .iter()
+                .find(|field| field.code == code)
+                .map(|field| field.message.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        Ok((message_type, field(b'C'), field(b'M')))
+    }
+
+    #[test]
+    fn error_translations() -> anyhow::Result<()> {
+        let translator = ErrorTranslations::from_toml(
+            r#"
+            [[errors]]
+            code = "HY000"
+            pattern = "^Lock wait timeout exceeded on (\\w+)$"
+            new_code = "55P03"
+            new_message = "canceling statement due to lock timeout on $1"
+
+            [[errors]]
+            code = "42P07"
+            severity = "NOTICE"
+            "#,
+        )?
+        .translator()?;
+
+        let translated = translator.translate(error(
+            "ERROR",
+            "HY000",
+            "Lock wait timeout exceeded on orders",
+        )?)?;
+        assert_eq!(
+            (
+                'E',
+                String::from("55P03"),
+                String::from("canceling statement due to lock timeout on orders")
+            ),
+            fields(translated)?
+        );
+
+        let downgraded =
+            translator.translate(error("ERROR", "42P07", "relation \"t\" already exists")?)?;
+        assert_eq!('N', downgraded.header.message_type as char);
+
+        // Left untouched
+        let untouched = translator.translate(error("ERROR", "HY000", "Out of memory")?)?;
+        assert_eq!(
+            error("ERROR", "HY000", "Out of memory")?.raw_body,
+            untouched.raw_body
+        );
+
+        let invalid = ErrorTranslations::from_toml("[[errors]]\ncode = \"42P07\"")?;
+        assert!(invalid.translator().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! Tunnel the messages over WebSocket, like the proxies of some cloud
+//! providers. The messages are a stream of bytes carried by binary frames:
+//! a message can span several frames and a frame can hold several messages,
+//! the handlers read them like on a TcpStream.
+
+use anyhow::anyhow;
+use bytes::{Buf, Bytes};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::{Message, WebSocket};
+
+use crate::handler::{client, server};
+
+/// One half of a WebSocket connection, both halves share the socket: a read
+/// blocks the writes of the other half until a frame is received.
+pub struct WebSocketStream {
+    socket: Arc<Mutex<WebSocket<TcpStream>>>,
+    // The rest of the last frame received
+    pending: Bytes,
+}
+
+impl WebSocketStream {
+    fn split(socket: WebSocket<TcpStream>) -> (Self, Self) {
+        let socket = Arc::new(Mutex::new(socket));
+        (
+            Self {
+                socket: socket.clone(),
+                pending: Bytes::new(),
+            },
+            Self {
+                socket,
+                pending: Bytes::new(),
+            },
+        )
+    }
+
+    fn socket(&self) -> io::Result<std::sync::MutexGuard<'_, WebSocket<TcpStream>>> {
+        self.socket
+            .lock()
+            .map_err(|_| io::Error::other("WebSocket lock poisoned"))
+    }
+}
+
+fn io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            let message = match self.socket()?.read() {
+                Ok(message) => message,
+                // The end of the stream
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                    return Ok(0);
+                }
+                Err(e) => return Err(io_error(e)),
+            };
+            match message {
+                Message::Binary(data) => self.pending = data,
+                Message::Close(_) => return Ok(0),
+                Message::Text(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Text frame in a WebSocket tunnel",
+                    ));
+                }
+                // The pings are answered by tungstenite
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => (),
+            }
+        }
+        let size = buf.len().min(self.pending.len());
+        self.pending.copy_to_slice(&mut buf[..size]);
+        Ok(size)
+    }
+}
+
+impl Write for WebSocketStream {
+    /// One binary frame per write, the handlers write through a BufWriter
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket()?
+            .send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(io_error)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket()?.flush().map_err(io_error)
+    }
+}
+
+pub struct WebSocketListener {
+    listener: TcpListener,
+}
+
+impl WebSocketListener {
+    pub fn bind<A>(address: A) -> anyhow::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        Ok(Self {
+            listener: TcpListener::bind(address)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Wait for a connection and complete the WebSocket handshake
+    pub fn accept(&self) -> anyhow::Result<server::Handler<WebSocketStream, WebSocketStream>> {
+        let (stream, _) = self.listener.accept()?;
+        let socket =
+            tungstenite::accept(stream).map_err(|e| anyhow!("WebSocket handshake: {e}"))?;
+        let (reader, writer) = WebSocketStream::split(socket);
+        Ok(server::Handler::from_parts(reader, writer))
+    }
+}
+
+/// Connect to a ws:// URL, there is no TLS support
+pub fn connect(url: &str) -> anyhow::Result<client::Handler<WebSocketStream, WebSocketStream>> {
+    let request = url.into_client_request()?;
+    let uri = request.uri();
+    if uri.scheme_str() != Some("ws") {
+        return Err(anyhow!("Only ws:// URLs are supported: {url}"));
+    }
+    let host = uri.host().ok_or(anyhow!("No host in {url}"))?;
+    let stream = TcpStream::connect((host, uri.port_u16().unwrap_or(80)))?;
+    let (socket, _) =
+        tungstenite::client(request, stream).map_err(|e| anyhow!("WebSocket handshake: {e}"))?;
+    let (reader, writer) = WebSocketStream::split(socket);
+    Ok(client::Handler::from_parts(reader, writer))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::columns;
+    use crate::handler::LibPqWriter;
+    use crate::handler::server::QueryResult;
+    use crate::message::Terminate;
+    use crate::value::PgValue;
+    use std::thread;
+
+    #[test]
+    fn websocket_tunnel() -> anyhow::Result<()> {
+        let listener = WebSocketListener::bind("127.0.0.1:0")?;
+        let url = format!("ws://{}/", listener.local_addr()?);
+
+        let server = thread::spawn(move || -> anyhow::Result<()> {
+            let mut handler = listener.accept()?;
+            handler.md5_authentication_handler(&|| true)?;
+            let executor = |_query: String| QueryResult {
+                columns: columns![("n", Int4)].expect("columns"),
+                // Larger than a frame written by the BufWriter
+                rows: (0..2000).map(|n| vec![PgValue::Int4(n)]).collect(),
+                command_tag: String::from("SELECT 2000"),
+                error: None,
+            };
+            while handler.query_handler(&executor)? {}
+            Ok(())
+        });
+
+        let mut handler = connect(&url)?;
+        handler.md5_authentication_handler()?;
+        assert_eq!(2000, handler.simple_query_handler("SELECT n FROM t")?.len());
+        handler.writer.put_message_and_flush(Terminate::new())?;
+        server.join().expect("server thread")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Display bytes like `hexdump -C`: offset, bytes in hex and printable ASCII
+pub struct Hexdump<'a> {
+    data: &'a [u8],
+    // Added to the offsets printed, to show where the bytes are in a message
+    offset: usize,
+}
+
+impl<'a> Hexdump<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    pub fn with_offset(data: &'a [u8], offset: usize) -> Self {
+        Self { data, offset }
+    }
+
+    /// One line per chunk of 16 bytes, the annotation is written after the
+    /// first line
+    fn write_lines(&self, f: &mut fmt::Formatter<'_>, annotation: &str) -> fmt::Result {
+        for (index, chunk) in self.data.chunks(BYTES_PER_LINE).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| match b {
+                    0x20..=0x7e => char::from(b),
+                    _ => '.',
+                })
+                .collect();
+            write!(
+                f,
+                "{:04x}  {:<width$}  ",
+                self.offset + index * BYTES_PER_LINE,
+                hex.join(" "),
+                width = BYTES_PER_LINE * 3 - 1
+            )?;
+            if index == 0 && !annotation.is_empty() {
+                writeln!(f, "{ascii:<BYTES_PER_LINE$}  {annotation}")?;
+            } else {
+                writeln!(f, "{ascii}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_lines(f, "")
+    }
+}
+
+/// Display a message split in its type, length and body, each part
+/// annotated with its meaning
+pub struct AnnotatedMessage<'a> {
+    pub name: String,
+    pub message_type: u8,
+    pub length: i32,
+    pub body: &'a [u8],
+}
+
+impl fmt::Display for AnnotatedMessage<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ('{}')", self.name, char::from(self.message_type))?;
+        Hexdump::new(&[self.message_type]).write_lines(f, "type")?;
+        Hexdump::with_offset(&self.length.to_be_bytes(), 1)
+            .write_lines(f, &format!("length: {}", self.length))?;
+        if (self.length - 4) as usize != self.body.len() {
+            writeln!(
+                f,
+                "      length mismatch: the body has {} bytes",
+                self.body.len()
+            )?;
+        }
+        Hexdump::with_offset(self.body, 5).write_lines(f, "body")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn annotated_message() -> anyhow::Result<()> {
+        let message = AnnotatedMessage {
+            name: String::from("CommandComplete"),
+            message_type: b'C',
+            length: 13,
+            body: b"SELECT 1\0",
+        };
+
+        assert_eq!(
+            "CommandComplete ('C')
+0000  43                                               C                 type
+0001  00 00 00 0d                                      ....              length: 13
+0005  53 45 4c 45 43 54 20 31 00                       SELECT 1.         body
+",
+            message.to_string()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use serde_json::Value;
+
+/// Write a document like the output function of jsonb: the keys of the
+/// objects are sorted by length then bytewise, and the separators are
+/// followed by a space.
+pub fn jsonb_to_string(value: &Value) -> String {
+    let mut out = String::new();
+    write_jsonb(&mut out, value);
+    out
+}
+
+fn write_jsonb(out: &mut String, value: &Value) {
+    match value {
+        Value::Array(values) => {
+            out.push('[');
+            for (index, value) in values.iter().enumerate() {
+                if index > 0 {
+                    out.push_str(", ");
+                }
+                write_jsonb(out, value);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then(a.cmp(b)));
+
+            out.push('{');
+            for (index, (key, value)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    out.push_str(", ");
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push_str(": ");
+                write_jsonb(out, value);
+            }
+            out.push('}');
+        }
+        // The scalars are written like serde_json does
+        _ => out.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn jsonb_output() -> anyhow::Result<()> {
+        // SELECT '{"bb": [1, "x\n"], "a": null, "c": {"z": true}}'::jsonb
+        let value = json!({"bb": [1, "x\n"], "a": null, "c": {"z": true}});
+        assert_eq!(
+            r#"{"a": null, "c": {"z": true}, "bb": [1, "x\n"]}"#,
+            jsonb_to_string(&value)
+        );
+
+        Ok(())
+    }
+}
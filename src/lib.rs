@@ -1,2 +1,67 @@
+#[cfg(feature = "server")]
+pub mod access;
+#[cfg(feature = "server")]
+pub mod anonymizer;
+#[cfg(all(feature = "server", not(target_family = "wasm")))]
+pub mod bench;
+#[cfg(feature = "server")]
+pub mod capture;
+#[cfg(feature = "server")]
+pub mod capture_file;
+#[cfg(feature = "catalog")]
+pub mod catalog;
+pub mod compression;
+#[cfg(feature = "server")]
+pub mod config;
+pub mod corruption;
+pub mod datetime;
+#[cfg(feature = "server")]
+pub mod drivers;
+pub mod dual_stack;
+pub mod encoding;
+pub mod error;
+#[cfg(feature = "server")]
+pub mod events;
+#[cfg(feature = "server")]
+pub mod executor;
+#[cfg(feature = "server")]
+pub mod functions;
+pub mod gss;
 pub mod handler;
+pub mod hexdump;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "server")]
+pub mod matcher;
 pub mod message;
+pub mod numeric;
+#[cfg(feature = "server")]
+pub mod pcap;
+pub mod protocol;
+pub mod proxy_protocol;
+pub mod random;
+#[cfg(feature = "server")]
+pub mod rate_limiter;
+#[cfg(feature = "proxy")]
+pub mod recorder;
+#[cfg(feature = "server")]
+pub mod scenario;
+#[cfg(feature = "server")]
+pub mod schedule;
+#[cfg(feature = "auth")]
+pub mod scram;
+#[cfg(feature = "server")]
+pub mod sessions;
+#[cfg(feature = "server")]
+pub mod simulation;
+#[cfg(feature = "server")]
+pub mod stats;
+#[cfg(feature = "server")]
+pub mod test_server;
+pub mod validator;
+pub mod value;
+
+#[cfg(test)]
+mod trace_snapshots;
+#[cfg(test)]
+mod wire_vectors;
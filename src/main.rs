@@ -0,0 +1,511 @@
+use anyhow::anyhow;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tracing::*;
+
+use fakepostmaster::bench;
+use fakepostmaster::capture::Capture;
+use fakepostmaster::config::ServerConfig;
+use fakepostmaster::dual_stack;
+use fakepostmaster::handler::probe::{self, ConnInfo};
+use fakepostmaster::matcher::split_statements;
+use fakepostmaster::pcap;
+use fakepostmaster::test_server::TestServer;
+
+const USAGE: &str = "Usage: fakepostmaster serve [--config FILE] [--listen ADDRESS | --listen-fd FD] [--scenario FILE] [--health-port PORT]
+       fakepostmaster trace dump [--message NAME,...] [--session ID] [--format trace|json|binary] [--port PORT] CAPTURE
+       fakepostmaster probe [--timeout MS] CONNINFO
+       fakepostmaster bench --conninfo CONNINFO --query FILE [--concurrency N] [--duration SECONDS]
+
+The options can also be set with the environment variables
+FAKEPOSTMASTER_CONFIG, FAKEPOSTMASTER_LISTEN (default 0.0.0.0:5432),
+FAKEPOSTMASTER_LISTEN_FD, FAKEPOSTMASTER_SCENARIO,
+FAKEPOSTMASTER_HEALTH_PORT and FAKEPOSTMASTER_LOG_LEVEL (default info), they
+override the configuration file and the command line wins.
+
+With --listen-fd the server accepts the connections of a socket inherited
+from the parent process, with systemd socket activation (LISTEN_FDS) the
+first socket passed is used.
+
+An ADDRESS with a name is bound on each of its addresses, e.g. 127.0.0.1 and
+::1 for localhost:5432, and *:5432 is any interface in IPv4 and IPv6.
+
+trace dump prints the messages of a capture, binary or JSON lines, like
+PQtrace, or converts it to JSON lines or to the binary format. The messages
+can be filtered by name (e.g. Query,ErrorResponse) and by session. A pcap
+file is imported with a session per connection to the server PORT (default
+5432), the sessions are listed on the standard error.
+
+probe connects to a server like a client and reports its answers to the
+encryption requests, the authentication it asks for and the parameters it
+reports, e.g. probe \"host=db port=5432 user=app password=secret\". No
+query is sent.
+
+bench runs the statements of FILE in a loop on N connections (default 1)
+for the duration (default 10 seconds), then prints the transactions per
+second and the latency percentiles of each statement.";
+
+#[derive(Debug, PartialEq)]
+struct Config {
+    // See fakepostmaster::config for the format
+    config: Option<PathBuf>,
+    listen: Option<String>,
+    // A listening socket inherited from the parent process, instead of listen
+    listen_fd: Option<i32>,
+    scenario: Option<PathBuf>,
+    // GET /healthz answers 200 on this port when set
+    health_port: Option<u16>,
+    log_level: Option<Level>,
+}
+
+impl Config {
+    fn new(var: &dyn Fn(&str) -> Option<String>, args: &[String]) -> anyhow::Result<Self> {
+        let mut config = Self {
+            config: var("FAKEPOSTMASTER_CONFIG").map(PathBuf::from),
+            listen: var("FAKEPOSTMASTER_LISTEN"),
+            listen_fd: var("FAKEPOSTMASTER_LISTEN_FD")
+                .map(|fd| fd.parse())
+                .transpose()?,
+            scenario: var("FAKEPOSTMASTER_SCENARIO").map(PathBuf::from),
+            health_port: var("FAKEPOSTMASTER_HEALTH_PORT")
+                .map(|port| port.parse())
+                .transpose()?,
+            log_level: var("FAKEPOSTMASTER_LOG_LEVEL")
+                .map(|level| level.parse())
+                .transpose()?,
+        };
+
+        let mut args = args.iter();
+        match args.next().map(|command| &command[..]) {
+            Some("serve") => (),
+            _ => return Err(anyhow!("{USAGE}")),
+        }
+        while let Some(option) = args.next() {
+            let value = args
+                .next()
+                .ok_or(anyhow!("Missing value for {option}\n\n{USAGE}"))?;
+            match &option[..] {
+                "--config" => config.config = Some(PathBuf::from(value)),
+                "--listen" => config.listen = Some(value.clone()),
+                "--listen-fd" => config.listen_fd = Some(value.parse()?),
+                "--scenario" => config.scenario = Some(PathBuf::from(value)),
+                "--health-port" => config.health_port = Some(value.parse()?),
+                _ => return Err(anyhow!("Unknown option {option}\n\n{USAGE}")),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum DumpFormat {
+    // The output of PQtrace
+    Trace,
+    Json,
+    Binary,
+}
+
+#[derive(Debug, PartialEq)]
+struct DumpConfig {
+    capture: PathBuf,
+    // All the messages when empty
+    messages: Vec<String>,
+    session: Option<u32>,
+    format: DumpFormat,
+    // The port of the server in a pcap file
+    port: u16,
+}
+
+impl DumpConfig {
+    /// The arguments after "trace"
+    fn new(args: &[String]) -> anyhow::Result<Self> {
+        let mut args = args.iter();
+        match args.next().map(|command| &command[..]) {
+            Some("dump") => (),
+            _ => return Err(anyhow!("{USAGE}")),
+        }
+        let mut capture = None;
+        let mut config = Self {
+            capture: PathBuf::new(),
+            messages: Vec::new(),
+            session: None,
+            format: DumpFormat::Trace,
+            port: 5432,
+        };
+        while let Some(option) = args.next() {
+            if !option.starts_with("--") {
+                capture = Some(PathBuf::from(option));
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or(anyhow!("Missing value for {option}\n\n{USAGE}"))?;
+            match &option[..] {
+                "--message" => config.messages = value.split(',').map(String::from).collect(),
+                "--session" => config.session = Some(value.parse()?),
+                "--port" => config.port = value.parse()?,
+                "--format" => {
+                    config.format = match &value[..] {
+                        "trace" => DumpFormat::Trace,
+                        "json" => DumpFormat::Json,
+                        "binary" => DumpFormat::Binary,
+                        _ => return Err(anyhow!("Unknown format {value}\n\n{USAGE}")),
+                    }
+                }
+                _ => return Err(anyhow!("Unknown option {option}\n\n{USAGE}")),
+            }
+        }
+        config.capture = capture.ok_or(anyhow!("Missing capture file\n\n{USAGE}"))?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ProbeConfig {
+    conninfo: ConnInfo,
+    // For each connection and each answer
+    timeout: Duration,
+}
+
+impl ProbeConfig {
+    /// The arguments after "probe"
+    fn new(args: &[String]) -> anyhow::Result<Self> {
+        let mut conninfo = None;
+        let mut timeout = Duration::from_secs(5);
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            if !option.starts_with("--") {
+                conninfo = Some(option.parse()?);
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or(anyhow!("Missing value for {option}\n\n{USAGE}"))?;
+            match &option[..] {
+                "--timeout" => timeout = Duration::from_millis(value.parse()?),
+                _ => return Err(anyhow!("Unknown option {option}\n\n{USAGE}")),
+            }
+        }
+        Ok(Self {
+            conninfo: conninfo.ok_or(anyhow!("Missing connection string\n\n{USAGE}"))?,
+            timeout,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct BenchConfig {
+    conninfo: ConnInfo,
+    query: PathBuf,
+    concurrency: usize,
+    duration: Duration,
+}
+
+impl BenchConfig {
+    /// The arguments after "bench"
+    fn new(args: &[String]) -> anyhow::Result<Self> {
+        let (mut conninfo, mut query) = (None, None);
+        let mut concurrency = 1;
+        let mut duration = Duration::from_secs(10);
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            let value = args
+                .next()
+                .ok_or(anyhow!("Missing value for {option}\n\n{USAGE}"))?;
+            match &option[..] {
+                "--conninfo" => conninfo = Some(value.parse()?),
+                "--query" => query = Some(PathBuf::from(value)),
+                "--concurrency" => concurrency = value.parse()?,
+                "--duration" => duration = Duration::from_secs(value.parse()?),
+                _ => return Err(anyhow!("Unknown option {option}\n\n{USAGE}")),
+            }
+        }
+        Ok(Self {
+            conninfo: conninfo.ok_or(anyhow!("Missing --conninfo\n\n{USAGE}"))?,
+            query: query.ok_or(anyhow!("Missing --query\n\n{USAGE}"))?,
+            concurrency,
+            duration,
+        })
+    }
+}
+
+/// Write the filtered capture on the standard output, with the session
+/// before each message of the trace when there are several
+fn dump(config: &DumpConfig) -> anyhow::Result<()> {
+    let bytes = fs::read(&config.capture)?;
+    let mut capture = match pcap::is_pcap(&bytes) {
+        true => {
+            let (capture, connections) = pcap::import(&bytes, config.port)
+                .map_err(|e| anyhow!("{}: {e}", config.capture.display()))?;
+            for connection in connections {
+                eprintln!(
+                    "session {}: {} -> {}",
+                    connection.session, connection.client, connection.server
+                );
+            }
+            capture
+        }
+        false => Capture::load(&config.capture)?,
+    };
+    let several_sessions = capture.sessions().len() > 1;
+    capture.messages.retain(|message| {
+        config
+            .session
+            .is_none_or(|session| session == message.session)
+            && (config.messages.is_empty() || config.messages.contains(&message.decode().0))
+    });
+
+    let mut stdout = std::io::stdout().lock();
+    match config.format {
+        DumpFormat::Trace => {
+            for message in &capture.messages {
+                if several_sessions {
+                    write!(stdout, "{}\t", message.session)?;
+                }
+                writeln!(stdout, "{}", message.trace())?;
+            }
+        }
+        DumpFormat::Json => stdout.write_all(capture.to_json_lines()?.as_bytes())?,
+        DumpFormat::Binary => stdout.write_all(&capture.to_bytes())?,
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// The status line and the body answering the first line of a request
+fn health_response(request_line: &str) -> (&'static str, &'static str) {
+    match request_line.split(' ').take(2).collect::<Vec<&str>>()[..] {
+        ["GET", "/healthz"] => ("200 OK", "ok\n"),
+        _ => ("404 Not Found", "not found\n"),
+    }
+}
+
+fn health_check(stream: TcpStream) -> anyhow::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let (status, body) = health_response(request_line.trim_end());
+    write!(
+        &stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// --listen or else the listeners of the configuration file, 0.0.0.0:5432
+/// without either. Each address of a name is bound, see dual_stack::bind.
+fn bind(config: &Config, server_config: &ServerConfig) -> anyhow::Result<Vec<TcpListener>> {
+    let addresses = match (&config.listen, &server_config.listeners[..]) {
+        (None, []) => vec!["0.0.0.0:5432"],
+        (None, listeners) => listeners
+            .iter()
+            .map(|listener| &listener.address[..])
+            .collect(),
+        (Some(listen), _) => vec![&listen[..]],
+    };
+    let mut listeners = Vec::new();
+    for address in addresses {
+        listeners.extend(dual_stack::bind(address)?);
+    }
+    Ok(listeners)
+}
+
+#[cfg(unix)]
+fn listeners(config: &Config, server_config: &ServerConfig) -> anyhow::Result<Vec<TcpListener>> {
+    use fakepostmaster::test_server::{listen_fds, listener_from_fd};
+
+    if let Some(fd) = config.listen_fd {
+        // The harness hands over the socket, nothing else uses it
+        return Ok(vec![unsafe { listener_from_fd(fd) }?]);
+    }
+    match listen_fds()?.into_iter().next() {
+        Some(listener) => Ok(vec![listener]),
+        None => bind(config, server_config),
+    }
+}
+
+#[cfg(not(unix))]
+fn listeners(config: &Config, server_config: &ServerConfig) -> anyhow::Result<Vec<TcpListener>> {
+    if config.listen_fd.is_some() {
+        return Err(anyhow!("--listen-fd is only supported on Unix"));
+    }
+    bind(config, server_config)
+}
+
+// Exit on SIGTERM: as PID 1 in a container the default action is ignored
+#[cfg(unix)]
+fn exit_on_signals() -> anyhow::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+
+    let mut signals = signal_hook::iterator::Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {signal}, exiting");
+            std::process::exit(0);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn exit_on_signals() -> anyhow::Result<()> {
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|command| command == "trace") {
+        return dump(&DumpConfig::new(&args[1..])?);
+    }
+    if args.first().is_some_and(|command| command == "probe") {
+        let config = ProbeConfig::new(&args[1..])?;
+        print!("{}", probe::probe(&config.conninfo, config.timeout)?);
+        return Ok(());
+    }
+    if args.first().is_some_and(|command| command == "bench") {
+        let config = BenchConfig::new(&args[1..])?;
+        let script = fs::read_to_string(&config.query)?;
+        let statements: Vec<String> = split_statements(&script)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let report = bench::run(
+            &config.conninfo,
+            &statements,
+            config.concurrency,
+            config.duration,
+        )?;
+        print!("{report}");
+        return Ok(());
+    }
+    let config = Config::new(&|name| std::env::var(name).ok(), &args)?;
+    let mut server_config = match &config.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    if let Some(path) = &config.scenario {
+        server_config.scenarios = vec![path.clone()];
+    }
+
+    tracing_subscriber::fmt()
+        .with_max_level(
+            config
+                .log_level
+                .or(server_config.logging.level())
+                .unwrap_or(Level::INFO),
+        )
+        .compact()
+        .init();
+    exit_on_signals()?;
+
+    let mut servers = Vec::new();
+    for listener in listeners(&config, &server_config)? {
+        #[allow(unused_mut)]
+        let mut server = TestServer::from_config(listener, &server_config)?;
+        // The rules of several files can't be reloaded one by one
+        #[cfg(feature = "hot-reload")]
+        if let [path] = &server_config.scenarios[..] {
+            server.watch_scenario(path)?;
+            info!("Watching {}", path.display());
+        }
+        servers.push(server);
+    }
+
+    match config.health_port {
+        Some(port) => {
+            let listener = TcpListener::bind(("0.0.0.0", port))?;
+            info!("Health check on http://0.0.0.0:{port}/healthz");
+            for stream in listener.incoming() {
+                if let Err(e) = stream.map_err(anyhow::Error::from).and_then(health_check) {
+                    warn!("Health check failed: {e}");
+                }
+            }
+        }
+        None => loop {
+            thread::park();
+        },
+    }
+    servers.into_iter().try_for_each(TestServer::stop)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serve_config() -> anyhow::Result<()> {
+        let var = |name: &str| match name {
+            "FAKEPOSTMASTER_LISTEN" => Some(String::from("127.0.0.1:5433")),
+            "FAKEPOSTMASTER_HEALTH_PORT" => Some(String::from("8080")),
+            _ => None,
+        };
+        let args: Vec<String> = ["serve", "--health-port", "9090", "--scenario", "s.toml"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(
+            Config {
+                config: None,
+                listen: Some(String::from("127.0.0.1:5433")),
+                listen_fd: None,
+                scenario: Some(PathBuf::from("s.toml")),
+                health_port: Some(9090),
+                log_level: None,
+            },
+            Config::new(&var, &args)?
+        );
+        assert!(Config::new(&var, &[]).is_err());
+        assert!(Config::new(&var, &args[..2]).is_err());
+
+        let args: Vec<String> = ["dump", "--message", "Query,DataRow", "capture.bin"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(
+            DumpConfig {
+                capture: PathBuf::from("capture.bin"),
+                messages: vec![String::from("Query"), String::from("DataRow")],
+                session: None,
+                format: DumpFormat::Trace,
+                port: 5432,
+            },
+            DumpConfig::new(&args)?
+        );
+        assert!(DumpConfig::new(&args[..3]).is_err());
+
+        let args = [
+            String::from("--timeout"),
+            String::from("100"),
+            String::from("port=5433"),
+        ];
+        let config = ProbeConfig::new(&args)?;
+        assert_eq!(Duration::from_millis(100), config.timeout);
+        assert_eq!(5433, config.conninfo.port);
+        assert!(ProbeConfig::new(&args[..2]).is_err());
+
+        let args: Vec<String> = [
+            "--conninfo",
+            "port=5433",
+            "--query",
+            "q.sql",
+            "--concurrency",
+            "8",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let config = BenchConfig::new(&args)?;
+        assert_eq!(8, config.concurrency);
+        assert_eq!(Duration::from_secs(10), config.duration);
+        assert!(BenchConfig::new(&args[..2]).is_err());
+
+        assert_eq!("200 OK", health_response("GET /healthz HTTP/1.1").0);
+        assert_eq!("404 Not Found", health_response("GET / HTTP/1.1").0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,476 @@
+use anyhow::anyhow;
+use regex::Regex;
+
+/// A piece of query text, as seen by the matcher
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    // Keywords and unquoted identifiers, lowercased
+    Word(String),
+    // Quoted identifiers, case is kept
+    QuotedIdentifier(String),
+    Number(String),
+    String(String),
+    // $1, $2, ... in a query with their number, also `?` in a pattern
+    Placeholder(Option<usize>),
+    Symbol(char),
+}
+
+impl Token {
+    /// Values that a placeholder in a pattern stands for
+    fn is_value(&self) -> bool {
+        matches!(
+            self,
+            Token::Number(_) | Token::String(_) | Token::Placeholder(_)
+        )
+    }
+}
+
+fn tokenize(query: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            // The comments are whitespace, the block comments nest
+            '-' if chars.peek() == Some(&'-') => while chars.next_if(|d| *d != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let (mut depth, mut previous) = (1, ' ');
+                for d in chars.by_ref() {
+                    match (previous, d) {
+                        ('*', '/') => depth -= 1,
+                        ('/', '*') => depth += 1,
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    previous = if (previous, d) == ('/', '*') { ' ' } else { d };
+                }
+            }
+            '\'' | '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        // A doubled quote is an escaped quote
+                        Some(q) if q == c && chars.peek() == Some(&c) => {
+                            chars.next();
+                            value.push(q);
+                        }
+                        Some(q) if q == c => break,
+                        Some(q) => value.push(q),
+                        None => return Err(anyhow!("Unterminated quote in query: {query}")),
+                    }
+                }
+                tokens.push(if c == '\'' {
+                    Token::String(value)
+                } else {
+                    Token::QuotedIdentifier(value)
+                });
+            }
+            '$' if chars.peek().is_some_and(|d| d.is_ascii_digit()) => {
+                let mut number = String::new();
+                while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+                    number.push(d);
+                }
+                tokens.push(Token::Placeholder(number.parse().ok()));
+            }
+            '?' => tokens.push(Token::Placeholder(None)),
+            c if c.is_ascii_digit() => {
+                let mut value = String::from(c);
+                while let Some(d) = chars.next_if(|d| d.is_ascii_digit() || *d == '.') {
+                    value.push(d);
+                }
+                tokens.push(Token::Number(value));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut value = String::from(c);
+                while let Some(d) = chars.next_if(|d| d.is_alphanumeric() || *d == '_' || *d == '$')
+                {
+                    value.push(d);
+                }
+                tokens.push(Token::Word(value.to_lowercase()));
+            }
+            c => tokens.push(Token::Symbol(c)),
+        }
+    }
+
+    // A trailing semicolon doesn't change the query
+    if tokens.last() == Some(&Token::Symbol(';')) {
+        tokens.pop();
+    }
+    Ok(tokens)
+}
+
+/// Rebuild a query from its tokens, with single spaces between them
+fn to_text(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    let mut placeholder = 0;
+    for token in tokens {
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        match token {
+            Token::Word(value) | Token::Number(value) => text.push_str(value),
+            Token::QuotedIdentifier(value) => {
+                text.push_str(&format!("\"{}\"", value.replace('"', "\"\"")))
+            }
+            Token::String(value) => text.push_str(&format!("'{}'", value.replace('\'', "''"))),
+            Token::Placeholder(_) => {
+                placeholder += 1;
+                text.push_str(&format!("${placeholder}"));
+            }
+            Token::Symbol(c) => text.push(*c),
+        }
+    }
+    text
+}
+
+/// Normalize a query so that queries differing only in case, whitespace,
+/// comments or placeholder numbering give the same text.
+///
+/// String literals and quoted identifiers are kept as is.
+pub fn normalize(query: &str) -> anyhow::Result<String> {
+    Ok(to_text(&tokenize(query)?))
+}
+
+/// The words, literals and symbols of a normalized query, see normalize,
+/// with its parameters `$1`, `$2`... replaced by the literals of their
+/// values, NULL for None. `None` when a parameter has no value.
+pub fn bound_tokens(
+    query: &str,
+    parameters: &[Option<String>],
+) -> anyhow::Result<Option<Vec<String>>> {
+    let mut bound = Vec::new();
+    for token in tokenize(query)? {
+        let token = match token {
+            Token::Placeholder(number) => {
+                match number.and_then(|n| parameters.get(n.checked_sub(1)?)) {
+                    Some(Some(value)) => Token::String(value.clone()),
+                    Some(None) => Token::Word(String::from("null")),
+                    None => return Ok(None),
+                }
+            }
+            token => token,
+        };
+        bound.push(to_text(&[token]));
+    }
+    Ok(Some(bound))
+}
+
+/// Replace the literals of a query by placeholders, to share it without its
+/// data: the strings (also dollar-quoted) become '?' and the numbers 0, the
+/// comments are emptied. Everything else is kept as is, the parameters `$1`
+/// included.
+pub fn redact_literals(query: &str) -> String {
+    let mut text = String::with_capacity(query.len());
+    let mut chars = query.chars().peekable();
+    // Within a word, e.g. t1, a digit is not a number
+    let mut in_word = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                loop {
+                    match chars.next() {
+                        // A doubled quote is an escaped quote
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                        }
+                        Some('\'') | None => break,
+                        Some(_) => (),
+                    }
+                }
+                text.push_str("'?'");
+            }
+            '"' => {
+                text.push(c);
+                for q in chars.by_ref() {
+                    text.push(q);
+                    if q == '"' {
+                        break;
+                    }
+                }
+            }
+            '$' if !in_word && chars.peek().is_some_and(|d| !d.is_ascii_digit()) => {
+                // $$...$$ or $tag$...$tag$
+                let mut tag = String::from('$');
+                while let Some(t) = chars.next_if(|t| t.is_alphanumeric() || *t == '_') {
+                    tag.push(t);
+                }
+                if chars.next_if_eq(&'$').is_none() {
+                    text.push_str(&tag);
+                    in_word = false;
+                    continue;
+                }
+                tag.push('$');
+                let mut body = String::new();
+                while !body.ends_with(&tag) {
+                    match chars.next() {
+                        Some(b) => body.push(b),
+                        None => break,
+                    }
+                }
+                text.push_str("'?'");
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                text.push_str("--");
+                while chars.next_if(|e| *e != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for e in chars.by_ref() {
+                    if previous == '*' && e == '/' {
+                        break;
+                    }
+                    previous = e;
+                }
+                text.push_str("/**/");
+            }
+            c if c.is_ascii_digit() && !in_word => {
+                while chars.next_if(|d| d.is_ascii_digit() || *d == '.').is_some() {}
+                text.push('0');
+            }
+            c => text.push(c),
+        }
+        in_word = c.is_alphanumeric() || c == '_' || c == '$';
+    }
+    text
+}
+
+/// The statements of a query separated by semicolons, as written without
+/// the semicolons and the surrounding whitespace. The semicolons within the
+/// strings, the quoted identifiers, the dollar-quoted bodies and the
+/// comments don't separate statements, the statements made of comments only
+/// are left out.
+pub fn split_statements(query: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    // Whether the statement has more than whitespace and comments
+    let mut code = false;
+    let mut chars = query.char_indices().peekable();
+    // Within a word, e.g. a$b, a dollar doesn't start a quote
+    let mut in_word = false;
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => {
+                if code {
+                    statements.push(query[start..i].trim());
+                }
+                (start, code) = (i + 1, false);
+            }
+            c if c.is_whitespace() => (),
+            '-' if chars.peek().is_some_and(|(_, d)| *d == '-') => {
+                while chars.next_if(|(_, e)| *e != '\n').is_some() {}
+            }
+            '/' if chars.peek().is_some_and(|(_, d)| *d == '*') => {
+                chars.next();
+                // The block comments nest
+                let (mut depth, mut previous) = (1, ' ');
+                for (_, e) in chars.by_ref() {
+                    match (previous, e) {
+                        ('*', '/') => depth -= 1,
+                        ('/', '*') => depth += 1,
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    // The star of */ can't also open a comment
+                    previous = if (previous, e) == ('/', '*') { ' ' } else { e };
+                }
+            }
+            '\'' | '"' => {
+                code = true;
+                // E'...' strings escape with backslashes
+                let escapes = c == '\''
+                    && query[..i]
+                        .chars()
+                        .next_back()
+                        .is_some_and(|e| e == 'e' || e == 'E');
+                while let Some((_, q)) = chars.next() {
+                    match q {
+                        '\\' if escapes => {
+                            chars.next();
+                        }
+                        q if q == c && chars.peek().is_some_and(|(_, d)| *d == c) => {
+                            chars.next();
+                        }
+                        q if q == c => break,
+                        _ => (),
+                    }
+                }
+            }
+            '$' if !in_word => {
+                code = true;
+                // $$...$$ or $tag$...$tag$, $1 is a parameter
+                let mut tag = String::from('$');
+                while let Some((_, t)) = chars.next_if(|(_, t)| t.is_alphanumeric() || *t == '_') {
+                    tag.push(t);
+                }
+                if tag[1..].starts_with(|t: char| t.is_ascii_digit())
+                    || chars.next_if(|(_, t)| *t == '$').is_none()
+                {
+                    in_word = false;
+                    continue;
+                }
+                tag.push('$');
+                let mut body = String::new();
+                while !body.ends_with(&tag) {
+                    match chars.next() {
+                        Some((_, b)) => body.push(b),
+                        None => break,
+                    }
+                }
+            }
+            _ => code = true,
+        }
+        in_word = c.is_alphanumeric() || c == '_' || c == '$';
+    }
+    if code {
+        statements.push(query[start..].trim());
+    }
+    statements
+}
+
+/// Decides whether a query received from the frontend is the one expected
+/// by a rule.
+#[derive(Debug, Clone)]
+pub enum QueryMatcher {
+    /// The query must be exactly the same
+    Exact(String),
+    /// The query is compared once normalized, placeholders (`$1` or `?`) in
+    /// the pattern match any literal or placeholder of the query
+    Normalized(String),
+    /// The whole query must match the regular expression
+    Regex(Regex),
+}
+
+impl QueryMatcher {
+    pub fn exact(query: &str) -> Self {
+        QueryMatcher::Exact(String::from(query))
+    }
+
+    pub fn normalized(pattern: &str) -> anyhow::Result<Self> {
+        Ok(QueryMatcher::Normalized(normalize(pattern)?))
+    }
+
+    pub fn regex(pattern: &str) -> anyhow::Result<Self> {
+        Ok(QueryMatcher::Regex(Regex::new(&format!(
+            "^(?:{pattern})$"
+        ))?))
+    }
+
+    pub fn matches(&self, query: &str) -> bool {
+        match self {
+            QueryMatcher::Exact(expected) => expected == query,
+            QueryMatcher::Normalized(pattern) => {
+                // An unparsable query can't match a valid pattern
+                let (Ok(pattern), Ok(query)) = (tokenize(pattern), tokenize(query)) else {
+                    return false;
+                };
+                pattern.len() == query.len()
+                    && pattern.iter().zip(&query).all(|(p, q)| match p {
+                        Token::Placeholder(_) => q.is_value(),
+                        p => p == q,
+                    })
+            }
+            QueryMatcher::Regex(regex) => regex.is_match(query),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_query() -> anyhow::Result<()> {
+        assert_eq!(
+            "select a , \"B\" from t where c = $1 and d = 'It''s'",
+            normalize("SELECT a,\n\t\"B\"  FROM T WHERE c = ? AND d = 'It''s';")?
+        );
+        assert!(normalize("SELECT 'abc").is_err());
+        assert_eq!(
+            "select 1 - 2",
+            normalize("/* a /* nested */ comment */ SELECT 1 - 2 -- the rest")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn split_query() -> anyhow::Result<()> {
+        assert_eq!(
+            vec![
+                "SELECT ';'",
+                "SELECT $a$;$a$, $1, E'\\';'",
+                "/* ; */ SELECT \"a;b\""
+            ],
+            split_statements(
+                "SELECT ';'; SELECT $a$;$a$, $1, E'\\';';\n/* ; */ SELECT \"a;b\" ; -- ;\n;"
+            )
+        );
+        assert!(split_statements(" ; -- SELECT 1").is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn normalized_matches() -> anyhow::Result<()> {
+        let matcher = QueryMatcher::normalized("select * from users where id = $1")?;
+        assert!(matcher.matches("SELECT *\nFROM users\nWHERE id = 42;"));
+        assert!(matcher.matches("select * from users where id = $3"));
+        assert!(matcher.matches("select * from users where id = 'abc'"));
+        assert!(!matcher.matches("select * from users where id = name"));
+        assert!(!matcher.matches("select * from users where id = 42 limit 1"));
+        assert!(!matcher.matches("select * from \"Users\" where id = 42"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bound_parameters() -> anyhow::Result<()> {
+        let parameters = [Some(String::from("It's")), None];
+        assert_eq!(
+            Some(vec!["select", "f", "(", "'It''s'", ",", "null", ")"]),
+            bound_tokens("SELECT f($1, $2);", &parameters)?
+                .as_ref()
+                .map(|tokens| tokens.iter().map(String::as_str).collect::<Vec<_>>())
+        );
+        assert_eq!(None, bound_tokens("SELECT $3", &parameters)?);
+        assert_eq!(None, bound_tokens("SELECT ?", &parameters)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn redacted_literals() -> anyhow::Result<()> {
+        assert_eq!(
+            "SELECT * FROM t1 WHERE a = '?' AND b > 0 AND c = $2 --\n AND d = '?' /**/",
+            redact_literals(
+                "SELECT * FROM t1 WHERE a = 'It''s' AND b > 4.2 AND c = $2 -- x\n AND d = $f$a'b$f$ /* y */"
+            )
+        );
+        assert_eq!(
+            "SELECT \"a1'\" FROM t",
+            redact_literals("SELECT \"a1'\" FROM t")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn regex_matches() -> anyhow::Result<()> {
+        let matcher = QueryMatcher::regex(r"(?i)select \d+")?;
+        assert!(matcher.matches("SELECT 1"));
+        assert!(!matcher.matches("SELECT 1; SELECT 2"));
+        assert!(QueryMatcher::exact("SELECT 1").matches("SELECT 1"));
+        assert!(!QueryMatcher::exact("SELECT 1").matches("select 1"));
+
+        Ok(())
+    }
+}
@@ -5,12 +5,17 @@ use libpq_serde_macros::{
 };
 use libpq_serde_types::{
     ByteSized, Deserialize, Serialize,
-    libpq_types::{Byte, Byte4, Vec16, Vec32, VecNull},
+    libpq_types::{Byte, Byte4, NullableBytes, Vec16, Vec32, VecEnd, VecNull},
 };
+#[cfg(feature = "auth")]
 use md5::{Digest, Md5};
 use std::ffi::CString;
+use std::fmt;
 use std::io::{BufReader, Read};
 
+use crate::hexdump::AnnotatedMessage;
+use crate::value::{OutputSettings, PgValue};
+
 // The list of messages can be found here and has been copied below (v17):
 // * https://www.postgresql.org/docs/17/protocol-flow.html
 // * https://www.postgresql.org/docs/17/protocol-message-formats.html
@@ -28,6 +33,10 @@ pub struct RequestHeader {
     pub length: i32,
 }
 
+/// Like PostgreSQL, the longest startup packet accepted, the requests are
+/// shorter
+pub const MAX_STARTUP_PACKET_LENGTH: i32 = 10000;
+
 /// This struct contains the raw request which can be transformed into
 /// a request message body after via the implementation of TryFrom().
 ///
@@ -50,6 +59,9 @@ impl RawRequest {
         let mut buffer = vec![0_u8; 4];
         buffered_reader.read_exact(&mut buffer)?;
         let header = RequestHeader::deserialize(&mut Bytes::from(buffer))?;
+        if !(8..=MAX_STARTUP_PACKET_LENGTH).contains(&header.length) {
+            return Err(anyhow!("invalid length of startup packet"));
+        }
 
         let mut buffer = vec![0_u8; (header.length - 4) as usize];
         buffered_reader.read_exact(&mut buffer)?;
@@ -80,10 +92,10 @@ pub enum RequestMessageKind {
 impl From<&RequestMessageKind> for i32 {
     fn from(msg_kind: &RequestMessageKind) -> i32 {
         match msg_kind {
-            &RequestMessageKind::StartupMessage => 196608,
-            &RequestMessageKind::CancelRequest => 80877102,
-            &RequestMessageKind::GSSENCRequest => 80877104,
-            &RequestMessageKind::SSLRequest => 80877103,
+            RequestMessageKind::StartupMessage => 196608,
+            RequestMessageKind::CancelRequest => 80877102,
+            RequestMessageKind::GSSENCRequest => 80877104,
+            RequestMessageKind::SSLRequest => 80877103,
         }
     }
 }
@@ -128,14 +140,19 @@ impl RawBackendMessage {
     where
         T: Read,
     {
-        let mut buffer = vec![0_u8; 4 + 1];
-        buffered_reader.read_exact(&mut buffer)?;
-        let header = MessageHeader::deserialize(&mut Bytes::from(buffer))?;
-
-        let mut buffer = vec![0_u8; (header.length - 4) as usize];
-        buffered_reader.read_exact(&mut buffer)?;
-        let raw_body = Bytes::from(buffer);
+        let header = MessageHeader::get(buffered_reader)?;
+        Self::get_body(buffered_reader, header)
+    }
 
+    /// Read the body of a message whose header was already read
+    pub fn get_body<T>(
+        buffered_reader: &mut BufReader<T>,
+        header: MessageHeader,
+    ) -> anyhow::Result<Self>
+    where
+        T: Read,
+    {
+        let raw_body = header.get_body(buffered_reader)?;
         Ok(Self { header, raw_body })
     }
 
@@ -143,10 +160,26 @@ impl RawBackendMessage {
         BackendMessageKind::try_from(self.header.message_type).ok()
     }
 
+    /// The message split in its fields, to be displayed as an annotated
+    /// hexdump
+    pub fn annotated(&self) -> AnnotatedMessage<'_> {
+        let name = match (self.get_message_kind(), self.get_auth_message_kind()) {
+            (_, Some(auth_kind)) => format!("Authentication{auth_kind:?}"),
+            (Some(kind), None) => format!("{kind:?}"),
+            (None, None) => String::from("Unknown"),
+        };
+        AnnotatedMessage {
+            name,
+            message_type: self.header.message_type,
+            length: self.header.length,
+            body: &self.raw_body,
+        }
+    }
+
     pub fn get_auth_message_kind(&self) -> Option<AuthenticationMessageKind> {
         if let Some(BackendMessageKind::Authentication) = self.get_message_kind() {
-            let mut msg_kind = [0_u8; 4];
-            msg_kind.copy_from_slice(&self.raw_body[0..4]);
+            // None for a body too short for the code
+            let msg_kind = self.raw_body.get(0..4)?.try_into().ok()?;
             let msg_kind = i32::from_be_bytes(msg_kind);
 
             AuthenticationMessageKind::try_from(msg_kind).ok()
@@ -156,8 +189,14 @@ impl RawBackendMessage {
     }
 }
 
+impl fmt::Display for RawBackendMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.annotated().fmt(f)
+    }
+}
+
 /// All the messages sent by the Backend
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BackendMessageKind {
     Authentication,           // R
     BackendKeyData,           // K
@@ -217,43 +256,55 @@ impl From<&BackendMessageKind> for u8 {
     }
 }
 
+impl BackendMessageKind {
+    pub const fn from_code(msg_code: u8) -> Option<BackendMessageKind> {
+        match msg_code {
+            0x52 /* 'R' */ => Some(BackendMessageKind::Authentication),
+            0x4b /* 'K' */ => Some(BackendMessageKind::BackendKeyData),
+            0x32 /* '2' */ => Some(BackendMessageKind::BindComplete),
+            0x33 /* '3' */ => Some(BackendMessageKind::CloseCompleten),
+            0x43 /* 'C' */ => Some(BackendMessageKind::CommandComplete),
+            0x64 /* 'd' */ => Some(BackendMessageKind::CopyData),
+            0x63 /* 'c' */ => Some(BackendMessageKind::CopyDone),
+            0x47 /* 'G' */ => Some(BackendMessageKind::CopyInResponse),
+            0x48 /* 'H' */ => Some(BackendMessageKind::CopyOutResponse),
+            0x57 /* 'W' */ => Some(BackendMessageKind::CopyBothResponse),
+            0x44 /* 'D' */ => Some(BackendMessageKind::DataRow),
+            0x49 /* 'I' */ => Some(BackendMessageKind::EmptyQuery),
+            0x45 /* 'E' */ => Some(BackendMessageKind::ErrorResponse),
+            0x56 /* 'V' */ => Some(BackendMessageKind::FunctionCallResponse),
+            0x76 /* 'v' */ => Some(BackendMessageKind::NegotiateProtocolVersion),
+            0x6e /* 'n' */ => Some(BackendMessageKind::NoData),
+            0x4e /* 'N' */ => Some(BackendMessageKind::NoticeResponse),
+            0x41 /* 'A' */ => Some(BackendMessageKind::NotificationResponse),
+            0x74 /* 't' */ => Some(BackendMessageKind::ParameterDescription),
+            0x53 /* 'S' */ => Some(BackendMessageKind::ParameterStatus),
+            0x31 /* '1' */ => Some(BackendMessageKind::ParseComplete),
+            0x73 /* 's' */ => Some(BackendMessageKind::PortalSuspended),
+            0x5a /* 'Z' */ => Some(BackendMessageKind::ReadyForQuery),
+            0x54 /* 'T' */ => Some(BackendMessageKind::RowDescription),
+            _ => None,
+        }
+    }
+
+    /// Checked at build time by TryFromRawBackendMessage, the kind of a
+    /// message must be listed here
+    pub const fn is_known(msg_code: u8) -> bool {
+        Self::from_code(msg_code).is_some()
+    }
+}
+
 impl TryFrom<u8> for BackendMessageKind {
     type Error = anyhow::Error;
 
     fn try_from(msg_code: u8) -> anyhow::Result<BackendMessageKind> {
-        match msg_code {
-            0x52 /* 'R' */ => Ok(BackendMessageKind::Authentication),
-            0x4b /* 'K' */ => Ok(BackendMessageKind::BackendKeyData),
-            0x32 /* '2' */ => Ok(BackendMessageKind::BindComplete),
-            0x33 /* '3' */ => Ok(BackendMessageKind::CloseCompleten),
-            0x43 /* 'C' */ => Ok(BackendMessageKind::CommandComplete),
-            0x64 /* 'd' */ => Ok(BackendMessageKind::CopyData),
-            0x63 /* 'c' */ => Ok(BackendMessageKind::CopyDone),
-            0x47 /* 'G' */ => Ok(BackendMessageKind::CopyInResponse),
-            0x48 /* 'H' */ => Ok(BackendMessageKind::CopyOutResponse),
-            0x57 /* 'W' */ => Ok(BackendMessageKind::CopyBothResponse),
-            0x44 /* 'D' */ => Ok(BackendMessageKind::DataRow),
-            0x49 /* 'I' */ => Ok(BackendMessageKind::EmptyQuery),
-            0x45 /* 'E' */ => Ok(BackendMessageKind::ErrorResponse),
-            0x56 /* 'V' */ => Ok(BackendMessageKind::FunctionCallResponse),
-            0x76 /* 'v' */ => Ok(BackendMessageKind::NegotiateProtocolVersion),
-            0x6e /* 'n' */ => Ok(BackendMessageKind::NoData),
-            0x4e /* 'N' */ => Ok(BackendMessageKind::NoticeResponse),
-            0x41 /* 'A' */ => Ok(BackendMessageKind::NotificationResponse),
-            0x74 /* 't' */ => Ok(BackendMessageKind::ParameterDescription),
-            0x53 /* 'S' */ => Ok(BackendMessageKind::ParameterStatus),
-            0x31 /* '1' */ => Ok(BackendMessageKind::ParseComplete),
-            0x73 /* 's' */ => Ok(BackendMessageKind::PortalSuspended),
-            0x5a /* 'Z' */ => Ok(BackendMessageKind::ReadyForQuery),
-            0x54 /* 'T' */ => Ok(BackendMessageKind::RowDescription),
-            _ => Err(anyhow!("Unsupported code for backend message")),
-        }
+        Self::from_code(msg_code).ok_or(anyhow!("Unsupported code for backend message"))
     }
 }
 
 /// AuthenticationMessage can have several different kind
 /// which are listed here
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuthenticationMessageKind {
     Ok,                // 0
     KerberosV5,        // 2
@@ -321,20 +372,50 @@ impl RawFrontendMessage {
     where
         T: Read,
     {
-        let mut buffer = vec![0_u8; 4 + 1];
-        buffered_reader.read_exact(&mut buffer)?;
-        let header = MessageHeader::deserialize(&mut Bytes::from(buffer))?;
-
-        let mut buffer = vec![0_u8; (header.length - 4) as usize];
-        buffered_reader.read_exact(&mut buffer)?;
-        let raw_body = Bytes::from(buffer);
+        let header = MessageHeader::get(buffered_reader)?;
+        Self::get_body(buffered_reader, header)
+    }
 
+    /// Read the body of a message whose header was already read
+    pub fn get_body<T>(
+        buffered_reader: &mut BufReader<T>,
+        header: MessageHeader,
+    ) -> anyhow::Result<Self>
+    where
+        T: Read,
+    {
+        let raw_body = header.get_body(buffered_reader)?;
         Ok(Self { header, raw_body })
     }
 
     pub fn get_message_kind(&self) -> Option<FrontendMessageKind> {
         FrontendMessageKind::try_from(self.header.message_type).ok()
     }
+
+    /// The message split in its fields, to be displayed as an annotated
+    /// hexdump
+    pub fn annotated(&self) -> AnnotatedMessage<'_> {
+        let name = match self.get_message_kind() {
+            Some(kind) => format!("{kind:?}"),
+            // The kind depends on the authentication method
+            None if self.header.message_type == b'p' => {
+                String::from("PasswordMessage, GSSResponse or SASLResponse")
+            }
+            None => String::from("Unknown"),
+        };
+        AnnotatedMessage {
+            name,
+            message_type: self.header.message_type,
+            length: self.header.length,
+            body: &self.raw_body,
+        }
+    }
+}
+
+impl fmt::Display for RawFrontendMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.annotated().fmt(f)
+    }
 }
 
 #[derive(Debug, PartialEq, SerdeLibpqData)]
@@ -344,27 +425,85 @@ pub struct MessageHeader {
 }
 
 impl MessageHeader {
-    pub fn new_header_from_body<T>(body: &T) -> Self
+    pub fn get<T>(buffered_reader: &mut BufReader<T>) -> anyhow::Result<Self>
+    where
+        T: Read,
+    {
+        let mut buffer = vec![0_u8; 4 + 1];
+        buffered_reader.read_exact(&mut buffer)?;
+        MessageHeader::deserialize(&mut Bytes::from(buffer))
+    }
+
+    fn get_body<T>(&self, buffered_reader: &mut BufReader<T>) -> anyhow::Result<Bytes>
+    where
+        T: Read,
+    {
+        // The length includes itself
+        if self.length < 4 {
+            return Err(anyhow!("Invalid message length: {}", self.length));
+        }
+        let mut buffer = vec![0_u8; (self.length - 4) as usize];
+        buffered_reader.read_exact(&mut buffer)?;
+        Ok(Bytes::from(buffer))
+    }
+
+    /// The length field of a message whose body is `body_size` bytes long,
+    /// it includes itself and is refused when it doesn't fit on 32 bits
+    pub fn length_for(body_size: usize) -> anyhow::Result<i32> {
+        body_size
+            .checked_add(4)
+            .and_then(|length| i32::try_from(length).ok())
+            .ok_or(anyhow!(
+                "Message body of {body_size} bytes exceeds the 32-bit length of the protocol"
+            ))
+    }
+
+    pub fn new_header_from_body<T>(body: &T) -> anyhow::Result<Self>
     where
         T: MessageBody + ByteSized,
     {
-        Self {
+        Ok(Self {
             message_type: body.message_type(),
-            length: body.byte_size() + 4,
-        }
+            length: Self::length_for(body.byte_size())?,
+        })
     }
 
-    pub fn new_raw_header_from_body<T>(buffer: &mut BytesMut, body: &T)
+    pub fn new_raw_header_from_body<T>(buffer: &mut BytesMut, body: &T) -> anyhow::Result<()>
     where
         T: MessageBody + ByteSized,
     {
         buffer.put_u8(body.message_type());
-        buffer.put_i32(body.byte_size() + 4);
+        buffer.put_i32(Self::length_for(body.byte_size())?);
+        Ok(())
+    }
+
+    /// Serialize the header and the body, the length of the header is
+    /// patched from the bytes actually written for the body instead of
+    /// relying on byte_size(). A body too large for the length field is
+    /// refused before being serialized, the buffer is left untouched.
+    pub fn serialize_message<T>(buffer: &mut BytesMut, body: &T) -> anyhow::Result<()>
+    where
+        T: MessageBody + Serialize + ByteSized,
+    {
+        Self::length_for(body.byte_size())?;
+        let start = buffer.len();
+        buffer.put_u8(body.message_type());
+        buffer.put_i32(0);
+        body.serialize(buffer);
+        let length = match Self::length_for(buffer.len() - start - 5) {
+            Ok(length) => length,
+            Err(e) => {
+                buffer.truncate(start);
+                return Err(e);
+            }
+        };
+        buffer[start + 1..start + 5].copy_from_slice(&length.to_be_bytes());
+        Ok(())
     }
 }
 
 /// All the messages sent by the Frontend
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FrontendMessageKind {
     Bind,                // B
     Close,               // C
@@ -373,14 +512,15 @@ pub enum FrontendMessageKind {
     CopyFail,            // f
     Describe,            // D
     Execute,             // E
-    Flush,               // F
-    FunctionCall,        // H
+    Flush,               // H
+    FunctionCall,        // F
     GSSResponse,         // p
     Parse,               // P
     PasswordMessage,     // p
     Query,               // Q
     SASLInitialResponse, // p
     SASLResponse,        // p
+    Sync,                // S
     Terminate,           // X
 }
 
@@ -394,43 +534,59 @@ impl From<&FrontendMessageKind> for u8 {
             FrontendMessageKind::CopyFail => 'f',
             FrontendMessageKind::Describe => 'D',
             FrontendMessageKind::Execute => 'E',
-            FrontendMessageKind::Flush => 'F',
-            FrontendMessageKind::FunctionCall => 'H',
+            FrontendMessageKind::Flush => 'H',
+            FrontendMessageKind::FunctionCall => 'F',
             FrontendMessageKind::GSSResponse => 'p',
             FrontendMessageKind::Parse => 'P',
             FrontendMessageKind::PasswordMessage => 'p',
             FrontendMessageKind::Query => 'Q',
             FrontendMessageKind::SASLInitialResponse => 'p',
             FrontendMessageKind::SASLResponse => 'p',
+            FrontendMessageKind::Sync => 'S',
             FrontendMessageKind::Terminate => 'X',
         };
         msg_code as u8
     }
 }
 
+impl FrontendMessageKind {
+    /// `None` for 'p' too, its kind depends on the authentication
+    pub const fn from_code(msg_code: u8) -> Option<FrontendMessageKind> {
+        match msg_code {
+            0x42 /* B */ => Some(FrontendMessageKind::Bind),
+            0x43 /* C */ => Some(FrontendMessageKind::Close),
+            0x64 /* d */ => Some(FrontendMessageKind::CopyData),
+            0x63 /* c */ => Some(FrontendMessageKind::CopyDone),
+            0x66 /* f */ => Some(FrontendMessageKind::CopyFail),
+            0x44 /* D */ => Some(FrontendMessageKind::Describe),
+            0x45 /* E */ => Some(FrontendMessageKind::Execute),
+            0x46 /* F */ => Some(FrontendMessageKind::FunctionCall),
+            0x48 /* H */ => Some(FrontendMessageKind::Flush),
+            0x50 /* P */ => Some(FrontendMessageKind::Parse),
+            0x51 /* Q */ => Some(FrontendMessageKind::Query),
+            0x53 /* S */ => Some(FrontendMessageKind::Sync),
+            0x58 /* X */ => Some(FrontendMessageKind::Terminate),
+            _ => None,
+        }
+    }
+
+    /// Checked at build time by TryFromRawFrontendMessage, the kind of a
+    /// message must be listed here
+    pub const fn is_known(msg_code: u8) -> bool {
+        msg_code == b'p' || Self::from_code(msg_code).is_some()
+    }
+}
+
 impl TryFrom<u8> for FrontendMessageKind {
     type Error = anyhow::Error;
 
     fn try_from(msg_code: u8) -> anyhow::Result<FrontendMessageKind> {
-        match msg_code {
-            0x42 /* B */ => Ok(FrontendMessageKind::Bind),
-            0x43 /* C */ => Ok(FrontendMessageKind::Close),
-            0x64 /* d */ => Ok(FrontendMessageKind::CopyData),
-            0x63 /* c */ => Ok(FrontendMessageKind::CopyDone),
-            0x66 /* f */ => Ok(FrontendMessageKind::CopyFail),
-            0x44 /* D */ => Ok(FrontendMessageKind::Describe),
-            0x45 /* E */ => Ok(FrontendMessageKind::Execute),
-            0x46 /* F */ => Ok(FrontendMessageKind::Flush),
-            0x48 /* H */ => Ok(FrontendMessageKind::FunctionCall),
-            0x51 /* Q */ => Ok(FrontendMessageKind::Query),
-            0x58 /* X */ => Ok(FrontendMessageKind::Terminate),
-            0x70 /* p */ => Err(anyhow!(
+        match Self::from_code(msg_code) {
+            Some(kind) => Ok(kind),
+            None if msg_code == b'p' => Err(anyhow!(
                 "Frontend Message kind cannot be guessed without context: 'p'"
             )),
-            0x50 /* P */ => Err(anyhow!(
-                "Frontend Message kind cannot be guessed without context: 'P'"
-            )),
-            _ => Err(anyhow!("Unsupported code for frontend message")),
+            None => Err(anyhow!("Unsupported code for frontend message")),
         }
     }
 }
@@ -455,15 +611,21 @@ impl AuthenticationOk {
     }
 }
 
+impl Default for AuthenticationOk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Auth message cannot derive TryFromRawBackendMessage they have a specific implementation
 impl TryFrom<&mut RawBackendMessage> for AuthenticationOk {
     type Error = anyhow::Error;
 
     fn try_from(message: &mut RawBackendMessage) -> anyhow::Result<AuthenticationOk> {
-        if let Some(BackendMessageKind::Authentication) = message.get_message_kind() {
-            if let Some(AuthenticationMessageKind::Ok) = message.get_auth_message_kind() {
-                return AuthenticationOk::deserialize(&mut message.raw_body);
-            }
+        if let Some(BackendMessageKind::Authentication) = message.get_message_kind()
+            && let Some(AuthenticationMessageKind::Ok) = message.get_auth_message_kind()
+        {
+            return AuthenticationOk::deserialize(&mut message.raw_body);
         }
         Err(anyhow!(
             "Impossible to create AuthenticationOk from RawBackendMessage"
@@ -507,10 +669,10 @@ impl TryFrom<&mut RawBackendMessage> for AuthenticationMD5Password {
     type Error = anyhow::Error;
 
     fn try_from(message: &mut RawBackendMessage) -> anyhow::Result<AuthenticationMD5Password> {
-        if let Some(BackendMessageKind::Authentication) = message.get_message_kind() {
-            if let Some(AuthenticationMessageKind::MD5Password) = message.get_auth_message_kind() {
-                return AuthenticationMD5Password::deserialize(&mut message.raw_body);
-            }
+        if let Some(BackendMessageKind::Authentication) = message.get_message_kind()
+            && let Some(AuthenticationMessageKind::MD5Password) = message.get_auth_message_kind()
+        {
+            return AuthenticationMD5Password::deserialize(&mut message.raw_body);
         }
         Err(anyhow!(
             "Impossible to create AuthenticationMD5Password from RawBackendMessage"
@@ -522,17 +684,115 @@ impl TryFrom<&mut RawBackendMessage> for AuthenticationMD5Password {
 // * Byte1('R') Identifies the message as an authentication request.
 // * Int32(8) Length of message contents in bytes, including self.
 // * Int32(7) Specifies that GSSAPI authentication is required.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody)]
+#[message_body(kind = 'R')]
+pub struct AuthenticationGSS {
+    pub code: i32,
+}
+
+impl AuthenticationGSS {
+    pub fn new() -> Self {
+        Self { code: 7 }
+    }
+}
+
+impl Default for AuthenticationGSS {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Auth message cannot derive TryFromRawBackendMessage they have a specific implementation
+impl TryFrom<&mut RawBackendMessage> for AuthenticationGSS {
+    type Error = anyhow::Error;
+
+    fn try_from(message: &mut RawBackendMessage) -> anyhow::Result<AuthenticationGSS> {
+        if let Some(BackendMessageKind::Authentication) = message.get_message_kind()
+            && let Some(AuthenticationMessageKind::GSS) = message.get_auth_message_kind()
+        {
+            return AuthenticationGSS::deserialize(&mut message.raw_body);
+        }
+        Err(anyhow!(
+            "Impossible to create AuthenticationGSS from RawBackendMessage"
+        ))
+    }
+}
 
 // AuthenticationGSSContinue (B)
 // * Byte1('R') Identifies the message as an authentication request.
 // * Int32 Length of message contents in bytes, including self.
 // * Int32(8) Specifies that this message contains GSSAPI or SSPI data.
 // * Byten GSSAPI or SSPI authentication data.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody)]
+#[message_body(kind = 'R')]
+pub struct AuthenticationGSSContinue {
+    pub code: i32,
+    pub data: VecEnd<Byte>,
+}
+
+impl AuthenticationGSSContinue {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            code: 8,
+            data: data.into(),
+        }
+    }
+}
+
+// Auth message cannot derive TryFromRawBackendMessage they have a specific implementation
+impl TryFrom<&mut RawBackendMessage> for AuthenticationGSSContinue {
+    type Error = anyhow::Error;
+
+    fn try_from(message: &mut RawBackendMessage) -> anyhow::Result<AuthenticationGSSContinue> {
+        if let Some(BackendMessageKind::Authentication) = message.get_message_kind()
+            && let Some(AuthenticationMessageKind::GSSContinue) = message.get_auth_message_kind()
+        {
+            return AuthenticationGSSContinue::deserialize(&mut message.raw_body);
+        }
+        Err(anyhow!(
+            "Impossible to create AuthenticationGSSContinue from RawBackendMessage"
+        ))
+    }
+}
 
 // AuthenticationSSPI (B)
 // * Byte1('R') Identifies the message as an authentication request.
 // * Int32(8) Length of message contents in bytes, including self.
 // * Int32(9) Specifies that SSPI authentication is required.
+// The tokens are then exchanged with GSSResponse and AuthenticationGSSContinue, like GSSAPI.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody)]
+#[message_body(kind = 'R')]
+pub struct AuthenticationSSPI {
+    pub code: i32,
+}
+
+impl AuthenticationSSPI {
+    pub fn new() -> Self {
+        Self { code: 9 }
+    }
+}
+
+impl Default for AuthenticationSSPI {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Auth message cannot derive TryFromRawBackendMessage they have a specific implementation
+impl TryFrom<&mut RawBackendMessage> for AuthenticationSSPI {
+    type Error = anyhow::Error;
+
+    fn try_from(message: &mut RawBackendMessage) -> anyhow::Result<AuthenticationSSPI> {
+        if let Some(BackendMessageKind::Authentication) = message.get_message_kind()
+            && let Some(AuthenticationMessageKind::SSPI) = message.get_auth_message_kind()
+        {
+            return AuthenticationSSPI::deserialize(&mut message.raw_body);
+        }
+        Err(anyhow!(
+            "Impossible to create AuthenticationSSPI from RawBackendMessage"
+        ))
+    }
+}
 
 // AuthenticationSASL (B)
 // * Byte1('R') Identifies the message as an authentication request.
@@ -544,21 +804,115 @@ impl TryFrom<&mut RawBackendMessage> for AuthenticationMD5Password {
 //     mechanism, there is the following:
 //
 // * String Name of a SASL authentication mechanism.
-//TODO: implement
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody)]
+#[message_body(kind = 'R')]
+pub struct AuthenticationSASL {
+    pub code: i32,
+    pub mechanisms: VecNull<CString>,
+}
+
+impl AuthenticationSASL {
+    pub fn new(mechanisms: &[&str]) -> anyhow::Result<Self> {
+        Ok(Self {
+            code: 10,
+            mechanisms: mechanisms
+                .iter()
+                .map(|mechanism| CString::new(*mechanism))
+                .collect::<Result<Vec<_>, _>>()?
+                .into(),
+        })
+    }
+}
+
+// Auth message cannot derive TryFromRawBackendMessage they have a specific implementation
+impl TryFrom<&mut RawBackendMessage> for AuthenticationSASL {
+    type Error = anyhow::Error;
+
+    fn try_from(message: &mut RawBackendMessage) -> anyhow::Result<AuthenticationSASL> {
+        if let Some(BackendMessageKind::Authentication) = message.get_message_kind()
+            && let Some(AuthenticationMessageKind::SASL) = message.get_auth_message_kind()
+        {
+            return AuthenticationSASL::deserialize(&mut message.raw_body);
+        }
+        Err(anyhow!(
+            "Impossible to create AuthenticationSASL from RawBackendMessage"
+        ))
+    }
+}
 
 // AuthenticationSASLContinue (B)
 // * Byte1('R') Identifies the message as an authentication request.
 // * Int32 Length of message contents in bytes, including self.
 // * Int32(11) Specifies that this message contains a SASL challenge.
 // * Byten SASL data, specific to the SASL mechanism being used.
-//TODO: implement
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody)]
+#[message_body(kind = 'R')]
+pub struct AuthenticationSASLContinue {
+    pub code: i32,
+    pub data: VecEnd<Byte>,
+}
+
+impl AuthenticationSASLContinue {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            code: 11,
+            data: data.into(),
+        }
+    }
+}
+
+// Auth message cannot derive TryFromRawBackendMessage they have a specific implementation
+impl TryFrom<&mut RawBackendMessage> for AuthenticationSASLContinue {
+    type Error = anyhow::Error;
+
+    fn try_from(message: &mut RawBackendMessage) -> anyhow::Result<AuthenticationSASLContinue> {
+        if let Some(BackendMessageKind::Authentication) = message.get_message_kind()
+            && let Some(AuthenticationMessageKind::SASLContinue) = message.get_auth_message_kind()
+        {
+            return AuthenticationSASLContinue::deserialize(&mut message.raw_body);
+        }
+        Err(anyhow!(
+            "Impossible to create AuthenticationSASLContinue from RawBackendMessage"
+        ))
+    }
+}
 
 // AuthenticationSASLFinal (B)
 // * Byte1('R') Identifies the message as an authentication request.
 // * Int32 Length of message contents in bytes, including self.
 // * Int32(12) Specifies that SASL authentication has completed.
 // * Byten SASL outcome "additional data", specific to the SASL mechanism being used.
-//TODO: implement
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody)]
+#[message_body(kind = 'R')]
+pub struct AuthenticationSASLFinal {
+    pub code: i32,
+    pub data: VecEnd<Byte>,
+}
+
+impl AuthenticationSASLFinal {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            code: 12,
+            data: data.into(),
+        }
+    }
+}
+
+// Auth message cannot derive TryFromRawBackendMessage they have a specific implementation
+impl TryFrom<&mut RawBackendMessage> for AuthenticationSASLFinal {
+    type Error = anyhow::Error;
+
+    fn try_from(message: &mut RawBackendMessage) -> anyhow::Result<AuthenticationSASLFinal> {
+        if let Some(BackendMessageKind::Authentication) = message.get_message_kind()
+            && let Some(AuthenticationMessageKind::SASLFinal) = message.get_auth_message_kind()
+        {
+            return AuthenticationSASLFinal::deserialize(&mut message.raw_body);
+        }
+        Err(anyhow!(
+            "Impossible to create AuthenticationSASLFinal from RawBackendMessage"
+        ))
+    }
+}
 
 // BackendKeyData (B)
 // * Byte1('K') Identifies the message as cancellation key data. The frontend must save these values if
@@ -611,10 +965,107 @@ impl BackendKeyData {
 //      format (text); or one, in which case the specified format code is applied to all result columns
 //  (if any); or it can equal the actual number of result columns of the query.
 // * Int16[R] The result-column format codes. Each must presently be zero (text) or one (binary).
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'B')]
+pub struct Bind {
+    pub portal: CString,
+    pub statement: CString,
+    pub parameter_formats: Vec16<i16>,
+    pub parameters: Vec16<ColumnData>,
+    pub result_formats: Vec16<i16>,
+}
+
+impl Bind {
+    pub fn new(
+        portal: &str,
+        statement: &str,
+        parameter_formats: Vec<FormatCode>,
+        parameters: Vec<ColumnData>,
+        result_formats: Vec<FormatCode>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            portal: CString::new(portal)?,
+            statement: CString::new(statement)?,
+            parameter_formats: parameter_formats
+                .iter()
+                .map(i16::from)
+                .collect::<Vec<i16>>()
+                .into(),
+            parameters: parameters.into(),
+            result_formats: result_formats
+                .iter()
+                .map(i16::from)
+                .collect::<Vec<i16>>()
+                .into(),
+        })
+    }
+
+    /// Decode the result-column format codes sent by the frontend.
+    pub fn result_formats(&self) -> anyhow::Result<Vec<FormatCode>> {
+        self.result_formats
+            .as_ref()
+            .iter()
+            .map(|code| FormatCode::try_from(*code))
+            .collect()
+    }
+}
+
+/// The format of a parameter or column value: zero (text) or one (binary).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatCode {
+    Text,
+    Binary,
+}
+
+impl FormatCode {
+    /// Pick the format of the column at `index` from a list of format codes
+    /// as sent in Bind: an empty list means text for every column, a single
+    /// code applies to every column, otherwise there is one code per column.
+    pub fn for_column(formats: &[FormatCode], index: usize) -> anyhow::Result<FormatCode> {
+        match formats.len() {
+            0 => Ok(FormatCode::Text),
+            1 => Ok(formats[0]),
+            _ => formats
+                .get(index)
+                .copied()
+                .ok_or(anyhow!("No format code for column {index}")),
+        }
+    }
+}
+
+impl From<&FormatCode> for i16 {
+    fn from(format: &FormatCode) -> i16 {
+        match format {
+            FormatCode::Text => 0,
+            FormatCode::Binary => 1,
+        }
+    }
+}
+
+impl TryFrom<i16> for FormatCode {
+    type Error = anyhow::Error;
+
+    fn try_from(code: i16) -> anyhow::Result<FormatCode> {
+        match code {
+            0 => Ok(FormatCode::Text),
+            1 => Ok(FormatCode::Binary),
+            _ => Err(anyhow!("Invalid format code: {code}")),
+        }
+    }
+}
 
 // BindComplete (B)
 // * Byte1('2') Identifies the message as a Bind-complete indicator.
 // * Int32(4) Length of message contents in bytes, including self.
+#[derive(Debug, Default, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = '2')]
+pub struct BindComplete {}
+
+impl BindComplete {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
 // CancelRequest (F)
 // * Int32(16) Length of message contents in bytes, including self.
@@ -630,10 +1081,34 @@ impl BackendKeyData {
 // * Byte1 'S' to close a prepared statement; or 'P' to close a portal.
 // * String The name of the prepared statement or portal to close (an empty string selects the unnamed
 //         prepared statement or portal).
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'C')]
+pub struct Close {
+    pub target: Byte,
+    pub name: CString,
+}
+
+impl Close {
+    pub fn new(target: DescribeTarget, name: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            target: Byte::from(&target),
+            name: CString::new(name)?,
+        })
+    }
+}
 
 // CloseComplete (B)
 // * Byte1('3') Identifies the message as a Close-complete indicator.
 // * Int32(4) Length of message contents in bytes, including self.
+#[derive(Debug, Default, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = '3')]
+pub struct CloseComplete {}
+
+impl CloseComplete {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
 // CommandComplete (B)
 // * Byte1('C') Identifies the message as a command-completed response.
@@ -675,15 +1150,65 @@ impl CommandComplete {
 // * Byten Data that forms part of a COPY data stream. Messages sent from the backend will always
 //     correspond to single data rows, but messages sent by frontends might divide the data stream
 //     arbitrarily.
+#[derive(
+    Debug,
+    PartialEq,
+    SerdeLibpqData,
+    MessageBody,
+    TryFromRawFrontendMessage,
+    TryFromRawBackendMessage,
+)]
+#[message_body(kind = 'd')]
+pub struct CopyData {
+    pub data: VecEnd<Byte>,
+}
+
+impl CopyData {
+    pub fn new(data: &[u8]) -> Self {
+        Self {
+            data: data.to_vec().into(),
+        }
+    }
+}
 
 // CopyDone (F & B)
 // * Byte1('c') Identifies the message as a COPY-complete indicator.
 // * Int32(4) Length of message contents in bytes, including self.
+#[derive(
+    Debug,
+    Default,
+    PartialEq,
+    SerdeLibpqData,
+    MessageBody,
+    TryFromRawFrontendMessage,
+    TryFromRawBackendMessage,
+)]
+#[message_body(kind = 'c')]
+pub struct CopyDone {}
+
+impl CopyDone {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
 // CopyFail (F)
 // * Byte1('f') Identifies the message as a COPY-failure indicator.
 // * Int32 Length of message contents in bytes, including self.
 // * String An error message to report as the cause of failure.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'f')]
+pub struct CopyFail {
+    pub message: CString,
+}
+
+impl CopyFail {
+    pub fn new(message: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            message: CString::new(message)?,
+        })
+    }
+}
 
 // CopyInResponse (B)
 // * Byte1('G') Identifies the message as a Start Copy In response. The frontend must now send copy-in
@@ -696,6 +1221,22 @@ impl CommandComplete {
 // * Int16 The number of columns in the data to be copied (denoted N below).
 // * Int16[N] The format codes to be used for each column. Each must presently be zero (text) or one
 //     (binary). All must be zero if the overall copy format is textual.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 'G')]
+pub struct CopyInResponse {
+    pub format: i8,
+    pub column_formats: Vec16<i16>,
+}
+
+impl CopyInResponse {
+    /// A textual COPY of `columns` columns
+    pub fn new(columns: usize) -> Self {
+        Self {
+            format: 0,
+            column_formats: vec![0; columns].into(),
+        }
+    }
+}
 
 // CopyOutResponse (B)
 // * Byte1('H') Identifies the message as a Start Copy Out response. This message will be followed by
@@ -708,6 +1249,22 @@ impl CommandComplete {
 // * Int16 The number of columns in the data to be copied (denoted N below).
 // * Int16[N] The format codes to be used for each column. Each must presently be zero (text) or one
 //   (binary). All must be zero if the overall copy format is textual.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 'H')]
+pub struct CopyOutResponse {
+    pub format: i8,
+    pub column_formats: Vec16<i16>,
+}
+
+impl CopyOutResponse {
+    /// A textual COPY of `columns` columns
+    pub fn new(columns: usize) -> Self {
+        Self {
+            format: 0,
+            column_formats: vec![0; columns].into(),
+        }
+    }
+}
 
 // CopyBothResponse (B)
 // * Byte1('W') Identifies the message as a Start Copy Both response. This message
@@ -743,9 +1300,23 @@ impl DataRow {
             columns: columns.into(),
         }
     }
+
+    /// Encode each value in the format requested for its column, see
+    /// FormatCode::for_column() for the meaning of `formats`.
+    pub fn new_from_values(
+        values: &[PgValue],
+        formats: &[FormatCode],
+        settings: &OutputSettings,
+    ) -> anyhow::Result<Self> {
+        let mut columns = Vec::with_capacity(values.len());
+        for (index, value) in values.iter().enumerate() {
+            columns.push(value.encode(FormatCode::for_column(formats, index)?, settings)?);
+        }
+        Ok(Self::new(columns))
+    }
 }
 
-pub type ColumnData = Vec32<Byte>;
+pub type ColumnData = NullableBytes;
 
 // Describe (F)
 // * Byte1('D') Identifies the message as a Describe command.
@@ -753,6 +1324,49 @@ pub type ColumnData = Vec32<Byte>;
 // * Byte1 'S' to describe a prepared statement; or 'P' to describe a portal.
 // * String The name of the prepared statement or portal to describe (an empty string selects the
 //         unnamed prepared statement or portal).
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'D')]
+pub struct Describe {
+    pub target: Byte,
+    pub name: CString,
+}
+
+impl Describe {
+    pub fn new(target: DescribeTarget, name: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            target: Byte::from(&target),
+            name: CString::new(name)?,
+        })
+    }
+}
+
+/// The object targeted by a Describe or a Close message
+#[derive(Debug, PartialEq)]
+pub enum DescribeTarget {
+    Statement,
+    Portal,
+}
+
+impl TryFrom<&Byte> for DescribeTarget {
+    type Error = anyhow::Error;
+
+    fn try_from(item: &Byte) -> anyhow::Result<DescribeTarget> {
+        match item {
+            b'S' => Ok(DescribeTarget::Statement),
+            b'P' => Ok(DescribeTarget::Portal),
+            _ => Err(anyhow!("Invalid target for Describe or Close: {item}")),
+        }
+    }
+}
+
+impl From<&DescribeTarget> for Byte {
+    fn from(item: &DescribeTarget) -> Byte {
+        match item {
+            DescribeTarget::Statement => b'S',
+            DescribeTarget::Portal => b'P',
+        }
+    }
+}
 
 // EmptyQueryResponse (B)
 // * Byte1('I') Identifies the message as a response to an empty query string. (This substitutes for
@@ -794,10 +1408,10 @@ pub struct ErrorMessage {
 }
 
 impl ErrorMessage {
-    pub fn new(code: char, message: &String) -> anyhow::Result<Self> {
+    pub fn new(code: char, message: &str) -> anyhow::Result<Self> {
         Ok(Self {
             code: code as u8,
-            message: CString::new(&message[..])?,
+            message: CString::new(message)?,
         })
     }
 }
@@ -808,10 +1422,34 @@ impl ErrorMessage {
 // * String The name of the portal to execute (an empty string selects the unnamed portal).
 // * Int32 Maximum number of rows to return, if portal contains a query that returns rows (ignored
 //         otherwise). Zero denotes “no limit”.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'E')]
+pub struct Execute {
+    pub portal: CString,
+    pub max_rows: i32,
+}
+
+impl Execute {
+    pub fn new(portal: &str, max_rows: i32) -> anyhow::Result<Self> {
+        Ok(Self {
+            portal: CString::new(portal)?,
+            max_rows,
+        })
+    }
+}
 
 // Flush (F)
 // * Byte1('H') Identifies the message as a Flush command.
 // * Int32(4) Length of message contents in bytes, including self.
+#[derive(Debug, Default, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'H')]
+pub struct Flush {}
+
+impl Flush {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
 // FunctionCall (F)
 // * Byte1('F') Identifies the message as a function call.
@@ -834,6 +1472,26 @@ impl ErrorMessage {
 // After the last argument, the following field appears:
 //
 // * Int16 The format code for the function result. Must presently be zero (text) or one (binary).
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'F')]
+pub struct FunctionCall {
+    pub function: i32,
+    pub argument_formats: Vec16<i16>,
+    pub arguments: Vec16<ColumnData>,
+    pub result_format: i16,
+}
+
+impl FunctionCall {
+    /// A call with the arguments and the result in binary format
+    pub fn new(function: u32, arguments: Vec<ColumnData>) -> Self {
+        Self {
+            function: function as i32,
+            argument_formats: vec![i16::from(&FormatCode::Binary)].into(),
+            arguments: arguments.into(),
+            result_format: i16::from(&FormatCode::Binary),
+        }
+    }
+}
 
 // FunctionCallResponse (B)
 // * Byte1('V') Identifies the message as a function call result.
@@ -843,6 +1501,19 @@ impl ErrorMessage {
 //       case.
 // * Byten The value of the function result, in the format indicated by the associated format code. n is
 //     the above length.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 'V')]
+pub struct FunctionCallResponse {
+    pub result: ColumnData,
+}
+
+impl FunctionCallResponse {
+    pub fn new(result: Vec<u8>) -> Self {
+        Self {
+            result: result.into(),
+        }
+    }
+}
 
 // GSSENCRequest (F)
 //
@@ -856,6 +1527,17 @@ impl ErrorMessage {
 //   SASL and password response messages. The exact message type can be deduced from the context.
 // * Int32 Length of message contents in bytes, including self.
 // * Byten GSSAPI/SSPI specific message data.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'p')]
+pub struct GSSResponse {
+    pub data: VecEnd<Byte>,
+}
+
+impl GSSResponse {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data: data.into() }
+    }
+}
 
 // NegotiateProtocolVersion (B)
 // * Byte1('v') Identifies the message as a protocol version negotiation message.
@@ -867,10 +1549,38 @@ impl ErrorMessage {
 // Then, for protocol option not recognized by the server, there is the following:
 //
 // * String The option name.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 'v')]
+pub struct NegotiateProtocolVersion {
+    pub newest_minor_version: i32,
+    pub options: Vec32<CString>,
+}
+
+impl NegotiateProtocolVersion {
+    pub fn new(newest_minor_version: i32, options: &[&str]) -> anyhow::Result<Self> {
+        Ok(Self {
+            newest_minor_version,
+            options: options
+                .iter()
+                .map(|option| CString::new(*option))
+                .collect::<Result<Vec<CString>, _>>()?
+                .into(),
+        })
+    }
+}
 
 // NoData (B)
 // * Byte1('n') Identifies the message as a no-data indicator.
 // * Int32(4) Length of message contents in bytes, including self.
+#[derive(Debug, Default, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 'n')]
+pub struct NoData {}
+
+impl NoData {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
 // NoticeResponse (B)
 // * Byte1('N') Identifies the message as a notice.
@@ -883,6 +1593,20 @@ impl ErrorMessage {
 // follows. The presently defined field types are listed in Section 53.8. Since more field types might
 // be added in future, frontends should silently ignore fields of unrecognized type.
 // * String The field value.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 'N')]
+pub struct NoticeResponse {
+    // Same fields as ErrorResponse
+    pub messages: VecNull<ErrorMessage>,
+}
+
+impl NoticeResponse {
+    pub fn new(messages: Vec<ErrorMessage>) -> Self {
+        Self {
+            messages: messages.into(),
+        }
+    }
+}
 
 // NotificationResponse (B)
 // * Byte1('A') Identifies the message as a notification response.
@@ -890,6 +1614,23 @@ impl ErrorMessage {
 // * Int32 The process ID of the notifying backend process.
 // * String The name of the channel that the notify has been raised on.
 // * String The “payload” string passed from the notifying process.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 'A')]
+pub struct NotificationResponse {
+    pub process_id: i32,
+    pub channel: CString,
+    pub payload: CString,
+}
+
+impl NotificationResponse {
+    pub fn new(process_id: i32, channel: &str, payload: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            process_id,
+            channel: CString::new(channel)?,
+            payload: CString::new(payload)?,
+        })
+    }
+}
 
 // ParameterDescription (B)
 // * Byte1('t') Identifies the message as a parameter description.
@@ -899,6 +1640,19 @@ impl ErrorMessage {
 // Then, for each parameter, there is the following:
 //
 // * Int32 Specifies the object ID of the parameter data type.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 't')]
+pub struct ParameterDescription {
+    pub parameter_types: Vec16<i32>,
+}
+
+impl ParameterDescription {
+    pub fn new(parameter_types: Vec<i32>) -> Self {
+        Self {
+            parameter_types: parameter_types.into(),
+        }
+    }
+}
 
 // ParameterStatus (B)
 // * Byte1('S') Identifies the message as a run-time parameter status report.
@@ -908,15 +1662,15 @@ impl ErrorMessage {
 #[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
 #[message_body(kind = 'S')]
 pub struct ParameterStatus {
-    name: CString,
-    value: CString,
+    pub name: CString,
+    pub value: CString,
 }
 
 impl ParameterStatus {
-    pub fn new(name: &String, value: &String) -> anyhow::Result<Self> {
+    pub fn new(name: &str, value: &str) -> anyhow::Result<Self> {
         Ok(Self {
-            name: CString::new(&name[..])?,
-            value: CString::new(&value[..])?,
+            name: CString::new(name)?,
+            value: CString::new(value)?,
         })
     }
 }
@@ -935,10 +1689,36 @@ impl ParameterStatus {
 //
 // * Int32 Specifies the object ID of the parameter data type. Placing a zero here is equivalent to
 //     leaving the type unspecified.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'P')]
+pub struct Parse {
+    pub statement: CString,
+    pub query: CString,
+    pub parameter_types: Vec16<i32>,
+}
+
+impl Parse {
+    pub fn new(statement: &str, query: &str, parameter_types: Vec<i32>) -> anyhow::Result<Self> {
+        Ok(Self {
+            statement: CString::new(statement)?,
+            query: CString::new(query)?,
+            parameter_types: parameter_types.into(),
+        })
+    }
+}
 
 // ParseComplete (B)
 // * Byte1('1') Identifies the message as a Parse-complete indicator.
 // * Int32(4) Length of message contents in bytes, including self.
+#[derive(Debug, Default, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = '1')]
+pub struct ParseComplete {}
+
+impl ParseComplete {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
 // PasswordMessage (F)
 // * Byte1('p') Identifies the message as a password response. Note that this is also used for GSSAPI,
@@ -952,12 +1732,13 @@ pub struct PasswordMessage {
 }
 
 impl PasswordMessage {
-    pub fn new(password: &String) -> anyhow::Result<Self> {
+    pub fn new(password: &str) -> anyhow::Result<Self> {
         Ok(Self {
-            password: CString::new(&password[..])?,
+            password: CString::new(password)?,
         })
     }
 
+    #[cfg(feature = "auth")]
     pub fn new_from_user_password(
         user: &String,
         password: &String,
@@ -981,6 +1762,15 @@ impl PasswordMessage {
 // * Byte1('s') Identifies the message as a portal-suspended indicator. Note this only appears if an
 //       Execute message's row-count limit was reached.
 // * Int32(4) Length of message contents in bytes, including self.
+#[derive(Debug, Default, PartialEq, SerdeLibpqData, MessageBody, TryFromRawBackendMessage)]
+#[message_body(kind = 's')]
+pub struct PortalSuspended {}
+
+impl PortalSuspended {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
 // Query (F)
 // * Byte1('Q') Identifies the message as a simple query.
@@ -1021,19 +1811,26 @@ impl ReadyForQuery {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum TransactionIndicator {
+    #[default]
     Idle,
     IdleInTransaction,
     IdlerInTransactionAborted,
 }
 
-impl From<&Byte> for TransactionIndicator {
-    fn from(item: &Byte) -> TransactionIndicator {
+impl TryFrom<&Byte> for TransactionIndicator {
+    type Error = anyhow::Error;
+
+    fn try_from(item: &Byte) -> anyhow::Result<TransactionIndicator> {
         match *item as char {
-            'I' => TransactionIndicator::Idle,
-            'T' => TransactionIndicator::IdleInTransaction,
-            'E' => TransactionIndicator::IdlerInTransactionAborted,
-            _ => unreachable!("Invalid value for TransactionIndicator"),
+            'I' => Ok(TransactionIndicator::Idle),
+            'T' => Ok(TransactionIndicator::IdleInTransaction),
+            'E' => Ok(TransactionIndicator::IdlerInTransactionAborted),
+            _ => Err(anyhow!(
+                "Invalid transaction status {:?} in ReadyForQuery",
+                *item as char
+            )),
         }
     }
 }
@@ -1041,9 +1838,9 @@ impl From<&Byte> for TransactionIndicator {
 impl From<&TransactionIndicator> for Byte {
     fn from(item: &TransactionIndicator) -> Byte {
         match item {
-            TransactionIndicator::Idle => 'I' as Byte,
-            TransactionIndicator::IdleInTransaction => 'T' as Byte,
-            TransactionIndicator::IdlerInTransactionAborted => 'E' as Byte,
+            TransactionIndicator::Idle => b'I',
+            TransactionIndicator::IdleInTransaction => b'T',
+            TransactionIndicator::IdlerInTransactionAborted => b'E',
         }
     }
 }
@@ -1081,7 +1878,7 @@ impl RowDescription {
     }
 }
 
-#[derive(Debug, PartialEq, SerdeLibpqData)]
+#[derive(Debug, Clone, PartialEq, SerdeLibpqData)]
 pub struct ColumnDescription {
     pub name: CString,
     pub relation_id: i32,
@@ -1093,9 +1890,9 @@ pub struct ColumnDescription {
 }
 
 impl ColumnDescription {
-    pub fn new(name: &String, pgtype: PgType) -> anyhow::Result<Self> {
+    pub fn new(name: &str, pgtype: PgType) -> anyhow::Result<Self> {
         Ok(Self {
-            name: CString::new(&name[..])?,
+            name: CString::new(name)?,
             relation_id: 0,
             attribute_id: 0,
             datatype_id: i32::from(&pgtype),
@@ -1106,12 +1903,38 @@ impl ColumnDescription {
     }
 }
 
-#[derive(Debug)]
+/// Build the columns of a RowDescription from a list of names and types:
+/// `columns![("id", Int8), ("name", Text.nullable())]`
+///
+/// The types are the variants of PgType, the result is an
+/// `anyhow::Result<Vec<ColumnDescription>>`. RowDescription doesn't tell
+/// whether a column is nullable, any column can hold a PgValue::Null:
+/// `.nullable()` is accepted to document the schema and changes nothing.
+#[macro_export]
+macro_rules! columns {
+    ($(($name:expr, $pg_type:ident $(.nullable())?)),* $(,)?) => {
+        [$(($name, $crate::message::PgType::$pg_type)),*]
+            .into_iter()
+            .map(|(name, pg_type)| $crate::message::ColumnDescription::new(name, pg_type))
+            .collect::<anyhow::Result<Vec<$crate::message::ColumnDescription>>>()
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PgType {
     Bool,
     Int4,
+    Int8,
     Text,
     Oid,
+    Date,
+    Timestamp,
+    Interval,
+    Numeric,
+    Json,
+    Jsonb,
+    Uuid,
+    Bytea,
 }
 
 impl From<&PgType> for i32 {
@@ -1119,35 +1942,140 @@ impl From<&PgType> for i32 {
         match pg_type {
             PgType::Bool => 16,
             PgType::Int4 => 23,
+            PgType::Int8 => 20,
             PgType::Text => 25,
             PgType::Oid => 26,
+            PgType::Date => 1082,
+            PgType::Timestamp => 1114,
+            PgType::Interval => 1186,
+            PgType::Numeric => 1700,
+            PgType::Json => 114,
+            PgType::Jsonb => 3802,
+            PgType::Uuid => 2950,
+            PgType::Bytea => 17,
+        }
+    }
+}
+
+impl TryFrom<i32> for PgType {
+    type Error = anyhow::Error;
+
+    fn try_from(oid: i32) -> anyhow::Result<PgType> {
+        match oid {
+            16 => Ok(PgType::Bool),
+            23 => Ok(PgType::Int4),
+            20 => Ok(PgType::Int8),
+            25 => Ok(PgType::Text),
+            26 => Ok(PgType::Oid),
+            1082 => Ok(PgType::Date),
+            1114 => Ok(PgType::Timestamp),
+            1186 => Ok(PgType::Interval),
+            1700 => Ok(PgType::Numeric),
+            114 => Ok(PgType::Json),
+            3802 => Ok(PgType::Jsonb),
+            2950 => Ok(PgType::Uuid),
+            17 => Ok(PgType::Bytea),
+            _ => Err(anyhow!("Unsupported type oid: {oid}")),
         }
     }
 }
 
+impl std::str::FromStr for PgType {
+    type Err = anyhow::Error;
+
+    /// The type named like in pg_type, e.g. int8
+    fn from_str(name: &str) -> anyhow::Result<PgType> {
+        PgType::ALL
+            .into_iter()
+            .find(|pg_type| pg_type.typname() == name)
+            .ok_or(anyhow!("Unsupported type: {name}"))
+    }
+}
+
 impl PgType {
+    pub const ALL: [PgType; 13] = [
+        PgType::Bool,
+        PgType::Int4,
+        PgType::Int8,
+        PgType::Text,
+        PgType::Oid,
+        PgType::Date,
+        PgType::Timestamp,
+        PgType::Interval,
+        PgType::Numeric,
+        PgType::Json,
+        PgType::Jsonb,
+        PgType::Uuid,
+        PgType::Bytea,
+    ];
+
+    pub fn typname(&self) -> &'static str {
+        match &self {
+            PgType::Bool => "bool",
+            PgType::Int4 => "int4",
+            PgType::Int8 => "int8",
+            PgType::Text => "text",
+            PgType::Oid => "oid",
+            PgType::Date => "date",
+            PgType::Timestamp => "timestamp",
+            PgType::Interval => "interval",
+            PgType::Numeric => "numeric",
+            PgType::Json => "json",
+            PgType::Jsonb => "jsonb",
+            PgType::Uuid => "uuid",
+            PgType::Bytea => "bytea",
+        }
+    }
+
     pub fn typlen(&self) -> i16 {
         match &self {
             PgType::Bool => 1,
             PgType::Int4 => 4,
+            PgType::Int8 => 8,
             PgType::Text => -1,
             PgType::Oid => 4,
+            PgType::Date => 4,
+            PgType::Timestamp => 8,
+            PgType::Interval => 16,
+            PgType::Numeric => -1,
+            PgType::Json => -1,
+            PgType::Jsonb => -1,
+            PgType::Uuid => 16,
+            PgType::Bytea => -1,
         }
     }
     pub fn typmod(&self) -> i32 {
         match &self {
             PgType::Bool => -1,
             PgType::Int4 => -1,
+            PgType::Int8 => -1,
             PgType::Text => -1,
             PgType::Oid => -1,
+            PgType::Date => -1,
+            PgType::Timestamp => -1,
+            PgType::Interval => -1,
+            PgType::Numeric => -1,
+            PgType::Json => -1,
+            PgType::Jsonb => -1,
+            PgType::Uuid => -1,
+            PgType::Bytea => -1,
         }
     }
     pub fn format(&self) -> i16 {
         match &self {
             PgType::Bool => 0,
             PgType::Int4 => 0,
-            PgType::Text => 1,
+            PgType::Int8 => 0,
+            PgType::Text => 0,
             PgType::Oid => 0,
+            PgType::Date => 0,
+            PgType::Timestamp => 0,
+            PgType::Interval => 0,
+            PgType::Numeric => 0,
+            PgType::Json => 0,
+            PgType::Jsonb => 0,
+            PgType::Uuid => 0,
+            PgType::Bytea => 0,
         }
     }
 }
@@ -1160,14 +2088,39 @@ impl PgType {
 // * Int32 Length of SASL mechanism specific "Initial Client Response" that follows, or -1 if there is
 //     no Initial Response.
 // * Byten SASL mechanism specific "Initial Response".
-//TODO: implement
+// The initial response is always sent, the length -1 is not supported.
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'p')]
+pub struct SASLInitialResponse {
+    pub mechanism: CString,
+    pub data: Vec32<Byte>,
+}
+
+impl SASLInitialResponse {
+    pub fn new(mechanism: &str, data: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(Self {
+            mechanism: CString::new(mechanism)?,
+            data: data.into(),
+        })
+    }
+}
 
 // SASLResponse (F)
 // * Byte1('p') Identifies the message as a SASL response. Note that this is also used for GSSAPI, SSPI
 //   and password response messages. The exact message type can be deduced from the context.
 // * Int32 Length of message contents in bytes, including self.
 // * Byten SASL mechanism specific message data.
-//TODO: implement
+#[derive(Debug, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'p')]
+pub struct SASLResponse {
+    pub data: VecEnd<Byte>,
+}
+
+impl SASLResponse {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data: data.into() }
+    }
+}
 
 // SSLRequest (F)
 // * Int32(8) Length of message contents in bytes, including self.
@@ -1243,25 +2196,113 @@ pub struct ProtocolVersion {
 // Sync (F)
 // * Byte1('S') Identifies the message as a Sync command.
 // * Int32(4) Length of message contents in bytes, including self.
+#[derive(Debug, Default, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'S')]
+pub struct Sync {}
+
+impl Sync {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
 
 // Terminate (F)
 // * Byte1('X') Identifies the message as a termination.
 // * Int32(4)
 // Length of message contents in bytes, including self.
+#[derive(Debug, Default, PartialEq, SerdeLibpqData, MessageBody, TryFromRawFrontendMessage)]
+#[message_body(kind = 'X')]
+pub struct Terminate {}
+
+impl Terminate {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+//*----------------------------------------------------------------------------
+// Constant messages
+//*----------------------------------------------------------------------------
+
+/// A message encoded at compile time, for the messages that never change
+/// (e.g. BindComplete) so that they are written without serialization.
+#[derive(Debug, PartialEq)]
+pub struct StaticMessage {
+    pub name: &'static str,
+    pub bytes: &'static [u8],
+}
+
+impl StaticMessage {
+    pub const fn message_type(&self) -> u8 {
+        self.bytes[0]
+    }
+}
+
+// The messages with an empty body only have a header
+const fn header_only(message_type: u8) -> [u8; 5] {
+    [message_type, 0, 0, 0, 4]
+}
+
+pub const PARSE_COMPLETE: StaticMessage = StaticMessage {
+    name: "ParseComplete",
+    bytes: &header_only(b'1'),
+};
+pub const BIND_COMPLETE: StaticMessage = StaticMessage {
+    name: "BindComplete",
+    bytes: &header_only(b'2'),
+};
+pub const CLOSE_COMPLETE: StaticMessage = StaticMessage {
+    name: "CloseComplete",
+    bytes: &header_only(b'3'),
+};
+pub const NO_DATA: StaticMessage = StaticMessage {
+    name: "NoData",
+    bytes: &header_only(b'n'),
+};
+pub const EMPTY_QUERY_RESPONSE: StaticMessage = StaticMessage {
+    name: "EmptyQueryResponse",
+    bytes: &header_only(b'I'),
+};
+pub const PORTAL_SUSPENDED: StaticMessage = StaticMessage {
+    name: "PortalSuspended",
+    bytes: &header_only(b's'),
+};
+pub const AUTHENTICATION_OK: StaticMessage = StaticMessage {
+    name: "AuthenticationOk",
+    bytes: &[b'R', 0, 0, 0, 8, 0, 0, 0, 0],
+};
+
+/// ReadyForQuery for each transaction status
+pub const fn ready_for_query(transaction: TransactionIndicator) -> &'static StaticMessage {
+    match transaction {
+        TransactionIndicator::Idle => &StaticMessage {
+            name: "ReadyForQuery(Idle)",
+            bytes: &[b'Z', 0, 0, 0, 5, b'I'],
+        },
+        TransactionIndicator::IdleInTransaction => &StaticMessage {
+            name: "ReadyForQuery(IdleInTransaction)",
+            bytes: &[b'Z', 0, 0, 0, 5, b'T'],
+        },
+        TransactionIndicator::IdlerInTransactionAborted => &StaticMessage {
+            name: "ReadyForQuery(IdlerInTransactionAborted)",
+            bytes: &[b'Z', 0, 0, 0, 5, b'E'],
+        },
+    }
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
     use bytes::{Bytes, BytesMut};
-    use std::io::{BufReader, Cursor, Read};
+    use std::ffi::CStr;
 
     #[test]
     fn authentication_ok_serialize() -> anyhow::Result<()> {
         // serialize
         let m = AuthenticationOk::new();
         let h = MessageHeader {
-            message_type: 'R' as u8,
-            length: 4 + m.byte_size(),
+            message_type: b'R',
+            length: MessageHeader::length_for(m.byte_size())?,
         };
 
         let mut buffer = BytesMut::new();
@@ -1279,8 +2320,8 @@ mod test {
     fn authentication_ok_deserialize() -> anyhow::Result<()> {
         let m = AuthenticationOk::new();
         let h = MessageHeader {
-            message_type: 'R' as u8,
-            length: 4 + m.byte_size(),
+            message_type: b'R',
+            length: MessageHeader::length_for(m.byte_size())?,
         };
 
         let mut buffer = Bytes::from(vec![0x52, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00]);
@@ -1293,14 +2334,15 @@ mod test {
         Ok(())
     }
 
+    #[test]
     fn datarow_emptydata_deserialize() -> anyhow::Result<()> {
         // Empty Row Data message
         // 0x0050:                      4400 0000 0a00 0100  ........D.......
         // 0x0060:  0000 00
         let m = DataRow::new(Vec::<ColumnData>::from([ColumnData::new()]));
         let h = MessageHeader {
-            message_type: 'D' as u8,
-            length: 4 + m.byte_size(),
+            message_type: b'D',
+            length: MessageHeader::length_for(m.byte_size())?,
         };
 
         let mut buffer = Bytes::from(vec![
@@ -1320,15 +2362,15 @@ mod test {
         // Empty Row Data message
         // 0x0050:                      4400 0000 0a00 0100  ........D.......
         // 0x0060:  0000 00
-        let col_data = Vec::<Byte>::from(['1' as u8]);
+        let col_data = Vec::<Byte>::from([b'1']);
         let m = DataRow::new(Vec::<ColumnData>::from([ColumnData::from(col_data)]));
         let h = MessageHeader {
-            message_type: 'D' as u8,
-            length: 4 + m.byte_size(),
+            message_type: b'D',
+            length: MessageHeader::length_for(m.byte_size())?,
         };
 
         let mut buffer = Bytes::from(vec![
-            0x44, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, '1' as u8,
+            0x44, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, b'1',
         ]);
         let h2 = MessageHeader::deserialize(&mut buffer)?;
         let m2 = DataRow::deserialize(&mut buffer)?;
@@ -1338,4 +2380,304 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn bind_deserialize() -> anyhow::Result<()> {
+        // Bind to the unnamed portal from statement "s1" with two result
+        // formats: text then binary
+        let m = Bind::new(
+            "",
+            "s1",
+            vec![],
+            vec![],
+            vec![FormatCode::Text, FormatCode::Binary],
+        )?;
+        let h = MessageHeader {
+            message_type: b'B',
+            length: MessageHeader::length_for(m.byte_size())?,
+        };
+
+        let mut buffer = Bytes::from(vec![
+            0x42, 0x00, 0x00, 0x00, 0x12, 0x00, b's', b'1', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00, 0x01,
+        ]);
+        let h2 = MessageHeader::deserialize(&mut buffer)?;
+        let m2 = Bind::deserialize(&mut buffer)?;
+
+        assert_eq!(m, m2);
+        assert_eq!(h, h2);
+        assert_eq!(
+            vec![FormatCode::Text, FormatCode::Binary],
+            m2.result_formats()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn format_code_for_column() -> anyhow::Result<()> {
+        // No format code: everything is text
+        assert_eq!(FormatCode::Text, FormatCode::for_column(&[], 3)?);
+        // One format code: applies to every column
+        assert_eq!(
+            FormatCode::Binary,
+            FormatCode::for_column(&[FormatCode::Binary], 3)?
+        );
+        // One format code per column
+        let formats = [FormatCode::Binary, FormatCode::Text];
+        assert_eq!(FormatCode::Binary, FormatCode::for_column(&formats, 0)?);
+        assert_eq!(FormatCode::Text, FormatCode::for_column(&formats, 1)?);
+        assert!(FormatCode::for_column(&formats, 2).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn datarow_from_values_serialize() -> anyhow::Result<()> {
+        let m = DataRow::new_from_values(
+            &[PgValue::Int4(1), PgValue::Int4(1)],
+            &[FormatCode::Text, FormatCode::Binary],
+            &OutputSettings::default(),
+        )?;
+
+        let mut buffer = BytesMut::new();
+        m.serialize(&mut buffer);
+
+        let e = vec![
+            0x00, 0x02, 0x00, 0x00, 0x00, 0x01, b'1', 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00,
+            0x01,
+        ];
+
+        assert_eq!(e, buffer.to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn columns_macro() -> anyhow::Result<()> {
+        let columns = crate::columns![("id", Int8), ("name", Text.nullable()), ("at", Timestamp)]?;
+
+        let fields: Vec<(&CStr, i32, i16, i32, i16, i32, i16)> = columns
+            .iter()
+            .map(|column| {
+                (
+                    column.name.as_c_str(),
+                    column.relation_id,
+                    column.attribute_id,
+                    column.datatype_id,
+                    column.datatype_len,
+                    column.datatype_mod,
+                    column.format,
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (c"id", 0, 0, 20, 8, -1, 0),
+                (c"name", 0, 0, 25, -1, -1, 0),
+                (c"at", 0, 0, 1114, 8, -1, 0),
+            ],
+            fields
+        );
+        // .nullable() only documents the schema
+        assert_eq!(
+            crate::columns![("name", Text)]?[0],
+            crate::columns![("name", Text.nullable())]?[0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn static_messages() -> anyhow::Result<()> {
+        fn serialize<T: MessageBody + Serialize + ByteSized>(message: T) -> Vec<u8> {
+            let mut buffer = BytesMut::new();
+            MessageHeader::new_raw_header_from_body(&mut buffer, &message).expect("message length");
+            message.serialize(&mut buffer);
+            buffer.to_vec()
+        }
+
+        assert_eq!(serialize(ParseComplete::new()), PARSE_COMPLETE.bytes);
+        assert_eq!(serialize(BindComplete::new()), BIND_COMPLETE.bytes);
+        assert_eq!(serialize(CloseComplete::new()), CLOSE_COMPLETE.bytes);
+        assert_eq!(serialize(NoData::new()), NO_DATA.bytes);
+        assert_eq!(serialize(PortalSuspended::new()), PORTAL_SUSPENDED.bytes);
+        assert_eq!(serialize(AuthenticationOk::new()), AUTHENTICATION_OK.bytes);
+        for transaction in [
+            TransactionIndicator::Idle,
+            TransactionIndicator::IdleInTransaction,
+            TransactionIndicator::IdlerInTransactionAborted,
+        ] {
+            assert_eq!(
+                serialize(ReadyForQuery::new(transaction)),
+                ready_for_query(transaction).bytes
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn serialize_message_length() -> anyhow::Result<()> {
+        // byte_size() disagrees with serialize()
+        #[derive(Debug)]
+        struct Wrong;
+        impl MessageBody for Wrong {
+            fn message_type(&self) -> u8 {
+                b'W'
+            }
+        }
+        impl Serialize for Wrong {
+            fn serialize(&self, buffer: &mut BytesMut) {
+                buffer.put_slice(b"abc");
+            }
+        }
+        impl ByteSized for Wrong {
+            fn byte_size(&self) -> usize {
+                1
+            }
+        }
+
+        let mut buffer = BytesMut::from(&b"xx"[..]);
+        MessageHeader::serialize_message(&mut buffer, &Wrong)?;
+        assert_eq!(b"xxW\x00\x00\x00\x07abc".to_vec(), buffer.to_vec());
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_message() -> anyhow::Result<()> {
+        // Claims more than the 32-bit length can tell, without allocating it
+        #[derive(Debug)]
+        struct Oversized;
+        impl MessageBody for Oversized {
+            fn message_type(&self) -> u8 {
+                b'd'
+            }
+        }
+        impl Serialize for Oversized {
+            fn serialize(&self, _buffer: &mut BytesMut) {
+                panic!("Oversized serialized");
+            }
+        }
+        impl ByteSized for Oversized {
+            fn byte_size(&self) -> usize {
+                i32::MAX as usize - 3
+            }
+        }
+
+        assert_eq!(i32::MAX, MessageHeader::length_for(i32::MAX as usize - 4)?);
+        assert!(MessageHeader::length_for(usize::MAX).is_err());
+        let mut buffer = BytesMut::from(&b"xx"[..]);
+        assert_eq!(
+            "Message body of 2147483644 bytes exceeds the 32-bit length of the protocol",
+            MessageHeader::serialize_message(&mut buffer, &Oversized)
+                .map_err(|e| e.to_string())
+                .expect_err("oversized")
+        );
+        assert_eq!(b"xx".to_vec(), buffer.to_vec());
+        assert!(MessageHeader::new_header_from_body(&Oversized).is_err());
+
+        Ok(())
+    }
+
+    // Every variant is converted to its code and back, the match fails to
+    // build when a variant is added to the enum without being listed here.
+    // The ambiguous variants only check their code, it can't be converted
+    // back without the context.
+    macro_rules! assert_kind_round_trips {
+        ($kind:ident, [$($variant:ident),+ $(,)?] $(, ambiguous: [$($ambiguous:ident),+ $(,)?])?) => {{
+            let listed = |kind: $kind| match kind {
+                $($kind::$variant => true,)+
+                $($($kind::$ambiguous => false,)+)?
+            };
+            $(
+                let code = u8::from(&$kind::$variant);
+                assert!(listed($kind::$variant));
+                assert_eq!($kind::$variant, $kind::try_from(code)?, "{}", code as char);
+            )+
+            $($(
+                let code = u8::from(&$kind::$ambiguous);
+                assert!(!listed($kind::$ambiguous));
+                assert!($kind::is_known(code) && $kind::try_from(code).is_err());
+            )+)?
+            // And the codes left out are not converted
+            for code in 0..=u8::MAX {
+                if let Ok(kind) = $kind::try_from(code) {
+                    assert_eq!(code, u8::from(&kind));
+                }
+            }
+        }};
+    }
+
+    #[test]
+    fn message_kinds_round_trip() -> anyhow::Result<()> {
+        assert_kind_round_trips!(
+            BackendMessageKind,
+            [
+                Authentication,
+                BackendKeyData,
+                BindComplete,
+                CloseCompleten,
+                CommandComplete,
+                CopyData,
+                CopyDone,
+                CopyInResponse,
+                CopyOutResponse,
+                CopyBothResponse,
+                DataRow,
+                EmptyQuery,
+                ErrorResponse,
+                FunctionCallResponse,
+                NegotiateProtocolVersion,
+                NoData,
+                NoticeResponse,
+                NotificationResponse,
+                ParameterDescription,
+                ParameterStatus,
+                ParseComplete,
+                PortalSuspended,
+                ReadyForQuery,
+                RowDescription,
+            ]
+        );
+        assert_kind_round_trips!(
+            FrontendMessageKind,
+            [
+                Bind,
+                Close,
+                CopyData,
+                CopyDone,
+                CopyFail,
+                Describe,
+                Execute,
+                Flush,
+                FunctionCall,
+                Parse,
+                Query,
+                Sync,
+                Terminate,
+            ],
+            ambiguous: [
+                GSSResponse,
+                PasswordMessage,
+                SASLInitialResponse,
+                SASLResponse,
+            ]
+        );
+
+        // The authentication messages don't derive TryFromRawBackendMessage
+        for code in [
+            AuthenticationOk::new().message_type(),
+            AuthenticationGSS::new().message_type(),
+            AuthenticationSSPI::new().message_type(),
+        ] {
+            assert_eq!(
+                BackendMessageKind::Authentication,
+                BackendMessageKind::try_from(code)?
+            );
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,261 @@
+use anyhow::anyhow;
+use std::{fmt, str::FromStr};
+
+// The digits are stored in base 10000, like in PostgreSQL
+const NBASE: i32 = 10000;
+const DEC_DIGITS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NumericSign {
+    Positive,
+    Negative,
+    NaN,
+    Infinity,
+    NegativeInfinity,
+}
+
+impl From<NumericSign> for u16 {
+    fn from(sign: NumericSign) -> u16 {
+        match sign {
+            NumericSign::Positive => 0x0000,
+            NumericSign::Negative => 0x4000,
+            NumericSign::NaN => 0xC000,
+            NumericSign::Infinity => 0xD000,
+            NumericSign::NegativeInfinity => 0xF000,
+        }
+    }
+}
+
+impl TryFrom<u16> for NumericSign {
+    type Error = anyhow::Error;
+
+    fn try_from(sign: u16) -> anyhow::Result<NumericSign> {
+        match sign {
+            0x0000 => Ok(NumericSign::Positive),
+            0x4000 => Ok(NumericSign::Negative),
+            0xC000 => Ok(NumericSign::NaN),
+            0xD000 => Ok(NumericSign::Infinity),
+            0xF000 => Ok(NumericSign::NegativeInfinity),
+            _ => Err(anyhow!("Invalid numeric sign: {sign:#x}")),
+        }
+    }
+}
+
+/// An arbitrary precision number in the representation of the binary
+/// format: the value is the sum of digits[i] * 10000^(weight - i), and
+/// dscale is the number of decimal digits after the point to display.
+///
+/// The digits never have leading or trailing zeros, zero has no digits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgNumeric {
+    pub sign: NumericSign,
+    pub weight: i16,
+    pub dscale: u16,
+    pub digits: Vec<i16>,
+}
+
+impl PgNumeric {
+    fn special(sign: NumericSign) -> Self {
+        Self {
+            sign,
+            weight: 0,
+            dscale: 0,
+            digits: Vec::new(),
+        }
+    }
+
+    /// Strip the leading and trailing zeros of the digits
+    fn normalize(mut self) -> Self {
+        let leading = self.digits.iter().take_while(|d| **d == 0).count();
+        self.digits.drain(..leading);
+        self.weight -= leading as i16;
+        while self.digits.last() == Some(&0) {
+            self.digits.pop();
+        }
+        if self.digits.is_empty() {
+            self.weight = 0;
+            if self.sign == NumericSign::Negative {
+                self.sign = NumericSign::Positive;
+            }
+        }
+        self
+    }
+
+    // The digit of base 10000 with the given weight, 0 outside of the digits
+    fn digit(&self, weight: i32) -> i16 {
+        usize::try_from(i32::from(self.weight) - weight)
+            .ok()
+            .and_then(|index| self.digits.get(index))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8 + 2 * self.digits.len());
+        data.extend_from_slice(&(self.digits.len() as i16).to_be_bytes());
+        data.extend_from_slice(&self.weight.to_be_bytes());
+        data.extend_from_slice(&u16::from(self.sign).to_be_bytes());
+        data.extend_from_slice(&self.dscale.to_be_bytes());
+        for digit in &self.digits {
+            data.extend_from_slice(&digit.to_be_bytes());
+        }
+        data
+    }
+
+    pub fn from_binary(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 8 {
+            return Err(anyhow!("Invalid numeric: {data:?}"));
+        }
+        let field = |index: usize| [data[2 * index], data[2 * index + 1]];
+        let ndigits = usize::try_from(i16::from_be_bytes(field(0)))?;
+        if data.len() != 8 + 2 * ndigits {
+            return Err(anyhow!("Invalid numeric: {data:?}"));
+        }
+        let numeric = Self {
+            weight: i16::from_be_bytes(field(1)),
+            sign: NumericSign::try_from(u16::from_be_bytes(field(2)))?,
+            dscale: u16::from_be_bytes(field(3)),
+            digits: (4..4 + ndigits)
+                .map(|index| i16::from_be_bytes(field(index)))
+                .collect(),
+        };
+        if numeric
+            .digits
+            .iter()
+            .any(|d| !(0..NBASE as i16).contains(d))
+        {
+            return Err(anyhow!("Invalid numeric digits: {:?}", numeric.digits));
+        }
+        Ok(numeric)
+    }
+}
+
+impl FromStr for PgNumeric {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        let text = text.trim();
+        match &text.to_lowercase()[..] {
+            "nan" => return Ok(Self::special(NumericSign::NaN)),
+            "infinity" | "+infinity" | "inf" | "+inf" => {
+                return Ok(Self::special(NumericSign::Infinity));
+            }
+            "-infinity" | "-inf" => return Ok(Self::special(NumericSign::NegativeInfinity)),
+            _ => (),
+        }
+
+        let (sign, unsigned) = match text.strip_prefix('-') {
+            Some(unsigned) => (NumericSign::Negative, unsigned),
+            None => (
+                NumericSign::Positive,
+                text.strip_prefix('+').unwrap_or(text),
+            ),
+        };
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if integer.is_empty() && fraction.is_empty()
+            || !integer
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(anyhow!("Invalid numeric: {text}"));
+        }
+
+        // Align both parts on the groups of 4 digits around the point
+        let integer = integer.trim_start_matches('0');
+        let integer_groups = integer.len().div_ceil(DEC_DIGITS);
+        let padded = format!(
+            "{integer:0>width$}{fraction:0<fraction_width$}",
+            width = integer_groups * DEC_DIGITS,
+            fraction_width = fraction.len().div_ceil(DEC_DIGITS) * DEC_DIGITS
+        );
+        let digits = padded
+            .as_bytes()
+            .chunks(DEC_DIGITS)
+            .map(|chunk| Ok(std::str::from_utf8(chunk)?.parse::<i16>()?))
+            .collect::<anyhow::Result<Vec<i16>>>()?;
+
+        Ok(Self {
+            sign,
+            weight: i16::try_from(integer_groups)? - 1,
+            dscale: u16::try_from(fraction.len())?,
+            digits,
+        }
+        .normalize())
+    }
+}
+
+impl fmt::Display for PgNumeric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.sign {
+            NumericSign::NaN => return write!(f, "NaN"),
+            NumericSign::Infinity => return write!(f, "Infinity"),
+            NumericSign::NegativeInfinity => return write!(f, "-Infinity"),
+            NumericSign::Negative => write!(f, "-")?,
+            NumericSign::Positive => (),
+        }
+
+        // The integer part, the first digit is not padded
+        let weight = i32::from(self.weight);
+        if weight < 0 {
+            write!(f, "0")?;
+        } else {
+            write!(f, "{}", self.digit(weight))?;
+            for w in (0..weight).rev() {
+                write!(f, "{:04}", self.digit(w))?;
+            }
+        }
+
+        // The fractional part, cut to dscale
+        if self.dscale > 0 {
+            let dscale = usize::from(self.dscale);
+            let groups = dscale.div_ceil(DEC_DIGITS) as i32;
+            let fraction: String = (1..=groups)
+                .map(|w| format!("{:04}", self.digit(-w)))
+                .collect();
+            write!(f, ".{}", &fraction[..dscale])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn numeric_encode() -> anyhow::Result<()> {
+        // The output of numeric_send() in PostgreSQL
+        for (text, binary) in [
+            ("0", "0000000000000000"),
+            ("123.45", "0002000000000002007b1194"),
+            ("-0.00012", "0002ffff40000005000107d0"),
+            ("10000", "00010001000000000001"),
+            ("1.50", "000200000000000200011388"),
+            ("NaN", "00000000c0000000"),
+            (
+                "123456789.000000001",
+                "0006000200000009000109291a850000000003e8",
+            ),
+            ("0.000", "0000000000000003"),
+            ("-5", "00010000400000000005"),
+        ] {
+            let numeric = PgNumeric::from_str(text)?;
+            let hex: String = numeric
+                .to_binary()
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            assert_eq!(binary, hex, "{text}");
+            assert_eq!(text, numeric.to_string());
+            assert_eq!(numeric, PgNumeric::from_binary(&numeric.to_binary())?);
+        }
+        assert_eq!("-Infinity", PgNumeric::from_str("-inf")?.to_string());
+        assert_eq!("12.5", PgNumeric::from_str("+0012.5")?.to_string());
+        assert_eq!("0", PgNumeric::from_str("-0")?.to_string());
+        assert!(PgNumeric::from_str("1.2.3").is_err());
+        assert!(PgNumeric::from_str(".").is_err());
+
+        Ok(())
+    }
+}
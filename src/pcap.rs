@@ -0,0 +1,437 @@
+//! Import the PostgreSQL connections of a pcap file (tcpdump -w) as a
+//! capture with a session per connection, to replay or analyze them.
+//!
+//! The packets are grouped by connection (the addresses and ports of both
+//! ends) and the TCP stream of each side is put back in order: the
+//! retransmitted segments are dropped and the ones arriving out of order
+//! wait for the gap before them to be filled. The messages are then split
+//! like in a recording, and added to the capture in the order their last
+//! byte was captured.
+//!
+//! The side connecting to the server port is the frontend. A connection is
+//! only imported from its SYN, the messages can't be found in a stream
+//! captured from its middle. The pcapng format and the IP fragments are not
+//! supported.
+
+use anyhow::anyhow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tracing::*;
+
+use crate::capture::{Capture, CapturingWriter, Recording};
+use crate::validator::Direction;
+
+// The link types of the pcap header
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+const TCP: u8 = 6;
+
+/// Whether the bytes start like a pcap file, in either byte order
+pub fn is_pcap(bytes: &[u8]) -> bool {
+    matches!(
+        bytes.get(..4),
+        Some([0xa1, 0xb2, 0xc3, 0xd4] | [0xd4, 0xc3, 0xb2, 0xa1])
+            | Some([0xa1, 0xb2, 0x3c, 0x4d] | [0x4d, 0x3c, 0xb2, 0xa1])
+    )
+}
+
+/// A connection imported as a session of the capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    pub session: u32,
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+}
+
+/// A TCP segment of a packet
+#[derive(Debug)]
+struct Segment<'a> {
+    source: SocketAddr,
+    destination: SocketAddr,
+    sequence: u32,
+    syn: bool,
+    ack: bool,
+    // FIN or RST
+    closing: bool,
+    payload: &'a [u8],
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// The IP packet of a frame, None for the other protocols
+fn ip_packet(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+    match link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            // 802.1Q and 802.1ad VLAN tags
+            while matches!(u16_at(frame, offset)?, 0x8100 | 0x88a8) {
+                offset += 4;
+            }
+            match u16_at(frame, offset)? {
+                0x0800 | 0x86dd => frame.get(offset + 2..),
+                _ => None,
+            }
+        }
+        LINKTYPE_LINUX_SLL => match u16_at(frame, 14)? {
+            0x0800 | 0x86dd => frame.get(16..),
+            _ => None,
+        },
+        LINKTYPE_LINUX_SLL2 => match u16_at(frame, 0)? {
+            0x0800 | 0x86dd => frame.get(20..),
+            _ => None,
+        },
+        // The family in the byte order of the host that captured, the
+        // version of the IP header tells the same
+        LINKTYPE_NULL => frame.get(4..),
+        LINKTYPE_RAW => Some(frame),
+        _ => None,
+    }
+}
+
+fn tcp_segment(packet: &[u8]) -> Option<Segment<'_>> {
+    let (source, destination, tcp) = match packet.first()? >> 4 {
+        4 => {
+            let header_length = usize::from(packet[0] & 0x0f) * 4;
+            let total_length = usize::from(u16_at(packet, 2)?);
+            // A fragment, the more fragments flag or an offset
+            if u16_at(packet, 6)? & 0x3fff != 0 || *packet.get(9)? != TCP {
+                return None;
+            }
+            let ip = |offset| -> Option<IpAddr> {
+                let bytes: [u8; 4] = packet.get(offset..offset + 4)?.try_into().ok()?;
+                Some(Ipv4Addr::from(bytes).into())
+            };
+            // The Ethernet padding is after the total length
+            let tcp = packet.get(header_length..total_length.min(packet.len()))?;
+            (ip(12)?, ip(16)?, tcp)
+        }
+        6 => {
+            let ip = |offset| -> Option<IpAddr> {
+                let bytes: [u8; 16] = packet.get(offset..offset + 16)?.try_into().ok()?;
+                Some(Ipv6Addr::from(bytes).into())
+            };
+            let end = (40 + usize::from(u16_at(packet, 4)?)).min(packet.len());
+            let mut next_header = *packet.get(6)?;
+            let mut offset = 40;
+            // The hop-by-hop, routing and destination options headers
+            while matches!(next_header, 0 | 43 | 60) {
+                next_header = *packet.get(offset)?;
+                offset += (usize::from(*packet.get(offset + 1)?) + 1) * 8;
+            }
+            if next_header != TCP {
+                return None;
+            }
+            (ip(8)?, ip(24)?, packet.get(offset..end)?)
+        }
+        _ => return None,
+    };
+    let flags = *tcp.get(13)?;
+    Some(Segment {
+        source: SocketAddr::new(source, u16_at(tcp, 0)?),
+        destination: SocketAddr::new(destination, u16_at(tcp, 2)?),
+        sequence: u32_at(tcp, 4)?,
+        syn: flags & 0x02 != 0,
+        ack: flags & 0x10 != 0,
+        closing: flags & 0x05 != 0,
+        payload: tcp.get(usize::from(tcp.get(12)? >> 4) * 4..)?,
+    })
+}
+
+/// The bytes of one side of a connection, put back in order
+struct Stream {
+    // The sequence number of the first byte, known from the SYN
+    first: Option<u32>,
+    // The bytes written so far
+    written: u64,
+    // The segments after a gap, by their position in the stream
+    pending: BTreeMap<u64, Vec<u8>>,
+    writer: CapturingWriter<io::Sink>,
+}
+
+impl Stream {
+    fn new(sender: Direction, recording: Recording) -> Self {
+        Self {
+            first: None,
+            written: 0,
+            pending: BTreeMap::new(),
+            writer: CapturingWriter::new(io::sink(), sender, recording),
+        }
+    }
+
+    fn segment(&mut self, segment: &Segment) -> io::Result<()> {
+        let mut sequence = segment.sequence;
+        if segment.syn {
+            // The SYN takes a sequence number before the data
+            sequence = sequence.wrapping_add(1);
+            self.first.get_or_insert(sequence);
+        }
+        let Some(first) = self.first else {
+            return Ok(());
+        };
+        if segment.payload.is_empty() {
+            return Ok(());
+        }
+        let position = u64::from(sequence.wrapping_sub(first));
+        if position > self.written {
+            let pending = self.pending.entry(position).or_default();
+            if pending.len() < segment.payload.len() {
+                *pending = segment.payload.to_vec();
+            }
+            return Ok(());
+        }
+        self.write(position, segment.payload)?;
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.written {
+                break;
+            }
+            let (position, payload) = entry.remove_entry();
+            self.write(position, &payload)?;
+        }
+        Ok(())
+    }
+
+    /// Write what follows the bytes already written, a retransmission
+    /// writes nothing
+    fn write(&mut self, position: u64, payload: &[u8]) -> io::Result<()> {
+        let end = position + payload.len() as u64;
+        if end > self.written {
+            self.writer
+                .write_all(&payload[(self.written - position) as usize..])?;
+            self.written = end;
+        }
+        Ok(())
+    }
+}
+
+struct TcpConnection {
+    connection: Connection,
+    frontend: Stream,
+    backend: Stream,
+    closed: bool,
+}
+
+impl TcpConnection {
+    fn warn_gaps(&self) {
+        for (side, stream) in [("frontend", &self.frontend), ("backend", &self.backend)] {
+            if !stream.pending.is_empty() {
+                warn!(
+                    "Session {}: the {side} stream stops at byte {}, segments are missing",
+                    self.connection.session, stream.written
+                );
+            }
+        }
+    }
+}
+
+/// The connections to `port` of a pcap file, each as a session of the
+/// capture
+pub fn import(bytes: &[u8], port: u16) -> anyhow::Result<(Capture, Vec<Connection>)> {
+    let header = bytes.get(..24).ok_or(anyhow!("Truncated pcap header"))?;
+    if !is_pcap(header) {
+        return Err(anyhow!("Not a pcap file, pcapng is not supported"));
+    }
+    // The magic number is written in the byte order of the fields
+    let big_endian = header[0] == 0xa1;
+    let u32_field = |bytes: &[u8], offset: usize| -> Option<u32> {
+        let field = bytes.get(offset..offset + 4)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u32::from_be_bytes(field),
+            false => u32::from_le_bytes(field),
+        })
+    };
+    let link_type = u32_field(header, 20).unwrap_or_default() & 0x0fff_ffff;
+
+    let recording = Recording::new();
+    let mut connections: HashMap<(SocketAddr, SocketAddr), TcpConnection> = HashMap::new();
+    let mut imported = Vec::new();
+    let mut records = &bytes[24..];
+    while !records.is_empty() {
+        let length = u32_field(records, 8).ok_or(anyhow!("Truncated pcap record"))? as usize;
+        let frame = records
+            .get(16..16 + length)
+            .ok_or(anyhow!("Truncated pcap record"))?;
+        records = &records[16 + length..];
+
+        let Some(segment) = ip_packet(link_type, frame).and_then(tcp_segment) else {
+            continue;
+        };
+        let (client, server) = match (segment.source, segment.destination) {
+            (source, destination) if connections.contains_key(&(source, destination)) => {
+                (source, destination)
+            }
+            (source, destination) if connections.contains_key(&(destination, source)) => {
+                (destination, source)
+            }
+            (source, destination) if destination.port() == port => (source, destination),
+            (source, destination) if source.port() == port => (destination, source),
+            _ => continue,
+        };
+        let key = (client, server);
+        let from_client = segment.source == client;
+
+        // A new connection, or the same addresses and ports used again
+        if segment.syn && !segment.ack && from_client {
+            let reused = connections.get(&key).is_some_and(|connection| {
+                connection.closed
+                    || connection.frontend.first != Some(segment.sequence.wrapping_add(1))
+            });
+            if reused && let Some(previous) = connections.remove(&key) {
+                previous.warn_gaps();
+            }
+        }
+        let connection = match connections.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(_) if !segment.syn => {
+                debug!(
+                    "Skipping a packet of {client} -> {server}, its connection started before the capture"
+                );
+                continue;
+            }
+            Entry::Vacant(entry) => {
+                let connection = Connection {
+                    session: imported.len() as u32,
+                    client,
+                    server,
+                };
+                let session = recording.for_session(connection.session);
+                imported.push(connection.clone());
+                entry.insert(TcpConnection {
+                    connection,
+                    frontend: Stream::new(Direction::Frontend, session.clone()),
+                    backend: Stream::new(Direction::Backend, session),
+                    closed: false,
+                })
+            }
+        };
+        let stream = match from_client {
+            true => &mut connection.frontend,
+            false => &mut connection.backend,
+        };
+        stream.segment(&segment)?;
+        connection.closed |= segment.closing;
+    }
+    for connection in connections.values() {
+        connection.warn_gaps();
+    }
+    Ok((recording.capture(), imported))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::*;
+    use bytes::{BufMut, BytesMut};
+    use libpq_serde_types::Serialize;
+
+    /// A record of an Ethernet frame with an IPv4 TCP segment
+    fn packet(source: u16, destination: u16, sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = BytesMut::new();
+        frame.put_slice(&[0; 12]);
+        frame.put_u16(0x0800);
+        frame.put_u8(0x45);
+        frame.put_u8(0);
+        frame.put_u16(20 + 20 + payload.len() as u16);
+        frame.put_slice(&[0, 0, 0x40, 0, 64, TCP, 0, 0]);
+        frame.put_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+        frame.put_u16(source);
+        frame.put_u16(destination);
+        frame.put_u32(sequence);
+        frame.put_u32(0);
+        frame.put_u8(5 << 4);
+        frame.put_u8(flags);
+        frame.put_slice(&[0; 6]);
+        frame.put_slice(payload);
+
+        let mut record = BytesMut::new();
+        record.put_u32_le(0);
+        record.put_u32_le(0);
+        record.put_u32_le(frame.len() as u32);
+        record.put_u32_le(frame.len() as u32);
+        record.put_slice(&frame);
+        record.to_vec()
+    }
+
+    #[test]
+    fn pcap_import() -> anyhow::Result<()> {
+        let mut startup = BytesMut::new();
+        let body = {
+            let mut body = BytesMut::new();
+            StartupMessage::new(
+                ProtocolVersion { major: 3, minor: 0 },
+                vec![ParameterStatus::new("user", "app")?],
+            )
+            .serialize(&mut body);
+            body
+        };
+        startup.put_i32(body.len() as i32 + 4);
+        startup.put_slice(&body);
+        let mut query = BytesMut::new();
+        MessageHeader::serialize_message(&mut query, &Query::new(String::from("SELECT 1"))?)?;
+        let mut ready = BytesMut::new();
+        MessageHeader::serialize_message(
+            &mut ready,
+            &ReadyForQuery::new(TransactionIndicator::Idle),
+        )?;
+
+        const SYN: u8 = 0x02;
+        const ACK: u8 = 0x10;
+        let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        pcap.extend_from_slice(&[0; 8]);
+        pcap.extend_from_slice(&65535u32.to_le_bytes());
+        pcap.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        let startup_end = 1001 + startup.len() as u32;
+        for packet in [
+            // A connection started before the capture
+            packet(40000, 5432, 5, ACK, &query),
+            packet(40001, 5432, 1000, SYN, &[]),
+            packet(5432, 40001, 7000, SYN | ACK, &[]),
+            packet(40002, 5432, 3000, SYN, &[]),
+            packet(40001, 5432, 1001, ACK, &startup),
+            // Out of order, then retransmitted with the missing bytes
+            packet(40002, 5432, 3001 + 4, ACK, &startup[4..]),
+            packet(5432, 40001, 7001, ACK, &ready),
+            packet(40002, 5432, 3001, ACK, &startup),
+            packet(40001, 5432, startup_end, ACK, &query[..3]),
+            packet(40001, 5432, startup_end, ACK, &query),
+        ] {
+            pcap.extend_from_slice(&packet);
+        }
+
+        let (capture, connections) = import(&pcap, 5432)?;
+        assert_eq!(2, connections.len());
+        assert_eq!(40002, connections[1].client.port());
+        let messages: Vec<String> = capture
+            .messages
+            .iter()
+            .map(|message| format!("{} {message}", message.session))
+            .collect();
+        assert_eq!(
+            vec![
+                "0 F StartupMessage protocol_version=3.0 parameters.user=\"app\"",
+                "0 B ReadyForQuery transaction_status='I'",
+                "1 F StartupMessage protocol_version=3.0 parameters.user=\"app\"",
+                "0 F Query query=\"SELECT 1\"",
+            ],
+            messages
+        );
+        assert!(import(b"\x0a\x0d\x0d\x0a", 5432).is_err());
+
+        Ok(())
+    }
+}
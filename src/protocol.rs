@@ -0,0 +1,238 @@
+//! The order of the messages allowed by the protocol, as tables of
+//! transitions shared by the validator, the server handler and the client
+//! handler, so that they agree on what is legal. See
+//! https://www.postgresql.org/docs/17/protocol-flow.html
+//!
+//! The connection is in a Phase, which tells what the frontend may send.
+//! Each frontend command waits for its answer, whose messages move it from
+//! an Answer state to the next one until it is done. The asynchronous
+//! messages (NoticeResponse, ParameterStatus, NotificationResponse) can be
+//! sent by the backend at any time and are not in the tables.
+
+/// The stage of the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    // Until the first ReadyForQuery
+    Startup,
+    Ready,
+    // Between a CopyInResponse and the CopyDone or CopyFail of the frontend
+    CopyIn,
+    // Between a CopyOutResponse and the CopyDone of the backend
+    CopyOut,
+    Terminated,
+}
+
+/// Where the backend is in the answer of a frontend command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    // Query: before the first result, in the rows of a result, after a
+    // result and after an error
+    QueryStarted,
+    QueryRows,
+    QueryCompleted,
+    QueryFailed,
+    Parse,
+    Bind,
+    Describe,
+    // After the ParameterDescription of a statement
+    DescribeParameters,
+    Execute,
+    Close,
+    Sync,
+    FunctionCall,
+    // After the FunctionCallResponse or the error
+    FunctionResult,
+}
+
+/// What a frontend message does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrontendEffect {
+    // A command waiting for an answer starting in this state
+    Command(Answer),
+    // Nothing to answer, e.g. Flush or CopyData
+    Nothing,
+    Enter(Phase),
+}
+
+/// What a backend message answering no command does to the phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendEffect {
+    Stay,
+    Enter(Phase),
+    // An error ending the COPY, the message is then part of the answer of
+    // the command in the Ready phase
+    Abort,
+}
+
+/// What a backend message does to the answer of the first pending command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnswerEffect {
+    Next(Answer),
+    // The command is answered
+    Done,
+    // The answer continues in this state after the COPY
+    CopyIn(Answer),
+    CopyOut(Answer),
+    // An error in the extended query protocol: the backend ignores the
+    // commands up to the next Sync
+    Discard,
+    // Illegal, with a reason more precise than the default one
+    Illegal(&'static str),
+}
+
+/// The messages sent by the backend at any time
+pub const ASYNCHRONOUS: &[u8] = b"NSA";
+
+const READY: &[Phase] = &[Phase::Ready, Phase::CopyOut];
+
+/// The frontend messages allowed in each phase, by type
+pub const FRONTEND_TRANSITIONS: &[(&[Phase], &[u8], FrontendEffect)] = &[
+    // The password, SASL and GSS responses
+    (&[Phase::Startup], b"p", FrontendEffect::Nothing),
+    (READY, b"Q", FrontendEffect::Command(Answer::QueryStarted)),
+    (READY, b"P", FrontendEffect::Command(Answer::Parse)),
+    (READY, b"B", FrontendEffect::Command(Answer::Bind)),
+    (READY, b"D", FrontendEffect::Command(Answer::Describe)),
+    (READY, b"E", FrontendEffect::Command(Answer::Execute)),
+    (READY, b"C", FrontendEffect::Command(Answer::Close)),
+    (READY, b"S", FrontendEffect::Command(Answer::Sync)),
+    (READY, b"F", FrontendEffect::Command(Answer::FunctionCall)),
+    (READY, b"H", FrontendEffect::Nothing),
+    (READY, b"X", FrontendEffect::Enter(Phase::Terminated)),
+    // Flush and Sync are ignored during COPY IN
+    (&[Phase::CopyIn], b"dHS", FrontendEffect::Nothing),
+    (&[Phase::CopyIn], b"cf", FrontendEffect::Enter(Phase::Ready)),
+];
+
+/// The backend messages of each phase, by type, answering no command. In
+/// the Ready phase, every message answers a command.
+pub const BACKEND_TRANSITIONS: &[(Phase, &[u8], BackendEffect)] = &[
+    (Phase::Startup, b"RKvE", BackendEffect::Stay),
+    (Phase::Startup, b"Z", BackendEffect::Enter(Phase::Ready)),
+    (Phase::CopyOut, b"d", BackendEffect::Stay),
+    (Phase::CopyOut, b"c", BackendEffect::Enter(Phase::Ready)),
+    (Phase::CopyOut, b"E", BackendEffect::Abort),
+    (Phase::CopyIn, b"E", BackendEffect::Abort),
+];
+
+/// The backend messages answering a command in each state, by type
+#[rustfmt::skip]
+pub const ANSWER_TRANSITIONS: &[(Answer, &[u8], AnswerEffect)] = &[
+    // Simple query: any number of results then ReadyForQuery
+    (Answer::QueryStarted, b"T", AnswerEffect::Next(Answer::QueryRows)),
+    (Answer::QueryStarted, b"CI", AnswerEffect::Next(Answer::QueryCompleted)),
+    (Answer::QueryStarted, b"G", AnswerEffect::CopyIn(Answer::QueryStarted)),
+    (Answer::QueryStarted, b"H", AnswerEffect::CopyOut(Answer::QueryStarted)),
+    (Answer::QueryStarted, b"E", AnswerEffect::Next(Answer::QueryFailed)),
+    (Answer::QueryStarted, b"D", AnswerEffect::Illegal("DataRow sent before RowDescription")),
+    (Answer::QueryRows, b"D", AnswerEffect::Next(Answer::QueryRows)),
+    (Answer::QueryRows, b"C", AnswerEffect::Next(Answer::QueryCompleted)),
+    (Answer::QueryRows, b"E", AnswerEffect::Next(Answer::QueryFailed)),
+    (Answer::QueryCompleted, b"T", AnswerEffect::Next(Answer::QueryRows)),
+    (Answer::QueryCompleted, b"CI", AnswerEffect::Next(Answer::QueryCompleted)),
+    (Answer::QueryCompleted, b"G", AnswerEffect::CopyIn(Answer::QueryStarted)),
+    (Answer::QueryCompleted, b"H", AnswerEffect::CopyOut(Answer::QueryStarted)),
+    (Answer::QueryCompleted, b"E", AnswerEffect::Next(Answer::QueryFailed)),
+    (Answer::QueryCompleted, b"Z", AnswerEffect::Done),
+    (Answer::QueryCompleted, b"D", AnswerEffect::Illegal("DataRow sent before RowDescription")),
+    (Answer::QueryFailed, b"Z", AnswerEffect::Done),
+    // Extended query: one answer per message, an error discards the rest
+    (Answer::Parse, b"1", AnswerEffect::Done),
+    (Answer::Bind, b"2", AnswerEffect::Done),
+    (Answer::Describe, b"t", AnswerEffect::Next(Answer::DescribeParameters)),
+    (Answer::Describe, b"Tn", AnswerEffect::Done),
+    (Answer::DescribeParameters, b"Tn", AnswerEffect::Done),
+    (Answer::Execute, b"D", AnswerEffect::Next(Answer::Execute)),
+    (Answer::Execute, b"G", AnswerEffect::CopyIn(Answer::Execute)),
+    (Answer::Execute, b"H", AnswerEffect::CopyOut(Answer::Execute)),
+    (Answer::Execute, b"CIs", AnswerEffect::Done),
+    (Answer::Close, b"3", AnswerEffect::Done),
+    (Answer::Parse, b"E", AnswerEffect::Discard),
+    (Answer::Bind, b"E", AnswerEffect::Discard),
+    (Answer::Describe, b"E", AnswerEffect::Discard),
+    (Answer::DescribeParameters, b"E", AnswerEffect::Discard),
+    (Answer::Execute, b"E", AnswerEffect::Discard),
+    (Answer::Close, b"E", AnswerEffect::Discard),
+    (Answer::Sync, b"E", AnswerEffect::Next(Answer::Sync)),
+    (Answer::Sync, b"Z", AnswerEffect::Done),
+    // Function call: the result or an error, then ReadyForQuery
+    (Answer::FunctionCall, b"VE", AnswerEffect::Next(Answer::FunctionResult)),
+    (Answer::FunctionResult, b"Z", AnswerEffect::Done),
+];
+
+pub fn is_asynchronous(message_type: u8) -> bool {
+    ASYNCHRONOUS.contains(&message_type)
+}
+
+/// What a frontend message does in a phase, None when it is not allowed
+pub fn frontend_transition(phase: Phase, message_type: u8) -> Option<FrontendEffect> {
+    FRONTEND_TRANSITIONS
+        .iter()
+        .find(|(phases, types, _)| phases.contains(&phase) && types.contains(&message_type))
+        .map(|(_, _, effect)| *effect)
+}
+
+/// What a backend message does in a phase, None when it must answer a
+/// command (or is not allowed outside the Ready phase)
+pub fn backend_transition(phase: Phase, message_type: u8) -> Option<BackendEffect> {
+    BACKEND_TRANSITIONS
+        .iter()
+        .find(|(from, types, _)| *from == phase && types.contains(&message_type))
+        .map(|(_, _, next)| *next)
+}
+
+/// What a backend message does to an answer, None when it is not allowed
+pub fn answer_transition(answer: Answer, message_type: u8) -> Option<AnswerEffect> {
+    ANSWER_TRANSITIONS
+        .iter()
+        .find(|(from, types, _)| *from == answer && types.contains(&message_type))
+        .map(|(_, _, effect)| *effect)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transitions() -> anyhow::Result<()> {
+        // A type appears once per state
+        for (i, (phases, types, _)) in FRONTEND_TRANSITIONS.iter().enumerate() {
+            for (other_phases, other_types, _) in &FRONTEND_TRANSITIONS[i + 1..] {
+                assert!(
+                    !phases.iter().any(|phase| other_phases.contains(phase))
+                        || !types.iter().any(|t| other_types.contains(t)),
+                    "{phases:?} {}",
+                    types.escape_ascii()
+                );
+            }
+        }
+        for (i, (answer, types, _)) in ANSWER_TRANSITIONS.iter().enumerate() {
+            for (other, other_types, _) in &ANSWER_TRANSITIONS[i + 1..] {
+                assert!(
+                    answer != other || !types.iter().any(|t| other_types.contains(t)),
+                    "{answer:?} {}",
+                    types.escape_ascii()
+                );
+            }
+        }
+
+        assert_eq!(
+            Some(FrontendEffect::Enter(Phase::Ready)),
+            frontend_transition(Phase::CopyIn, b'c')
+        );
+        assert_eq!(None, frontend_transition(Phase::Ready, b'd'));
+        assert_eq!(None, frontend_transition(Phase::CopyOut, b'd'));
+        assert_eq!(
+            Some(BackendEffect::Enter(Phase::Ready)),
+            backend_transition(Phase::Startup, b'Z')
+        );
+        assert_eq!(None, backend_transition(Phase::Ready, b'Z'));
+        assert_eq!(
+            Some(AnswerEffect::Done),
+            answer_transition(Answer::QueryCompleted, b'Z')
+        );
+        assert_eq!(None, answer_transition(Answer::QueryStarted, b'Z'));
+
+        Ok(())
+    }
+}
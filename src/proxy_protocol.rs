@@ -0,0 +1,149 @@
+//! The header of the PROXY protocol version 2, sent by a TCP load balancer
+//! (HAProxy, AWS NLB...) before the startup packet to tell the address of the
+//! client it forwards. See
+//! https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+//!
+//! The text header of the version 1 is not supported.
+
+use anyhow::anyhow;
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The connection of the client, as seen by the load balancer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProxyHeader {
+    // None for the connections of the load balancer itself, e.g. its health
+    // checks, and for the addresses other than IPv4 and IPv6
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    pub fn new(source: SocketAddr, destination: SocketAddr) -> Self {
+        Self {
+            source: Some(source),
+            destination: Some(destination),
+        }
+    }
+
+    /// Read the header and nothing after it, the startup packet is left in
+    /// the stream
+    pub fn read(reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        if header[..12] != SIGNATURE {
+            return Err(anyhow!("Invalid PROXY protocol signature"));
+        }
+        let mut addresses = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+        reader.read_exact(&mut addresses)?;
+
+        let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+        if version != 2 {
+            return Err(anyhow!("Unsupported PROXY protocol version {version}"));
+        }
+        match command {
+            // LOCAL, the addresses are ignored
+            0 => return Ok(Self::default()),
+            // PROXY
+            1 => {}
+            _ => return Err(anyhow!("Unsupported PROXY protocol command {command}")),
+        }
+        // The TLVs after the addresses are skipped
+        let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+        match header[13] >> 4 {
+            // AF_INET
+            1 if addresses.len() >= 12 => {
+                let ip = |bytes: &[u8]| Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+                Ok(Self::new(
+                    SocketAddr::new(ip(&addresses[0..4]).into(), port(&addresses[8..10])),
+                    SocketAddr::new(ip(&addresses[4..8]).into(), port(&addresses[10..12])),
+                ))
+            }
+            // AF_INET6
+            2 if addresses.len() >= 36 => {
+                let ip = |bytes: &[u8]| {
+                    Ipv6Addr::from(<[u8; 16]>::try_from(bytes).expect("16 bytes address"))
+                };
+                Ok(Self::new(
+                    SocketAddr::new(ip(&addresses[0..16]).into(), port(&addresses[32..34])),
+                    SocketAddr::new(ip(&addresses[16..32]).into(), port(&addresses[34..36])),
+                ))
+            }
+            1 | 2 => Err(anyhow!("Truncated PROXY protocol addresses")),
+            // AF_UNSPEC or AF_UNIX
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// The header of a PROXY command, or of a LOCAL one without addresses
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = SIGNATURE.to_vec();
+        let (family, addresses) = match (self.source, self.destination) {
+            (Some(SocketAddr::V4(source)), Some(SocketAddr::V4(destination))) => (
+                0x11,
+                [
+                    &source.ip().octets()[..],
+                    &destination.ip().octets(),
+                    &source.port().to_be_bytes(),
+                    &destination.port().to_be_bytes(),
+                ]
+                .concat(),
+            ),
+            (Some(SocketAddr::V6(source)), Some(SocketAddr::V6(destination))) => (
+                0x21,
+                [
+                    &source.ip().octets()[..],
+                    &destination.ip().octets(),
+                    &source.port().to_be_bytes(),
+                    &destination.port().to_be_bytes(),
+                ]
+                .concat(),
+            ),
+            _ => {
+                bytes.extend_from_slice(&[0x20, 0x00, 0, 0]);
+                return bytes;
+            }
+        };
+        bytes.extend_from_slice(&[0x21, family]);
+        bytes.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&addresses);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn proxy_header() -> anyhow::Result<()> {
+        let header = ProxyHeader::new("203.0.113.7:4242".parse()?, "10.0.0.1:5432".parse()?);
+        let mut bytes = header.to_bytes();
+        assert_eq!(16 + 12, bytes.len());
+        // The startup packet is not read
+        bytes.extend_from_slice(&[0, 0, 0, 8]);
+        let mut reader = &bytes[..];
+        assert_eq!(header, ProxyHeader::read(&mut reader)?);
+        assert_eq!([0, 0, 0, 8], reader);
+
+        let header = ProxyHeader::new("[2001:db8::1]:4242".parse()?, "[::1]:5432".parse()?);
+        assert_eq!(header, ProxyHeader::read(&mut &header.to_bytes()[..])?);
+
+        // A health check, with a TLV
+        let mut local = ProxyHeader::default().to_bytes();
+        local[15] = 3;
+        local.extend_from_slice(&[0x04, 0, 0]);
+        assert_eq!(ProxyHeader::default(), ProxyHeader::read(&mut &local[..])?);
+
+        // A startup packet instead of a header
+        let startup = [0, 0, 0, 8, 4, 210, 22, 47, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(ProxyHeader::read(&mut &startup[..]).is_err());
+        let mut truncated = header.to_bytes();
+        truncated[15] = 12;
+        assert!(ProxyHeader::read(&mut &truncated[..]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+//! The random values of the crate
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// A different number at each call, for the jitters and the seeds of the
+/// simulations. It is not a source of secrets, see secure_bytes for them.
+pub fn random_u64() -> u64 {
+    // The keys of each RandomState come from the OS, then are incremented
+    RandomState::new().build_hasher().finish()
+}
+
+/// Bytes of the operating system CSPRNG, for the nonces and salts of the
+/// authentications
+#[cfg(feature = "auth")]
+pub fn secure_bytes<const N: usize>() -> anyhow::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow::anyhow!("no random bytes: {e}"))?;
+    Ok(bytes)
+}
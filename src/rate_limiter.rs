@@ -0,0 +1,130 @@
+//! Throttle the connections by source address, to test how the frontends
+//! (and their pools) cope with a server refusing them.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::PgError;
+use crate::simulation::Clock;
+
+#[derive(Debug, Default)]
+struct Client {
+    // The connections accepted during the last second
+    recent: VecDeque<Instant>,
+    sessions: usize,
+}
+
+/// The limits on the connections of each source address, shared by the
+/// sessions: cloning gives another handle on the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    pub max_connections_per_second: Option<usize>,
+    pub max_sessions: Option<usize>,
+    pub clock: Clock,
+    clients: Arc<Mutex<HashMap<IpAddr, Client>>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_connections_per_second: Option<usize>, max_sessions: Option<usize>) -> Self {
+        Self {
+            max_connections_per_second,
+            max_sessions,
+            clock: Clock::Real,
+            clients: Arc::default(),
+        }
+    }
+
+    /// Count a new connection, it is a session until the permit is dropped.
+    /// The connections refused are not counted.
+    pub fn admit(&self, address: IpAddr) -> Result<RateLimitPermit, PgError> {
+        self.admit_at(address, self.clock.now())
+    }
+
+    fn admit_at(&self, address: IpAddr, now: Instant) -> Result<RateLimitPermit, PgError> {
+        let mut clients = self.clients.lock().expect("rate limiter lock");
+        let client = clients.entry(address).or_default();
+        while client
+            .recent
+            .front()
+            .is_some_and(|accepted| now.duration_since(*accepted) >= Duration::from_secs(1))
+        {
+            client.recent.pop_front();
+        }
+        if let Some(max) = self.max_sessions
+            && client.sessions >= max
+        {
+            return Err(PgError::new(
+                "53300",
+                &format!("too many connections from host \"{address}\""),
+            ));
+        }
+        if let Some(max) = self.max_connections_per_second
+            && client.recent.len() >= max
+        {
+            return Err(PgError::new(
+                "08004",
+                &format!("too many connection attempts from host \"{address}\", try again later"),
+            ));
+        }
+        client.recent.push_back(now);
+        client.sessions += 1;
+        Ok(RateLimitPermit {
+            limiter: self.clone(),
+            address,
+        })
+    }
+}
+
+/// A session counted by a RateLimiter
+#[derive(Debug)]
+pub struct RateLimitPermit {
+    limiter: RateLimiter,
+    address: IpAddr,
+}
+
+impl Drop for RateLimitPermit {
+    fn drop(&mut self) {
+        if let Ok(mut clients) = self.limiter.clients.lock()
+            && let Some(client) = clients.get_mut(&self.address)
+        {
+            client.sessions -= 1;
+            // The addresses are forgotten once idle, the recent connections
+            // only matter for a second
+            if client.sessions == 0 && client.recent.is_empty() {
+                clients.remove(&self.address);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter() -> anyhow::Result<()> {
+        let limiter = RateLimiter::new(Some(2), Some(2));
+        let (one, other): (IpAddr, IpAddr) = ("10.0.0.1".parse()?, "10.0.0.2".parse()?);
+        let start = Instant::now();
+        let code = |result: Result<RateLimitPermit, PgError>| match result {
+            Ok(_) => String::new(),
+            Err(e) => e.code,
+        };
+
+        let first = limiter.admit_at(one, start)?;
+        let second = limiter.admit_at(one, start)?;
+        assert_eq!("53300", code(limiter.admit_at(one, start)));
+        assert!(limiter.admit_at(other, start).is_ok());
+
+        // A session ends, but the rate is still over the limit
+        drop(first);
+        assert_eq!("08004", code(limiter.admit_at(one, start)));
+        let later = start + Duration::from_secs(1);
+        let _third = limiter.admit_at(one, later)?;
+
+        drop(second);
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+use anyhow::anyhow;
+use std::collections::{HashMap, VecDeque};
+use tracing::*;
+
+use crate::handler::proxy::ProxiedMessage;
+use crate::matcher::split_statements;
+use crate::message::*;
+use crate::scenario::{Cell, Column, MatchKind, Rule, Scenario};
+use crate::value::PgValue;
+
+/// Builds a scenario from the messages relayed by the proxy: every query
+/// sent by the frontend becomes a rule answering with the result sent by
+/// the real server.
+///
+/// Only the first result of a given query is recorded, queries that failed
+/// are not recorded. The statements of a multi-statement Query are
+/// recorded one by one.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    pub scenario: Scenario,
+    // The query of the prepared statements, by name
+    statements: HashMap<String, String>,
+    // The query and result formats of the portals, by name
+    portals: HashMap<String, (String, Vec<FormatCode>)>,
+    // Queries waiting for their result with their result formats, in order
+    pending: VecDeque<(String, Vec<FormatCode>)>,
+    columns: Vec<ColumnDescription>,
+    rows: Vec<Vec<Cell>>,
+    // The current result can't be recorded
+    skipped: bool,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn reset_result(&mut self) {
+        self.columns.clear();
+        self.rows.clear();
+        self.skipped = false;
+    }
+
+    pub fn message(&mut self, message: ProxiedMessage) -> anyhow::Result<()> {
+        match message {
+            ProxiedMessage::Frontend(mut raw_message) => match raw_message.get_message_kind() {
+                Some(FrontendMessageKind::Query) => {
+                    let message = Query::try_from(&mut raw_message)?;
+                    let query = message.query.into_string()?;
+                    // One result per statement, a single statement is
+                    // recorded as sent like the server passes it
+                    match split_statements(&query)[..] {
+                        [] | [_] => self.pending.push_back((query, Vec::new())),
+                        ref statements => self.pending.extend(
+                            statements
+                                .iter()
+                                .map(|statement| (String::from(*statement), Vec::new())),
+                        ),
+                    }
+                }
+                Some(FrontendMessageKind::Parse) => {
+                    let message = Parse::try_from(&mut raw_message)?;
+                    self.statements.insert(
+                        message.statement.into_string()?,
+                        message.query.into_string()?,
+                    );
+                }
+                Some(FrontendMessageKind::Bind) => {
+                    let message = Bind::try_from(&mut raw_message)?;
+                    if let Some(query) = self.statements.get(message.statement.to_str()?) {
+                        let portal = (query.clone(), message.result_formats()?);
+                        self.portals.insert(message.portal.into_string()?, portal);
+                    }
+                }
+                Some(FrontendMessageKind::Execute) => {
+                    let message = Execute::try_from(&mut raw_message)?;
+                    if let Some(portal) = self.portals.get(message.portal.to_str()?) {
+                        self.pending.push_back(portal.clone());
+                    }
+                }
+                _ => (),
+            },
+            ProxiedMessage::Backend(mut raw_message) => match raw_message.get_message_kind() {
+                Some(BackendMessageKind::RowDescription) => {
+                    let message = RowDescription::try_from(&mut raw_message)?;
+                    self.reset_result();
+                    self.columns = message.columns.as_ref().clone();
+                }
+                Some(BackendMessageKind::NoData) => self.reset_result(),
+                Some(BackendMessageKind::DataRow) => {
+                    let message = DataRow::try_from(&mut raw_message)?;
+                    match self.decode_row(&message) {
+                        Ok(row) => self.rows.push(row),
+                        Err(e) => {
+                            if !self.skipped {
+                                warn!("The result can't be recorded: {e}");
+                            }
+                            self.skipped = true;
+                        }
+                    }
+                }
+                Some(BackendMessageKind::CommandComplete) => {
+                    let message = CommandComplete::try_from(&mut raw_message)?;
+                    if let Some((query, _)) = self.pending.pop_front() {
+                        self.record(query, message.command_tag.into_string()?);
+                    }
+                    self.reset_result();
+                }
+                Some(BackendMessageKind::EmptyQuery)
+                | Some(BackendMessageKind::PortalSuspended) => {
+                    //FIXME: Partial results of suspended portals are not recorded
+                    self.pending.pop_front();
+                    self.reset_result();
+                }
+                // After an error, the rest of the query or everything up to
+                // Sync is skipped by the server
+                Some(BackendMessageKind::ErrorResponse)
+                | Some(BackendMessageKind::ReadyForQuery) => {
+                    self.pending.clear();
+                    self.reset_result();
+                }
+                _ => (),
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Convert a row in text format, the format of the columns comes from
+    /// Bind since the RowDescription of a statement always says text.
+    fn decode_row(&self, message: &DataRow) -> anyhow::Result<Vec<Cell>> {
+        let columns = message.columns.as_ref();
+        if columns.len() != self.columns.len() {
+            return Err(anyhow!("DataRow without a matching RowDescription"));
+        }
+        let formats = match self.pending.front() {
+            Some((_, formats)) => &formats[..],
+            None => &[],
+        };
+
+        let mut row = Vec::with_capacity(columns.len());
+        for (index, (data, column)) in columns.iter().zip(&self.columns).enumerate() {
+            let Some(data) = data.as_bytes() else {
+                row.push(Cell::null());
+                continue;
+            };
+            row.push(Cell::Text(match FormatCode::for_column(formats, index)? {
+                FormatCode::Text => String::from_utf8(data.to_vec())?,
+                FormatCode::Binary => {
+                    let value = PgValue::from_binary(PgType::try_from(column.datatype_id)?, data)?;
+                    String::from_utf8(value.to_text())?
+                }
+            }));
+        }
+        Ok(row)
+    }
+
+    fn record(&mut self, query: String, command_tag: String) {
+        if self.skipped || self.scenario.rules.iter().any(|rule| rule.query == query) {
+            return;
+        }
+        debug!("recording: {query}");
+
+        let columns = self
+            .columns
+            .iter()
+            .map(|column| {
+                Ok(Column {
+                    name: column.name.to_str()?.to_string(),
+                    type_oid: column.datatype_id,
+                    type_len: column.datatype_len,
+                    type_mod: column.datatype_mod,
+                })
+            })
+            .collect::<anyhow::Result<Vec<Column>>>();
+        match columns {
+            Ok(columns) => self.scenario.rules.push(Rule {
+                query,
+                match_kind: MatchKind::Exact,
+                command_tag,
+                columns,
+                rows: self.rows.clone(),
+                steps: Vec::new(),
+                error: None,
+            }),
+            Err(e) => warn!("The result can't be recorded: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+    use libpq_serde_types::{ByteSized, Serialize};
+
+    fn frontend<U>(message: U) -> ProxiedMessage
+    where
+        U: MessageBody + Serialize + ByteSized,
+    {
+        let mut raw_body = BytesMut::new();
+        message.serialize(&mut raw_body);
+        ProxiedMessage::Frontend(RawFrontendMessage {
+            header: MessageHeader::new_header_from_body(&message).expect("message length"),
+            raw_body: Bytes::from(raw_body),
+        })
+    }
+
+    fn backend<U>(message: U) -> ProxiedMessage
+    where
+        U: MessageBody + Serialize + ByteSized,
+    {
+        let mut raw_body = BytesMut::new();
+        message.serialize(&mut raw_body);
+        ProxiedMessage::Backend(RawBackendMessage {
+            header: MessageHeader::new_header_from_body(&message).expect("message length"),
+            raw_body: Bytes::from(raw_body),
+        })
+    }
+
+    #[test]
+    fn recorder_simple_and_extended_query() -> anyhow::Result<()> {
+        let columns = vec![ColumnDescription::new("id", PgType::Int4)?];
+        let mut recorder = Recorder::new();
+
+        // simple query
+        recorder.message(frontend(Query::new(String::from("SELECT 42 AS id"))?))?;
+        recorder.message(backend(RowDescription::new(columns.clone())))?;
+        recorder.message(backend(DataRow::new(vec![b"42".to_vec().into()])))?;
+        recorder.message(backend(CommandComplete::new(String::from("SELECT 1"))?))?;
+        recorder.message(backend(ReadyForQuery::new(TransactionIndicator::Idle)))?;
+
+        // extended query in binary format, described before Bind
+        recorder.message(frontend(Parse::new("", "SELECT $1::int4 AS id", vec![])?))?;
+        recorder.message(frontend(Describe::new(DescribeTarget::Statement, "")?))?;
+        recorder.message(frontend(Bind::new(
+            "",
+            "",
+            vec![],
+            vec![],
+            vec![FormatCode::Binary],
+        )?))?;
+        recorder.message(frontend(Execute::new("", 0)?))?;
+        recorder.message(frontend(Sync::new()))?;
+        recorder.message(backend(ParseComplete::new()))?;
+        recorder.message(backend(ParameterDescription::new(vec![23])))?;
+        recorder.message(backend(RowDescription::new(columns)))?;
+        recorder.message(backend(BindComplete::new()))?;
+        recorder.message(backend(DataRow::new(vec![vec![0, 0, 0, 7].into()])))?;
+        recorder.message(backend(CommandComplete::new(String::from("SELECT 1"))?))?;
+        recorder.message(backend(ReadyForQuery::new(TransactionIndicator::Idle)))?;
+
+        // failed query
+        recorder.message(frontend(Query::new(String::from("SELECT nope"))?))?;
+        recorder.message(backend(ErrorResponse::new(vec![])))?;
+        recorder.message(backend(ReadyForQuery::new(TransactionIndicator::Idle)))?;
+
+        // multi-statement query, a result set per statement
+        recorder.message(frontend(Query::new(String::from(
+            "SET search_path TO app; SELECT 'a;b' AS id",
+        ))?))?;
+        recorder.message(backend(CommandComplete::new(String::from("SET"))?))?;
+        recorder.message(backend(RowDescription::new(vec![ColumnDescription::new(
+            "id",
+            PgType::Text,
+        )?])))?;
+        recorder.message(backend(DataRow::new(vec![b"a;b".to_vec().into()])))?;
+        recorder.message(backend(DataRow::new(vec![ColumnData::null()])))?;
+        recorder.message(backend(CommandComplete::new(String::from("SELECT 1"))?))?;
+        recorder.message(backend(ReadyForQuery::new(TransactionIndicator::Idle)))?;
+
+        let rules = &recorder.scenario.rules;
+        assert_eq!(4, rules.len());
+        assert_eq!("SET search_path TO app", rules[2].query);
+        assert_eq!("SELECT 'a;b' AS id", rules[3].query);
+        assert_eq!(
+            vec![vec![Cell::from("a;b")], vec![Cell::null()]],
+            rules[3].rows
+        );
+        assert_eq!("SELECT 42 AS id", rules[0].query);
+        assert_eq!(vec![vec![Cell::from("42")]], rules[0].rows);
+        assert_eq!("SELECT $1::int4 AS id", rules[1].query);
+        assert_eq!(vec![vec![Cell::from("7")]], rules[1].rows);
+        assert_eq!(23, rules[1].columns[0].type_oid);
+
+        Ok(())
+    }
+}
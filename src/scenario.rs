@@ -0,0 +1,293 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::{ffi::CString, fs, path::Path};
+
+use crate::error::PgError;
+use crate::executor::{ScriptedExecutor, Step};
+use crate::handler::server::{QueryResult, ServerVersion};
+use crate::matcher::QueryMatcher;
+use crate::message::{ColumnDescription, PgType};
+use crate::value::PgValue;
+
+/// How the query of a rule is compared to the queries received
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    #[default]
+    Exact,
+    Normalized,
+    Regex,
+}
+
+/// A column of a result, the type is kept as an oid so that the columns of
+/// any type can be replayed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    pub type_oid: i32,
+    #[serde(default = "default_type_len")]
+    pub type_len: i16,
+    #[serde(default = "default_type_mod")]
+    pub type_mod: i32,
+}
+
+fn default_type_len() -> i16 {
+    -1
+}
+
+fn default_type_mod() -> i32 {
+    -1
+}
+
+/// A value of a row in text format, NULL is written `{ null = true }`
+/// since TOML has no null
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Cell {
+    Text(String),
+    Null { null: bool },
+}
+
+impl Cell {
+    pub fn null() -> Self {
+        Cell::Null { null: true }
+    }
+}
+
+impl From<&str> for Cell {
+    fn from(value: &str) -> Self {
+        Cell::Text(String::from(value))
+    }
+}
+
+impl From<String> for Cell {
+    fn from(value: String) -> Self {
+        Cell::Text(value)
+    }
+}
+
+/// A query and its result, the values are in text format
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    pub query: String,
+    #[serde(default, rename = "match")]
+    pub match_kind: MatchKind,
+    #[serde(default)]
+    pub command_tag: String,
+    #[serde(default)]
+    pub columns: Vec<Column>,
+    #[serde(default)]
+    pub rows: Vec<Vec<Cell>>,
+    // Run before answering, to coordinate several sessions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<Step>,
+    // Sent instead of the result, after the steps. The rows of a COPY TO
+    // STDOUT are sent before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<PgError>,
+}
+
+impl Rule {
+    pub fn matcher(&self) -> anyhow::Result<QueryMatcher> {
+        match self.match_kind {
+            MatchKind::Exact => Ok(QueryMatcher::exact(&self.query)),
+            MatchKind::Normalized => QueryMatcher::normalized(&self.query),
+            MatchKind::Regex => QueryMatcher::regex(&self.query),
+        }
+    }
+
+    pub fn result(&self) -> anyhow::Result<QueryResult> {
+        let columns = self
+            .columns
+            .iter()
+            .map(|column| {
+                Ok(ColumnDescription {
+                    name: CString::new(&column.name[..])?,
+                    relation_id: 0,
+                    attribute_id: 0,
+                    datatype_id: column.type_oid,
+                    datatype_len: column.type_len,
+                    datatype_mod: column.type_mod,
+                    format: 0,
+                })
+            })
+            .collect::<anyhow::Result<Vec<ColumnDescription>>>()?;
+
+        //FIXME: The values of unsupported types are sent as text, even when
+        //the binary format is requested.
+        let mut rows = Vec::with_capacity(self.rows.len());
+        for row in &self.rows {
+            let mut values = Vec::with_capacity(row.len());
+            for (cell, column) in row.iter().zip(&self.columns) {
+                values.push(match (cell, PgType::try_from(column.type_oid)) {
+                    (Cell::Null { null: true }, _) => PgValue::Null,
+                    (Cell::Null { null: false }, _) => {
+                        return Err(anyhow!("A NULL value must be written {{ null = true }}"));
+                    }
+                    (Cell::Text(value), Ok(pg_type)) => PgValue::from_text(pg_type, value)?,
+                    (Cell::Text(value), Err(_)) => PgValue::Text(value.clone()),
+                });
+            }
+            rows.push(values);
+        }
+
+        Ok(QueryResult {
+            columns,
+            rows,
+            command_tag: self.command_tag.clone(),
+            error: self.error.clone(),
+        })
+    }
+}
+
+/// A list of rules that can be saved to a TOML file and replayed by a
+/// ScriptedExecutor, e.g.:
+///
+/// ```toml
+/// [[rules]]
+/// query = "select id from users where name = $1"
+/// match = "normalized"
+/// command_tag = "SELECT 1"
+/// columns = [{ name = "id", type_oid = 23 }]
+/// rows = [["42"]]
+///
+/// # DML returning rows, the tag counts the affected rows
+/// [[rules]]
+/// query = "INSERT INTO users (name) VALUES ('a'), ('b') RETURNING id"
+/// command_tag = "INSERT 0 2"
+/// columns = [{ name = "id", type_oid = 23 }]
+/// rows = [["43"], ["44"]]
+///
+/// [[rules]]
+/// query = "LOCK users"
+/// command_tag = "LOCK TABLE"
+/// steps = [{ wait_for = "b_committed" }, { signal = "a_locked" }]
+///
+/// # A lock wait ending in a deadlock
+/// [[rules]]
+/// query = "UPDATE accounts SET balance = 0"
+/// steps = [{ wait_for_query = "UPDATE accounts SET balance = 1" }, { sleep_ms = 1000 }]
+/// error = { code = "40P01", message = "deadlock detected" }
+///
+/// # The rows go to the frontend of a COPY TO STDOUT, a COPY FROM STDIN
+/// # runs once the data is received
+/// [[rules]]
+/// query = "COPY users TO STDOUT"
+/// columns = [{ name = "id", type_oid = 23 }, { name = "name", type_oid = 25 }]
+/// rows = [["42", "a"], ["43", { null = true }]]
+///
+/// # A long call reporting its progress with notices
+/// [[rules]]
+/// query = "CALL refresh_reports()"
+/// command_tag = "CALL"
+/// steps = [{ notice = "refreshing" }, { sleep_ms = 500 }, { notice = "refreshed" }]
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    // The version of PostgreSQL emulated, see ServerVersion
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<ServerVersion>,
+}
+
+impl Scenario {
+    pub fn from_toml(content: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, self.to_toml()?)?;
+
+        Ok(())
+    }
+
+    pub fn executor(&self) -> anyhow::Result<ScriptedExecutor> {
+        let mut executor = ScriptedExecutor::new();
+        if let Some(version) = &self.version {
+            executor.version = version.clone();
+        }
+        for rule in &self.rules {
+            executor = executor.on_scheduled(rule.matcher()?, rule.result()?, rule.steps.clone());
+        }
+        Ok(executor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scenario_executor() -> anyhow::Result<()> {
+        let scenario = Scenario::from_toml(
+            r#"
+            [[rules]]
+            query = "SELECT id, name FROM users WHERE id = $1"
+            match = "normalized"
+            command_tag = "SELECT 1"
+            columns = [{ name = "id", type_oid = 23 }, { name = "name", type_oid = 1043 }]
+            rows = [["42", "bob"], ["43", { null = true }]]
+
+            [[rules]]
+            query = "BEGIN"
+            command_tag = "BEGIN"
+            steps = [{ barrier = { name = "start", parties = 1 } }, { signal = "begun" }]
+            "#,
+        )?;
+        assert_eq!(scenario, Scenario::from_toml(&scenario.to_toml()?)?);
+
+        let executor = scenario.executor()?;
+        let result = executor.execute(String::from("select id, name from users where id = 7"));
+        assert_eq!(
+            vec![
+                vec![PgValue::Int4(42), PgValue::Text(String::from("bob"))],
+                vec![PgValue::Int4(43), PgValue::Null]
+            ],
+            result.rows
+        );
+        assert_eq!(1043, result.columns[1].datatype_id);
+        assert_eq!("BEGIN", executor.execute(String::from("BEGIN")).command_tag);
+        assert_eq!(vec![String::from("begun")], executor.schedule.events());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scenario_deadlock() -> anyhow::Result<()> {
+        let scenario = Scenario::from_toml(
+            r#"
+            [[rules]]
+            query = "UPDATE t SET n = 1"
+            steps = [{ wait_for_query = "commit" }, { sleep_ms = 10 }]
+            error = { code = "40P01", message = "deadlock detected" }
+            "#,
+        )?;
+        assert_eq!(scenario, Scenario::from_toml(&scenario.to_toml()?)?);
+
+        let executor = scenario.executor()?;
+        let session = executor.clone();
+        let update =
+            std::thread::spawn(move || session.execute(String::from("UPDATE t SET n = 1")));
+        // Only a COMMIT run while the UPDATE waits releases it
+        while !update.is_finished() {
+            executor.execute(String::from("COMMIT"));
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let result = update.join().expect("update thread");
+        assert_eq!(
+            Some(PgError::new("40P01", "deadlock detected")),
+            result.error
+        );
+
+        Ok(())
+    }
+}
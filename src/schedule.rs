@@ -0,0 +1,190 @@
+use anyhow::anyhow;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::matcher::normalize;
+
+/// How long a session waits for an event before giving up
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct State {
+    // Every event signaled, in order
+    events: Vec<String>,
+    // The number of sessions that reached each barrier
+    barriers: HashMap<String, usize>,
+    // How many times each query was executed, normalized
+    queries: HashMap<String, usize>,
+}
+
+/// Coordinates the sessions of a multi-connection scenario, e.g. the
+/// session running `LOCK t` waits for the `b_committed` event signaled by
+/// another session when it runs `COMMIT`.
+///
+/// The schedule is shared by all the sessions, cloning it gives another
+/// handle on the same events.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn wait_until(
+        &self,
+        timeout: Duration,
+        condition: impl Fn(&State) -> bool,
+    ) -> anyhow::Result<()> {
+        let (lock, condvar) = &*self.state;
+        let deadline = Instant::now() + timeout;
+        let mut state = lock.lock().map_err(|_| anyhow!("Schedule lock poisoned"))?;
+        while !condition(&state) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(anyhow!("Timeout"));
+            }
+            state = condvar
+                .wait_timeout(state, remaining)
+                .map_err(|_| anyhow!("Schedule lock poisoned"))?
+                .0;
+        }
+        Ok(())
+    }
+
+    pub fn signal(&self, event: &str) {
+        let (lock, condvar) = &*self.state;
+        if let Ok(mut state) = lock.lock() {
+            state.events.push(String::from(event));
+        }
+        condvar.notify_all();
+    }
+
+    /// Block until the event is signaled, returns immediately if it already was
+    pub fn wait_for(&self, event: &str, timeout: Duration) -> anyhow::Result<()> {
+        self.wait_until(timeout, |state| state.events.iter().any(|e| e == event))
+            .map_err(|_| anyhow!("Timeout while waiting for event \"{event}\""))
+    }
+
+    /// Record a query run by a session, for wait_for_query()
+    pub fn query_executed(&self, query: &str) {
+        let (lock, condvar) = &*self.state;
+        if let Ok(mut state) = lock.lock() {
+            *state
+                .queries
+                .entry(normalize(query).unwrap_or_else(|_| String::from(query)))
+                .or_default() += 1;
+        }
+        condvar.notify_all();
+    }
+
+    /// Block until a session runs the query, e.g. the COMMIT releasing a
+    /// lock. The queries are compared once normalized, only the executions
+    /// from now on count.
+    pub fn wait_for_query(&self, query: &str, timeout: Duration) -> anyhow::Result<()> {
+        let query = normalize(query)?;
+        let executed = |state: &State| state.queries.get(&query).copied().unwrap_or_default();
+        let before = match self.state.0.lock() {
+            Ok(state) => executed(&state),
+            Err(_) => return Err(anyhow!("Schedule lock poisoned")),
+        };
+        self.wait_until(timeout, |state| executed(state) > before)
+            .map_err(|_| anyhow!("Timeout while waiting for the query \"{query}\""))
+    }
+
+    /// Block until `parties` sessions reached the barrier
+    pub fn barrier(&self, name: &str, parties: usize, timeout: Duration) -> anyhow::Result<()> {
+        let (lock, condvar) = &*self.state;
+        if let Ok(mut state) = lock.lock() {
+            *state.barriers.entry(String::from(name)).or_default() += 1;
+        }
+        condvar.notify_all();
+
+        self.wait_until(timeout, |state| {
+            state.barriers.get(name).copied().unwrap_or_default() >= parties
+        })
+        .map_err(|_| anyhow!("Timeout while waiting on barrier \"{name}\""))
+    }
+
+    pub fn events(&self) -> Vec<String> {
+        match self.state.0.lock() {
+            Ok(state) => state.events.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Check that the events were signaled in this order, other events can
+    /// be interleaved.
+    pub fn assert_order(&self, expected: &[&str]) -> anyhow::Result<()> {
+        let events = self.events();
+        let mut remaining = events.iter();
+        for event in expected {
+            if !remaining.any(|e| e == event) {
+                return Err(anyhow!(
+                    "Event \"{event}\" missing or out of order in {events:?}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn schedule_wait_for_signal() -> anyhow::Result<()> {
+        let schedule = Schedule::new();
+
+        let session_a = schedule.clone();
+        let thread_a = thread::spawn(move || -> anyhow::Result<()> {
+            session_a.barrier("start", 2, DEFAULT_TIMEOUT)?;
+            session_a.wait_for("b_committed", DEFAULT_TIMEOUT)?;
+            session_a.signal("a_locked");
+            Ok(())
+        });
+
+        schedule.barrier("start", 2, DEFAULT_TIMEOUT)?;
+        schedule.signal("b_committed");
+        thread_a.join().expect("session A panicked")?;
+
+        schedule.assert_order(&["b_committed", "a_locked"])?;
+        assert!(schedule.assert_order(&["a_locked", "b_committed"]).is_err());
+        assert!(
+            schedule
+                .wait_for("never", Duration::from_millis(10))
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn schedule_wait_for_query() -> anyhow::Result<()> {
+        let schedule = Schedule::new();
+        // Executed before anyone waits
+        schedule.query_executed("COMMIT");
+        assert!(
+            schedule
+                .wait_for_query("commit", Duration::from_millis(10))
+                .is_err()
+        );
+
+        let session = schedule.clone();
+        let waiting = thread::spawn(move || session.wait_for_query("commit", DEFAULT_TIMEOUT));
+        while !waiting.is_finished() {
+            schedule.query_executed("COMMIT");
+            thread::sleep(Duration::from_millis(1));
+        }
+        waiting.join().expect("waiting thread panicked")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! The client side of SCRAM-SHA-256 (RFC 5802 and RFC 7677), the SASL
+//! mechanism of the servers with password_encryption = scram-sha-256.
+//!
+//! The channel binding is not supported: the client announces it with the
+//! GS2 header "n,,". The password is used as it is, without SASLprep, which
+//! gives the same keys for the ASCII passwords.
+
+use anyhow::anyhow;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::random;
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+// No channel binding, base64 of "n,," in the client-final-message
+const GS2_HEADER: &str = "n,,";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The state of an exchange, from the client-first-message to the check of
+/// the server signature
+pub struct ScramClient {
+    // Empty for PostgreSQL, which takes the one of the StartupMessage
+    user: String,
+    password: String,
+    nonce: String,
+    // Computed with the client-final-message
+    server_signature: Option<Vec<u8>>,
+}
+
+impl ScramClient {
+    pub fn new(password: &str) -> anyhow::Result<Self> {
+        let nonce: [u8; 18] = random::secure_bytes()?;
+        Ok(Self::with_nonce("", password, &STANDARD.encode(nonce)))
+    }
+
+    /// Like new(), with a user and a known nonce e.g. for the test vectors
+    pub fn with_nonce(user: &str, password: &str, nonce: &str) -> Self {
+        Self {
+            user: String::from(user),
+            password: String::from(password),
+            nonce: String::from(nonce),
+            server_signature: None,
+        }
+    }
+
+    fn client_first_bare(&self) -> String {
+        format!("n={},r={}", self.user, self.nonce)
+    }
+
+    /// The data of the SASLInitialResponse
+    pub fn client_first(&self) -> String {
+        format!("{GS2_HEADER}{}", self.client_first_bare())
+    }
+
+    /// The data of the SASLResponse answering the AuthenticationSASLContinue
+    pub fn client_final(&mut self, server_first: &str) -> anyhow::Result<String> {
+        let attribute = |name: &str| {
+            server_first
+                .split(',')
+                .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+                .ok_or(anyhow!("Missing attribute {name} in \"{server_first}\""))
+        };
+        let nonce = attribute("r")?;
+        if !nonce.starts_with(&self.nonce) || nonce.len() == self.nonce.len() {
+            return Err(anyhow!("The server nonce doesn't extend the client nonce"));
+        }
+        let salt = STANDARD.decode(attribute("s")?)?;
+        let iterations: u32 = attribute("i")?.parse()?;
+        if iterations == 0 {
+            return Err(anyhow!("Invalid iteration count 0"));
+        }
+
+        let salted_password = hi(self.password.as_bytes(), &salt, iterations)?;
+        let client_key = hmac(&salted_password, b"Client Key")?;
+        let stored_key = Sha256::digest(&client_key);
+        let without_proof = format!("c={},r={nonce}", STANDARD.encode(GS2_HEADER));
+        let auth_message = format!(
+            "{},{server_first},{without_proof}",
+            self.client_first_bare()
+        );
+        let client_signature = hmac(&stored_key, auth_message.as_bytes())?;
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+        let server_key = hmac(&salted_password, b"Server Key")?;
+        self.server_signature = Some(hmac(&server_key, auth_message.as_bytes())?);
+
+        Ok(format!("{without_proof},p={}", STANDARD.encode(proof)))
+    }
+
+    /// Check the server signature of the AuthenticationSASLFinal, the
+    /// server proves that it knows the password too
+    pub fn verify(&self, server_final: &str) -> anyhow::Result<()> {
+        if let Some(error) = server_final.strip_prefix("e=") {
+            return Err(anyhow!("SCRAM authentication refused: {error}"));
+        }
+        let signature = server_final
+            .strip_prefix("v=")
+            .ok_or(anyhow!("Missing server signature in \"{server_final}\""))?;
+        match &self.server_signature {
+            Some(expected) if *expected == STANDARD.decode(signature)? => Ok(()),
+            Some(_) => Err(anyhow!("Invalid server signature")),
+            None => Err(anyhow!("Server signature received before the proof")),
+        }
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut mac = HmacSha256::new_from_slice(key)?;
+    mac.update(message);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Hi() of RFC 5802, PBKDF2 with HMAC-SHA-256 for a single block
+fn hi(password: &[u8], salt: &[u8], iterations: u32) -> anyhow::Result<Vec<u8>> {
+    let mut u = hmac(password, &[salt, &1_u32.to_be_bytes()].concat())?;
+    let mut result = u.clone();
+    for _ in 1..iterations {
+        u = hmac(password, &u)?;
+        result.iter_mut().zip(&u).for_each(|(r, u)| *r ^= u);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The example of RFC 7677
+    #[test]
+    fn scram_sha_256() -> anyhow::Result<()> {
+        let mut client = ScramClient::with_nonce("user", "pencil", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!("n,,n=user,r=rOprNGfwEbeRWgbNEkqO", client.client_first());
+        assert_eq!(
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            client.client_final(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+            )?
+        );
+        client.verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")?;
+        assert!(
+            client
+                .verify("v=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=")
+                .is_err()
+        );
+        assert!(client.verify("e=invalid-proof").is_err());
+
+        // The server must add its own part to the nonce
+        let mut client = ScramClient::new("pencil")?;
+        let nonce = client.nonce.clone();
+        assert!(client.client_first().starts_with("n,,n=,r="));
+        assert!(
+            client
+                .client_final(&format!("r={nonce},s=QUJD,i=4096"))
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,985 @@
+//! The sessions of a server, and the virtual schema `fakepostmaster` that
+//! shows them with the rest of the internal state to any SQL client:
+//!
+//! * `SELECT * FROM fakepostmaster.sessions`, the sessions connected
+//! * `SELECT * FROM fakepostmaster.stats`, the latency by query pattern
+//! * `SELECT * FROM fakepostmaster.config`, the configuration by key, without
+//!   the passwords
+//!
+//! The registry also answers the monitoring queries of PostgreSQL:
+//! `SELECT * FROM pg_stat_activity`, `SELECT * FROM pg_prepared_xacts`,
+//! `SELECT pg_cancel_backend(pid)` and `SELECT pg_terminate_backend(pid)`,
+//! and with the catalog feature the system catalogs of the declared
+//! tables, see catalog.
+//!
+//! The views can be filtered with `WHERE column = value [AND ...]`, `<>`
+//! or `!=` for the inequality, and `pg_backend_pid()` is the pid of the
+//! session asking, e.g. `SELECT pid FROM pg_stat_activity WHERE pid <>
+//! pg_backend_pid()`.
+
+use anyhow::anyhow;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::*;
+
+#[cfg(feature = "catalog")]
+use crate::catalog;
+use crate::columns;
+use crate::config::ServerConfig;
+use crate::datetime::timestamp_from_system_time;
+use crate::handler::large_object::LargeObjects;
+use crate::handler::server::{PreparedTransactions, QueryResult, StartupParameters};
+use crate::matcher::bound_tokens;
+use crate::message::ColumnDescription;
+use crate::random;
+use crate::simulation::Clock;
+use crate::stats::QueryStats;
+use crate::value::PgValue;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub pid: i32,
+    pub client_addr: Option<SocketAddr>,
+    pub startup: StartupParameters,
+    pub backend_start: SystemTime,
+    pub queries: u64,
+    pub last_query: String,
+    // Whether the executor is running a query
+    pub active: bool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: SessionInfo,
+    // Set by pg_cancel_backend() and by a CancelRequest with the secret
+    // key, see server::Handler::cancel
+    cancel: Arc<AtomicBool>,
+    // Sent to the frontend in BackendKeyData
+    secret_key: i32,
+    // Shut down by pg_terminate_backend()
+    stream: Option<TcpStream>,
+}
+
+/// The sessions of a server by pid, shared by the sessions: cloning gives
+/// another handle on the same sessions.
+#[derive(Debug, Clone, Default)]
+pub struct SessionRegistry {
+    // The time of backend_start
+    pub clock: Clock,
+    sessions: Arc<Mutex<BTreeMap<i32, Entry>>>,
+    last_pid: Arc<AtomicI32>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a session until the returned guard is dropped, a session with a
+    /// stream can be terminated
+    pub fn register(&self, stream: Option<&TcpStream>) -> anyhow::Result<RegisteredSession> {
+        // The only authentication of a CancelRequest
+        let secret_key = i32::from_be_bytes(random::secure_bytes()?);
+        let pid = self.last_pid.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.insert(
+                pid,
+                Entry {
+                    info: SessionInfo {
+                        pid,
+                        client_addr: stream.and_then(|stream| stream.peer_addr().ok()),
+                        startup: StartupParameters::default(),
+                        backend_start: self.clock.system_time(),
+                        queries: 0,
+                        last_query: String::new(),
+                        active: false,
+                    },
+                    cancel: Arc::new(AtomicBool::new(false)),
+                    secret_key,
+                    stream: stream.and_then(|stream| stream.try_clone().ok()),
+                },
+            );
+        }
+        Ok(RegisteredSession {
+            registry: self.clone(),
+            pid,
+        })
+    }
+
+    /// The sessions connected, by pid
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        match self.sessions.lock() {
+            Ok(sessions) => sessions.values().map(|entry| entry.info.clone()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Cancel the query running in a session, it fails once the executor
+    /// returns. False when there is no such session.
+    pub fn cancel(&self, pid: i32) -> bool {
+        match self.sessions.lock() {
+            Ok(sessions) => match sessions.get(&pid) {
+                Some(entry) => {
+                    entry.cancel.store(true, Ordering::SeqCst);
+                    true
+                }
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// cancel() for a CancelRequest, which must give the secret key of the
+    /// session. False when there is no such session or the key is wrong.
+    pub fn cancel_request(&self, pid: i32, secret_key: i32) -> bool {
+        let known = match self.sessions.lock() {
+            Ok(sessions) => sessions
+                .get(&pid)
+                .is_some_and(|entry| entry.secret_key == secret_key),
+            Err(_) => false,
+        };
+        if !known {
+            warn!("CancelRequest for {pid} ignored: no session with this key");
+        }
+        known && self.cancel(pid)
+    }
+
+    /// Close the connection of a session
+    pub fn terminate(&self, pid: i32) -> bool {
+        match self.sessions.lock() {
+            Ok(sessions) => match sessions.get(&pid).and_then(|entry| entry.stream.as_ref()) {
+                Some(stream) => stream.shutdown(Shutdown::Both).is_ok(),
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// A session in the registry, removed when dropped
+#[derive(Debug)]
+pub struct RegisteredSession {
+    registry: SessionRegistry,
+    pid: i32,
+}
+
+impl RegisteredSession {
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    pub fn update(&self, update: impl FnOnce(&mut SessionInfo)) {
+        if let Ok(mut sessions) = self.registry.sessions.lock()
+            && let Some(entry) = sessions.get_mut(&self.pid)
+        {
+            update(&mut entry.info);
+        }
+    }
+
+    pub fn info(&self) -> Option<SessionInfo> {
+        let sessions = self.registry.sessions.lock().ok()?;
+        sessions.get(&self.pid).map(|entry| entry.info.clone())
+    }
+
+    /// For BackendKeyData, see SessionRegistry::cancel_request
+    pub fn secret_key(&self) -> i32 {
+        match self.registry.sessions.lock() {
+            Ok(sessions) => sessions.get(&self.pid).map_or(0, |entry| entry.secret_key),
+            Err(_) => 0,
+        }
+    }
+
+    /// Set when the query is canceled, for server::Handler::cancel
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        match self.registry.sessions.lock() {
+            Ok(sessions) => match sessions.get(&self.pid) {
+                Some(entry) => entry.cancel.clone(),
+                None => Arc::default(),
+            },
+            Err(_) => Arc::default(),
+        }
+    }
+}
+
+impl Drop for RegisteredSession {
+    fn drop(&mut self) {
+        if let Ok(mut sessions) = self.registry.sessions.lock() {
+            sessions.remove(&self.pid);
+        }
+    }
+}
+
+/// Answers the queries on the views of the fakepostmaster schema, before
+/// the executor
+#[derive(Debug, Clone)]
+pub struct VirtualSchema {
+    pub sessions: SessionRegistry,
+    pub stats: QueryStats,
+    pub config: Arc<ServerConfig>,
+    pub prepared_transactions: PreparedTransactions,
+    pub large_objects: LargeObjects,
+    // The session asking, for pg_backend_pid()
+    pub backend_pid: Option<i32>,
+}
+
+impl VirtualSchema {
+    /// The result of a query on a view, `None` for the other queries
+    pub fn answer(&self, query: &str) -> Option<QueryResult> {
+        self.answer_bound(query, &[])
+    }
+
+    /// answer() for a portal, with the parameters `$n` of its statement
+    pub fn answer_bound(&self, query: &str, parameters: &[Option<String>]) -> Option<QueryResult> {
+        let view = |view: &str| match view {
+            "fakepostmaster . sessions" => Some(self.sessions()),
+            "fakepostmaster . stats" => Some(self.stats()),
+            "fakepostmaster . config" => Some(self.config()),
+            "pg_stat_activity" | "pg_catalog . pg_stat_activity" => Some(self.pg_stat_activity()),
+            "pg_prepared_xacts" | "pg_catalog . pg_prepared_xacts" => {
+                Some(self.pg_prepared_xacts())
+            }
+            #[cfg(feature = "catalog")]
+            view => catalog::relation(&self.config, view),
+            #[cfg(not(feature = "catalog"))]
+            _ => None,
+        };
+        let result = match select_session_view(query, parameters, self.backend_pid, view) {
+            Some(result) => result,
+            None => {
+                let tokens = bound_tokens(query, parameters).ok()??;
+                let (function, pid) = match &tokens[..] {
+                    [select, function, open, pid, close]
+                        if select == "select" && open == "(" && close == ")" =>
+                    {
+                        (function.as_str(), pid.trim_matches('\''))
+                    }
+                    _ => return None,
+                };
+                let pid = pid.parse().ok()?;
+                let found = match function {
+                    "pg_cancel_backend" => self.sessions.cancel(pid),
+                    "pg_terminate_backend" => self.sessions.terminate(pid),
+                    _ => return None,
+                };
+                columns![(function, Bool)].map(|columns| QueryResult {
+                    columns,
+                    rows: vec![vec![PgValue::Bool(found)]],
+                    command_tag: String::from("SELECT 1"),
+                    error: None,
+                })
+            }
+        };
+        match result {
+            Ok(result) => Some(result),
+            Err(e) => {
+                error!("{query}: {e}");
+                None
+            }
+        }
+    }
+
+    fn sessions(&self) -> anyhow::Result<QueryResult> {
+        let text = |value: Option<&str>| PgValue::Text(String::from(value.unwrap_or_default()));
+        let rows: Vec<Vec<PgValue>> = self
+            .sessions
+            .sessions()
+            .into_iter()
+            .map(|session| {
+                vec![
+                    PgValue::Int4(session.pid),
+                    PgValue::Text(
+                        session
+                            .client_addr
+                            .map_or(String::new(), |address| address.ip().to_string()),
+                    ),
+                    text(session.startup.user()),
+                    text(session.startup.database()),
+                    text(session.startup.application_name()),
+                    PgValue::Timestamp(timestamp_from_system_time(session.backend_start)),
+                    PgValue::Int8(session.queries as i64),
+                    PgValue::Text(session.last_query),
+                ]
+            })
+            .collect();
+        Ok(QueryResult {
+            columns: columns![
+                ("pid", Int4),
+                ("client_addr", Text),
+                ("user", Text),
+                ("database", Text),
+                ("application_name", Text),
+                ("backend_start", Timestamp),
+                ("queries", Int8),
+                ("last_query", Text),
+            ]?,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        })
+    }
+
+    /// The columns of pg_stat_activity that the sessions can fill
+    fn pg_stat_activity(&self) -> anyhow::Result<QueryResult> {
+        let text = |value: Option<&str>| PgValue::Text(String::from(value.unwrap_or_default()));
+        let rows: Vec<Vec<PgValue>> = self
+            .sessions
+            .sessions()
+            .into_iter()
+            .map(|session| {
+                vec![
+                    text(session.startup.database()),
+                    PgValue::Int4(session.pid),
+                    text(session.startup.user()),
+                    text(session.startup.application_name()),
+                    PgValue::Text(
+                        session
+                            .client_addr
+                            .map_or(String::new(), |address| address.ip().to_string()),
+                    ),
+                    PgValue::Timestamp(timestamp_from_system_time(session.backend_start)),
+                    PgValue::Text(String::from(if session.active { "active" } else { "idle" })),
+                    PgValue::Text(session.last_query),
+                    PgValue::Text(String::from("client backend")),
+                ]
+            })
+            .collect();
+        Ok(QueryResult {
+            columns: columns![
+                ("datname", Text),
+                ("pid", Int4),
+                ("usename", Text),
+                ("application_name", Text),
+                ("client_addr", Text),
+                ("backend_start", Timestamp),
+                ("state", Text),
+                ("query", Text),
+                ("backend_type", Text),
+            ]?,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        })
+    }
+
+    fn pg_prepared_xacts(&self) -> anyhow::Result<QueryResult> {
+        let rows: Vec<Vec<PgValue>> = self
+            .prepared_transactions
+            .gids()
+            .into_iter()
+            .map(|gid| vec![PgValue::Text(gid)])
+            .collect();
+        Ok(QueryResult {
+            columns: columns![("gid", Text)]?,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        })
+    }
+
+    fn stats(&self) -> anyhow::Result<QueryResult> {
+        let interval = |duration: Duration| PgValue::Interval {
+            months: 0,
+            days: 0,
+            microseconds: duration.as_micros() as i64,
+        };
+        let rows: Vec<Vec<PgValue>> = self
+            .stats
+            .summary()
+            .into_iter()
+            .map(|query| {
+                vec![
+                    PgValue::Text(query.pattern),
+                    PgValue::Int8(query.count as i64),
+                    interval(query.p50),
+                    interval(query.p95),
+                    interval(query.p99),
+                    interval(query.max),
+                ]
+            })
+            .collect();
+        Ok(QueryResult {
+            columns: columns![
+                ("pattern", Text),
+                ("calls", Int8),
+                ("p50", Interval),
+                ("p95", Interval),
+                ("p99", Interval),
+                ("max", Interval),
+            ]?,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        })
+    }
+
+    fn config(&self) -> anyhow::Result<QueryResult> {
+        let mut settings = Vec::new();
+        flatten("", &toml::Value::try_from(&*self.config)?, &mut settings);
+        let rows: Vec<Vec<PgValue>> = settings
+            .into_iter()
+            .filter(|(key, _)| !key.ends_with(".password"))
+            .map(|(key, value)| vec![PgValue::Text(key), PgValue::Text(value)])
+            .collect();
+        Ok(QueryResult {
+            columns: columns![("key", Text), ("value", Text)]?,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        })
+    }
+}
+
+/// The result of `SELECT columns FROM view [WHERE ...]` on a view given by
+/// `view` from its normalized name, e.g. "pg_catalog . pg_class". `None`
+/// for the other queries and views.
+pub fn select_view(
+    query: &str,
+    view: impl Fn(&str) -> Option<anyhow::Result<QueryResult>>,
+) -> Option<anyhow::Result<QueryResult>> {
+    select_bound_view(query, &[], view)
+}
+
+/// select_view with the parameters `$n` of a portal. The views can be
+/// joined and the rows sorted: `SELECT columns FROM view [[AS] alias]
+/// [[INNER] JOIN view [[AS] alias] ON conditions]... [WHERE conditions]
+/// [ORDER BY column [ASC | DESC], ...]`, the columns qualified by their
+/// view or not. The conditions are `operand = operand [AND ...]`, or `<>`
+/// and `!=`, the operands being columns or literals.
+pub fn select_bound_view(
+    query: &str,
+    parameters: &[Option<String>],
+    view: impl Fn(&str) -> Option<anyhow::Result<QueryResult>>,
+) -> Option<anyhow::Result<QueryResult>> {
+    select_session_view(query, parameters, None, view)
+}
+
+/// select_bound_view in a session, `pg_backend_pid()` being its pid
+fn select_session_view(
+    query: &str,
+    parameters: &[Option<String>],
+    backend_pid: Option<i32>,
+    view: impl Fn(&str) -> Option<anyhow::Result<QueryResult>>,
+) -> Option<anyhow::Result<QueryResult>> {
+    let tokens = bound_tokens(query, parameters).ok()??;
+    let select = Parser {
+        tokens: &tokens,
+        backend_pid,
+    }
+    .select()?;
+    let mut views = Vec::new();
+    for (name, alias) in &select.views {
+        match view(name)? {
+            Ok(result) => views.push((name.as_str(), alias.as_str(), result)),
+            Err(e) => return Some(Err(e)),
+        }
+    }
+    Some(select.run(views))
+}
+
+/// A column, e.g. `c . relname`, its qualifier being the name or the alias
+/// of a view
+#[derive(Debug)]
+struct Column {
+    qualifier: Option<String>,
+    name: String,
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.qualifier {
+            Some(qualifier) => write!(f, "{qualifier}.{}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Operand {
+    Column(Column),
+    // In text, None for NULL
+    Value(Option<Vec<u8>>),
+}
+
+/// A query on the views, as far as select_bound_view understands it
+#[derive(Debug)]
+struct Select {
+    // The columns with the name given to them, None for `*`
+    columns: Option<Vec<(Column, String)>>,
+    // The views joined with their alias
+    views: Vec<(String, String)>,
+    // The conditions of the joins and of the WHERE clause, true for `=`
+    // and false for `<>`
+    conditions: Vec<(Operand, bool, Operand)>,
+    // The columns sorting the rows, true for DESC
+    order: Vec<(Column, bool)>,
+}
+
+impl Select {
+    fn run(&self, views: Vec<(&str, &str, QueryResult)>) -> anyhow::Result<QueryResult> {
+        // The columns of the views side by side, with their view
+        let mut columns = Vec::new();
+        let mut rows: Vec<Vec<PgValue>> = vec![Vec::new()];
+        for (name, alias, result) in views {
+            for column in result.columns {
+                columns.push((name, alias, column));
+            }
+            rows = rows
+                .iter()
+                .flat_map(|row| result.rows.iter().map(|joined| [&row[..], joined].concat()))
+                .collect();
+        }
+        let index = |column: &Column| {
+            let mut found = columns.iter().enumerate().filter(|(_, (name, alias, c))| {
+                c.name.to_bytes() == column.name.as_bytes()
+                    && column.qualifier.as_ref().is_none_or(|qualifier| {
+                        [*alias, *name].contains(&&qualifier[..])
+                            || name.rsplit(" . ").next() == Some(&qualifier[..])
+                    })
+            });
+            match (found.next(), found.next()) {
+                (Some((i, _)), None) => Ok(i),
+                (Some(_), Some(_)) => Err(anyhow!("column reference \"{column}\" is ambiguous")),
+                (None, _) => Err(anyhow!("column \"{column}\" does not exist")),
+            }
+        };
+        let operand = |operand: &Operand| match operand {
+            Operand::Column(column) => index(column).map(Some),
+            Operand::Value(_) => Ok(None),
+        };
+
+        let mut tests = Vec::new();
+        for (left, equal, right) in &self.conditions {
+            tests.push((operand(left)?, left, *equal, operand(right)?, right));
+        }
+        let value =
+            |row: &[PgValue], index: Option<usize>, operand: &Operand| match (index, operand) {
+                (Some(i), _) if row[i] == PgValue::Null => None,
+                (Some(i), _) => Some(row[i].to_text()),
+                (None, Operand::Value(value)) => value.clone(),
+                (None, Operand::Column(_)) => None,
+            };
+        // NULL is equal to nothing, and not different either
+        rows.retain(|row| {
+            tests.iter().all(|(i, left, equal, j, right)| {
+                match (value(row, *i, left), value(row, *j, right)) {
+                    (Some(left), Some(right)) => (left == right) == *equal,
+                    _ => false,
+                }
+            })
+        });
+
+        let mut order = Vec::new();
+        for (column, descending) in &self.order {
+            order.push((index(column)?, *descending));
+        }
+        rows.sort_by(|a, b| {
+            order
+                .iter()
+                .map(|(i, descending)| {
+                    let ordering = compare(&a[*i], &b[*i]);
+                    if *descending {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(cmp::Ordering::Equal)
+        });
+
+        let (columns, rows) = match &self.columns {
+            None => (
+                columns.into_iter().map(|(_, _, column)| column).collect(),
+                rows,
+            ),
+            Some(selected) => {
+                let mut indexes = Vec::new();
+                let mut described = Vec::new();
+                for (column, name) in selected {
+                    let i = index(column)?;
+                    indexes.push(i);
+                    described.push(ColumnDescription {
+                        name: CString::new(name.as_bytes())?,
+                        ..columns[i].2.clone()
+                    });
+                }
+                let rows = rows
+                    .iter()
+                    .map(|row| indexes.iter().map(|&i| row[i].clone()).collect())
+                    .collect();
+                (described, rows)
+            }
+        };
+        Ok(QueryResult {
+            columns,
+            command_tag: format!("SELECT {}", rows.len()),
+            rows,
+            error: None,
+        })
+    }
+}
+
+/// The numbers by their value, the rest by their text, NULL last
+fn compare(a: &PgValue, b: &PgValue) -> cmp::Ordering {
+    let number = |value: &PgValue| String::from_utf8(value.to_text()).ok()?.parse::<f64>().ok();
+    match (a, b) {
+        (PgValue::Null, PgValue::Null) => cmp::Ordering::Equal,
+        (PgValue::Null, _) => cmp::Ordering::Greater,
+        (_, PgValue::Null) => cmp::Ordering::Less,
+        _ => match (number(a), number(b)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            _ => a.to_text().cmp(&b.to_text()),
+        },
+    }
+}
+
+// The words that can't be an alias or a column
+const KEYWORDS: &[&str] = &[
+    "select", "from", "where", "and", "or", "not", "on", "join", "inner", "left", "right", "full",
+    "cross", "order", "by", "asc", "desc", "as", "group", "having", "limit", "offset", "union",
+    "null", "true", "false", "is", "in", "like",
+];
+
+// The casts that keep the text of a value, e.g. `$1 :: oid`
+const CASTS: &[&str] = &[
+    "oid", "int2", "int4", "int8", "smallint", "integer", "bigint", "text", "name", "varchar",
+    "bool", "boolean",
+];
+
+/// Reads a Select from the tokens of a query, None for what it doesn't
+/// understand
+struct Parser<'a> {
+    tokens: &'a [String],
+    // The value of pg_backend_pid(), not understood without a session
+    backend_pid: Option<i32>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.first().map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let (first, rest) = self.tokens.split_first()?;
+        self.tokens = rest;
+        Some(first)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let eaten = self.peek() == Some(token);
+        if eaten {
+            self.next();
+        }
+        eaten
+    }
+
+    fn expect(&mut self, token: &str) -> Option<()> {
+        self.eat(token).then_some(())
+    }
+
+    /// A name, without its quotes when it is a quoted identifier
+    fn identifier(&mut self) -> Option<String> {
+        let token = self.peek()?;
+        let identifier = match token.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+            Some(quoted) => quoted.replace("\"\"", "\""),
+            None if token.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                && !KEYWORDS.contains(&token) =>
+            {
+                String::from(token)
+            }
+            None => return None,
+        };
+        self.next();
+        Some(identifier)
+    }
+
+    /// The parts of a dotted name, e.g. `pg_catalog . pg_class`
+    fn name(&mut self) -> Option<Vec<String>> {
+        let mut parts = vec![self.identifier()?];
+        while self.eat(".") {
+            parts.push(self.identifier()?);
+        }
+        Some(parts)
+    }
+
+    fn column(&mut self) -> Option<Column> {
+        let mut parts = self.name()?;
+        let name = parts.pop()?;
+        Some(Column {
+            qualifier: (!parts.is_empty()).then(|| parts.join(" . ")),
+            name,
+        })
+    }
+
+    fn alias(&mut self) -> Option<String> {
+        self.eat("as");
+        self.identifier()
+    }
+
+    fn view(&mut self) -> Option<(String, String)> {
+        let name = self.name()?;
+        let alias = self.alias().or(name.last().cloned())?;
+        Some((name.join(" . "), alias))
+    }
+
+    fn operand(&mut self) -> Option<Operand> {
+        let token = self.peek()?;
+        let operand =
+            if let Some(text) = token.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+                self.next();
+                Operand::Value(Some(text.replace("''", "'").into_bytes()))
+            } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+                self.next();
+                Operand::Value(Some(token.as_bytes().to_vec()))
+            } else if let Some(value) = match token {
+                "true" => Some(Some(b"t".to_vec())),
+                "false" => Some(Some(b"f".to_vec())),
+                "null" => Some(None),
+                _ => None,
+            } {
+                self.next();
+                Operand::Value(value)
+            } else if token == "pg_backend_pid" {
+                let pid = self.backend_pid?;
+                self.next();
+                self.expect("(")?;
+                self.expect(")")?;
+                Operand::Value(Some(pid.to_string().into_bytes()))
+            } else {
+                Operand::Column(self.column()?)
+            };
+        while self.eat(":") {
+            self.expect(":")?;
+            CASTS.contains(&self.next()?).then_some(())?;
+        }
+        Some(operand)
+    }
+
+    /// `=`, or `<>` and `!=` for false
+    fn equal(&mut self) -> Option<bool> {
+        if self.eat("=") {
+            Some(true)
+        } else if self.eat("<") {
+            self.expect(">").map(|_| false)
+        } else {
+            self.expect("!")?;
+            self.expect("=").map(|_| false)
+        }
+    }
+
+    /// `operand = operand [AND ...]`, the conditions can be in parentheses
+    fn conditions(&mut self, conditions: &mut Vec<(Operand, bool, Operand)>) -> Option<()> {
+        loop {
+            if self.eat("(") {
+                self.conditions(conditions)?;
+                self.expect(")")?;
+            } else {
+                let left = self.operand()?;
+                let equal = self.equal()?;
+                conditions.push((left, equal, self.operand()?));
+            }
+            if !self.eat("and") {
+                return Some(());
+            }
+        }
+    }
+
+    fn select(mut self) -> Option<Select> {
+        self.expect("select")?;
+        let columns = match self.eat("*") {
+            true => None,
+            false => {
+                let mut columns = Vec::new();
+                loop {
+                    let column = self.column()?;
+                    let name = self.alias().unwrap_or(column.name.clone());
+                    columns.push((column, name));
+                    if !self.eat(",") {
+                        break Some(columns);
+                    }
+                }
+            }
+        };
+        self.expect("from")?;
+        let mut select = Select {
+            columns,
+            views: vec![self.view()?],
+            conditions: Vec::new(),
+            order: Vec::new(),
+        };
+        while self.eat("join") || (self.eat("inner") && self.eat("join")) {
+            select.views.push(self.view()?);
+            self.expect("on")?;
+            self.conditions(&mut select.conditions)?;
+        }
+        if self.eat("where") {
+            self.conditions(&mut select.conditions)?;
+        }
+        if self.eat("order") {
+            self.expect("by")?;
+            loop {
+                let column = self.column()?;
+                let descending = self.eat("desc");
+                if !descending {
+                    self.eat("asc");
+                }
+                select.order.push((column, descending));
+                if !self.eat(",") {
+                    break;
+                }
+            }
+        }
+        self.tokens.is_empty().then_some(select)
+    }
+}
+
+// The keys are written like in the validation errors, e.g. auth[0].method
+fn flatten(key: &str, value: &toml::Value, settings: &mut Vec<(String, String)>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (name, value) in table {
+                let key = match key {
+                    "" => name.clone(),
+                    key => format!("{key}.{name}"),
+                };
+                flatten(&key, value, settings);
+            }
+        }
+        toml::Value::Array(array) => {
+            for (i, value) in array.iter().enumerate() {
+                flatten(&format!("{key}[{i}]"), value, settings);
+            }
+        }
+        toml::Value::String(value) => settings.push((String::from(key), value.clone())),
+        value => settings.push((String::from(key), value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn virtual_schema() -> anyhow::Result<()> {
+        let schema = VirtualSchema {
+            sessions: SessionRegistry::new(),
+            stats: QueryStats::default(),
+            config: Arc::new(ServerConfig::from_toml(
+                "[[users]]\nname = \"app\"\npassword = \"secret\"\n",
+            )?),
+            prepared_transactions: PreparedTransactions::new(),
+            large_objects: LargeObjects::new(),
+            backend_pid: None,
+        };
+        let session = schema.sessions.register(None)?;
+        session.update(|session| session.last_query = String::from("SELECT 1"));
+        schema
+            .stats
+            .record("SELECT 1", Duration::from_millis(1), "");
+
+        let sessions = schema
+            .answer("select * from FAKEPOSTMASTER.sessions;")
+            .ok_or(anyhow::anyhow!("sessions not answered"))?;
+        assert_eq!("SELECT 1", sessions.command_tag);
+        assert_eq!(PgValue::Text(String::from("SELECT 1")), sessions.rows[0][7]);
+        assert_eq!(
+            1,
+            schema
+                .answer("SELECT * FROM fakepostmaster.stats")
+                .map_or(0, |r| r.rows.len())
+        );
+        let config = schema
+            .answer("SELECT * FROM fakepostmaster.config")
+            .ok_or(anyhow::anyhow!("config not answered"))?;
+        assert!(config.rows.contains(&vec![
+            PgValue::Text(String::from("users[0].name")),
+            PgValue::Text(String::from("app"))
+        ]));
+        assert!(
+            !config
+                .rows
+                .iter()
+                .flatten()
+                .any(|value| String::from_utf8_lossy(&value.to_text()).contains("secret"))
+        );
+        assert!(
+            schema
+                .answer("SELECT * FROM fakepostmaster.other")
+                .is_none()
+        );
+
+        let canceled = schema
+            .answer(&format!("SELECT pg_cancel_backend({})", session.pid()))
+            .ok_or(anyhow::anyhow!("pg_cancel_backend not answered"))?;
+        assert_eq!(vec![vec![PgValue::Bool(true)]], canceled.rows);
+        assert!(session.cancel_flag().load(Ordering::SeqCst));
+        assert_eq!(
+            2,
+            schema
+                .answer("SELECT pid, state FROM pg_stat_activity")
+                .map_or(0, |r| r.columns.len())
+        );
+        let activity = schema
+            .answer(&format!(
+                "SELECT pid FROM pg_stat_activity WHERE pid = {} AND state = 'idle'",
+                session.pid()
+            ))
+            .ok_or(anyhow::anyhow!("pg_stat_activity not answered"))?;
+        assert_eq!("SELECT 1", activity.command_tag);
+        let other = schema.sessions.register(None)?;
+        let schema = VirtualSchema {
+            backend_pid: Some(session.pid()),
+            ..schema
+        };
+        for query in [
+            "SELECT pid FROM pg_stat_activity WHERE pid <> pg_backend_pid()",
+            "SELECT pid FROM pg_stat_activity WHERE pg_backend_pid() != pid AND state = 'idle'",
+        ] {
+            let activity = schema
+                .answer(query)
+                .ok_or(anyhow::anyhow!("{query} not answered"))?;
+            assert_eq!(vec![vec![PgValue::Int4(other.pid())]], activity.rows);
+        }
+        assert!(
+            schema
+                .answer("SELECT pid FROM pg_stat_activity WHERE pid < pg_backend_pid()")
+                .is_none()
+        );
+
+        // A CancelRequest must give the secret key of the session
+        let key = other.secret_key();
+        assert!(
+            !schema
+                .sessions
+                .cancel_request(other.pid(), key.wrapping_add(1))
+        );
+        assert!(!other.cancel_flag().load(Ordering::SeqCst));
+        assert!(schema.sessions.cancel_request(other.pid(), key));
+        assert!(other.cancel_flag().load(Ordering::SeqCst));
+        drop(other);
+        #[cfg(feature = "catalog")]
+        {
+            let typname = schema
+                .answer("SELECT typname FROM pg_catalog.pg_type WHERE oid = 3802")
+                .ok_or(anyhow::anyhow!("pg_type not answered"))?;
+            assert_eq!(vec![vec![PgValue::from("jsonb")]], typname.rows);
+            assert!(
+                schema
+                    .answer("SELECT * FROM pg_type WHERE oid > 3802")
+                    .is_none()
+            );
+        }
+
+        drop(session);
+        assert!(schema.sessions.sessions().is_empty());
+        assert!(!schema.sessions.cancel(1));
+
+        Ok(())
+    }
+}
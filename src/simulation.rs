@@ -0,0 +1,567 @@
+//! Deterministic simulation of sessions, to test the races between the
+//! queries, the timeouts and the cancel requests reproducibly.
+//!
+//! The sessions are actors run by threads, but only one of them runs at a
+//! time: the simulation waits until every session is idle (waiting for an
+//! event or sleeping), then moves on with one of the things that can happen
+//! at the current virtual time. An event scripted for a session is
+//! delivered, or a sleeping session wakes up. When several can happen, the
+//! seed picks one. When none can, the virtual clock jumps to the next one.
+//!
+//! The time only passes for the steps `sleep_ms` of the scenarios, run with
+//! the Clock of the simulation. The other steps of the scenarios wait for
+//! real.
+//!
+//! Outside of a simulation, a manual Clock lets a test advance the time of
+//! the executors, the rate limits, the sessions and the client keepalives
+//! instead of sleeping.
+
+use anyhow::anyhow;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::handler::actor::{Event, InboxSender, SessionActor};
+use crate::handler::server::{Authentication, QueryResult};
+use crate::message::RawBackendMessage;
+
+thread_local! {
+    // The session run by the thread, for the virtual sleeps
+    static CURRENT_SESSION: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// How the time passes for the executors, the rate limits, the sessions
+/// and the clients
+#[derive(Clone, Default)]
+pub enum Clock {
+    #[default]
+    Real,
+    // The time of a simulation, sleeping lets the other sessions run
+    Simulated(Arc<Shared>),
+    // Only passes when the test advances it
+    Manual(Arc<ManualClock>),
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Clock::Real => write!(f, "Real"),
+            Clock::Simulated(_) => write!(f, "Simulated"),
+            Clock::Manual(clock) => write!(f, "Manual({:?})", clock.elapsed()),
+        }
+    }
+}
+
+impl Clock {
+    /// A clock starting now, see ManualClock::advance()
+    pub fn manual() -> Self {
+        Clock::Manual(Arc::new(ManualClock::new()))
+    }
+
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::Real => Instant::now(),
+            Clock::Simulated(shared) => shared.start.0 + shared.lock().now,
+            Clock::Manual(clock) => clock.start.0 + clock.elapsed(),
+        }
+    }
+
+    /// The wall clock, e.g. for the timestamps of the sessions
+    pub fn system_time(&self) -> SystemTime {
+        match self {
+            Clock::Real => SystemTime::now(),
+            Clock::Simulated(shared) => shared.start.1 + shared.lock().now,
+            Clock::Manual(clock) => clock.start.1 + clock.elapsed(),
+        }
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        if let Clock::Manual(clock) = self {
+            return clock.sleep(duration);
+        }
+        let shared = match (self, CURRENT_SESSION.get()) {
+            (Clock::Simulated(shared), Some(_)) => shared,
+            // Not a session of the simulation, e.g. the executor is called
+            // by the test itself
+            _ => return thread::sleep(duration),
+        };
+        let session = CURRENT_SESSION.get().expect("simulated session");
+        let mut state = shared.lock();
+        let wake_up = state.now + duration;
+        state.set(session, Task::Sleeping(wake_up));
+        shared.condvar.notify_all();
+        while state.tasks[session] != Task::Running {
+            state = shared.wait(state);
+        }
+    }
+}
+
+/// A clock moved by hand, the threads sleeping on it wake up once it is
+/// advanced past their wake up time
+#[derive(Debug)]
+pub struct ManualClock {
+    // The real time when the clock was created
+    start: (Instant, SystemTime),
+    elapsed: Mutex<Duration>,
+    condvar: Condvar,
+    // The threads in sleep()
+    sleepers: AtomicUsize,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: (Instant::now(), SystemTime::now()),
+            elapsed: Mutex::new(Duration::ZERO),
+            condvar: Condvar::new(),
+            sleepers: AtomicUsize::new(0),
+        }
+    }
+
+    /// The threads waiting for the clock to be advanced, e.g. to advance it
+    /// only once a session sleeps
+    pub fn sleepers(&self) -> usize {
+        self.sleepers.load(Ordering::SeqCst)
+    }
+
+    /// The virtual time since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("clock lock")
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().expect("clock lock") += duration;
+        self.condvar.notify_all();
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut elapsed = self.elapsed.lock().expect("clock lock");
+        let wake_up = *elapsed + duration;
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        while *elapsed < wake_up {
+            elapsed = self.condvar.wait(elapsed).expect("clock lock");
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// What a simulated session is doing
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Task {
+    Running,
+    // For the next event of its inbox
+    Waiting,
+    // Until the virtual time given
+    Sleeping(Duration),
+    Finished,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    now: Duration,
+    tasks: Vec<Task>,
+}
+
+impl State {
+    fn set(&mut self, session: usize, task: Task) {
+        // A finished session stays finished
+        if self.tasks[session] != Task::Finished {
+            self.tasks[session] = task;
+        }
+    }
+}
+
+/// The state of a simulation, shared with its sessions and its clock
+#[derive(Debug)]
+pub struct Shared {
+    // The real time when the simulation was created, at virtual time 0
+    start: (Instant, SystemTime),
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Self {
+            start: (Instant::now(), SystemTime::now()),
+            state: Mutex::default(),
+            condvar: Condvar::new(),
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("simulation lock")
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.condvar.wait(state).expect("simulation lock")
+    }
+
+    /// Wait until no session runs
+    fn idle(&self) -> MutexGuard<'_, State> {
+        let mut state = self.lock();
+        while state.tasks.contains(&Task::Running) {
+            state = self.wait(state);
+        }
+        state
+    }
+}
+
+/// An event scripted for a session, delivered once the virtual time reaches
+/// `at`. A Cancel sets the cancel flag of the session, like a CancelRequest
+/// sent on another connection, instead of going to its inbox.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptedEvent {
+    pub at: Duration,
+    pub event: Event,
+}
+
+type Executor = Arc<dyn Fn(String) -> QueryResult + Send + Sync>;
+
+struct SimulatedSession {
+    executor: Executor,
+    script: VecDeque<ScriptedEvent>,
+}
+
+/// The thread of a session and the ends of its boxes
+struct RunningSession {
+    // Dropped to disconnect the frontend
+    inbox: Option<InboxSender>,
+    // Reads the outbox, its sends block once it is full
+    outbox: JoinHandle<Vec<u8>>,
+    cancel: Arc<AtomicBool>,
+    thread: JoinHandle<Result<(), String>>,
+}
+
+/// What happened during a simulation
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationReport {
+    // What the simulation did, in order, with the virtual time
+    pub trace: Vec<String>,
+    // The bytes sent by each session
+    pub outputs: Vec<Vec<u8>>,
+    // How each session ended
+    pub results: Vec<Result<(), String>>,
+}
+
+impl SimulationReport {
+    /// The types of the messages sent by a session, e.g. "RSZCZ"
+    pub fn message_types(&self, session: usize) -> String {
+        let mut reader = BufReader::new(&self.outputs[session][..]);
+        let mut types = String::new();
+        while let Ok(raw_message) = RawBackendMessage::get(&mut reader) {
+            types.push(raw_message.header.message_type as char);
+        }
+        types
+    }
+}
+
+/// Sessions run with a virtual clock and scripted events, the same seed
+/// gives the same interleaving
+pub struct Simulation {
+    seed: u64,
+    shared: Arc<Shared>,
+    sessions: Vec<SimulatedSession>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            shared: Arc::default(),
+            sessions: Vec::new(),
+        }
+    }
+
+    /// The clock to give to the executors, e.g. ScriptedExecutor::clock
+    pub fn clock(&self) -> Clock {
+        Clock::Simulated(self.shared.clone())
+    }
+
+    /// Add a session trusting its frontend, its number is returned
+    pub fn session(
+        &mut self,
+        executor: impl Fn(String) -> QueryResult + Send + Sync + 'static,
+    ) -> usize {
+        self.sessions.push(SimulatedSession {
+            executor: Arc::new(executor),
+            script: VecDeque::new(),
+        });
+        self.sessions.len() - 1
+    }
+
+    /// Script an event, the events of a session are delivered in order
+    pub fn at(&mut self, at: Duration, session: usize, event: Event) -> anyhow::Result<()> {
+        let script = &mut self
+            .sessions
+            .get_mut(session)
+            .ok_or(anyhow!("No session {session}"))?
+            .script;
+        if script.back().is_some_and(|last| last.at > at) {
+            return Err(anyhow!("The events of session {session} are not in order"));
+        }
+        script.push_back(ScriptedEvent { at, event });
+        Ok(())
+    }
+
+    /// Run the sessions until every event is delivered and no session
+    /// sleeps, then disconnect the frontends
+    pub fn run(mut self) -> anyhow::Result<SimulationReport> {
+        let shared = self.shared.clone();
+        let mut trace = Vec::new();
+        let mut random = self.seed | 1;
+        let mut running = Vec::new();
+        for (session, simulated) in self.sessions.iter().enumerate() {
+            running.push(self.spawn(session, simulated.executor.clone()));
+            drop(shared.idle());
+        }
+
+        loop {
+            let mut state = shared.idle();
+            let now = state.now;
+            // Each thing that can happen now: an event or a wake up
+            let mut ready: Vec<(usize, bool)> = Vec::new();
+            for (session, simulated) in self.sessions.iter().enumerate() {
+                if simulated.script.front().is_some_and(|next| next.at <= now) {
+                    ready.push((session, true));
+                }
+                if matches!(state.tasks[session], Task::Sleeping(wake_up) if wake_up <= now) {
+                    ready.push((session, false));
+                }
+            }
+            if ready.is_empty() {
+                let next = self
+                    .sessions
+                    .iter()
+                    .filter_map(|simulated| simulated.script.front().map(|next| next.at))
+                    .chain(state.tasks.iter().filter_map(|task| match task {
+                        Task::Sleeping(wake_up) => Some(*wake_up),
+                        _ => None,
+                    }))
+                    .min();
+                match next {
+                    Some(next) => state.now = next,
+                    None => break,
+                }
+                continue;
+            }
+
+            // xorshift64, the seed decides the interleaving
+            random ^= random << 13;
+            random ^= random >> 7;
+            random ^= random << 17;
+            let (session, scripted) = ready[(random % ready.len() as u64) as usize];
+            if !scripted {
+                trace.push(format!("{now:?} session {session}: wake up"));
+                state.set(session, Task::Running);
+                shared.condvar.notify_all();
+                continue;
+            }
+            let event = self.sessions[session]
+                .script
+                .pop_front()
+                .expect("scripted event")
+                .event;
+            trace.push(format!("{now:?} session {session}: {}", describe(&event)));
+            match event {
+                Event::Cancel => running[session].cancel.store(true, Ordering::SeqCst),
+                event => {
+                    // A sleeping session reads the event once it wakes up
+                    if state.tasks[session] == Task::Waiting {
+                        state.set(session, Task::Running);
+                    }
+                    if let Some(inbox) = &running[session].inbox
+                        && inbox.send(event).is_err()
+                    {
+                        state.set(session, Task::Finished);
+                    }
+                }
+            }
+        }
+
+        // The frontends still connected are gone
+        for running in running.iter_mut() {
+            running.inbox.take();
+        }
+        let mut report = SimulationReport {
+            trace,
+            outputs: Vec::new(),
+            results: Vec::new(),
+        };
+        for running in running {
+            report.results.push(match running.thread.join() {
+                Ok(result) => result,
+                Err(_) => Err(String::from("The session panicked")),
+            });
+            report
+                .outputs
+                .push(running.outbox.join().unwrap_or_default());
+        }
+        Ok(report)
+    }
+
+    fn spawn(&self, session: usize, executor: Executor) -> RunningSession {
+        let (mut actor, inbox, outbox) = SessionActor::new();
+        let cancel = actor.handler.cancel.clone().unwrap_or_default();
+        let shared = self.shared.clone();
+        shared.lock().tasks.push(Task::Running);
+        let waiting = shared.clone();
+        actor.on_waiting(move || {
+            waiting.lock().set(session, Task::Waiting);
+            waiting.condvar.notify_all();
+        });
+        let thread = thread::spawn(move || {
+            CURRENT_SESSION.set(Some(session));
+            let result = actor
+                .run(&|_| Authentication::Trust, &|query| executor(query))
+                .map_err(|e| e.to_string());
+            shared.lock().set(session, Task::Finished);
+            shared.condvar.notify_all();
+            result
+        });
+        let outbox = thread::spawn(move || outbox.into_iter().flatten().collect());
+        RunningSession {
+            inbox: Some(inbox),
+            outbox,
+            cancel,
+            thread,
+        }
+    }
+}
+
+fn describe(event: &Event) -> String {
+    match event {
+        Event::Received(bytes) => format!("received {} bytes", bytes.len()),
+        Event::Cancel => String::from("cancel"),
+        Event::Disconnected => String::from("disconnected"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::{ScriptedExecutor, Step};
+    use crate::matcher::QueryMatcher;
+    use crate::message::*;
+    use bytes::{BufMut, BytesMut};
+    use libpq_serde_types::Serialize;
+
+    fn startup() -> anyhow::Result<Vec<u8>> {
+        let startup = StartupMessage::new(
+            ProtocolVersion { major: 3, minor: 0 },
+            vec![ParameterStatus::new("user", "postgres")?],
+        );
+        let mut body = BytesMut::new();
+        startup.serialize(&mut body);
+        let mut buffer = BytesMut::new();
+        buffer.put_i32(body.len() as i32 + 4);
+        buffer.extend_from_slice(&body);
+        Ok(buffer.to_vec())
+    }
+
+    fn query(query: &str) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = BytesMut::new();
+        MessageHeader::serialize_message(&mut buffer, &Query::new(String::from(query))?)?;
+        Ok(buffer.to_vec())
+    }
+
+    /// A slow query canceled at `cancel`, and another session querying
+    /// meanwhile
+    fn simulation(seed: u64, cancel: u64) -> anyhow::Result<SimulationReport> {
+        let mut simulation = Simulation::new(seed);
+        let mut executor = ScriptedExecutor::new().on_scheduled(
+            QueryMatcher::exact("SELECT slow"),
+            QueryResult {
+                command_tag: String::from("SELECT 0"),
+                ..Default::default()
+            },
+            vec![Step::SleepMs(100)],
+        );
+        executor.clock = simulation.clock();
+        let other = executor.clone();
+        let slow = simulation.session(move |query| executor.execute(query));
+        let fast = simulation.session(move |query| other.execute(query));
+        let ms = Duration::from_millis;
+        simulation.at(ms(0), slow, Event::Received(startup()?))?;
+        simulation.at(ms(10), slow, Event::Received(query("SELECT slow")?))?;
+        simulation.at(ms(cancel), slow, Event::Cancel)?;
+        simulation.at(ms(0), fast, Event::Received(startup()?))?;
+        simulation.at(ms(50), fast, Event::Received(query("SELECT 1")?))?;
+        simulation.run()
+    }
+
+    #[test]
+    fn deterministic_simulation() -> anyhow::Result<()> {
+        // Canceled while it sleeps, the other session answers meanwhile
+        let report = simulation(1, 50)?;
+        assert_eq!("RSSSSZEZ", report.message_types(0));
+        assert_eq!("RSSSSZCZ", report.message_types(1));
+        assert!(
+            report
+                .trace
+                .contains(&String::from("110ms session 0: wake up"))
+        );
+        // The query is over when the cancel request comes
+        assert_eq!("RSSSSZCZ", simulation(1, 150)?.message_types(0));
+
+        // The same seed, the same interleaving
+        for seed in 2..6 {
+            assert_eq!(simulation(seed, 50)?.trace, simulation(seed, 50)?.trace);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn manual_clock() -> anyhow::Result<()> {
+        let manual = Arc::new(ManualClock::new());
+        let clock = Clock::Manual(manual.clone());
+        let (before, wall) = (clock.now(), clock.system_time());
+
+        let sleeper = clock.clone();
+        let sleeping = thread::spawn(move || sleeper.sleep(Duration::from_millis(100)));
+        while manual.sleepers() == 0 {
+            thread::yield_now();
+        }
+        manual.advance(Duration::from_millis(60));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!sleeping.is_finished());
+        manual.advance(Duration::from_millis(40));
+        sleeping
+            .join()
+            .map_err(|_| anyhow!("The sleeper panicked"))?;
+
+        assert_eq!(Duration::from_millis(100), clock.now() - before);
+        assert_eq!(
+            Duration::from_millis(100),
+            clock.system_time().duration_since(wall)?
+        );
+
+        let mut limiter = crate::rate_limiter::RateLimiter::new(Some(1), None);
+        limiter.clock = clock;
+        let address = "127.0.0.1".parse()?;
+        let _permit = limiter
+            .admit(address)
+            .map_err(|error| anyhow!(error.message))?;
+        assert!(limiter.admit(address).is_err());
+        manual.advance(Duration::from_secs(1));
+        let _permit = limiter
+            .admit(address)
+            .map_err(|error| anyhow!(error.message))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+//! The latency of the queries by pattern, measured around the executor of
+//! the server, or between a query and its ReadyForQuery in proxy mode and
+//! by the client.
+
+#[cfg(feature = "proxy")]
+use libpq_serde_types::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(feature = "proxy")]
+use std::time::Instant;
+use tracing::*;
+
+#[cfg(feature = "proxy")]
+use crate::handler::proxy::ProxiedMessage;
+use crate::handler::server::StartupParameters;
+use crate::matcher::normalize;
+#[cfg(feature = "proxy")]
+use crate::message::{BackendMessageKind, FrontendMessageKind, Parse, Query};
+
+// The bucket i counts the latencies below 2^i microseconds, the last one
+// the longer ones
+const BUCKETS: usize = 32;
+
+/// The distribution of the latencies in buckets of powers of two
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The upper bound of the bucket holding the percentile, e.g. 0.99, at
+    /// most the max
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((percentile * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(1 << i).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// The latencies of the queries of one pattern
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuerySummary {
+    pub pattern: String,
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// The latency histograms by query pattern (the normalized query), shared
+/// by the sessions: cloning gives another handle on the same histograms.
+///
+/// The queries slower than the threshold are logged as warnings.
+#[derive(Debug, Clone, Default)]
+pub struct QueryStats {
+    histograms: Arc<Mutex<HashMap<String, LatencyHistogram>>>,
+    pub slow_query_threshold: Option<Duration>,
+}
+
+impl QueryStats {
+    pub fn new(slow_query_threshold: Option<Duration>) -> Self {
+        Self {
+            slow_query_threshold,
+            ..Self::default()
+        }
+    }
+
+    /// Record the latency of a query, `context` tells which session ran it
+    pub fn record(&self, query: &str, latency: Duration, context: &str) {
+        let pattern = normalize(query).unwrap_or_else(|_| String::from(query));
+        if let Ok(mut histograms) = self.histograms.lock() {
+            histograms.entry(pattern).or_default().record(latency);
+        }
+        if self
+            .slow_query_threshold
+            .is_some_and(|threshold| latency >= threshold)
+        {
+            warn!("Slow query ({latency:?}, {context}): {query}");
+        }
+    }
+
+    /// The percentiles of every pattern, sorted by pattern
+    pub fn summary(&self) -> Vec<QuerySummary> {
+        let histograms = match self.histograms.lock() {
+            Ok(histograms) => histograms.clone(),
+            Err(_) => return Vec::new(),
+        };
+        let mut summary: Vec<QuerySummary> = histograms
+            .into_iter()
+            .map(|(pattern, histogram)| QuerySummary {
+                pattern,
+                count: histogram.count(),
+                p50: histogram.percentile(0.5),
+                p95: histogram.percentile(0.95),
+                p99: histogram.percentile(0.99),
+                max: histogram.max(),
+            })
+            .collect();
+        summary.sort_by(|a, b| a.pattern.cmp(&b.pattern));
+        summary
+    }
+}
+
+/// The user, the database and the application of a session, for the logs
+pub fn session_context(startup: &StartupParameters) -> String {
+    format!(
+        "user={} database={} application_name={}",
+        startup.user().unwrap_or_default(),
+        startup.database().unwrap_or_default(),
+        startup.application_name().unwrap_or_default()
+    )
+}
+
+/// Measures the queries relayed by a proxy, from the Query or the first
+/// Parse to the ReadyForQuery of the backend. Give it every message seen by
+/// the observer of proxy::TcpHandler::relay.
+#[cfg(feature = "proxy")]
+pub struct ProxyLatency {
+    stats: QueryStats,
+    context: String,
+    pending: Option<(String, Instant)>,
+}
+
+#[cfg(feature = "proxy")]
+impl ProxyLatency {
+    pub fn new(stats: QueryStats, context: &str) -> Self {
+        Self {
+            stats,
+            context: String::from(context),
+            pending: None,
+        }
+    }
+
+    pub fn observe(&mut self, message: &ProxiedMessage) {
+        match message {
+            ProxiedMessage::Frontend(message) if self.pending.is_none() => {
+                let mut body = message.raw_body.clone();
+                let query = match message.get_message_kind() {
+                    Some(FrontendMessageKind::Query) => Query::deserialize(&mut body)
+                        .map(|query| query.query.to_string_lossy().into_owned()),
+                    Some(FrontendMessageKind::Parse) => Parse::deserialize(&mut body)
+                        .map(|parse| parse.query.to_string_lossy().into_owned()),
+                    _ => return,
+                };
+                match query {
+                    Ok(query) => self.pending = Some((query, Instant::now())),
+                    Err(e) => debug!("Query not measured: {e}"),
+                }
+            }
+            ProxiedMessage::Backend(message)
+                if matches!(
+                    message.get_message_kind(),
+                    Some(BackendMessageKind::ReadyForQuery)
+                ) =>
+            {
+                if let Some((query, start)) = self.pending.take() {
+                    self.stats.record(&query, start.elapsed(), &self.context);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn query_stats() -> anyhow::Result<()> {
+        let mut histogram = LatencyHistogram::new();
+        for millis in 1..=100 {
+            histogram.record(Duration::from_millis(millis));
+        }
+        assert_eq!(100, histogram.count());
+        // 50ms is in the bucket up to 2^16µs
+        assert_eq!(Duration::from_micros(65536), histogram.percentile(0.5));
+        assert_eq!(Duration::from_millis(100), histogram.percentile(0.99));
+
+        let stats = QueryStats::new(Some(Duration::from_millis(10)));
+        stats.record("SELECT 1", Duration::from_millis(1), "");
+        stats.record("select  1", Duration::from_millis(20), "");
+        stats.record("SELECT 2", Duration::from_millis(1), "");
+        let summary = stats.summary();
+        assert_eq!(2, summary.len());
+        assert_eq!(2, summary[0].count);
+        assert_eq!(Duration::from_millis(20), summary[0].max);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,797 @@
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::*;
+
+use crate::access::AccessList;
+use crate::config::ServerConfig;
+#[cfg(feature = "hot-reload")]
+use crate::drivers::Driver;
+use crate::error::PgError;
+use crate::events::{EventBus, ServerEvent};
+use crate::executor::ScriptedExecutor;
+use crate::functions;
+use crate::handler::actor::{Pump, SessionActor};
+use crate::handler::large_object::LargeObjects;
+use crate::handler::server::{PreparedTransactions, TcpHandler};
+use crate::proxy_protocol::ProxyHeader;
+use crate::rate_limiter::RateLimiter;
+use crate::scenario::Scenario;
+use crate::sessions::{SessionRegistry, VirtualSchema};
+use crate::simulation::Clock;
+use crate::stats::QueryStats;
+
+/// A server answering with the rules of a scenario, it accepts the
+/// connections on a background thread and runs one thread per session.
+///
+/// The scenario can be replaced while the server runs, the sessions use the
+/// new one from their next query.
+pub struct TestServer {
+    address: SocketAddr,
+    executor: Arc<RwLock<ScriptedExecutor>>,
+    clock: Clock,
+    stats: QueryStats,
+    sessions: SessionRegistry,
+    events: EventBus,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    #[cfg(feature = "hot-reload")]
+    watcher: Option<notify::RecommendedWatcher>,
+    // Their rules follow the ones of the scenario reloaded
+    #[cfg(feature = "hot-reload")]
+    drivers: Vec<Driver>,
+}
+
+impl TestServer {
+    /// Listen on `listen`, e.g. "127.0.0.1:0" for a free port
+    pub fn start(listen: &str, scenario: &Scenario) -> anyhow::Result<Self> {
+        Self::from_listener(TcpListener::bind(listen)?, scenario)
+    }
+
+    /// Accept the connections of a socket already bound, e.g. by systemd
+    pub fn from_listener(listener: TcpListener, scenario: &Scenario) -> anyhow::Result<Self> {
+        Self::spawn(listener, scenario, ServerConfig::default(), Clock::Real)
+    }
+
+    /// Accept the connections with the authentication, the sessions and the
+    /// scenarios of a configuration, its listeners are bound by the caller
+    pub fn from_config(listener: TcpListener, config: &ServerConfig) -> anyhow::Result<Self> {
+        Self::with_clock(listener, config, Clock::Real)
+    }
+
+    /// Like from_config(), with the time of the steps sleep_ms, of the rate
+    /// limits and of the sessions given by `clock`, e.g. Clock::manual()
+    pub fn with_clock(
+        listener: TcpListener,
+        config: &ServerConfig,
+        clock: Clock,
+    ) -> anyhow::Result<Self> {
+        if config.tls.is_some() {
+            return Err(anyhow!("tls: TLS is not supported yet"));
+        }
+        Self::spawn(listener, &config.scenario()?, config.clone(), clock)
+    }
+
+    fn spawn(
+        listener: TcpListener,
+        scenario: &Scenario,
+        config: ServerConfig,
+        clock: Clock,
+    ) -> anyhow::Result<Self> {
+        let address = listener.local_addr()?;
+        #[cfg(feature = "hot-reload")]
+        let drivers = config.drivers.clone();
+        let mut executor = scenario.executor()?;
+        executor.clock = clock.clone();
+        let executor = Arc::new(RwLock::new(executor));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stats = QueryStats::new(config.logging.slow_query_ms.map(Duration::from_millis));
+        let mut sessions = SessionRegistry::new();
+        sessions.clock = clock.clone();
+        let events = EventBus::new();
+        let access = config.access.access_list()?;
+        let mut limiter = config.connections.rate_limiter();
+        limiter.clock = clock.clone();
+        let schema = VirtualSchema {
+            sessions: sessions.clone(),
+            stats: stats.clone(),
+            config: Arc::new(config),
+            prepared_transactions: PreparedTransactions::new(),
+            large_objects: LargeObjects::new(),
+            backend_pid: None,
+        };
+
+        let thread = {
+            let executor = executor.clone();
+            let events = events.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                // The sockets of the connections by id, to close them at the
+                // end, each one is removed once its thread ends
+                let open: Arc<Mutex<HashMap<u64, TcpStream>>> = Arc::default();
+                let mut threads: Vec<JoinHandle<()>> = Vec::new();
+                for (id, stream) in (0..).zip(listener.incoming()) {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream.and_then(|stream| Ok((stream.try_clone()?, stream))) {
+                        Ok((control, stream)) => {
+                            threads.retain(|thread| !thread.is_finished());
+                            open.lock().expect("connections lock").insert(id, control);
+                            let executor = executor.clone();
+                            let (schema, events) = (schema.clone(), events.clone());
+                            let (access, limiter) = (access.clone(), limiter.clone());
+                            let (stopped, open) = (stopped.clone(), open.clone());
+                            threads.push(thread::spawn(move || {
+                                match accept(stream, &access, &limiter, &executor, &schema, &events)
+                                {
+                                    Ok(()) => {}
+                                    Err(e) if stopped.load(Ordering::SeqCst) => {
+                                        debug!("Session ended by the shutdown: {e}");
+                                    }
+                                    Err(e) => error!("Session failed: {e}"),
+                                }
+                                open.lock().expect("connections lock").remove(&id);
+                            }));
+                        }
+                        Err(e) => error!("error: {e}"),
+                    }
+                }
+                // The threads blocked reading their socket see the end of
+                // the stream
+                for stream in open.lock().expect("connections lock").values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                for thread in threads {
+                    if thread.join().is_err() {
+                        error!("A session thread panicked");
+                    }
+                }
+            })
+        };
+        info!("Listening on {address}");
+
+        Ok(Self {
+            address,
+            executor,
+            clock,
+            stats,
+            sessions,
+            events,
+            stopped,
+            thread: Some(thread),
+            #[cfg(feature = "hot-reload")]
+            watcher: None,
+            #[cfg(feature = "hot-reload")]
+            drivers,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The latency of the queries of all the sessions
+    pub fn stats(&self) -> &QueryStats {
+        &self.stats
+    }
+
+    /// The sessions connected
+    pub fn sessions(&self) -> &SessionRegistry {
+        &self.sessions
+    }
+
+    /// Receive the events of the server from now on, each call gives a new
+    /// subscriber, see events::wait_for()
+    pub fn events(&self) -> Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn set_scenario(&self, scenario: &Scenario) -> anyhow::Result<()> {
+        let mut executor = scenario.executor()?;
+        executor.clock = self.clock.clone();
+        *self.executor.write().expect("executor lock") = executor;
+        Ok(())
+    }
+
+    /// Load the scenario file again each time it changes, the scenario is
+    /// kept when the file is invalid. The rules of the drivers of the
+    /// configuration are kept after the ones of the file.
+    #[cfg(feature = "hot-reload")]
+    pub fn watch_scenario(&mut self, path: &std::path::Path) -> anyhow::Result<()> {
+        use notify::{EventKind, RecursiveMode, Watcher};
+
+        let path = path.canonicalize()?;
+        let executor = self.executor.clone();
+        let clock = self.clock.clone();
+        let watched = path.clone();
+        let drivers = self.drivers.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => return error!("Watching {}: {e}", watched.display()),
+                };
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                    || !event.paths.contains(&watched)
+                {
+                    return;
+                }
+                let reloaded = Scenario::load(&watched).and_then(|mut scenario| {
+                    for driver in &drivers {
+                        scenario.rules.extend(driver.scenario()?.rules);
+                    }
+                    scenario.executor()
+                });
+                match reloaded {
+                    Ok(mut reloaded) => {
+                        reloaded.clock = clock.clone();
+                        *executor.write().expect("executor lock") = reloaded;
+                        info!("Reloaded {}", watched.display());
+                    }
+                    Err(e) => error!("Reloading {}: {e}", watched.display()),
+                }
+            })?;
+        // The editors often replace the file instead of writing it, the
+        // directory is watched to see the new file
+        let directory = path
+            .parent()
+            .ok_or(anyhow!("No directory for {}", path.display()))?;
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+        self.watcher = Some(watcher);
+        Ok(())
+    }
+
+    /// Stop accepting connections and close the connections of the
+    /// sessions, once their threads have ended. A session running a step
+    /// sleep_ms ends after the step.
+    pub fn stop(mut self) -> anyhow::Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        if let Some(thread) = self.thread.take() {
+            self.stopped.store(true, Ordering::SeqCst);
+            // Wake up the accept loop
+            let _ = TcpStream::connect(self.address);
+            thread
+                .join()
+                .map_err(|_| anyhow!("The accept thread panicked"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!("{e}");
+        }
+    }
+}
+
+/// The first file descriptor passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// The listening sockets passed with socket activation, see sd_listen_fds(3):
+/// LISTEN_FDS sockets from the file descriptor 3 when LISTEN_PID is this
+/// process
+#[cfg(unix)]
+pub fn listen_fds() -> anyhow::Result<Vec<TcpListener>> {
+    let fds = listen_fds_range(&|name| std::env::var(name).ok(), std::process::id())?;
+    fds.map(|fd| unsafe { listener_from_fd(fd) }).collect()
+}
+
+#[cfg(unix)]
+fn listen_fds_range(
+    var: &dyn Fn(&str) -> Option<String>,
+    pid: u32,
+) -> anyhow::Result<std::ops::Range<i32>> {
+    match (var("LISTEN_PID"), var("LISTEN_FDS")) {
+        (Some(listen_pid), Some(count)) if listen_pid.parse::<u32>()? == pid => {
+            Ok(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count.parse::<i32>()?)
+        }
+        // Not for this process or no socket activation
+        _ => Ok(0..0),
+    }
+}
+
+/// A listening socket inherited from the parent process
+///
+/// # Safety
+/// `fd` is an open socket that nothing else owns
+#[cfg(unix)]
+pub unsafe fn listener_from_fd(fd: i32) -> anyhow::Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    if fd < 0 {
+        return Err(anyhow!("Invalid file descriptor: {fd}"));
+    }
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // Fails when the file descriptor is not a socket
+    listener.local_addr()?;
+    Ok(listener)
+}
+
+/// Check a new connection against the access list and the rate limits
+/// before running its session, with the address of the client given by the
+/// load balancer when there is one
+fn accept(
+    stream: TcpStream,
+    access: &AccessList,
+    limiter: &RateLimiter,
+    executor: &Arc<RwLock<ScriptedExecutor>>,
+    schema: &VirtualSchema,
+    events: &EventBus,
+) -> anyhow::Result<()> {
+    let mut client = stream.peer_addr()?;
+    if schema.config.connections.proxy_protocol {
+        stream.set_read_timeout(Some(schema.config.connections.startup_limits().timeout))?;
+        match ProxyHeader::read(&mut &stream) {
+            // The connections of the load balancer itself keep its address
+            Ok(header) => client = header.source.unwrap_or(client),
+            Err(e) => {
+                warn!("Closing the connection of {client}: {e}");
+                return Ok(());
+            }
+        }
+        stream.set_read_timeout(None)?;
+    }
+    if let Err(e) = access.check(&client.ip()) {
+        if schema.config.access.error_response {
+            return deny(stream, &e);
+        }
+        warn!("Connection refused: {e}");
+        return Ok(());
+    }
+    match limiter.admit(client.ip()) {
+        Ok(_permit) => session(stream, client, executor, schema, events),
+        Err(e) => reject(stream, &e, schema),
+    }
+}
+
+/// Send an error before the frontend sends anything, the connection is only
+/// closed once the frontend has read it or after a second
+fn deny(stream: TcpStream, e: &PgError) -> anyhow::Result<()> {
+    TcpHandler::new(stream.try_clone()?)?.refuse(e)?;
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    // Closing with unread bytes could reset the connection before the
+    // frontend reads the error
+    let _ = io::copy(&mut &stream, &mut io::sink());
+    Ok(())
+}
+
+/// Answer the startup message with an error and close the connection
+fn reject(stream: TcpStream, e: &PgError, schema: &VirtualSchema) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(schema.config.connections.startup_limits().timeout))?;
+    TcpHandler::new(stream)?.reject(e)
+}
+
+fn session(
+    stream: TcpStream,
+    client: SocketAddr,
+    executor: &Arc<RwLock<ScriptedExecutor>>,
+    schema: &VirtualSchema,
+    events: &EventBus,
+) -> anyhow::Result<()> {
+    let registered = schema.sessions.register(Some(&stream))?;
+    registered.update(|session| session.client_addr = Some(client));
+    let pid = registered.pid();
+    events.publish(ServerEvent::Connected { pid, client });
+    let schema = &VirtualSchema {
+        backend_pid: Some(pid),
+        ..schema.clone()
+    };
+    let (mut actor, inbox, outbox) = SessionActor::with_cancel_flag(registered.cancel_flag());
+    let pump = Pump::with_startup_limits(
+        &stream,
+        inbox,
+        outbox,
+        schema.config.connections.startup_limits(),
+        actor.authenticated(),
+    )?;
+    let handler = &mut actor.handler;
+    schema.config.configure(handler)?;
+    handler.stats = Some(schema.stats.clone());
+    handler.session.prepared_transactions = schema.prepared_transactions.clone();
+    handler.session.large_objects.objects = schema.large_objects.clone();
+    handler.server_version = executor.read().expect("executor lock").version.clone();
+    handler.clock = schema.sessions.clock.clone();
+    // A CancelRequest sets the same flag as pg_cancel_backend()
+    handler.backend_key = Some((pid, registered.secret_key()));
+    let sessions = schema.sessions.clone();
+    handler.cancel_request = Some(Box::new(move |pid, secret_key| {
+        sessions.cancel_request(pid, secret_key);
+    }));
+    // The columns of the rules, without running their steps
+    let rules = executor.clone();
+    handler.describe_statement = Some(Box::new(move |query| {
+        let executor = rules.read().expect("executor lock");
+        let rule = executor.find(query)?;
+        Some(rule.result.columns.clone())
+    }));
+    // The views and the catalogs compared to the parameters of a portal
+    let bound_schema = schema.clone();
+    handler.answer_portal = Some(Box::new(move |query, parameters| {
+        bound_schema.answer_bound(query, parameters)
+    }));
+    let notices = actor.handler.notices.clone();
+
+    let result = actor.run(
+        &|startup| {
+            registered.update(|session| session.startup = startup.clone());
+            schema.config.authentication(startup)
+        },
+        &|query| {
+            registered.update(|session| {
+                session.queries += 1;
+                session.last_query = query.clone();
+            });
+            events.publish(ServerEvent::Query {
+                pid,
+                query: query.clone(),
+            });
+            if let Some(result) = schema.answer(&query) {
+                return result;
+            }
+            // The steps of a rule can wait, the lock is not held meanwhile
+            let mut executor = executor.read().expect("executor lock").clone();
+            executor.notices = notices.clone();
+            if executor.find(&query).is_none()
+                && let Some(info) = registered.info()
+            {
+                match functions::call(&query, &info, &schema.sessions.clock, &executor.version) {
+                    Some(Ok(result)) => return result,
+                    Some(Err(e)) => error!("{query}: {e}"),
+                    None => (),
+                }
+            }
+            registered.update(|session| session.active = true);
+            let result = executor.execute(query.clone());
+            registered.update(|session| session.active = false);
+            if let Some(error) = &result.error {
+                events.publish(ServerEvent::Error {
+                    pid,
+                    query,
+                    error: error.clone(),
+                });
+            }
+            result
+        },
+    );
+    events.publish(ServerEvent::Disconnected {
+        pid,
+        error: result.as_ref().err().map(|e| e.to_string()),
+    });
+    drop(actor);
+    pump.close()?;
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handler::client;
+
+    #[test]
+    fn test_server() -> anyhow::Result<()> {
+        let server = TestServer::start(
+            "127.0.0.1:0",
+            &Scenario::from_toml(
+                r#"
+                [[rules]]
+                query = "SELECT n FROM t"
+                command_tag = "SELECT 1"
+                columns = [{ name = "n", type_oid = 23 }]
+                rows = [["1"]]
+
+                [version]
+                server_version = "16.2"
+                "#,
+            )?,
+        )?;
+
+        let mut handler = client::TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+        assert_eq!(Some("16.2"), handler.parameter("server_version"));
+        assert_eq!(1, handler.simple_query_handler("SELECT n FROM t")?.len());
+
+        assert_eq!(1, server.stats().summary()[0].count);
+        let sessions = handler.simple_query_handler("SELECT * FROM fakepostmaster.sessions")?;
+        assert_eq!(1, sessions.len());
+        let functions = handler.simple_query_handler("SELECT version(), pg_backend_pid()")?;
+        assert_eq!(1, functions.len());
+
+        server.set_scenario(&Scenario::default())?;
+        assert!(handler.simple_query_handler("SELECT n FROM t")?.is_empty());
+
+        server.stop()
+    }
+
+    #[test]
+    fn large_objects() -> anyhow::Result<()> {
+        use crate::handler::large_object::{FUNCTIONS, INV_READ, INV_WRITE};
+
+        let server = TestServer::start(
+            "127.0.0.1:0",
+            &Scenario::from_toml(
+                r#"
+                [[rules]]
+                query = "BEGIN"
+                command_tag = "BEGIN"
+
+                [[rules]]
+                query = "COMMIT"
+                command_tag = "COMMIT"
+                "#,
+            )?,
+        )?;
+        let mut handler = client::TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+        let functions = handler.simple_query_handler(
+            "select proname, oid from pg_catalog.pg_proc where proname in ('lo_open', 'lo_creat', 'loread', 'lowrite')",
+        )?;
+        assert_eq!(4, functions.len());
+        let oid = |name: &str| {
+            FUNCTIONS
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map_or(0, |f| f.1)
+        };
+        let int = |value: i32| value.to_be_bytes().to_vec().into();
+
+        handler.simple_query_handler("BEGIN")?;
+        let created = handler.function_call(oid("lo_creat"), vec![int(INV_WRITE)])?;
+        let fd = handler.function_call(oid("lo_open"), vec![created, int(INV_READ | INV_WRITE)])?;
+        handler.function_call(oid("lowrite"), vec![fd.clone(), b"blob".to_vec().into()])?;
+        handler.function_call(oid("lo_lseek"), vec![fd.clone(), int(0), int(0)])?;
+        let read = handler.function_call(oid("loread"), vec![fd.clone(), int(10)])?;
+        assert_eq!(Some(&b"blob"[..]), read.as_bytes());
+        handler.simple_query_handler("COMMIT")?;
+        // The descriptors are closed with the transaction
+        assert!(
+            handler
+                .function_call(oid("loread"), vec![fd, int(10)])
+                .is_err()
+        );
+
+        server.stop()
+    }
+
+    #[test]
+    fn server_events() -> anyhow::Result<()> {
+        use crate::events::{self, ServerEvent};
+        use crate::matcher::QueryMatcher;
+
+        let server = TestServer::start(
+            "127.0.0.1:0",
+            &Scenario::from_toml(
+                r#"
+                [[rules]]
+                query = "UPDATE t SET n = 1"
+                error = { code = "40P01", message = "deadlock detected" }
+                "#,
+            )?,
+        )?;
+        let events = server.events();
+        let timeout = Duration::from_secs(5);
+
+        let mut handler = client::TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+        handler.simple_query_handler("SELECT 1")?;
+        assert!(handler.simple_query_handler("UPDATE t SET n = 1").is_err());
+        drop(handler);
+
+        let pid = events::wait_for(&events, timeout, |event| {
+            matches!(event, ServerEvent::Connected { .. })
+        })?
+        .pid();
+        events::wait_for_query(&events, &QueryMatcher::normalized("select $1")?, timeout)?;
+        let error = events::wait_for(&events, timeout, |event| {
+            matches!(event, ServerEvent::Error { .. })
+        })?;
+        assert!(matches!(error, ServerEvent::Error { error, .. } if error.code == "40P01"));
+        let disconnected = events::wait_for(&events, timeout, |event| {
+            matches!(event, ServerEvent::Disconnected { .. })
+        })?;
+        assert_eq!(pid, disconnected.pid());
+
+        server.stop()?;
+        assert!(events::wait_for(&events, timeout, |_| true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown() -> anyhow::Result<()> {
+        use std::io::Read;
+
+        let server = TestServer::start("127.0.0.1:0", &Scenario::default())?;
+        let mut handler = client::TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+        // Waiting for the startup packet
+        let mut silent = TcpStream::connect(server.address())?;
+        while server.sessions().sessions().len() < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        server.stop()?;
+        assert!(handler.simple_query_handler("SELECT 1").is_err());
+        assert_eq!(0, silent.read(&mut [0; 1])?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_request() -> anyhow::Result<()> {
+        use std::io::{Read, Write};
+
+        let server = TestServer::start(
+            "127.0.0.1:0",
+            &Scenario::from_toml(
+                r#"
+                [[rules]]
+                query = "SELECT pg_sleep(1)"
+                command_tag = "SELECT 1"
+                steps = [{ sleep_ms = 500 }]
+                "#,
+            )?,
+        )?;
+        let mut handler = client::TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+        let (pid, secret_key) = handler.backend_key.ok_or(anyhow!("No BackendKeyData"))?;
+        let query = thread::spawn(move || handler.simple_query_handler("SELECT pg_sleep(1)"));
+        while !server
+            .sessions()
+            .sessions()
+            .iter()
+            .any(|session| session.active)
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Like PostgreSQL, the connection of the request gets no answer
+        let mut stream = TcpStream::connect(server.address())?;
+        let request = [16, 80877102, pid, secret_key].map(i32::to_be_bytes);
+        stream.write_all(&request.concat())?;
+        assert_eq!(0, stream.read(&mut [0; 1])?);
+        let error = query.join().expect("query thread").unwrap_err();
+        assert!(error.to_string().contains("57014"), "{error}");
+
+        server.stop()
+    }
+
+    #[test]
+    fn startup_limits() -> anyhow::Result<()> {
+        use std::io::{Read, Write};
+
+        let config = ServerConfig::from_toml(
+            "[connections]\nauthentication_timeout_ms = 100\nmax_startup_bytes = 8\n",
+        )?;
+        let server = TestServer::from_config(TcpListener::bind("127.0.0.1:0")?, &config)?;
+        let closed = |bytes: &[u8]| -> anyhow::Result<bool> {
+            let mut stream = TcpStream::connect(server.address())?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            stream.write_all(bytes)?;
+            // Closed without any message
+            Ok(stream.read(&mut [0; 1])? == 0)
+        };
+        // Nothing sent
+        assert!(closed(b"")?);
+        // The beginning of a startup packet longer than allowed
+        assert!(closed(&[0, 0, 0, 100, 0, 3, 0, 0, b'u'])?);
+
+        server.stop()
+    }
+
+    #[test]
+    fn proxy_protocol() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let config = ServerConfig::from_toml(
+            "[access]\ndeny = [\"192.0.2.0/24\"]\n[connections]\nproxy_protocol = true\n",
+        )?;
+        let server = TestServer::from_config(TcpListener::bind("127.0.0.1:0")?, &config)?;
+        let connect = |source: &str| -> anyhow::Result<client::TcpHandler> {
+            let mut stream = TcpStream::connect(server.address())?;
+            let header = ProxyHeader::new(source.parse()?, server.address());
+            stream.write_all(&header.to_bytes())?;
+            client::TcpHandler::new(stream)
+        };
+
+        let mut handler = connect("203.0.113.7:4242")?;
+        handler.md5_authentication_handler()?;
+        assert_eq!(
+            vec![Some("203.0.113.7:4242".parse()?)],
+            server
+                .sessions()
+                .sessions()
+                .iter()
+                .map(|session| session.client_addr)
+                .collect::<Vec<_>>()
+        );
+        // The access list applies to the address of the client
+        assert!(
+            connect("192.0.2.1:4242")?
+                .md5_authentication_handler()
+                .is_err()
+        );
+
+        server.stop()
+    }
+
+    #[test]
+    #[cfg(feature = "hot-reload")]
+    fn watch_scenario() -> anyhow::Result<()> {
+        use std::time::Instant;
+
+        let rule = |tag: &str| {
+            format!("[[rules]]\nquery = \"SELECT n FROM t\"\ncommand_tag = \"{tag}\"\n")
+        };
+        let directory = std::env::temp_dir().join(format!("watch_scenario_{}", std::process::id()));
+        std::fs::create_dir_all(&directory)?;
+        let path = directory.join("scenario.toml");
+        std::fs::write(&path, rule("BEFORE"))?;
+
+        let config = ServerConfig {
+            scenarios: vec![path.clone()],
+            drivers: vec![Driver::Psycopg],
+            ..ServerConfig::default()
+        };
+        let mut server = TestServer::from_config(TcpListener::bind("127.0.0.1:0")?, &config)?;
+        server.watch_scenario(&path)?;
+        let tag_of = |server: &TestServer, query: &str| {
+            let executor = server.executor.read().expect("executor lock");
+            executor.execute(String::from(query)).command_tag
+        };
+        let tag = |server: &TestServer| tag_of(server, "SELECT n FROM t");
+        assert_eq!("BEFORE", tag(&server));
+
+        // The invalid version is skipped
+        std::fs::write(&path, "[[")?;
+        std::fs::write(&path, rule("AFTER"))?;
+        let start = Instant::now();
+        while tag(&server) != "AFTER" && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!("AFTER", tag(&server));
+        // The rules of the drivers are still there
+        assert_eq!(
+            "SHOW",
+            tag_of(&server, "SHOW default_transaction_isolation")
+        );
+
+        std::fs::remove_dir_all(&directory)?;
+        server.stop()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn inherited_listener() -> anyhow::Result<()> {
+        use std::os::fd::IntoRawFd;
+
+        let var = |name: &str| match name {
+            "LISTEN_PID" => Some(String::from("42")),
+            "LISTEN_FDS" => Some(String::from("2")),
+            _ => None,
+        };
+        assert_eq!(3..5, listen_fds_range(&var, 42)?);
+        assert!(listen_fds_range(&var, 43)?.is_empty());
+
+        let fd = TcpListener::bind("127.0.0.1:0")?.into_raw_fd();
+        let server =
+            TestServer::from_listener(unsafe { listener_from_fd(fd) }?, &Scenario::default())?;
+        let mut handler = client::TcpHandler::new(TcpStream::connect(server.address())?)?;
+        handler.md5_authentication_handler()?;
+
+        server.stop()
+    }
+}
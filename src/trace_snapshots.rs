@@ -0,0 +1,170 @@
+//! Snapshots of the Debug output and of the trace (the annotated hexdump of
+//! the "rcv:" logs) of every message, so that a change of their fields or
+//! of their format shows up in the review of the snapshots in snapshots/.
+//! The requests without a message type (StartupMessage, SSLRequest...) are
+//! not traced by type and are left out.
+
+use bytes::BytesMut;
+use std::fmt::Debug;
+use std::io::BufReader;
+
+use libpq_serde_types::{ByteSized, Serialize};
+
+use crate::message::*;
+use crate::value::{OutputSettings, PgValue};
+
+/// The Debug output of the message, then its trace as received by the
+/// frontend or by the backend
+fn render<T>(message: &T, backend: bool) -> anyhow::Result<String>
+where
+    T: MessageBody + Serialize + ByteSized + Debug,
+{
+    let mut buffer = BytesMut::new();
+    MessageHeader::serialize_message(&mut buffer, message)?;
+    let mut reader = BufReader::new(&buffer[..]);
+    let trace = match backend {
+        true => RawBackendMessage::get(&mut reader)?.to_string(),
+        false => RawFrontendMessage::get(&mut reader)?.to_string(),
+    };
+    Ok(format!("{message:#?}\n\n{trace}"))
+}
+
+// The snapshot is named after the sender and the type of the message
+macro_rules! assert_trace_snapshot {
+    (backend, $message:expr) => {
+        assert_trace_snapshot!("backend", true, $message)
+    };
+    (frontend, $message:expr) => {
+        assert_trace_snapshot!("frontend", false, $message)
+    };
+    ($sender:literal, $backend:literal, $message:expr) => {{
+        let message = $message;
+        let type_name = std::any::type_name_of_val(&message);
+        let name = type_name.rsplit("::").next().unwrap_or(type_name);
+        insta::assert_snapshot!(format!("{}_{name}", $sender), render(&message, $backend)?);
+    }};
+}
+
+#[test]
+fn backend_messages() -> anyhow::Result<()> {
+    assert_trace_snapshot!(backend, AuthenticationOk::new());
+    assert_trace_snapshot!(backend, AuthenticationMD5Password::new([1, 2, 3, 4]));
+    assert_trace_snapshot!(backend, AuthenticationGSS::new());
+    assert_trace_snapshot!(backend, AuthenticationGSSContinue::new(b"token".to_vec()));
+    assert_trace_snapshot!(backend, AuthenticationSSPI::new());
+    assert_trace_snapshot!(backend, AuthenticationSASL::new(&["SCRAM-SHA-256"])?);
+    assert_trace_snapshot!(
+        backend,
+        AuthenticationSASLContinue::new(b"r=abc,s=QUJD,i=4096".to_vec())
+    );
+    assert_trace_snapshot!(
+        backend,
+        AuthenticationSASLFinal::new(b"v=c2lnbmF0dXJl".to_vec())
+    );
+    assert_trace_snapshot!(backend, BackendKeyData::new(42, 1234));
+    assert_trace_snapshot!(backend, BindComplete::new());
+    assert_trace_snapshot!(backend, CloseComplete::new());
+    assert_trace_snapshot!(backend, CommandComplete::new(String::from("SELECT 1"))?);
+    assert_trace_snapshot!(backend, CopyData::new(b"1\ta\n"));
+    assert_trace_snapshot!(backend, CopyDone::new());
+    assert_trace_snapshot!(backend, CopyInResponse::new(2));
+    assert_trace_snapshot!(backend, CopyOutResponse::new(2));
+    assert_trace_snapshot!(
+        backend,
+        DataRow::new_from_values(
+            &[
+                PgValue::Int4(1),
+                PgValue::Text(String::from("a")),
+                PgValue::Null
+            ],
+            &[],
+            &OutputSettings::default()
+        )?
+    );
+    assert_trace_snapshot!(
+        backend,
+        ErrorResponse::new(vec![
+            ErrorMessage::new('S', "ERROR")?,
+            ErrorMessage::new('C', "42P01")?,
+            ErrorMessage::new('M', "relation \"t\" does not exist")?,
+        ])
+    );
+    assert_trace_snapshot!(backend, FunctionCallResponse::new(vec![0, 0, 0, 1]));
+    assert_trace_snapshot!(
+        backend,
+        NegotiateProtocolVersion::new(0, &["_pq_.compression"])?
+    );
+    assert_trace_snapshot!(backend, NoData::new());
+    assert_trace_snapshot!(
+        backend,
+        NoticeResponse::new(vec![
+            ErrorMessage::new('S', "NOTICE")?,
+            ErrorMessage::new('M', "hi")?,
+        ])
+    );
+    assert_trace_snapshot!(backend, NotificationResponse::new(42, "jobs", "new")?);
+    assert_trace_snapshot!(backend, ParameterDescription::new(vec![23, 25]));
+    assert_trace_snapshot!(backend, ParameterStatus::new("TimeZone", "UTC")?);
+    assert_trace_snapshot!(backend, ParseComplete::new());
+    assert_trace_snapshot!(backend, PortalSuspended::new());
+    assert_trace_snapshot!(
+        backend,
+        ReadyForQuery::new(TransactionIndicator::IdleInTransaction)
+    );
+    assert_trace_snapshot!(
+        backend,
+        RowDescription::new(vec![
+            ColumnDescription::new("n", PgType::Int4)?,
+            ColumnDescription::new("s", PgType::Text)?,
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn frontend_messages() -> anyhow::Result<()> {
+    assert_trace_snapshot!(
+        frontend,
+        Bind::new(
+            "portal",
+            "statement",
+            vec![FormatCode::Text],
+            vec![ColumnData::from(b"1".to_vec())],
+            vec![FormatCode::Binary],
+        )?
+    );
+    assert_trace_snapshot!(frontend, Close::new(DescribeTarget::Portal, "portal")?);
+    assert_trace_snapshot!(frontend, CopyData::new(b"1\ta\n"));
+    assert_trace_snapshot!(frontend, CopyDone::new());
+    assert_trace_snapshot!(frontend, CopyFail::new("canceled by the user")?);
+    assert_trace_snapshot!(
+        frontend,
+        Describe::new(DescribeTarget::Statement, "statement")?
+    );
+    assert_trace_snapshot!(frontend, Execute::new("portal", 10)?);
+    assert_trace_snapshot!(frontend, Flush::new());
+    assert_trace_snapshot!(
+        frontend,
+        FunctionCall::new(764, vec![ColumnData::from(vec![0, 0, 0, 1])])
+    );
+    assert_trace_snapshot!(frontend, GSSResponse::new(b"token".to_vec()));
+    assert_trace_snapshot!(
+        frontend,
+        Parse::new("statement", "SELECT n FROM t WHERE n = $1", vec![23])?
+    );
+    assert_trace_snapshot!(frontend, PasswordMessage::new("secret")?);
+    assert_trace_snapshot!(frontend, Query::new(String::from("SELECT 1"))?);
+    assert_trace_snapshot!(
+        frontend,
+        SASLInitialResponse::new("SCRAM-SHA-256", b"n,,n=,r=abc".to_vec())?
+    );
+    assert_trace_snapshot!(
+        frontend,
+        SASLResponse::new(b"c=biws,r=abc,p=cHJvb2Y=".to_vec())
+    );
+    assert_trace_snapshot!(frontend, Sync::new());
+    assert_trace_snapshot!(frontend, Terminate::new());
+
+    Ok(())
+}
@@ -0,0 +1,333 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::message::BackendMessageKind;
+use crate::protocol::{self, Answer, AnswerEffect, BackendEffect, FrontendEffect, Phase};
+
+// The message flow is documented here:
+// * https://www.postgresql.org/docs/17/protocol-flow.html
+// and its rules are in the tables of the protocol module.
+
+/// Number of messages kept to give some context when a violation is reported
+const HISTORY_SIZE: usize = 10;
+
+/// The side that sent a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Frontend,
+    Backend,
+}
+
+/// A message that doesn't fit the protocol flow
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub reason: String,
+    // The last messages seen, including the faulty one
+    pub history: Vec<(Direction, u8)>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "protocol violation: {} (last messages:", self.reason)?;
+        for (direction, message_type) in &self.history {
+            let direction = match direction {
+                Direction::Frontend => 'F',
+                Direction::Backend => 'B',
+            };
+            write!(f, " {direction}:'{}'", *message_type as char)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Checks that the messages exchanged on a connection follow the protocol
+/// flow, e.g. a DataRow must be preceded by a RowDescription in a simple
+/// query and a ReadyForQuery must answer a Sync or a Query.
+///
+/// Every message must be given to the validator in the order it was sent or
+/// received, starting right after the StartupMessage.
+#[derive(Debug)]
+pub struct Validator {
+    phase: Phase,
+    // Frontend commands waiting for their response, in order
+    pending: VecDeque<Answer>,
+    // After an error in an extended query, the backend ignores every message
+    // until Sync
+    discarding: bool,
+    history: VecDeque<(Direction, u8)>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self {
+            phase: Phase::Startup,
+            pending: VecDeque::new(),
+            discarding: false,
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+        }
+    }
+
+    /// The stage of the connection after the messages seen so far
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Whether a command of the frontend is still waiting for its answer
+    pub fn is_waiting(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn violation(&self, reason: String) -> Violation {
+        Violation {
+            reason,
+            history: self.history.iter().copied().collect(),
+        }
+    }
+
+    fn remember(&mut self, direction: Direction, message_type: u8) {
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back((direction, message_type));
+    }
+
+    /// Check a message sent by the frontend
+    pub fn frontend_message(&mut self, message_type: u8) -> Result<(), Violation> {
+        self.remember(Direction::Frontend, message_type);
+
+        if self.phase == Phase::Terminated {
+            return Err(self.violation(String::from("message sent after Terminate")));
+        }
+        match protocol::frontend_transition(self.phase, message_type) {
+            Some(FrontendEffect::Command(answer)) => {
+                if answer == Answer::Sync {
+                    self.discarding = false;
+                }
+                if !self.discarding {
+                    self.pending.push_back(answer);
+                }
+                Ok(())
+            }
+            Some(FrontendEffect::Nothing) => Ok(()),
+            Some(FrontendEffect::Enter(phase)) => {
+                self.phase = phase;
+                Ok(())
+            }
+            None => {
+                let message_type = message_type as char;
+                Err(self.violation(match self.phase {
+                    Phase::Startup => {
+                        format!("'{message_type}' sent before the end of the startup")
+                    }
+                    Phase::CopyIn => format!("'{message_type}' sent during COPY IN"),
+                    _ if "dcf".contains(message_type) => {
+                        format!("'{message_type}' sent outside of COPY IN")
+                    }
+                    _ => format!("unexpected frontend message '{message_type}'"),
+                }))
+            }
+        }
+    }
+
+    /// Check a message sent by the backend
+    pub fn backend_message(&mut self, message_type: u8) -> Result<(), Violation> {
+        self.remember(Direction::Backend, message_type);
+
+        let kind = BackendMessageKind::try_from(message_type).map_err(|_| {
+            self.violation(format!(
+                "unknown backend message '{}'",
+                message_type as char
+            ))
+        })?;
+
+        // Asynchronous messages can be sent at any time
+        if protocol::is_asynchronous(message_type) {
+            return Ok(());
+        }
+        match (
+            self.phase,
+            protocol::backend_transition(self.phase, message_type),
+        ) {
+            (Phase::Terminated, _) => {
+                return Err(self.violation(format!("{kind:?} sent after Terminate")));
+            }
+            (_, Some(BackendEffect::Stay)) => return Ok(()),
+            (_, Some(BackendEffect::Enter(phase))) => {
+                self.phase = phase;
+                return Ok(());
+            }
+            (_, Some(BackendEffect::Abort)) => self.phase = Phase::Ready,
+            (Phase::Ready, None) => (),
+            (Phase::Startup, None) => {
+                return Err(self.violation(format!("{kind:?} sent during the startup")));
+            }
+            (Phase::CopyIn, None) => {
+                return Err(self.violation(format!("{kind:?} sent during COPY IN")));
+            }
+            (Phase::CopyOut, None) => {
+                return Err(self.violation(format!("{kind:?} sent during COPY OUT")));
+            }
+        }
+
+        let Some(answer) = self.pending.front_mut() else {
+            return Err(self.violation(format!("{kind:?} sent while no command is pending")));
+        };
+        match protocol::answer_transition(*answer, message_type) {
+            Some(AnswerEffect::Next(next)) => *answer = next,
+            Some(AnswerEffect::Done) => {
+                self.pending.pop_front();
+            }
+            Some(AnswerEffect::CopyIn(next)) => {
+                *answer = next;
+                self.phase = Phase::CopyIn;
+            }
+            Some(AnswerEffect::CopyOut(next)) => {
+                *answer = next;
+                self.phase = Phase::CopyOut;
+            }
+            Some(AnswerEffect::Discard) => {
+                // Extended query: everything up to the next Sync is discarded
+                while let Some(pending) = self.pending.front() {
+                    if *pending == Answer::Sync {
+                        break;
+                    }
+                    self.pending.pop_front();
+                }
+                self.discarding = self.pending.is_empty();
+            }
+            Some(AnswerEffect::Illegal(reason)) => {
+                return Err(self.violation(String::from(reason)));
+            }
+            None => {
+                let reason = format!("{kind:?} sent in answer to {answer:?}");
+                return Err(self.violation(reason));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn validator_after_startup() -> Result<Validator, Violation> {
+        let mut validator = Validator::new();
+        validator.backend_message(b'R')?;
+        validator.frontend_message(b'p')?;
+        validator.backend_message(b'R')?;
+        validator.backend_message(b'S')?;
+        validator.backend_message(b'K')?;
+        validator.backend_message(b'Z')?;
+        Ok(validator)
+    }
+
+    #[test]
+    fn simple_query_valid() -> anyhow::Result<()> {
+        let mut validator = validator_after_startup()?;
+        validator.frontend_message(b'Q')?;
+        for message_type in [b'T', b'D', b'D', b'C', b'N', b'T', b'C', b'Z'] {
+            validator.backend_message(message_type)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn simple_query_datarow_before_rowdescription() -> anyhow::Result<()> {
+        let mut validator = validator_after_startup()?;
+        validator.frontend_message(b'Q')?;
+        validator.backend_message(b'T')?;
+        validator.backend_message(b'C')?;
+
+        let violation = validator.backend_message(b'D').unwrap_err();
+        assert_eq!("DataRow sent before RowDescription", violation.reason);
+        assert_eq!(
+            vec![
+                (Direction::Frontend, b'Q'),
+                (Direction::Backend, b'T'),
+                (Direction::Backend, b'C'),
+                (Direction::Backend, b'D'),
+            ],
+            violation.history[violation.history.len() - 4..]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn extended_query_valid() -> anyhow::Result<()> {
+        let mut validator = validator_after_startup()?;
+        for message_type in [b'P', b'B', b'D', b'E', b'S'] {
+            validator.frontend_message(message_type)?;
+        }
+        for message_type in [b'1', b'2', b'T', b'D', b'C', b'Z'] {
+            validator.backend_message(message_type)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn extended_query_readyforquery_without_sync() -> anyhow::Result<()> {
+        let mut validator = validator_after_startup()?;
+        validator.frontend_message(b'P')?;
+        validator.frontend_message(b'H')?;
+        validator.backend_message(b'1')?;
+
+        assert!(validator.backend_message(b'Z').is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn extended_query_error_discards_until_sync() -> anyhow::Result<()> {
+        let mut validator = validator_after_startup()?;
+        validator.frontend_message(b'P')?;
+        validator.frontend_message(b'B')?;
+        validator.backend_message(b'E')?;
+        // ignored by the backend
+        validator.frontend_message(b'E')?;
+        validator.frontend_message(b'S')?;
+
+        assert!(validator.backend_message(b'C').is_err());
+        validator.backend_message(b'Z')?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn startup_unexpected_message() -> anyhow::Result<()> {
+        let mut validator = Validator::new();
+        validator.backend_message(b'R')?;
+
+        assert!(validator.frontend_message(b'Q').is_err());
+        assert!(validator.backend_message(b'D').is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn copy_data_outside_of_copy_in() -> anyhow::Result<()> {
+        let mut validator = validator_after_startup()?;
+        validator.frontend_message(b'Q')?;
+        validator.backend_message(b'G')?;
+        validator.frontend_message(b'd')?;
+        validator.frontend_message(b'c')?;
+        validator.backend_message(b'C')?;
+        validator.backend_message(b'Z')?;
+
+        let violation = validator.frontend_message(b'd').unwrap_err();
+        assert_eq!("'d' sent outside of COPY IN", violation.reason);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,557 @@
+use anyhow::anyhow;
+#[cfg(feature = "json")]
+use serde_json::Value;
+use std::fmt::Write;
+
+use crate::datetime::{self, DateStyle, IntervalStyle};
+use crate::encoding::ClientEncoding;
+use crate::error::PgError;
+#[cfg(feature = "json")]
+use crate::json;
+use crate::message::{ColumnData, FormatCode, PgType};
+use crate::numeric::PgNumeric;
+
+// The only version of the binary format of jsonb
+#[cfg(feature = "json")]
+const JSONB_VERSION: u8 = 1;
+
+/// The text output of bytea values, set with bytea_output
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ByteaOutput {
+    // \x followed by two hex digits per byte
+    #[default]
+    Hex,
+    // The printable ASCII characters as they are, the others in octal
+    Escape,
+}
+
+impl TryFrom<&str> for ByteaOutput {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> anyhow::Result<ByteaOutput> {
+        match &value.to_lowercase()[..] {
+            "hex" => Ok(ByteaOutput::Hex),
+            "escape" => Ok(ByteaOutput::Escape),
+            _ => Err(PgError::new(
+                "22023",
+                &format!("invalid value for parameter \"bytea_output\": \"{value}\""),
+            )
+            .into()),
+        }
+    }
+}
+
+fn format_bytea(data: &[u8], output: ByteaOutput) -> String {
+    match output {
+        ByteaOutput::Hex => data.iter().fold(String::from("\\x"), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        }),
+        ByteaOutput::Escape => data.iter().fold(String::new(), |mut escaped, &byte| {
+            match byte {
+                b'\\' => escaped.push_str("\\\\"),
+                0x20..=0x7e => escaped.push(char::from(byte)),
+                _ => {
+                    let _ = write!(escaped, "\\{byte:03o}");
+                }
+            }
+            escaped
+        }),
+    }
+}
+
+/// Parse both the hex and the escape formats
+fn parse_bytea(text: &str) -> anyhow::Result<Vec<u8>> {
+    if let Some(hex) = text.strip_prefix("\\x") {
+        let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        if !digits.len().is_multiple_of(2) || !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err(anyhow!("Invalid bytea: {text}"));
+        }
+        return digits
+            .chunks(2)
+            .map(|pair| Ok(u8::from_str_radix(str::from_utf8(pair)?, 16)?))
+            .collect();
+    }
+
+    let mut data = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            data.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'\\') => data.push(b'\\'),
+            Some(first @ b'0'..=b'3') => {
+                let octal = [Some(first), bytes.next(), bytes.next()];
+                let octal: Vec<u8> = octal.into_iter().flatten().collect();
+                if octal.len() != 3 {
+                    return Err(anyhow!("Invalid bytea: {text}"));
+                }
+                data.push(u8::from_str_radix(str::from_utf8(&octal)?, 8)?);
+            }
+            _ => return Err(anyhow!("Invalid bytea: {text}")),
+        }
+    }
+    Ok(data)
+}
+
+fn format_uuid(uuid: &[u8; 16]) -> String {
+    uuid.iter()
+        .enumerate()
+        .fold(String::with_capacity(36), |mut text, (index, byte)| {
+            if [4, 6, 8, 10].contains(&index) {
+                text.push('-');
+            }
+            let _ = write!(text, "{byte:02x}");
+            text
+        })
+}
+
+/// The json and jsonb values are only read with the json feature
+#[cfg(not(feature = "json"))]
+fn json_disabled() -> anyhow::Error {
+    anyhow!("The json values need the json feature")
+}
+
+/// Parse a uuid, the braces and the hyphens are optional like in PostgreSQL
+fn parse_uuid(text: &str) -> anyhow::Result<[u8; 16]> {
+    let digits = text
+        .strip_prefix('{')
+        .and_then(|inner| inner.strip_suffix('}'))
+        .unwrap_or(text);
+    let digits: Vec<u8> = digits.bytes().filter(|b| *b != b'-').collect();
+    if digits.len() != 32 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return Err(anyhow!("Invalid uuid: {text}"));
+    }
+    let mut uuid = [0; 16];
+    for (byte, pair) in uuid.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(str::from_utf8(pair)?, 16)?;
+    }
+    Ok(uuid)
+}
+
+/// The session parameters that change how the values are sent
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OutputSettings {
+    pub encoding: ClientEncoding,
+    pub date_style: DateStyle,
+    pub interval_style: IntervalStyle,
+    pub bytea_output: ByteaOutput,
+}
+
+impl OutputSettings {
+    /// Apply a parameter given in the startup message or with SET, the other
+    /// parameters are ignored
+    pub fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        match &name.to_lowercase()[..] {
+            "client_encoding" => self.encoding = ClientEncoding::try_from(value)?,
+            "datestyle" => self.date_style = self.date_style.parse(value)?,
+            "intervalstyle" => self.interval_style = IntervalStyle::try_from(value)?,
+            "bytea_output" => self.bytea_output = ByteaOutput::try_from(value)?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Restore a parameter to its value at the start of the session
+    pub fn reset(&mut self, name: &str, startup: &OutputSettings) {
+        match &name.to_lowercase()[..] {
+            "client_encoding" => self.encoding = startup.encoding,
+            "datestyle" => self.date_style = startup.date_style,
+            "intervalstyle" => self.interval_style = startup.interval_style,
+            "bytea_output" => self.bytea_output = startup.bytea_output,
+            _ => (),
+        }
+    }
+}
+
+/// A value that can be sent to the frontend in a DataRow, in text or in
+/// binary format depending on what the frontend asked for.
+///
+/// The text and binary representations follow the output and send
+/// functions of the corresponding types in PostgreSQL.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PgValue {
+    Bool(bool),
+    Int4(i32),
+    Int8(i64),
+    Text(String),
+    Oid(u32),
+    // Days from 2000-01-01
+    Date(i32),
+    // Microseconds from 2000-01-01 00:00:00, without time zone
+    Timestamp(i64),
+    Interval {
+        months: i32,
+        days: i32,
+        microseconds: i64,
+    },
+    Numeric(PgNumeric),
+    #[cfg(feature = "json")]
+    Json(Value),
+    Uuid([u8; 16]),
+    Bytea(Vec<u8>),
+    // Written with the keys sorted like jsonb_out() does
+    #[cfg(feature = "json")]
+    Jsonb(Value),
+    // Sent with the length -1, in any column
+    Null,
+}
+
+impl PgValue {
+    pub fn pg_type(&self) -> PgType {
+        match self {
+            PgValue::Bool(_) => PgType::Bool,
+            PgValue::Int4(_) => PgType::Int4,
+            PgValue::Int8(_) => PgType::Int8,
+            PgValue::Text(_) => PgType::Text,
+            PgValue::Oid(_) => PgType::Oid,
+            PgValue::Date(_) => PgType::Date,
+            PgValue::Timestamp(_) => PgType::Timestamp,
+            PgValue::Interval { .. } => PgType::Interval,
+            PgValue::Numeric(_) => PgType::Numeric,
+            #[cfg(feature = "json")]
+            PgValue::Json(_) => PgType::Json,
+            #[cfg(feature = "json")]
+            PgValue::Jsonb(_) => PgType::Jsonb,
+            PgValue::Uuid(_) => PgType::Uuid,
+            PgValue::Bytea(_) => PgType::Bytea,
+            // Like an untyped NULL literal
+            PgValue::Null => PgType::Text,
+        }
+    }
+
+    /// The text representation with the default settings (ISO dates and
+    /// postgres intervals), the one parsed by from_text()
+    pub fn to_text(&self) -> Vec<u8> {
+        self.to_text_with(&OutputSettings::default())
+    }
+
+    pub fn to_text_with(&self, settings: &OutputSettings) -> Vec<u8> {
+        match self {
+            PgValue::Bool(true) => b"t".to_vec(),
+            PgValue::Bool(false) => b"f".to_vec(),
+            PgValue::Int4(value) => value.to_string().into_bytes(),
+            PgValue::Int8(value) => value.to_string().into_bytes(),
+            PgValue::Text(value) => value.as_bytes().to_vec(),
+            PgValue::Oid(value) => value.to_string().into_bytes(),
+            PgValue::Date(value) => {
+                datetime::format_date(*value, &settings.date_style).into_bytes()
+            }
+            PgValue::Timestamp(value) => {
+                datetime::format_timestamp(*value, &settings.date_style).into_bytes()
+            }
+            PgValue::Interval {
+                months,
+                days,
+                microseconds,
+            } => datetime::format_interval(*months, *days, *microseconds, settings.interval_style)
+                .into_bytes(),
+            PgValue::Numeric(value) => value.to_string().into_bytes(),
+            #[cfg(feature = "json")]
+            PgValue::Json(value) => value.to_string().into_bytes(),
+            #[cfg(feature = "json")]
+            PgValue::Jsonb(value) => json::jsonb_to_string(value).into_bytes(),
+            PgValue::Uuid(value) => format_uuid(value).into_bytes(),
+            PgValue::Bytea(value) => format_bytea(value, settings.bytea_output).into_bytes(),
+            // Only in the formats writing the values one after the other,
+            // a DataRow sends NULL without a value
+            PgValue::Null => Vec::new(),
+        }
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        match self {
+            PgValue::Bool(value) => vec![*value as u8],
+            PgValue::Int4(value) => value.to_be_bytes().to_vec(),
+            PgValue::Int8(value) => value.to_be_bytes().to_vec(),
+            PgValue::Text(value) => value.as_bytes().to_vec(),
+            PgValue::Oid(value) => value.to_be_bytes().to_vec(),
+            PgValue::Date(value) => value.to_be_bytes().to_vec(),
+            PgValue::Timestamp(value) => value.to_be_bytes().to_vec(),
+            PgValue::Interval {
+                months,
+                days,
+                microseconds,
+            } => [
+                &microseconds.to_be_bytes()[..],
+                &days.to_be_bytes(),
+                &months.to_be_bytes(),
+            ]
+            .concat(),
+            PgValue::Numeric(value) => value.to_binary(),
+            #[cfg(feature = "json")]
+            PgValue::Json(_) => self.to_text(),
+            // The format version, then the text
+            #[cfg(feature = "json")]
+            PgValue::Jsonb(_) => [&[JSONB_VERSION][..], &self.to_text()].concat(),
+            PgValue::Uuid(value) => value.to_vec(),
+            PgValue::Bytea(value) => value.clone(),
+            PgValue::Null => Vec::new(),
+        }
+    }
+
+    /// Parse the text representation of a value of the given type
+    pub fn from_text(pg_type: PgType, text: &str) -> anyhow::Result<PgValue> {
+        Ok(match pg_type {
+            PgType::Bool => match text {
+                "t" => PgValue::Bool(true),
+                "f" => PgValue::Bool(false),
+                _ => return Err(anyhow!("Invalid bool: {text}")),
+            },
+            PgType::Int4 => PgValue::Int4(text.parse()?),
+            PgType::Int8 => PgValue::Int8(text.parse()?),
+            PgType::Text => PgValue::Text(String::from(text)),
+            PgType::Oid => PgValue::Oid(text.parse()?),
+            PgType::Date => PgValue::Date(datetime::parse_date(text)?),
+            PgType::Timestamp => PgValue::Timestamp(datetime::parse_timestamp(text)?),
+            PgType::Interval => {
+                let (months, days, microseconds) = datetime::parse_interval(text)?;
+                PgValue::Interval {
+                    months,
+                    days,
+                    microseconds,
+                }
+            }
+            PgType::Numeric => PgValue::Numeric(text.parse()?),
+            #[cfg(feature = "json")]
+            PgType::Json => PgValue::Json(serde_json::from_str(text)?),
+            #[cfg(feature = "json")]
+            PgType::Jsonb => PgValue::Jsonb(serde_json::from_str(text)?),
+            #[cfg(not(feature = "json"))]
+            PgType::Json | PgType::Jsonb => return Err(json_disabled()),
+            PgType::Uuid => PgValue::Uuid(parse_uuid(text)?),
+            PgType::Bytea => PgValue::Bytea(parse_bytea(text)?),
+        })
+    }
+
+    /// Parse the binary representation of a value of the given type
+    pub fn from_binary(pg_type: PgType, data: &[u8]) -> anyhow::Result<PgValue> {
+        Ok(match pg_type {
+            PgType::Bool => match data {
+                [0] => PgValue::Bool(false),
+                [1] => PgValue::Bool(true),
+                _ => return Err(anyhow!("Invalid bool: {data:?}")),
+            },
+            PgType::Int4 => PgValue::Int4(i32::from_be_bytes(data.try_into()?)),
+            PgType::Int8 => PgValue::Int8(i64::from_be_bytes(data.try_into()?)),
+            PgType::Text => PgValue::Text(String::from_utf8(data.to_vec())?),
+            PgType::Oid => PgValue::Oid(u32::from_be_bytes(data.try_into()?)),
+            PgType::Date => PgValue::Date(i32::from_be_bytes(data.try_into()?)),
+            PgType::Timestamp => PgValue::Timestamp(i64::from_be_bytes(data.try_into()?)),
+            PgType::Interval => {
+                if data.len() != 16 {
+                    return Err(anyhow!("Invalid interval: {data:?}"));
+                }
+                PgValue::Interval {
+                    microseconds: i64::from_be_bytes(data[0..8].try_into()?),
+                    days: i32::from_be_bytes(data[8..12].try_into()?),
+                    months: i32::from_be_bytes(data[12..16].try_into()?),
+                }
+            }
+            PgType::Numeric => PgValue::Numeric(PgNumeric::from_binary(data)?),
+            #[cfg(feature = "json")]
+            PgType::Json => PgValue::Json(serde_json::from_slice(data)?),
+            #[cfg(not(feature = "json"))]
+            PgType::Json | PgType::Jsonb => return Err(json_disabled()),
+            #[cfg(feature = "json")]
+            PgType::Jsonb => match data.split_first() {
+                Some((&JSONB_VERSION, text)) => PgValue::Jsonb(serde_json::from_slice(text)?),
+                _ => return Err(anyhow!("Unsupported jsonb version: {data:?}")),
+            },
+            PgType::Uuid => PgValue::Uuid(data.try_into()?),
+            PgType::Bytea => PgValue::Bytea(data.to_vec()),
+        })
+    }
+
+    /// Encode the value in the given format, the text follows the session
+    /// settings and is converted to the client encoding
+    pub fn encode(
+        &self,
+        format: FormatCode,
+        settings: &OutputSettings,
+    ) -> anyhow::Result<ColumnData> {
+        let encoding = settings.encoding;
+        Ok(match (format, self) {
+            (_, PgValue::Null) => ColumnData::null(),
+            (FormatCode::Text, _) => encoding
+                .encode(&String::from_utf8(self.to_text_with(settings))?)?
+                .into(),
+            (FormatCode::Binary, PgValue::Text(value)) => encoding.encode(value)?.into(),
+            #[cfg(feature = "json")]
+            (FormatCode::Binary, PgValue::Json(_)) => {
+                encoding.encode(&String::from_utf8(self.to_text())?)?.into()
+            }
+            #[cfg(feature = "json")]
+            (FormatCode::Binary, PgValue::Jsonb(_)) => [
+                vec![JSONB_VERSION],
+                encoding.encode(&String::from_utf8(self.to_text())?)?,
+            ]
+            .concat()
+            .into(),
+            (FormatCode::Binary, _) => self.to_binary().into(),
+        })
+    }
+}
+
+impl From<bool> for PgValue {
+    fn from(value: bool) -> Self {
+        PgValue::Bool(value)
+    }
+}
+
+impl From<i32> for PgValue {
+    fn from(value: i32) -> Self {
+        PgValue::Int4(value)
+    }
+}
+
+impl From<i64> for PgValue {
+    fn from(value: i64) -> Self {
+        PgValue::Int8(value)
+    }
+}
+
+impl From<&str> for PgValue {
+    fn from(value: &str) -> Self {
+        PgValue::Text(value.to_string())
+    }
+}
+
+impl From<String> for PgValue {
+    fn from(value: String) -> Self {
+        PgValue::Text(value)
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl From<uuid::Uuid> for PgValue {
+    fn from(uuid: uuid::Uuid) -> Self {
+        PgValue::Uuid(uuid.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bool_encode() -> anyhow::Result<()> {
+        assert_eq!(b"t".to_vec(), PgValue::Bool(true).to_text());
+        assert_eq!(vec![0x01], PgValue::Bool(true).to_binary());
+        assert_eq!(b"f".to_vec(), PgValue::Bool(false).to_text());
+        assert_eq!(vec![0x00], PgValue::Bool(false).to_binary());
+
+        Ok(())
+    }
+
+    #[test]
+    fn int4_encode() -> anyhow::Result<()> {
+        assert_eq!(b"-42".to_vec(), PgValue::Int4(-42).to_text());
+        assert_eq!(vec![0xff, 0xff, 0xff, 0xd6], PgValue::Int4(-42).to_binary());
+
+        Ok(())
+    }
+
+    #[test]
+    fn text_encode() -> anyhow::Result<()> {
+        let value = PgValue::Text(String::from("aldabis"));
+        assert_eq!(b"aldabis".to_vec(), value.to_text());
+        assert_eq!(b"aldabis".to_vec(), value.to_binary());
+
+        Ok(())
+    }
+
+    #[test]
+    fn value_decode() -> anyhow::Result<()> {
+        for value in [
+            PgValue::Bool(false),
+            PgValue::Int4(-42),
+            PgValue::Int8(i64::MIN),
+            PgValue::Text(String::from("aldabis")),
+            PgValue::Oid(u32::MAX),
+            PgValue::Date(-730000),
+            PgValue::Timestamp(-1),
+            PgValue::Interval {
+                months: -14,
+                days: 3,
+                microseconds: -1_500_000,
+            },
+            PgValue::Numeric("-1234.5670".parse()?),
+            #[cfg(feature = "json")]
+            PgValue::Json(serde_json::json!({"b": [1, "x"], "a": null})),
+            #[cfg(feature = "json")]
+            PgValue::Jsonb(serde_json::json!({"b": [1, "x"], "a": null})),
+            PgValue::Uuid([0xa0; 16]),
+            PgValue::Bytea(vec![0x00, b'\\', b'a', 0xff]),
+        ] {
+            let text = String::from_utf8(value.to_text())?;
+            assert_eq!(value, PgValue::from_text(value.pg_type(), &text)?);
+            assert_eq!(
+                value,
+                PgValue::from_binary(value.pg_type(), &value.to_binary())?
+            );
+        }
+        assert!(PgValue::from_text(PgType::Int4, "abc").is_err());
+        assert!(PgValue::from_binary(PgType::Int4, &[0x01]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn oid_encode() -> anyhow::Result<()> {
+        assert_eq!(b"4294967295".to_vec(), PgValue::Oid(u32::MAX).to_text());
+        assert_eq!(
+            vec![0xff, 0xff, 0xff, 0xff],
+            PgValue::Oid(u32::MAX).to_binary()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn null_encode() -> anyhow::Result<()> {
+        let settings = OutputSettings::default();
+        for format in [FormatCode::Text, FormatCode::Binary] {
+            assert!(PgValue::Null.encode(format, &settings)?.is_null());
+        }
+        // An empty text is not NULL
+        assert_eq!(
+            Some(&b""[..]),
+            PgValue::from("")
+                .encode(FormatCode::Text, &settings)?
+                .as_bytes()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn uuid_bytea_encode() -> anyhow::Result<()> {
+        let uuid = PgValue::from_text(PgType::Uuid, "{A0EEBC99-9C0B4EF8-BB6D-6BB9BD380A11}")?;
+        assert_eq!(
+            b"a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11".to_vec(),
+            uuid.to_text()
+        );
+        assert_eq!(16, uuid.to_binary().len());
+
+        // SELECT '\x00015c61ff'::bytea with both bytea_output
+        let bytea = PgValue::Bytea(vec![0x00, 0x01, b'\\', b'a', 0xff]);
+        assert_eq!(b"\\x00015c61ff".to_vec(), bytea.to_text());
+        let settings = OutputSettings {
+            bytea_output: ByteaOutput::Escape,
+            ..OutputSettings::default()
+        };
+        assert_eq!(
+            b"\\000\\001\\\\a\\377".to_vec(),
+            bytea.to_text_with(&settings)
+        );
+        assert_eq!(
+            bytea,
+            PgValue::from_text(PgType::Bytea, r"\000\001\\a\377")?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Check the parsers and the serializers against the encoded messages of
+//! testdata/wire_vectors.toml, where the source of each one is given:
+//!
+//! - PostgreSQL 14.17: the startup and the backend messages up to the
+//!   first ReadyForQuery, from tcpdumps/tcpdump_connect_no_ssl.txt
+//! - PostgreSQL 15.18: the backend messages of the simple and extended
+//!   queries, including a DataRow with a NULL value, and the frontend
+//!   messages it accepted
+//! - the protocol documentation: the authentication exchanges, NoData,
+//!   PortalSuspended, Flush and Terminate, not seen in the captures
+
+use anyhow::anyhow;
+use bytes::{BufMut, BytesMut};
+use serde::Deserialize;
+use std::io::BufReader;
+
+use libpq_serde_types::{ByteSized, Serialize};
+
+use crate::message::*;
+
+#[derive(Debug, Deserialize)]
+struct Vector {
+    message: String,
+    source: String,
+    hex: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Vectors {
+    vector: Vec<Vector>,
+}
+
+pub(crate) fn from_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| Ok(u8::from_str_radix(&hex[index..index + 2], 16)?))
+        .collect()
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn serialize<T>(message: &T) -> anyhow::Result<Vec<u8>>
+where
+    T: MessageBody + Serialize + ByteSized,
+{
+    let mut buffer = BytesMut::new();
+    MessageHeader::serialize_message(&mut buffer, message)?;
+    Ok(buffer.to_vec())
+}
+
+fn backend<T>(data: &[u8]) -> anyhow::Result<Vec<u8>>
+where
+    T: for<'a> TryFrom<&'a mut RawBackendMessage, Error = anyhow::Error>
+        + MessageBody
+        + Serialize
+        + ByteSized,
+{
+    let mut raw_message = RawBackendMessage::get(&mut BufReader::new(data))?;
+    serialize(&T::try_from(&mut raw_message)?)
+}
+
+fn frontend<T>(data: &[u8]) -> anyhow::Result<Vec<u8>>
+where
+    T: for<'a> TryFrom<&'a mut RawFrontendMessage, Error = anyhow::Error>
+        + MessageBody
+        + Serialize
+        + ByteSized,
+{
+    let mut raw_message = RawFrontendMessage::get(&mut BufReader::new(data))?;
+    serialize(&T::try_from(&mut raw_message)?)
+}
+
+fn startup_message(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let message = StartupMessage::try_from(&mut RawRequest::get(&mut BufReader::new(data))?)?;
+    let mut buffer = BytesMut::new();
+    buffer.put_i32(MessageHeader::length_for(message.byte_size())?);
+    message.serialize(&mut buffer);
+    Ok(buffer.to_vec())
+}
+
+/// Parse the message then serialize it again
+fn roundtrip(message: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match message {
+        "AuthenticationOk" => backend::<AuthenticationOk>(data),
+        "AuthenticationMD5Password" => backend::<AuthenticationMD5Password>(data),
+        "AuthenticationGSS" => backend::<AuthenticationGSS>(data),
+        "AuthenticationGSSContinue" => backend::<AuthenticationGSSContinue>(data),
+        "AuthenticationSSPI" => backend::<AuthenticationSSPI>(data),
+        "BackendKeyData" => backend::<BackendKeyData>(data),
+        "BindComplete" => backend::<BindComplete>(data),
+        "CloseComplete" => backend::<CloseComplete>(data),
+        "CommandComplete" => backend::<CommandComplete>(data),
+        "DataRow" => backend::<DataRow>(data),
+        "ErrorResponse" => backend::<ErrorResponse>(data),
+        "NoData" => backend::<NoData>(data),
+        "NoticeResponse" => backend::<NoticeResponse>(data),
+        "NotificationResponse" => backend::<NotificationResponse>(data),
+        "ParameterDescription" => backend::<ParameterDescription>(data),
+        "ParameterStatus" => backend::<ParameterStatus>(data),
+        "ParseComplete" => backend::<ParseComplete>(data),
+        "PortalSuspended" => backend::<PortalSuspended>(data),
+        "ReadyForQuery" => backend::<ReadyForQuery>(data),
+        "RowDescription" => backend::<RowDescription>(data),
+        "Bind" => frontend::<Bind>(data),
+        "Close" => frontend::<Close>(data),
+        "Describe" => frontend::<Describe>(data),
+        "Execute" => frontend::<Execute>(data),
+        "Flush" => frontend::<Flush>(data),
+        "GSSResponse" => frontend::<GSSResponse>(data),
+        "Parse" => frontend::<Parse>(data),
+        "PasswordMessage" => frontend::<PasswordMessage>(data),
+        "Query" => frontend::<Query>(data),
+        "Sync" => frontend::<Sync>(data),
+        "Terminate" => frontend::<Terminate>(data),
+        "StartupMessage" => startup_message(data),
+        _ => Err(anyhow!("No test for {message}")),
+    }
+}
+
+#[test]
+fn wire_vectors() -> anyhow::Result<()> {
+    let vectors: Vectors = toml::from_str(include_str!("../testdata/wire_vectors.toml"))?;
+    assert!(!vectors.vector.is_empty());
+
+    for vector in vectors.vector {
+        let data = from_hex(&vector.hex)?;
+        let context = format!("{} from {}", vector.message, vector.source);
+        let encoded = roundtrip(&vector.message, &data).map_err(|e| anyhow!("{context}: {e}"))?;
+        assert_eq!(vector.hex, to_hex(&encoded), "{context}");
+    }
+
+    Ok(())
+}